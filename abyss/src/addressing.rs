@@ -52,6 +52,9 @@ impl Pa {
 pub struct Va(usize);
 
 impl Va {
+    /// VA with address 0.
+    pub const ZERO: Self = Self(0);
+
     /// Create a new virtual address with a check.
     #[inline(always)]
     pub const fn new(addr: usize) -> Option<Self> {
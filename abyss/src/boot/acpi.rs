@@ -0,0 +1,111 @@
+//! Minimal ACPI table parser to discover the cpus.
+//!
+//! Only the tables required to enumerate the local APICs are parsed:
+//! RSDP -> RSDT/XSDT -> MADT.
+//!
+//! See ACPI Specification 6.4, 5.2 ACPI System Description Tables.
+
+use crate::addressing::Pa;
+
+/// 5.2.5.3 Root System Description Pointer (RSDP) Structure
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    _checksum: u8,
+    _oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    _length: u32,
+    xsdt_address: u64,
+    _ext_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// 5.2.6 System Description Table Header
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    _revision: u8,
+    _checksum: u8,
+    _oem_id: [u8; 6],
+    _oem_table_id: [u8; 8],
+    _oem_revision: u32,
+    _creator_id: u32,
+    _creator_revision: u32,
+}
+
+#[inline]
+unsafe fn read<T>(pa: usize) -> T {
+    core::ptr::read_unaligned(Pa::new(pa).unwrap().into_va().into_usize() as *const T)
+}
+
+unsafe fn checksum(pa: usize, len: usize) -> bool {
+    (0..len).fold(0u8, |acc, i| acc.wrapping_add(read::<u8>(pa + i))) == 0
+}
+
+/// 5.2.5.1 Finding the RSDP on IA-PC Systems
+unsafe fn find_rsdp() -> Option<Rsdp> {
+    // The first 1 KB of the Extended BIOS Data Area, or
+    // the BIOS read-only memory space between 0E0000h and 0FFFFFh.
+    let ebda = (read::<u16>(0x40e) as usize) << 4;
+    let ebda = if ebda != 0 { ebda..ebda + 0x400 } else { 0..0 };
+    ebda.chain(0xe0000..0x100000)
+        .step_by(16)
+        .filter(|&pa| &read::<[u8; 8]>(pa) == b"RSD PTR ")
+        .find(|&pa| checksum(pa, 20))
+        .map(|pa| read::<Rsdp>(pa))
+}
+
+/// Find the system description table that has signature `sig`.
+unsafe fn find_table(sig: &[u8; 4]) -> Option<usize> {
+    let rsdp = find_rsdp()?;
+    // Prefer XSDT on ACPI 2.0+.
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+    let header = read::<SdtHeader>(root);
+    let entries = (header.length as usize - core::mem::size_of::<SdtHeader>()) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let pa = root + core::mem::size_of::<SdtHeader>() + i * entry_size;
+            if entry_size == 8 {
+                read::<u64>(pa) as usize
+            } else {
+                read::<u32>(pa) as usize
+            }
+        })
+        .find(|&pa| {
+            &read::<SdtHeader>(pa).signature == sig
+                && checksum(pa, read::<SdtHeader>(pa).length as usize)
+        })
+}
+
+/// Enumerate the APIC ids of enabled processors described in the MADT.
+///
+/// Returns `None` if the firmware does not provide the MADT, for example,
+/// when the kernel runs as a guest of KeV.
+pub(crate) unsafe fn for_each_cpu(mut f: impl FnMut(usize)) -> Option<()> {
+    // 5.2.12 Multiple APIC Description Table (MADT)
+    let madt = find_table(b"APIC")?;
+    let end = madt + read::<SdtHeader>(madt).length as usize;
+    // Skip the header, Local Interrupt Controller Address and Flags.
+    let mut pos = madt + core::mem::size_of::<SdtHeader>() + 8;
+    while pos + 2 <= end {
+        let (ty, len) = (read::<u8>(pos), read::<u8>(pos + 1) as usize);
+        if len < 2 {
+            break;
+        }
+        match ty {
+            // 5.2.12.2 Processor Local APIC Structure
+            0 if read::<u32>(pos + 4) & 1 != 0 => f(read::<u8>(pos + 3) as usize),
+            // 5.2.12.12 Processor Local x2APIC Structure
+            9 if read::<u32>(pos + 8) & 1 != 0 => f(read::<u32>(pos + 4) as usize),
+            _ => (),
+        }
+        pos += len;
+    }
+    Some(())
+}
@@ -236,9 +236,9 @@ _boot_stack_top:
   .space  0x1000
 _boot_stack_bottom:
 
-// We can have up to 8 cores.
+// We can have up to 16 (MAX_CPU) cores.
 IDLE_STACK:
-  .space 0x400000
+  .space 0x1000000
 
 
 .code64
@@ -1,5 +1,6 @@
 //! Booting sequence
 
+mod acpi;
 mod multiboot;
 
 use crate::{
@@ -9,7 +10,7 @@ use crate::{
         msr::Msr,
         pio::Pio,
         segmentation::{Segment, SegmentSelector},
-        Cr0,
+        Cr0, PrivilegeLevel,
     },
     MAX_CPU,
 };
@@ -39,6 +40,21 @@ impl Regions {
     }
 }
 
+// The boot command line, copied out of the multiboot info on the boot.
+static mut CMDLINE: [u8; 256] = [0; 256];
+static mut CMDLINE_LEN: usize = 0;
+
+/// Get the command line that the bootloader passed to the kernel.
+///
+/// The command line is truncated to 256 bytes, and is empty if it is not
+/// valid utf-8.
+pub fn cmdline() -> &'static str {
+    unsafe {
+        let cmdline = &*core::ptr::addr_of!(CMDLINE);
+        core::str::from_utf8(&cmdline[..CMDLINE_LEN]).unwrap_or("")
+    }
+}
+
 global_asm!(include_str!("bootstrap.s"));
 #[cfg(feature = "smp")]
 global_asm!(include_str!("ap.s"));
@@ -99,7 +115,7 @@ pub unsafe fn bootup_mps() {
     core::intrinsics::volatile_store(hi, (MP_ENTRY as u16) & 0xf);
 
     // Bootup mps.
-    for mpid in 1..MAX_CPU {
+    for mpid in crate::cpus().filter(|&mpid| mpid != crate::x86_64::intrinsics::cpuid()) {
         crate::dev::x86_64::apic::send_ipi(mpid, 0x500); // init
         crate::dev::x86_64::apic::send_ipi(mpid, 0x600 | (MP_ENTRY >> 12)); // Startup
         crate::dev::x86_64::apic::send_ipi(mpid, 0x600 | (MP_ENTRY >> 12)); // Startup
@@ -125,7 +141,13 @@ unsafe extern "C" fn bootstrap(core_id: usize, mbinfo: &MultiBootInfo2) {
             core::slice::from_raw_parts_mut(start as *mut u8, end - start)
         }
         .fill(0);
+        if let Some(cmdline) = mbinfo.get_cmdline() {
+            let len = cmdline.len().min(CMDLINE.len());
+            CMDLINE[..len].copy_from_slice(&cmdline[..len]);
+            CMDLINE_LEN = len;
+        }
 
+        discover_cpus();
         initialize_idt();
     }
 
@@ -142,6 +164,38 @@ unsafe extern "C" fn bootstrap(core_id: usize, mbinfo: &MultiBootInfo2) {
     }
 }
 
+/// Discover the cpus of this machine.
+///
+/// The MADT is the primary source. When the firmware does not provide it (e.g.
+/// the kernel runs as a guest of KeV), fall back to the number of logical
+/// processors reported by the cpuid.
+unsafe fn discover_cpus() {
+    use core::sync::atomic::Ordering;
+
+    let mut mask = 0u64;
+    if acpi::for_each_cpu(|apic_id| {
+        if apic_id < MAX_CPU {
+            mask |= 1 << apic_id;
+        }
+    })
+    .is_none()
+        || mask == 0
+    {
+        // CPUID.01H:EBX[23:16] is valid only when CPUID.01H:EDX.HTT is set.
+        let leaf1 = core::arch::x86_64::__cpuid(1);
+        let cnt = if leaf1.edx & (1 << 28) != 0 {
+            ((leaf1.ebx >> 16) & 0xff) as usize
+        } else {
+            1
+        };
+        mask = (1 << cnt.clamp(1, MAX_CPU)) - 1;
+    }
+    crate::CPU_MASK.store(
+        mask | 1 << crate::x86_64::intrinsics::cpuid(),
+        Ordering::Relaxed,
+    );
+}
+
 use crate::x86_64::interrupt::IDT;
 
 unsafe fn per_cpu_init(core_id: usize) {
@@ -149,6 +203,8 @@ unsafe fn per_cpu_init(core_id: usize) {
     IDT.load();
     SEGMENT_TABLE.load();
     SEGMENT_TABLE.init_tss();
+    crate::x86_64::gs::init(core_id);
+    crate::x86_64::pat::init();
 
     crate::dev::x86_64::apic::init(core_id).expect("Failed to initialize apic");
     crate::dev::x86_64::timer::init(core_id).expect("Failed to initialize timer");
@@ -260,7 +316,6 @@ fn initialize_idt() {
         fn do_isr_125(_: &mut InterruptStackFrame);
         fn do_isr_126(_: &mut InterruptStackFrame);
         fn do_isr_127(_: &mut InterruptStackFrame);
-        fn do_isr_128(_: &mut InterruptStackFrame);
         fn do_isr_129(_: &mut InterruptStackFrame);
         fn do_isr_130(_: &mut InterruptStackFrame);
         fn do_isr_131(_: &mut InterruptStackFrame);
@@ -394,6 +449,7 @@ fn initialize_idt() {
         fn page_fault(_: &mut InterruptStackFrame, _: PFErrorCode);
         fn device_not_available(_: &mut InterruptStackFrame);
         fn simd_floating_point_exception(_: &mut InterruptStackFrame);
+        fn syscall_entry(_: &mut InterruptStackFrame);
     }
     let idt = unsafe { &mut IDT };
     idt.invalid_opcode.set(
@@ -906,10 +962,11 @@ fn initialize_idt() {
         ExceptionType::Interrupt,
         do_isr_127,
     );
-    idt.user_defined[96].set(
-        Segment::KernelCode.into_selector(),
+    // The user can raise the system call vector with `int`.
+    idt.user_defined[crate::interrupt::SYSCALL_VECTOR - 32].set(
+        SegmentSelector::new(Segment::KERNEL_CODE_SELECTOR.index(), PrivilegeLevel::Ring3),
         ExceptionType::Interrupt,
-        do_isr_128,
+        syscall_entry,
     );
     idt.user_defined[97].set(
         Segment::KernelCode.into_selector(),
@@ -118,28 +118,29 @@ pub(crate) struct MultiBootInfo2 {
     pub(crate) fields: [u8; 0],
 }
 
-impl MultiBootInfo2 {
-    pub(crate) fn get_memory_map(&self) -> Option<MemoryMap> {
-        #[repr(C)]
-        struct Header {
-            ty: u32,
-            size: u32,
-            o: [u8; 0],
-        }
+#[repr(C)]
+struct Header {
+    ty: u32,
+    size: u32,
+    o: [u8; 0],
+}
 
-        impl Header {
-            fn read_at<T>(&self, p: usize) -> T
-            where
-                T: Copy,
-            {
-                unsafe {
-                    *((self.o.as_ptr() as usize + p) as *const T)
-                        .as_ref()
-                        .unwrap()
-                }
-            }
+impl Header {
+    fn read_at<T>(&self, p: usize) -> T
+    where
+        T: Copy,
+    {
+        unsafe {
+            *((self.o.as_ptr() as usize + p) as *const T)
+                .as_ref()
+                .unwrap()
         }
+    }
+}
 
+impl MultiBootInfo2 {
+    // Find the first tag of the type `ty`.
+    fn find(&self, ty: u32) -> Option<&Header> {
         let mut pos = 0;
 
         while pos + 8 <= self.total_size as usize {
@@ -153,20 +154,31 @@ impl MultiBootInfo2 {
                 0 => {
                     return None;
                 }
-                6 => {
-                    return Some(MemoryMap {
-                        stride: en.read_at(0),
-                        _version: en.read_at(4),
-                        total_size: en.size as usize - 16,
-                        entries: unsafe {
-                            ((en.o.as_ptr() as usize + 8) as *const [u8; 0]).as_ref()
-                        }
-                        .unwrap(),
-                    });
+                t if t == ty => {
+                    return Some(en);
                 }
                 _ => (),
             }
         }
         None
     }
+
+    pub(crate) fn get_memory_map(&self) -> Option<MemoryMap> {
+        let en = self.find(6)?;
+        Some(MemoryMap {
+            stride: en.read_at(0),
+            _version: en.read_at(4),
+            total_size: en.size as usize - 16,
+            entries: unsafe { ((en.o.as_ptr() as usize + 8) as *const [u8; 0]).as_ref() }.unwrap(),
+        })
+    }
+
+    /// Get the boot command line, without the terminating nul.
+    pub(crate) fn get_cmdline(&self) -> Option<&[u8]> {
+        let en = self.find(1)?;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(en.o.as_ptr(), (en.size as usize).saturating_sub(8))
+        };
+        Some(bytes.split(|&b| b == 0).next().unwrap_or_default())
+    }
 }
@@ -14,7 +14,10 @@
 
 //! Mmio interface.
 
-use crate::addressing::{Pa, Va};
+use crate::{
+    addressing::{Pa, Va, PAGE_MASK, PAGE_SHIFT},
+    x86_64::pat::CacheAttr,
+};
 
 /// Type for accessing mmio register.
 #[repr(transparent)]
@@ -100,11 +103,15 @@ impl MmioArea {
     }
 
     /// Activate this mmio area.
+    ///
+    /// The area is mapped as uncached for the rest of the kernel.
     #[inline(always)]
     pub fn activate(self) -> ActiveMmioArea {
-        let core::ops::Range { start, end } = self.0;
-
-        ActiveMmioArea(start.into_va()..end.into_va())
+        ActiveMmioArea(
+            map_mmio(self.0.start, self.size(), CacheAttr::Uncached)
+                .expect("Mmio window is exhausted.")
+                .leak(),
+        )
     }
 
     /// Clone this mmio area.
@@ -176,52 +183,287 @@ impl ActiveMmioArea {
     }
 }
 
+/// Number of the 4-KByte pages in the mmio window.
+const WINDOW_PAGES: usize = 16 * 512;
+/// Base of the mmio window, the last 1-GByte slot of the kernel mapping.
+const WINDOW_BASE: usize = 0xffff_ff7f_c000_0000;
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+// The page tables of the window. They are static, so that the devices can be
+// mapped before the heap is ready.
+static mut WINDOW_PD: PageTable = PageTable([0; 512]);
+static mut WINDOW_PTS: [PageTable; WINDOW_PAGES / 512] =
+    [const { PageTable([0; 512]) }; WINDOW_PAGES / 512];
+
+struct Window {
+    installed: bool,
+    // Number of the pages handed out. The pages are never reused, as the
+    // stale translations may be cached on the other cpus.
+    next: usize,
+}
+
+static WINDOW: crate::spin_lock::SpinLock<Window> = crate::spin_lock::SpinLock::new(Window {
+    installed: false,
+    next: 0,
+});
+
+// Install the page tables of the window into the kernel mapping.
+unsafe fn install_window() {
+    extern "C" {
+        static mut boot_pdpt2: PageTable;
+    }
+    let pa = |va: *const PageTable| Va::new(va as usize).unwrap().into_pa().into_usize() as u64;
+    for (pde, pt) in WINDOW_PD.0.iter_mut().zip(WINDOW_PTS.iter()) {
+        *pde = pa(pt) | 0x3;
+    }
+    boot_pdpt2.0[(WINDOW_BASE >> 30) & 511] = pa(core::ptr::addr_of!(WINDOW_PD)) | 0x3;
+}
+
+/// A mmio region mapped with a cache attribute.
+///
+/// The registers are accessed with [`MmioMapping::read`] and
+/// [`MmioMapping::write`]. The region is unmapped on drop.
+#[derive(Debug)]
+pub struct MmioMapping {
+    pa: Pa,
+    va: Va,
+    len: usize,
+    attr: CacheAttr,
+}
+
+unsafe impl Send for MmioMapping {}
+unsafe impl Sync for MmioMapping {}
+
+/// Map the `len` bytes of the mmio region at `pa` with the cache attribute
+/// `attr`.
+///
+/// The device registers must be mapped as [`CacheAttr::Uncached`], as the
+/// direct mapping of the physical memory caches them. Returns `None` if the
+/// mmio window of the kernel is exhausted.
+pub fn map_mmio(pa: Pa, len: usize, attr: CacheAttr) -> Option<MmioMapping> {
+    let ofs = unsafe { pa.into_usize() } & PAGE_MASK;
+    let base = unsafe { pa.into_usize() } - ofs;
+    let pages = (ofs + len + PAGE_MASK) >> PAGE_SHIFT;
+    let first = {
+        let mut window = WINDOW.lock();
+        if window.next + pages > WINDOW_PAGES {
+            return None;
+        }
+        if !window.installed {
+            unsafe { install_window() };
+            window.installed = true;
+        }
+        window.next += pages;
+        window.next - pages
+    };
+    for i in 0..pages {
+        let idx = first + i;
+        unsafe {
+            WINDOW_PTS[idx / 512].0[idx % 512] =
+                (base + (i << PAGE_SHIFT)) as u64 | attr.pte_bits() | 0x3;
+        }
+    }
+    Some(MmioMapping {
+        pa,
+        va: Va::new(WINDOW_BASE + (first << PAGE_SHIFT) + ofs).unwrap(),
+        len,
+        attr,
+    })
+}
+
+impl MmioMapping {
+    /// Get the physical address of the region.
+    #[inline]
+    pub fn pa(&self) -> Pa {
+        self.pa
+    }
+
+    /// Get the virtual address of the region.
+    #[inline]
+    pub fn va(&self) -> Va {
+        self.va
+    }
+
+    /// Get the size of the region in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the region is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the cache attribute of the mapping.
+    #[inline]
+    pub fn attr(&self) -> CacheAttr {
+        self.attr
+    }
+
+    fn at<T>(&self, ofs: usize) -> *mut T {
+        assert!(
+            ofs.checked_add(core::mem::size_of::<T>())
+                .is_some_and(|end| end <= self.len),
+            "Mmio access out of bounds."
+        );
+        (unsafe { self.va.into_usize() } + ofs) as *mut T
+    }
+
+    /// Read the register of the type `T` at the offset `ofs`.
+    #[inline]
+    pub fn read<T: Copy>(&self, ofs: usize) -> T {
+        unsafe { core::ptr::read_volatile(self.at(ofs)) }
+    }
+
+    /// Write the register of the type `T` at the offset `ofs`.
+    #[inline]
+    pub fn write<T: Copy>(&self, ofs: usize, v: T) {
+        unsafe { core::ptr::write_volatile(self.at(ofs), v) }
+    }
+
+    /// Keep the region mapped for the rest of the kernel, and returns its
+    /// virtual address range.
+    pub fn leak(self) -> core::ops::Range<Va> {
+        let this = core::mem::ManuallyDrop::new(self);
+        this.va..this.va + this.len
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        let va = unsafe { self.va.into_usize() } & !PAGE_MASK;
+        let pages =
+            ((unsafe { self.va.into_usize() } & PAGE_MASK) + self.len + PAGE_MASK) >> PAGE_SHIFT;
+        for i in 0..pages {
+            let idx = ((va - WINDOW_BASE) >> PAGE_SHIFT) + i;
+            unsafe {
+                WINDOW_PTS[idx / 512].0[idx % 512] = 0;
+            }
+            crate::x86_64::intrinsics::invlpg(va + (i << PAGE_SHIFT));
+        }
+    }
+}
+
+/// Description of a register of a [`mmio!`] register group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioRegister {
+    /// Name of the register.
+    pub name: &'static str,
+    /// Offset of the (first element of the) register in bytes.
+    pub offset: usize,
+    /// Width of the register in bytes.
+    pub width: usize,
+    /// Distance between the elements of a register array in bytes.
+    pub stride: usize,
+    /// Number of the elements; 1 if the register is not an array.
+    pub count: usize,
+    /// Whether the register is readable.
+    pub readable: bool,
+    /// Whether the register is writable.
+    pub writable: bool,
+}
+
+impl MmioRegister {
+    /// Get the index of the element that covers the byte at `offset`, if
+    /// any.
+    pub const fn index_of(&self, offset: usize) -> Option<usize> {
+        if offset < self.offset {
+            return None;
+        }
+        let (idx, rem) = (
+            (offset - self.offset) / self.stride,
+            (offset - self.offset) % self.stride,
+        );
+        if idx < self.count && rem < self.width {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+}
+
+/// Register layout of a [`mmio!`] register group.
+///
+/// Device models use it to dispatch the emulated accesses by the offset.
+pub trait MmioLayout {
+    /// The registers, in the order of the definition.
+    const REGISTERS: &'static [MmioRegister];
+
+    /// Find the register and the index of its element that covers the byte
+    /// at `offset`.
+    fn register_at(offset: usize) -> Option<(&'static MmioRegister, usize)> {
+        Self::REGISTERS
+            .iter()
+            .find_map(|r| r.index_of(offset).map(|idx| (r, idx)))
+    }
+}
+
 #[doc(hidden)]
 #[macro_export(local_inner_macros)]
 macro_rules! __mmio_mk_register {
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => R, $T:ty; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, true, false);
-        __mmio_mk_register!($e, $($t)*);
+    // Register
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => R, $T:ty; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, true, false, "read-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, 1, true, false)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => W, $T:ty; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, false, true);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => W, $T:ty; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, false, true, "write-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, 1, false, true)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => RW, $T:ty; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, true, true);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => RW, $T:ty; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $off, true, true, "read-write");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, 1, true, true)], $($t)*);
     };
 
     // Array
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => R, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, false);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => R, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, false, "read-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, false)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => W, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, false, true);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => W, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, false, true, "write-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, $sz, false, true)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr => RW, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, true);
-       __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr => RW, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, true, "read-write");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, core::mem::size_of::<$T>(), $off, $sz, true, true)], $($t)*);
     };
 
     // Array with Stride
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => R, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, true, false);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => R, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, true, false, "read-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, $S, $off, $sz, true, false)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => W, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, false, true);
-        __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => W, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, false, true, "write-only");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, $S, $off, $sz, false, true)], $($t)*);
     };
-    ($e:ident, $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => RW, $T:ty, $sz:expr; $($t:tt)*) => {
-        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, true, true);
-       __mmio_mk_register!($e, $($t)*);
+    ($e:ident, [$($acc:tt)*], $(#[$attr:meta])* $N:ident @ $off:expr, $S:expr => RW, $T:ty, $sz:expr; $($t:tt)*) => {
+        __mmio_mk_register!(@MAKE, $e, $(#[$attr])*, $N, $T, $S, $off, $sz, true, true, "read-write");
+        __mmio_mk_register!($e, [$($acc)* (@REG $N, $T, $S, $off, $sz, true, true)], $($t)*);
     };
-    (@MAKE, $e:ident, $(#[$attr:meta])*, $N:ident, $T: ty, $off:expr, $r:expr, $w:expr) => {
+    (@MAKE, $e:ident, $(#[$attr:meta])*, $N:ident, $T: ty, $off:expr, $r:expr, $w:expr, $access:literal) => {
+        // The register must be naturally aligned, and accessed in a single
+        // access of the cpu.
+        const _: () = {
+            core::assert!(
+                core::matches!(core::mem::size_of::<$T>(), 1 | 2 | 4 | 8),
+                core::concat!("Mmio register `", core::stringify!($N), "` must be 1, 2, 4 or 8 bytes wide.")
+            );
+            core::assert!(
+                ($off) % core::mem::size_of::<$T>() == 0,
+                core::concat!("Mmio register `", core::stringify!($N), "` is not aligned.")
+            );
+        };
+
         impl $e {
             $(#[$attr])*
+            #[doc = ""]
+            #[doc = core::concat!("Offset `", core::stringify!($off), "`, ", $access, " `", core::stringify!($T), "`.")]
             #[inline(always)]
             #[allow(non_snake_case)]
             #[allow(dead_code)]
@@ -240,9 +482,25 @@ macro_rules! __mmio_mk_register {
             }
         }
     };
-    (@MAKE, $e:ident, $(#[$attr:meta])*, $N:ident, $T:ty, $S:expr, $off:expr, $sz:expr, $r:expr, $w:expr) => {
+    (@MAKE, $e:ident, $(#[$attr:meta])*, $N:ident, $T:ty, $S:expr, $off:expr, $sz:expr, $r:expr, $w:expr, $access:literal) => {
+        const _: () = {
+            core::assert!(
+                core::matches!(core::mem::size_of::<$T>(), 1 | 2 | 4 | 8),
+                core::concat!("Mmio register `", core::stringify!($N), "` must be 1, 2, 4 or 8 bytes wide.")
+            );
+            core::assert!(
+                ($off) % core::mem::size_of::<$T>() == 0 && ($S) % core::mem::size_of::<$T>() == 0,
+                core::concat!("Mmio register `", core::stringify!($N), "` is not aligned.")
+            );
+        };
+
         impl $e {
             $(#[$attr])*
+            #[doc = ""]
+            #[doc = core::concat!(
+                "Offset `", core::stringify!($off), "`, ", $access, " array of ", core::stringify!($sz),
+                " `", core::stringify!($T), "` with the stride of `", core::stringify!($S), "`."
+            )]
             #[inline(always)]
             #[allow(non_snake_case)]
             pub fn $N(&self) -> $crate::dev::mmio::MmioArrayAccessor<$T, $r, $w, $sz> {
@@ -253,7 +511,21 @@ macro_rules! __mmio_mk_register {
             }
         }
     };
-    ($e:expr,) => ();
+    ($e:ident, [$((@REG $N:ident, $T:ty, $S:expr, $off:expr, $sz:expr, $r:expr, $w:expr))*],) => {
+        impl $crate::dev::mmio::MmioLayout for $e {
+            const REGISTERS: &'static [$crate::dev::mmio::MmioRegister] = &[$(
+                $crate::dev::mmio::MmioRegister {
+                    name: core::stringify!($N),
+                    offset: $off,
+                    width: core::mem::size_of::<$T>(),
+                    stride: $S,
+                    count: $sz,
+                    readable: $r,
+                    writable: $w,
+                },
+            )*];
+        }
+    };
 }
 
 #[doc(hidden)]
@@ -263,8 +535,21 @@ macro_rules! __mmio_mk_method {
         impl $N {
             /// Create new mmio area.
             pub fn new_from_mmio_area(area: $crate::dev::mmio::MmioArea) -> Self {
-                let core::ops::Range { start, end } = area.0;
-                unsafe { Self(start.into_va().into_usize()..end.into_va().into_usize()) }
+                let (start, end) = area.activate().start_end();
+                Self(start..end)
+            }
+
+            /// Create the register group on the memory of `range`.
+            ///
+            /// Device models use it to lay the registers over the memory that
+            /// backs an emulated device.
+            ///
+            /// # Safety
+            /// `range` must be mapped, and must not be accessed otherwise while
+            /// the register group is alive.
+            #[allow(dead_code)]
+            pub unsafe fn new_from_va(range: core::ops::Range<$crate::addressing::Va>) -> Self {
+                Self(range.start.into_usize()..range.end.into_usize())
             }
 
             /// Get starting virtual address.
@@ -278,6 +563,38 @@ macro_rules! __mmio_mk_method {
 }
 
 /// Make mmio register groups.
+///
+/// Each register is declared with its name, byte offset, access and type.
+/// An array of registers takes the number of the elements, and optionally
+/// the stride in bytes after the offset:
+///
+/// ```ignore
+/// abyss::mmio! {
+///     /// Registers of a device.
+///     pub DeviceRegs:
+///         /// Version of the device.
+///         version @ 0x0 => R, u32;
+///         /// Doorbell.
+///         notify @ 0x4 => W, u32;
+///         status @ 0x8 => RW, u8;
+///         /// Eight 4-byte mailboxes.
+///         mailbox @ 0x10 => RW, u32, 8;
+///         /// Four 2-byte queue indices, 16 bytes apart.
+///         queue @ 0x40, 16 => R, u16, 4;
+/// }
+///
+/// let regs = DeviceRegs::new_from_mmio_area(area);
+/// regs.notify().write(1);
+/// let v = regs.version().read();
+/// // regs.version().write(1) does not compile; the register is read-only.
+/// ```
+///
+/// Every access is a volatile access of the width of the register. The
+/// registers must be 1, 2, 4 or 8 bytes wide and naturally aligned; it is
+/// checked at the compile time. The group implements [`MmioLayout`], which
+/// describes the registers for the device models.
+///
+/// [`MmioLayout`]: crate::dev::mmio::MmioLayout
 #[macro_export]
 macro_rules! mmio {
     ($(#[$attr:meta])* $N:ident: $($t:tt)*) => {
@@ -285,7 +602,7 @@ macro_rules! mmio {
         struct $N(core::ops::Range<usize>);
 
         $crate::__mmio_mk_method!($N);
-        $crate::__mmio_mk_register!($N, $($t)*);
+        $crate::__mmio_mk_register!($N, [], $($t)*);
     };
 
     ($(#[$attr:meta])* pub $N:ident: $($t:tt)*) => {
@@ -293,15 +610,15 @@ macro_rules! mmio {
         pub struct $N(core::ops::Range<usize>);
 
         $crate::__mmio_mk_method!($N);
-        $crate::__mmio_mk_register!($N, $($t)*);
+        $crate::__mmio_mk_register!($N, [], $($t)*);
     };
 
     ($(#[$attr:meta])* pub ($($vis:tt)+) $N:ident: $($t:tt)*) => {
         $(#[$attr])*
         #[allow(non_snake_case, dead_code)]
-        pub ($($vis:tt)+) struct $N(core::ops::Range<usize>);
+        pub ($($vis)+) struct $N(core::ops::Range<usize>);
 
         $crate::__mmio_mk_method!($N);
-        $crate::__mmio_mk_register!($N, $($t)*);
+        $crate::__mmio_mk_register!($N, [], $($t)*);
     };
 }
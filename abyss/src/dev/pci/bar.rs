@@ -1,5 +1,8 @@
 use crate::addressing::Pa;
+use crate::spin_lock::SpinLock;
 use crate::x86_64::pio::Pio;
+use alloc::vec::Vec;
+use core::ops::Range;
 
 /// BAR on IO Space.
 #[derive(Debug)]
@@ -9,6 +12,16 @@ pub struct IoSpace {
 }
 
 impl IoSpace {
+    /// Get the base port of the IO Space.
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Get the length of the IO Space.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
     /// Access offset on the IO Space.
     #[cfg(target_arch = "x86_64")]
     pub fn offset<const IDX: u32>(&self) -> Option<Pio> {
@@ -27,10 +40,31 @@ impl IoSpace {
 pub struct MemorySpace {
     pub(crate) base: Pa,
     pub(crate) length: usize,
-    pub(crate) _prefetchable: bool,
+    pub(crate) prefetchable: bool,
+    pub(crate) is_64bit: bool,
 }
 
 impl MemorySpace {
+    /// Get the base address of the Memory Space.
+    pub fn base(&self) -> Pa {
+        self.base
+    }
+
+    /// Get the length of the Memory Space.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Check whether the Memory Space is prefetchable.
+    pub fn is_prefetchable(&self) -> bool {
+        self.prefetchable
+    }
+
+    /// Check whether the BAR is 64-bit, which can be placed above 4GiB.
+    pub fn is_64bit(&self) -> bool {
+        self.is_64bit
+    }
+
     pub fn all(&self) -> crate::dev::mmio::MmioArea {
         unsafe { crate::dev::mmio::MmioArea::new(self.base..(self.base + self.length)) }
     }
@@ -83,3 +117,75 @@ impl Bar {
         }
     }
 }
+
+/// Allocator of the host physical addresses for the memory BARs.
+///
+/// The BARs are naturally aligned, so an allocation of `size` bytes is aligned
+/// to `size`.
+pub struct MmioAllocator {
+    window: Range<usize>,
+    // The allocated ranges, sorted by the address.
+    allocated: Vec<Range<usize>>,
+}
+
+/// Allocator of the 32-bit PCI hole of the host.
+///
+/// The BARs programmed by the firmware are reserved on [`init`].
+///
+/// [`init`]: super::init
+pub static HOST_MMIO: SpinLock<MmioAllocator> =
+    SpinLock::new(MmioAllocator::new(0xc000_0000..0xfec0_0000));
+
+impl MmioAllocator {
+    /// Create a new allocator of the `window`.
+    pub const fn new(window: Range<usize>) -> Self {
+        Self {
+            window,
+            allocated: Vec::new(),
+        }
+    }
+
+    /// Reserve the `range`, such as a BAR already programmed.
+    ///
+    /// Returns false if the range overlaps with the allocated ranges.
+    pub fn reserve(&mut self, range: Range<usize>) -> bool {
+        if range.is_empty()
+            || self
+                .allocated
+                .iter()
+                .any(|r| r.start < range.end && range.start < r.end)
+        {
+            return false;
+        }
+        let idx = self.allocated.partition_point(|r| r.start < range.start);
+        self.allocated.insert(idx, range);
+        true
+    }
+
+    /// Allocate `size` bytes aligned to the `size` from the window.
+    pub fn alloc(&mut self, size: usize) -> Option<Pa> {
+        if !size.is_power_of_two() {
+            return None;
+        }
+        let mut base = self.window.start.checked_add(size - 1)? & !(size - 1);
+        for r in self.allocated.iter() {
+            if base.checked_add(size)? <= r.start {
+                break;
+            }
+            if base < r.end {
+                base = r.end.checked_add(size - 1)? & !(size - 1);
+            }
+        }
+        if base.checked_add(size)? > self.window.end {
+            return None;
+        }
+        self.reserve(base..base + size);
+        Pa::new(base)
+    }
+
+    /// Free the range at `base`.
+    pub fn free(&mut self, base: Pa) {
+        let base = unsafe { base.into_usize() };
+        self.allocated.retain(|r| r.start != base);
+    }
+}
@@ -7,12 +7,35 @@ pub struct Capability<'a, const V: usize> {
 }
 
 impl<'a, const V: usize> Capability<'a, V> {
+    /// Capability id of the vendor specific capabilities.
+    pub const ID_VENDOR: u8 = 0x9;
+
     /// Get vendor id of the capability.
     #[inline]
     pub fn vendor(&self) -> u8 {
+        self.id()
+    }
+
+    /// Get id of the capability.
+    #[inline]
+    pub fn id(&self) -> u8 {
         self.pci_header.accessor(self.base).read_u8()
     }
 
+    /// Get offset of the capability in the configuration space.
+    #[inline]
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+
+    /// Get length of the vendor specific capability.
+    ///
+    /// Returns `None` if the capability is not vendor specific.
+    #[inline]
+    pub fn length(&self) -> Option<u8> {
+        (self.id() == Self::ID_VENDOR).then(|| self.pci_header.accessor(self.base + 2).read_u8())
+    }
+
     /// Get accessor to read/write the vendor specific capabilities.
     #[inline]
     pub fn offset(&self, offset: u8) -> PciAccessor {
@@ -23,30 +46,34 @@ impl<'a, const V: usize> Capability<'a, V> {
             self.base + offset,
         )
     }
-
 }
 
 #[doc(hidden)]
 pub struct CapabilityIterator<'a, const V: usize> {
     pub(crate) next: u8,
+    pub(crate) visited: usize,
     pub(crate) pci_header: &'a PciHeader<V>,
 }
 
+// Maximum number of the capabilities in the 256-byte configuration space,
+// to stop on a looped list.
+const MAX_CAPABILITIES: usize = 48;
+
 impl<'a, const V: usize> core::iter::Iterator for CapabilityIterator<'a, V> {
     type Item = Capability<'a, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next != 0 {
-            let cur = self.next;
-            self.next = self.pci_header.accessor(cur + 1).read_u8();
-
-            Some(Capability {
-                base: cur,
-                pci_header: self.pci_header,
-            })
-        } else {
-            None
+        // The capabilities are placed after the header, aligned to 4 bytes.
+        let cur = self.next & !3;
+        if cur < 0x40 || self.visited >= MAX_CAPABILITIES {
+            return None;
         }
+        self.visited += 1;
+        self.next = self.pci_header.accessor(cur + 1).read_u8();
+        Some(Capability {
+            base: cur,
+            pci_header: self.pci_header,
+        })
     }
 }
 
@@ -1,4 +1,4 @@
-use super::bar::{Bar, IoSpace, MemorySpace};
+use super::bar::{Bar, IoSpace, MemorySpace, MmioAllocator};
 use super::cap::CapabilityIterator;
 use super::{PciAccessor, PciDevice};
 use crate::addressing::Pa;
@@ -67,6 +67,15 @@ impl<const V: usize> PciHeader<V> {
     }
 }
 
+// Decode the length of the BAR from its size mask.
+fn bar_length(value: u64, mask: u64) -> usize {
+    if value & 1 == 1 {
+        (!((mask as u32 & !0b11) | 0xffff_0000)).wrapping_add(1) as usize
+    } else {
+        (!(mask & !0b1111)).wrapping_add(1) as usize
+    }
+}
+
 impl PciHeader<0> {
     /// Get status of the device.
     #[inline]
@@ -83,47 +92,123 @@ impl PciHeader<0> {
             } else {
                 0
             },
+            visited: 0,
             pci_header: self,
         }
     }
 
-    /// Get BAR of the device.
-    #[inline]
-    pub fn bar(&self, index: u8) -> Option<Bar> {
+    // Size the BAR `index` by writing all ones, with the decoding of the device
+    // disabled not to decode the transient address.
+    //
+    // Returns the value and the size mask of the BAR. The upper half of a
+    // 64-bit BAR is merged.
+    fn size_bar(&self, index: u8) -> Option<(u64, u64)> {
         if index > 5 {
             return None;
         }
-        let bar_accessor = self.accessor(0x10 + 4 * index);
-        let bar = bar_accessor.read_u32();
+        let lo = self.accessor(0x10 + 4 * index);
+        let raw = lo.read_u32();
+        let is_64bit = raw & 1 == 0 && (raw >> 1) & 3 == 2;
+        if is_64bit && index > 4 {
+            return None;
+        }
+        let command = self.accessor(0x4);
+        let cmd = command.read_u16();
+        command.write_u16(cmd & !0b11);
+
+        lo.write_u32(u32::MAX);
+        let mut mask = lo.read_u32() as u64;
+        lo.write_u32(raw);
+        let mut value = raw as u64;
+        if is_64bit {
+            let hi = self.accessor(0x10 + 4 * index + 4);
+            let raw_hi = hi.read_u32();
+            hi.write_u32(u32::MAX);
+            mask |= (hi.read_u32() as u64) << 32;
+            hi.write_u32(raw_hi);
+            value |= (raw_hi as u64) << 32;
+        } else {
+            mask |= 0xffff_ffff << 32;
+        }
+
+        command.write_u16(cmd);
+        // Unimplemented BAR.
+        if mask as u32 == 0 {
+            return None;
+        }
+        Some((value, mask))
+    }
 
-        // Resolve length.
-        bar_accessor.write_u32(u32::MAX);
-        let bar_length = bar_accessor.read_u32();
-        bar_accessor.write_u32(bar);
+    /// Get the size of the BAR `index` in bytes.
+    #[inline]
+    pub fn bar_size(&self, index: u8) -> Option<usize> {
+        self.size_bar(index)
+            .map(|(value, mask)| bar_length(value, mask))
+    }
 
-        if bar & 1 == 1 {
+    /// Get BAR of the device.
+    #[inline]
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let (value, mask) = self.size_bar(index)?;
+        let length = bar_length(value, mask);
+        if value & 1 == 1 {
             Some(Bar::IoSpace(IoSpace {
-                base: bar & !3,
-                length: !(bar_length & !0b11) + 1,
+                base: value as u32 & !3,
+                length: length as u32,
             }))
         } else {
-            let (prefetchable, ty) = (bar & 8 == 8, (bar >> 1) & 3);
-            let addr = match ty {
-                0 => (bar & !0xf) as usize,
-                2 if index <= 4 => {
-                    (((bar & !0xf) as u64)
-                        | ((self.accessor(0x10 + 4 * index + 4).read_u32() as u64) << 32))
-                        as usize
-                }
-                // 1 => reserved.
-                _ => return None,
-            };
-            Pa::new(addr).map(|base| {
-            Bar::MemorySpace(MemorySpace {
-                base,
-                length: (!(bar_length & !0b1111) + 1) as usize,
-                _prefetchable: prefetchable,
-            })})
+            let (prefetchable, ty) = (value & 8 == 8, (value >> 1) & 3);
+            // 1 => reserved.
+            if ty != 0 && ty != 2 {
+                return None;
+            }
+            Pa::new((value & !0xf) as usize).map(|base| {
+                Bar::MemorySpace(MemorySpace {
+                    base,
+                    length,
+                    prefetchable,
+                    is_64bit: ty == 2,
+                })
+            })
+        }
+    }
+
+    /// Program the memory BAR `index` to `base`, and get the relocated BAR.
+    ///
+    /// The `base` must be aligned to the size of the BAR, and a 32-bit BAR
+    /// must be placed below 4GiB. The accessors made from the old BAR must not
+    /// be used afterward.
+    pub fn set_bar(&self, index: u8, base: Pa) -> Option<Bar> {
+        let (value, mask) = self.size_bar(index)?;
+        let size = bar_length(value, mask) as u64;
+        let addr = unsafe { base.into_usize() } as u64;
+        let is_64bit = (value >> 1) & 3 == 2;
+        if value & 1 == 1 || addr & (size - 1) != 0 || (!is_64bit && addr + size > 1 << 32) {
+            return None;
+        }
+        let command = self.accessor(0x4);
+        let cmd = command.read_u16();
+        command.write_u16(cmd & !0b11);
+        self.accessor(0x10 + 4 * index)
+            .write_u32(addr as u32 | (value as u32 & 0xf));
+        if is_64bit {
+            self.accessor(0x10 + 4 * index + 4)
+                .write_u32((addr >> 32) as u32);
+        }
+        command.write_u16(cmd);
+        self.bar(index)
+    }
+
+    /// Assign an address from the `allocator` to the memory BAR `index`, and
+    /// get the relocated BAR.
+    ///
+    /// See [`PciHeader::set_bar`].
+    pub fn assign_bar(&self, index: u8, allocator: &mut MmioAllocator) -> Option<Bar> {
+        let base = allocator.alloc(self.bar_size(index)?)?;
+        let bar = self.set_bar(index, base);
+        if bar.is_none() {
+            allocator.free(base);
         }
+        bar
     }
 }
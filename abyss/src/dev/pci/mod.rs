@@ -6,7 +6,8 @@ mod header;
 pub mod virtio;
 mod x86_config;
 
-pub use bar::{Bar, IoSpace, MemorySpace};
+use alloc::boxed::Box;
+pub use bar::{Bar, IoSpace, MemorySpace, MmioAllocator, HOST_MMIO};
 pub use cap::{Capability, CapabilityIterator, MessageControl};
 pub use header::*;
 use x86_config::X86Config;
@@ -319,10 +320,28 @@ pub fn scan() -> PciIterator {
     }
 }
 
+// Reserve the memory BARs programmed by the firmware from the HOST_MMIO.
+fn reserve_bars(header: &PciHeader<0>) {
+    let mut index = 0;
+    while index < 6 {
+        if let Some(Bar::MemorySpace(bar)) = header.bar(index) {
+            let base = unsafe { bar.base().into_usize() };
+            HOST_MMIO.lock().reserve(base..base + bar.length());
+            if bar.is_64bit() {
+                index += 1;
+            }
+        }
+        index += 1;
+    }
+}
+
 /// Initialize pci devices.
 pub unsafe fn init() {
     // Scan pci bus
     for dev in scan().flat_map(|dev| dev.functions()) {
+        if let PciDeviceHeader::Type0(header) = &dev {
+            reserve_bars(header);
+        }
         match dev.device_vendor() {
             DeviceVendor {
                 dev_id: 0x1001,
@@ -330,13 +349,13 @@ pub unsafe fn init() {
             } => {
                 let dev = virtio::block::VirtIoBlock::from_pci(dev)
                     .expect("Failed to create virtio block device.");
-                for slot in super::BLOCK_DEVS.iter_mut() {
-                    if slot.is_none() {
-                        *slot = Some(dev);
-                        slot.as_ref()
-                            .unwrap()
-                            .init()
-                            .expect("Failed to initialize virtio block device.");
+                let dev: &'static _ = Box::leak(Box::new(dev));
+                dev.init()
+                    .expect("Failed to initialize virtio block device.");
+                for slot in super::BLOCK_DEVS.iter() {
+                    let mut slot = slot.write();
+                    if slot.get().is_none() {
+                        slot.replace(Some(dev));
                         break;
                     }
                 }
@@ -7,8 +7,11 @@
 // mod adaptor;
 mod tys;
 
-use crate::dev::pci::virtio::{PciTransport, VirtIoDevice, VirtIoFeaturesCommon};
+use crate::dev::pci::virtio::{
+    virt_queue::VirtQueue, PciTransport, VirtIoDevice, VirtIoFeaturesCommon,
+};
 use crate::dev::pci::PciDeviceHeader;
+use crate::spin_lock::SpinLockGuard;
 use tys::*;
 
 mmio! {
@@ -31,24 +34,24 @@ mmio! {
         geometry_heads @ 18 => RW, u8;
         geometry_sectors @ 19 => RW, u8;
 
-        blk_size @ 20 => RW, u16;
+        blk_size @ 20 => RW, u32;
 
         // topology
         /// # of blocks per physical block (log2)
-        topology_physical_block_exp @ 22 => RW, u8;
+        topology_physical_block_exp @ 24 => RW, u8;
         /// offset of first aligned logical block
-        topology_alignment_offset @ 23 => RW, u8;
+        topology_alignment_offset @ 25 => RW, u8;
         /// suggested minimum I/O size in blocks
-        topology_min_io_size @ 24 => RW, u16;
+        topology_min_io_size @ 26 => RW, u16;
         /// optimal (suggested maximum) I/O size in blocks
-        topology_opt_io_size @ 26 => RW, u32;
-        writeback @ 28 => RW, u8;
-        max_discard_sectors @ 32 => RW, u32;
-        max_discard_seg @ 36 => RW, u32;
-        discard_sector_alignment @ 40 => RW, u32;
-        max_write_zeros_sectors @ 44 => RW, u32;
-        max_write_zeros_seg @ 48 => RW, u32;
-        write_zeros_may_unmap @ 52 => RW, u8;
+        topology_opt_io_size @ 28 => RW, u32;
+        writeback @ 32 => RW, u8;
+        max_discard_sectors @ 36 => RW, u32;
+        max_discard_seg @ 40 => RW, u32;
+        discard_sector_alignment @ 44 => RW, u32;
+        max_write_zeros_sectors @ 48 => RW, u32;
+        max_write_zeros_seg @ 52 => RW, u32;
+        write_zeros_may_unmap @ 56 => RW, u8;
 }
 
 pub struct VirtIoBlock {
@@ -151,8 +154,23 @@ impl VirtIoBlock {
 
     /// Flush write bio request to the disk.
     pub fn write_bios(&self, bios: &mut dyn Iterator<Item = (usize, &[u8])>) -> Result<(), ()> {
+        self.write_bios_on(self.dev.get_queue(0).unwrap(), bios)
+    }
+
+    /// Flush write bio request to the disk without waiting for the queue.
+    ///
+    /// Fails if the queue is in use, e.g. by the thread that panicked.
+    pub fn try_write_bios(&self, bios: &mut dyn Iterator<Item = (usize, &[u8])>) -> Result<(), ()> {
+        self.write_bios_on(self.dev.try_get_queue(0).ok_or(())?, bios)
+    }
+
+    fn write_bios_on(
+        &self,
+        virtq: SpinLockGuard<VirtQueue>,
+        bios: &mut dyn Iterator<Item = (usize, &[u8])>,
+    ) -> Result<(), ()> {
         let (mut virtq, mut req, mut resp) = (
-            self.dev.get_queue(0).unwrap(),
+            virtq,
             VirtIoBlockReq {
                 type_: VirtIoBlockType::Out,
                 sector: 0,
@@ -98,6 +98,12 @@ impl<V: Send + Sync, const MAX_QUEUE: usize> VirtIoDevice<V, MAX_QUEUE> {
     pub fn get_queue(&self, qid: u16) -> Option<SpinLockGuard<VirtQueue>> {
         self.virtqs.get(qid as usize).map(|n| n.lock())
     }
+
+    /// Get the queue, or `None` if it is in use.
+    #[inline]
+    pub fn try_get_queue(&self, qid: u16) -> Option<SpinLockGuard<VirtQueue>> {
+        self.virtqs.get(qid as usize)?.try_lock().ok()
+    }
 }
 
 pub struct QueueScope<'a, V: Send + Sync, const MAX_QUEUE: usize> {
@@ -20,7 +20,7 @@ use crate::dev::pci::{self, Capability};
 use core::sync::atomic::{AtomicU64, Ordering};
 
 #[repr(u8)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum PciCapabilityType {
     /// Common configuration
     CommonCfg = 1,
@@ -32,55 +32,105 @@ pub enum PciCapabilityType {
     DeviceCfg = 4,
     /// PCI configuration access
     PciCfg = 5,
+    /// Shared memory region
+    SharedMemoryCfg = 8,
+    /// Vendor-specific data
+    VendorCfg = 9,
     Unknown,
 }
 
-struct VirtIoCapability<'a> {
-    cap: Capability<'a, 0>,
+/// Error on parsing the virtio pci capability.
+#[derive(Debug, Eq, PartialEq)]
+pub enum VirtIoCapError {
+    /// The capability is shorter than its structure.
+    TooShort(u8),
+    /// The capability refers to a nonexistent BAR.
+    InvalidBar(u8),
+    /// The notify_off_multiplier is neither 0 nor an even power of 2.
+    InvalidMultiplier(u32),
+}
+
+/// 4.1.4 Virtio Structure PCI Capabilities
+#[derive(Debug, Clone, Copy)]
+pub struct VirtIoPciCap {
+    /// Type of the structure.
+    pub ty: PciCapabilityType,
+    /// BAR that holds the structure.
+    pub bar: u8,
+    /// Offset of the structure within the BAR.
+    pub offset: usize,
+    /// Length of the structure.
+    pub length: usize,
+    /// Multiplier of the queue_notify_off, only for the notification
+    /// structure.
+    pub notify_off_multiplier: usize,
 }
 
-impl<'a> VirtIoCapability<'a> {
-    const VENDOR_VIRTIO: u8 = 9;
+impl VirtIoPciCap {
     const TYPE_OFFSET: u8 = 3;
     const BAR_OFFSET: u8 = 4;
     const OFFSET_OFFSET: u8 = 8;
     const LENGTH_OFFSET: u8 = 12;
+    const MULTIPLIER_OFFSET: u8 = 16;
+    // Length of struct virtio_pci_cap.
+    const CAP_LEN: u8 = 16;
+    // Length of struct virtio_pci_notify_cap.
+    const NOTIFY_CAP_LEN: u8 = 20;
 
-    #[inline]
-    fn try_from(cap: Capability<'a, 0>) -> Option<Self> {
-        if cap.vendor() == Self::VENDOR_VIRTIO {
-            Some(VirtIoCapability { cap })
-        } else {
-            None
-        }
+    /// Parse the virtio pci capability.
+    ///
+    /// Returns `None` if the capability is not vendor specific.
+    pub fn parse(cap: &Capability<'_, 0>) -> Option<Result<Self, VirtIoCapError>> {
+        let cap_len = cap.length()?;
+        Some(Self::do_parse(cap, cap_len))
     }
 
-    #[inline]
-    fn ty(&self) -> PciCapabilityType {
-        match self.cap.offset(Self::TYPE_OFFSET).read_u8() {
+    fn do_parse(cap: &Capability<'_, 0>, cap_len: u8) -> Result<Self, VirtIoCapError> {
+        if cap_len < Self::CAP_LEN {
+            return Err(VirtIoCapError::TooShort(cap_len));
+        }
+        let ty = match cap.offset(Self::TYPE_OFFSET).read_u8() {
             1 => PciCapabilityType::CommonCfg,
             2 => PciCapabilityType::NotifyCfg,
             3 => PciCapabilityType::IsrCfg,
             4 => PciCapabilityType::DeviceCfg,
             5 => PciCapabilityType::PciCfg,
+            8 => PciCapabilityType::SharedMemoryCfg,
+            9 => PciCapabilityType::VendorCfg,
             _ => PciCapabilityType::Unknown,
+        };
+        let bar = cap.offset(Self::BAR_OFFSET).read_u8();
+        // The PciCfg does not refer to a BAR.
+        if bar > 5 && ty != PciCapabilityType::PciCfg {
+            return Err(VirtIoCapError::InvalidBar(bar));
         }
+        let notify_off_multiplier = if ty == PciCapabilityType::NotifyCfg {
+            if cap_len < Self::NOTIFY_CAP_LEN {
+                return Err(VirtIoCapError::TooShort(cap_len));
+            }
+            let mult = cap.offset(Self::MULTIPLIER_OFFSET).read_u32();
+            if mult != 0 && (!mult.is_power_of_two() || mult & 1 == 1) {
+                return Err(VirtIoCapError::InvalidMultiplier(mult));
+            }
+            mult as usize
+        } else {
+            0
+        };
+        Ok(Self {
+            ty,
+            bar,
+            offset: cap.offset(Self::OFFSET_OFFSET).read_u32() as usize,
+            length: cap.offset(Self::LENGTH_OFFSET).read_u32() as usize,
+            notify_off_multiplier,
+        })
     }
+}
 
-    #[inline]
-    fn bar(&self) -> u8 {
-        self.cap.offset(Self::BAR_OFFSET).read_u8()
-    }
-
-    #[inline]
-    fn offset(&self) -> usize {
-        self.cap.offset(Self::OFFSET_OFFSET).read_u32() as usize
-    }
-
-    #[inline]
-    fn length(&self) -> usize {
-        self.cap.offset(Self::LENGTH_OFFSET).read_u32() as usize
-    }
+/// Iterate over the valid virtio pci capabilities of the `pci`.
+pub fn virtio_capabilities(pci: &pci::PciHeader<0>) -> impl Iterator<Item = VirtIoPciCap> + '_ {
+    pci.capabilities()
+        .filter_map(|cap| VirtIoPciCap::parse(&cap))
+        .filter_map(Result::ok)
 }
 
 mmio! {
@@ -153,6 +203,7 @@ mmio! {
 pub struct NotifyCfgTriple {
     memory_space: pci::MemorySpace,
     offset: usize,
+    length: usize,
     mult: usize,
 }
 
@@ -164,72 +215,43 @@ pub fn try_get_configurations(
     let mut virtio_isr_cfg = None;
     let mut virtio_notify_cfg = None;
 
-    for cap in pci.capabilities() {
-        if let Some(cap) = VirtIoCapability::try_from(cap) {
-            let (bar, offset, length) = (cap.bar(), cap.offset(), cap.length());
-            match cap.ty() {
-                PciCapabilityType::CommonCfg => {
-                    virtio_common_cfg = pci
-                        .bar(bar)
-                        .and_then(|bar| bar.try_get_memory_bar())
-                        .and_then(|memory_bar| memory_bar.try_split_mmio_range(offset, length))
-                        .map(VirtIoPciCommonCfg::new_from_mmio_area)
-                }
-                PciCapabilityType::IsrCfg => {
-                    virtio_isr_cfg = pci
-                        .bar(bar)
-                        .and_then(|bar| bar.try_get_memory_bar())
-                        .and_then(|memory_bar| memory_bar.try_split_mmio_range(offset, length))
-                        .map(VirtIoIsrCfg::new_from_mmio_area);
-                }
-                PciCapabilityType::DeviceCfg => {
-                    virtio_device_cfg = pci
-                        .bar(bar)
-                        .and_then(|bar| bar.try_get_memory_bar())
-                        .and_then(|memory_bar| memory_bar.try_split_mmio_range(offset, length))
-                }
-                PciCapabilityType::NotifyCfg => {
-                    virtio_notify_cfg = Some(cap);
-                }
-                _ => (),
+    let area = |cap: &VirtIoPciCap| {
+        pci.bar(cap.bar)
+            .and_then(|bar| bar.try_get_memory_bar())
+            .and_then(|memory_bar| memory_bar.try_split_mmio_range(cap.offset, cap.length))
+    };
+    // The device may expose a structure several times, in the order of the
+    // preference. Use the first one that is usable.
+    for cap in virtio_capabilities(&pci) {
+        match cap.ty {
+            PciCapabilityType::CommonCfg if virtio_common_cfg.is_none() => {
+                virtio_common_cfg = area(&cap).map(VirtIoPciCommonCfg::new_from_mmio_area);
             }
+            PciCapabilityType::IsrCfg if virtio_isr_cfg.is_none() => {
+                virtio_isr_cfg = area(&cap).map(VirtIoIsrCfg::new_from_mmio_area);
+            }
+            PciCapabilityType::DeviceCfg if virtio_device_cfg.is_none() => {
+                virtio_device_cfg = area(&cap);
+            }
+            PciCapabilityType::NotifyCfg if virtio_notify_cfg.is_none() => {
+                virtio_notify_cfg = area(&cap).and_then(|_| {
+                    Some(NotifyCfgTriple {
+                        memory_space: pci.bar(cap.bar)?.try_get_memory_bar()?,
+                        offset: cap.offset,
+                        length: cap.length,
+                        mult: cap.notify_off_multiplier,
+                    })
+                });
+            }
+            _ => (),
         }
     }
-    match (
-        virtio_common_cfg,
-        virtio_device_cfg,
-        virtio_isr_cfg,
-        virtio_notify_cfg,
-    ) {
-        (
-            Some(virtio_common_cfg),
-            Some(virtio_device_cfg),
-            Some(virtio_isr_cfg),
-            Some(virtio_notify_cfg),
-        ) => {
-            // calculate notify_cfg.
-            let (bar, offset, notify_off_multiplier) = (
-                virtio_notify_cfg.bar(),
-                virtio_notify_cfg.offset(),
-                virtio_notify_cfg.cap.offset(16).read_u32() as usize,
-            );
-            pci.bar(bar)
-                .and_then(|bar| bar.try_get_memory_bar())
-                .map(|memory_bar| {
-                    (
-                        virtio_common_cfg,
-                        virtio_device_cfg,
-                        virtio_isr_cfg,
-                        NotifyCfgTriple {
-                            memory_space: memory_bar,
-                            offset,
-                            mult: notify_off_multiplier,
-                        },
-                    )
-                })
-        }
-        _ => None,
-    }
+    Some((
+        virtio_common_cfg?,
+        virtio_device_cfg?,
+        virtio_isr_cfg?,
+        virtio_notify_cfg?,
+    ))
 }
 
 pub struct PciTransport<V: Send + Sync> {
@@ -291,13 +313,18 @@ impl<V: Send + Sync> PciTransport<V> {
         let NotifyCfgTriple {
             memory_space,
             offset,
+            length,
             mult,
         } = &self.notify;
-        let queue_notify_off = self.common.queue_notify_off().read() as usize;
+        let notify_off = self.common.queue_notify_off().read() as usize * mult;
+        assert!(
+            notify_off + 2 <= *length,
+            "Queue notify address is out of the notification structure."
+        );
         Kick::Pci(
             VirtIoNotifyCfg::new_from_mmio_area(
                 memory_space
-                    .try_split_mmio_range(offset + queue_notify_off * mult, 2)
+                    .try_split_mmio_range(offset + notify_off, 2)
                     .unwrap(),
             )
             .v(),
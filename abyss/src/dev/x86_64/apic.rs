@@ -1,9 +1,23 @@
 //! Advanced Programmable Interrupt Controller (APIC) driver.
 //!
-//! This implements X2Apic mode.
-use crate::dev::DeviceError;
-use crate::x86_64::{msr::Msr, pio::Pio};
+//! The local APIC is driven in one of the two modes, selected at boot by the
+//! cpuid:
+//! - x2APIC: the registers are accessed through the MSRs. Preferred if the
+//!   cpu supports it.
+//! - xAPIC: the legacy mode, of which registers are memory-mapped.
+//!
+//! The rest of the kernel does not care about the mode; it uses [`eoi`],
+//! [`send_ipi`] and the timer APIs, which dispatch to the selected backend.
+use crate::addressing::Pa;
+use crate::dev::{mmio::map_mmio, DeviceError};
+use crate::interrupt::InterruptGuard;
+use crate::x86_64::{
+    msr::{rdmsr, wrmsr, Msr},
+    pat::CacheAttr,
+    pio::Pio,
+};
 use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 enum MapDest {
     Master(u8),
@@ -93,42 +107,220 @@ impl _8259A {
     }
 }
 
+/// Operating mode of the local APIC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Memory-mapped legacy mode.
+    XApic,
+    /// MSR based mode.
+    X2Apic,
+}
+
+// Registers of the local APIC, by the offset in the xAPIC MMIO page.
+const REG_ID: u32 = 0x20;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SVR: u32 = 0xf0;
+const REG_ICR_LO: u32 = 0x300;
+const REG_ICR_HI: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_PERF: u32 = 0x340;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+
+// IA32_APIC_BASE.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+// ICR delivery status.
+const ICR_PENDING: u32 = 1 << 12;
+
+// Register access of a mode.
+trait Backend: Sync {
+    fn read(&self, reg: u32) -> u32;
+    unsafe fn write(&self, reg: u32, v: u32);
+    unsafe fn send_ipi(&self, dest: u32, icr: u32);
+}
+
+struct XApic;
+
+impl XApic {
+    fn reg(reg: u32) -> *mut u32 {
+        (XAPIC_REGS.load(Ordering::Relaxed) + reg as usize) as *mut u32
+    }
+}
+
+impl Backend for XApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe { core::ptr::read_volatile(Self::reg(reg)) }
+    }
+
+    unsafe fn write(&self, reg: u32, v: u32) {
+        core::ptr::write_volatile(Self::reg(reg), v)
+    }
+
+    unsafe fn send_ipi(&self, dest: u32, icr: u32) {
+        // The ICR is written in two halves; do not be interleaved with the
+        // IPIs sent from the interrupt handlers.
+        let _guard = InterruptGuard::new();
+        while self.read(REG_ICR_LO) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        self.write(REG_ICR_HI, dest << 24);
+        self.write(REG_ICR_LO, icr);
+    }
+}
+
+struct X2Apic;
+
+impl Backend for X2Apic {
+    fn read(&self, reg: u32) -> u32 {
+        rdmsr(0x800 + (reg >> 4)) as u32
+    }
+
+    unsafe fn write(&self, reg: u32, v: u32) {
+        wrmsr(0x800 + (reg >> 4), v as u64)
+    }
+
+    unsafe fn send_ipi(&self, dest: u32, icr: u32) {
+        // The ICR is a single 64-bit register in x2APIC mode.
+        wrmsr(
+            0x800 + (REG_ICR_LO >> 4),
+            ((dest as u64) << 32) | icr as u64,
+        )
+    }
+}
+
+static X2APIC: AtomicBool = AtomicBool::new(false);
+// Virtual address of the uncached mapping of the xAPIC registers.
+static XAPIC_REGS: AtomicUsize = AtomicUsize::new(0);
+
+fn backend() -> &'static dyn Backend {
+    if X2APIC.load(Ordering::Relaxed) {
+        &X2Apic
+    } else {
+        &XApic
+    }
+}
+
+/// Get the mode of the local APIC.
+pub fn mode() -> ApicMode {
+    if X2APIC.load(Ordering::Relaxed) {
+        ApicMode::X2Apic
+    } else {
+        ApicMode::XApic
+    }
+}
+
+/// Get the id of the local APIC of the current cpu.
+pub fn id() -> u32 {
+    match mode() {
+        ApicMode::X2Apic => backend().read(REG_ID),
+        ApicMode::XApic => backend().read(REG_ID) >> 24,
+    }
+}
+
+/// Initialize the local APIC of the cpu.
+///
+/// The bootstrap processor selects the mode: x2APIC if the cpu supports it,
+/// xAPIC otherwise. The application processors follow the mode.
 pub unsafe fn init(core_id: usize) -> Result<(), DeviceError> {
-    if core::arch::x86_64::__cpuid(1).ecx & (1 << 21) != 0 {
-        // Enable the x2 apic
-        let apic_base = Msr::<0x1b>::read();
-        Msr::<0x1b>::write(apic_base | (1 << 10));
-        // Enable local apic and set susprious irq vector.
-        // IRQ_SUSPRIOUS = 0xff;
-        // SIV
-        Msr::<0x80f>::write(0x100 | 0xff);
-        // TP
-        Msr::<0x808>::write((Msr::<0x808>::read() & 0xff) | 0x10);
-        // lint1 = MASK | NMI
-        Msr::<0x836>::write(0x10000 | 0x400);
-        if core_id == 0 {
-            // lint0
-            Msr::<0x835>::write(0x700);
-            _8259A::init();
-        } else {
-            // lint0
-            // MASK | ExtInt
-            Msr::<0x835>::write(0x10000 | 0x700);
+    if core_id == 0 {
+        X2APIC.store(
+            core::arch::x86_64::__cpuid(1).ecx & (1 << 21) != 0,
+            Ordering::Relaxed,
+        );
+    }
+    let apic_base = Msr::<0x1b>::read();
+    match mode() {
+        ApicMode::X2Apic => {
+            Msr::<0x1b>::write(apic_base | APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE)
+        }
+        ApicMode::XApic => {
+            if core::arch::x86_64::__cpuid(1).edx & (1 << 9) == 0 {
+                return Err(DeviceError("Apic is not supported."));
+            }
+            Msr::<0x1b>::write(apic_base | APIC_BASE_ENABLE);
+            if core_id == 0 {
+                let regs = map_mmio(
+                    Pa::new((apic_base & 0xf_ffff_f000) as usize).unwrap(),
+                    0x1000,
+                    CacheAttr::Uncached,
+                )
+                .ok_or(DeviceError("Failed to map the apic registers."))?
+                .leak();
+                XAPIC_REGS.store(regs.start.into_usize(), Ordering::Relaxed);
+            }
         }
-        Ok(())
+    }
+    let apic = backend();
+    // Enable local apic and set susprious irq vector.
+    // IRQ_SUSPRIOUS = 0xff;
+    apic.write(REG_SVR, 0x100 | 0xff);
+    apic.write(REG_TPR, (apic.read(REG_TPR) & 0xff) | 0x10);
+    // lint1 = MASK | NMI
+    apic.write(REG_LVT_LINT1, 0x10000 | 0x400);
+    if core_id == 0 {
+        // lint0
+        apic.write(REG_LVT_LINT0, 0x700);
+        _8259A::init();
     } else {
-        Err(DeviceError("X2Apic is not supported."))
+        // lint0
+        // MASK | ExtInt
+        apic.write(REG_LVT_LINT0, 0x10000 | 0x700);
     }
+    Ok(())
 }
 
+/// Signal the end of the interrupt.
 pub fn eoi() {
     unsafe {
-        Msr::<0x80b>::write(0);
+        backend().write(REG_EOI, 0);
     }
 }
 
+/// Send the inter-processor interrupt `ipi` to the cpu `cpuid`.
+///
+/// `ipi` is the low half of the interrupt command register, such as the
+/// vector of a fixed interrupt.
 pub unsafe fn send_ipi(cpuid: usize, ipi: u32) {
     unsafe {
-        Msr::<0x830>::write(((cpuid as u64) << 32) | 0x4000 | (ipi as u64));
+        backend().send_ipi(cpuid as u32, 0x4000 | ipi);
     }
 }
+
+/// Set the local timer to fire the interrupt `vector` on the tsc deadline.
+///
+/// The deadline is programmed with [`set_tsc_deadline`].
+///
+/// # Safety
+/// The local APIC must be initialized.
+pub unsafe fn enable_tsc_deadline_timer(vector: u8) {
+    backend().write(REG_LVT_TIMER, (0b10 << 17) | vector as u32);
+    // In xAPIC mode, the write to the LVT must be ordered before the write to
+    // the deadline msr.
+    core::sync::atomic::fence(Ordering::SeqCst);
+}
+
+/// Set the performance counters to fire the interrupt `vector` on their
+/// overflows.
+///
+/// The processor masks the interrupt when it is delivered, so it is set again
+/// before the counters are restarted.
+///
+/// # Safety
+/// The local APIC must be initialized.
+pub unsafe fn set_pmi_vector(vector: u8) {
+    backend().write(REG_LVT_PERF, vector as u32);
+}
+
+/// Program the tsc deadline of the local timer.
+///
+/// The timer fires when the tsc reaches `tsc`, and is disarmed on 0.
+///
+/// # Safety
+/// The timer must be enabled by [`enable_tsc_deadline_timer`].
+pub unsafe fn set_tsc_deadline(tsc: u64) {
+    // IA32_TSC_DEADLINE is an msr in both modes.
+    Msr::<0x6e0>::write(tsc);
+    core::sync::atomic::fence(Ordering::SeqCst);
+}
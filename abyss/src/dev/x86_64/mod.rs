@@ -1,5 +1,6 @@
 //! x86_64 specific devices.
 
 pub mod apic;
+pub mod rtc;
 pub mod serial;
 pub mod timer;
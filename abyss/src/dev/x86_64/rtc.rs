@@ -0,0 +1,215 @@
+//! CMOS real-time clock.
+//!
+//! The real-time clock keeps the wall-clock time across the power cycles. As
+//! reading the clock through the CMOS ports is slow, it is read once, and the
+//! time afterwards is derived from the time-stamp counter.
+use crate::x86_64::pio::Pio;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Register indices of the real-time clock.
+pub mod reg {
+    /// Seconds.
+    pub const SECONDS: u8 = 0x00;
+    /// Minutes.
+    pub const MINUTES: u8 = 0x02;
+    /// Hours.
+    pub const HOURS: u8 = 0x04;
+    /// Day of the week.
+    pub const WEEKDAY: u8 = 0x06;
+    /// Day of the month.
+    pub const DAY: u8 = 0x07;
+    /// Month.
+    pub const MONTH: u8 = 0x08;
+    /// Year.
+    pub const YEAR: u8 = 0x09;
+    /// Status register A.
+    pub const STATUS_A: u8 = 0x0a;
+    /// Status register B.
+    pub const STATUS_B: u8 = 0x0b;
+    /// Status register C.
+    pub const STATUS_C: u8 = 0x0c;
+    /// Status register D.
+    pub const STATUS_D: u8 = 0x0d;
+    /// Century.
+    pub const CENTURY: u8 = 0x32;
+}
+
+// Update in progress (status register A).
+const UIP: u8 = 1 << 7;
+// 24-hour mode (status register B).
+const HOUR_24: u8 = 1 << 1;
+// Binary mode (status register B).
+const BINARY: u8 = 1 << 2;
+// PM bit of the hours in 12-hour mode.
+const PM: u8 = 1 << 7;
+
+/// A calendar date and time in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    /// Year (e.g. 2023).
+    pub year: u16,
+    /// Month (1-12).
+    pub month: u8,
+    /// Day of the month (1-31).
+    pub day: u8,
+    /// Hour (0-23).
+    pub hour: u8,
+    /// Minute (0-59).
+    pub minute: u8,
+    /// Second (0-59).
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert the seconds since the unix epoch into the date and time.
+    pub fn from_unix(secs: u64) -> Self {
+        // Civil from days, by Howard Hinnant.
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u16;
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Convert into the seconds since the unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        // Days from civil, by Howard Hinnant.
+        let y = self.year as i64 - (self.month <= 2) as i64;
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Day of the week (1: Sunday, ..., 7: Saturday).
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 is Thursday.
+        ((self.to_unix() / 86400 + 4) % 7 + 1) as u8
+    }
+}
+
+#[inline]
+fn read_reg(index: u8) -> u8 {
+    Pio::new(CMOS_INDEX).write_u8(index);
+    Pio::new(CMOS_DATA).read_u8()
+}
+
+#[inline]
+fn from_bcd(v: u8) -> u8 {
+    (v & 0xf) + (v >> 4) * 10
+}
+
+fn read_raw() -> [u8; 7] {
+    while read_reg(reg::STATUS_A) & UIP != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_reg(reg::SECONDS),
+        read_reg(reg::MINUTES),
+        read_reg(reg::HOURS),
+        read_reg(reg::DAY),
+        read_reg(reg::MONTH),
+        read_reg(reg::YEAR),
+        read_reg(reg::CENTURY),
+    ]
+}
+
+/// Read the date and time from the real-time clock.
+pub fn read() -> DateTime {
+    // Read until two consecutive reads agree, not to see an update in the
+    // middle of the read.
+    let mut raw = read_raw();
+    loop {
+        let next = read_raw();
+        if next == raw {
+            break;
+        }
+        raw = next;
+    }
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+    let status_b = read_reg(reg::STATUS_B);
+    let pm = hour & PM != 0;
+    hour &= !PM;
+    if status_b & BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+    if status_b & HOUR_24 == 0 {
+        hour = (hour % 12) + if pm { 12 } else { 0 };
+    }
+    // The century register is not always present.
+    let century = if (19..=99).contains(&century) {
+        century as u16
+    } else {
+        20
+    };
+    DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+// Unix time at the tsc of BASE_TSC.
+static BASE_SECS: AtomicU64 = AtomicU64::new(0);
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Get the current time in nanoseconds since the unix epoch.
+pub fn unix_time_ns() -> u64 {
+    let khz = super::timer::tsc_khz();
+    if khz == 0 {
+        return read().to_unix() * 1_000_000_000;
+    }
+    if BASE_TSC.load(Ordering::Acquire) == 0 {
+        let secs = read().to_unix();
+        if BASE_SECS
+            .compare_exchange(0, secs, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            BASE_TSC.store(unsafe { _rdtsc() }, Ordering::Release);
+        }
+    }
+    let base_tsc = loop {
+        match BASE_TSC.load(Ordering::Acquire) {
+            0 => core::hint::spin_loop(),
+            tsc => break tsc,
+        }
+    };
+    let elapsed = unsafe { _rdtsc() }.saturating_sub(base_tsc);
+    BASE_SECS.load(Ordering::Acquire) * 1_000_000_000
+        + (elapsed as u128 * 1_000_000 / khz as u128) as u64
+}
+
+/// Get the current time in seconds since the unix epoch.
+pub fn unix_time() -> u64 {
+    unix_time_ns() / 1_000_000_000
+}
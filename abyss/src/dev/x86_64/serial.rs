@@ -14,19 +14,28 @@ pub unsafe fn init() {
     Pio::new(0x3f8).read_u8();
 }
 
+// Delay about 1us.
+fn delay() {
+    Pio::new(0x84).read_u8();
+}
+
+fn write_byte(b: u8) {
+    for _ in 0..12800 {
+        if Pio::new(0x3f8 + 5).read_u8() & 0x20 != 0 {
+            break;
+        }
+        // delay
+        delay();
+        delay();
+        delay();
+        delay();
+    }
+    Pio::new(0x3f8).write_u8(b);
+}
+
 pub(crate) fn write_str(s: &str) {
     for b in s.as_bytes() {
-        for _ in 0..12800 {
-            if Pio::new(0x3f8 + 5).read_u8() & 0x20 != 0 {
-                break;
-            }
-            // delay
-            Pio::new(0x84).read_u8();
-            Pio::new(0x84).read_u8();
-            Pio::new(0x84).read_u8();
-            Pio::new(0x84).read_u8();
-        }
-        Pio::new(0x3f8).write_u8(*b);
+        write_byte(*b);
     }
 }
 
@@ -41,6 +50,24 @@ impl Serial {
     }
 }
 
+impl Serial {
+    /// Write a raw byte.
+    pub fn write_byte(&mut self, b: u8) {
+        write_byte(b)
+    }
+
+    /// Read a raw byte, waiting at most about `timeout_us` microseconds.
+    pub fn read_byte(&mut self, timeout_us: usize) -> Option<u8> {
+        for _ in 0..=timeout_us {
+            if Pio::new(0x3f8 + 5).read_u8() & 0x1 != 0 {
+                return Some(Pio::new(0x3f8).read_u8());
+            }
+            delay();
+        }
+        None
+    }
+}
+
 impl core::fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         write_str(s);
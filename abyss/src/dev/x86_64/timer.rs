@@ -1,8 +1,11 @@
 //! APIC based timer.
 use crate::addressing::Va;
 use crate::dev::DeviceError;
-use crate::x86_64::{msr::Msr, pio::Pio};
+use crate::interrupt::InterruptGuard;
+use crate::x86_64::{intrinsics::cpuid, msr::Msr, pio::Pio};
+use crate::MAX_CPU;
 use core::arch::x86_64::{CpuidResult, __cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Find cpu frequency
 unsafe fn find_cpu_frequncy() -> Option<u64> {
@@ -150,6 +153,13 @@ unsafe fn find_cpu_frequncy() -> Option<u64> {
 
 static mut CPU_FREQ: u64 = 0;
 
+/// Get the frequency of the tsc in kHz.
+///
+/// Returns 0 if the timer is not initialized.
+pub fn tsc_khz() -> u64 {
+    unsafe { CPU_FREQ }
+}
+
 /// Initialize the timer system.
 pub unsafe fn init(core_id: usize) -> Result<(), DeviceError> {
     if core::arch::x86_64::__cpuid(1).ecx & (1 << 24) != 0 {
@@ -158,7 +168,7 @@ pub unsafe fn init(core_id: usize) -> Result<(), DeviceError> {
         }
         // Timer
         // Irq #32.
-        Msr::<0x832>::write((0b10 << 17) | 32);
+        super::apic::enable_tsc_deadline_timer(32);
         set_tsc_timer();
         Ok(())
     } else {
@@ -166,11 +176,37 @@ pub unsafe fn init(core_id: usize) -> Result<(), DeviceError> {
     }
 }
 
+// Whether the periodic tick of each cpu is stopped.
+#[allow(clippy::declare_interior_mutable_const)]
+const RUNNING: AtomicBool = AtomicBool::new(false);
+static TICK_STOPPED: [AtomicBool; MAX_CPU] = [RUNNING; MAX_CPU];
+
 /// Program the deadline timer.
+///
+/// This (re)starts the periodic tick of the cpu.
 pub unsafe fn set_tsc_timer() {
+    TICK_STOPPED[cpuid()].store(false, Ordering::Relaxed);
     // TscDeadline
     // 1ms resolution.
-    let next = _rdtsc() + CPU_FREQ;
-    Msr::<0x6e0>::write(next);
-    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    super::apic::set_tsc_deadline(_rdtsc() + CPU_FREQ);
+}
+
+/// Stop the periodic tick of the cpu, and fire the timer once at the tsc
+/// `deadline` instead. The timer does not fire if `deadline` is `None`.
+///
+/// The next timer interrupt restarts the periodic tick.
+///
+/// # Safety
+/// The timer must be initialized.
+pub unsafe fn stop_tick(deadline: Option<u64>) {
+    let _guard = InterruptGuard::new();
+    TICK_STOPPED[cpuid()].store(true, Ordering::Relaxed);
+    // The deadline of 0 disarms the timer; fire it right away if the
+    // deadline already passed.
+    super::apic::set_tsc_deadline(deadline.map_or(0, |deadline| deadline.max(1)));
+}
+
+/// Check whether the periodic tick of the cpu is stopped.
+pub fn tick_stopped() -> bool {
+    TICK_STOPPED[cpuid()].load(Ordering::Relaxed)
 }
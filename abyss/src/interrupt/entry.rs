@@ -6,15 +6,26 @@ use core::arch::global_asm;
 
 global_asm!(include_str!("entry.s"));
 
+extern "Rust" {
+    fn do_handle_syscall(frame: &mut TrapFrame);
+    fn do_handle_user_fault(frame: &mut TrapFrame, vector: u8, error_code: u64);
+}
+
 // Load interrupt descriptor table.
 #[no_mangle]
 #[allow(clippy::empty_loop)]
-extern "C" fn handle_general_protection_fault(frame: &mut TrapFrame, _c: SegmentSelector) {
+extern "C" fn handle_general_protection_fault(frame: &mut TrapFrame, c: SegmentSelector) {
+    if frame.is_user() {
+        return unsafe { do_handle_user_fault(frame, 13, c.pack() as u64) };
+    }
     panic!("General Protection Fault! {:#?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn handle_page_fault(_frame: &mut TrapFrame, _ec: PFErrorCode) {
+extern "C" fn handle_page_fault(frame: &mut TrapFrame, ec: PFErrorCode) {
+    if frame.is_user() {
+        return unsafe { do_handle_user_fault(frame, 14, ec.bits()) };
+    }
     todo!("PF");
 }
 
@@ -28,19 +39,35 @@ extern "C" fn handle_double_fault(
 
 #[no_mangle]
 extern "C" fn handle_invalid_opcode(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return unsafe { do_handle_user_fault(frame, 6, 0) };
+    }
     panic!("Invalid Opcode!\n{:#?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn handle_simd_floating_point_exception(_frame: &mut TrapFrame) {
+extern "C" fn handle_simd_floating_point_exception(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        return unsafe { do_handle_user_fault(frame, 19, 0) };
+    }
     panic!("Floating Point Exception!");
 }
 
 #[no_mangle]
-extern "C" fn handle_device_not_available(_frame: &mut TrapFrame) {
+extern "C" fn handle_device_not_available(frame: &mut TrapFrame) {
+    // The fpu state is not saved across the threads, so the user can not use
+    // it either.
+    if frame.is_user() {
+        return unsafe { do_handle_user_fault(frame, 7, 0) };
+    }
     panic!("Device Not Available");
 }
 
+#[no_mangle]
+extern "C" fn handle_syscall(frame: &mut TrapFrame) {
+    unsafe { do_handle_syscall(frame) }
+}
+
 #[no_mangle]
 #[allow(clippy::empty_loop)]
 extern "C" fn do_handle_irq(_frame: &mut TrapFrame, vec: usize) {
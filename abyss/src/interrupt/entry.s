@@ -262,6 +262,7 @@ mk_intr_with_ec general_protection_fault handle_general_protection_fault
 mk_intr_no_ec device_not_available handle_device_not_available
 mk_intr_no_ec invalid_opcode handle_invalid_opcode
 mk_intr_no_ec simd_floating_point_exception handle_simd_floating_point_exception
+mk_intr_no_ec syscall_entry handle_syscall
 
 mk_isr do_isr_32 32
 mk_isr do_isr_33 33
@@ -359,7 +360,6 @@ mk_isr do_isr_124 124
 mk_isr do_isr_125 125
 mk_isr do_isr_126 126
 mk_isr do_isr_127 127
-mk_isr do_isr_128 128
 mk_isr do_isr_129 129
 mk_isr do_isr_130 130
 mk_isr do_isr_131 131
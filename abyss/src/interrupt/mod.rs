@@ -10,6 +10,11 @@ mod entry;
 
 pub use entry::irq_handler;
 
+/// Interrupt vector of the system call.
+///
+/// The gate is the only one that the user mode can raise with `int`.
+pub const SYSCALL_VECTOR: usize = 0x80;
+
 /// Enumeration for representing interrupt state
 #[derive(PartialEq, Eq, Debug)]
 pub enum InterruptState {
@@ -172,6 +177,38 @@ impl TrapFrame {
         }
     }
 
+    /// Get the general purpose registers.
+    #[inline]
+    pub fn gprs(&self) -> &GeneralPurposeRegisters {
+        &self.gprs
+    }
+
+    /// Get the mutable general purpose registers.
+    #[inline]
+    pub fn gprs_mut(&mut self) -> &mut GeneralPurposeRegisters {
+        &mut self.gprs
+    }
+
+    /// Get the interrupted rip.
+    #[inline]
+    pub fn rip(&self) -> usize {
+        self.interrupt_stack_frame.rip
+    }
+
+    /// Check whether the frame is interrupted from the user mode.
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        self.interrupt_stack_frame.cs.dpl() == PrivilegeLevel::Ring3
+    }
+
+    #[inline]
+    pub fn set_rip(&mut self, rip: usize) {
+        self.interrupt_stack_frame.rip = rip;
+    }
+    #[inline]
+    pub fn set_rsp(&mut self, rsp: usize) {
+        self.interrupt_stack_frame.rsp = rsp;
+    }
     #[inline]
     pub fn set_cs(&mut self, cs: u16) {
         self.interrupt_stack_frame.cs = SegmentSelector::new(cs >> 3, PrivilegeLevel::Ring3);
@@ -3,8 +3,36 @@
 use crate::dev::x86_64::serial::Serial;
 use crate::spin_lock::SpinLock;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 static SERIAL: SpinLock<Serial> = SpinLock::new(Serial::new());
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// Level of the log messages.
+///
+/// The messages below the level set with [`set_log_level`] are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// [`debug!`], only printed on the debug build.
+    Debug = 0,
+    /// [`info!`].
+    Info = 1,
+    /// [`warning!`].
+    Warning = 2,
+    /// No log message.
+    Off = 3,
+}
+
+/// Set the lowest level of the log messages to print.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Check whether the log messages of the `level` are printed.
+#[inline]
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 >= LOG_LEVEL.load(Ordering::Relaxed)
+}
 
 #[doc(hidden)]
 #[no_mangle]
@@ -12,6 +40,13 @@ pub fn _print(fmt: core::fmt::Arguments<'_>) {
     let _ = write!(&mut *SERIAL.lock(), "{}", fmt);
 }
 
+/// Run `f` with the exclusive access to the serial.
+///
+/// Messages printed by the other cores are held until `f` returns.
+pub fn with_serial<R>(f: impl FnOnce(&mut Serial) -> R) -> R {
+    f(&mut SERIAL.lock())
+}
+
 /// Prints out the message.
 ///
 /// Use the format! syntax to write data to the standard output.
@@ -37,13 +72,16 @@ macro_rules! println {
 /// This first holds the lock for console device.
 #[macro_export]
 macro_rules! info {
-    ($($arg:tt)*) => ($crate::kprint::_print(
-            format_args!(
-                "[INFO] {}\n",
-                format_args!($($arg)*)
+    ($($arg:tt)*) => {
+        if $crate::kprint::log_enabled($crate::kprint::LogLevel::Info) {
+            $crate::kprint::_print(
+                format_args!(
+                    "[INFO] {}\n",
+                    format_args!($($arg)*)
+                )
             )
-        )
-    );
+        }
+    };
 }
 
 /// Display a warning message.
@@ -52,20 +90,23 @@ macro_rules! info {
 /// This first holds the lock for console device.
 #[macro_export]
 macro_rules! warning {
-    ($($arg:tt)*) => ($crate::kprint::_print(
-            format_args!(
-                "[WARNING] {}\n",
-                format_args!($($arg)*)
+    ($($arg:tt)*) => {
+        if $crate::kprint::log_enabled($crate::kprint::LogLevel::Warning) {
+            $crate::kprint::_print(
+                format_args!(
+                    "[WARNING] {}\n",
+                    format_args!($($arg)*)
+                )
             )
-        )
-    );
+        }
+    };
 }
 
 /// Print msg if debug build
 #[macro_export]
 macro_rules! debug {
-    ($($e:tt)*) => {
-        if cfg!(debug_assertions) {
+    ($($arg:tt)*) => {
+        if cfg!(debug_assertions) && $crate::kprint::log_enabled($crate::kprint::LogLevel::Debug) {
             $crate::kprint::_print(
                 format_args!(
                     "[DEBUG] {}\n",
@@ -23,8 +23,29 @@ pub mod boot;
 #[macro_use]
 pub mod dev;
 pub mod interrupt;
+pub mod rcu;
 pub mod spin_lock;
 pub mod x86_64;
 
+use core::sync::atomic::{AtomicU64, Ordering};
+
 /// Maximum number of CPU this kernel can support.
-pub const MAX_CPU: usize = 4;
+///
+/// The actual number of cpus is discovered at boot. See [`ncpu`].
+pub const MAX_CPU: usize = 16;
+const _: () = assert!(MAX_CPU <= 64, "CPU_MASK can hold up to 64 cpus.");
+
+/// Bitmap of the cpus discovered at boot, indexed by the APIC id.
+pub(crate) static CPU_MASK: AtomicU64 = AtomicU64::new(1);
+
+/// Get the number of cpus discovered at boot.
+#[inline]
+pub fn ncpu() -> usize {
+    CPU_MASK.load(Ordering::Relaxed).count_ones() as usize
+}
+
+/// Iterate over the APIC ids of the cpus discovered at boot.
+pub fn cpus() -> impl Iterator<Item = usize> {
+    let mask = CPU_MASK.load(Ordering::Relaxed);
+    (0..MAX_CPU).filter(move |cpu| mask & (1 << cpu) != 0)
+}
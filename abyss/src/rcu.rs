@@ -0,0 +1,223 @@
+//! Read-copy-update for the read-mostly data.
+//!
+//! The registries, such as the block devices or the interrupt handlers, are
+//! read on every access but rarely updated. An [`Rcu`] lets the readers access
+//! the data without any lock; the writer publishes a new version of the data,
+//! and frees the old version after every reader that might see it has left:
+//!
+//! ```ignore
+//! static DEVS: Rcu<Vec<Dev>> = Rcu::empty();
+//!
+//! // Reader.
+//! if let Some(devs) = DEVS.read() {
+//!     devs.iter().for_each(Dev::poll);
+//! }
+//! // Writer.
+//! DEVS.update(|devs| {
+//!     let mut devs = devs.cloned().unwrap_or_default();
+//!     devs.push(dev);
+//!     Some(devs)
+//! });
+//! ```
+//!
+//! A read-side critical section disables the interrupts of the cpu, so it must
+//! be short, and must not block or switch the thread.
+
+use crate::{interrupt::InterruptGuard, spin_lock::SpinLock, x86_64::intrinsics::cpuid, MAX_CPU};
+use alloc::boxed::Box;
+use core::{
+    marker::PhantomData,
+    ops::Deref,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+// The read-side critical sections of a cpu.
+#[repr(align(64))]
+struct Reader {
+    // Depth of the nested read-side critical sections.
+    nest: AtomicUsize,
+    // Number of the read-side critical sections that have ended.
+    seq: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const READER: Reader = Reader {
+    nest: AtomicUsize::new(0),
+    seq: AtomicUsize::new(0),
+};
+static READERS: [Reader; MAX_CPU] = [READER; MAX_CPU];
+
+// A read-side critical section on this cpu.
+struct ReadSection {
+    reader: &'static Reader,
+    _guard: InterruptGuard,
+}
+
+impl ReadSection {
+    fn enter() -> Self {
+        // Not to be migrated to the other cpu in the section.
+        let _guard = InterruptGuard::new();
+        let reader = &READERS[cpuid()];
+        reader.nest.fetch_add(1, Ordering::SeqCst);
+        Self { reader, _guard }
+    }
+}
+
+impl Drop for ReadSection {
+    fn drop(&mut self) {
+        if self.reader.nest.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.reader.seq.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Wait until every read-side critical section that is running now ends.
+///
+/// The data unpublished before the call is not referenced by any reader after
+/// the call. It must not be called in a read-side critical section.
+pub fn synchronize() {
+    {
+        let _guard = InterruptGuard::new();
+        assert_eq!(
+            READERS[cpuid()].nest.load(Ordering::SeqCst),
+            0,
+            "synchronize() in a read-side critical section."
+        );
+    }
+    for reader in READERS.iter() {
+        let seq = reader.seq.load(Ordering::SeqCst);
+        while reader.nest.load(Ordering::SeqCst) != 0 && reader.seq.load(Ordering::SeqCst) == seq {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// A read-mostly data, updated by read-copy-update.
+///
+/// The readers access the data through [`read`] without any lock. The writers
+/// are serialized, and replace the data with [`replace`] or [`update`].
+///
+/// [`read`]: Self::read
+/// [`replace`]: Self::replace
+/// [`update`]: Self::update
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+    writer: SpinLock<()>,
+    _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Default for Rcu<T> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<T> Rcu<T> {
+    /// Create a new empty `Rcu`.
+    pub const fn empty() -> Self {
+        Self {
+            ptr: AtomicPtr::new(null_mut()),
+            writer: SpinLock::new(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a new `Rcu` of the `value`.
+    pub fn new(value: T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: SpinLock::new(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Read the current version of the data.
+    ///
+    /// Returns `None` if the `Rcu` is empty. The version is not freed until
+    /// the returned guard is dropped.
+    pub fn read(&self) -> Option<RcuReadGuard<'_, T>> {
+        let section = ReadSection::enter();
+        let value = unsafe { self.ptr.load(Ordering::SeqCst).as_ref()? };
+        Some(RcuReadGuard {
+            value,
+            _section: section,
+        })
+    }
+
+    /// Lock the `Rcu` for the update.
+    ///
+    /// The readers are not blocked while the guard is held.
+    pub fn write(&self) -> RcuWriteGuard<'_, T> {
+        RcuWriteGuard {
+            rcu: self,
+            _guard: self.writer.lock(),
+        }
+    }
+
+    /// Replace the data with the `value`, and return the old version once no
+    /// reader references it.
+    pub fn replace(&self, value: Option<T>) -> Option<T> {
+        self.write().replace(value)
+    }
+
+    /// Replace the data with the new version that `f` makes from the current
+    /// version.
+    pub fn update(&self, f: impl FnOnce(Option<&T>) -> Option<T>) {
+        let mut guard = self.write();
+        let value = f(guard.get());
+        guard.replace(value);
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+/// An RAII guard of a read-side critical section, returned by [`Rcu::read`].
+pub struct RcuReadGuard<'a, T> {
+    value: &'a T,
+    _section: ReadSection,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// An RAII guard of the writer, returned by [`Rcu::write`].
+pub struct RcuWriteGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    _guard: crate::spin_lock::SpinLockGuard<'a, ()>,
+}
+
+impl<T> RcuWriteGuard<'_, T> {
+    /// Get the current version of the data.
+    pub fn get(&self) -> Option<&T> {
+        // Only the writer frees the data.
+        unsafe { self.rcu.ptr.load(Ordering::SeqCst).as_ref() }
+    }
+
+    /// Replace the data with the `value`, and return the old version once no
+    /// reader references it.
+    pub fn replace(&mut self, value: Option<T>) -> Option<T> {
+        let new = value.map_or(null_mut(), |value| Box::into_raw(Box::new(value)));
+        let old = self.rcu.ptr.swap(new, Ordering::SeqCst);
+        if old.is_null() {
+            return None;
+        }
+        synchronize();
+        Some(*unsafe { Box::from_raw(old) })
+    }
+}
@@ -0,0 +1,84 @@
+//! Per-cpu area addressed by the GS segment.
+//!
+//! The GS base of each cpu points to its own area, so the kernel reads a field
+//! of the current cpu with a single `mov` from the GS segment, without looking
+//! up the id of the cpu. The area holds the pointer to the thread running on
+//! the cpu.
+//!
+//! Some interrupt entries execute `swapgs` and the others do not, whether they
+//! come from the user or the kernel. Both IA32_GS_BASE and
+//! IA32_KERNEL_GS_BASE therefore hold the area, so the swap is a no-op. A guest
+//! that executes `swapgs` without switching the IA32_KERNEL_GS_BASE overwrites
+//! it; the hypervisor calls [`restore`] after each vmexit.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 3.4.4 Segment Loading Instructions in IA-32e Mode.
+use super::msr::Msr;
+use crate::MAX_CPU;
+use core::arch::asm;
+
+const IA32_GS_BASE: usize = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: usize = 0xC000_0102;
+
+#[repr(C, align(64))]
+struct Area {
+    // Offset 0: the current thread.
+    current: usize,
+    // Offset 8: the address of the area itself.
+    this: usize,
+}
+
+const INIT: Area = Area {
+    current: 0,
+    this: 0,
+};
+static mut AREAS: [Area; MAX_CPU] = [INIT; MAX_CPU];
+
+/// Point the GS base of the current cpu to its area.
+pub(crate) unsafe fn init(core_id: usize) {
+    let area = core::ptr::addr_of_mut!(AREAS[core_id]);
+    (*area).this = area as usize;
+    Msr::<IA32_GS_BASE>::write(area as u64);
+    Msr::<IA32_KERNEL_GS_BASE>::write(area as u64);
+}
+
+/// Get the address of the area of the current cpu.
+///
+/// The hypervisor loads it to the GS base on the vmexits.
+#[inline]
+pub fn base() -> u64 {
+    let base: u64;
+    unsafe {
+        asm!("mov {}, gs:[8]", out(reg) base, options(nostack, readonly, preserves_flags));
+    }
+    base
+}
+
+/// Get the current thread of the cpu, or 0 if none.
+#[inline(always)]
+pub fn current() -> usize {
+    let current: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) current, options(nostack, readonly, preserves_flags));
+    }
+    current
+}
+
+/// Set the current thread of the cpu.
+///
+/// # Safety
+/// The interrupts must be disabled until the cpu switches to the thread.
+#[inline(always)]
+pub unsafe fn set_current(current: usize) {
+    asm!("mov gs:[0], {}", in(reg) current, options(nostack, preserves_flags));
+}
+
+/// Point the IA32_KERNEL_GS_BASE of the current cpu back to its area.
+///
+/// # Safety
+/// The interrupts must be disabled; the entries that swap the GS base use the
+/// IA32_KERNEL_GS_BASE.
+#[inline]
+pub unsafe fn restore() {
+    Msr::<IA32_KERNEL_GS_BASE>::write(base());
+}
@@ -16,3 +16,84 @@ pub fn read_cr3() -> usize {
         r as usize
     }
 }
+
+/// read current cr2, the faulting address of the last page fault.
+pub fn read_cr2() -> usize {
+    unsafe {
+        let r: u64;
+        asm!("mov {}, cr2", out(reg) r);
+        r as usize
+    }
+}
+
+/// Load `cr3`.
+///
+/// # Safety
+/// The page table at `cr3` must map the kernel.
+pub unsafe fn write_cr3(cr3: usize) {
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack));
+}
+
+/// Invalidate the TLB entry of `va`.
+pub fn invlpg(va: usize) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) va, options(nostack));
+    }
+}
+
+/// Invalidate all non-global TLB entries by reloading cr3.
+pub fn flush_tlb() {
+    unsafe {
+        asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack));
+    }
+}
+
+/// Invalidate the cached mappings derived from the extended page table.
+///
+/// `ty` is the INVEPT type (1: single-context, 2: all-context), and `eptp` is
+/// the EPT pointer for the single-context invalidation.
+///
+/// # Safety
+/// The processor must be in VMX operation.
+pub unsafe fn invept(ty: u64, eptp: u64) -> Result<(), ()> {
+    let desc: [u64; 2] = [eptp, 0];
+    let failed: u8;
+    asm!(
+        "invept {}, [{}]",
+        "setna {}",
+        in(reg) ty,
+        in(reg) &desc,
+        out(reg_byte) failed,
+        options(nostack)
+    );
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+/// Invalidate the cached mappings tagged with the virtual processor identifier.
+///
+/// `ty` is the INVVPID type (0: individual-address, 1: single-context,
+/// 2: all-context, 3: single-context-retaining-globals).
+///
+/// # Safety
+/// The processor must be in VMX operation.
+pub unsafe fn invvpid(ty: u64, vpid: u16, addr: u64) -> Result<(), ()> {
+    let desc: [u64; 2] = [vpid as u64, addr];
+    let failed: u8;
+    asm!(
+        "invvpid {}, [{}]",
+        "setna {}",
+        in(reg) ty,
+        in(reg) &desc,
+        out(reg_byte) failed,
+        options(nostack)
+    );
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
@@ -1,8 +1,10 @@
 //! x86_64 specific
 
+pub mod gs;
 pub mod interrupt;
 pub mod intrinsics;
 pub mod msr;
+pub mod pat;
 pub mod pio;
 pub mod segmentation;
 pub mod table;
@@ -29,3 +29,26 @@ impl<const ADDR: usize> Msr<ADDR> {
         );
     }
 }
+
+/// Read the msr of which address is determined at runtime.
+#[inline(always)]
+pub fn rdmsr(addr: u32) -> u64 {
+    let hi: u32;
+    let lo: u32;
+    unsafe {
+        asm!("rdmsr", out("edx") hi, out("eax") lo, in("ecx") addr, options(nomem, nostack));
+    }
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+/// Write to the msr of which address is determined at runtime.
+#[inline(always)]
+pub unsafe fn wrmsr(addr: u32, v: u64) {
+    asm!(
+        "wrmsr",
+        in("edx") (v >> 32) as u32,
+        in("eax") v as u32,
+        in("ecx") addr,
+        options(nomem, nostack)
+    );
+}
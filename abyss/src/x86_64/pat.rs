@@ -0,0 +1,69 @@
+//! Page attribute table.
+//!
+//! The memory type of a page is selected by the PAT, PCD and PWT bits of its
+//! page table entry, which index the eight entries of the IA32_PAT msr. The
+//! first four entries keep their power-up values, so the pages that do not
+//! set the PAT bit are mapped as before; the upper four add the
+//! write-combining type for the frame buffers and the like.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 13.12 Page Attribute Table (PAT).
+use super::msr::Msr;
+
+const IA32_PAT: usize = 0x277;
+
+// Memory types of the entries.
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WP: u64 = 0x05;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+// Entries 0..8 of the PAT.
+const PAT: [u64; 8] = [WB, WT, UC_MINUS, UC, WC, WP, UC_MINUS, UC];
+
+// Bits of a 4-KByte page table entry.
+const PTE_PWT: u64 = 1 << 3;
+const PTE_PCD: u64 = 1 << 4;
+const PTE_PAT: u64 = 1 << 7;
+
+/// Cache attribute of a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheAttr {
+    /// Write-back; the normal memory.
+    WriteBack,
+    /// Write-through.
+    WriteThrough,
+    /// Strong uncacheable; the device registers.
+    Uncached,
+    /// Write-combining; the frame buffers.
+    WriteCombining,
+}
+
+impl CacheAttr {
+    /// Get the PAT, PCD and PWT bits of a 4-KByte page table entry.
+    pub const fn pte_bits(self) -> u64 {
+        match self {
+            // Entry 0.
+            Self::WriteBack => 0,
+            // Entry 1.
+            Self::WriteThrough => PTE_PWT,
+            // Entry 3.
+            Self::Uncached => PTE_PCD | PTE_PWT,
+            // Entry 4.
+            Self::WriteCombining => PTE_PAT,
+        }
+    }
+}
+
+/// Program the PAT of the current cpu.
+///
+/// All cpus must have the same PAT.
+pub(crate) unsafe fn init() {
+    Msr::<IA32_PAT>::write(
+        PAT.iter()
+            .enumerate()
+            .fold(0, |pat, (i, ty)| pat | ty << (i * 8)),
+    );
+}
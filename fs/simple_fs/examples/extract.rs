@@ -0,0 +1,111 @@
+//! Extract a file from the disk image.
+//!
+//! ```text
+//! $ cargo run --example extract -- <disk image> <file name> <output>
+//! ```
+//!
+//! If the file is a core dump of kev, its header is printed.
+use simple_fs::{Disk, Error, FileSystem, Record, Sector};
+use std::os::unix::fs::FileExt;
+
+struct ImageDisk(std::fs::File);
+
+impl Disk for ImageDisk {
+    fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+        self.0
+            .read_exact_at(buf.as_mut(), sector.into_offset() as u64)
+            .map_err(|_| Error::DiskError)
+    }
+    fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        self.0
+            .write_all_at(buf.as_ref(), sector.into_offset() as u64)
+            .map_err(|_| Error::DiskError)
+    }
+}
+
+simple_fs::record! {
+    struct CoreHeader {
+        magic: [u8; 8],
+        version: u32,
+        vcpus: u32,
+        regions: u32,
+        flags: u32,
+        nonce: u64,
+    }
+}
+
+simple_fs::record! {
+    struct CoreVCpu {
+        id: u32,
+        valid: u32,
+        regs: [u64; 21],
+    }
+}
+
+simple_fs::record! {
+    struct CoreRegion {
+        base: u64,
+        len: u64,
+        offset: u64,
+    }
+}
+
+// Print the header of the core dump. See kev::core_dump for the format.
+//
+// Returns `None` if the core dump is truncated.
+fn print_core(b: &[u8]) -> Option<()> {
+    let header = CoreHeader::decode(b)?;
+    let (vcpus, regions) = (header.vcpus as usize, header.regions as usize);
+    println!("core dump version {}", header.version);
+    if header.flags & 1 != 0 {
+        println!("  ram encrypted with nonce {:#018x}", header.nonce);
+    }
+    if header.flags & 2 != 0 {
+        println!("  ram in the frames of the stream codec");
+    }
+    for i in 0..vcpus {
+        let vcpu = CoreVCpu::decode(b.get(CoreHeader::SIZE + i * CoreVCpu::SIZE..)?)?;
+        if vcpu.valid == 0 {
+            println!("  vcpu#{}: running", vcpu.id);
+            continue;
+        }
+        let r = vcpu.regs;
+        println!(
+            "  vcpu#{}: rip={:#x} rsp={:#x} rflags={:#x} cr0={:#x} cr3={:#x} cr4={:#x}",
+            vcpu.id, r[0], r[1], r[2], r[3], r[4], r[5]
+        );
+    }
+    for i in 0..regions {
+        let ofs = CoreHeader::SIZE + vcpus * CoreVCpu::SIZE + i * CoreRegion::SIZE;
+        let region = CoreRegion::decode(b.get(ofs..)?)?;
+        println!(
+            "  ram [{:#x}, {:#x}) at offset {:#x}",
+            region.base,
+            region.base.wrapping_add(region.len),
+            region.offset
+        );
+    }
+    Some(())
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() != 4 {
+        eprintln!("usage: {} <disk image> <file name> <output>", args[0]);
+        std::process::exit(1);
+    }
+    let image = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args[1])
+        .expect("Failed to open the disk image.");
+    let fs = FileSystem::load(ImageDisk(image)).expect("Failed to load the filesystem.");
+    let file = fs.open(&args[2]).expect("File does not exist.");
+    let mut contents = vec![0; file.size()];
+    file.read(0, &mut contents)
+        .expect("Failed to read the file.");
+    std::fs::write(&args[3], &contents).expect("Failed to write the output.");
+    if contents.starts_with(b"KEVCORE\0") && print_core(&contents).is_none() {
+        eprintln!("The core dump is truncated.");
+    }
+}
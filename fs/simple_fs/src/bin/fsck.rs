@@ -0,0 +1,30 @@
+//! Check the consistency of a disk image.
+//!
+//! ```text
+//! $ cargo run --features std --bin fsck -- <disk image>
+//! ```
+//!
+//! Prints the files and the inconsistencies, and exits with 1 if the image is
+//! inconsistent.
+use simple_fs::{image::ImageDisk, FileSystem};
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() != 2 {
+        eprintln!("usage: {} <disk image>", args[0]);
+        std::process::exit(1);
+    }
+    let disk = ImageDisk::open(&args[1]).expect("Failed to open the disk image.");
+    let fs = FileSystem::load(disk).expect("Not a simple_fs image.");
+    let inconsistencies = fs.check().expect("Failed to read the disk image.");
+    if inconsistencies.is_empty() {
+        for name in fs.files().expect("Failed to read the disk image.") {
+            println!("{:>10} {}", fs.open(&name).unwrap().size(), name);
+        }
+        return;
+    }
+    for inconsistency in inconsistencies {
+        println!("{:?}", inconsistency);
+    }
+    std::process::exit(1);
+}
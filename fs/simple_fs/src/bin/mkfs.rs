@@ -0,0 +1,36 @@
+//! Create a disk image with the files.
+//!
+//! ```text
+//! $ cargo run --features std --bin mkfs -- <disk image> <size in MiB> [files...]
+//! ```
+//!
+//! The files are stored by their names, without the directories. The image is
+//! followed by the reserved sectors.
+use simple_fs::{image::ImageDisk, FileSystem, RESERVED_SECTORS};
+use std::path::Path;
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() < 3 {
+        eprintln!("usage: {} <disk image> <size in MiB> [files...]", args[0]);
+        std::process::exit(1);
+    }
+    let size = args[2].parse::<u64>().expect("The size must be a number.") * 1024 * 1024;
+    let files = args[3..]
+        .iter()
+        .map(|path| {
+            let name = Path::new(path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .expect("Invalid file name.");
+            let contents = std::fs::read(path).expect("Failed to read the file.");
+            (name, contents)
+        })
+        .collect::<Vec<_>>();
+    let disk = ImageDisk::create(&args[1], size + RESERVED_SECTORS as u64 * 512)
+        .expect("Failed to create the disk image.");
+    if FileSystem::build_image(disk, files, size as usize).is_err() {
+        eprintln!("Failed to build the image; the disk is full or a name is duplicated.");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,340 @@
+//! A sector cache in front of a disk.
+//!
+//! [`CachedDisk`] keeps the recently used sectors of a [`Disk`] in the memory.
+//! When the writes reach the disk is selected by the [`CachePolicy`]:
+//!
+//! | Policy                        | Write                       | Flush                      |
+//! |-------------------------------|-----------------------------|----------------------------|
+//! | [`CachePolicy::WriteThrough`] | written and flushed at once | flushes the disk           |
+//! | [`CachePolicy::WriteBack`]    | kept dirty in the cache     | writes back, then flushes  |
+//! | [`CachePolicy::Unsafe`]       | kept dirty in the cache     | ignored                    |
+//!
+//! A write is durable after the next [`Disk::flush`] returns, or at once if it
+//! is written with [`CachedDisk::write_fua`] (force unit access). The dirty
+//! sectors are also written back when they are evicted, but with no ordering
+//! guarantee until the flush.
+//!
+//! ```ignore
+//! let disk = CachedDisk::new(disk, CachePolicy::WriteBack).capacity(1024);
+//! disk.write(Sector(1), &data)?;
+//! disk.flush()?; // The sector 1 is durable.
+//! ```
+use crate::{Disk, Error, Sector};
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::cell::{Cell, RefCell};
+
+/// When the writes reach the disk.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// Every write is written to the disk and flushed before it completes.
+    WriteThrough,
+    /// The writes are written to the disk on the flush.
+    WriteBack,
+    /// As the write-back, but the flushes are ignored. Only for the
+    /// throwaway disks; the writes may be lost at any time.
+    Unsafe,
+}
+
+impl CachePolicy {
+    /// Parse the policy from `writethrough`, `writeback` or `unsafe`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "writethrough" => Some(Self::WriteThrough),
+            "writeback" => Some(Self::WriteBack),
+            "unsafe" => Some(Self::Unsafe),
+            _ => None,
+        }
+    }
+}
+
+struct Line {
+    data: Box<[u8; 512]>,
+    dirty: bool,
+    // The time of the last use, for the eviction.
+    used: u64,
+}
+
+/// A [`Disk`] with a sector cache.
+pub struct CachedDisk<D: Disk> {
+    disk: D,
+    policy: CachePolicy,
+    capacity: usize,
+    lines: RefCell<BTreeMap<Sector, Line>>,
+    clock: Cell<u64>,
+}
+
+impl<D: Disk> CachedDisk<D> {
+    /// Create a cache of 256 sectors in front of the `disk`.
+    pub fn new(disk: D, policy: CachePolicy) -> Self {
+        Self {
+            disk,
+            policy,
+            capacity: 256,
+            lines: RefCell::new(BTreeMap::new()),
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Set the number of the sectors in the cache.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Get the policy.
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    /// Get the number of the dirty sectors.
+    pub fn dirty(&self) -> usize {
+        self.lines.borrow().values().filter(|l| l.dirty).count()
+    }
+
+    /// Write `buf` to the `sector`, and make it durable before returning.
+    ///
+    /// Only the sector is made durable; the other dirty sectors are not
+    /// written back. The unsafe policy treats it as a normal write.
+    pub fn write_fua(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        if self.policy == CachePolicy::Unsafe {
+            return self.write(sector, buf);
+        }
+        self.disk.write(sector, buf)?;
+        self.disk.flush()?;
+        self.insert(sector, buf, false)
+    }
+
+    /// Write back the dirty sectors, flush the disk and return it.
+    pub fn into_inner(self) -> Result<D, Error> {
+        self.write_back()?;
+        self.disk.flush()?;
+        Ok(self.disk)
+    }
+
+    fn tick(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    // Put the sector into the cache, evicting the least recently used one.
+    fn insert(&self, sector: Sector, buf: &[u8; 512], dirty: bool) -> Result<(), Error> {
+        let used = self.tick();
+        let mut lines = self.lines.borrow_mut();
+        if let Some(line) = lines.get_mut(&sector) {
+            line.data.copy_from_slice(buf);
+            line.dirty |= dirty;
+            line.used = used;
+            return Ok(());
+        }
+        if lines.len() >= self.capacity {
+            let victim = lines
+                .iter()
+                .min_by_key(|(_, l)| l.used)
+                .map(|(sector, _)| *sector)
+                .unwrap();
+            if lines[&victim].dirty {
+                self.disk.write(victim, &lines[&victim].data)?;
+            }
+            lines.remove(&victim);
+        }
+        lines.insert(
+            sector,
+            Line {
+                data: Box::new(*buf),
+                dirty,
+                used,
+            },
+        );
+        Ok(())
+    }
+
+    // Write the dirty sectors to the disk, in the order of the sectors.
+    fn write_back(&self) -> Result<(), Error> {
+        let mut lines = self.lines.borrow_mut();
+        for (sector, line) in lines.iter_mut().filter(|(_, l)| l.dirty) {
+            self.disk.write(*sector, &line.data)?;
+            line.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Disk> Disk for CachedDisk<D> {
+    fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+        let used = self.tick();
+        if let Some(line) = self.lines.borrow_mut().get_mut(&sector) {
+            buf.copy_from_slice(line.data.as_ref());
+            line.used = used;
+            return Ok(());
+        }
+        self.disk.read(sector, buf)?;
+        self.insert(sector, buf, false)
+    }
+
+    fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        match self.policy {
+            CachePolicy::WriteThrough => {
+                self.disk.write(sector, buf)?;
+                self.disk.flush()?;
+                self.insert(sector, buf, false)
+            }
+            CachePolicy::WriteBack | CachePolicy::Unsafe => self.insert(sector, buf, true),
+        }
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        match self.policy {
+            CachePolicy::WriteThrough => self.disk.flush(),
+            CachePolicy::WriteBack => {
+                self.write_back()?;
+                self.disk.flush()
+            }
+            CachePolicy::Unsafe => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, vec::Vec};
+
+    // A disk with a volatile write cache: the writes are durable only after
+    // a flush, and a crash drops the rest.
+    #[derive(Clone, Default)]
+    struct VolatileDisk {
+        durable: Rc<RefCell<BTreeMap<Sector, [u8; 512]>>>,
+        volatile: Rc<RefCell<BTreeMap<Sector, [u8; 512]>>>,
+        writes: Rc<RefCell<Vec<Sector>>>,
+    }
+
+    impl VolatileDisk {
+        fn durable(&self, sector: usize) -> Option<u8> {
+            self.durable.borrow().get(&Sector(sector)).map(|b| b[0])
+        }
+
+        fn crash(&self) {
+            self.volatile.borrow_mut().clear();
+        }
+    }
+
+    impl Disk for VolatileDisk {
+        fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+            *buf = self
+                .volatile
+                .borrow()
+                .get(&sector)
+                .or(self.durable.borrow().get(&sector))
+                .copied()
+                .unwrap_or([0; 512]);
+            Ok(())
+        }
+
+        fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+            self.writes.borrow_mut().push(sector);
+            self.volatile.borrow_mut().insert(sector, *buf);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            let mut volatile = self.volatile.borrow_mut();
+            self.durable.borrow_mut().append(&mut volatile);
+            Ok(())
+        }
+    }
+
+    fn cached(policy: CachePolicy) -> (VolatileDisk, CachedDisk<VolatileDisk>) {
+        let disk = VolatileDisk::default();
+        (disk.clone(), CachedDisk::new(disk, policy).capacity(4))
+    }
+
+    #[test]
+    fn test_write_back_durable_after_flush() {
+        let (disk, cache) = cached(CachePolicy::WriteBack);
+        cache.write(Sector(1), &[1; 512]).unwrap();
+        cache.write(Sector(2), &[2; 512]).unwrap();
+        assert_eq!(cache.dirty(), 2);
+        assert!(disk.writes.borrow().is_empty());
+        assert_eq!(disk.durable(1), None);
+
+        cache.flush().unwrap();
+        assert_eq!(cache.dirty(), 0);
+        assert_eq!(disk.durable(1), Some(1));
+        assert_eq!(disk.durable(2), Some(2));
+    }
+
+    #[test]
+    fn test_write_back_crash_keeps_last_flush() {
+        let (disk, cache) = cached(CachePolicy::WriteBack);
+        cache.write(Sector(1), &[1; 512]).unwrap();
+        cache.flush().unwrap();
+        cache.write(Sector(1), &[2; 512]).unwrap();
+        cache.write(Sector(3), &[3; 512]).unwrap();
+        disk.crash();
+        assert_eq!(disk.durable(1), Some(1));
+        assert_eq!(disk.durable(3), None);
+
+        // The cache still serves the latest data.
+        let mut buf = [0; 512];
+        cache.read(Sector(1), &mut buf).unwrap();
+        assert_eq!(buf[0], 2);
+    }
+
+    #[test]
+    fn test_write_through_is_durable_at_once() {
+        let (disk, cache) = cached(CachePolicy::WriteThrough);
+        cache.write(Sector(5), &[5; 512]).unwrap();
+        assert_eq!(cache.dirty(), 0);
+        disk.crash();
+        assert_eq!(disk.durable(5), Some(5));
+    }
+
+    #[test]
+    fn test_fua_is_durable_alone() {
+        let (disk, cache) = cached(CachePolicy::WriteBack);
+        cache.write(Sector(1), &[1; 512]).unwrap();
+        cache.write_fua(Sector(2), &[2; 512]).unwrap();
+        disk.crash();
+        assert_eq!(disk.durable(2), Some(2));
+        assert_eq!(disk.durable(1), None);
+        assert_eq!(cache.dirty(), 1);
+    }
+
+    #[test]
+    fn test_unsafe_ignores_flush() {
+        let (disk, cache) = cached(CachePolicy::Unsafe);
+        cache.write(Sector(1), &[1; 512]).unwrap();
+        cache.write_fua(Sector(2), &[2; 512]).unwrap();
+        cache.flush().unwrap();
+        disk.crash();
+        assert_eq!(disk.durable(1), None);
+        assert_eq!(disk.durable(2), None);
+
+        // Closing the disk still writes everything back.
+        cache.into_inner().unwrap();
+        assert_eq!(disk.durable(1), Some(1));
+        assert_eq!(disk.durable(2), Some(2));
+    }
+
+    #[test]
+    fn test_eviction_writes_back() {
+        let (disk, cache) = cached(CachePolicy::WriteBack);
+        for i in 0..8 {
+            cache.write(Sector(i), &[i as u8; 512]).unwrap();
+        }
+        assert_eq!(cache.dirty(), 4);
+        assert_eq!(*disk.writes.borrow(), [0, 1, 2, 3].map(Sector));
+
+        let mut buf = [0; 512];
+        for i in 0..8 {
+            cache.read(Sector(i), &mut buf).unwrap();
+            assert_eq!(buf, [i as u8; 512]);
+        }
+        cache.flush().unwrap();
+        disk.crash();
+        for i in 0..8 {
+            assert_eq!(disk.durable(i), Some(i as u8));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Disk images on the host.
+//!
+//! The build scripts of the projects build the disk images with
+//! [`ImageBuilder`]:
+//! ```ignore
+//! ImageBuilder::new()
+//!     .dir("rootfs")?
+//!     .file("keos.cfg", "log=info")
+//!     .build("blk.bin")?;
+//! ```
+//! The files are stored in the order of their names, so the same files always
+//! build the same image.
+use crate::{Disk, Error, FileSystem, Sector, RESERVED_SECTORS};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+/// A disk backed by an image file.
+pub struct ImageDisk(std::fs::File);
+
+impl ImageDisk {
+    /// Open the image at `path`.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map(Self)
+    }
+
+    /// Create a new image of `size` bytes at `path`.
+    pub fn create(path: impl AsRef<Path>, size: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size)?;
+        Ok(Self(file))
+    }
+}
+
+impl Disk for ImageDisk {
+    fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+        self.0
+            .read_exact_at(buf.as_mut(), sector.into_offset() as u64)
+            .map_err(|_| Error::DiskError)
+    }
+    fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        self.0
+            .write_all_at(buf.as_ref(), sector.into_offset() as u64)
+            .map_err(|_| Error::DiskError)
+    }
+}
+
+impl<T: Disk> FileSystem<T> {
+    /// Create a new file system of `size` bytes on the disk, which contains
+    /// the `files` of (name, contents).
+    pub fn build_image<N, B>(
+        t: T,
+        files: impl IntoIterator<Item = (N, B)>,
+        size: usize,
+    ) -> Result<Self, Error>
+    where
+        N: AsRef<str>,
+        B: AsRef<[u8]>,
+    {
+        let mut fs = Self::new(t, size)?;
+        for (name, contents) in files {
+            fs.create(name.as_ref(), contents.as_ref())?;
+        }
+        Ok(fs)
+    }
+}
+
+/// A builder of a disk image.
+pub struct ImageBuilder {
+    files: BTreeMap<String, Vec<u8>>,
+    free: usize,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// Create a builder of an empty image with 1 GiB of free space.
+    pub fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            free: 1 << 30,
+        }
+    }
+
+    /// Add a file of `name` that contains `contents`.
+    ///
+    /// The file replaces the previous file of the same name.
+    pub fn file(mut self, name: impl Into<String>, contents: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(name.into(), contents.into());
+        self
+    }
+
+    /// Add the files in the directory at `path`, by their names.
+    ///
+    /// The subdirectories are not supported.
+    pub fn dir(mut self, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        for entry in path.as_ref().read_dir()? {
+            let path: PathBuf = entry?.path();
+            if path.is_dir() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("{} is a directory.", path.display()),
+                ));
+            }
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+            self.files.insert(String::from(name), std::fs::read(&path)?);
+        }
+        Ok(self)
+    }
+
+    /// Set the free space of the image in bytes.
+    pub fn free(mut self, free: usize) -> Self {
+        self.free = free;
+        self
+    }
+
+    /// Get the size of the image in bytes, rounded up to 1 MiB.
+    pub fn size(&self) -> usize {
+        const M: usize = 1 << 20;
+        // The superblock, and the header of the free segment.
+        let used = 1024
+            + self
+                .files
+                .values()
+                .map(|contents| 512 + ((contents.len() + 511) & !511))
+                .sum::<usize>();
+        (used + self.free + M - 1) & !(M - 1)
+    }
+
+    /// Build the image on the disk.
+    pub fn build_on<T: Disk>(self, t: T) -> Result<FileSystem<T>, Error> {
+        let size = self.size();
+        FileSystem::build_image(t, self.files, size)
+    }
+
+    /// Build the image at `path`, followed by the [`RESERVED_SECTORS`].
+    pub fn build(self, path: impl AsRef<Path>) -> Result<FileSystem<ImageDisk>, Error> {
+        let size = self.size() + RESERVED_SECTORS * 512;
+        let disk = ImageDisk::create(path, size as u64).map_err(|_| Error::DiskError)?;
+        self.build_on(disk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A disk in the memory.
+    struct MemDisk(std::cell::RefCell<Vec<u8>>);
+
+    impl Disk for MemDisk {
+        fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+            let ofs = sector.into_offset();
+            buf.copy_from_slice(&self.0.borrow()[ofs..ofs + 512]);
+            Ok(())
+        }
+        fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+            let ofs = sector.into_offset();
+            self.0.borrow_mut()[ofs..ofs + 512].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn build(builder: ImageBuilder) -> FileSystem<MemDisk> {
+        let disk = MemDisk(std::cell::RefCell::new(vec![0; builder.size()]));
+        builder.build_on(disk).unwrap()
+    }
+
+    #[test]
+    fn test_builder() {
+        let fs = build(
+            ImageBuilder::new()
+                .free(0)
+                .file("b", vec![2; 1000])
+                .file("a", "hello")
+                .file("c", vec![3; 1 << 20]),
+        );
+        assert_eq!(fs.files().unwrap(), ["a", "b", "c"]);
+        assert_eq!(fs.check().unwrap(), []);
+        let mut buf = [0; 5];
+        fs.open("a").unwrap().read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(fs.open("c").unwrap().size(), 1 << 20);
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let builder = || {
+            ImageBuilder::new()
+                .free(1 << 20)
+                .file("y", vec![1; 100])
+                .file("x", vec![2; 100])
+        };
+        let (a, b) = (build(builder()).close(), build(builder()).close());
+        assert!(a.0 == b.0);
+    }
+
+    #[test]
+    fn test_reserved() {
+        let path = std::env::temp_dir().join(format!("simple_fs-{}.img", std::process::id()));
+        let builder = ImageBuilder::new().free(0).file("a", "hello");
+        let size = builder.size();
+        let fs = builder.build(&path).unwrap();
+        assert_eq!(fs.size(), size);
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(len as usize, size + RESERVED_SECTORS * 512);
+    }
+}
@@ -23,12 +23,35 @@
 //! [Offset 1536]
 //! File_contents
 //! ...
+//! [Offset disk_size]
+//! Reserved (RESERVED_SECTORS * 512 bytes)
 //! ```
 //!
-//! This file system only have file abstraction (**NO DIRECTORY!!**) and the file can only be read, overwrite.
+//! The [`RESERVED_SECTORS`] after the file system are not used by the file
+//! system; keos keeps the log of its last panic there.
+//!
+//! This file system only have file abstraction (**NO DIRECTORY!!**) and the file can only be read, overwrite and removed.
+//!
+//! With the `std` feature, the `mkfs` and `fsck` binaries build and check the
+//! disk images on the host:
+//! ```text
+//! $ cargo run --features std --bin mkfs -- <disk image> <size in MiB> [files...]
+//! $ cargo run --features std --bin fsck -- <disk image>
+//! ```
 
 extern crate alloc;
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+pub mod cache;
+#[cfg(any(feature = "std", test))]
+pub mod image;
+pub mod record;
+
+pub use record::{LeBytes, Record};
+
+/// Number of the sectors reserved after the file system, at the end of the
+/// disk images.
+pub const RESERVED_SECTORS: usize = 8;
 
 /// A utilties to read/write bytes to u8 slice.
 #[doc(hidden)]
@@ -81,6 +104,61 @@ impl<'a> ByteRw<'a> {
     pub fn write_u64(&mut self, p: usize, v: u64) {
         self.b.as_mut()[p..p + 8].copy_from_slice(&u64::to_le_bytes(v))
     }
+    /// Read u8 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_read_u8(&self, p: usize) -> Option<u8> {
+        <u8 as LeBytes>::from_le(self.b.get(p..)?)
+    }
+    /// Read u16 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_read_u16(&self, p: usize) -> Option<u16> {
+        <u16 as LeBytes>::from_le(self.b.get(p..)?)
+    }
+    /// Read u32 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_read_u32(&self, p: usize) -> Option<u32> {
+        <u32 as LeBytes>::from_le(self.b.get(p..)?)
+    }
+    /// Read u64 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_read_u64(&self, p: usize) -> Option<u64> {
+        <u64 as LeBytes>::from_le(self.b.get(p..)?)
+    }
+    /// Write u8 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_write_u8(&mut self, p: usize, v: u8) -> Option<()> {
+        LeBytes::to_le(&v, self.b.get_mut(p..)?)
+    }
+    /// Write u16 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_write_u16(&mut self, p: usize, v: u16) -> Option<()> {
+        LeBytes::to_le(&v, self.b.get_mut(p..)?)
+    }
+    /// Write u32 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_write_u32(&mut self, p: usize, v: u32) -> Option<()> {
+        LeBytes::to_le(&v, self.b.get_mut(p..)?)
+    }
+    /// Write u64 from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_write_u64(&mut self, p: usize, v: u64) -> Option<()> {
+        LeBytes::to_le(&v, self.b.get_mut(p..)?)
+    }
+    /// Get `len` bytes from position `p`, or `None` if it is out of the buffer.
+    #[inline]
+    pub fn try_bytes(&self, p: usize, len: usize) -> Option<&[u8]> {
+        self.b.get(p..p.checked_add(len)?)
+    }
+    /// Read the record from position `p`.
+    #[inline]
+    pub fn read_record<R: Record>(&self, p: usize) -> Option<R> {
+        R::decode(self.b.get(p..)?)
+    }
+    /// Write the record from position `p`.
+    #[inline]
+    pub fn write_record<R: Record>(&mut self, p: usize, r: &R) -> Option<()> {
+        r.encode(self.b.get_mut(p..)?)
+    }
     /// Get underlying buffer as reference.
     #[inline]
     pub fn inner(&self) -> &[u8] {
@@ -93,6 +171,35 @@ impl<'a> ByteRw<'a> {
     }
 }
 
+record! {
+    // The super block at the sector 0.
+    struct SuperBlock {
+        magic: [u8; 8],
+        size: u64,
+    }
+}
+
+record! {
+    // The header of a segment, followed by the name.
+    struct SegmentHeader {
+        name_len: u64,
+        size: u64,
+    }
+}
+
+impl SegmentHeader {
+    // Get the name in the header sector `b`, or `None` if it is malformed.
+    fn name<'b>(&self, b: &'b [u8]) -> Option<&'b str> {
+        let len = usize::try_from(self.name_len).ok()?;
+        core::str::from_utf8(b.get(Self::SIZE..Self::SIZE.checked_add(len)?)?).ok()
+    }
+
+    // Get the number of the sectors of the segment, including the header.
+    fn sectors(&self) -> Option<usize> {
+        Some(1 + usize::try_from(self.size.checked_add(511)?).ok()? / 512)
+    }
+}
+
 /// Sector.
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
@@ -121,12 +228,29 @@ pub enum Error {
     FsError,
 }
 
+/// An inconsistency found by [`FileSystem::check`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// The name of the header at the sector is too long or not UTF-8.
+    BadName(Sector),
+    /// The segment of the header at the sector runs past the end of the disk.
+    Overflow(Sector),
+    /// The header at the sector has the name of a previous file.
+    Duplicated(Sector),
+}
+
 /// A device that has byte sink.
 pub trait Disk {
     /// Read 512 bytes from disk starting from sector.
     fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error>;
     /// Write 512 bytes to disk starting from sector.
     fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error>;
+    /// Make the completed writes durable.
+    ///
+    /// The disks without a volatile write cache have nothing to do.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// The root file system
@@ -141,8 +265,14 @@ impl<T: Disk> FileSystem<T> {
         // Set header
         let mut buf = Box::new([0; 512]);
         let mut rw = ByteRw::new(buf.as_mut());
-        rw.inner_mut()[0..8].copy_from_slice(b"SIMPLEFS");
-        rw.write_u64(8, size as u64);
+        rw.write_record(
+            0,
+            &SuperBlock {
+                magic: *b"SIMPLEFS",
+                size: size as u64,
+            },
+        )
+        .ok_or(Error::FsError)?;
         drop(rw);
         t.write(Sector(0), buf.as_ref())?;
 
@@ -160,24 +290,33 @@ impl<T: Disk> FileSystem<T> {
     pub fn load(t: T) -> Result<Self, Error> {
         let mut buf = Box::new([0; 512]);
         t.read(Sector(0), buf.as_mut())?;
-        let mut rw = ByteRw::new(buf.as_mut());
-        if &rw.inner_mut()[0..8] != b"SIMPLEFS" {
-            return Err(Error::FsError);
+        let rw = ByteRw::new(buf.as_mut());
+        match rw.read_record::<SuperBlock>(0) {
+            Some(SuperBlock { magic, size }) if &magic == b"SIMPLEFS" => Ok(Self {
+                t,
+                size: usize::try_from(size).map_err(|_| Error::FsError)?,
+            }),
+            _ => Err(Error::FsError),
         }
-        let size = rw.read_u64(8) as usize;
-        Ok(Self { t, size })
     }
 
     fn write_file_header(&self, sector: Sector, name: &str, size: usize) -> Result<(), Error> {
         let mut buf = Box::new([0; 512]);
         let mut rw = ByteRw::new(buf.as_mut());
         let name_len = name.len();
-        if name_len > 512 - 16 {
+        if name_len > 512 - SegmentHeader::SIZE {
             return Err(Error::FsError);
         }
-        rw.write_u64(0, name_len as u64);
-        rw.write_u64(8, size as u64);
-        rw.inner_mut()[16..16 + name_len].copy_from_slice(name.as_bytes());
+        rw.write_record(
+            0,
+            &SegmentHeader {
+                name_len: name_len as u64,
+                size: size as u64,
+            },
+        )
+        .ok_or(Error::FsError)?;
+        rw.inner_mut()[SegmentHeader::SIZE..SegmentHeader::SIZE + name_len]
+            .copy_from_slice(name.as_bytes());
         drop(rw);
 
         self.t.write(sector, buf.as_ref())
@@ -193,25 +332,44 @@ impl<T: Disk> FileSystem<T> {
         while pos < self.size / 512 {
             self.t.read(Sector(pos), buf.as_mut()).ok()?;
             let rw = ByteRw::new(buf.as_mut());
-            let len = rw.read_u64(0) as usize;
-            let fname = core::str::from_utf8(&rw.inner()[16..16 + len]).ok()?;
-            if fname == name {
+            let header = rw.read_record::<SegmentHeader>(0)?;
+            if header.name(rw.inner())? == name {
                 return Some(File {
                     name: String::from(name),
-                    size: rw.read_u64(8) as usize,
+                    size: usize::try_from(header.size).ok()?,
                     start_sector: Sector(pos),
                     fs: self,
                 });
             }
-            let this_segment_size = ((rw.read_u64(8) + 511) & !511) as usize;
-            pos += 1 + this_segment_size / 512;
+            pos = pos.checked_add(header.sectors()?)?;
         }
         None
     }
 
+    /// Get the names of the files, in the order on the disk.
+    pub fn files(&self) -> Result<Vec<String>, Error> {
+        let mut files = Vec::new();
+        let mut buf = Box::new([0; 512]);
+        let mut pos = 1;
+        while pos < self.size / 512 {
+            self.t.read(Sector(pos), buf.as_mut())?;
+            let rw = ByteRw::new(buf.as_mut());
+            let header = rw.read_record::<SegmentHeader>(0).ok_or(Error::FsError)?;
+            if header.name_len != 0 {
+                let fname = header.name(rw.inner()).ok_or(Error::FsError)?;
+                files.push(String::from(fname));
+            }
+            pos = header
+                .sectors()
+                .and_then(|sectors| pos.checked_add(sectors))
+                .ok_or(Error::FsError)?;
+        }
+        Ok(files)
+    }
+
     /// Create a file that contains `contents`.
     pub fn create(&mut self, name: &str, contents: &[u8]) -> Result<(), Error> {
-        if name.len() == 0 {
+        if name.len() == 0 || self.open(name).is_some() {
             return Err(Error::FsError);
         }
         let file_size = contents.len();
@@ -221,8 +379,9 @@ impl<T: Disk> FileSystem<T> {
         while pos < self.size / 512 {
             self.t.read(Sector(pos), buf.as_mut())?;
             let rw = ByteRw::new(buf.as_mut());
-            let this_segment_size = ((rw.read_u64(8) + 511) & !511) as usize;
-            if rw.read_u64(0) == 0 && this_segment_size >= required {
+            let header = rw.read_record::<SegmentHeader>(0).ok_or(Error::FsError)?;
+            let this_segment_size = (header.sectors().ok_or(Error::FsError)? - 1) * 512;
+            if header.name_len == 0 && this_segment_size >= required {
                 if this_segment_size != required {
                     // split
                     let nseg_size = this_segment_size - required - 512;
@@ -252,6 +411,91 @@ impl<T: Disk> FileSystem<T> {
         Err(Error::FsError)
     }
 
+    /// Remove the file with `name`.
+    ///
+    /// The segment of the file is freed, and merged with the free segments
+    /// around it. The file is removed by a single write of a header, so a
+    /// power failure leaves either the file or the free segment.
+    pub fn remove(&mut self, name: &str) -> Result<(), Error> {
+        if name.is_empty() {
+            return Err(Error::FsError);
+        }
+        let mut buf = Box::new([0; 512]);
+        let mut pos = 1;
+        // The free segment right before the current one.
+        let mut free_before: Option<(usize, usize)> = None;
+        while pos < self.size / 512 {
+            self.t.read(Sector(pos), buf.as_mut())?;
+            let rw = ByteRw::new(buf.as_mut());
+            let header = rw.read_record::<SegmentHeader>(0).ok_or(Error::FsError)?;
+            let sectors = header.sectors().ok_or(Error::FsError)?;
+            let this_segment_size = (sectors - 1) * 512;
+            let next = pos.checked_add(sectors).ok_or(Error::FsError)?;
+            if header.name_len == 0 {
+                free_before = Some(free_before.unwrap_or((pos, 0)));
+            } else if header.name(rw.inner()) == Some(name) {
+                let (start, mut free) = match free_before {
+                    Some((start, _)) => (start, (pos - start) * 512 + this_segment_size),
+                    None => (pos, this_segment_size),
+                };
+                if next < self.size / 512 {
+                    self.t.read(Sector(next), buf.as_mut())?;
+                    let rw = ByteRw::new(buf.as_mut());
+                    let header = rw.read_record::<SegmentHeader>(0).ok_or(Error::FsError)?;
+                    if header.name_len == 0 {
+                        free += header.sectors().ok_or(Error::FsError)? * 512;
+                    }
+                }
+                return self.write_file_header(Sector(start), "", free);
+            } else {
+                free_before = None;
+            }
+            pos = next;
+        }
+        Err(Error::FsError)
+    }
+
+    /// Check the consistency of the file system.
+    ///
+    /// Returns the inconsistencies found by walking the headers; the walk
+    /// stops at the first header that runs past the end of the disk.
+    pub fn check(&self) -> Result<Vec<Inconsistency>, Error> {
+        let mut found = Vec::new();
+        let mut names: Vec<String> = Vec::new();
+        let mut buf = Box::new([0; 512]);
+        let mut pos = 1;
+        while pos < self.size / 512 {
+            self.t.read(Sector(pos), buf.as_mut())?;
+            let rw = ByteRw::new(buf.as_mut());
+            let header = rw.read_record::<SegmentHeader>(0).ok_or(Error::FsError)?;
+            match header.name(rw.inner()) {
+                None => found.push(Inconsistency::BadName(Sector(pos))),
+                Some("") => (),
+                Some(name) if names.iter().any(|n| n == name) => {
+                    found.push(Inconsistency::Duplicated(Sector(pos)))
+                }
+                Some(name) => names.push(String::from(name)),
+            }
+            match header
+                .sectors()
+                .and_then(|sectors| pos.checked_add(sectors))
+            {
+                Some(next) if next <= self.size / 512 => pos = next,
+                _ => {
+                    found.push(Inconsistency::Overflow(Sector(pos)));
+                    break;
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Get the size of the file system in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     /// Close this filesystem.
     #[inline]
     pub fn close(self) -> T {
@@ -310,6 +554,11 @@ impl<'a, T: Disk> File<'a, T> {
         Ok(len)
     }
 
+    /// Make the writes to the file durable.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.fs.t.flush()
+    }
+
     /// Write to file starting from `ofs` from `contents`.
     pub fn write(&self, ofs: usize, contents: &[u8]) -> Result<usize, Error> {
         let len = contents.len().min(self.size.saturating_sub(ofs));
@@ -335,6 +584,8 @@ impl<'a, T: Disk> File<'a, T> {
             }
             let remainder = chunks.remainder();
             if remainder.len() != 0 {
+                // Keep the rest of the last sector.
+                self.fs.t.read(Sector(pos), buf.as_mut())?;
                 buf[..remainder.len()].copy_from_slice(remainder);
                 self.fs.t.write(Sector(pos), buf.as_ref())?;
             }
@@ -343,7 +594,7 @@ impl<'a, T: Disk> File<'a, T> {
     }
 }
 
-#[cfg(not(all(not(feature = "std"), not(test))))]
+#[cfg(test)]
 mod tests {
     use super::*;
     use rand::distributions::Alphanumeric;
@@ -445,6 +696,7 @@ mod tests {
                 assert_eq!(&readbuf[..j], &content[i..i + j]);
             }
         }
+        assert_eq!(fs.files().unwrap(), ["a", "b"]);
         let fs = FileSystem::load(fs.close()).unwrap();
         // Read test - persistent
         let mut readbuf = vec![0; 0x3ff];
@@ -465,4 +717,181 @@ mod tests {
             }
         }
     }
+
+    // A disk in the memory that loses the power after `budget` writes.
+    struct MemDisk {
+        image: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+        budget: std::cell::Cell<usize>,
+    }
+
+    impl Disk for MemDisk {
+        fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+            let ofs = sector.into_offset();
+            buf.copy_from_slice(&self.image.borrow()[ofs..ofs + 512]);
+            Ok(())
+        }
+        fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+            match self.budget.get() {
+                0 => Err(Error::DiskError),
+                budget => {
+                    self.budget.set(budget - 1);
+                    let ofs = sector.into_offset();
+                    self.image.borrow_mut()[ofs..ofs + 512].copy_from_slice(buf);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut fs = FileSystem::new(FileDisk::new(), 512 * 0x100).unwrap();
+        fs.create("a", &[1; 1000]).unwrap();
+        fs.create("b", &[2; 100]).unwrap();
+        fs.create("c", &[3; 100]).unwrap();
+        fs.remove("b").unwrap();
+        assert!(fs.remove("b").is_err());
+        assert_eq!(fs.files().unwrap(), ["a", "c"]);
+        // The freed segment of "b" is reused.
+        fs.create("d", &[4; 512]).unwrap();
+        assert_eq!(fs.files().unwrap(), ["a", "d", "c"]);
+        fs.remove("a").unwrap();
+        fs.remove("d").unwrap();
+        fs.remove("c").unwrap();
+        assert!(fs.files().unwrap().is_empty());
+        // All segments are merged.
+        fs.create("e", &vec![5; 512 * 0xfd]).unwrap();
+        assert_eq!(fs.check().unwrap(), []);
+    }
+
+    #[test]
+    fn test_check() {
+        let disk = FileDisk::new();
+        let fs = FileSystem::new(disk, 512 * 0x10).unwrap();
+        fs.write_file_header(Sector(1), "", 512 * 0x20).unwrap();
+        assert_eq!(fs.check().unwrap(), [Inconsistency::Overflow(Sector(1))]);
+        fs.write_file_header(Sector(1), "a", 0).unwrap();
+        fs.write_file_header(Sector(2), "a", 0).unwrap();
+        fs.write_file_header(Sector(3), "", 512 * 0xb).unwrap();
+        assert_eq!(fs.check().unwrap(), [Inconsistency::Duplicated(Sector(2))]);
+    }
+
+    #[test]
+    fn test_power_failure() {
+        use rand::{rngs::StdRng, SeedableRng};
+        const SIZE: usize = 512 * 0x80;
+
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..200 {
+            let image = std::rc::Rc::new(std::cell::RefCell::new(vec![0; SIZE]));
+            let disk = MemDisk {
+                image: image.clone(),
+                budget: std::cell::Cell::new(usize::MAX),
+            };
+            let mut fs = FileSystem::new(disk, SIZE).unwrap();
+            fs.t.budget.set(rng.gen_range(0..64));
+            // The files that are completely created or removed.
+            let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+            let result = (0..32).try_for_each(|_| match rng.gen_range(0..3) {
+                0 => {
+                    let name = format!("f{}", rng.gen_range(0..8));
+                    let contents = (0..rng.gen_range(0..2048))
+                        .map(|_| rng.gen())
+                        .collect::<Vec<u8>>();
+                    match fs.create(&name, &contents) {
+                        Ok(()) => {
+                            files.push((name, contents));
+                            Ok(())
+                        }
+                        // The disk is full.
+                        Err(Error::FsError) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                }
+                1 if !files.is_empty() => {
+                    let idx = rng.gen_range(0..files.len());
+                    let (name, contents) = &mut files[idx];
+                    let ofs = rng.gen_range(0..=contents.len());
+                    let data = (0..rng.gen_range(0..600))
+                        .map(|_| rng.gen())
+                        .collect::<Vec<u8>>();
+                    let len = fs.open(name).unwrap().write(ofs, &data)?;
+                    contents[ofs..ofs + len].copy_from_slice(&data[..len]);
+                    Ok(())
+                }
+                2 if !files.is_empty() => {
+                    let (name, _) = files.swap_remove(rng.gen_range(0..files.len()));
+                    fs.remove(&name)
+                }
+                _ => Ok(()),
+            });
+            // Drop the disk, and boot again from the image.
+            drop(fs);
+            let fs = FileSystem::load(MemDisk {
+                image,
+                budget: std::cell::Cell::new(usize::MAX),
+            })
+            .unwrap();
+            assert_eq!(fs.check().unwrap(), []);
+            if result.is_ok() {
+                let mut names = fs.files().unwrap();
+                let mut expected = files.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+                names.sort();
+                expected.sort();
+                assert_eq!(names, expected);
+                for (name, contents) in files.iter() {
+                    let mut buf = vec![0; contents.len()];
+                    fs.open(name).unwrap().read(0, &mut buf).unwrap();
+                    assert!(&buf == contents, "{} is corrupted", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_rw() {
+        let mut b = [0u8; 10];
+        let mut rw = ByteRw::new(&mut b);
+        rw.write_u64(0, 0x0123_4567_89ab_cdef);
+        assert_eq!(rw.try_read_u64(0), Some(0x0123_4567_89ab_cdef));
+        assert_eq!(rw.try_read_u16(8), Some(0));
+        assert_eq!(rw.try_read_u32(8), None);
+        assert_eq!(rw.try_read_u8(usize::MAX), None);
+        assert_eq!(rw.try_write_u32(7, 1), None);
+        assert_eq!(rw.try_bytes(8, 2), Some(&[0u8, 0][..]));
+        assert_eq!(rw.try_bytes(8, usize::MAX), None);
+
+        let header = SegmentHeader {
+            name_len: 3,
+            size: 0x200,
+        };
+        assert_eq!(rw.write_record(2, &header), None);
+        let mut b = [0u8; SegmentHeader::SIZE];
+        let mut rw = ByteRw::new(&mut b);
+        rw.write_record(0, &header).unwrap();
+        let header = rw.read_record::<SegmentHeader>(0).unwrap();
+        assert_eq!((header.name_len, header.size), (3, 0x200));
+        assert_eq!(header.sectors(), Some(2));
+        assert!(rw.read_record::<SegmentHeader>(1).is_none());
+    }
+
+    #[test]
+    fn test_corrupted_header() {
+        let fs = FileSystem::new(FileDisk::new(), 512 * 0x10).unwrap();
+        fs.write_file_header(Sector(1), "a", 0).unwrap();
+        // The name overflows the header sector.
+        let mut buf = Box::new([0; 512]);
+        fs.t.read(Sector(1), &mut buf).unwrap();
+        ByteRw::new(buf.as_mut()).write_u64(0, 0x1000);
+        fs.t.write(Sector(1), &buf).unwrap();
+        assert!(fs.open("a").is_none());
+        assert!(fs.files().is_err());
+        // The size overflows.
+        fs.write_file_header(Sector(1), "a", 0).unwrap();
+        fs.t.read(Sector(1), &mut buf).unwrap();
+        ByteRw::new(buf.as_mut()).write_u64(8, u64::MAX);
+        fs.t.write(Sector(1), &buf).unwrap();
+        assert!(fs.open("b").is_none());
+        assert!(fs.files().is_err());
+    }
 }
@@ -0,0 +1,133 @@
+//! Fixed-layout records on the disk.
+//!
+//! The on-disk structures, such as the headers of the file system, are
+//! sequences of little-endian integers without padding. The [`record!`] macro
+//! declares such a structure and implements [`Record`] for it, which decodes
+//! and encodes the fields in the order of the declaration:
+//!
+//! ```
+//! simple_fs::record! {
+//!     /// The header of a segment.
+//!     pub struct Header {
+//!         /// Length of the name.
+//!         pub name_len: u64,
+//!         /// Size of the file.
+//!         pub size: u64,
+//!     }
+//! }
+//!
+//! use simple_fs::Record;
+//! let header = Header::decode(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0]).unwrap();
+//! assert_eq!((header.name_len, header.size), (3, 0x200));
+//! // A short buffer is an error, not a panic.
+//! assert!(Header::decode(&[0; 15]).is_none());
+//! ```
+
+/// A field of a [`Record`], stored in little-endian.
+pub trait LeBytes: Sized {
+    /// Size of the field in bytes.
+    const SIZE: usize;
+    /// Decode the field from the start of `b`.
+    ///
+    /// Returns `None` if `b` is shorter than the field.
+    fn from_le(b: &[u8]) -> Option<Self>;
+    /// Encode the field to the start of `b`.
+    ///
+    /// Returns `None` if `b` is shorter than the field.
+    fn to_le(&self, b: &mut [u8]) -> Option<()>;
+}
+
+macro_rules! impl_le_bytes {
+    ($($ty:ty),*) => {
+        $(
+            impl LeBytes for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+                #[inline]
+                fn from_le(b: &[u8]) -> Option<Self> {
+                    Some(<$ty>::from_le_bytes(b.get(..Self::SIZE)?.try_into().ok()?))
+                }
+                #[inline]
+                fn to_le(&self, b: &mut [u8]) -> Option<()> {
+                    b.get_mut(..Self::SIZE)?.copy_from_slice(&self.to_le_bytes());
+                    Some(())
+                }
+            }
+        )*
+    };
+}
+
+impl_le_bytes!(u8, u16, u32, u64, i32, i64);
+
+impl<T: LeBytes + Copy + Default, const N: usize> LeBytes for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+    fn from_le(b: &[u8]) -> Option<Self> {
+        let mut out = [T::default(); N];
+        for (i, v) in out.iter_mut().enumerate() {
+            *v = T::from_le(b.get(i * T::SIZE..)?)?;
+        }
+        Some(out)
+    }
+    fn to_le(&self, b: &mut [u8]) -> Option<()> {
+        for (i, v) in self.iter().enumerate() {
+            v.to_le(b.get_mut(i * T::SIZE..)?)?;
+        }
+        Some(())
+    }
+}
+
+/// A fixed-layout record on the disk, declared with [`record!`].
+pub trait Record: Sized {
+    /// Size of the record in bytes.
+    const SIZE: usize;
+    /// Decode the record from the start of `b`.
+    ///
+    /// Returns `None` if `b` is shorter than the record.
+    fn decode(b: &[u8]) -> Option<Self>;
+    /// Encode the record to the start of `b`.
+    ///
+    /// Returns `None` if `b` is shorter than the record.
+    fn encode(&self, b: &mut [u8]) -> Option<()>;
+}
+
+/// Declare a fixed-layout record on the disk.
+///
+/// The fields are [`LeBytes`], laid out in the order of the declaration
+/// without padding. See the [module documentation](crate::record).
+#[macro_export]
+macro_rules! record {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$fmeta:meta])* $fvis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$fmeta])* $fvis $field: $ty),*
+        }
+
+        impl $crate::Record for $name {
+            const SIZE: usize = 0 $(+ <$ty as $crate::LeBytes>::SIZE)*;
+
+            fn decode(b: &[u8]) -> Option<Self> {
+                let mut _ofs = 0;
+                Some(Self {
+                    $($field: {
+                        let v = <$ty as $crate::LeBytes>::from_le(b.get(_ofs..)?)?;
+                        _ofs += <$ty as $crate::LeBytes>::SIZE;
+                        v
+                    }),*
+                })
+            }
+
+            fn encode(&self, b: &mut [u8]) -> Option<()> {
+                let mut _ofs = 0;
+                $(
+                    $crate::LeBytes::to_le(&self.$field, b.get_mut(_ofs..)?)?;
+                    _ofs += <$ty as $crate::LeBytes>::SIZE;
+                )*
+                Some(())
+            }
+        }
+    };
+}
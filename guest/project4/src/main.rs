@@ -159,6 +159,7 @@ mod round_robin {
     use alloc::{collections::VecDeque, format, string::ToString, sync::Arc};
     use keos::{
         intrinsics::cpuid,
+        ncpu,
         sync::SpinLock,
         thread::{scheduler::Scheduler, Thread, ThreadBuilder},
         MAX_CPU,
@@ -193,7 +194,7 @@ mod round_robin {
         let cnt = Arc::new(SpinLock::new(0));
         let scheduler = Arc::new(project1::rr::RoundRobin::new());
         let mut handles = VecDeque::new();
-        for i in 0..MAX_CPU {
+        for i in 0..ncpu() {
             let cnt = cnt.clone();
             let scheduler = scheduler.clone();
             let handle = ThreadBuilder::new(format!("t{}", i)).spawn(move || {
@@ -203,18 +204,18 @@ mod round_robin {
                 {
                     *cnt.lock() += 1;
                 }
-                while *cnt.lock() < MAX_CPU {}
+                while *cnt.lock() < ncpu() {}
 
                 // Generate N-1 Tasks and pull them on a single core.
-                for i in 0..MAX_CPU {
+                for i in 0..ncpu() {
                     if i != cid {
                         let thread = Thread::new(cid.to_string());
                         scheduler.push_to_queue(thread);
                         *cnt.lock() += 1;
-                        while *cnt.lock() < (2 + i) * MAX_CPU {}
+                        while *cnt.lock() < (2 + i) * ncpu() {}
                     } else {
-                        while *cnt.lock() != (2 + i) * MAX_CPU - 1 {}
-                        for _ in 0..MAX_CPU - 1 {
+                        while *cnt.lock() != (2 + i) * ncpu() - 1 {}
+                        for _ in 0..ncpu() - 1 {
                             assert!(scheduler.next_to_run().is_some());
                         }
                         assert!(scheduler.next_to_run().is_none());
@@ -233,7 +234,7 @@ mod round_robin {
         let cnt = Arc::new(SpinLock::new(0));
         let scheduler = Arc::new(project1::rr::RoundRobin::new());
         let mut handles = VecDeque::new();
-        for i in 0..MAX_CPU {
+        for i in 0..ncpu() {
             // Diable all cores' interrupt.
             let cnt = cnt.clone();
             let scheduler = scheduler.clone();
@@ -243,14 +244,14 @@ mod round_robin {
                 {
                     *cnt.lock() += 1;
                 }
-                while *cnt.lock() < MAX_CPU {}
+                while *cnt.lock() < ncpu() {}
 
                 // Now, all cores pushed a dummy thread into their run queue one by one.
                 loop {
                     let mut c = cnt.lock();
-                    if *c >= 5 * MAX_CPU {
+                    if *c >= 5 * ncpu() {
                         break;
-                    } else if *c % MAX_CPU == cid {
+                    } else if *c % ncpu() == cid {
                         scheduler.push_to_queue(Thread::new(cid.to_string()));
                         *c += 1;
                     }
@@ -260,9 +261,9 @@ mod round_robin {
                 loop {
                     let mut c = cnt.lock();
                     // Because each core pushes the thread with same frequency, threads MUST not be moved between queues.
-                    if *c == 9 * MAX_CPU {
+                    if *c == 9 * ncpu() {
                         break;
-                    } else if MAX_CPU - 1 - *c % MAX_CPU == cid {
+                    } else if ncpu() - 1 - *c % ncpu() == cid {
                         assert_eq!(
                             scheduler
                                 .next_to_run()
@@ -0,0 +1,251 @@
+//! Asynchronous hypercalls.
+//!
+//! When keos runs as a guest of KeV, a long hypercall, e.g. a large read of a
+//! shared file, need not stall the vcpu until it completes. An asynchronous
+//! hypercall returns a token at once, and the host does the work on its own
+//! thread. On the completion, the host puts a [`CompletionRecord`] on the
+//! [`CompletionRing`] shared with the guest, and raises the interrupt of the
+//! vector that the guest registered with the [`HC_ASYNC`] hypercall:
+//!
+//! | Register | Value                                                    |
+//! |----------|----------------------------------------------------------|
+//! | rax      | [`HC_ASYNC`] (0x209)                                     |
+//! | rdi      | [`OP_SETUP`]                                             |
+//! | rsi      | guest physical address of the [`CompletionRing`]         |
+//! | rdx      | vector of the completion interrupt                       |
+//!
+//! The hypercall returns 0, or the negated errno in rax. The host produces
+//! the records at the `head` of the ring, and the guest consumes them at the
+//! `tail`; the host fails the asynchronous hypercalls with [`EAGAIN`] while
+//! [`RING_SIZE`] records are in flight, so the ring never overflows.
+//!
+//! A thread waits for the completion of a token, sleeping until the
+//! interrupt delivers its record:
+//!
+//! ```ignore
+//! let token = file.read_async(0, &mut buf)?;
+//! let read = token.wait()?;
+//! ```
+//!
+//! The ring is registered on the first asynchronous hypercall, with the
+//! interrupt of the [`ASYNC_VECTOR`].
+use crate::{
+    sync::SpinLock,
+    thread::{Thread, TimedParkHandle},
+};
+use abyss::addressing::Va;
+use alloc::collections::BTreeMap;
+use core::{
+    arch::asm,
+    sync::atomic::{fence, AtomicU8, Ordering},
+    time::Duration,
+};
+
+/// Hypercall number of the asynchronous hypercalls.
+pub const HC_ASYNC: usize = 0x209;
+/// Register the completion ring.
+pub const OP_SETUP: usize = 0;
+/// Bit of the paravirtual features (CPUID 0x4000_0001:EAX) of the
+/// asynchronous hypercalls.
+pub const FEATURE_ASYNC: u32 = 1 << 25;
+/// Vector of the completion interrupt of keos.
+pub const ASYNC_VECTOR: usize = 103;
+/// Number of the records of the ring.
+pub const RING_SIZE: usize = 128;
+/// The errno of an asynchronous hypercall while the ring is full.
+pub const EAGAIN: usize = 11;
+
+/// A completed asynchronous hypercall.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompletionRecord {
+    /// The token returned by the hypercall.
+    pub token: u64,
+    /// The result, or the negated errno.
+    pub result: u64,
+}
+
+/// The ring of the completions, shared with the host.
+#[repr(C, align(4096))]
+pub struct CompletionRing {
+    /// Number of the records produced by the host.
+    pub head: u32,
+    /// Number of the records consumed by the guest.
+    pub tail: u32,
+    /// Reserved.
+    pub reserved: [u32; 14],
+    /// The records, at the index modulo [`RING_SIZE`].
+    pub records: [CompletionRecord; RING_SIZE],
+}
+
+impl CompletionRing {
+    /// Create an empty ring.
+    pub const fn new() -> Self {
+        Self {
+            head: 0,
+            tail: 0,
+            reserved: [0; 14],
+            records: [CompletionRecord {
+                token: 0,
+                result: 0,
+            }; RING_SIZE],
+        }
+    }
+
+    /// Take the records produced by the host, calling `f` on each.
+    ///
+    /// # Safety
+    /// The `ring` must be valid, and only a single consumer takes the records.
+    pub unsafe fn consume(ring: *mut CompletionRing, mut f: impl FnMut(CompletionRecord)) {
+        let head = core::ptr::addr_of!((*ring).head).read_volatile();
+        fence(Ordering::Acquire);
+        let mut tail = core::ptr::addr_of!((*ring).tail).read_volatile();
+        while tail != head {
+            f(core::ptr::addr_of!((*ring).records[tail as usize % RING_SIZE]).read_volatile());
+            tail = tail.wrapping_add(1);
+        }
+        fence(Ordering::Release);
+        core::ptr::addr_of_mut!((*ring).tail).write_volatile(tail);
+    }
+
+    /// Put the `record`, and returns false if the ring is full.
+    ///
+    /// # Safety
+    /// The `ring` must be valid, and only a single producer puts the records.
+    pub unsafe fn produce(ring: *mut CompletionRing, record: CompletionRecord) -> bool {
+        let head = core::ptr::addr_of!((*ring).head).read_volatile();
+        let tail = core::ptr::addr_of!((*ring).tail).read_volatile();
+        if head.wrapping_sub(tail) as usize >= RING_SIZE {
+            return false;
+        }
+        core::ptr::addr_of_mut!((*ring).records[head as usize % RING_SIZE]).write_volatile(record);
+        fence(Ordering::Release);
+        core::ptr::addr_of_mut!((*ring).head).write_volatile(head.wrapping_add(1));
+        true
+    }
+}
+
+impl Default for CompletionRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut RING: CompletionRing = CompletionRing::new();
+
+// 0 if the ring is not registered yet, 1 if registered, 2 if failed.
+static STATE: AtomicU8 = AtomicU8::new(0);
+
+// The tokens completed but not taken yet, and the threads waiting for them.
+struct Waiters {
+    done: BTreeMap<u64, usize>,
+    waiting: BTreeMap<u64, TimedParkHandle>,
+}
+
+static WAITERS: SpinLock<Waiters> = SpinLock::new(Waiters {
+    done: BTreeMap::new(),
+    waiting: BTreeMap::new(),
+});
+
+// Handle the completion interrupt.
+fn complete() {
+    let mut waiters = WAITERS.lock();
+    unsafe {
+        CompletionRing::consume(core::ptr::addr_of_mut!(RING), |record| {
+            waiters.done.insert(record.token, record.result as usize);
+            if let Some(handle) = waiters.waiting.remove(&record.token) {
+                let _ = handle.unpark();
+            }
+        });
+    }
+}
+
+/// Register the completion ring to the host, if not yet.
+///
+/// Fails with the errno if the host does not support the asynchronous
+/// hypercalls.
+pub fn setup() -> Result<(), usize> {
+    match STATE.load(Ordering::Acquire) {
+        1 => return Ok(()),
+        2 => return Err(crate::hypercall::ENOSYS),
+        _ => (),
+    }
+    if crate::hypercall::pv_features() & FEATURE_ASYNC == 0 {
+        STATE.store(2, Ordering::Release);
+        return Err(crate::hypercall::ENOSYS);
+    }
+    crate::interrupt::register(ASYNC_VECTOR, complete);
+    let ret: usize;
+    unsafe {
+        let gpa = Va::new(core::ptr::addr_of!(RING) as usize)
+            .unwrap()
+            .into_pa()
+            .into_usize();
+        asm!(
+            "vmcall",
+            inout("rax") HC_ASYNC => ret,
+            in("rdi") OP_SETUP,
+            in("rsi") gpa,
+            in("rdx") ASYNC_VECTOR,
+        );
+    }
+    // The registration is idempotent, so a racing cpu may register the ring
+    // again.
+    match ret as isize {
+        e if e < 0 => {
+            STATE.store(2, Ordering::Release);
+            Err(-e as usize)
+        }
+        _ => {
+            STATE.store(1, Ordering::Release);
+            Ok(())
+        }
+    }
+}
+
+/// A token of an asynchronous hypercall in flight.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Token(u64);
+
+impl Token {
+    /// Create the token returned by an asynchronous hypercall.
+    pub fn new(token: u64) -> Self {
+        Self(token)
+    }
+
+    /// Get the raw token.
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+
+    /// Take the result if the hypercall is completed.
+    pub fn poll(&self) -> Option<Result<usize, usize>> {
+        WAITERS.lock().done.remove(&self.0).map(decode)
+    }
+
+    /// Wait until the hypercall is completed, and returns its result.
+    pub fn wait(self) -> Result<usize, usize> {
+        loop {
+            if let Some(result) = self.poll() {
+                return result;
+            }
+            // The record may arrive on another cpu before the thread parks;
+            // the timeout then wakes the thread up.
+            Thread::park_current_timeout(Duration::from_millis(10), |handle| {
+                let mut waiters = WAITERS.lock();
+                if !waiters.done.contains_key(&self.0) {
+                    waiters.waiting.insert(self.0, handle);
+                }
+            });
+        }
+    }
+}
+
+// Decode the result of the negated errno.
+fn decode(result: usize) -> Result<usize, usize> {
+    match result as isize {
+        e if e < 0 => Err(-e as usize),
+        result => Ok(result as usize),
+    }
+}
@@ -0,0 +1,157 @@
+//! Microbenchmark utilities.
+//!
+//! The time is measured in cycles of the time-stamp counter. The reads of the
+//! counter are serialized, so the instructions of the measured code are not
+//! reordered out of the measured interval. The overhead of the timer itself is
+//! measured once and subtracted from each sample.
+//!
+//! ```
+//! let stats = keos::bench!("vmcall", 10000, {
+//!     // Code to measure.
+//! });
+//! ```
+//!
+//! Each benchmark prints a line as it finishes, and [`do_tests`] prints the
+//! summary of the benchmarks that ran with the tests.
+//!
+//! [`do_tests`]: crate::do_tests
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+
+static RESULTS: SpinLock<Vec<Stats>> = SpinLock::new(Vec::new());
+
+/// Read the time-stamp counter at the beginning of the measurement.
+///
+/// Preceding instructions complete before the counter is read.
+#[inline(always)]
+pub fn start() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Read the time-stamp counter at the end of the measurement.
+///
+/// The counter is read after all the preceding instructions complete.
+#[inline(always)]
+pub fn end() -> u64 {
+    unsafe {
+        let mut aux = 0;
+        let tsc = __rdtscp(&mut aux);
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Measure the cycles to run `f` once.
+#[inline(always)]
+pub fn cycles(f: impl FnOnce()) -> u64 {
+    let begin = start();
+    f();
+    end() - begin
+}
+
+/// Statistics of a benchmark in cycles.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Name of the benchmark.
+    pub name: &'static str,
+    /// Number of iterations.
+    pub iterations: usize,
+    /// Minimum.
+    pub min: u64,
+    /// Median.
+    pub median: u64,
+    /// 99th percentile.
+    pub p99: u64,
+    /// Maximum.
+    pub max: u64,
+    /// Arithmetic mean.
+    pub mean: u64,
+}
+
+impl Stats {
+    /// Build the statistics from the `samples`.
+    ///
+    /// Returns `None` if `samples` is empty.
+    pub fn from_samples(name: &'static str, samples: &mut [u64]) -> Option<Self> {
+        let n = samples.len();
+        if n == 0 {
+            return None;
+        }
+        samples.sort_unstable();
+        Some(Self {
+            name,
+            iterations: n,
+            min: samples[0],
+            median: samples[n / 2],
+            p99: samples[(n * 99 / 100).min(n - 1)],
+            max: samples[n - 1],
+            mean: samples.iter().sum::<u64>() / n as u64,
+        })
+    }
+}
+
+impl core::fmt::Display for Stats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "min {} / median {} / p99 {} / max {} cycles",
+            self.min, self.median, self.p99, self.max
+        )?;
+        let khz = abyss::dev::x86_64::timer::tsc_khz();
+        if khz != 0 {
+            write!(f, " (median {} ns)", self.median * 1_000_000 / khz)?;
+        }
+        write!(f, ", {} iterations", self.iterations)
+    }
+}
+
+// Overhead of the timer in cycles.
+fn overhead() -> u64 {
+    let mut samples: Vec<u64> = (0..1000).map(|_| cycles(|| ())).collect();
+    samples.sort_unstable();
+    samples[samples.len() / 2]
+}
+
+/// Run `f` for `iterations` times and report the statistics.
+///
+/// Prefer the [`bench!`] macro.
+///
+/// [`bench!`]: crate::bench!
+pub fn run(name: &'static str, iterations: usize, mut f: impl FnMut()) -> Stats {
+    let overhead = overhead();
+    // Warm up the caches and the branch predictors.
+    for _ in 0..iterations.min(100) {
+        f();
+    }
+    let mut samples: Vec<u64> = (0..iterations.max(1))
+        .map(|_| cycles(&mut f).saturating_sub(overhead))
+        .collect();
+    let stats = Stats::from_samples(name, &mut samples).unwrap();
+    println!("bench {} ... {}", name, stats);
+    RESULTS.lock().push(stats.clone());
+    stats
+}
+
+/// Take the statistics of the benchmarks that ran so far.
+pub fn take_results() -> Vec<Stats> {
+    core::mem::take(&mut *RESULTS.lock())
+}
+
+/// Run a benchmark.
+///
+/// `bench!(name, iterations, body)` runs `body` for `iterations` times and
+/// returns the [`Stats`] in cycles.
+///
+/// [`Stats`]: crate::bench::Stats
+#[macro_export]
+macro_rules! bench {
+    ($name:expr, $iterations:expr, $body:block) => {
+        $crate::bench::run($name, $iterations, || $body)
+    };
+}
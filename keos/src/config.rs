@@ -0,0 +1,177 @@
+//! Boot-time configuration.
+//!
+//! The knobs of the kernel are given as the `key=value` options separated by
+//! the whitespaces, on the command line from the bootloader or in the
+//! [`CONFIG_FILE`] on the filesystem disk. The command line overrides the
+//! file:
+//!
+//! | Option       | Description                                     | Example             |
+//! |--------------|-------------------------------------------------|---------------------|
+//! | `log`        | `debug`, `info`, `warning` or `off`.            | `log=warning`       |
+//! | `test`       | Run only the tests whose names contain it.      | `test=page_walk`    |
+//! | `sched`      | The scheduler, picked by the projects.          | `sched=stride`      |
+//! | `vm_mem`     | The default memory of the vms; `K`, `M` or `G`. | `vm_mem=64M`        |
+//! | `panic`      | `halt`, `reboot`, `exit` or `monitor`.          | `panic=exit`        |
+//! | `irq`        | `bsp`, `round-robin` or `least-loaded`.         | `irq=bsp`           |
+//! | `disk_cache` | `writethrough`, `writeback` or `unsafe`.        | `disk_cache=unsafe` |
+//!
+//! The other options are kept as they are, and read with [`BootConfig::get`]:
+//!
+//! ```ignore
+//! let ram_in_kib = keos::config::boot_config().vm_memory_kib.unwrap_or(256 * 1024);
+//! ```
+use crate::{
+    fs::file_system,
+    panicking::{set_panic_policy, PanicPolicy},
+};
+use abyss::kprint::LogLevel;
+use alloc::{collections::BTreeMap, string::String, vec};
+
+/// Name of the configuration file on the filesystem disk.
+pub const CONFIG_FILE: &str = "keos.cfg";
+
+/// The configuration of the kernel.
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    /// The lowest level of the log messages.
+    pub log_level: LogLevel,
+    /// Run only the tests whose names contain the filter.
+    pub test_filter: Option<String>,
+    /// The name of the scheduler.
+    pub scheduler: Option<String>,
+    /// The default memory of the vms in KiB.
+    pub vm_memory_kib: Option<usize>,
+    /// The policy on a panic.
+    pub panic_policy: PanicPolicy,
+    // The unknown options.
+    extra: BTreeMap<String, String>,
+}
+
+impl BootConfig {
+    /// The configuration without any option.
+    pub const fn new() -> Self {
+        Self {
+            log_level: LogLevel::Debug,
+            test_filter: None,
+            scheduler: None,
+            vm_memory_kib: None,
+            panic_policy: PanicPolicy::Halt,
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Apply the options in `line` over the configuration.
+    ///
+    /// A malformed option is ignored with a warning.
+    pub fn parse(self, line: &str) -> Self {
+        self.parse_options(line, true)
+    }
+
+    fn parse_options(mut self, line: &str, warn: bool) -> Self {
+        for option in line.split_whitespace() {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            if self.apply(key, value).is_none() && warn {
+                warning!("Ignoring the malformed boot option `{}`.", option);
+            }
+        }
+        self
+    }
+
+    fn apply(&mut self, key: &str, value: &str) -> Option<()> {
+        match key {
+            "log" => {
+                self.log_level = match value {
+                    "debug" => LogLevel::Debug,
+                    "info" => LogLevel::Info,
+                    "warning" => LogLevel::Warning,
+                    "off" => LogLevel::Off,
+                    _ => return None,
+                }
+            }
+            "test" => self.test_filter = Some(String::from(value)),
+            "sched" => self.scheduler = Some(String::from(value)),
+            "vm_mem" => self.vm_memory_kib = Some(parse_kib(value)?),
+            "panic" => {
+                self.panic_policy = match value {
+                    "halt" => PanicPolicy::Halt,
+                    "reboot" => PanicPolicy::Reboot,
+                    "exit" => PanicPolicy::ExitQemu,
+                    "monitor" => PanicPolicy::Monitor,
+                    _ => return None,
+                }
+            }
+            _ => {
+                self.extra.insert(String::from(key), String::from(value));
+            }
+        }
+        Some(())
+    }
+
+    /// Get the value of the option `key` that the kernel does not know.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.extra.get(key).map(String::as_str)
+    }
+
+    /// Check whether the test `name` passes the test filter.
+    pub fn run_test(&self, name: &str) -> bool {
+        self.test_filter
+            .as_ref()
+            .map_or(true, |filter| name.contains(filter.as_str()))
+    }
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Parse the size in KiB, with an optional suffix.
+fn parse_kib(value: &str) -> Option<usize> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 0),
+        b'M' | b'm' => (&value[..value.len() - 1], 10),
+        b'G' | b'g' => (&value[..value.len() - 1], 20),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+static mut CONFIG: BootConfig = BootConfig::new();
+
+/// Parse the command line into the configuration.
+///
+/// # Safety
+/// Called on the boot, before any other thread runs.
+pub(crate) unsafe fn init() {
+    CONFIG = BootConfig::new().parse(abyss::boot::cmdline());
+    abyss::kprint::set_log_level(CONFIG.log_level);
+    set_panic_policy(CONFIG.panic_policy);
+}
+
+/// Apply the [`CONFIG_FILE`] under the command line.
+///
+/// # Safety
+/// Called on the boot after the filesystem is initialized, before any other
+/// thread runs.
+pub(crate) unsafe fn load_file() {
+    let Some(file) = file_system().and_then(|fs| fs.open(CONFIG_FILE)) else {
+        return;
+    };
+    let mut buf = vec![0; file.size()];
+    if file.read(0, &mut buf).is_err() {
+        warning!("Failed to read {}.", CONFIG_FILE);
+        return;
+    }
+    // The command line is already warned on the boot.
+    CONFIG = BootConfig::new()
+        .parse(&String::from_utf8_lossy(&buf))
+        .parse_options(abyss::boot::cmdline(), false);
+    abyss::kprint::set_log_level(CONFIG.log_level);
+    set_panic_policy(CONFIG.panic_policy);
+}
+
+/// Get the configuration of the kernel.
+pub fn boot_config() -> &'static BootConfig {
+    unsafe { &*core::ptr::addr_of!(CONFIG) }
+}
@@ -0,0 +1,123 @@
+//! Fault injection.
+//!
+//! The error paths of the device and the memory backends are rarely exercised,
+//! as the disk and the allocator hardly fail. A [`FaultInjector`] makes the
+//! operations of a backend fail on purpose, either randomly with the given
+//! probability or at the scripted operations:
+//!
+//! ```
+//! // Fail 1% of the operations, and always the operations 3 and 10.
+//! let faults = keos::fault::FaultInjector::new()
+//!     .probability(1, 100)
+//!     .seed(0xcafe)
+//!     .fail_at([3, 10]);
+//! ```
+//!
+//! The backends take the injector through their builders and consult
+//! [`FaultInjector::should_fail`] on each operation. The random faults are
+//! drawn from a seeded generator, so a run is reproducible with the same seed.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Decides whether an operation fails.
+///
+/// The default injector never fails.
+pub struct FaultInjector {
+    // Fail with the probability of num / den.
+    num: u32,
+    den: u32,
+    // Sorted indices of the operations to fail.
+    script: Vec<usize>,
+    ops: AtomicUsize,
+    injected: AtomicUsize,
+    // State of the xorshift generator.
+    rng: AtomicU64,
+}
+
+impl FaultInjector {
+    /// Create a new injector that never fails.
+    pub const fn new() -> Self {
+        Self {
+            num: 0,
+            den: 1,
+            script: Vec::new(),
+            ops: AtomicUsize::new(0),
+            injected: AtomicUsize::new(0),
+            rng: AtomicU64::new(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+
+    /// Fail each operation with the probability of `num` / `den`.
+    pub fn probability(mut self, num: u32, den: u32) -> Self {
+        assert!(den != 0 && num <= den);
+        self.num = num;
+        self.den = den;
+        self
+    }
+
+    /// Seed the generator of the random faults.
+    pub fn seed(self, seed: u64) -> Self {
+        // Zero is the fixed point of xorshift.
+        self.rng.store(seed | 1, Ordering::Relaxed);
+        self
+    }
+
+    /// Fail the operations of the `indices`, counted from 0.
+    pub fn fail_at(mut self, indices: impl IntoIterator<Item = usize>) -> Self {
+        self.script.extend(indices);
+        self.script.sort_unstable();
+        self.script.dedup();
+        self
+    }
+
+    /// Check whether this injector can fail any operation.
+    pub fn is_enabled(&self) -> bool {
+        self.num != 0 || !self.script.is_empty()
+    }
+
+    /// Count an operation, and decide whether it fails.
+    pub fn should_fail(&self) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let op = self.ops.fetch_add(1, Ordering::Relaxed);
+        let fail = self.script.binary_search(&op).is_ok() || self.roll();
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+
+    /// Number of the operations counted so far.
+    pub fn operations(&self) -> usize {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    /// Number of the faults injected so far.
+    pub fn injected(&self) -> usize {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    fn roll(&self) -> bool {
+        if self.num == 0 {
+            return false;
+        }
+        let next = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let prev = self
+            .rng
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(next(x)))
+            .unwrap();
+        next(prev) % (self.den as u64) < self.num as u64
+    }
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,119 @@
+//! Filesystem implementation.
+//!
+//! This filesystem only supported fixed-size file. (No directory!)
+//!
+//! The files shared by the hypervisor are accessed with [`shared`], and the
+//! sectors after the file system keep the log of the last panic; see
+//! [`pstore`].
+pub mod pstore;
+pub mod shared;
+
+pub use simple_fs::*;
+
+use crate::fault::FaultInjector;
+
+/// The filesystem disk.
+pub struct FsDisk {
+    read_faults: FaultInjector,
+    write_faults: FaultInjector,
+}
+
+impl FsDisk {
+    /// Create a new filesystem disk on the second block device.
+    pub const fn new() -> Self {
+        Self {
+            read_faults: FaultInjector::new(),
+            write_faults: FaultInjector::new(),
+        }
+    }
+
+    /// Fail the reads of the disk with the `faults`.
+    pub fn read_faults(mut self, faults: FaultInjector) -> Self {
+        self.read_faults = faults;
+        self
+    }
+
+    /// Fail the writes of the disk with the `faults`.
+    pub fn write_faults(mut self, faults: FaultInjector) -> Self {
+        self.write_faults = faults;
+        self
+    }
+}
+
+impl Default for FsDisk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disk for FsDisk {
+    fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+        if self.read_faults.should_fail() {
+            return Err(Error::DiskError);
+        }
+        let dev = abyss::dev::get_bdev(1).ok_or(Error::DiskError)?;
+        dev.read_bios(&mut Some((512 * sector.into_usize(), buf.as_mut())).into_iter())
+            .map_err(|_| Error::DiskError)
+    }
+    fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        if self.write_faults.should_fail() {
+            return Err(Error::DiskError);
+        }
+        let dev = abyss::dev::get_bdev(1).ok_or(Error::DiskError)?;
+        dev.write_bios(&mut Some((512 * sector.into_usize(), buf.as_ref())).into_iter())
+            .map_err(|_| Error::DiskError)
+    }
+}
+
+static mut FS: Option<FileSystem<FsDisk>> = None;
+
+/// Initialize the fs.
+pub unsafe fn init_fs() {
+    if mount(FsDisk::new()).is_err() {
+        warning!("Failed to open fs.");
+    } else if let Some(fs) = file_system() {
+        pstore::init(fs.size());
+    }
+}
+
+/// Mount the filesystem on the `disk`, replacing the current one.
+///
+/// This is for remounting the fs on a disk that injects the faults.
+///
+/// # Safety
+/// The files opened from the previous filesystem must not be used afterwards.
+pub unsafe fn mount(disk: FsDisk) -> Result<(), Error> {
+    FS = Some(FileSystem::load(disk)?);
+    Ok(())
+}
+
+/// Get a filesystem reference of the kernel.
+pub fn file_system() -> Option<&'static FileSystem<FsDisk>> {
+    unsafe { FS.as_ref() }
+}
+
+/// The file.
+pub type File = simple_fs::File<'static, FsDisk>;
+
+/// A disk on a file, e.g. the disk of a guest.
+///
+/// Wrap it with a [`cache::CachedDisk`] to cache the sectors.
+pub struct FileDisk(pub File);
+
+impl Disk for FileDisk {
+    fn read(&self, sector: Sector, buf: &mut [u8; 512]) -> Result<(), Error> {
+        match self.0.read(sector.into_offset(), buf)? {
+            512 => Ok(()),
+            _ => Err(Error::DiskError),
+        }
+    }
+    fn write(&self, sector: Sector, buf: &[u8; 512]) -> Result<(), Error> {
+        match self.0.write(sector.into_offset(), buf)? {
+            512 => Ok(()),
+            _ => Err(Error::DiskError),
+        }
+    }
+    fn flush(&self) -> Result<(), Error> {
+        self.0.flush()
+    }
+}
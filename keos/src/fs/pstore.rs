@@ -0,0 +1,173 @@
+//! Persistent log of the panics.
+//!
+//! The serial output of a panic is lost once qemu restarts. The panic handler
+//! also writes the location, the message and the backtrace of the panic to the
+//! [`RESERVED_SECTORS`] at the end of the fs disk, after the file system, and
+//! the next boot prints them:
+//!
+//! ```text
+//! [WARN] Previous boot crashed at keos/src/thread/mod.rs:120:9: not runnable
+//! Backtrace of the previous boot:
+//!    1: 0xffffff0000123456  - keos::thread::Thread::run
+//! ```
+//!
+//! The log is printed once; the boot clears it, and [`previous`] keeps it
+//! until the next boot.
+//!
+//! The log is written from the panic path with the polling I/O of the disk,
+//! without the allocator and without waiting for a lock. If the panicked
+//! thread holds the disk, the log is not written. The message is written
+//! before the header, and the header has the checksum of the message, so a
+//! log torn by a crash while writing it is ignored.
+//!
+//! The disks built by [`ImageBuilder`] have the reserved sectors; on the
+//! older disks, the log is off.
+//!
+//! [`ImageBuilder`]: simple_fs::image::ImageBuilder
+use super::{Disk, FsDisk, Sector, RESERVED_SECTORS};
+use crate::{panicking::FRAMES_MAX, sync::SpinLock};
+use alloc::string::String;
+use core::{
+    fmt::{self, Write},
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use simple_fs::{record, Record};
+
+/// Maximum length of the logged message.
+pub const LOG_MAX: usize = (RESERVED_SECTORS - 1) * 512;
+
+const MAGIC: [u8; 8] = *b"KEOSLOG\0";
+
+record! {
+    // The header of the log, at the first reserved sector.
+    struct Header {
+        magic: [u8; 8],
+        // Length of the message.
+        len: u64,
+        // FNV-1a of the message.
+        checksum: u32,
+        // The pcs of the backtrace.
+        depth: u64,
+        pcs: [u64; FRAMES_MAX],
+    }
+}
+
+// The first reserved sector, or 0 if the log is off.
+static START: AtomicUsize = AtomicUsize::new(0);
+
+static PREVIOUS: SpinLock<Option<String>> = SpinLock::new(None);
+
+fn checksum(b: &[u8]) -> u32 {
+    b.iter().fold(0x811c_9dc5u32, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+// The message, truncated to the reserved sectors.
+struct Log {
+    buf: [u8; LOG_MAX],
+    len: usize,
+}
+
+impl Write for Log {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(LOG_MAX - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Find the reserved sectors after the `fs_size` bytes of the file system,
+/// and print the log of the previous boot.
+pub(crate) fn init(fs_size: usize) {
+    let Some(dev) = abyss::dev::get_bdev(1) else {
+        return;
+    };
+    let start = fs_size.div_ceil(512);
+    if dev.block_cnt() < start + RESERVED_SECTORS {
+        return;
+    }
+    let disk = FsDisk::new();
+    let mut buf = [0; 512];
+    if disk.read(Sector(start), &mut buf).is_err() {
+        return;
+    }
+    START.store(start, Ordering::SeqCst);
+    let header = match Header::decode(&buf) {
+        Some(header) if header.magic == MAGIC && header.len as usize <= LOG_MAX => header,
+        _ => return,
+    };
+    let mut log = alloc::vec![0; LOG_MAX];
+    for (i, chunk) in log.chunks_mut(512).enumerate() {
+        if disk
+            .read(Sector(start + 1 + i), chunk.try_into().unwrap())
+            .is_err()
+        {
+            return;
+        }
+    }
+    log.truncate(header.len as usize);
+    if checksum(&log) == header.checksum {
+        let log = String::from_utf8_lossy(&log).into_owned();
+        warning!("Previous boot crashed at {}", log);
+        println!("Backtrace of the previous boot:");
+        for (depth, pc) in header.pcs.iter().take(header.depth as usize).enumerate() {
+            crate::panicking::print_frame(depth + 1, *pc as usize);
+        }
+        *PREVIOUS.lock() = Some(log);
+    }
+    // Print the log only once.
+    let _ = disk.write(Sector(start), &[0; 512]);
+}
+
+/// Get the location and the message of the panic of the previous boot, or
+/// `None` if it did not panic.
+pub fn previous() -> Option<String> {
+    PREVIOUS.lock().clone()
+}
+
+/// Write the panic at the `location` with the `message` and the `pcs` of the
+/// backtrace to the log.
+///
+/// This runs on the panic path.
+pub(crate) fn write(location: Option<&Location>, message: Option<&fmt::Arguments>, pcs: &[u64]) {
+    let start = START.load(Ordering::SeqCst);
+    let Some(dev) = abyss::dev::get_bdev(1).filter(|_| start != 0) else {
+        return;
+    };
+    let mut log = Log {
+        buf: [0; LOG_MAX],
+        len: 0,
+    };
+    let _ = match location {
+        Some(location) => write!(log, "{}", location),
+        None => write!(log, "an unknown location"),
+    };
+    if let Some(message) = message {
+        let _ = write!(log, ": {}", message);
+    }
+    let mut header = Header {
+        magic: MAGIC,
+        len: log.len as u64,
+        checksum: checksum(&log.buf[..log.len]),
+        depth: pcs.len().min(FRAMES_MAX) as u64,
+        pcs: [0; FRAMES_MAX],
+    };
+    header.pcs[..header.depth as usize].copy_from_slice(&pcs[..header.depth as usize]);
+    let mut buf = [0; 512];
+    if header.encode(&mut buf).is_none() {
+        return;
+    }
+    let sectors = log.len.div_ceil(512);
+    let written = dev.try_write_bios(
+        &mut log.buf[..sectors * 512]
+            .chunks(512)
+            .enumerate()
+            .map(|(i, chunk)| ((start + 1 + i) * 512, chunk)),
+    );
+    if written.is_ok() {
+        let _ = dev.try_write_bios(&mut Some((start * 512, buf.as_ref())).into_iter());
+    }
+}
@@ -0,0 +1,217 @@
+//! Folder shared by the hypervisor.
+//!
+//! When keos runs as a guest, the hypervisor may share a folder of its file
+//! system with the [`HC_SHARED_FS`] hypercall. The files of the folder are
+//! accessed like the files of the local file system, without baking them into
+//! the disk image:
+//!
+//! ```ignore
+//! for name in keos::fs::shared::files()? {
+//!     let file = keos::fs::shared::SharedFile::open(&name)?;
+//!     let mut buf = alloc::vec![0; file.size()];
+//!     file.read(0, &mut buf)?;
+//! }
+//! ```
+//!
+//! The buffers are passed to the hypervisor by their physical addresses, so
+//! they must be in the kernel memory (the heap or the thread stacks).
+use crate::async_call::{self, Token};
+use alloc::{string::String, vec::Vec};
+use core::{arch::asm, marker::PhantomData};
+
+/// Hypercall number of the shared folder.
+pub const HC_SHARED_FS: usize = 0x202;
+
+/// Open a file.
+pub const OP_OPEN: usize = 0;
+/// Read from a file.
+pub const OP_READ: usize = 1;
+/// Write to a file.
+pub const OP_WRITE: usize = 2;
+/// Close a file.
+pub const OP_CLOSE: usize = 3;
+/// Get the name of a file in the folder.
+pub const OP_LIST: usize = 4;
+/// Read from a file asynchronously.
+pub const OP_READ_ASYNC: usize = 5;
+
+/// Error of an operation on the shared folder, returned as the negated value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SharedFsError {
+    /// No such file.
+    NoEntry = 2,
+    /// The file system of the host failed.
+    Io = 5,
+    /// Too many asynchronous reads are in flight.
+    Again = 11,
+    /// Bad handle.
+    BadHandle = 9,
+    /// The buffer is not mapped to the guest.
+    BadAddress = 14,
+    /// Bad operation or argument.
+    Invalid = 22,
+}
+
+impl SharedFsError {
+    /// Decode the error from the `errno`.
+    pub fn from_errno(errno: usize) -> Self {
+        match errno {
+            2 => Self::NoEntry,
+            11 => Self::Again,
+            9 => Self::BadHandle,
+            14 => Self::BadAddress,
+            22 => Self::Invalid,
+            _ => Self::Io,
+        }
+    }
+}
+
+// Issue the hypercall, and returns rax and rdx.
+fn hypercall(op: usize, args: [usize; 4]) -> Result<(usize, usize), SharedFsError> {
+    let (rax, rdx): (usize, usize);
+    unsafe {
+        asm!(
+            "vmcall",
+            inout("rax") HC_SHARED_FS => rax,
+            in("rdi") op,
+            in("rsi") args[0],
+            inout("rdx") args[1] => rdx,
+            in("rcx") args[2],
+            in("r8") args[3],
+        );
+    }
+    match rax as isize {
+        e if e < 0 => Err(SharedFsError::from_errno(-e as usize)),
+        _ => Ok((rax, rdx)),
+    }
+}
+
+// Get the guest physical address of the kernel buffer.
+fn gpa(buf: &[u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    unsafe {
+        abyss::addressing::Va::new(buf.as_ptr() as usize)
+            .unwrap()
+            .into_pa()
+            .into_usize()
+    }
+}
+
+/// Get the names of the files in the shared folder.
+pub fn files() -> Result<Vec<String>, SharedFsError> {
+    let mut files = Vec::new();
+    let mut buf = alloc::vec![0; 512];
+    loop {
+        let len = match hypercall(
+            OP_LIST,
+            [files.len(), gpa(buf.as_mut_slice()), buf.len(), 0],
+        ) {
+            Ok((len, _)) => len,
+            Err(SharedFsError::NoEntry) => return Ok(files),
+            Err(e) => return Err(e),
+        };
+        let name = buf.get(..len).ok_or(SharedFsError::Invalid)?;
+        files.push(String::from_utf8_lossy(name).into_owned());
+    }
+}
+
+/// A file in the shared folder.
+///
+/// The file is closed when dropped.
+pub struct SharedFile {
+    name: String,
+    handle: usize,
+    size: usize,
+}
+
+impl SharedFile {
+    /// Open the file `name` in the shared folder.
+    pub fn open(name: &str) -> Result<Self, SharedFsError> {
+        let name = String::from(name);
+        let (handle, size) = hypercall(OP_OPEN, [gpa(name.as_bytes()), name.len(), 0, 0])?;
+        Ok(Self { name, handle, size })
+    }
+
+    /// Get name of this file.
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    /// Get size of this file.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read from file starting from `ofs` to `contents`.
+    pub fn read(&self, ofs: usize, contents: &mut [u8]) -> Result<usize, SharedFsError> {
+        hypercall(OP_READ, [self.handle, ofs, gpa(contents), contents.len()]).map(|(len, _)| len)
+    }
+
+    /// Read from file starting from `ofs` to `contents`, without stalling
+    /// the vcpu while the host reads the file.
+    ///
+    /// The `contents` is borrowed until the read completes. See
+    /// [`async_call`] for the details.
+    pub fn read_async<'a>(
+        &self,
+        ofs: usize,
+        contents: &'a mut [u8],
+    ) -> Result<PendingRead<'a>, SharedFsError> {
+        async_call::setup().map_err(SharedFsError::from_errno)?;
+        let (token, _) = hypercall(
+            OP_READ_ASYNC,
+            [self.handle, ofs, gpa(contents), contents.len()],
+        )?;
+        Ok(PendingRead {
+            token: Some(Token::new(token as u64)),
+            _contents: PhantomData,
+        })
+    }
+
+    /// Write to file starting from `ofs` from `contents`.
+    ///
+    /// The file does not grow; the bytes past the end of the file are not
+    /// written.
+    pub fn write(&self, ofs: usize, contents: &[u8]) -> Result<usize, SharedFsError> {
+        hypercall(OP_WRITE, [self.handle, ofs, gpa(contents), contents.len()]).map(|(len, _)| len)
+    }
+}
+
+impl Drop for SharedFile {
+    fn drop(&mut self) {
+        let _ = hypercall(OP_CLOSE, [self.handle, 0, 0, 0]);
+    }
+}
+
+/// A read of a shared file in flight.
+///
+/// Dropping the read waits until it completes, as the host writes to the
+/// buffer until then.
+pub struct PendingRead<'a> {
+    token: Option<Token>,
+    _contents: PhantomData<&'a mut [u8]>,
+}
+
+impl PendingRead<'_> {
+    /// Wait until the read completes, and returns the number of the bytes
+    /// read.
+    pub fn wait(mut self) -> Result<usize, SharedFsError> {
+        self.token
+            .take()
+            .unwrap()
+            .wait()
+            .map_err(SharedFsError::from_errno)
+    }
+}
+
+impl Drop for PendingRead<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            let _ = token.wait();
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Cpu hotplug of a guest keos.
+//!
+//! When keos runs as a guest of KeV, the host may add or remove the vcpus at
+//! runtime. The host notifies the bsp with an interrupt, and the guest takes
+//! the events with the [`HC_HOTPLUG`] hypercall:
+//!
+//! | Register | Value                                                    |
+//! |----------|----------------------------------------------------------|
+//! | rax      | [`HC_HOTPLUG`] (0x206)                                   |
+//! | rdi      | [`OP_EVENT`] or [`OP_OFFLINE`]                           |
+//!
+//! [`OP_EVENT`] returns the kind of the next event in rax and the vcpu id in
+//! rdx, or [`EVENT_NONE`] if there is none:
+//!
+//! - [`EVENT_ADDED`]: the vcpu is present, and is started with the INIT-SIPI
+//!   sequence as the cpus at the boot.
+//! - [`EVENT_REMOVE`]: the host asks to remove the vcpu. The guest migrates
+//!   the work off the cpu, and the cpu itself issues [`OP_OFFLINE`], which
+//!   does not return. This is the quiesce handshake; the vcpu is not removed
+//!   until the guest agrees.
+//!
+//! The present vcpus are reported by the CPUID leaf [`TOPOLOGY_LEAF`]: eax
+//! and ebx are the low and the high 32 bits of the bitmask of the present
+//! vcpus, and ecx is the number of the vcpu slots.
+//!
+//! ```ignore
+//! while let Some(event) = keos::hotplug::next_event() {
+//!     match event {
+//!         HotplugEvent::Added(id) => start_ap(id),
+//!         HotplugEvent::Remove(id) => run_on(id, || keos::hotplug::offline()),
+//!     }
+//! }
+//! ```
+use core::arch::{asm, x86_64::__cpuid};
+
+/// Hypercall number of the cpu hotplug.
+pub const HC_HOTPLUG: usize = 0x206;
+/// Take the next hotplug event.
+pub const OP_EVENT: usize = 0;
+/// Remove the current vcpu, after the host asked to.
+pub const OP_OFFLINE: usize = 1;
+
+/// No event is pending.
+pub const EVENT_NONE: usize = 0;
+/// A vcpu is added.
+pub const EVENT_ADDED: usize = 1;
+/// The host asks to remove a vcpu.
+pub const EVENT_REMOVE: usize = 2;
+
+/// The CPUID leaf that reports the present vcpus.
+pub const TOPOLOGY_LEAF: u32 = 0x4000_0003;
+
+/// A hotplug event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HotplugEvent {
+    /// The vcpu of the id is added.
+    Added(usize),
+    /// The host asks to remove the vcpu of the id.
+    Remove(usize),
+}
+
+impl HotplugEvent {
+    /// Decode the event from the `kind` and the vcpu `id`.
+    pub fn from_raw(kind: usize, id: usize) -> Option<Self> {
+        match kind {
+            EVENT_ADDED => Some(Self::Added(id)),
+            EVENT_REMOVE => Some(Self::Remove(id)),
+            _ => None,
+        }
+    }
+
+    /// Encode the event into the kind and the vcpu id.
+    pub fn into_raw(self) -> (usize, usize) {
+        match self {
+            Self::Added(id) => (EVENT_ADDED, id),
+            Self::Remove(id) => (EVENT_REMOVE, id),
+        }
+    }
+}
+
+/// Take the next hotplug event from the hypervisor.
+pub fn next_event() -> Option<HotplugEvent> {
+    let (kind, id): (usize, usize);
+    unsafe {
+        asm!(
+            "vmcall",
+            inout("rax") HC_HOTPLUG => kind,
+            in("rdi") OP_EVENT,
+            out("rdx") id,
+        );
+    }
+    HotplugEvent::from_raw(kind, id)
+}
+
+/// Remove the current cpu, after the host asked to with
+/// [`HotplugEvent::Remove`].
+///
+/// The cpu must have no work left, and runs with the interrupts off.
+pub fn offline() -> ! {
+    unsafe {
+        asm!("cli");
+        asm!(
+            "vmcall",
+            inout("rax") HC_HOTPLUG => _,
+            in("rdi") OP_OFFLINE,
+        );
+        // The host refused, e.g. it did not ask to remove this cpu.
+        loop {
+            asm!("hlt");
+        }
+    }
+}
+
+/// Get the bitmask of the present vcpus.
+pub fn present_cpus() -> u64 {
+    let leaf = unsafe { __cpuid(TOPOLOGY_LEAF) };
+    leaf.eax as u64 | (leaf.ebx as u64) << 32
+}
@@ -0,0 +1,182 @@
+//! Batched hypercalls.
+//!
+//! When keos runs as a guest of KeV, each hypercall costs a vmexit. A guest
+//! that issues many small hypercalls in a row queues them in a [`Batch`], and
+//! the host executes them all in a single exit with the [`HC_BATCH`]
+//! hypercall:
+//!
+//! | Register | Value                                                    |
+//! |----------|----------------------------------------------------------|
+//! | rax      | [`HC_BATCH`] (0x208)                                     |
+//! | rdi      | guest physical address of the [`HypercallEntry`] array   |
+//! | rsi      | number of the entries, at most [`MAX_BATCH`]             |
+//!
+//! Each entry is executed as if the guest issued the hypercall `nr` with the
+//! arguments in rdi, rsi, rdx, rcx and r8, and its rax and rdx are written
+//! back to the entry. The hypercall returns the number of the executed
+//! entries in rax, or the negated errno. An entry of an unknown hypercall
+//! fails with [`ENOSYS`]; a batch can not be nested.
+//!
+//! ```ignore
+//! let mut batch = Batch::new();
+//! for (handle, ofs, buf) in requests {
+//!     batch.push(HC_SHARED_FS, [OP_READ, handle, ofs, gpa(buf), buf.len()]);
+//! }
+//! for entry in batch.submit()? {
+//!     println!("read {} bytes", entry.rax);
+//! }
+//! ```
+//!
+//! The hypercalls that do not return to the guest, e.g. the shutdown, end the
+//! batch; the later entries are not executed. The host that supports the
+//! batch reports [`FEATURE_BATCH`] in its [`pv_features`].
+use abyss::addressing::Va;
+use alloc::vec::Vec;
+use core::arch::{asm, x86_64::__cpuid};
+
+/// Hypercall number of the batch.
+pub const HC_BATCH: usize = 0x208;
+/// Maximum number of the entries of a [`HC_BATCH`] hypercall.
+pub const MAX_BATCH: usize = 64;
+/// The errno of an entry of an unknown hypercall.
+pub const ENOSYS: usize = 38;
+/// Bit of the paravirtual features (CPUID 0x4000_0001:EAX) of the batch.
+pub const FEATURE_BATCH: u32 = 1 << 26;
+
+/// Get the paravirtual features of KeV (CPUID 0x4000_0001:EAX), or 0 if keos
+/// does not run on KeV.
+pub fn pv_features() -> u32 {
+    unsafe {
+        if __cpuid(1).ecx & (1 << 31) == 0 {
+            return 0;
+        }
+        let signature = __cpuid(0x4000_0000);
+        if [signature.ebx, signature.ecx, signature.edx]
+            .iter()
+            .flat_map(|r| r.to_le_bytes())
+            .ne(*b"KeVKeVKeV\0\0\0")
+        {
+            return 0;
+        }
+        __cpuid(0x4000_0001).eax
+    }
+}
+
+/// A hypercall in a batch.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HypercallEntry {
+    /// The hypercall number, passed in rax.
+    pub nr: u64,
+    /// The arguments, passed in rdi, rsi, rdx, rcx and r8.
+    pub args: [u64; 5],
+    /// The returned rax.
+    pub rax: u64,
+    /// The returned rdx.
+    pub rdx: u64,
+}
+
+impl HypercallEntry {
+    /// Create an entry of the hypercall `nr` with the `args`.
+    pub fn new(nr: usize, args: [usize; 5]) -> Self {
+        Self {
+            nr: nr as u64,
+            args: args.map(|arg| arg as u64),
+            rax: 0,
+            rdx: 0,
+        }
+    }
+
+    /// Get the returned rax as a result, decoding the negated errno.
+    pub fn result(&self) -> Result<usize, usize> {
+        match self.rax as isize {
+            e if e < 0 => Err(-e as usize),
+            rax => Ok(rax as usize),
+        }
+    }
+}
+
+/// A batch of the hypercalls.
+///
+/// The entries are passed to the host by their physical addresses, so the
+/// batch lives in the kernel heap.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    entries: Vec<HypercallEntry>,
+}
+
+impl Batch {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the hypercall `nr` with the `args`, and returns its index.
+    pub fn push(&mut self, nr: usize, args: [usize; 5]) -> usize {
+        self.entries.push(HypercallEntry::new(nr, args));
+        self.entries.len() - 1
+    }
+
+    /// Get the number of the queued hypercalls.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the entries, with their results after [`Batch::submit`].
+    pub fn entries(&self) -> &[HypercallEntry] {
+        &self.entries
+    }
+
+    /// Execute the queued hypercalls, in chunks of [`MAX_BATCH`] entries.
+    ///
+    /// Returns the executed entries with their results; the batch is cut
+    /// short if a hypercall ends it. Fails with the errno if the host rejects
+    /// the batch, e.g. the entries are not mapped to the guest.
+    pub fn submit(&mut self) -> Result<&[HypercallEntry], usize> {
+        let mut done = 0;
+        while done < self.entries.len() {
+            let chunk = &mut self.entries[done..];
+            let len = chunk.len().min(MAX_BATCH);
+            let n = submit(&mut chunk[..len])?;
+            done += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(&self.entries[..done])
+    }
+
+    /// Remove all the entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Issue the batch of the `entries`, and returns the number of the executed
+// entries.
+fn submit(entries: &mut [HypercallEntry]) -> Result<usize, usize> {
+    let gpa = unsafe {
+        Va::new(entries.as_mut_ptr() as usize)
+            .unwrap()
+            .into_pa()
+            .into_usize()
+    };
+    let rax: usize;
+    unsafe {
+        asm!(
+            "vmcall",
+            inout("rax") HC_BATCH => rax,
+            in("rdi") gpa,
+            in("rsi") entries.len(),
+        );
+    }
+    match rax as isize {
+        e if e < 0 => Err(-e as usize),
+        n => Ok(n as usize),
+    }
+}
@@ -1,14 +1,165 @@
 //! Interrupt management.
-use crate::sync::SpinLock;
+//!
+//! Every external interrupt vector from 32 to 255 is dispatched to the handler
+//! registered on the vector. The fixed vectors, such as the timer (32), are
+//! registered with [`register`] on the boot. The drivers claim a vector at
+//! runtime:
+//!
+//! ```ignore
+//! let vector = interrupt::allocate_vector().expect("No free vector.");
+//! interrupt::register_handler(vector, move || dev.handle_irq())?;
+//! // Program the vector into the device, e.g. the msi data register.
+//! ```
+//!
+//! An allocated vector is routed to a cpu by the policy of the `irq` boot
+//! option: `bsp` routes all of them to the cpu 0, `round-robin` (the default)
+//! spreads them over the cpus, and `least-loaded` picks the cpu that handled
+//! the fewest device interrupts. A driver that signals the vector with a
+//! message signaled interrupt registers how to program the message, and the
+//! vector then follows [`set_affinity`]:
+//!
+//! ```ignore
+//! interrupt::set_msi_handler(vector, move |msg| dev.program_msix(0, msg.address, msg.data));
+//! interrupt::set_affinity(vector, 3)?;
+//! ```
+//!
+//! The interrupts are counted for each vector on each cpu, and
+//! [`dump_stats`] prints the distribution.
+use crate::{
+    stats::PerCpuCounter,
+    sync::{CachePadded, PerCpu, Rcu},
+    MAX_CPU,
+};
 use alloc::sync::Arc;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 
-const INIT: SpinLock<Option<Arc<dyn Fn() + Send + Sync>>> = SpinLock::new(None);
-static HANDLERS: [SpinLock<Option<Arc<dyn Fn() + Send + Sync>>>; 224] = [INIT; 224];
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+// The handlers are read on every interrupt, and rarely updated.
+const INIT: Rcu<Handler> = Rcu::empty();
+static HANDLERS: [Rcu<Handler>; 224] = [INIT; 224];
+
+/// Vectors that [`allocate_vector`] hands out.
+///
+/// The vectors below the range are reserved for the fixed vectors and the
+/// system call ([`SYSCALL_VECTOR`]).
+///
+/// [`SYSCALL_VECTOR`]: abyss::interrupt::SYSCALL_VECTOR
+pub const DYNAMIC_VECTORS: Range<usize> = 129..240;
+
+static INTERRUPTS: PerCpuCounter = PerCpuCounter::new("interrupt.count");
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: AtomicBool = AtomicBool::new(false);
+static ALLOCATED: [AtomicBool; 224] = [FREE; 224];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_COUNTS: CachePadded<[AtomicU64; 224]> = CachePadded::new([ZERO; 224]);
+// The interrupts of each vector on each cpu.
+static COUNTS: PerCpu<[AtomicU64; 224]> = PerCpu::new([NO_COUNTS; MAX_CPU]);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const BSP: AtomicUsize = AtomicUsize::new(0);
+// The destination cpu of each vector.
+static AFFINITY: [AtomicUsize; 224] = [BSP; 224];
+// The next cpu of the round-robin policy.
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+type MsiHandler = Arc<dyn Fn(MsiMessage) + Send + Sync>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_MSI: Rcu<MsiHandler> = Rcu::empty();
+static MSI_HANDLERS: [Rcu<MsiHandler>; 224] = [NO_MSI; 224];
+
+/// Message of a message signaled interrupt, to be programmed into the msi
+/// capability or the msi-x table of a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsiMessage {
+    /// The message address.
+    pub address: u64,
+    /// The message data.
+    pub data: u32,
+}
+
+impl MsiMessage {
+    /// Make the message that raises the `vector` on the `cpu`, in the fixed
+    /// delivery mode and the physical destination mode.
+    pub const fn new(vector: usize, cpu: usize) -> Self {
+        Self {
+            address: 0xfee0_0000 | ((cpu as u64 & 0xff) << 12),
+            data: vector as u32 & 0xff,
+        }
+    }
+}
+
+/// Policy to route the allocated vectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AffinityPolicy {
+    /// Route all vectors to the cpu 0.
+    Bsp,
+    /// Spread the vectors over the cpus in turn.
+    RoundRobin,
+    /// Route to the cpu that handled the fewest device interrupts.
+    LeastLoaded,
+}
+
+impl AffinityPolicy {
+    /// Get the policy of the `irq` boot option.
+    pub fn from_config() -> Self {
+        match crate::config::boot_config().get("irq") {
+            Some("bsp") => Self::Bsp,
+            Some("least-loaded") => Self::LeastLoaded,
+            _ => Self::RoundRobin,
+        }
+    }
+
+    // Pick the destination of a new vector.
+    fn pick(self) -> usize {
+        match self {
+            Self::Bsp => 0,
+            Self::RoundRobin => {
+                let ncpu = crate::ncpu().max(1);
+                let nth = NEXT_CPU.fetch_add(1, Ordering::Relaxed) % ncpu;
+                crate::cpus().nth(nth).unwrap_or(0)
+            }
+            Self::LeastLoaded => crate::cpus()
+                .min_by_key(|cpu| {
+                    DYNAMIC_VECTORS
+                        .clone()
+                        .map(|vector| count_on(vector, *cpu).unwrap_or(0))
+                        .sum::<u64>()
+                })
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Error of the interrupt handler registration.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InterruptError {
+    /// The vector is not an external interrupt vector.
+    InvalidVector(usize),
+    /// The vector already has a handler.
+    Occupied(usize),
+    /// The cpu does not exist.
+    InvalidCpu(usize),
+}
 
 #[doc(hidden)]
 #[no_mangle]
 pub fn do_handle_interrupt(idx: usize) {
-    let handler = HANDLERS.get(idx).unwrap().lock().clone();
+    INTERRUPTS.inc();
+    if let Some(count) = COUNTS.get().get(idx) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+    // The handler may switch the thread, so it runs outside of the read-side
+    // critical section.
+    let handler = HANDLERS.get(idx).unwrap().read().map(|h| h.clone());
     if let Some(handler) = handler {
         handler()
     } else {
@@ -18,5 +169,134 @@ pub fn do_handle_interrupt(idx: usize) {
 
 /// Register interrupt handler
 pub fn register(vec: usize, handler: impl Fn() + Send + Sync + 'static) {
-    *HANDLERS.get(vec - 32).expect("Invalid index").lock() = Some(Arc::new(handler));
+    HANDLERS
+        .get(vec - 32)
+        .expect("Invalid index")
+        .replace(Some(Arc::new(handler)));
+}
+
+/// Register the interrupt `handler` on the `vector`.
+///
+/// Unlike [`register`], fails if the vector already has a handler.
+pub fn register_handler(
+    vector: usize,
+    handler: impl Fn() + Send + Sync + 'static,
+) -> Result<(), InterruptError> {
+    let slot = vector
+        .checked_sub(32)
+        .and_then(|idx| HANDLERS.get(idx))
+        .ok_or(InterruptError::InvalidVector(vector))?;
+    let mut slot = slot.write();
+    if slot.get().is_some() {
+        return Err(InterruptError::Occupied(vector));
+    }
+    slot.replace(Some(Arc::new(handler)));
+    Ok(())
+}
+
+/// Remove the interrupt handler of the `vector`.
+///
+/// The interrupts on the vector panic afterward, so the device must stop
+/// raising them first.
+pub fn unregister_handler(vector: usize) {
+    if let Some(slot) = vector.checked_sub(32).and_then(|idx| HANDLERS.get(idx)) {
+        slot.replace(None);
+    }
+}
+
+/// Allocate a free vector from the [`DYNAMIC_VECTORS`].
+///
+/// Returns `None` if all vectors are in use.
+///
+/// The vector is routed to a cpu by the [`AffinityPolicy::from_config`].
+pub fn allocate_vector() -> Option<usize> {
+    let vector = DYNAMIC_VECTORS.clone().find(|vector| {
+        HANDLERS[vector - 32].read().is_none()
+            && ALLOCATED[vector - 32]
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    })?;
+    AFFINITY[vector - 32].store(AffinityPolicy::from_config().pick(), Ordering::SeqCst);
+    Some(vector)
+}
+
+/// Free the `vector` allocated by [`allocate_vector`], and remove its
+/// handler.
+pub fn free_vector(vector: usize) {
+    if DYNAMIC_VECTORS.contains(&vector) {
+        unregister_handler(vector);
+        MSI_HANDLERS[vector - 32].replace(None);
+        AFFINITY[vector - 32].store(0, Ordering::SeqCst);
+        ALLOCATED[vector - 32].store(false, Ordering::SeqCst);
+    }
+}
+
+/// Get the destination cpu of the `vector`.
+pub fn affinity(vector: usize) -> Option<usize> {
+    let idx = vector.checked_sub(32)?;
+    Some(AFFINITY.get(idx)?.load(Ordering::SeqCst))
+}
+
+/// Route the `vector` to the `cpu`.
+///
+/// The msi handler of the vector, if any, reprograms the device.
+pub fn set_affinity(vector: usize, cpu: usize) -> Result<(), InterruptError> {
+    let idx = vector
+        .checked_sub(32)
+        .filter(|idx| *idx < 224)
+        .ok_or(InterruptError::InvalidVector(vector))?;
+    if !crate::cpus().any(|c| c == cpu) {
+        return Err(InterruptError::InvalidCpu(cpu));
+    }
+    AFFINITY[idx].store(cpu, Ordering::SeqCst);
+    let handler = MSI_HANDLERS[idx].read().map(|h| h.clone());
+    if let Some(handler) = handler {
+        handler(MsiMessage::new(vector, cpu));
+    }
+    Ok(())
+}
+
+/// Register how to program the message of the `vector` into the device.
+///
+/// The `handler` is called at once with the message to the current
+/// destination, and again on every [`set_affinity`].
+pub fn set_msi_handler(
+    vector: usize,
+    handler: impl Fn(MsiMessage) + Send + Sync + 'static,
+) -> Result<(), InterruptError> {
+    let cpu = affinity(vector).ok_or(InterruptError::InvalidVector(vector))?;
+    handler(MsiMessage::new(vector, cpu));
+    MSI_HANDLERS[vector - 32].replace(Some(Arc::new(handler)));
+    Ok(())
+}
+
+/// Get the number of the interrupts of the `vector` on all cpus.
+pub fn count(vector: usize) -> u64 {
+    crate::cpus().filter_map(|cpu| count_on(vector, cpu)).sum()
+}
+
+/// Get the number of the interrupts of the `vector` on the `cpu`.
+pub fn count_on(vector: usize, cpu: usize) -> Option<u64> {
+    let idx = vector.checked_sub(32)?;
+    Some(COUNTS.get_of(cpu)?.get(idx)?.load(Ordering::Relaxed))
+}
+
+/// Print the interrupts of each vector on each cpu, and the destination of
+/// the vector.
+pub fn dump_stats() {
+    print!("{:>6}", "vector");
+    for cpu in crate::cpus() {
+        print!(" {:>10}", alloc::format!("cpu{}", cpu));
+    }
+    println!(" {:>8}", "affinity");
+    for vector in 32..256 {
+        if count(vector) == 0 {
+            continue;
+        }
+        print!("{:>6}", vector);
+        for cpu in crate::cpus() {
+            print!(" {:>10}", count_on(vector, cpu).unwrap_or(0));
+        }
+        println!(" {:>8}", affinity(vector).unwrap_or(0));
+    }
 }
@@ -8,7 +8,8 @@
     naked_functions,
     lang_items,
     new_uninit,
-    const_mut_refs
+    const_mut_refs,
+    panic_info_message
 )]
 #![deny(missing_docs)]
 
@@ -16,21 +17,37 @@
 extern crate abyss;
 extern crate alloc;
 
+pub mod async_call;
+pub mod bench;
+pub mod config;
+pub mod fault;
 pub mod fs;
+pub mod hotplug;
+pub mod hypercall;
 pub mod interrupt;
+pub mod metrics;
 pub mod mm;
 pub mod panicking;
+pub mod process;
+pub mod serial;
+pub mod stats;
 pub mod sync;
 pub mod thread;
+pub mod topology;
 
-pub use abyss::{addressing, debug, info, print, println, spin_lock, warning, MAX_CPU};
+pub use abyss::{addressing, cpus, debug, info, ncpu, print, println, spin_lock, warning, MAX_CPU};
 
 /// The first function of rust world.
 #[no_mangle]
 unsafe fn rust_main(core_id: usize, regions: abyss::boot::Regions) {
     info!("boot KeOS...");
+    info!("    {} cpu(s) found.", ncpu());
     // Init memory.
     crate::mm::init_mm(regions);
+    crate::config::init();
+    crate::topology::init();
+    crate::mm::tlb::init(core_id);
+    crate::process::init();
     // Init pci device
     info!("initialize devices...");
     abyss::dev::pci::init();
@@ -41,6 +58,7 @@ unsafe fn rust_main(core_id: usize, regions: abyss::boot::Regions) {
     }
     info!("initialize fs...");
     crate::fs::init_fs();
+    crate::config::load_file();
 
     extern "Rust" {
         fn main();
@@ -61,6 +79,7 @@ unsafe fn rust_ap_main(core_id: usize) {
     extern "Rust" {
         fn ap_main();
     }
+    crate::mm::tlb::init(core_id);
     ap_main();
     crate::thread::scheduler::start_idle(core_id);
 }
@@ -71,15 +90,21 @@ pub trait TestFn
 where
     Self: Sync + Send,
 {
+    fn name(&'static self) -> &'static str;
     fn run(&'static self) -> bool;
 }
 
 /// Run the given tests.
+///
+/// Only the tests that pass the test filter of the [`config::BootConfig`] run.
 pub fn do_tests(tests: &'static [&'static dyn TestFn]) {
     impl<T> TestFn for T
     where
         T: Fn() + Send + Sync + 'static,
     {
+        fn name(&'static self) -> &'static str {
+            core::any::type_name::<T>()
+        }
         fn run(&'static self) -> bool {
             print!("test {} ... ", core::any::type_name::<T>());
             if crate::thread::ThreadBuilder::new(core::any::type_name::<T>())
@@ -96,6 +121,11 @@ pub fn do_tests(tests: &'static [&'static dyn TestFn]) {
         }
     }
     crate::thread::ThreadBuilder::new("test_main").spawn(move || {
+        let config = crate::config::boot_config();
+        let tests = tests
+            .iter()
+            .filter(|test| config.run_test(test.name()))
+            .collect::<alloc::vec::Vec<_>>();
         let (total, mut succ) = (tests.len(), 0);
         println!(
             "running {} test{}",
@@ -114,6 +144,13 @@ pub fn do_tests(tests: &'static [&'static dyn TestFn]) {
             succ,
             total - succ
         );
+        let benches = crate::bench::take_results();
+        if !benches.is_empty() {
+            println!("bench result: {} benchmark(s)", benches.len());
+            for stats in benches {
+                println!("    {}: {}", stats.name, stats);
+            }
+        }
 
         use abyss::x86_64::pio::Pio;
         #[cfg(feature = "exit_on_qemu")]
@@ -121,4 +158,4 @@ pub fn do_tests(tests: &'static [&'static dyn TestFn]) {
     });
 }
 
-pub use abyss::x86_64::intrinsics;
+pub use abyss::{dev::x86_64::rtc, x86_64::intrinsics};
@@ -0,0 +1,205 @@
+//! Performance metrics published by the hypervisor.
+//!
+//! When keos runs as a guest of KeV, the host publishes the live metrics of
+//! each vcpu on a page shared with the guest, so the guest reads them without
+//! a vmexit. The guest registers the page with the [`HC_METRICS`] hypercall:
+//!
+//! | Register | Value                                                    |
+//! |----------|----------------------------------------------------------|
+//! | rax      | [`HC_METRICS`] (0x207)                                   |
+//! | rdi      | guest physical address of the [`MetricsPage`], or 0      |
+//!
+//! The hypercall returns 0, or the negated errno in rax. The host then keeps
+//! the slot of each vcpu up to date while the vcpu runs. A slot is guarded by
+//! its version, which is odd while the host writes the slot, in the manner of
+//! the kvmclock; [`MetricsPage::read`] retries until it reads a consistent
+//! slot.
+//!
+//! The metrics are taken lazily; the page is registered on the first call:
+//!
+//! ```ignore
+//! if let Some(m) = keos::metrics::vcpu(cpuid()) {
+//!     println!("exits: {}, steal: {} ns", m.exits, m.steal);
+//! }
+//! ```
+//!
+//! The [`steal_time`] is the time that the vcpu was ready to run, but the
+//! host ran something else, as the steal time of the KVM.
+use abyss::addressing::Va;
+use core::{
+    arch::asm,
+    sync::atomic::{fence, AtomicU8, Ordering},
+};
+
+/// Hypercall number to register the metrics page.
+pub const HC_METRICS: usize = 0x207;
+/// Bit of the paravirtual features (CPUID 0x4000_0001:EAX) of the metrics.
+pub const FEATURE_METRICS: u32 = 1 << 30;
+/// Magic of the page, "KeVm".
+pub const METRICS_MAGIC: u32 = u32::from_le_bytes(*b"KeVm");
+/// Number of the slots of the page.
+pub const MAX_VCPUS: usize = 63;
+
+/// Metrics of a vcpu.
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VCpuMetrics {
+    /// Odd while the host updates the slot.
+    pub version: u32,
+    /// Reserved.
+    pub pad: u32,
+    /// Number of the vmexits.
+    pub exits: u64,
+    /// Number of the interrupts injected to the vcpu.
+    pub injected: u64,
+    /// Time that the vcpu waited for a host cpu, in nanoseconds.
+    pub steal: u64,
+    /// Time that the host ran the vcpu, in nanoseconds.
+    pub run_time: u64,
+}
+
+/// The page shared with the host.
+#[repr(C, align(4096))]
+pub struct MetricsPage {
+    /// [`METRICS_MAGIC`] once the host registers the page.
+    pub magic: u32,
+    /// Number of the vcpus of the vm.
+    pub vcpus: u32,
+    /// Reserved.
+    pub reserved: [u32; 14],
+    /// The slots of the vcpus, by their ids.
+    pub slots: [VCpuMetrics; MAX_VCPUS],
+}
+
+impl MetricsPage {
+    /// Create an empty page.
+    pub const fn new() -> Self {
+        const EMPTY: VCpuMetrics = VCpuMetrics {
+            version: 0,
+            pad: 0,
+            exits: 0,
+            injected: 0,
+            steal: 0,
+            run_time: 0,
+        };
+        Self {
+            magic: 0,
+            vcpus: 0,
+            reserved: [0; 14],
+            slots: [EMPTY; MAX_VCPUS],
+        }
+    }
+
+    /// Read the slot of the vcpu `id`.
+    ///
+    /// Returns `None` if the host has not registered the page, or the vcpu
+    /// does not exist.
+    ///
+    /// # Safety
+    /// The `page` must be valid for the reads.
+    pub unsafe fn read(page: *const MetricsPage, id: usize) -> Option<VCpuMetrics> {
+        let (magic, vcpus) = (
+            core::ptr::addr_of!((*page).magic).read_volatile(),
+            core::ptr::addr_of!((*page).vcpus).read_volatile(),
+        );
+        if magic != METRICS_MAGIC || id >= (vcpus as usize).min(MAX_VCPUS) {
+            return None;
+        }
+        let slot = core::ptr::addr_of!((*page).slots[id]);
+        loop {
+            let version = core::ptr::addr_of!((*slot).version).read_volatile();
+            fence(Ordering::Acquire);
+            let metrics = slot.read_volatile();
+            fence(Ordering::Acquire);
+            if version & 1 == 0 && version == core::ptr::addr_of!((*slot).version).read_volatile() {
+                return Some(metrics);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Write the `metrics` to the slot of the vcpu `id`.
+    ///
+    /// The version of the `metrics` is ignored. Only the vcpu `id` writes its
+    /// slot.
+    ///
+    /// # Safety
+    /// The `page` must be valid for the writes.
+    pub unsafe fn write(page: *mut MetricsPage, id: usize, metrics: &VCpuMetrics) {
+        if id >= MAX_VCPUS {
+            return;
+        }
+        let slot = core::ptr::addr_of_mut!((*page).slots[id]);
+        let version = core::ptr::addr_of!((*slot).version)
+            .read_volatile()
+            .wrapping_add(1)
+            | 1;
+        core::ptr::addr_of_mut!((*slot).version).write_volatile(version);
+        fence(Ordering::Release);
+        slot.write_volatile(VCpuMetrics {
+            version,
+            ..*metrics
+        });
+        fence(Ordering::Release);
+        core::ptr::addr_of_mut!((*slot).version).write_volatile(version.wrapping_add(1));
+    }
+}
+
+impl Default for MetricsPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static mut PAGE: MetricsPage = MetricsPage::new();
+
+// 0 if the page is not registered yet, 1 if registered, 2 if unavailable.
+static STATE: AtomicU8 = AtomicU8::new(0);
+
+// Register the page to the host.
+fn register() -> bool {
+    if crate::hypercall::pv_features() & FEATURE_METRICS == 0 {
+        return false;
+    }
+    let ret: usize;
+    unsafe {
+        let gpa = Va::new(core::ptr::addr_of!(PAGE) as usize)
+            .unwrap()
+            .into_pa()
+            .into_usize();
+        asm!(
+            "vmcall",
+            inout("rax") HC_METRICS => ret,
+            in("rdi") gpa,
+        );
+    }
+    ret == 0
+}
+
+/// Get the metrics page, registering it on the first call.
+///
+/// Returns `None` if the host does not publish the metrics.
+pub fn page() -> Option<&'static MetricsPage> {
+    match STATE.load(Ordering::Acquire) {
+        1 => Some(unsafe { &*core::ptr::addr_of!(PAGE) }),
+        2 => None,
+        _ => {
+            let state = if register() { 1 } else { 2 };
+            // The registration is idempotent, so a racing cpu may register
+            // the page again.
+            STATE.store(state, Ordering::Release);
+            (state == 1).then(|| unsafe { &*core::ptr::addr_of!(PAGE) })
+        }
+    }
+}
+
+/// Get the metrics of the vcpu `id`.
+pub fn vcpu(id: usize) -> Option<VCpuMetrics> {
+    page().and_then(|page| unsafe { MetricsPage::read(page, id) })
+}
+
+/// Get the steal time of the current cpu in nanoseconds, or 0 if the host
+/// does not publish it.
+pub fn steal_time() -> u64 {
+    vcpu(abyss::x86_64::intrinsics::cpuid()).map_or(0, |m| m.steal)
+}
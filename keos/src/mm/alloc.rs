@@ -1,70 +1,131 @@
 //! Heap allocator for KeOS.
 
 use crate::addressing::{Va, PAGE_MASK};
-use crate::{mm::slob_allocator::SlobAllocator, spin_lock::SpinLock};
+use crate::stats::{Counter, PerCpuCounter};
+use crate::spin_lock::SpinLock;
 use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kcore::slob::SlobAllocator;
 
-use super::ContigPages;
+use super::{heap_profile, ContigPages};
 
 /// Out-of memory handler
+///
+/// Prints the usage of the heap and its largest consumers before aborting.
 #[alloc_error_handler]
-fn oom(_l: Layout) -> ! {
-    unreachable!()
+fn oom(l: Layout) -> ! {
+    println!(
+        "\n========== OUT OF MEMORY ==========\nFailed to allocate {} bytes (align {}).\nHeap arenas: {} ({} KiB), large allocations: {} KiB",
+        l.size(),
+        l.align(),
+        ARENAS.get(),
+        ARENA_BYTES.get() / 1024,
+        LARGE_BYTES.load(Ordering::Relaxed) / 1024
+    );
+    heap_profile::dump();
+    panic!("out of memory");
 }
 
 pub struct Allocator(SpinLock<SlobAllocator>);
 
-unsafe impl GlobalAlloc for Allocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+// Minimum size of an arena that the heap grows by.
+const ARENA_SIZE: usize = 0x40000;
+
+static ALLOCS: PerCpuCounter = PerCpuCounter::new("alloc.allocs");
+static ALLOC_BYTES: PerCpuCounter = PerCpuCounter::new("alloc.bytes");
+static FREES: PerCpuCounter = PerCpuCounter::new("alloc.frees");
+static ARENAS: Counter = Counter::new("alloc.arenas");
+static ARENA_BYTES: Counter = Counter::new("alloc.arena_bytes");
+// Bytes of the live large allocations.
+static LARGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+impl Allocator {
+    // Returns null if out of memory.
+    pub(super) unsafe fn do_alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.inc();
+        ALLOC_BYTES.add(layout.size() as u64);
         if layout.size() >= 65536 {
-            if let Some(pg) = crate::mm::ContigPages::new_with_align(
-                (layout.size() + PAGE_MASK) & !PAGE_MASK,
-                layout.align(),
-            ) {
-                let va = pg.va().into_usize();
-                core::mem::forget(pg);
-                return va as *mut u8;
-            } else {
-                oom(layout)
-            }
+            let size = (layout.size() + PAGE_MASK) & !PAGE_MASK;
+            return match crate::mm::ContigPages::new_with_align(size, layout.align()) {
+                Some(pg) => {
+                    let va = pg.va().into_usize();
+                    core::mem::forget(pg);
+                    LARGE_BYTES.fetch_add(size, Ordering::Relaxed);
+                    heap_profile::record(size);
+                    va as *mut u8
+                }
+                None => null_mut(),
+            };
         }
         // perform layout adjustments
-        let (size, align) = SlobAllocator::align_to_slob_node(layout);
+        let (size, _) = SlobAllocator::align_to_slob_node(layout);
         let mut allocator = self.0.lock();
 
-        loop {
-            if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-                let alloc_end = alloc_start.checked_add(size).expect("overflow");
-                let excess_size = region.end_addr() - alloc_end;
-                if excess_size > 0 {
-                    allocator.add_free_region(alloc_end, excess_size);
+        let mut grown = 0;
+        let ptr = loop {
+            if let Some(alloc_start) = allocator.alloc(layout) {
+                break alloc_start as *mut u8;
+            }
+            // Grow the heap by an arena, or by the allocation itself if the
+            // physical memory is fragmented.
+            match crate::mm::ContigPages::new(size.max(ARENA_SIZE))
+                .or_else(|| crate::mm::ContigPages::new(size))
+            {
+                Some(pg) => {
+                    let va = pg.va().into_usize();
+                    allocator.add_free_region(va, pg.cnt * 0x1000);
+                    grown += pg.cnt * 0x1000;
+                    ARENAS.inc();
+                    ARENA_BYTES.add((pg.cnt * 0x1000) as u64);
+                    core::mem::forget(pg);
                 }
-                return alloc_start as *mut u8;
-            } else if let Some(pg) = crate::mm::ContigPages::new(size) {
-                let va = pg.va().into_usize();
-                allocator.add_free_region(va, pg.cnt * 0x1000);
-                core::mem::forget(pg);
-            } else {
-                oom(layout);
+                None => break null_mut(),
             }
+        };
+        drop(allocator);
+        if grown != 0 {
+            heap_profile::record(grown);
         }
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    pub(super) unsafe fn do_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREES.inc();
         if layout.size() >= 65536 {
+            LARGE_BYTES.fetch_sub((layout.size() + PAGE_MASK) & !PAGE_MASK, Ordering::Relaxed);
             ContigPages::from_va(
                 Va::new(ptr as usize).unwrap(),
                 (layout.size() + PAGE_MASK) & !PAGE_MASK,
             );
         } else {
             return; // BUG: slob has a bug. Mitigate by not freeing now.
-            // perform layout adjustments
-            let (size, _) = SlobAllocator::align_to_slob_node(layout);
-
-            self.0.lock().add_free_region(ptr as usize, size)
+            self.0.lock().dealloc(ptr as usize, layout)
         }
     }
 }
 
+unsafe impl GlobalAlloc for Allocator {
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.do_alloc(layout)
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.do_dealloc(ptr, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::kasan::alloc(self, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        super::kasan::dealloc(self, ptr, layout)
+    }
+}
+
 #[global_allocator]
 pub(crate) static ALLOCATOR: Allocator = Allocator(SpinLock::new(SlobAllocator::new()));
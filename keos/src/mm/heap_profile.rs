@@ -0,0 +1,112 @@
+//! Sampled profile of the heap consumers.
+//!
+//! The heap grows by an arena whenever the small allocations fill it, and
+//! the large allocations take the pages of their own. Each growth and each
+//! large allocation is a sample, attributed to the backtrace of the
+//! allocation that triggered it, so the callsites that consume the most of
+//! the heap have the most bytes. The profile is printed when the heap runs
+//! out of memory.
+use crate::{
+    sync::{CachePadded, PerCpu, SpinLock},
+    MAX_CPU,
+};
+use abyss::interrupt::InterruptGuard;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Number of the frames in the backtrace of a site.
+const DEPTH: usize = 6;
+// Number of the frames of the profiler and the allocator to skip.
+const SKIP: usize = 3;
+// Number of the tracked sites.
+const SITES: usize = 32;
+// Number of the sites printed in the report.
+const TOP: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Site {
+    backtrace: [usize; DEPTH],
+    bytes: u64,
+    samples: u64,
+}
+
+impl Site {
+    const INIT: Self = Self {
+        backtrace: [0; DEPTH],
+        bytes: 0,
+        samples: 0,
+    };
+}
+
+static PROFILE: SpinLock<[Site; SITES]> = SpinLock::new([Site::INIT; SITES]);
+
+// Whether the current cpu is in the profiler, to not sample the allocations
+// of the unwinder.
+#[allow(clippy::declare_interior_mutable_const)]
+const BUSY_INIT: CachePadded<AtomicBool> = CachePadded::new(AtomicBool::new(false));
+static BUSY: PerCpu<AtomicBool> = PerCpu::new([BUSY_INIT; MAX_CPU]);
+
+struct Busy;
+
+impl Busy {
+    fn enter() -> Option<Self> {
+        (!BUSY.get().swap(true, Ordering::Relaxed)).then_some(Self)
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.get().store(false, Ordering::Relaxed);
+    }
+}
+
+/// Attribute the `bytes` to the backtrace of the current allocation.
+#[inline(never)]
+pub(super) fn record(bytes: usize) {
+    let _p = InterruptGuard::new();
+    let Some(_busy) = Busy::enter() else {
+        return;
+    };
+    let mut backtrace = [0; DEPTH];
+    let mut frames = 0;
+    crate::panicking::walk_stack(|pc| {
+        if (SKIP..DEPTH + SKIP).contains(&frames) {
+            backtrace[frames - SKIP] = pc;
+        }
+        frames += 1;
+    });
+
+    let mut profile = PROFILE.lock();
+    // Evict the smallest site if the backtrace is new.
+    let site = match profile.iter().position(|s| s.backtrace == backtrace) {
+        Some(i) => &mut profile[i],
+        None => {
+            let site = profile.iter_mut().min_by_key(|s| s.bytes).unwrap();
+            *site = Site {
+                backtrace,
+                ..Site::INIT
+            };
+            site
+        }
+    };
+    site.bytes += bytes as u64;
+    site.samples += 1;
+}
+
+/// Print the sites that consumed the most of the heap.
+pub fn dump() {
+    let _busy = Busy::enter();
+    let mut sites = *PROFILE.lock();
+    sites.sort_unstable_by_key(|s| core::cmp::Reverse(s.bytes));
+    println!("Largest heap consumers:");
+    for (rank, site) in sites.iter().take(TOP).filter(|s| s.bytes != 0).enumerate() {
+        println!(
+            "#{} {} KiB in {} samples, allocated at:",
+            rank + 1,
+            site.bytes / 1024,
+            site.samples
+        );
+        for (depth, pc) in site.backtrace.iter().take_while(|pc| **pc != 0).enumerate() {
+            crate::panicking::print_frame(depth + 1, *pc);
+        }
+    }
+}
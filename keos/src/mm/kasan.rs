@@ -0,0 +1,246 @@
+//! Kernel address sanitizer (lite) for the heap.
+//!
+//! With the `kasan` feature, each small allocation is surrounded by the red
+//! zones filled with a known pattern, and recorded in the shadow map together
+//! with the backtrace of the allocation. On the free (and the realloc, which
+//! frees the old allocation), the red zones are checked, so that an
+//! out-of-bounds write is reported at the free instead of corrupting an
+//! unrelated allocation.
+//!
+//! The freed allocation is poisoned and kept in the quarantine for a while
+//! before it is returned to the allocator. A write to the poisoned memory
+//! (i.e. a use-after-free) is reported when the allocation leaves the
+//! quarantine, and freeing it again is reported as a double free.
+//!
+//! The large allocations backed by the pages, and the allocations made while
+//! the shadow map is full are not tracked.
+use super::alloc::Allocator;
+use crate::{
+    sync::{CachePadded, PerCpu, SpinLock},
+    MAX_CPU,
+};
+use abyss::interrupt::InterruptGuard;
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Minimum size of each red zone.
+const REDZONE: usize = 32;
+// Pattern of the red zones.
+const REDZONE_BYTE: u8 = 0xfc;
+// Pattern of the freed allocations.
+const FREED_BYTE: u8 = 0xfb;
+// Number of the frames in the recorded backtrace.
+const DEPTH: usize = 8;
+// Number of the tracked allocations.
+const SLOTS: usize = 4096;
+// Number of the freed allocations in the quarantine.
+const QUARANTINE: usize = 256;
+// Allocations at least this large are backed by the pages.
+const LARGE: usize = 65536;
+
+// Key of the empty slot and the removed slot.
+const EMPTY: usize = 0;
+const REMOVED: usize = 1;
+
+#[derive(Clone, Copy)]
+struct Shadow {
+    // Address returned to the caller.
+    ptr: usize,
+    size: usize,
+    align: usize,
+    redzone: usize,
+    freed: bool,
+    backtrace: [usize; DEPTH],
+}
+
+impl Shadow {
+    const INIT: Self = Self {
+        ptr: EMPTY,
+        size: 0,
+        align: 0,
+        redzone: 0,
+        freed: false,
+        backtrace: [0; DEPTH],
+    };
+
+    fn base(&self) -> usize {
+        self.ptr - self.redzone
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size + 2 * self.redzone, self.align).unwrap()
+    }
+
+    // Find the first byte in [start, start + len) that differs from `pattern`.
+    unsafe fn find_corrupted(start: usize, len: usize, pattern: u8) -> Option<usize> {
+        core::slice::from_raw_parts(start as *const u8, len)
+            .iter()
+            .position(|b| *b != pattern)
+            .map(|ofs| start + ofs)
+    }
+
+    unsafe fn check_redzones(&self) -> Option<usize> {
+        Self::find_corrupted(self.base(), self.redzone, REDZONE_BYTE)
+            .or_else(|| Self::find_corrupted(self.ptr + self.size, self.redzone, REDZONE_BYTE))
+    }
+}
+
+struct ShadowMap {
+    slots: [Shadow; SLOTS],
+    // Ring of the freed allocations.
+    quarantine: [usize; QUARANTINE],
+    head: usize,
+}
+
+impl ShadowMap {
+    fn probe(ptr: usize) -> impl Iterator<Item = usize> {
+        let hash = (ptr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 52;
+        (0..SLOTS).map(move |i| (hash + i) % SLOTS)
+    }
+
+    fn insert(&mut self, shadow: Shadow) -> bool {
+        for idx in Self::probe(shadow.ptr) {
+            if matches!(self.slots[idx].ptr, EMPTY | REMOVED) {
+                self.slots[idx] = shadow;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn find(&mut self, ptr: usize) -> Option<&mut Shadow> {
+        let idx = Self::probe(ptr)
+            .take_while(|idx| self.slots[*idx].ptr != EMPTY)
+            .find(|idx| self.slots[*idx].ptr == ptr)?;
+        Some(&mut self.slots[idx])
+    }
+
+    // Put `ptr` into the quarantine, and returns the evicted allocation.
+    fn quarantine(&mut self, ptr: usize) -> Option<Shadow> {
+        let evicted = core::mem::replace(&mut self.quarantine[self.head], ptr);
+        self.head = (self.head + 1) % QUARANTINE;
+        let shadow = self.find(evicted)?;
+        let old = *shadow;
+        shadow.ptr = REMOVED;
+        Some(old)
+    }
+}
+
+static SHADOW: SpinLock<ShadowMap> = SpinLock::new(ShadowMap {
+    slots: [Shadow::INIT; SLOTS],
+    quarantine: [EMPTY; QUARANTINE],
+    head: 0,
+});
+
+// Whether the current cpu is in the sanitizer, to not track the allocations
+// of the sanitizer itself.
+const BUSY_INIT: CachePadded<AtomicBool> = CachePadded::new(AtomicBool::new(false));
+static BUSY: PerCpu<AtomicBool> = PerCpu::new([BUSY_INIT; MAX_CPU]);
+
+struct Busy;
+
+impl Busy {
+    fn enter() -> Option<Self> {
+        (!BUSY.get().swap(true, Ordering::Relaxed)).then_some(Self)
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.get().store(false, Ordering::Relaxed);
+    }
+}
+
+fn report(kind: &str, addr: usize, shadow: &Shadow) -> ! {
+    let _busy = Busy::enter();
+    println!(
+        "\n========== KASAN: {} at 0x{:x} ==========\nThe {}-byte region [0x{:x}, 0x{:x}) is allocated at:",
+        kind,
+        addr,
+        shadow.size,
+        shadow.ptr,
+        shadow.ptr + shadow.size
+    );
+    for (depth, pc) in shadow
+        .backtrace
+        .iter()
+        .take_while(|pc| **pc != 0)
+        .enumerate()
+    {
+        crate::panicking::print_frame(depth + 1, *pc);
+    }
+    panic!("KASAN: {}", kind);
+}
+
+pub(super) unsafe fn alloc(allocator: &Allocator, layout: Layout) -> *mut u8 {
+    let redzone = REDZONE.max(layout.align());
+    if layout.size() + 2 * redzone >= LARGE {
+        return allocator.do_alloc(layout);
+    }
+    let _p = InterruptGuard::new();
+    let Some(_busy) = Busy::enter() else {
+        return allocator.do_alloc(layout);
+    };
+
+    let mut shadow = Shadow {
+        size: layout.size(),
+        align: layout.align(),
+        redzone,
+        ..Shadow::INIT
+    };
+    // Skip the frames of the sanitizer and the allocator.
+    let mut frames = 0;
+    crate::panicking::walk_stack(|pc| {
+        if (3..DEPTH + 3).contains(&frames) {
+            shadow.backtrace[frames - 3] = pc;
+        }
+        frames += 1;
+    });
+
+    let base = allocator.do_alloc(shadow.layout()) as usize;
+    if base == 0 {
+        return core::ptr::null_mut();
+    }
+    shadow.ptr = base + redzone;
+    core::ptr::write_bytes(base as *mut u8, REDZONE_BYTE, redzone);
+    core::ptr::write_bytes((shadow.ptr + shadow.size) as *mut u8, REDZONE_BYTE, redzone);
+    if SHADOW.lock().insert(shadow) {
+        shadow.ptr as *mut u8
+    } else {
+        allocator.do_dealloc(base as *mut u8, shadow.layout());
+        allocator.do_alloc(layout)
+    }
+}
+
+pub(super) unsafe fn dealloc(allocator: &Allocator, ptr: *mut u8, layout: Layout) {
+    let _p = InterruptGuard::new();
+    let mut map = SHADOW.lock();
+    let Some(shadow) = map.find(ptr as usize) else {
+        drop(map);
+        return allocator.do_dealloc(ptr, layout);
+    };
+    let current = *shadow;
+    if current.freed {
+        drop(map);
+        report("double free", ptr as usize, &current);
+    }
+    if let Some(addr) = current.check_redzones() {
+        drop(map);
+        report("heap out-of-bounds write", addr, &current);
+    }
+    shadow.freed = true;
+    core::ptr::write_bytes(ptr, FREED_BYTE, current.size);
+
+    let evicted = map.quarantine(ptr as usize);
+    drop(map);
+    if let Some(old) = evicted {
+        if let Some(addr) =
+            Shadow::find_corrupted(old.ptr, old.size, FREED_BYTE).or_else(|| old.check_redzones())
+        {
+            report("heap use-after-free write", addr, &old);
+        }
+        allocator.do_dealloc(old.base() as *mut u8, old.layout());
+    }
+}
@@ -1,12 +1,22 @@
 //! Memory management including heap and physical memory.
 mod alloc;
-mod slob_allocator;
+mod heap_profile;
+#[cfg(feature = "kasan")]
+mod kasan;
+pub mod tlb;
 
 use crate::addressing::{Pa, Va, PAGE_MASK, PAGE_SHIFT};
-use crate::sync::SpinLock;
+use crate::sync::{CachePadded, PerCpu, SpinLock};
+use crate::MAX_CPU;
 use ::alloc::vec::Vec;
 use abyss::boot::Regions;
-use core::ops::Range;
+use core::{alloc::Layout, ops::Range, ptr::NonNull};
+
+pub use abyss::{
+    dev::mmio::{map_mmio, MmioMapping},
+    x86_64::pat::CacheAttr,
+};
+pub use heap_profile::dump as dump_heap_profile;
 
 /// Initialize the physical memory allocator.
 pub unsafe fn init_mm(regions: Regions) {
@@ -93,6 +103,13 @@ impl Arena {
         }
         None
     }
+    // Check whether the page at `va` is allocated.
+    fn is_allocated(&self, va: Va) -> bool {
+        let index = unsafe { (va.into_usize() - self.start.into_usize()) >> PAGE_SHIFT };
+        // The bitmap itself is on the first pages.
+        let bitmap_pages = (self.bitmap.len() * 8 + PAGE_MASK) >> PAGE_SHIFT;
+        index >= bitmap_pages && self.bitmap[index / 64] & (1 << (index % 64)) == 0
+    }
     fn dealloc(&mut self, va: Va, cnt: usize) {
         let ofs = unsafe { (va.into_usize() - self.start.into_usize()) >> PAGE_SHIFT };
         for i in ofs..ofs + cnt {
@@ -111,6 +128,44 @@ static PALLOC: SpinLock<PhysicalAllocator> = SpinLock::new(PhysicalAllocator {
     max_idx: 0,
 });
 
+/// Per-cpu cache of the single pages.
+///
+/// Allocating and freeing a single page is the most common case. Serving them
+/// from the per-cpu cache avoids contending on the global `PALLOC` lock.
+struct PageCache {
+    pages: [(usize, Va); PageCache::CAPACITY],
+    len: usize,
+}
+
+impl PageCache {
+    const CAPACITY: usize = 32;
+    const INIT: CachePadded<SpinLock<PageCache>> = CachePadded::new(SpinLock::new(PageCache {
+        pages: [(0, Va::ZERO); PageCache::CAPACITY],
+        len: 0,
+    }));
+
+    fn pop(&mut self) -> Option<(usize, Va)> {
+        if self.len != 0 {
+            self.len -= 1;
+            Some(self.pages[self.len])
+        } else {
+            None
+        }
+    }
+
+    fn push(&mut self, arena_idx: usize, va: Va) -> bool {
+        if self.len < Self::CAPACITY {
+            self.pages[self.len] = (arena_idx, va);
+            self.len += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+static PAGE_CACHE: PerCpu<SpinLock<PageCache>> = PerCpu::new([PageCache::INIT; MAX_CPU]);
+
 impl PhysicalAllocator {
     unsafe fn foster(&mut self, start: Va, end: Va) {
         // Calculate usable page of this region.
@@ -232,6 +287,14 @@ impl ContigPages {
         if size != 0 {
             // align up to page size.
             let cnt = (size + PAGE_MASK) >> PAGE_SHIFT;
+            if cnt == 1 && align <= 0x1000 {
+                if let Some((arena_idx, va)) = PAGE_CACHE.get().lock().pop() {
+                    unsafe {
+                        core::slice::from_raw_parts_mut(va.into_usize() as *mut u64, 512).fill(0);
+                    }
+                    return Some(Self { arena_idx, va, cnt });
+                }
+            }
             let mut allocator = PALLOC.lock();
             let max_idx = allocator.max_idx;
             for (arena_idx, arena) in allocator.inner.iter_mut().take(max_idx).enumerate() {
@@ -307,6 +370,9 @@ impl ContigPages {
 
 impl Drop for ContigPages {
     fn drop(&mut self) {
+        if self.cnt == 1 && PAGE_CACHE.get().lock().push(self.arena_idx, self.va) {
+            return;
+        }
         let mut allocator = PALLOC.lock();
         allocator.inner[self.arena_idx]
             .as_mut()
@@ -315,6 +381,38 @@ impl Drop for ContigPages {
     }
 }
 
+/// Check whether the page at `pa` is allocated from the physical memory
+/// allocator.
+///
+/// The pages of the kernel image and of the allocator itself are never
+/// allocated. A freed page that is cached for the reuse still counts as
+/// allocated.
+pub fn is_allocated(pa: Pa) -> bool {
+    let va = pa.into_va();
+    let allocator = PALLOC.lock();
+    allocator
+        .inner
+        .iter()
+        .take(allocator.max_idx)
+        .flatten()
+        .any(|arena| (arena.start..arena.end).contains(&va) && arena.is_allocated(va))
+}
+
+/// Allocate the memory of the `layout` from the heap.
+///
+/// Unlike the allocations of `Box` or `Vec`, which abort the kernel when the
+/// heap runs out of memory, returns `None` so that the caller can recover,
+/// e.g. by failing a request. The memory is freed with
+/// [`alloc::alloc::dealloc`](::alloc::alloc::dealloc) and the same layout,
+/// except the zero-sized one that is dangling. `Vec::try_reserve` fails in the
+/// same way.
+pub fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    if layout.size() == 0 {
+        return NonNull::new(layout.align() as *mut u8);
+    }
+    NonNull::new(unsafe { ::alloc::alloc::alloc(layout) })
+}
+
 /// Align upwards. Returns the smallest x with alignment `align`
 /// so that x >= addr. The alignment must be a power of 2.
 pub fn align_up(addr: usize, align: usize) -> usize {
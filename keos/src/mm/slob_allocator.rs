@@ -1,142 +0,0 @@
-// Copyright (c) 2023 KAIST Computer Architecture and System Lab
-
-// Permission is hereby granted, free of charge, to any
-// person obtaining a copy of this software and associated
-// documentation files (the "Software"), to deal in the
-// Software without restriction, including without
-// limitation the rights to use, copy, modify, merge,
-// publish, distribute, sublicense, and/or sell copies of
-// the Software, and to permit persons to whom the Software
-// is furnished to do so, subject to the following
-// conditions:
-
-// The above copyright notice and this permission notice
-// shall be included in all copies or substantial portions
-// of the Software.
-
-// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
-// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
-// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
-// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
-// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
-// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
-// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
-// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
-// DEALINGS IN THE SOFTWARE.
-
-use core::alloc::Layout;
-
-pub struct SlobNode {
-    size: usize,
-    next: Option<&'static mut SlobNode>,
-}
-
-impl SlobNode {
-    const fn new(size: usize) -> Self {
-        SlobNode { size, next: None }
-    }
-
-    pub fn start_addr(&self) -> usize {
-        self as *const Self as usize
-    }
-
-    pub fn end_addr(&self) -> usize {
-        match self.start_addr().overflowing_add(self.size) {
-            (_, true) => panic!("{:x} {:x}", self.start_addr(), self.size),
-            (e, false) => e,
-        }
-    }
-}
-
-pub struct SlobAllocator {
-    head: SlobNode,
-}
-
-impl SlobAllocator {
-    /// Creates an empty LinkedListAllocator.
-    pub const fn new() -> Self {
-        Self {
-            head: SlobNode::new(0),
-        }
-    }
-
-    /// region is also capable of storing a `ListNode`.
-    ///
-    /// Returns the adjusted size and alignment as a (size, align) tuple.
-    pub fn align_to_slob_node(layout: Layout) -> (usize, usize) {
-        let layout = layout
-            .align_to(core::mem::align_of::<SlobNode>())
-            .expect("adjusting alignment failed")
-            .pad_to_align();
-        let size = layout.size().max(core::mem::size_of::<SlobNode>());
-        (size, layout.align())
-    }
-
-    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
-        // ensure that the freed region is capable of holding ListNode
-        assert_eq!(
-            super::align_up(addr, core::mem::align_of::<SlobNode>()),
-            addr
-        );
-        assert!(size >= core::mem::size_of::<SlobNode>());
-        // create a new list node and append it at the start of the list
-        let mut node = SlobNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut SlobNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
-    }
-
-    /// Looks for a free region with the given size and alignment and removes
-    /// it from the list.
-    ///
-    /// Returns a tuple of the list node and the start address of the allocation.
-    pub fn find_region(
-        &mut self,
-        size: usize,
-        align: usize,
-    ) -> Option<(&'static mut SlobNode, usize)> {
-        // reference to current list node, updated for each iteration
-        let mut current = &mut self.head;
-        // look for a large enough memory region in linked list
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // region suitable for allocation -> remove node from list
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                // region not suitable -> continue with next region
-                current = current.next.as_mut().unwrap();
-            }
-        }
-
-        // no suitable region found
-        None
-    }
-
-    /// Try to use the given region for an allocation with given size and
-    /// alignment.
-    ///
-    /// Returns the allocation start address on success.
-    fn alloc_from_region(region: &SlobNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = super::align_up(region.start_addr(), align);
-        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
-
-        if alloc_end > region.end_addr() {
-            // region too small
-            return Err(());
-        }
-
-        let excess_size = region.end_addr() - alloc_end;
-        if excess_size > 0 && excess_size < core::mem::size_of::<SlobNode>() {
-            // rest of region too small to hold a ListNode (required because the
-            // allocation splits the region in a used and a free part)
-            return Err(());
-        }
-
-        // region suitable for allocation
-        Ok(alloc_start)
-    }
-}
@@ -0,0 +1,132 @@
+//! TLB shootdown.
+//!
+//! Modifying a page table (or an extended page table) only invalidates the
+//! stale translations cached on the current core. Other cores may still hold
+//! the old translations in their TLBs until they are explicitly invalidated.
+//!
+//! [`shootdown`] invalidates the translation on the current core, queues the
+//! request on the other online cores, and kicks them with an IPI. It returns
+//! after every core has processed the request.
+use crate::sync::{CachePadded, PerCpu, SpinLock};
+use crate::{addressing::Va, MAX_CPU};
+use abyss::x86_64::{intrinsics, Cr4};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Interrupt vector for the TLB shootdown.
+pub const TLB_SHOOTDOWN_VECTOR: usize = 101;
+
+/// Kinds of the invalidation request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flush {
+    /// Invalidate the TLB entry of the virtual address (invlpg).
+    Page(Va),
+    /// Invalidate all non-global TLB entries (reload cr3).
+    All,
+    /// Invalidate the mappings derived from the extended page table whose
+    /// EPT pointer is the given value (single-context invept).
+    Ept(u64),
+    /// Invalidate the mappings derived from all extended page tables
+    /// (all-context invept).
+    AllEpt,
+    /// Invalidate the mappings tagged with the virtual processor identifier
+    /// (single-context invvpid).
+    Vpid(u16),
+    /// Invalidate the mapping of the linear address tagged with the virtual
+    /// processor identifier (individual-address invvpid).
+    VpidAddress(u16, u64),
+    /// Invalidate the mappings tagged with all virtual processor identifiers
+    /// except 0 (all-context invvpid).
+    AllVpid,
+}
+
+impl Flush {
+    /// Perform the invalidation on the current core.
+    pub fn flush_local(&self) {
+        // invept and invvpid raise #UD outside of VMX operation. As there is
+        // nothing cached for the guests on such a core, it is safe to skip.
+        let vmx_on = Cr4::current().contains(Cr4::VMXE);
+        unsafe {
+            match self {
+                Flush::Page(va) => intrinsics::invlpg(va.into_usize()),
+                Flush::All => intrinsics::flush_tlb(),
+                Flush::Ept(eptp) if vmx_on => {
+                    intrinsics::invept(1, *eptp).expect("Failed to invept.")
+                }
+                Flush::AllEpt if vmx_on => intrinsics::invept(2, 0).expect("Failed to invept."),
+                Flush::Vpid(vpid) if vmx_on => {
+                    intrinsics::invvpid(1, *vpid, 0).expect("Failed to invvpid.")
+                }
+                Flush::VpidAddress(vpid, addr) if vmx_on => {
+                    intrinsics::invvpid(0, *vpid, *addr).expect("Failed to invvpid.")
+                }
+                Flush::AllVpid if vmx_on => {
+                    intrinsics::invvpid(2, 0, 0).expect("Failed to invvpid.")
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+struct Request {
+    flush: Flush,
+    pending: Arc<AtomicUsize>,
+}
+
+const INIT: CachePadded<SpinLock<VecDeque<Request>>> =
+    CachePadded::new(SpinLock::new(VecDeque::new()));
+static QUEUES: PerCpu<SpinLock<VecDeque<Request>>> = PerCpu::new([INIT; MAX_CPU]);
+
+/// Bitmap of the cores that can process the shootdown requests.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Process the queued requests of the current core.
+fn process_local_queue() {
+    loop {
+        let request = QUEUES.get().lock().pop_front();
+        match request {
+            Some(Request { flush, pending }) => {
+                flush.flush_local();
+                pending.fetch_sub(1, Ordering::SeqCst);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Invalidate the translation described by `flush` on every online core.
+pub fn shootdown(flush: Flush) {
+    let _p = abyss::interrupt::InterruptGuard::new();
+    let me = intrinsics::cpuid();
+    flush.flush_local();
+
+    let online = ONLINE.load(Ordering::SeqCst) & !(1 << me);
+    if online == 0 {
+        return;
+    }
+    let pending = Arc::new(AtomicUsize::new(online.count_ones() as usize));
+    for cpu in (0..MAX_CPU).filter(|cpu| online & (1 << cpu) != 0) {
+        QUEUES.get_of(cpu).unwrap().lock().push_back(Request {
+            flush,
+            pending: pending.clone(),
+        });
+        unsafe {
+            abyss::dev::x86_64::apic::send_ipi(cpu, TLB_SHOOTDOWN_VECTOR as u32);
+        }
+    }
+    // Interrupt is disabled while waiting. Serve the requests from the other
+    // cores to avoid the deadlock of concurrent shootdowns.
+    while pending.load(Ordering::SeqCst) != 0 {
+        process_local_queue();
+        core::hint::spin_loop();
+    }
+}
+
+/// Start to serve the shootdown requests on this core.
+pub(crate) fn init(core_id: usize) {
+    if core_id == 0 {
+        crate::interrupt::register(TLB_SHOOTDOWN_VECTOR, process_local_queue);
+    }
+    ONLINE.fetch_or(1 << core_id, Ordering::SeqCst);
+}
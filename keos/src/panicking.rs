@@ -1,9 +1,244 @@
 //! KEOS panic handler.
-use crate::thread::STACK_SIZE;
+//!
+//! After the panic is reported, the kernel follows the [`PanicPolicy`] set
+//! with [`set_panic_policy`] or with the `panic` boot option (see
+//! [`config`]):
+//!
+//! | Policy                    | Boot option     | Action                                         |
+//! |---------------------------|-----------------|------------------------------------------------|
+//! | [`PanicPolicy::Halt`]     | `panic=halt`    | Halt the cpu.                                  |
+//! | [`PanicPolicy::Reboot`]   | `panic=reboot`  | Reset the machine.                             |
+//! | [`PanicPolicy::ExitQemu`] | `panic=exit`    | Exit qemu with the code of the panic location. |
+//! | [`PanicPolicy::Monitor`]  | `panic=monitor` | Serve the monitor commands on the serial.      |
+//!
+//! The exit code is written to the isa-debug-exit device of qemu at
+//! [`DEBUG_EXIT_PORT`], which must be attached with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. Qemu exits with the
+//! status of `(code << 1) | 1`, so the grading scripts tell the panics apart
+//! by the status without parsing the serial output; see [`exit_code`].
+//!
+//! When keos runs as a guest of KeV, the panic is also reported to the
+//! hypervisor with the [`HC_GUEST_PANIC`] hypercall, so the host sees the
+//! message and the backtrace instead of the exit code alone:
+//!
+//! | Register | Value                                          |
+//! |----------|------------------------------------------------|
+//! | rax      | [`HC_GUEST_PANIC`] (0x204)                     |
+//! | rdi, rsi | physical address and length of the message     |
+//! | rdx      | pc of the innermost frame                      |
+//! | rcx, r8  | physical address and number of the frame pcs   |
+//!
+//! The message is truncated to [`MESSAGE_MAX`] bytes, and the backtrace to
+//! [`FRAMES_MAX`] frames.
+//!
+//! The panic is also written to the persistent log on the fs disk, which the
+//! next boot prints; see [`pstore`](crate::fs::pstore).
+//!
+//! The other crates extend the monitor with their commands, e.g. to inspect
+//! the vms that still run on the other cpus, with [`add_monitor_command`].
+//! They also print their own state on the panic, e.g. the input that crashed
+//! the kernel, with [`add_panic_hook`].
+//!
+//! [`config`]: crate::config
+use crate::sync::SpinLock;
+use abyss::x86_64::pio::Pio;
 use addr2line::{Context, Frame};
-use alloc::{borrow::Cow, sync::Arc};
+use alloc::{borrow::Cow, string::String, sync::Arc, vec::Vec};
+use core::{
+    arch::asm,
+    panic::Location,
+    sync::atomic::{AtomicU8, Ordering},
+};
 use unwind::{DwarfReader, Peeker, StackFrame, UnwindContext};
 
+/// I/O port of the isa-debug-exit device of qemu.
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Hypercall number to report a panic to the hypervisor.
+pub const HC_GUEST_PANIC: usize = 0x204;
+/// Maximum length of the message reported to the hypervisor.
+pub const MESSAGE_MAX: usize = 512;
+/// Maximum number of the frames reported to the hypervisor.
+pub const FRAMES_MAX: usize = 32;
+
+/// What the kernel does after reporting a panic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Halt the cpu.
+    Halt = 0,
+    /// Reset the machine.
+    Reboot = 1,
+    /// Exit qemu with the [`exit_code`] of the panic location.
+    ExitQemu = 2,
+    /// Serve the monitor commands on the serial.
+    Monitor = 3,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+
+/// Set the policy on a panic.
+pub fn set_panic_policy(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// Get the policy on a panic.
+pub fn panic_policy() -> PanicPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        1 => PanicPolicy::Reboot,
+        2 => PanicPolicy::ExitQemu,
+        3 => PanicPolicy::Monitor,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+/// Get the code written to the isa-debug-exit device on a panic at the
+/// `location`.
+///
+/// The code is in `0x40..0x80`, a hash of the location, so qemu exits with an
+/// odd status from 129 to 255. A panic without the location has the code
+/// 0x40.
+pub fn exit_code(location: Option<&Location>) -> u32 {
+    let Some(location) = location else {
+        return 0x40;
+    };
+    // FNV-1a.
+    let hash = location
+        .file()
+        .bytes()
+        .chain(location.line().to_le_bytes())
+        .chain(location.column().to_le_bytes())
+        .fold(0x811c_9dc5u32, |hash, b| {
+            (hash ^ b as u32).wrapping_mul(0x0100_0193)
+        });
+    0x40 | (hash & 0x3f)
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt") };
+    }
+}
+
+fn reboot() -> ! {
+    // Reset through the reset control register, then the keyboard controller.
+    Pio::new(0xcf9).write_u8(0x06);
+    Pio::new(0x64).write_u8(0xfe);
+    halt()
+}
+
+fn exit_qemu(code: u32) -> ! {
+    Pio::new(DEBUG_EXIT_PORT).write_u32(code);
+    // No isa-debug-exit device.
+    halt()
+}
+
+// Read a line from the serial, with the echo.
+fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        let Some(b) = abyss::kprint::with_serial(|serial| serial.read_byte(1_000_000)) else {
+            continue;
+        };
+        match b {
+            b'\r' | b'\n' => {
+                println!();
+                return line;
+            }
+            // Backspace and delete.
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            b if b.is_ascii() && !b.is_ascii_control() => {
+                line.push(b as char);
+                print!("{}", b as char);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A command of the monitor.
+#[derive(Clone, Copy)]
+pub struct MonitorCommand {
+    /// Name of the command.
+    pub name: &'static str,
+    /// Usage of the command, printed by `help`.
+    pub usage: &'static str,
+    /// Description of the command, printed by `help`.
+    pub help: &'static str,
+    /// Run the command with the arguments.
+    pub run: fn(&[&str]),
+}
+
+// The commands added by the other crates.
+static COMMANDS: SpinLock<Vec<MonitorCommand>> = SpinLock::new(Vec::new());
+
+/// Add the `command` to the monitor.
+///
+/// The built-in commands can not be overridden.
+pub fn add_monitor_command(command: MonitorCommand) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|c| c.name != command.name);
+    commands.push(command);
+}
+
+// The hooks added by the other crates.
+static HOOKS: SpinLock<Vec<fn()>> = SpinLock::new(Vec::new());
+
+/// Add the `hook` that runs after the panic is reported, before the kernel
+/// follows the panic policy.
+pub fn add_panic_hook(hook: fn()) {
+    HOOKS.lock().push(hook);
+}
+
+// Get the added commands, unless the panicked thread holds them.
+fn monitor_commands() -> Vec<MonitorCommand> {
+    COMMANDS
+        .try_lock()
+        .map(|commands| commands.clone())
+        .unwrap_or_default()
+}
+
+fn monitor(code: u32) -> ! {
+    println!("Entering the monitor. Type `help` for the commands.");
+    loop {
+        print!("monitor> ");
+        let line = read_line();
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next()) {
+            (None, _) => (),
+            (Some("help"), _) => {
+                println!("  stats        dump the statistics counters");
+                println!("  send <file>  send a file over the serial (YMODEM)");
+                println!("  halt         halt the cpu");
+                println!("  reboot       reset the machine");
+                println!("  exit         exit qemu with the code of the panic");
+                for command in monitor_commands() {
+                    println!("  {:<12} {}", command.usage, command.help);
+                }
+            }
+            (Some("stats"), _) => crate::stats::dump(),
+            (Some("send"), Some(name)) => {
+                if let Err(e) = crate::serial::send_file(name) {
+                    println!("Failed to send {}: {:?}", name, e);
+                }
+            }
+            (Some("halt"), _) => halt(),
+            (Some("reboot"), _) => reboot(),
+            (Some("exit"), _) => exit_qemu(code),
+            (Some(name), _) => match monitor_commands().iter().find(|c| c.name == name) {
+                Some(command) => {
+                    let args = line.split_whitespace().skip(1).collect::<Vec<_>>();
+                    (command.run)(&args)
+                }
+                None => println!("Unknown command `{}`.", name),
+            },
+        }
+    }
+}
+
 #[derive(Clone)]
 struct EhFrameReader;
 
@@ -68,8 +303,82 @@ impl<'a> core::fmt::Display for BackTracePrinter<'a> {
 
 static mut DEBUG_CONTEXT: Option<Context<gimli::EndianArcSlice<gimli::LittleEndian>>> = None;
 
+// A message truncated to the fixed buffer, formatted without the heap.
+struct Message {
+    buf: [u8; MESSAGE_MAX],
+    len: usize,
+}
+
+impl core::fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(MESSAGE_MAX - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// The frames of the backtrace.
+struct Backtrace {
+    depth: usize,
+    pcs: [u64; FRAMES_MAX],
+}
+
+impl Backtrace {
+    fn push(&mut self, frame: &StackFrame) {
+        if let Some(pc) = self.pcs.get_mut(self.depth) {
+            *pc = frame.pc() as u64;
+        }
+        self.depth += 1;
+        print_frame(self.depth, frame.pc());
+    }
+}
+
+// Check whether keos runs as a guest of KeV.
+fn is_kev_guest() -> bool {
+    use core::arch::x86_64::__cpuid;
+    unsafe {
+        if __cpuid(1).ecx & (1 << 31) == 0 {
+            return false;
+        }
+        let leaf = __cpuid(0x4000_0000);
+        [leaf.ebx, leaf.ecx, leaf.edx].map(u32::to_le_bytes) == [*b"KeVK", *b"eVKe", *b"V\0\0\0"]
+    }
+}
+
+// Get the physical address of the kernel buffer.
+fn pa_of<T>(buf: &[T]) -> usize {
+    abyss::addressing::Va::new(buf.as_ptr() as usize)
+        .map(|va| unsafe { va.into_pa().into_usize() })
+        .unwrap_or(0)
+}
+
+// Report the panic to the hypervisor.
+fn report_to_hypervisor(info: &core::panic::PanicInfo, backtrace: &Backtrace) {
+    use core::fmt::Write;
+    if !is_kev_guest() {
+        return;
+    }
+    let mut message = Message {
+        buf: [0; MESSAGE_MAX],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let frames = &backtrace.pcs[..backtrace.depth.min(FRAMES_MAX)];
+    unsafe {
+        asm!(
+            "vmcall",
+            inout("rax") HC_GUEST_PANIC => _,
+            in("rdi") pa_of(&message.buf),
+            in("rsi") message.len,
+            in("rdx") frames.first().copied().unwrap_or(0),
+            in("rcx") pa_of(frames),
+            in("r8") frames.len(),
+        );
+    }
+}
+
 #[allow(dead_code)]
-#[allow(clippy::empty_loop)]
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -82,46 +391,97 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     let frame = unwind::StackFrame::current();
     println!("Stack Backtrace:");
 
-    fn do_backtrace(depth: &mut usize, frame: &StackFrame) {
-        let pc = frame.pc() as u64;
-        *depth += 1;
-        if let Some(ctxt) = unsafe { DEBUG_CONTEXT.as_ref() } {
-            if let Ok(mut frames) = ctxt.find_frames(pc) {
-                if let Ok(Some(frame)) = frames.next() {
-                    println!(
-                        "  {:2}: 0x{:016x}  - {}",
-                        depth,
-                        pc,
-                        BackTracePrinter(frame, true)
-                    );
-                    while let Ok(Some(frame)) = frames.next() {
-                        println!("{}", BackTracePrinter(frame, false));
-                    }
-                    return;
-                }
-            }
-        }
-        println!("  {:2}: 0x{:016x}  - ?", depth, pc);
-    }
-
-    let sp_hi = frame.sp() & !(STACK_SIZE - 1);
-    if unsafe {
+    let stack = crate::thread::stack_of(frame.sp());
+    // The unwinder calls the finish hook even if it fails on a frame, so the
+    // panic is reported only once.
+    let _ = unsafe {
         UnwindContext::new_boxed(
             frame,
-            sp_hi..sp_hi + STACK_SIZE,
+            stack,
             DwarfReader::from_peeker(EhFrameReader::get_eh_frame_start(), EhFrameReader),
         )
         .unwind_raise_exception_with_hook(
-            0,
-            |depth, this, _| do_backtrace(depth, &this.frame),
-            |_| loop {},
+            Backtrace {
+                depth: 0,
+                pcs: [0; FRAMES_MAX],
+            },
+            |backtrace, this, _| backtrace.push(&this.frame),
+            |backtrace| after_backtrace(info, &backtrace),
         )
+    };
+    after_backtrace(
+        info,
+        &Backtrace {
+            depth: 0,
+            pcs: [0; FRAMES_MAX],
+        },
+    )
+}
+
+// Report the panic, and follow the panic policy.
+fn after_backtrace(info: &core::panic::PanicInfo, backtrace: &Backtrace) -> ! {
+    if backtrace.depth == 0 {
+        println!("?: ? at ?:?:?");
     }
-    .is_err()
+    report_to_hypervisor(info, backtrace);
+    crate::fs::pstore::write(
+        info.location(),
+        info.message(),
+        &backtrace.pcs[..backtrace.depth.min(FRAMES_MAX)],
+    );
+    // The panicked thread may hold the hooks.
+    for hook in HOOKS
+        .try_lock()
+        .map(|hooks| hooks.clone())
+        .unwrap_or_default()
     {
-        println!("?: ? at ?:?:?");
+        hook();
+    }
+    let code = exit_code(info.location());
+    match panic_policy() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot => reboot(),
+        PanicPolicy::ExitQemu => {
+            println!("Exiting qemu with the code {:#x}.", code);
+            exit_qemu(code)
+        }
+        PanicPolicy::Monitor => monitor(code),
     }
-    loop {}
+}
+
+/// Print the `depth`-th frame of the backtrace at `pc`.
+pub(crate) fn print_frame(depth: usize, pc: usize) {
+    let pc = pc as u64;
+    if let Some(ctxt) = unsafe { DEBUG_CONTEXT.as_ref() } {
+        if let Ok(mut frames) = ctxt.find_frames(pc) {
+            if let Ok(Some(frame)) = frames.next() {
+                println!(
+                    "  {:2}: 0x{:016x}  - {}",
+                    depth,
+                    pc,
+                    BackTracePrinter(frame, true)
+                );
+                while let Ok(Some(frame)) = frames.next() {
+                    println!("{}", BackTracePrinter(frame, false));
+                }
+                return;
+            }
+        }
+    }
+    println!("  {:2}: 0x{:016x}  - ?", depth, pc);
+}
+
+/// Call `f` with the pc of each frame of the current stack, from the caller.
+#[inline(never)]
+pub(crate) fn walk_stack(mut f: impl FnMut(usize)) {
+    let frame = unwind::StackFrame::current();
+    let stack = crate::thread::stack_of(frame.sp());
+    let _ = UnwindContext::new_boxed(
+        frame,
+        stack,
+        DwarfReader::from_peeker(EhFrameReader::get_eh_frame_start(), EhFrameReader),
+    )
+    .unwind_frame(|this, _| f(this.frame.pc()));
 }
 
 /// Load debugging symbols from kernel image
@@ -0,0 +1,192 @@
+//! User address space.
+use super::LoadError;
+use crate::{
+    addressing::{Pa, PAGE_MASK, PAGE_SIZE},
+    mm::Page,
+};
+use abyss::x86_64::intrinsics;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Flags of the page-table entries.
+const P: usize = 1 << 0;
+const RW: usize = 1 << 1;
+const US: usize = 1 << 2;
+const XD: usize = 1 << 63;
+const ADDR_MASK: usize = 0x000f_ffff_ffff_f000;
+
+/// The end of the user address space (the lower half).
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+// The process id of the next address space.
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// The page table of the kernel threads.
+static KERNEL_CR3: AtomicUsize = AtomicUsize::new(0);
+
+/// Record the page table of the kernel.
+///
+/// Called on the boot, before any address space is created.
+pub(crate) fn init() {
+    KERNEL_CR3.store(intrinsics::read_cr3(), Ordering::SeqCst);
+}
+
+/// Load the page table of `aspace`, or the kernel page table if `None`.
+///
+/// # Safety
+/// Interrupt must be blocked.
+pub(crate) unsafe fn activate(aspace: Option<&AddressSpace>) {
+    let cr3 = match aspace {
+        Some(aspace) => aspace.pml4.pa().into_usize(),
+        None => KERNEL_CR3.load(Ordering::SeqCst),
+    };
+    // Loading cr3 flushes the tlb; skip it if nothing changes.
+    if cr3 != 0 && cr3 != intrinsics::read_cr3() {
+        intrinsics::write_cr3(cr3);
+    }
+}
+
+/// An address space of a user process.
+///
+/// The upper half is shared with the kernel; only the lower half is private
+/// to the process. The pages mapped into the address space are owned by it,
+/// and freed when it is dropped.
+pub struct AddressSpace {
+    pid: u64,
+    pml4: Page,
+    // The pages of the lower-level tables.
+    tables: Vec<Page>,
+    // The pages mapped to the user.
+    pages: Vec<Page>,
+}
+
+fn zeroed_page() -> Result<Page, LoadError> {
+    let mut page = Page::new().ok_or(LoadError::OutOfMemory)?;
+    unsafe { page.inner_mut().fill(0) };
+    Ok(page)
+}
+
+// Get the table that the `entry` points to.
+unsafe fn table<'a>(entry: usize) -> &'a mut [usize] {
+    core::slice::from_raw_parts_mut(
+        Pa::new(entry & ADDR_MASK).unwrap().into_va().into_usize() as *mut usize,
+        512,
+    )
+}
+
+impl AddressSpace {
+    /// Create an empty address space.
+    pub fn new() -> Result<Self, LoadError> {
+        let pml4 = zeroed_page()?;
+        unsafe {
+            let kernel = table(KERNEL_CR3.load(Ordering::SeqCst));
+            table(pml4.pa().into_usize())[256..].copy_from_slice(&kernel[256..]);
+        }
+        Ok(Self {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            pml4,
+            tables: Vec::new(),
+            pages: Vec::new(),
+        })
+    }
+
+    /// Get the id of the process that owns the address space.
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    // Get the last-level entry of `va`, creating the tables if `create`.
+    fn pte(&mut self, va: usize, create: bool) -> Result<Option<&mut usize>, LoadError> {
+        let mut entries = unsafe { table(self.pml4.pa().into_usize()) };
+        for level in (1..4).rev() {
+            let entry = &mut entries[(va >> (12 + 9 * level)) & 0x1ff];
+            if *entry & P == 0 {
+                if !create {
+                    return Ok(None);
+                }
+                let page = zeroed_page()?;
+                // The permissions are checked on the last level.
+                *entry = unsafe { page.pa().into_usize() } | P | RW | US;
+                self.tables.push(page);
+            }
+            entries = unsafe { table(*entry) };
+        }
+        Ok(Some(&mut entries[(va >> 12) & 0x1ff]))
+    }
+
+    /// Map a zeroed page at `va`, and returns its physical address.
+    ///
+    /// If `va` is already mapped, the page is reused and the permissions are
+    /// widened, as the segments of a program may share a page.
+    pub fn map(&mut self, va: usize, writable: bool, executable: bool) -> Result<Pa, LoadError> {
+        if va >= USER_END || va & PAGE_MASK != 0 {
+            return Err(LoadError::BadAddress(va));
+        }
+        if let Some(pte) = self.pte(va, false)?.filter(|pte| **pte & P != 0) {
+            if writable {
+                *pte |= RW;
+            }
+            if executable {
+                *pte &= !XD;
+            }
+            return Ok(Pa::new(*pte & ADDR_MASK).unwrap());
+        }
+        let page = zeroed_page()?;
+        let pa = page.pa();
+        let pte = self.pte(va, true)?.unwrap();
+        *pte = unsafe { pa.into_usize() }
+            | P
+            | US
+            | if writable { RW } else { 0 }
+            | if executable { 0 } else { XD };
+        self.pages.push(page);
+        Ok(pa)
+    }
+
+    // Get the last-level entry of `va` without creating the tables.
+    fn lookup(&self, va: usize) -> Option<usize> {
+        let mut entries = unsafe { table(self.pml4.pa().into_usize()) };
+        for level in (1..4).rev() {
+            let entry = entries[(va >> (12 + 9 * level)) & 0x1ff];
+            if entry & P == 0 {
+                return None;
+            }
+            entries = unsafe { table(entry) };
+        }
+        Some(entries[(va >> 12) & 0x1ff])
+    }
+
+    /// Translate the user address `va`.
+    ///
+    /// Returns `None` if the user can not access `va`, or can not write to it
+    /// if `write`.
+    pub fn translate(&self, va: usize, write: bool) -> Option<Pa> {
+        if va >= USER_END {
+            return None;
+        }
+        let pte = self.lookup(va)?;
+        if pte & (P | US) != P | US || (write && pte & RW == 0) {
+            return None;
+        }
+        Pa::new((pte & ADDR_MASK) | (va & PAGE_MASK))
+    }
+
+    /// Copy `buf.len()` bytes from the user address `va` into `buf`.
+    ///
+    /// Returns `false` if any of the bytes is not accessible.
+    pub fn copy_from_user(&self, va: usize, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            let va = va.wrapping_add(done);
+            let len = (buf.len() - done).min(PAGE_SIZE - (va & PAGE_MASK));
+            let Some(pa) = self.translate(va, false) else {
+                return false;
+            };
+            buf[done..done + len].copy_from_slice(unsafe {
+                core::slice::from_raw_parts(pa.into_va().into_usize() as *const u8, len)
+            });
+            done += len;
+        }
+        true
+    }
+}
@@ -0,0 +1,107 @@
+//! Minimal reader of the static ELF64 executables.
+use super::LoadError;
+use alloc::vec::Vec;
+
+// Types of the program header.
+const PT_LOAD: u32 = 1;
+// Flags of the program header.
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// A loadable segment.
+pub struct Segment<'a> {
+    /// The virtual address to load the segment.
+    pub vaddr: usize,
+    /// The size of the segment in the memory.
+    pub memsz: usize,
+    /// The bytes of the segment in the file. The rest is zero-filled.
+    pub data: &'a [u8],
+    /// The segment is writable.
+    pub writable: bool,
+    /// The segment is executable.
+    pub executable: bool,
+}
+
+/// A static ELF64 executable for x86_64.
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], LoadError> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(LoadError::BadElf("truncated"))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<usize, LoadError> {
+    Ok(u16::from_le_bytes(read(bytes, offset)?) as usize)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, LoadError> {
+    Ok(u32::from_le_bytes(read(bytes, offset)?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Result<usize, LoadError> {
+    Ok(u64::from_le_bytes(read(bytes, offset)?) as usize)
+}
+
+impl<'a> Elf<'a> {
+    /// Validate the header of the executable.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, LoadError> {
+        if read::<4>(bytes, 0)? != *b"\x7fELF" {
+            return Err(LoadError::BadElf("bad magic"));
+        }
+        // ELFCLASS64, ELFDATA2LSB.
+        if read::<2>(bytes, 4)? != [2, 1] {
+            return Err(LoadError::BadElf("not a little-endian ELF64"));
+        }
+        // ET_EXEC, EM_X86_64.
+        if u16_at(bytes, 16)? != 2 || u16_at(bytes, 18)? != 62 {
+            return Err(LoadError::BadElf("not a static x86_64 executable"));
+        }
+        Ok(Self { bytes })
+    }
+
+    /// Get the entry point.
+    pub fn entry(&self) -> Result<usize, LoadError> {
+        u64_at(self.bytes, 24)
+    }
+
+    /// Get the loadable segments.
+    pub fn segments(&self) -> Result<Vec<Segment<'a>>, LoadError> {
+        let (phoff, phentsize, phnum) = (
+            u64_at(self.bytes, 32)?,
+            u16_at(self.bytes, 54)?,
+            u16_at(self.bytes, 56)?,
+        );
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if u32_at(self.bytes, ph)? != PT_LOAD {
+                continue;
+            }
+            let flags = u32_at(self.bytes, ph + 4)?;
+            let (offset, vaddr, filesz, memsz) = (
+                u64_at(self.bytes, ph + 8)?,
+                u64_at(self.bytes, ph + 16)?,
+                u64_at(self.bytes, ph + 32)?,
+                u64_at(self.bytes, ph + 40)?,
+            );
+            if filesz > memsz {
+                return Err(LoadError::BadElf("segment larger than its memory"));
+            }
+            segments.push(Segment {
+                vaddr,
+                memsz,
+                data: offset
+                    .checked_add(filesz)
+                    .and_then(|end| self.bytes.get(offset..end))
+                    .ok_or(LoadError::BadElf("truncated"))?,
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            });
+        }
+        Ok(segments)
+    }
+}
@@ -0,0 +1,98 @@
+//! User processes.
+//!
+//! A process is a thread that runs a static ELF executable in the user mode,
+//! on its own [`AddressSpace`]. The process talks to the kernel only through
+//! the system calls (see [`syscall`]), and is killed when it faults:
+//!
+//! ```ignore
+//! let handle = keos::process::spawn("hello", include_bytes!("hello.elf"))?;
+//! assert_eq!(handle.join(), 0);
+//! ```
+//!
+//! The process is scheduled like any other thread. The executable must not
+//! use the fpu or the sse registers, as their states are not saved across the
+//! threads.
+mod address_space;
+mod elf;
+pub mod syscall;
+
+pub(crate) use address_space::{activate, init};
+pub use address_space::{AddressSpace, USER_END};
+
+use crate::{
+    addressing::{PAGE_MASK, PAGE_SIZE},
+    thread::{JoinHandle, ThreadBuilder},
+};
+use abyss::interrupt::TrapFrame;
+use alloc::sync::Arc;
+
+/// Top of the user stack.
+pub const USER_STACK_TOP: usize = USER_END - PAGE_SIZE;
+/// Size of the user stack.
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// Error on loading an executable.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The executable is malformed.
+    BadElf(&'static str),
+    /// The executable is loaded outside of the user address space.
+    BadAddress(usize),
+    /// Failed to allocate the memory.
+    OutOfMemory,
+}
+
+/// Load the executable `elf` into a new address space.
+///
+/// Returns the address space and the entry point.
+pub fn load(elf: &[u8]) -> Result<(AddressSpace, usize), LoadError> {
+    let elf = elf::Elf::new(elf)?;
+    let mut aspace = AddressSpace::new()?;
+    for segment in elf.segments()? {
+        let end = segment
+            .vaddr
+            .checked_add(segment.memsz)
+            .filter(|&end| end <= USER_STACK_TOP - USER_STACK_SIZE)
+            .ok_or(LoadError::BadAddress(segment.vaddr))?;
+        let file_end = segment.vaddr + segment.data.len();
+        let mut va = segment.vaddr & !PAGE_MASK;
+        while va < end {
+            let pa = aspace.map(va, segment.writable, segment.executable)?;
+            // Copy the part of the file that falls into this page. The rest is
+            // left zeroed.
+            let (from, to) = (va.max(segment.vaddr), (va + PAGE_SIZE).min(file_end));
+            if from < to {
+                unsafe {
+                    core::slice::from_raw_parts_mut(
+                        (pa.into_va().into_usize() + (from & PAGE_MASK)) as *mut u8,
+                        to - from,
+                    )
+                }
+                .copy_from_slice(&segment.data[from - segment.vaddr..to - segment.vaddr]);
+            }
+            va += PAGE_SIZE;
+        }
+    }
+    let mut va = USER_STACK_TOP - USER_STACK_SIZE;
+    while va < USER_STACK_TOP {
+        aspace.map(va, true, false)?;
+        va += PAGE_SIZE;
+    }
+    Ok((aspace, elf.entry()?))
+}
+
+/// Spawn a process `name` that runs the executable `elf`.
+///
+/// The exit code of the process is delivered through the [`JoinHandle`].
+pub fn spawn<I>(name: I, elf: &[u8]) -> Result<JoinHandle, LoadError>
+where
+    alloc::string::String: core::convert::From<I>,
+{
+    let (aspace, entry) = load(elf)?;
+    let mut frame = TrapFrame::new_user();
+    frame.set_rip(entry);
+    frame.set_rsp(USER_STACK_TOP);
+    Ok(ThreadBuilder::new(name)
+        .address_space(Arc::new(aspace))
+        .spawn(move || frame.launch()))
+}
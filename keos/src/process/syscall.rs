@@ -0,0 +1,137 @@
+//! System calls.
+//!
+//! The user raises the [`SYSCALL_VECTOR`] with `int 0x80`. The number of the
+//! system call is in `rax`, and the arguments are in `rdi`, `rsi` and `rdx`.
+//! The result is returned in `rax`; a negative value is a [`SyscallError`].
+//!
+//! | Number | System call                    |
+//! |--------|--------------------------------|
+//! | 0      | `exit(code)`                   |
+//! | 1      | `write(fd, buf, len) -> len`   |
+//! | 2      | `yield()`                      |
+//!
+//!
+//! A tracer installed with [`set_tracer`] observes every system call before
+//! it is served, e.g. to report it to the hypervisor.
+//!
+//! [`SYSCALL_VECTOR`]: abyss::interrupt::SYSCALL_VECTOR
+use crate::{
+    addressing::PAGE_SIZE,
+    sync::Rcu,
+    thread::{scheduler::scheduler, with_current},
+};
+use abyss::interrupt::TrapFrame;
+use alloc::vec;
+
+/// A system call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    /// Exit the process with the code.
+    Exit = 0,
+    /// Write the buffer to the file descriptor; only the stdout (1) and the
+    /// stderr (2), which go to the console.
+    Write = 1,
+    /// Yield the cpu to the other threads.
+    Yield = 2,
+}
+
+impl TryFrom<usize> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(nr: usize) -> Result<Self, Self::Error> {
+        match nr {
+            0 => Ok(Self::Exit),
+            1 => Ok(Self::Write),
+            2 => Ok(Self::Yield),
+            _ => Err(SyscallError::NoSys),
+        }
+    }
+}
+
+/// Error of a system call, returned as the negated value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallError {
+    /// Bad file descriptor.
+    BadFd = 9,
+    /// Bad address.
+    BadAddress = 14,
+    /// No such system call.
+    NoSys = 38,
+}
+
+/// A tracer of the system calls, called with the process id, the number and
+/// the first three arguments of each system call.
+pub type Tracer = fn(pid: u64, nr: usize, args: [usize; 3]);
+
+static TRACER: Rcu<Tracer> = Rcu::empty();
+
+/// Install the `tracer` of the system calls, or remove it if `None`.
+pub fn set_tracer(tracer: Option<Tracer>) {
+    TRACER.replace(tracer);
+}
+
+fn write(fd: usize, buf: usize, len: usize) -> Result<usize, SyscallError> {
+    if fd != 1 && fd != 2 {
+        return Err(SyscallError::BadFd);
+    }
+    // Copy in chunks, not to allocate as much as the user asks at once.
+    let mut bytes = vec![0; len.min(PAGE_SIZE)];
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        let bytes = &mut bytes[..(len - offset).min(PAGE_SIZE)];
+        with_current(|th| {
+            th.address_space
+                .as_ref()
+                .filter(|aspace| aspace.copy_from_user(buf.wrapping_add(offset), bytes))
+                .map(|_| ())
+                .ok_or(SyscallError::BadAddress)
+        })?;
+        print!("{}", alloc::string::String::from_utf8_lossy(bytes));
+    }
+    Ok(len)
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub fn do_handle_syscall(frame: &mut TrapFrame) {
+    let regs = frame.gprs();
+    let (nr, arg0, arg1, arg2) = (regs.rax, regs.rdi, regs.rsi, regs.rdx);
+    if let Some(tracer) = TRACER.read().map(|tracer| *tracer) {
+        let pid = with_current(|th| th.address_space.as_ref().map_or(0, |aspace| aspace.pid()));
+        tracer(pid, nr, [arg0, arg1, arg2]);
+    }
+    let result = Syscall::try_from(nr).and_then(|syscall| match syscall {
+        Syscall::Exit => with_current(|th| th.exit(arg0 as i32)),
+        Syscall::Write => write(arg0, arg1, arg2),
+        Syscall::Yield => {
+            scheduler().reschedule();
+            Ok(0)
+        }
+    });
+    frame.gprs_mut().rax = match result {
+        Ok(v) => v,
+        Err(e) => -(e as isize) as usize,
+    };
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub fn do_handle_user_fault(frame: &mut TrapFrame, vector: u8, error_code: u64) {
+    let name = with_current(|th| th.name.clone());
+    match vector {
+        14 => warning!(
+            "{}: page fault at {:#x} (error code {:#x}), rip {:#x}",
+            name,
+            abyss::x86_64::intrinsics::read_cr2(),
+            error_code,
+            frame.rip()
+        ),
+        _ => warning!(
+            "{}: exception #{} (error code {:#x}), rip {:#x}",
+            name,
+            vector,
+            error_code,
+            frame.rip()
+        ),
+    }
+    with_current(|th| th.exit(-1))
+}
@@ -0,0 +1,316 @@
+//! File transfer over the serial.
+//!
+//! Debug artifacts such as the core dumps and the logs are pulled off the
+//! machine, and the test payloads are pushed into it, without rebuilding the
+//! disk image. The transfer follows the YMODEM batch protocol with 1K blocks
+//! and the CRC-16, for a single file:
+//!
+//! ```text
+//! sender                           receiver
+//!                                  <- 'C'
+//! SOH 00 FF "name\0size\0" CRC ->
+//!                                  <- ACK 'C'
+//! STX 01 FE data[1024] CRC     ->
+//!                                  <- ACK
+//! ...
+//! EOT                          ->
+//!                                  <- ACK 'C'
+//! SOH 00 FF zeros[128] CRC     ->
+//!                                  <- ACK
+//! ```
+//!
+//! A block is resent when the receiver replies NAK, and the transfer is
+//! aborted by CAN. Run qemu with the serial on a pty or a socket, and use
+//! a YMODEM tool on the host such as `sb --ymodem` and `rb --ymodem` of lrzsz.
+//!
+//! The serial is exclusively held during a transfer, so the messages printed
+//! by the other cores are held until the transfer finishes.
+use crate::fs::{file_system, Error};
+use abyss::{dev::x86_64::serial::Serial, kprint::with_serial};
+use alloc::{string::String, vec, vec::Vec};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+// Padding of the last block.
+const SUB: u8 = 0x1a;
+
+const BLOCK: usize = 1024;
+const HEADER: usize = 128;
+const RETRIES: usize = 10;
+// Timeout for a reply, and between the bytes of a block.
+const TIMEOUT_US: usize = 1_000_000;
+// Timeout for the peer to start the transfer.
+const START_TIMEOUT_US: usize = 60_000_000;
+
+/// Error of the transfer.
+#[derive(Debug)]
+pub enum TransferError {
+    /// The peer does not respond.
+    Timeout,
+    /// The peer cancels the transfer.
+    Cancelled,
+    /// A block fails too many times.
+    TooManyRetries,
+    /// The peer violates the protocol.
+    Protocol,
+    /// The file does not exist.
+    NoFile,
+    /// The received file does not fit in the file.
+    TooLarge {
+        /// The size of the received file.
+        size: usize,
+    },
+    /// The filesystem has an error.
+    Fs(Error),
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, b| {
+        (0..8).fold(crc ^ ((*b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn read(serial: &mut Serial, timeout_us: usize) -> Result<u8, TransferError> {
+    serial.read_byte(timeout_us).ok_or(TransferError::Timeout)
+}
+
+fn cancel(serial: &mut Serial) {
+    (0..3).for_each(|_| serial.write_byte(CAN));
+}
+
+// Discard the remaining bytes of a broken block.
+fn purge(serial: &mut Serial) {
+    while serial.read_byte(TIMEOUT_US / 10).is_some() {}
+}
+
+fn header(name: &str, size: usize) -> Vec<u8> {
+    let info = alloc::format!("{}\0{}", name, size);
+    // A long name is sent in a 1K block.
+    let mut block = vec![0; if info.len() < HEADER { HEADER } else { BLOCK }];
+    block[..info.len()].copy_from_slice(info.as_bytes());
+    block
+}
+
+// Wait for the receiver to request the next block with `C`.
+fn wait_start(serial: &mut Serial, timeout_us: usize) -> Result<(), TransferError> {
+    loop {
+        match read(serial, timeout_us)? {
+            CRC => return Ok(()),
+            CAN => return Err(TransferError::Cancelled),
+            _ => (),
+        }
+    }
+}
+
+fn send_block(serial: &mut Serial, seq: u8, data: &[u8]) -> Result<(), TransferError> {
+    let crc = crc16(data);
+    for _ in 0..RETRIES {
+        serial.write_byte(if data.len() == BLOCK { STX } else { SOH });
+        serial.write_byte(seq);
+        serial.write_byte(!seq);
+        data.iter().for_each(|b| serial.write_byte(*b));
+        serial.write_byte((crc >> 8) as u8);
+        serial.write_byte(crc as u8);
+        match serial.read_byte(TIMEOUT_US) {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(TransferError::Cancelled),
+            _ => (),
+        }
+    }
+    cancel(serial);
+    Err(TransferError::TooManyRetries)
+}
+
+fn send_with(
+    name: &str,
+    size: usize,
+    mut read_at: impl FnMut(usize, &mut [u8]) -> Result<(), TransferError>,
+) -> Result<(), TransferError> {
+    with_serial(|serial| {
+        wait_start(serial, START_TIMEOUT_US)?;
+        send_block(serial, 0, &header(name, size))?;
+        wait_start(serial, TIMEOUT_US)?;
+
+        let mut block = [0; BLOCK];
+        for (idx, ofs) in (0..size).step_by(BLOCK).enumerate() {
+            let len = (size - ofs).min(BLOCK);
+            if let Err(e) = read_at(ofs, &mut block[..len]) {
+                cancel(serial);
+                return Err(e);
+            }
+            block[len..].fill(SUB);
+            send_block(serial, (idx + 1) as u8, &block)?;
+        }
+
+        let mut retries = 0;
+        loop {
+            serial.write_byte(EOT);
+            match serial.read_byte(TIMEOUT_US) {
+                Some(ACK) => break,
+                _ if retries == RETRIES => return Err(TransferError::TooManyRetries),
+                _ => retries += 1,
+            }
+        }
+        // Finish the batch with an empty header.
+        wait_start(serial, TIMEOUT_US)?;
+        send_block(serial, 0, &[0; HEADER])
+    })
+}
+
+/// Send the `data` as a file of `name`.
+pub fn send(name: &str, data: &[u8]) -> Result<(), TransferError> {
+    send_with(name, data.len(), |ofs, buf| {
+        buf.copy_from_slice(&data[ofs..ofs + buf.len()]);
+        Ok(())
+    })
+}
+
+/// Send the file of `name` in the filesystem.
+pub fn send_file(name: &str) -> Result<(), TransferError> {
+    let file = file_system()
+        .and_then(|fs| fs.open(name))
+        .ok_or(TransferError::NoFile)?;
+    send_with(name, file.size(), |ofs, buf| {
+        file.read(ofs, buf).map(|_| ()).map_err(TransferError::Fs)
+    })
+}
+
+enum Packet {
+    Block(u8, Vec<u8>),
+    Eot,
+}
+
+fn recv_packet(serial: &mut Serial, timeout_us: usize) -> Result<Packet, TransferError> {
+    for _ in 0..RETRIES {
+        let len = match read(serial, timeout_us)? {
+            SOH => HEADER,
+            STX => BLOCK,
+            EOT => return Ok(Packet::Eot),
+            CAN => return Err(TransferError::Cancelled),
+            _ => {
+                purge(serial);
+                serial.write_byte(NAK);
+                continue;
+            }
+        };
+        let mut raw = vec![0; len + 4];
+        if raw
+            .iter_mut()
+            .try_for_each(|b| read(serial, TIMEOUT_US).map(|v| *b = v))
+            .is_err()
+        {
+            serial.write_byte(NAK);
+            continue;
+        }
+        let crc = u16::from_be_bytes([raw[len + 2], raw[len + 3]]);
+        if raw[0] != !raw[1] || crc16(&raw[2..len + 2]) != crc {
+            purge(serial);
+            serial.write_byte(NAK);
+            continue;
+        }
+        raw.truncate(len + 2);
+        let seq = raw[0];
+        raw.drain(..2);
+        return Ok(Packet::Block(seq, raw));
+    }
+    cancel(serial);
+    Err(TransferError::TooManyRetries)
+}
+
+fn recv_with(
+    mut write_at: impl FnMut(usize, usize, &[u8]) -> Result<(), TransferError>,
+) -> Result<(String, usize), TransferError> {
+    with_serial(|serial| {
+        // Request the header until the sender starts.
+        let mut waited = 0;
+        let header = loop {
+            serial.write_byte(CRC);
+            match recv_packet(serial, TIMEOUT_US) {
+                Ok(Packet::Block(0, header)) => break header,
+                Err(TransferError::Timeout) if waited < START_TIMEOUT_US => waited += TIMEOUT_US,
+                Err(e) => return Err(e),
+                Ok(_) => {
+                    cancel(serial);
+                    return Err(TransferError::Protocol);
+                }
+            }
+        };
+        let mut fields = header.split(|b| *b == 0 || *b == b' ');
+        let name = String::from_utf8_lossy(fields.next().unwrap_or_default()).into_owned();
+        let size = core::str::from_utf8(fields.next().unwrap_or_default())
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(TransferError::Protocol)?;
+        serial.write_byte(ACK);
+        serial.write_byte(CRC);
+
+        let (mut seq, mut ofs) = (1u8, 0);
+        loop {
+            match recv_packet(serial, TIMEOUT_US)? {
+                Packet::Block(s, data) if s == seq => {
+                    let len = data.len().min(size - ofs);
+                    if let Err(e) = write_at(size, ofs, &data[..len]) {
+                        cancel(serial);
+                        return Err(e);
+                    }
+                    ofs += len;
+                    seq = seq.wrapping_add(1);
+                    serial.write_byte(ACK);
+                }
+                // The ACK of the previous block is lost.
+                Packet::Block(s, _) if s == seq.wrapping_sub(1) => serial.write_byte(ACK),
+                Packet::Block(..) => {
+                    cancel(serial);
+                    return Err(TransferError::Protocol);
+                }
+                Packet::Eot => break,
+            }
+        }
+        serial.write_byte(ACK);
+        // Receive the empty header that finishes the batch.
+        serial.write_byte(CRC);
+        if let Ok(Packet::Block(0, _)) = recv_packet(serial, TIMEOUT_US) {
+            serial.write_byte(ACK);
+        }
+        Ok((name, size))
+    })
+}
+
+/// Receive a file, and returns its name and contents.
+pub fn recv() -> Result<(String, Vec<u8>), TransferError> {
+    let mut contents = Vec::new();
+    let (name, _) = recv_with(|size, ofs, data| {
+        contents.resize(size, 0);
+        contents[ofs..ofs + data.len()].copy_from_slice(data);
+        Ok(())
+    })?;
+    Ok((name, contents))
+}
+
+/// Receive a file into the file of `name` in the filesystem.
+///
+/// As the files have the fixed size, the file must exist with enough size.
+/// Returns the size of the received file.
+pub fn recv_file(name: &str) -> Result<usize, TransferError> {
+    let file = file_system()
+        .and_then(|fs| fs.open(name))
+        .ok_or(TransferError::NoFile)?;
+    recv_with(|size, ofs, data| {
+        if size > file.size() {
+            return Err(TransferError::TooLarge { size });
+        }
+        file.write(ofs, data).map(|_| ()).map_err(TransferError::Fs)
+    })
+    .map(|(_, size)| size)
+}
@@ -0,0 +1,183 @@
+//! Statistics counters.
+//!
+//! The subsystems count their events, such as the vmexits or the interrupts,
+//! with the counters declared as statics:
+//!
+//! ```ignore
+//! static INTERRUPTS: PerCpuCounter = PerCpuCounter::new("interrupt.count");
+//!
+//! INTERRUPTS.inc();
+//! println!("{}", INTERRUPTS.get());
+//! ```
+//!
+//! A [`Counter`] is a single atomic counter, and a [`PerCpuCounter`] keeps a
+//! counter for each cpu so that the hot paths do not bounce a cache line
+//! between the cpus; its reads sum the counters of all cpus. The increments
+//! are relaxed, so the reads are not synchronized with the other memory
+//! accesses.
+//!
+//! A counter is registered on its first increment, and [`dump`] prints all
+//! the registered counters.
+use crate::sync::{CachePadded, PerCpu};
+use crate::MAX_CPU;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+// Maximum number of the registered counters of each kind.
+const MAX_COUNTERS: usize = 128;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_COUNTER: AtomicPtr<Counter> = AtomicPtr::new(core::ptr::null_mut());
+static COUNTERS: [AtomicPtr<Counter>; MAX_COUNTERS] = [NONE_COUNTER; MAX_COUNTERS];
+static NR_COUNTERS: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_PERCPU: AtomicPtr<PerCpuCounter> = AtomicPtr::new(core::ptr::null_mut());
+static PERCPU_COUNTERS: [AtomicPtr<PerCpuCounter>; MAX_COUNTERS] = [NONE_PERCPU; MAX_COUNTERS];
+static NR_PERCPU_COUNTERS: AtomicUsize = AtomicUsize::new(0);
+
+// Register the `counter` to the `registry` once.
+//
+// This does not allocate, as the allocator also counts its events.
+fn register<T>(
+    registered: &AtomicBool,
+    counter: &'static T,
+    registry: &[AtomicPtr<T>; MAX_COUNTERS],
+    len: &AtomicUsize,
+) {
+    if registered.load(Ordering::Relaxed) || registered.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let idx = len.fetch_add(1, Ordering::SeqCst);
+    if let Some(slot) = registry.get(idx) {
+        slot.store(counter as *const T as *mut T, Ordering::SeqCst);
+    }
+}
+
+fn registered<T>(
+    registry: &'static [AtomicPtr<T>; MAX_COUNTERS],
+    len: &AtomicUsize,
+) -> impl Iterator<Item = &'static T> {
+    registry[..len.load(Ordering::SeqCst).min(MAX_COUNTERS)]
+        .iter()
+        .filter_map(|slot| unsafe { slot.load(Ordering::SeqCst).as_ref() })
+}
+
+/// A statistics counter.
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+    registered: AtomicBool,
+}
+
+impl Counter {
+    /// Create a new counter of `name`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the name of the counter.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Add `n` to the counter.
+    #[inline]
+    pub fn add(&'static self, n: u64) {
+        register(&self.registered, self, &COUNTERS, &NR_COUNTERS);
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Increment the counter.
+    #[inline]
+    pub fn inc(&'static self) {
+        self.add(1)
+    }
+
+    /// Get the value of the counter.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: CachePadded<AtomicU64> = CachePadded::new(AtomicU64::new(0));
+
+/// A statistics counter that counts on each cpu.
+pub struct PerCpuCounter {
+    name: &'static str,
+    values: PerCpu<AtomicU64>,
+    registered: AtomicBool,
+}
+
+impl PerCpuCounter {
+    /// Create a new per-cpu counter of `name`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            values: PerCpu::new([ZERO; MAX_CPU]),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the name of the counter.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Add `n` to the counter of the current cpu.
+    #[inline]
+    pub fn add(&'static self, n: u64) {
+        register(
+            &self.registered,
+            self,
+            &PERCPU_COUNTERS,
+            &NR_PERCPU_COUNTERS,
+        );
+        self.values.get().fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Increment the counter of the current cpu.
+    #[inline]
+    pub fn inc(&'static self) {
+        self.add(1)
+    }
+
+    /// Get the sum of the counters of all cpus.
+    pub fn get(&self) -> u64 {
+        self.values
+            .iter()
+            .map(|value| value.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Get the counter of the cpu `cpu`.
+    pub fn get_of(&self, cpu: usize) -> Option<u64> {
+        self.values
+            .get_of(cpu)
+            .map(|value| value.load(Ordering::Relaxed))
+    }
+}
+
+/// Print all the registered counters.
+///
+/// The per-cpu counters are printed with the counters of each cpu.
+pub fn dump() {
+    println!("{:<32} {:>16}", "counter", "value");
+    for counter in registered(&COUNTERS, &NR_COUNTERS) {
+        println!("{:<32} {:>16}", counter.name(), counter.get());
+    }
+    for counter in registered(&PERCPU_COUNTERS, &NR_PERCPU_COUNTERS) {
+        print!("{:<32} {:>16} [", counter.name(), counter.get());
+        for cpu in 0..crate::ncpu() {
+            if cpu != 0 {
+                print!(" ");
+            }
+            print!("{}", counter.get_of(cpu).unwrap_or(0));
+        }
+        println!("]");
+    }
+}
@@ -84,6 +84,14 @@
 //! - [`SpinLock`]: Mutual Exclusion mechanism, which ensures that at
 //!   most one thread at a time is able to access some data.
 //!
+//! - [`Rcu`]: Read-copy-update, which lets the readers of a read-mostly
+//!   data access it without any lock.
+//!
 //! [`SpinLock`]: crate::sync::SpinLock
+//! [`Rcu`]: crate::sync::Rcu
+
+pub mod percpu;
 
+pub use abyss::rcu::{synchronize, Rcu, RcuReadGuard, RcuWriteGuard};
 pub use abyss::spin_lock::{SpinLock, SpinLockGuard};
+pub use percpu::{CachePadded, PerCpu};
@@ -0,0 +1,126 @@
+//! Per-cpu variables.
+//!
+//! A [`PerCpu<T>`] holds one instance of `T` for each cpu. Each instance is
+//! padded to its own cache line, so that cores updating their own copy do not
+//! bounce the line between each other. This makes it suitable for hot-path
+//! data such as run queues, allocator caches and statistics counters, which
+//! otherwise would be serialized by a single global [`SpinLock`].
+//!
+//! ```
+//! use keos::sync::{CachePadded, PerCpu, SpinLock};
+//! use keos::MAX_CPU;
+//!
+//! const INIT: CachePadded<SpinLock<usize>> = CachePadded::new(SpinLock::new(0));
+//! static COUNTS: PerCpu<SpinLock<usize>> = PerCpu::new([INIT; MAX_CPU]);
+//!
+//! *COUNTS.get().lock() += 1;
+//! let total: usize = COUNTS.iter().map(|c| *c.lock()).sum();
+//! ```
+//!
+//! [`SpinLock`]: crate::sync::SpinLock
+
+use crate::MAX_CPU;
+use abyss::interrupt::InterruptGuard;
+
+/// Size of the cache line.
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// Pads and aligns a value to the length of a cache line.
+#[repr(align(64))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    /// Pads and aligns a value to the length of a cache line.
+    pub const fn new(t: T) -> Self {
+        Self(t)
+    }
+
+    /// Returns the inner value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> core::ops::DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// A per-cpu variable.
+///
+/// The instance of the current cpu is obtained by [`PerCpu::get`], and the
+/// instances of all cpus can be visited by [`PerCpu::iter`].
+///
+/// Note that a thread can be migrated to another cpu at any time while
+/// interrupts are enabled. [`PerCpu::get`] therefore only guarantees that the
+/// returned reference *was* local at the time of the call, which is fine for
+/// atomics or lock-protected data. Use [`PerCpu::with`] to access the local
+/// instance without being migrated.
+pub struct PerCpu<T> {
+    inner: [CachePadded<T>; MAX_CPU],
+}
+
+// SAFETY: Each instance is only shared as `&T`, which requires `T: Sync`.
+unsafe impl<T: Send + Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Create a new per-cpu variable from the instances of each cpu.
+    pub const fn new(inner: [CachePadded<T>; MAX_CPU]) -> Self {
+        Self { inner }
+    }
+
+    /// Create a new per-cpu variable by calling `f` with each cpu id.
+    pub fn from_fn(mut f: impl FnMut(usize) -> T) -> Self {
+        Self {
+            inner: core::array::from_fn(|cpu| CachePadded::new(f(cpu))),
+        }
+    }
+
+    /// Get the instance of the current cpu.
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.inner[abyss::x86_64::intrinsics::cpuid()]
+    }
+
+    /// Get the mutable instance of the current cpu.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner[abyss::x86_64::intrinsics::cpuid()]
+    }
+
+    /// Get the instance of the cpu `cpu`.
+    #[inline]
+    pub fn get_of(&self, cpu: usize) -> Option<&T> {
+        self.inner.get(cpu).map(|v| &v.0)
+    }
+
+    /// Run `f` with the instance of the current cpu while the interrupt is
+    /// disabled, so that the current thread can not be migrated to another
+    /// cpu.
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let _p = InterruptGuard::new();
+        f(self.get())
+    }
+
+    /// Iterate over the instances of all cpus.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.inner.iter().map(|v| &v.0)
+    }
+}
+
+impl<T: Default> Default for PerCpu<T> {
+    fn default() -> Self {
+        Self::from_fn(|_| T::default())
+    }
+}
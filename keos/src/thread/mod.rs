@@ -5,36 +5,189 @@
 //! An executing kernel consists of a collection of threads,
 //! each with their own stack and local state. Threads can be named, and
 //! provide some built-in support for low-level synchronization.
+//!
+//! ## Stacks
+//!
+//! Each thread runs on its own stack of [`STACK_SIZE`] bytes, or of the size
+//! set by [`ThreadBuilder::stack_size`]. The stack is not aligned to its size;
+//! the current thread is found through the per-cpu area of the GS segment
+//! (see [`abyss::x86_64::gs`]), which the context switch updates.
+//!
+//! ## Diagnostics
+//!
+//! Every live thread is registered by its name, which is set with
+//! [`Thread::set_name`]. [`dump_all`] lists the live threads with their state,
+//! the cpu they run on, and the high-water mark of their stack usage, which
+//! helps to find out which thread hangs:
+//!
+//! ```text
+//! NAME                                           STATE      CPU  CPU TIME   STACK
+//! 6ba7b810-9dad-11d1-80b4-00c04fd430c8/vcpu#0    Running    1    1203 ms    23 KiB
+//! 6ba7b810-9dad-11d1-80b4-00c04fd430c8/watchdog  Parked     -    0 ms       4 KiB
+//! ```
 pub mod channel;
 pub mod scheduler;
-
-use abyss::{interrupt::InterruptGuard, x86_64::intrinsics::cpuid};
-use alloc::{boxed::Box, string::String, sync::Arc};
+pub mod stride;
+mod timer;
+pub mod workqueue;
+
+use crate::{process::AddressSpace, sync::SpinLock};
+use abyss::{
+    addressing::PAGE_SIZE,
+    interrupt::InterruptGuard,
+    x86_64::{gs, intrinsics::cpuid},
+};
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
 use core::{
-    arch::asm,
-    sync::atomic::{AtomicI32, AtomicU64, Ordering},
+    arch::{asm, x86_64::_rdtsc},
+    ops::Range,
+    ptr::{addr_of, read_volatile},
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+    time::Duration,
 };
 
-/// Size of each thread's stack.
-pub const STACK_SIZE: usize = 0x100000;
+/// Default size of each thread's stack.
+///
+/// Set the size of a thread with [`ThreadBuilder::stack_size`].
+pub const STACK_SIZE: usize = 0x40000;
+/// Smallest size of a thread's stack.
+pub const MIN_STACK_SIZE: usize = 0x4000;
+// Size of the boot stacks, which the idle threads run on.
+pub(crate) const BOOT_STACK_SIZE: usize = 0x100000;
 /// Thread magic to detect stack overflow.
 pub const THREAD_MAGIC: usize = 0xdeadbeefcafebabe;
+// Pattern filled in the unused stack, to find the high-water mark.
+const STACK_PAINT: u8 = 0xa5;
+
+// Names of the live threads, by the address of the thread.
+static THREADS: SpinLock<BTreeMap<usize, String>> = SpinLock::new(BTreeMap::new());
 
 /// The Thread stack.
 ///
-/// DO NOT MODIFY THIS STRUCT.
-#[repr(C, align(0x100000))]
-#[doc(hidden)]
+/// The stack grows down from the top, and the [`THREAD_MAGIC`] at the bottom
+/// is overwritten when the stack overflows.
 pub(crate) struct ThreadStack {
-    pub(crate) thread: *mut Thread,
-    pub(crate) magic: usize,
-    /// Padding to fill up to [`STACK_SIZE`]
-    pub(crate) _pad:
-        [u8; STACK_SIZE - core::mem::size_of::<*mut Thread>() - core::mem::size_of::<usize>()],
-    /// Marker of address of usable stack.
-    pub(crate) _usable_marker: [u8; 0],
-    /// Pinned.
-    _pin: core::marker::PhantomPinned,
+    base: *mut u8,
+    size: usize,
+    // Whether the stack is allocated on the heap. The idle threads run on the
+    // boot stacks.
+    owned: bool,
+}
+
+impl ThreadStack {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, PAGE_SIZE).unwrap()
+    }
+
+    // Allocate a stack of at least `size` bytes.
+    fn new(size: usize) -> Self {
+        let size = size.max(MIN_STACK_SIZE).next_multiple_of(PAGE_SIZE);
+        let base = unsafe { alloc(Self::layout(size)) };
+        assert!(!base.is_null(), "Failed to allocate a thread stack.");
+        unsafe {
+            core::ptr::write_bytes(base, STACK_PAINT, size);
+            (base as *mut usize).write(THREAD_MAGIC);
+        }
+        Self {
+            base,
+            size,
+            owned: true,
+        }
+    }
+
+    /// The boot stack that `sp` is on.
+    pub(crate) unsafe fn boot(sp: usize) -> Self {
+        let base = (sp & !(BOOT_STACK_SIZE - 1)) as *mut u8;
+        (base as *mut usize).write(THREAD_MAGIC);
+        Self {
+            base,
+            size: BOOT_STACK_SIZE,
+            owned: false,
+        }
+    }
+
+    /// Address range of the stack.
+    pub(crate) fn range(&self) -> Range<usize> {
+        self.base as usize..self.base as usize + self.size
+    }
+
+    fn is_overflowed(&self) -> bool {
+        unsafe { read_volatile(self.base as *const usize) != THREAD_MAGIC }
+    }
+
+    // The most bytes ever used, counted by the paint that is never
+    // overwritten. The stack may be in use on the other cpu.
+    fn used(&self) -> usize {
+        let paint = usize::from_ne_bytes([STACK_PAINT; 8]);
+        let words = self.base as *const usize;
+        let untouched = (1..self.size / 8)
+            .take_while(|i| unsafe { read_volatile(words.add(*i)) } == paint)
+            .count();
+        self.size - 8 - untouched * 8
+    }
+}
+
+impl Drop for ThreadStack {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { dealloc(self.base, Self::layout(self.size)) }
+        }
+    }
+}
+
+/// Cpu time consumed by a thread.
+#[derive(Default)]
+pub(crate) struct CpuTime {
+    // Cycles consumed until the thread is switched out last time.
+    total: AtomicU64,
+    // Tsc when the thread is switched in, or 0 if it is not running.
+    since: AtomicU64,
+    // Cycles waited in the run queue until the thread is switched in.
+    delay: AtomicU64,
+    // Tsc when the thread becomes runnable, or 0 if it is not waiting.
+    waiting: AtomicU64,
+}
+
+impl CpuTime {
+    fn get(&self) -> u64 {
+        let (total, since) = (
+            self.total.load(Ordering::SeqCst),
+            self.since.load(Ordering::SeqCst),
+        );
+        match since {
+            0 => total,
+            since => total + unsafe { _rdtsc() }.saturating_sub(since),
+        }
+    }
+
+    fn switch_in(&self, now: u64) {
+        self.since.store(now, Ordering::SeqCst);
+        let waiting = self.waiting.swap(0, Ordering::SeqCst);
+        if waiting != 0 {
+            self.delay
+                .fetch_add(now.saturating_sub(waiting), Ordering::SeqCst);
+        }
+    }
+
+    // The thread becomes runnable at `now`.
+    fn wait(&self, now: u64) {
+        self.waiting.store(now, Ordering::SeqCst);
+    }
+
+    fn switch_out(&self, now: u64) {
+        let since = self.since.swap(0, Ordering::SeqCst);
+        if since != 0 {
+            self.total
+                .fetch_add(now.saturating_sub(since), Ordering::SeqCst);
+        }
+    }
 }
 
 /// A possible state of the thread.
@@ -63,13 +216,19 @@ pub struct Thread {
     /// You must add your own members **BELOWS** this sp field.
     pub(crate) sp: usize,
     /// Thread Stack
-    pub(crate) stack: Box<ThreadStack>,
+    pub(crate) stack: ThreadStack,
     /// Thread name
     pub name: String,
     /// State of the thread.
     pub state: ThreadState,
     pub(crate) running_cpu: Arc<AtomicI32>,
     pub(crate) exit_status: Arc<AtomicU64>,
+    pub(crate) cpu_time: Arc<CpuTime>,
+    pub(crate) stop: Arc<AtomicBool>,
+    pub(crate) weight: u32,
+    pub(crate) pass: u64,
+    /// The address space of the user process, if the thread is.
+    pub(crate) address_space: Option<Arc<AddressSpace>>,
 }
 
 impl Thread {
@@ -79,17 +238,77 @@ impl Thread {
     where
         alloc::string::String: core::convert::From<I>,
     {
-        let mut stack: Box<ThreadStack> = unsafe { Box::new_uninit().assume_init() };
-        stack.magic = THREAD_MAGIC;
+        Self::with_stack(name, ThreadStack::new(STACK_SIZE))
+    }
 
-        Box::new(Self {
+    pub(crate) fn with_stack<I>(name: I, stack: ThreadStack) -> Box<Self>
+    where
+        alloc::string::String: core::convert::From<I>,
+    {
+        let name = String::from(name);
+        let th = Box::new(Self {
             sp: 0,
             stack,
-            name: String::from(name),
+            name: name.clone(),
             state: ThreadState::Runnable,
             exit_status: Arc::new(AtomicU64::new(0)),
             running_cpu: Arc::new(AtomicI32::new(-1)),
-        })
+            cpu_time: Arc::new(CpuTime::default()),
+            stop: Arc::new(AtomicBool::new(false)),
+            weight: 1,
+            pass: 0,
+            address_space: None,
+        });
+        THREADS
+            .lock()
+            .insert(th.as_ref() as *const _ as usize, name);
+        th
+    }
+
+    /// Set the name of the thread.
+    ///
+    /// Use this instead of assigning the [`Thread::name`], to update the name
+    /// shown by [`dump_all`].
+    pub fn set_name<I>(&mut self, name: I)
+    where
+        alloc::string::String: core::convert::From<I>,
+    {
+        self.name = String::from(name);
+        if let Some(name) = THREADS.lock().get_mut(&(self as *const _ as usize)) {
+            name.clone_from(&self.name);
+        }
+    }
+
+    /// Get the scheduling weight of the thread.
+    ///
+    /// See [`stride`] for the details.
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Set the scheduling weight of the thread.
+    ///
+    /// The weight must be positive.
+    pub fn set_weight(&mut self, weight: u32) {
+        assert!(weight > 0, "Weight must be positive.");
+        self.weight = weight;
+    }
+
+    /// Get the cpu time consumed by the thread, in tsc cycles.
+    ///
+    /// Use [`abyss::dev::x86_64::timer::tsc_khz`] to convert it into the
+    /// wall-clock time.
+    pub fn cpu_time(&self) -> u64 {
+        self.cpu_time.get()
+    }
+
+    /// Get the time that the thread was runnable but waited for a cpu, in tsc
+    /// cycles.
+    ///
+    /// This is the run delay of the thread, e.g. the steal time of a vcpu
+    /// thread.
+    pub fn run_delay(&self) -> u64 {
+        self.cpu_time.delay.load(Ordering::SeqCst)
     }
 
     /// Exit the thread with `exit_code`.
@@ -110,6 +329,46 @@ impl Thread {
         scheduler::scheduler().reschedule();
     }
 
+    /// Park the current thread for up to `timeout`, and run the given closure.
+    ///
+    /// The thread is woken up when the [`TimedParkHandle`] is unparked or the
+    /// `timeout` expires, whichever comes first. Returns `true` if the thread
+    /// is woken up by the handle.
+    pub fn park_current_timeout(timeout: Duration, f: impl FnOnce(TimedParkHandle)) -> bool {
+        let slot: timer::Slot = Arc::new(SpinLock::new(None));
+        let woken = Arc::new(AtomicBool::new(false));
+        let deadline = timer::deadline(timeout);
+        // Not to be woken up by the timer of this cpu before being switched
+        // out.
+        let _p = Thread::pin();
+        Thread::park_current_and(|handle| {
+            *slot.lock() = Some(handle);
+            timer::add(deadline, slot.clone());
+            f(TimedParkHandle {
+                slot: slot.clone(),
+                woken: woken.clone(),
+            });
+        });
+        woken.load(Ordering::SeqCst)
+    }
+
+    /// Check whether the current thread is requested to stop.
+    ///
+    /// A long-running thread calls this at the points where it can stop
+    /// safely, and exits by itself when requested. See
+    /// [`JoinHandle::request_stop`].
+    pub fn should_stop() -> bool {
+        with_current(|th| th.stop.load(Ordering::SeqCst))
+    }
+
+    /// Put the current thread to sleep for `duration`.
+    ///
+    /// Unlike spinning, the cpu runs the other threads while sleeping. The
+    /// sleep lasts at least `duration`, and is rounded up to the timer tick.
+    pub fn sleep(duration: Duration) {
+        Thread::park_current_timeout(duration, |_| ());
+    }
+
     pub(crate) unsafe fn do_run(&mut self) {
         let _p = abyss::interrupt::InterruptGuard::new();
         let next_sp = self.sp;
@@ -123,6 +382,8 @@ impl Thread {
             abyss::interrupt::InterruptState::current(),
             abyss::interrupt::InterruptState::Off
         );
+        crate::process::activate(self.address_space.as_deref());
+        gs::set_current(self as *mut _ as usize);
         context_switch_trampoline(current_sp, next_sp)
     }
 
@@ -139,9 +400,105 @@ impl Thread {
     }
 }
 
+impl Drop for Thread {
+    fn drop(&mut self) {
+        // The stack is freed after the thread is removed, so [`threads`]
+        // never scans a freed stack.
+        THREADS.lock().remove(&(self as *const _ as usize));
+    }
+}
+
 /// A RAII implementation of the thread pinning.
 pub type ThreadPinGuard = InterruptGuard;
 
+/// A snapshot of a live thread.
+///
+/// See [`threads`].
+#[derive(Clone, Debug)]
+pub struct ThreadInfo {
+    /// Name of the thread.
+    pub name: String,
+    /// State of the thread.
+    pub state: ThreadState,
+    /// The cpu that the thread is running on.
+    pub cpu: Option<usize>,
+    /// Cpu time consumed by the thread, in tsc cycles.
+    pub cpu_time: u64,
+    /// The most bytes of the stack ever used, or `None` for the idle threads
+    /// that run on the boot stacks.
+    pub stack_used: Option<usize>,
+}
+
+// Read the snapshot of the thread at `th`, which is not freed while the
+// registry is locked.
+//
+// The thread may run on the other cpu, so its fields are read without
+// creating a reference to it.
+unsafe fn snapshot(th: *const Thread, name: &str) -> ThreadInfo {
+    let state = read_volatile(addr_of!((*th).state));
+    let cpu = match (*addr_of!((*th).running_cpu)).load(Ordering::SeqCst) {
+        v if v < 0 => None,
+        v => Some(v as usize),
+    };
+    let stack_used = if state == ThreadState::Idle {
+        None
+    } else {
+        Some((*addr_of!((*th).stack)).used())
+    };
+    ThreadInfo {
+        name: String::from(name),
+        state,
+        cpu,
+        cpu_time: (*addr_of!((*th).cpu_time)).get(),
+        stack_used,
+    }
+}
+
+/// Get the snapshots of the live threads.
+///
+/// The threads keep running while the snapshots are taken, so the snapshots
+/// may not be consistent with each other.
+pub fn threads() -> Vec<ThreadInfo> {
+    THREADS
+        .lock()
+        .iter()
+        .map(|(th, name)| unsafe { snapshot(*th as *const Thread, name) })
+        .collect()
+}
+
+/// Print the live threads with their state, cpu and stack usage.
+pub fn dump_all() {
+    let threads = threads();
+    let width = threads
+        .iter()
+        .map(|th| th.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let khz = abyss::dev::x86_64::timer::tsc_khz().max(1);
+    println!(
+        "{:width$}  {:10} {:4} {:10} STACK",
+        "NAME", "STATE", "CPU", "CPU TIME"
+    );
+    for th in threads {
+        let state = alloc::format!("{:?}", th.state);
+        let cpu = th
+            .cpu
+            .map_or(String::from("-"), |cpu| alloc::format!("{}", cpu));
+        let time = alloc::format!("{} ms", th.cpu_time / khz);
+        let stack = th.stack_used.map_or(String::from("-"), |used| {
+            alloc::format!("{} KiB", used.div_ceil(1024))
+        });
+        println!(
+            "{:width$}  {:10} {:4} {:10} {}",
+            th.name, state, cpu, time, stack
+        );
+    }
+}
+
+// Interval to check whether the joined thread exits.
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 /// A handle to join thread.
 pub struct JoinHandle
 where
@@ -150,6 +507,8 @@ where
     // Define any member you need.
     exit_status: Arc<AtomicU64>,
     running_cpu: Arc<AtomicI32>,
+    cpu_time: Arc<CpuTime>,
+    stop: Arc<AtomicBool>,
 }
 
 impl JoinHandle {
@@ -159,19 +518,64 @@ impl JoinHandle {
         Self {
             exit_status: th.exit_status.clone(),
             running_cpu: th.running_cpu.clone(),
+            cpu_time: th.cpu_time.clone(),
+            stop: th.stop.clone(),
+        }
+    }
+
+    /// Get the cpu time consumed by the underlying thread, in tsc cycles.
+    ///
+    /// See [`Thread::cpu_time`].
+    pub fn cpu_time(&self) -> u64 {
+        self.cpu_time.get()
+    }
+
+    // Exit code of the thread, or `None` if it is running.
+    fn exit_code(&self) -> Option<i32> {
+        match self.exit_status.load(Ordering::SeqCst) {
+            v if v >= 0x8000_0000_0000_0000 => Some(v as i32),
+            _ => None,
         }
     }
 
     /// Join this handle and returns exit code.
+    ///
+    /// The current thread sleeps while the underlying thread is running.
     pub fn join(self) -> i32 {
         loop {
-            let v = self.exit_status.load(Ordering::SeqCst);
-            if v >= 0x8000_0000_0000_0000 {
-                return v as i32;
+            if let Some(exit_code) = self.exit_code() {
+                return exit_code;
+            }
+            Thread::sleep(JOIN_POLL_INTERVAL);
+        }
+    }
+
+    /// Join this handle for up to `timeout`, and returns exit code.
+    ///
+    /// Returns `None` if the thread does not exit in time. The handle is not
+    /// consumed, so the thread can be joined again, e.g. after
+    /// [`JoinHandle::request_stop`].
+    pub fn join_timeout(&self, timeout: Duration) -> Option<i32> {
+        let deadline = timer::deadline(timeout);
+        loop {
+            if let Some(exit_code) = self.exit_code() {
+                return Some(exit_code);
+            }
+            if timer::now() >= deadline {
+                return None;
             }
+            Thread::sleep(JOIN_POLL_INTERVAL);
         }
     }
 
+    /// Request the underlying thread to stop.
+    ///
+    /// The stop is cooperative; the thread stops only when it checks
+    /// [`Thread::should_stop`].
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
     /// Get scheudled cpu id of the underlying thread.
     ///
     /// If the thread is not runnig, returns None.
@@ -196,6 +600,11 @@ impl ParkHandle {
         Self { th }
     }
 
+    /// Get a [`JoinHandle`] of the underlying thread.
+    pub fn join_handle(&self) -> JoinHandle {
+        JoinHandle::new_for(&self.th)
+    }
+
     /// Consume the handle and unpark the underlying thread.
     pub fn unpark(mut self) {
         // Wait until context switch is finished.
@@ -203,6 +612,7 @@ impl ParkHandle {
             core::hint::spin_loop()
         }
         self.th.state = ThreadState::Runnable;
+        self.th.cpu_time.wait(unsafe { _rdtsc() });
         scheduler::scheduler().push_to_queue(self.th);
     }
 }
@@ -210,6 +620,30 @@ impl ParkHandle {
 unsafe impl Send for ParkHandle {}
 unsafe impl Sync for ParkHandle {}
 
+/// A handle that represent the thread parked with a timeout.
+///
+/// See [`Thread::park_current_timeout`].
+pub struct TimedParkHandle {
+    slot: timer::Slot,
+    woken: Arc<AtomicBool>,
+}
+
+impl TimedParkHandle {
+    /// Consume the handle and unpark the underlying thread.
+    ///
+    /// Returns `false` if the thread is already woken up by the timeout.
+    pub fn unpark(self) -> bool {
+        match self.slot.lock().take() {
+            Some(handle) => {
+                self.woken.store(true, Ordering::SeqCst);
+                handle.unpark();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 // Context switch related codes.
 
 /// The context-switch magic.
@@ -248,6 +682,9 @@ unsafe extern "C" fn finish_context_switch(prev: &'static mut Thread) {
         abyss::interrupt::InterruptState::current(),
         abyss::interrupt::InterruptState::Off
     );
+    scheduler::CONTEXT_SWITCHES.inc();
+    let now = _rdtsc();
+    prev.cpu_time.switch_out(now);
     match prev.state {
         ThreadState::Exited(_e) => {
             let _ = Box::from_raw(prev);
@@ -255,6 +692,7 @@ unsafe extern "C" fn finish_context_switch(prev: &'static mut Thread) {
         ThreadState::Idle => (),
         ThreadState::Running => {
             prev.state = ThreadState::Runnable;
+            prev.cpu_time.wait(now);
             let th = Box::from_raw(prev);
             scheduler::scheduler().push_to_queue(th);
         }
@@ -265,37 +703,44 @@ unsafe extern "C" fn finish_context_switch(prev: &'static mut Thread) {
         if th.state != ThreadState::Idle {
             th.state = ThreadState::Running
         }
-        abyss::x86_64::segmentation::SegmentTable::update_tss(
-            th.stack.as_mut() as *mut _ as usize + STACK_SIZE,
-        );
+        abyss::x86_64::segmentation::SegmentTable::update_tss(th.stack.range().end);
         th.running_cpu.store(cpuid() as i32, Ordering::SeqCst);
+        th.cpu_time.switch_in(now);
     });
     prev.running_cpu.store(-1, Ordering::SeqCst);
 }
 
 /// Run a function `f` with current thread as an argument.
 pub fn with_current<R>(f: impl FnOnce(&mut Thread) -> R) -> R {
-    unsafe {
-        let mut sp: usize;
-        asm!("mov {}, rsp", out(reg) sp);
-        let current_stack = ((sp & !(STACK_SIZE - 1)) as *mut ThreadStack)
-            .as_mut()
-            .unwrap();
-
-        if current_stack.magic != THREAD_MAGIC {
-            panic!(
-                "Stack overflow detected! You might allocate big local variable. Stack: {:?}",
-                current_stack as *const _
-            )
-        } else {
-            f(current_stack.thread.as_mut().unwrap())
+    let current = unsafe { (gs::current() as *mut Thread).as_mut() }.expect("No current thread.");
+    if current.stack.is_overflowed() {
+        panic!(
+            "Stack overflow detected! You might allocate big local variable. Stack: {:#x?}",
+            current.stack.range()
+        )
+    }
+    f(current)
+}
+
+/// Get the range of the stack that `sp` is on.
+///
+/// Before the threads start, the stack is the boot stack.
+pub(crate) fn stack_of(sp: usize) -> Range<usize> {
+    match unsafe { (gs::current() as *const Thread).as_ref() } {
+        Some(th) if th.stack.range().contains(&sp) => th.stack.range(),
+        _ => {
+            let base = sp & !(BOOT_STACK_SIZE - 1);
+            base..base + BOOT_STACK_SIZE
         }
     }
 }
 
 /// A struct to build a new thread.
 pub struct ThreadBuilder {
-    th: Box<Thread>,
+    name: String,
+    stack_size: usize,
+    weight: u32,
+    address_space: Option<Arc<AddressSpace>>,
 }
 
 /// A struct to mimic a stack state on context switch.
@@ -319,7 +764,10 @@ impl ThreadBuilder {
         alloc::string::String: core::convert::From<I>,
     {
         Self {
-            th: Thread::new(name),
+            name: String::from(name),
+            stack_size: STACK_SIZE,
+            weight: 1,
+            address_space: None,
         }
     }
 
@@ -344,11 +792,11 @@ impl ThreadBuilder {
             unreachable!()
         }
 
-        let Self { mut th } = self;
-        let stack = th.stack.as_mut();
+        let mut th = Thread::with_stack(self.name, ThreadStack::new(self.stack_size));
+        th.set_weight(self.weight);
+        th.address_space = self.address_space;
         let frame = unsafe {
-            ((&mut stack._usable_marker as *mut _ as usize
-                - core::mem::size_of::<ContextSwitchFrame<F>>())
+            ((th.stack.range().end - core::mem::size_of::<ContextSwitchFrame<F>>())
                 as *mut ContextSwitchFrame<F>)
                 .as_mut()
                 .unwrap()
@@ -357,10 +805,34 @@ impl ThreadBuilder {
         frame.thread_fn = Box::into_raw(Box::new(thread_fn));
         frame.ret_addr = start::<F> as usize;
         th.sp = frame as *mut _ as usize;
-        th.stack.thread = th.as_mut() as *mut _;
         th
     }
 
+    /// Set the scheduling weight of the thread.
+    ///
+    /// The weight is 1 by default. See [`stride`] for the details.
+    pub fn weight(mut self, weight: u32) -> Self {
+        assert!(weight > 0, "Weight must be positive.");
+        self.weight = weight;
+        self
+    }
+
+    /// Set the size of the stack of the thread.
+    ///
+    /// The size is [`STACK_SIZE`] by default, and is rounded up to the page
+    /// size and to at least [`MIN_STACK_SIZE`]. A small stack lets thousands
+    /// of light threads, e.g. the I/O workers, run at once.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Run the thread on the user address space `aspace`.
+    pub(crate) fn address_space(mut self, aspace: Arc<AddressSpace>) -> Self {
+        self.address_space = Some(aspace);
+        self
+    }
+
     /// Spawn the thread as a parked state.
     pub fn spawn_as_parked<F: FnOnce() + Send + 'static>(self, thread_fn: F) -> ParkHandle {
         let th = self.to_thread(thread_fn);
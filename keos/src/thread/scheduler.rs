@@ -1,8 +1,15 @@
 //! Thread scheduler
 
-use super::{ParkHandle, Thread, ThreadStack, ThreadState, STACK_SIZE, THREAD_MAGIC};
+use super::{ParkHandle, Thread, ThreadStack, ThreadState};
+use crate::{
+    stats::PerCpuCounter,
+    sync::{CachePadded, PerCpu},
+};
 use alloc::boxed::Box;
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 /// Common features of thread scheduler.
 pub trait Scheduler {
@@ -13,15 +20,49 @@ pub trait Scheduler {
     /// Push a thread `th` into scheduling queue.
     fn push_to_queue(&self, th: Box<Thread>);
     /// Called on every timer interrupt (1ms).
+    ///
+    /// The tick is stopped while the cpu is idle.
     fn timer_tick(&self);
+    /// Called on every timer interrupt before [`Scheduler::timer_tick`], with
+    /// the time that the hypervisor stole from this cpu since the last call,
+    /// in nanoseconds.
+    ///
+    /// The current thread did not run while its vcpu waited for the host, so a
+    /// fair scheduler does not charge the stolen time to the thread. It is not
+    /// called if the hypervisor does not publish the steal time; see
+    /// [`metrics`](crate::metrics).
+    fn account_steal(&self, _stolen: u64) {}
 }
 
 static mut SCHEDULER: Option<&'static dyn Scheduler> = None;
 
+pub(crate) static CONTEXT_SWITCHES: PerCpuCounter = PerCpuCounter::new("sched.context_switches");
+static TIMER_TICKS: PerCpuCounter = PerCpuCounter::new("sched.timer_ticks");
+static IDLE_RUNS: PerCpuCounter = PerCpuCounter::new("sched.idle");
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_STEAL: CachePadded<AtomicU64> = CachePadded::new(AtomicU64::new(0));
+// Steal time of each cpu at the last timer interrupt, in nanoseconds.
+static STEAL: PerCpu<AtomicU64> = PerCpu::new([NO_STEAL; abyss::MAX_CPU]);
+
+// Get the time stolen from the current cpu since the last call.
+fn stolen() -> Option<u64> {
+    let steal = crate::metrics::vcpu(abyss::x86_64::intrinsics::cpuid())?.steal;
+    Some(steal.saturating_sub(STEAL.get().swap(steal, Ordering::Relaxed)))
+}
+
 /// Set the scheduler of the kernel.
 pub unsafe fn set_scheduler(t: impl Scheduler + 'static) {
     SCHEDULER = (Box::into_raw(Box::new(t)) as *const dyn Scheduler).as_ref();
-    crate::interrupt::register(32, || scheduler().timer_tick());
+    crate::interrupt::register(32, || {
+        TIMER_TICKS.inc();
+        super::timer::expire();
+        let scheduler = scheduler();
+        if let Some(stolen) = stolen() {
+            scheduler.account_steal(stolen);
+        }
+        scheduler.timer_tick()
+    });
 }
 
 /// Get the reference of the kernel
@@ -36,11 +77,9 @@ impl dyn Scheduler {
         if let Some(th) = self.next_to_run() {
             th.run();
         } else {
+            IDLE_RUNS.inc();
             unsafe {
-                IDLE[abyss::x86_64::intrinsics::cpuid()]
-                    .as_mut()
-                    .unwrap()
-                    .do_run();
+                IDLE.get_mut().as_mut().unwrap().do_run();
             }
         }
     }
@@ -59,26 +98,29 @@ impl dyn Scheduler {
     }
 }
 
-const INIT: Option<Box<Thread>> = None;
-static mut IDLE: [Option<Box<Thread>>; abyss::MAX_CPU] = [INIT; abyss::MAX_CPU];
+const INIT: CachePadded<Option<Box<Thread>>> = CachePadded::new(None);
+static mut IDLE: PerCpu<Option<Box<Thread>>> = PerCpu::new([INIT; abyss::MAX_CPU]);
 
 /// Transmute this thread into the idle.
 pub unsafe fn start_idle(core_id: usize) -> ! {
     let mut sp: usize;
     asm!("mov {}, rsp", out(reg) sp);
 
-    let mut tcb = Thread::new("idle");
+    let mut tcb = Thread::with_stack("idle", ThreadStack::boot(sp));
     tcb.state = ThreadState::Idle;
-
-    tcb.stack = Box::from_raw((sp & !(STACK_SIZE - 1)) as *mut ThreadStack);
-    tcb.stack.magic = THREAD_MAGIC;
-    tcb.stack.thread = tcb.as_mut() as *mut _;
-    IDLE[core_id] = Some(tcb);
+    abyss::x86_64::gs::set_current(tcb.as_mut() as *mut _ as usize);
+    debug_assert_eq!(core_id, abyss::x86_64::intrinsics::cpuid());
+    *IDLE.get_mut() = Some(tcb);
 
     let scheduler = scheduler();
     loop {
         if let Some(th) = scheduler.next_to_run() {
+            if abyss::dev::x86_64::timer::tick_stopped() {
+                abyss::dev::x86_64::timer::set_tsc_timer();
+            }
             th.run();
+        } else if !abyss::dev::x86_64::timer::tick_stopped() {
+            super::timer::stop_tick();
         }
     }
 }
@@ -0,0 +1,114 @@
+//! Weighted stride scheduler.
+//!
+//! Each thread receives the cpu in proportion to its weight, which is set with
+//! [`ThreadBuilder::weight`]. A thread has a stride inversely proportional to
+//! its weight, and a pass that advances by the stride whenever the thread is
+//! scheduled. The scheduler always runs the thread with the smallest pass, so
+//! a thread of weight 2 runs twice as often as a thread of weight 1:
+//!
+//! ```ignore
+//! scheduler::set_scheduler(Stride::new());
+//! let vcpu = ThreadBuilder::new("vcpu").weight(4).spawn(run_vcpu);
+//! let worker = ThreadBuilder::new("worker").spawn(background);
+//! ```
+//!
+//! A thread that joins the run queue, such as a new or an unparked thread, can
+//! not run ahead of the others with its old pass; its pass is lifted to the
+//! pass of the last scheduled thread.
+//!
+//! A thread runs for a [`TimeSlice`] of 5ms. When keos runs on a hypervisor
+//! that publishes the steal time, the time stolen from the vcpu is not charged
+//! to the slice, so a thread whose slice overlaps with the host preemption
+//! still gets its share.
+//!
+//! The run queue is a [`StrideQueue`] of the kcore crate, which is tested on
+//! the host.
+//!
+//! [`ThreadBuilder::weight`]: super::ThreadBuilder::weight
+use super::{scheduler::Scheduler, Thread};
+use crate::sync::{PerCpu, SpinLock};
+use alloc::boxed::Box;
+use core::arch::x86_64::_rdtsc;
+use kcore::stride::{StrideQueue, Strided, TimeSlice};
+// Length of a time slice in nanoseconds.
+const TIME_SLICE: u64 = 5_000_000;
+
+// The time slice of a cpu.
+struct CpuSlice {
+    slice: TimeSlice,
+    // Tsc at the last timer tick.
+    last_tick: u64,
+}
+
+/// A weighted stride scheduler.
+pub struct Stride {
+    // The threads are boxed, as their stacks point to them.
+    queue: SpinLock<StrideQueue<Box<Thread>>>,
+    slices: PerCpu<SpinLock<CpuSlice>>,
+}
+
+unsafe impl Send for Stride {}
+unsafe impl Sync for Stride {}
+
+impl Default for Stride {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stride {
+    /// Create a new stride scheduler.
+    pub fn new() -> Self {
+        Self {
+            queue: SpinLock::new(StrideQueue::new()),
+            slices: PerCpu::from_fn(|_| {
+                SpinLock::new(CpuSlice {
+                    slice: TimeSlice::new(TIME_SLICE),
+                    last_tick: unsafe { _rdtsc() },
+                })
+            }),
+        }
+    }
+}
+
+impl Strided for Thread {
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    fn pass(&self) -> u64 {
+        self.pass
+    }
+
+    fn set_pass(&mut self, pass: u64) {
+        self.pass = pass;
+    }
+}
+
+impl Scheduler for Stride {
+    fn next_to_run(&self) -> Option<Box<Thread>> {
+        self.queue.lock().pop()
+    }
+
+    fn push_to_queue(&self, th: Box<Thread>) {
+        self.queue.lock().push(th);
+    }
+
+    fn timer_tick(&self) {
+        let expired = {
+            let mut cpu = self.slices.get().lock();
+            let now = unsafe { _rdtsc() };
+            let khz = abyss::dev::x86_64::timer::tsc_khz().max(1);
+            let elapsed = now.saturating_sub(cpu.last_tick) as u128 * 1_000_000 / khz as u128;
+            cpu.last_tick = now;
+            cpu.slice.tick(elapsed as u64)
+        };
+        if expired {
+            super::scheduler::scheduler().reschedule();
+        }
+    }
+
+    fn account_steal(&self, stolen: u64) {
+        self.slices.get().lock().slice.steal(stolen);
+    }
+}
@@ -0,0 +1,68 @@
+//! Timers of the sleeping threads.
+//!
+//! The parked threads are kept in a [`TimerQueue`] ordered by their
+//! deadlines, and unparked on the timer interrupt once their deadlines pass.
+//!
+//! An idle cpu stops its periodic tick, and the timer fires only at the
+//! earliest deadline (tickless idle).
+use super::ParkHandle;
+use crate::sync::SpinLock;
+use abyss::dev::x86_64::{rtc::unix_time_ns, timer as tsc};
+use alloc::sync::Arc;
+use core::time::Duration;
+use kcore::timer::TimerQueue;
+
+/// The parked thread that the timer or the others wake up, whichever comes
+/// first.
+pub(crate) type Slot = Arc<SpinLock<Option<ParkHandle>>>;
+
+static TIMERS: SpinLock<TimerQueue<Slot>> = SpinLock::new(TimerQueue::new());
+
+/// Get the current time in nanoseconds.
+pub(crate) fn now() -> u64 {
+    unix_time_ns()
+}
+
+/// Get the deadline after `duration` from now.
+pub(crate) fn deadline(duration: Duration) -> u64 {
+    now().saturating_add(duration.as_nanos() as u64)
+}
+
+/// Wake up the thread in the `slot` at the `deadline`.
+pub(crate) fn add(deadline: u64, slot: Slot) {
+    TIMERS.lock().add(deadline, slot);
+}
+
+/// Wake up the threads whose deadlines pass.
+///
+/// Called on every timer interrupt.
+pub(crate) fn expire() {
+    // Another cpu is handling the timers.
+    let Ok(mut timers) = TIMERS.try_lock() else {
+        return;
+    };
+    if timers.is_empty() {
+        return;
+    }
+    let now = now();
+    while let Some(slot) = timers.pop_expired(now) {
+        // The slot is empty if the others already woke up the thread.
+        let handle = slot.lock().take();
+        if let Some(handle) = handle {
+            handle.unpark();
+        }
+    }
+}
+
+/// Stop the periodic tick of the idle cpu until the earliest deadline.
+pub(crate) fn stop_tick() {
+    let next = TIMERS.lock().next_deadline();
+    let deadline = next.map(|deadline| {
+        let ns = deadline.saturating_sub(now());
+        let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+        tsc.saturating_add((ns as u128 * tsc::tsc_khz() as u128 / 1_000_000) as u64)
+    });
+    unsafe {
+        tsc::stop_tick(deadline);
+    }
+}
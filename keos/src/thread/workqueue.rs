@@ -0,0 +1,99 @@
+//! Deferred work.
+//!
+//! An interrupt handler runs with the interrupts disabled, so it must finish
+//! quickly. The handler only acknowledges the device and defers the rest of
+//! the work, such as processing the virtqueue, to a [`WorkQueue`]. The work
+//! runs later on the worker thread of the queue with the interrupts enabled:
+//!
+//! ```ignore
+//! interrupt::register_handler(vector, move || {
+//!     dev.ack();
+//!     let dev = dev.clone();
+//!     WorkQueue::system().schedule(move || dev.process_queue());
+//! })?;
+//! ```
+//!
+//! The works of a queue run one by one in the order of scheduling.
+use super::{ParkHandle, Thread, ThreadBuilder};
+use crate::sync::SpinLock;
+use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+struct Inner {
+    works: SpinLock<VecDeque<Work>>,
+    // The worker thread, parked while the queue is empty.
+    idle: SpinLock<Option<ParkHandle>>,
+}
+
+/// A queue of the deferred works, with its worker thread.
+pub struct WorkQueue {
+    inner: Arc<Inner>,
+}
+
+static SYSTEM: SpinLock<Option<Arc<WorkQueue>>> = SpinLock::new(None);
+
+impl WorkQueue {
+    /// Create a new work queue, and spawn its worker thread of `name`.
+    pub fn new<I>(name: I) -> Arc<Self>
+    where
+        String: From<I>,
+    {
+        let inner = Arc::new(Inner {
+            works: SpinLock::new(VecDeque::new()),
+            idle: SpinLock::new(None),
+        });
+        let worker = inner.clone();
+        ThreadBuilder::new(name).spawn(move || Self::work(worker));
+        Arc::new(Self { inner })
+    }
+
+    /// Get the work queue shared by the kernel.
+    pub fn system() -> Arc<Self> {
+        SYSTEM
+            .lock()
+            .get_or_insert_with(|| Self::new("workqueue"))
+            .clone()
+    }
+
+    /// Schedule the `work` to run on the worker thread.
+    ///
+    /// This can be called in the interrupt context.
+    pub fn schedule(&self, work: impl FnOnce() + Send + 'static) {
+        let idle = {
+            let mut works = self.inner.works.lock();
+            works.push_back(Box::new(work));
+            self.inner.idle.lock().take()
+        };
+        if let Some(worker) = idle {
+            worker.unpark();
+        }
+    }
+
+    /// Get the number of the works waiting to run.
+    pub fn pending(&self) -> usize {
+        self.inner.works.lock().len()
+    }
+
+    fn work(inner: Arc<Inner>) {
+        loop {
+            let work = {
+                // Not to be woken up by an interrupt of this cpu before
+                // being switched out.
+                let _p = Thread::pin();
+                let mut works = inner.works.lock();
+                match works.pop_front() {
+                    Some(work) => work,
+                    None => {
+                        Thread::park_current_and(|handle| {
+                            *inner.idle.lock() = Some(handle);
+                            drop(works);
+                        });
+                        continue;
+                    }
+                }
+            };
+            work();
+        }
+    }
+}
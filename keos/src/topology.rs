@@ -0,0 +1,290 @@
+//! Cpu topology and caches.
+//!
+//! The cpus are identified by their APIC ids, which encode the place of the
+//! cpu in the package: the low bits select the SMT thread in a core, the
+//! middle bits the core in a package, and the high bits the package. The
+//! cpus that share a cache have the same APIC id above the number of the bits
+//! the cache spans. The widths are read from the CPUID once at the boot, and
+//! the schedulers query the relations of the cpus:
+//!
+//! ```ignore
+//! // Steal from the cpus that share the caches first.
+//! for victim in keos::topology::nearest(cpuid()) {
+//!     if let Some(th) = steal_from(victim) {
+//!         return Some(th);
+//!     }
+//! }
+//! // Do not place the vcpus of two vms on the siblings of a core.
+//! let siblings = keos::topology::smt_siblings(cpu) & !(1 << cpu);
+//! ```
+//!
+//! The masks are bitmaps of the cpus, and only include the cpus discovered
+//! at the boot.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 10.9 Programming Considerations for Hardware Multi-Threading Capable
+//! Processors.
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+// Maximum number of the caches reported by the CPUID leaf 4.
+const MAX_CACHES: usize = 8;
+
+// Level types of the CPUID leaf 0xb.
+const LEVEL_SMT: u32 = 1;
+const LEVEL_CORE: u32 = 2;
+
+/// Kind of a cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    /// Data cache.
+    Data,
+    /// Instruction cache.
+    Instruction,
+    /// Unified cache.
+    Unified,
+}
+
+/// A cache of the cpus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cache {
+    /// Level of the cache, from 1.
+    pub level: u8,
+    /// Kind of the cache.
+    pub kind: CacheKind,
+    /// Size of the cache in bytes.
+    pub size: usize,
+    /// Size of a cache line in bytes.
+    pub line_size: usize,
+    /// Number of the ways.
+    pub ways: usize,
+    // Number of the low bits of the APIC id that the cpus sharing the cache
+    // differ in.
+    shift: u32,
+}
+
+/// Place of a cpu in the topology.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// APIC id of the cpu.
+    pub apic_id: usize,
+    /// Id of the SMT thread in the core.
+    pub smt_id: usize,
+    /// Id of the core in the package.
+    pub core_id: usize,
+    /// Id of the package.
+    pub package_id: usize,
+}
+
+struct Topology {
+    smt_shift: u32,
+    package_shift: u32,
+    caches: [Option<Cache>; MAX_CACHES],
+}
+
+static TOPOLOGY: SpinLock<Topology> = SpinLock::new(Topology {
+    smt_shift: 0,
+    package_shift: 0,
+    caches: [None; MAX_CACHES],
+});
+
+// Number of the bits to hold `n` ids.
+fn order(n: u32) -> u32 {
+    n.max(1).next_power_of_two().trailing_zeros()
+}
+
+// Read the widths of the SMT and the core levels.
+fn probe_levels(max_leaf: u32) -> (u32, u32) {
+    if max_leaf >= 0xb && unsafe { __cpuid_count(0xb, 0) }.ebx & 0xffff != 0 {
+        let (mut smt_shift, mut package_shift) = (0, 0);
+        for subleaf in 0..8 {
+            let leaf = unsafe { __cpuid_count(0xb, subleaf) };
+            let shift = leaf.eax & 0x1f;
+            match (leaf.ecx >> 8) & 0xff {
+                0 => break,
+                LEVEL_SMT => smt_shift = shift,
+                LEVEL_CORE => package_shift = shift,
+                _ => (),
+            }
+            package_shift = package_shift.max(shift);
+        }
+        (smt_shift, package_shift)
+    } else {
+        // No extended topology; the logical cpus of the package are known,
+        // but not how they are split into the cores.
+        let leaf = unsafe { __cpuid(1) };
+        if leaf.edx & (1 << 28) != 0 {
+            (0, order((leaf.ebx >> 16) & 0xff))
+        } else {
+            (0, 0)
+        }
+    }
+}
+
+// Read the deterministic cache parameters.
+fn probe_caches(max_leaf: u32) -> [Option<Cache>; MAX_CACHES] {
+    let mut caches = [None; MAX_CACHES];
+    if max_leaf < 4 {
+        return caches;
+    }
+    for (subleaf, slot) in caches.iter_mut().enumerate() {
+        let leaf = unsafe { __cpuid_count(4, subleaf as u32) };
+        let kind = match leaf.eax & 0x1f {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => break,
+        };
+        let line_size = (leaf.ebx & 0xfff) as usize + 1;
+        let partitions = ((leaf.ebx >> 12) & 0x3ff) as usize + 1;
+        let ways = (leaf.ebx >> 22) as usize + 1;
+        let sets = leaf.ecx as usize + 1;
+        *slot = Some(Cache {
+            level: ((leaf.eax >> 5) & 0x7) as u8,
+            kind,
+            size: ways * partitions * line_size * sets,
+            line_size,
+            ways,
+            shift: order(((leaf.eax >> 14) & 0xfff) + 1),
+        });
+    }
+    caches
+}
+
+/// Read the topology of the cpus.
+///
+/// Called on the boot, on the bootstrap processor. The cpus are assumed to
+/// be of the same kind.
+pub(crate) fn init() {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let (smt_shift, package_shift) = probe_levels(max_leaf);
+    *TOPOLOGY.lock() = Topology {
+        smt_shift,
+        package_shift,
+        caches: probe_caches(max_leaf),
+    };
+}
+
+// Bitmap of the present cpus of which APIC id equals to that of `cpu` above
+// the low `shift` bits.
+fn siblings(cpu: usize, shift: u32) -> u64 {
+    crate::cpus()
+        .filter(|other| other >> shift == cpu >> shift)
+        .fold(0, |mask, other| mask | 1 << other)
+}
+
+/// Get the place of the `cpu` in the topology.
+pub fn cpu(cpu: usize) -> CpuTopology {
+    let topology = TOPOLOGY.lock();
+    let (smt_shift, package_shift) = (topology.smt_shift, topology.package_shift);
+    CpuTopology {
+        apic_id: cpu,
+        smt_id: cpu & ((1 << smt_shift) - 1),
+        core_id: (cpu & ((1 << package_shift) - 1)) >> smt_shift,
+        package_id: cpu >> package_shift,
+    }
+}
+
+/// Get the caches of a cpu, from the lowest level.
+pub fn caches() -> Vec<Cache> {
+    TOPOLOGY.lock().caches.iter().flatten().copied().collect()
+}
+
+/// Get the bitmap of the SMT siblings of the `cpu`, including itself.
+pub fn smt_siblings(cpu: usize) -> u64 {
+    let shift = TOPOLOGY.lock().smt_shift;
+    siblings(cpu, shift)
+}
+
+/// Get the bitmap of the cpus in the package of the `cpu`, including itself.
+pub fn package_siblings(cpu: usize) -> u64 {
+    let shift = TOPOLOGY.lock().package_shift;
+    siblings(cpu, shift)
+}
+
+/// Get the bitmap of the cpus that share the data or unified cache of the
+/// `level` with the `cpu`, including itself.
+///
+/// Returns `None` if there is no such cache.
+pub fn cache_siblings(cpu: usize, level: u8) -> Option<u64> {
+    let cache = TOPOLOGY
+        .lock()
+        .caches
+        .iter()
+        .flatten()
+        .find(|c| c.level == level && c.kind != CacheKind::Instruction)
+        .copied()?;
+    Some(siblings(cpu, cache.shift))
+}
+
+/// Get the bitmap of the cpus that share the last level cache with the
+/// `cpu`, including itself.
+pub fn llc_siblings(cpu: usize) -> u64 {
+    let shift = TOPOLOGY
+        .lock()
+        .caches
+        .iter()
+        .flatten()
+        .filter(|c| c.kind != CacheKind::Instruction)
+        .max_by_key(|c| c.level)
+        .map(|c| c.shift);
+    siblings(cpu, shift.unwrap_or(0))
+}
+
+/// Get the other cpus, from the nearest to the `cpu`.
+///
+/// The SMT siblings come first, then the cpus that share a cache from the
+/// lowest level, then the cpus in the same package, and then the rest.
+pub fn nearest(cpu: usize) -> Vec<usize> {
+    let topology = TOPOLOGY.lock();
+    let mut shifts = Vec::from([topology.smt_shift]);
+    shifts.extend(
+        topology
+            .caches
+            .iter()
+            .flatten()
+            .filter(|c| c.kind != CacheKind::Instruction)
+            .map(|c| c.shift),
+    );
+    shifts.push(topology.package_shift);
+    drop(topology);
+
+    let mut cpus = crate::cpus()
+        .filter(|other| *other != cpu)
+        .collect::<Vec<_>>();
+    cpus.sort_by_key(|other| {
+        // The first level that the cpus share.
+        shifts
+            .iter()
+            .position(|shift| other >> shift == cpu >> shift)
+            .unwrap_or(shifts.len())
+    });
+    cpus
+}
+
+/// Print the topology of the cpus.
+pub fn dump() {
+    for id in crate::cpus() {
+        let CpuTopology {
+            apic_id,
+            smt_id,
+            core_id,
+            package_id,
+        } = cpu(id);
+        println!(
+            "cpu {}: apic {:#x}, package {}, core {}, smt {}",
+            id, apic_id, package_id, core_id, smt_id
+        );
+    }
+    for cache in caches() {
+        println!(
+            "L{} {:?}: {} KB, {}-byte lines, {}-way",
+            cache.level,
+            cache.kind,
+            cache.size / 1024,
+            cache.line_size,
+            cache.ways
+        );
+    }
+}
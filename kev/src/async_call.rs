@@ -0,0 +1,284 @@
+//! Asynchronous hypercalls.
+//!
+//! A hypercall that takes long, e.g. a large read of a shared file, need not
+//! stall its vcpu. Its controller validates the arguments on the vcpu, and
+//! submits the rest of the work to the [`CompletionQueue`] of the vm:
+//!
+//! ```ignore
+//! let vm = generic_vcpu_state.vm.upgrade().unwrap();
+//! let queue = vm.completions().unwrap();
+//! let token = queue.submit(generic_vcpu_state.id(), move |vm| {
+//!     // On the worker thread of the vm.
+//!     read_into_guest(vm, gpa, len)
+//! })?;
+//! generic_vcpu_state.gprs.rax = token as usize;
+//! ```
+//!
+//! The hypercall returns the token at once. The work runs on a worker thread
+//! of the vm, spawned on the first submission, in the order of the
+//! submissions. On the completion, the result is put on the completion ring
+//! of the guest with the token, and the vcpu that submitted the work takes the
+//! interrupt of the vector of the ring. The guest registers the ring with the
+//! [`HC_ASYNC`] hypercall, handled by [`Controller`]; see [`keos::async_call`]
+//! for the protocol and the guest side.
+//!
+//! The works in flight and the records that the guest has not consumed yet
+//! are at most [`RING_SIZE`]; the submissions beyond fail with [`EAGAIN`], so
+//! the ring never overflows.
+use crate::{
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::{Gpa, VmOps},
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use keos::{
+    addressing::PAGE_SIZE,
+    sync::SpinLock,
+    thread::{
+        channel::{channel, Receiver, Sender},
+        ThreadBuilder,
+    },
+    warning,
+};
+
+pub use keos::async_call::{
+    CompletionRecord, CompletionRing, EAGAIN, FEATURE_ASYNC, HC_ASYNC, OP_SETUP, RING_SIZE,
+};
+
+// Bad address.
+const EFAULT: usize = 14;
+// Invalid argument.
+const EINVAL: usize = 22;
+
+type Work = Box<dyn FnOnce(&dyn VmOps) -> usize + Send>;
+
+struct Job {
+    token: u64,
+    // The vcpu that submitted the work.
+    vcpu: usize,
+    work: Work,
+}
+
+// The completion ring registered by the guest.
+struct Ring {
+    // Host virtual address of the ring.
+    page: usize,
+    vector: u8,
+    vm: Weak<dyn VmOps>,
+}
+
+impl Ring {
+    // Get the number of the records that the guest has not consumed.
+    fn pending(&self) -> usize {
+        let ring = self.page as *const CompletionRing;
+        unsafe {
+            let head = core::ptr::addr_of!((*ring).head).read_volatile();
+            let tail = core::ptr::addr_of!((*ring).tail).read_volatile();
+            head.wrapping_sub(tail) as usize
+        }
+    }
+}
+
+struct Shared {
+    ring: SpinLock<Option<Ring>>,
+    // Number of the works submitted but not completed.
+    in_flight: AtomicUsize,
+}
+
+/// The completion queue of the asynchronous hypercalls of a vm.
+pub struct CompletionQueue {
+    shared: Arc<Shared>,
+    next_token: AtomicU64,
+    // Sender to the worker, spawned on the first submission.
+    worker: SpinLock<Option<Sender<Job>>>,
+}
+
+impl Default for CompletionQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompletionQueue {
+    /// Create a queue without a ring.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                ring: SpinLock::new(None),
+                in_flight: AtomicUsize::new(0),
+            }),
+            next_token: AtomicU64::new(1),
+            worker: SpinLock::new(None),
+        }
+    }
+
+    /// Check whether the guest registered the ring.
+    pub fn is_ready(&self) -> bool {
+        self.shared.ring.lock().is_some()
+    }
+
+    /// Get the number of the works in flight.
+    pub fn in_flight(&self) -> usize {
+        self.shared.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Register the ring at the `page` of the vm, or unregister with `None`.
+    fn set_ring(&self, ring: Option<Ring>) {
+        *self.shared.ring.lock() = ring;
+    }
+
+    /// Submit the `work` of the vcpu `vcpu`, and returns its token.
+    ///
+    /// The `work` runs on the worker thread of the vm, and returns the result
+    /// of the hypercall, or the negated errno. Fails with the errno,
+    /// [`EAGAIN`] if the ring is full, or `EINVAL` if the guest has not
+    /// registered the ring.
+    pub fn submit(
+        &self,
+        vcpu: usize,
+        work: impl FnOnce(&dyn VmOps) -> usize + Send + 'static,
+    ) -> Result<u64, usize> {
+        let ring = self.shared.ring.lock();
+        let vm = match &*ring {
+            Some(ring) if self.in_flight() + ring.pending() >= RING_SIZE => return Err(EAGAIN),
+            Some(ring) => ring.vm.upgrade().ok_or(EINVAL)?,
+            None => return Err(EINVAL),
+        };
+        drop(ring);
+        let mut worker = self.worker.lock();
+        let tx = worker.get_or_insert_with(|| {
+            let (tx, rx) = channel(RING_SIZE);
+            let shared = self.shared.clone();
+            ThreadBuilder::new(vm.uuid().thread_name("async")).spawn(move || serve(shared, rx));
+            tx
+        });
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst);
+        self.shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        let job = Job {
+            token,
+            vcpu,
+            work: Box::new(work),
+        };
+        if tx.try_send(job).is_err() {
+            self.shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return Err(EAGAIN);
+        }
+        Ok(token)
+    }
+}
+
+// The worker thread of a vm, which exits when the vm drops the queue.
+fn serve(shared: Arc<Shared>, rx: Receiver<Job>) {
+    while let Ok(Job { token, vcpu, work }) = rx.recv() {
+        let vm = shared
+            .ring
+            .lock()
+            .as_ref()
+            .and_then(|ring| ring.vm.upgrade());
+        let Some(vm) = vm else {
+            shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            continue;
+        };
+        let result = work(&*vm);
+        let vector = {
+            let ring = shared.ring.lock();
+            shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            // The guest unregistered the ring.
+            let Some(ring) = ring.as_ref() else {
+                continue;
+            };
+            let record = CompletionRecord {
+                token,
+                result: result as u64,
+            };
+            if !unsafe { CompletionRing::produce(ring.page as *mut CompletionRing, record) } {
+                warning!("Completion ring is full; dropping the token {}.", token);
+                continue;
+            }
+            ring.vector
+        };
+        vm.notify_vcpu(vcpu, vector);
+    }
+}
+
+/// Vmexit controller of the [`HC_ASYNC`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+#[derive(Default)]
+pub struct Controller;
+
+impl Controller {
+    /// Create a new asynchronous hypercall controller.
+    pub fn new() -> Self {
+        Self
+    }
+
+    // Register the ring at the `gpa` with the `vector`, or unregister with 0.
+    fn setup(
+        &self,
+        generic_vcpu_state: &GenericVCpuState,
+        gpa: usize,
+        vector: usize,
+    ) -> Result<(), usize> {
+        let vm = generic_vcpu_state.vm.upgrade().ok_or(EINVAL)?;
+        let queue = vm.completions().ok_or(EINVAL)?;
+        if gpa == 0 {
+            queue.set_ring(None);
+            return Ok(());
+        }
+        if gpa % PAGE_SIZE != 0 || !(32..256).contains(&vector) {
+            return Err(EINVAL);
+        }
+        let pa = Gpa::new(gpa)
+            .and_then(|gpa| vm.gpa2hpa(gpa))
+            .ok_or(EFAULT)?;
+        queue.set_ring(Some(Ring {
+            page: unsafe { pa.into_va().into_usize() },
+            vector: vector as u8,
+            vm: generic_vcpu_state.vm.clone(),
+        }));
+        Ok(())
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_ASYNC => {
+                // The ring is written by the host at any time.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let (op, gpa, vector) = (
+                    generic_vcpu_state.gprs.rdi,
+                    generic_vcpu_state.gprs.rsi,
+                    generic_vcpu_state.gprs.rdx,
+                );
+                let result = match op {
+                    OP_SETUP => self.setup(generic_vcpu_state, gpa, vector),
+                    _ => Err(EINVAL),
+                };
+                generic_vcpu_state.gprs.rax = match result {
+                    Ok(()) => 0,
+                    Err(errno) => errno.wrapping_neg(),
+                };
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
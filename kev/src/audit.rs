@@ -0,0 +1,365 @@
+//! Audit of the guest accesses to the emulated devices.
+//!
+//! In the audit mode, every access of the guest to the watched ports and mmio
+//! regions is recorded with the vcpu, the guest rip and the current privilege
+//! level, and the value written or read:
+//!
+//! ```text
+//! vcpu=0 cpl=0 rip=0xffffff0000104a1c out port=0x3f8 size=1 value=0x41
+//! vcpu=1 cpl=3 rip=0x0000000000401017 write mmio=0xfee00030 size=4 value=0x0
+//! vcpu=0 cpl=0 rip=0xffffff00001051e0 in port=0x1f0 size=2 value=-
+//! ```
+//!
+//! The value is `-` if it is unknown, e.g. of the string io instructions, or
+//! of a read that the vmexit handler failed.
+//!
+//! The log keeps the last [`Audit::capacity`] records; the older ones are
+//! counted as overwritten. The host turns on the audit when it builds the vm,
+//! and collects the records at any time:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?
+//!     .audit(Audit::new().ports(0x1f0..=0x1f7).mmio(VIRTIO_BASE, 0x1000))
+//!     .finalize()?;
+//! // ...
+//! for record in vm.audit_log().take() {
+//!     if record.cpl != 0 {
+//!         println!("user access: {}", record);
+//!     }
+//! }
+//! ```
+//!
+//! Only the exits handed to [`VCpuState::handle_vmexit`] are audited; an ept
+//! violation is decoded to find its size and value, so watch the mmio regions
+//! only, not the guest memory.
+//!
+//! [`VCpuState::handle_vmexit`]: crate::vcpu::VCpuState::handle_vmexit
+use crate::{
+    exit_history::ExitRecord,
+    stepping::{self, VmProbe},
+    vcpu::{GeneralPurposeRegisters, GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{BasicExitReason, EptViolationQualification},
+    VmError,
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    fmt,
+    ops::{Range, RangeInclusive},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use iced_x86::{OpKind, Register};
+use keos::sync::SpinLock;
+
+/// The resources to audit.
+#[derive(Clone, Debug)]
+pub struct Audit {
+    ports: Vec<RangeInclusive<u16>>,
+    mmio: Vec<Range<usize>>,
+    capacity: usize,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Audit {
+    /// Number of the records kept by default.
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    /// Create an audit that watches nothing.
+    pub fn new() -> Self {
+        Self {
+            ports: Vec::new(),
+            mmio: Vec::new(),
+            capacity: Self::DEFAULT_CAPACITY,
+        }
+    }
+
+    /// Watch the `ports`.
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    /// Watch the mmio region of the `size` bytes at the `start`.
+    pub fn mmio(mut self, start: Gpa, size: usize) -> Self {
+        let start = unsafe { start.into_usize() };
+        self.mmio.push(start..start + size);
+        self
+    }
+
+    /// Keep the last `capacity` records.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    fn watches(&self, resource: Resource) -> bool {
+        match resource {
+            Resource::Port(port) => self.ports.iter().any(|r| r.contains(&port)),
+            Resource::Mmio(gpa) => {
+                let gpa = unsafe { gpa.into_usize() };
+                self.mmio.iter().any(|r| r.contains(&gpa))
+            }
+        }
+    }
+}
+
+/// A resource accessed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// The io port.
+    Port(u16),
+    /// The guest physical address in a mmio region.
+    Mmio(Gpa),
+}
+
+/// An audited access of the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct AuditRecord {
+    /// The vcpu that accessed.
+    pub vcpu: usize,
+    /// Tsc at the access.
+    pub tsc: u64,
+    /// The guest rip of the accessing instruction.
+    pub rip: u64,
+    /// The current privilege level of the guest.
+    pub cpl: u8,
+    /// The accessed resource.
+    pub resource: Resource,
+    /// Whether the guest wrote to the resource.
+    pub write: bool,
+    /// Size of the access in bytes.
+    pub size: usize,
+    /// The written or read value, if known.
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "vcpu={} cpl={} rip={:#018x} ",
+            self.vcpu, self.cpl, self.rip
+        )?;
+        match (self.resource, self.write) {
+            (Resource::Port(port), true) => write!(f, "out port={:#x}", port)?,
+            (Resource::Port(port), false) => write!(f, "in port={:#x}", port)?,
+            (Resource::Mmio(gpa), write) => write!(
+                f,
+                "{} mmio={:#x}",
+                if write { "write" } else { "read" },
+                unsafe { gpa.into_usize() }
+            )?,
+        }
+        write!(f, " size={} value=", self.size)?;
+        match self.value {
+            Some(value) => write!(f, "{:#x}", value),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// An access being handled; the value of a read is known after the handler.
+pub(crate) struct Pending {
+    record: AuditRecord,
+    // The register that the value is read into.
+    read_into: Option<Register>,
+}
+
+/// Log of the audited accesses of a vm.
+pub struct AuditLog {
+    enabled: AtomicBool,
+    audit: SpinLock<Option<Audit>>,
+    records: SpinLock<VecDeque<AuditRecord>>,
+    overwritten: AtomicU64,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditLog {
+    /// Create a log that is off.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            audit: SpinLock::new(None),
+            records: SpinLock::new(VecDeque::new()),
+            overwritten: AtomicU64::new(0),
+        }
+    }
+
+    /// Turn on the audit of the resources in the `audit`, or turn off the
+    /// audit with `None`.
+    pub fn set(&self, audit: Option<Audit>) {
+        let mut guard = self.audit.lock();
+        self.enabled.store(audit.is_some(), Ordering::SeqCst);
+        *guard = audit;
+    }
+
+    /// Check whether the audit is on.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Get the records, from the oldest.
+    pub fn records(&self) -> Vec<AuditRecord> {
+        self.records.lock().iter().copied().collect()
+    }
+
+    /// Take the records out of the log, from the oldest.
+    pub fn take(&self) -> Vec<AuditRecord> {
+        self.records.lock().drain(..).collect()
+    }
+
+    /// Get the number of the records overwritten by the newer ones.
+    pub fn overwritten(&self) -> u64 {
+        self.overwritten.load(Ordering::Relaxed)
+    }
+
+    /// Start the record of the access that caused the `exit`.
+    ///
+    /// Returns `None` if the audit is off, or the exit is not an access to a
+    /// watched resource.
+    pub(crate) fn start(
+        &self,
+        generic_state: &GenericVCpuState,
+        exit: &ExitRecord,
+    ) -> Option<Pending> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let q = exit.qualification;
+        let resource = match exit.reason.get_basic_reason() {
+            BasicExitReason::IoInstruction => Resource::Port((q >> 16) as u16),
+            BasicExitReason::EptViolation {
+                fault_addr: Some(gpa),
+                ..
+            } => Resource::Mmio(*gpa),
+            _ => return None,
+        };
+        if !self.audit.lock().as_ref()?.watches(resource) {
+            return None;
+        }
+        let (write, size, value, read_into) = match exit.reason.get_basic_reason() {
+            BasicExitReason::EptViolation { qualification, .. } => {
+                let write = qualification.contains(EptViolationQualification::BIT1);
+                decode(generic_state, write).unwrap_or((write, 0, None, None))
+            }
+            // See Intel® 64 and IA-32 Architectures Software Developer’s
+            // Manual, Table 28-5. Exit Qualification for I/O Instructions.
+            _ => {
+                let size = (q & 7) as usize + 1;
+                let write = q & (1 << 3) == 0;
+                if q & (1 << 4) != 0 {
+                    // String io.
+                    (write, size, None, None)
+                } else if write {
+                    let value = generic_state.gprs.rax as u64 & mask(size);
+                    (write, size, Some(value), None)
+                } else {
+                    let reg = match size {
+                        1 => Register::AL,
+                        2 => Register::AX,
+                        _ => Register::EAX,
+                    };
+                    (write, size, None, Some(reg))
+                }
+            }
+        };
+        Some(Pending {
+            record: AuditRecord {
+                vcpu: generic_state.id(),
+                tsc: exit.tsc,
+                rip: exit.rip,
+                cpl: generic_state.vmcs.guest_cpl().unwrap_or(0),
+                resource,
+                write,
+                size,
+                value,
+            },
+            read_into,
+        })
+    }
+
+    /// Finish the `pending` record with the `result` of the vmexit handler.
+    pub(crate) fn finish(
+        &self,
+        pending: Pending,
+        generic_state: &mut GenericVCpuState,
+        result: &Result<VmexitResult, VmError>,
+    ) {
+        let Pending {
+            mut record,
+            read_into,
+        } = pending;
+        if let (Some(reg), Ok(_)) = (read_into, result) {
+            record.value = register(generic_state.gprs, reg);
+        }
+        let capacity = self
+            .audit
+            .lock()
+            .as_ref()
+            .map_or(Audit::DEFAULT_CAPACITY, |a| a.capacity);
+        let mut records = self.records.lock();
+        while records.len() >= capacity {
+            records.pop_front();
+            self.overwritten.fetch_add(1, Ordering::Relaxed);
+        }
+        records.push_back(record);
+    }
+}
+
+fn mask(size: usize) -> u64 {
+    u64::MAX >> (64 - size * 8)
+}
+
+// Get the value of the `reg`.
+fn register(gprs: &mut GeneralPurposeRegisters, reg: Register) -> Option<u64> {
+    let full = *stepping::gpr(gprs, reg)? as u64;
+    Some(match reg {
+        Register::AH | Register::CH | Register::DH | Register::BH => (full >> 8) & 0xff,
+        reg => full & mask(reg.size()),
+    })
+}
+
+// Decode the mov instruction that accesses the mmio region.
+//
+// Returns the direction, the size, the written value, and the register that
+// the value is read into.
+fn decode(
+    generic_state: &GenericVCpuState,
+    write: bool,
+) -> Option<(bool, usize, Option<u64>, Option<Register>)> {
+    let vm = generic_state.vm.upgrade()?;
+    let insn = generic_state.vmcs.get_instruction(&VmProbe(&*vm)).ok()?;
+    let size = insn.memory_size().size();
+    if !(1..=8).contains(&size) {
+        return None;
+    }
+    if write {
+        let value = match insn.op1_kind() {
+            OpKind::Register => register(&mut generic_state.gprs.clone(), insn.op1_register()),
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => Some(insn.immediate(1) & mask(size)),
+            _ => None,
+        };
+        Some((true, size, value, None))
+    } else {
+        let read_into = (insn.op0_kind() == OpKind::Register).then(|| insn.op0_register());
+        Some((false, size, None, read_into))
+    }
+}
@@ -0,0 +1,165 @@
+//! Batched hypercalls.
+//!
+//! The [`HC_BATCH`] hypercall executes an array of the hypercalls of the
+//! guest in a single vmexit; see [`keos::hypercall`] for the interface of the
+//! guest. The batch [`Controller`] wraps the controller of the hypercalls, and
+//! replays each entry on it as if the guest issued the hypercall:
+//!
+//! ```ignore
+//! let mut hypercalls = ControllerStack::new();
+//! hypercalls.push(kev::shutdown::Controller::new());
+//! hypercalls.push(kev::shared_fs::Controller::new(folder));
+//! dispatch.register([BasicExitReason::Vmcall], kev::batch::Controller::new(hypercalls));
+//! ```
+//!
+//! An entry is completed when its hypercall is handled and advances the rip;
+//! the returned rax and rdx are written back to the entry. An entry that no
+//! controller handles fails with [`ENOSYS`]. Any other result, e.g. an
+//! exception injected on the guest or the exit of the vcpu, ends the batch:
+//! the completed entries are written back, and the result is of the batch.
+//!
+//! Only the guest kernel issues the batch, as the entries are at a guest
+//! physical address; each entry is still checked by its own controller.
+use crate::{
+    event, guest_copy,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::vec;
+pub use keos::hypercall::{HypercallEntry, ENOSYS, HC_BATCH, MAX_BATCH};
+
+// Bad address.
+const EFAULT: usize = 14;
+// Invalid argument.
+const EINVAL: usize = 22;
+
+/// Vmexit controller of the [`HC_BATCH`] hypercall.
+///
+/// The other exits are handed to the wrapped controller.
+pub struct Controller<C: VmexitController> {
+    inner: C,
+}
+
+impl<C: VmexitController> Controller<C> {
+    /// Create a batch controller that executes the entries on the `inner`.
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// Get the wrapped controller.
+    pub fn inner(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    // Execute the `entry` on the wrapped controller.
+    //
+    // Returns `None` if the entry is completed, or the result that ends the
+    // batch.
+    fn execute<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+        entry: &mut HypercallEntry,
+    ) -> Option<Result<VmexitResult, VmError>> {
+        if entry.nr as usize == HC_BATCH {
+            entry.rax = EINVAL.wrapping_neg() as u64;
+            return None;
+        }
+        let gprs = *generic_vcpu_state.gprs;
+        let [rdi, rsi, rdx, rcx, r8] = entry.args.map(|arg| arg as usize);
+        generic_vcpu_state.gprs.rax = entry.nr as usize;
+        generic_vcpu_state.gprs.rdi = rdi;
+        generic_vcpu_state.gprs.rsi = rsi;
+        generic_vcpu_state.gprs.rdx = rdx;
+        generic_vcpu_state.gprs.rcx = rcx;
+        generic_vcpu_state.gprs.r8 = r8;
+        let result = match self.inner.handle(reason, p, generic_vcpu_state) {
+            Ok(VmexitResult::HandledAdvance) => {
+                entry.rax = generic_vcpu_state.gprs.rax as u64;
+                entry.rdx = generic_vcpu_state.gprs.rdx as u64;
+                None
+            }
+            Err(VmError::HandleVmexitFailed(_)) => {
+                entry.rax = ENOSYS.wrapping_neg() as u64;
+                None
+            }
+            r => Some(r),
+        };
+        *generic_vcpu_state.gprs = gprs;
+        result
+    }
+
+    // Execute the batch of the `len` entries at the `gpa`.
+    fn batch<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+        gpa: usize,
+        len: usize,
+    ) -> Result<VmexitResult, VmError> {
+        let gpa = match Gpa::new(gpa) {
+            Some(gpa) if len <= MAX_BATCH => gpa,
+            _ => return Ok(Self::fail(generic_vcpu_state, EINVAL)),
+        };
+        let size = core::mem::size_of::<HypercallEntry>();
+        let mut entries = vec![HypercallEntry::default(); len];
+        // SAFETY: the entries are plain integers.
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(entries.as_mut_ptr() as *mut u8, len * size) };
+        if guest_copy::read_gpa(p, &generic_vcpu_state.vmcs, gpa, bytes).is_err() {
+            return Ok(Self::fail(generic_vcpu_state, EFAULT));
+        }
+        let mut done = 0;
+        let mut result = Ok(VmexitResult::HandledAdvance);
+        for entry in entries.iter_mut() {
+            if let Some(r) = self.execute(reason, p, generic_vcpu_state, entry) {
+                result = r;
+                break;
+            }
+            done += 1;
+        }
+        let bytes =
+            unsafe { core::slice::from_raw_parts(entries.as_ptr() as *const u8, done * size) };
+        if guest_copy::write_gpa(p, &generic_vcpu_state.vmcs, gpa, bytes).is_err() {
+            return Ok(Self::fail(generic_vcpu_state, EFAULT));
+        }
+        if let Ok(VmexitResult::HandledAdvance) = result {
+            generic_vcpu_state.gprs.rax = done;
+        }
+        result
+    }
+
+    // Fail the batch with the `errno`.
+    fn fail(generic_vcpu_state: &mut GenericVCpuState, errno: usize) -> VmexitResult {
+        generic_vcpu_state.gprs.rax = errno.wrapping_neg();
+        VmexitResult::HandledAdvance
+    }
+}
+
+impl<C: VmexitController> VmexitController for Controller<C> {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_BATCH => {
+                // The entries are read and written at the guest physical
+                // address.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let (gpa, len) = (generic_vcpu_state.gprs.rdi, generic_vcpu_state.gprs.rsi);
+                self.batch(reason, p, generic_vcpu_state, gpa, len)
+            }
+            _ => self.inner.handle(reason, p, generic_vcpu_state),
+        }
+    }
+}
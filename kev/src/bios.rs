@@ -0,0 +1,503 @@
+//! Emulated BIOS for the real-mode guests.
+//!
+//! Without firmware, the guest must start in protected mode with the segment
+//! state written by hand in [`VmState::setup_vbsp`]. This module provides a
+//! tiny BIOS so that a classic boot sector (or a bootloader like GRUB) can run
+//! from the reset vector under the unrestricted guest.
+//!
+//! The BIOS consists of two parts:
+//! - [`image`]: 16-bit firmware code that is loaded at [`BIOS_BASE`]. On reset,
+//!   it builds the interrupt vector table, loads the boot sector at
+//!   [`BOOT_SECTOR`] and jumps to it. Each BIOS service is a stub that traps
+//!   into the hypervisor with `vmcall` and returns with `iret`.
+//! - [`Controller`]: the vmexit controller that serves the trapped services
+//!   with the host devices. The service is identified by the address of the
+//!   `vmcall`.
+//!
+//! Supported services are:
+//! - INT 10h: teletype output to the console.
+//! - INT 12h: conventional memory size.
+//! - INT 13h: CHS and extended (LBA) disk accesses on the drive 80h.
+//! - INT 15h: E820 memory map, E801h/88h memory size and A20 gate.
+//!
+//! To run a real-mode guest, copy the [`image`] into the guest memory at
+//! [`BIOS_BASE`], call [`init_real_mode`] on the bootstrap vcpu, and enable
+//! [`UNRESTRICTED_GUEST`] with [`ENABLE_EPT`] without the
+//! [`IA32E_MODE_GUEST`] entry control.
+//!
+//! [`VmState::setup_vbsp`]: crate::vm::VmState::setup_vbsp
+//! [`UNRESTRICTED_GUEST`]: crate::vm_control::VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST
+//! [`ENABLE_EPT`]: crate::vm_control::VmcsProcBasedSecondaryVmexecCtl::ENABLE_EPT
+//! [`IA32E_MODE_GUEST`]: crate::vm_control::VmcsEntryCtl::IA32E_MODE_GUEST
+use crate::{
+    e820::MemoryMap,
+    entry_state::GuestEntryState,
+    guest_copy,
+    probe::Probe,
+    vcpu::{Cr0, GeneralPurposeRegisters, GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason, Field},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{boxed::Box, vec};
+
+/// Guest physical address of the BIOS segment (F000:0000).
+pub const BIOS_BASE: usize = 0xf0000;
+/// Size of the BIOS segment.
+pub const BIOS_SIZE: usize = 0x10000;
+/// Guest physical address that the boot sector is loaded.
+pub const BOOT_SECTOR: usize = 0x7c00;
+/// Size of a disk sector.
+pub const SECTOR_SIZE: usize = 512;
+
+// Offsets of the code within the BIOS segment.
+const RESET: usize = 0xfff0;
+const POST: usize = 0xe05b;
+const DEFAULT_HANDLER: usize = 0xff00;
+const INT10: usize = 0xff10;
+const INT12: usize = 0xff20;
+const INT13: usize = 0xff30;
+const INT15: usize = 0xff40;
+const BOOT: usize = POST + POST_CODE.len() - 8;
+
+// Power-on self test.
+#[rustfmt::skip]
+const POST_CODE: [u8; 60] = [
+    0xfa,                               // cli
+    0xfc,                               // cld
+    0x31, 0xc0,                         // xor ax, ax
+    0x8e, 0xd8,                         // mov ds, ax
+    0x8e, 0xc0,                         // mov es, ax
+    0x8e, 0xd0,                         // mov ss, ax
+    0xbc, 0x00, 0x7c,                   // mov sp, 0x7c00
+    0x31, 0xff,                         // xor di, di
+    0xb9, 0x00, 0x01,                   // mov cx, 0x100
+    // Point every vector to the default handler.
+    0xb8, DEFAULT_HANDLER as u8, (DEFAULT_HANDLER >> 8) as u8, // 1: mov ax, DEFAULT_HANDLER
+    0xab,                               // stosw
+    0xb8, 0x00, 0xf0,                   // mov ax, 0xf000
+    0xab,                               // stosw
+    0xe2, 0xf6,                         // loop 1b
+    // Install the services.
+    0xc7, 0x06, 0x40, 0x00, INT10 as u8, (INT10 >> 8) as u8, // mov word [0x10 * 4], INT10
+    0xc7, 0x06, 0x48, 0x00, INT12 as u8, (INT12 >> 8) as u8, // mov word [0x12 * 4], INT12
+    0xc7, 0x06, 0x4c, 0x00, INT13 as u8, (INT13 >> 8) as u8, // mov word [0x13 * 4], INT13
+    0xc7, 0x06, 0x54, 0x00, INT15 as u8, (INT15 >> 8) as u8, // mov word [0x15 * 4], INT15
+    // Load the boot sector and the boot drive in dl.
+    0x0f, 0x01, 0xc1,                   // vmcall
+    0xea, 0x00, 0x7c, 0x00, 0x00,       // jmp 0x0000:0x7c00
+];
+
+// Trap into the hypervisor and return from the interrupt.
+const SERVICE_CODE: [u8; 4] = [
+    0x0f, 0x01, 0xc1, // vmcall
+    0xcf, // iret
+];
+
+/// Build the BIOS image that is loaded at [`BIOS_BASE`].
+pub fn image() -> Box<[u8]> {
+    let mut image = vec![0; BIOS_SIZE].into_boxed_slice();
+    image[POST..POST + POST_CODE.len()].copy_from_slice(&POST_CODE);
+    image[DEFAULT_HANDLER] = 0xcf; // iret
+    for service in [INT10, INT12, INT13, INT15] {
+        image[service..service + SERVICE_CODE.len()].copy_from_slice(&SERVICE_CODE);
+    }
+    // jmp 0xf000:POST
+    image[RESET..RESET + 5].copy_from_slice(&[0xea, POST as u8, (POST >> 8) as u8, 0x00, 0xf0]);
+    image
+}
+
+/// Initialize the vcpu state to the state after the processor reset.
+///
+/// The vcpu starts in the real mode from the reset vector (F000:FFF0). See
+/// [`GuestEntryState::real_mode`].
+pub fn init_real_mode(vmcs: &ActiveVmcs) -> Result<(), VmError> {
+    GuestEntryState::real_mode().write(vmcs)
+}
+
+/// Disk that backs the INT 13h services of the drive 80h.
+pub trait BiosDisk
+where
+    Self: Send + Sync,
+{
+    /// Number of sectors of the disk.
+    fn sectors(&self) -> u64;
+    /// Read the sector `lba` into `buf`.
+    ///
+    /// Returns false if failed to read.
+    fn read(&self, lba: u64, buf: &mut [u8; SECTOR_SIZE]) -> bool;
+    /// Write `buf` into the sector `lba`.
+    ///
+    /// Returns false if failed to write.
+    fn write(&self, lba: u64, buf: &[u8; SECTOR_SIZE]) -> bool;
+}
+
+// The disk geometry reported to the CHS services.
+const HEADS: u64 = 16;
+const SECTORS_PER_TRACK: u64 = 63;
+const BOOT_DRIVE: u8 = 0x80;
+// "SMAP"
+const SMAP: u32 = 0x534d_4150;
+
+// INT 13h status codes.
+const INVALID_COMMAND: u8 = 0x01;
+const SECTOR_NOT_FOUND: u8 = 0x04;
+const BOUNDARY_ERROR: u8 = 0x09;
+const NOT_READY: u8 = 0xaa;
+// INT 15h status codes.
+const UNSUPPORTED: u8 = 0x86;
+
+#[inline]
+fn set_lo8(r: &mut usize, v: u8) {
+    *r = (*r & !0xff) | v as usize;
+}
+
+#[inline]
+fn set_hi8(r: &mut usize, v: u8) {
+    *r = (*r & !0xff00) | (v as usize) << 8;
+}
+
+#[inline]
+fn set_lo16(r: &mut usize, v: u16) {
+    *r = (*r & !0xffff) | v as usize;
+}
+
+#[inline]
+fn set_lo32(r: &mut usize, v: u32) {
+    *r = (*r & !0xffff_ffff) | v as usize;
+}
+
+/// Guest memory accessed by the BIOS services.
+///
+/// As the paging is disabled on the real mode, the linear address is the
+/// guest physical address.
+struct GuestMemory<'a, P: Probe> {
+    p: &'a P,
+    vmcs: &'a ActiveVmcs,
+}
+
+impl<P: Probe> GuestMemory<'_, P> {
+    fn read(&self, gpa: usize, buf: &mut [u8]) -> bool {
+        Gpa::new(gpa).is_some_and(|gpa| guest_copy::read_gpa(self.p, self.vmcs, gpa, buf).is_ok())
+    }
+
+    fn write(&self, gpa: usize, buf: &[u8]) -> bool {
+        Gpa::new(gpa).is_some_and(|gpa| guest_copy::write_gpa(self.p, self.vmcs, gpa, buf).is_ok())
+    }
+
+    /// Translate the `segment`:`offset` into the guest physical address.
+    fn linear(&self, segment: Field, offset: usize) -> Result<usize, VmError> {
+        Ok(self.vmcs.read(segment)? as usize + (offset & 0xffff))
+    }
+}
+
+/// Vmexit controller that serves the BIOS services.
+///
+/// Returns [`VmError::HandleVmexitFailed`] for the vmcalls that are not
+/// issued by the BIOS, so it can be chained in front of the hypercall
+/// controller.
+pub struct Controller {
+    disk: Option<Box<dyn BiosDisk>>,
+    memory_map: MemoryMap,
+}
+
+impl Controller {
+    /// Create a new BIOS controller that reports `memory_map` to the guest.
+    pub fn new(memory_map: MemoryMap) -> Self {
+        Self {
+            disk: None,
+            memory_map,
+        }
+    }
+
+    /// Attach the boot disk as the drive 80h.
+    pub fn attach_disk(&mut self, disk: impl BiosDisk + 'static) {
+        self.disk = Some(Box::new(disk));
+    }
+
+    /// Size of the conventional memory (below 640 KiB) in KiB.
+    fn conventional_kib(&self) -> u16 {
+        self.memory_map
+            .ram()
+            .find(|e| e.base == 0)
+            .map(|e| (e.len.min(0xa0000) / 1024) as u16)
+            .unwrap_or(0)
+    }
+
+    /// Load the boot sector and pass the boot drive.
+    fn boot<P: Probe>(
+        &self,
+        mem: &GuestMemory<P>,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> Result<VmexitResult, VmError> {
+        // Base memory size in the BIOS data area (40:13h).
+        mem.write(0x413, &self.conventional_kib().to_le_bytes());
+
+        let mut mbr = [0; SECTOR_SIZE];
+        match &self.disk {
+            Some(disk) if disk.read(0, &mut mbr) && mbr[510..] == [0x55, 0xaa] => {
+                if !mem.write(BOOT_SECTOR, &mbr) {
+                    return Err(VmError::ControllerError(Box::new(
+                        "Failed to load the boot sector.",
+                    )));
+                }
+                set_lo8(&mut gprs.rdx, BOOT_DRIVE);
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => {
+                println!("No bootable device.");
+                Ok(VmexitResult::Exited(1))
+            }
+        }
+    }
+
+    /// INT 10h, video services.
+    fn video(&self, gprs: &mut GeneralPurposeRegisters) -> Result<(), u8> {
+        match (gprs.rax >> 8) as u8 {
+            // Teletype output.
+            0x0e => print!("{}", gprs.rax as u8 as char),
+            // Get cursor position and shape.
+            0x03 => {
+                set_lo16(&mut gprs.rcx, 0x0607);
+                set_lo16(&mut gprs.rdx, 0);
+            }
+            // Get current video mode: 80x25 color text.
+            0x0f => {
+                set_lo16(&mut gprs.rax, (80 << 8) | 0x03);
+                set_hi8(&mut gprs.rbx, 0);
+            }
+            // Ignore the other requests.
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Transfer `count` sectors from `lba` between the disk and `gpa`.
+    fn transfer<P: Probe>(
+        &self,
+        mem: &GuestMemory<P>,
+        lba: u64,
+        count: usize,
+        gpa: usize,
+        write: bool,
+    ) -> Result<(), u8> {
+        let disk = self.disk.as_ref().ok_or(NOT_READY)?;
+        if lba + count as u64 > disk.sectors() {
+            return Err(SECTOR_NOT_FOUND);
+        }
+        let mut sector = [0; SECTOR_SIZE];
+        for (i, lba) in (lba..lba + count as u64).enumerate() {
+            let gpa = gpa + i * SECTOR_SIZE;
+            if write {
+                if !mem.read(gpa, &mut sector) {
+                    return Err(BOUNDARY_ERROR);
+                }
+                if !disk.write(lba, &sector) {
+                    return Err(SECTOR_NOT_FOUND);
+                }
+            } else {
+                if !disk.read(lba, &mut sector) {
+                    return Err(SECTOR_NOT_FOUND);
+                }
+                if !mem.write(gpa, &sector) {
+                    return Err(BOUNDARY_ERROR);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// INT 13h, disk services.
+    fn disk<P: Probe>(
+        &self,
+        mem: &GuestMemory<P>,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> Result<(), u8> {
+        if gprs.rdx as u8 != BOOT_DRIVE {
+            return Err(INVALID_COMMAND);
+        }
+        let sectors = self.disk.as_ref().ok_or(NOT_READY)?.sectors();
+        match (gprs.rax >> 8) as u8 {
+            // Reset disk system.
+            0x00 => (),
+            // Read / Write sectors in CHS.
+            ah @ (0x02 | 0x03) => {
+                let (cl, ch, dh) = (gprs.rcx as u8, (gprs.rcx >> 8) as u8, (gprs.rdx >> 8) as u8);
+                let cylinder = ch as u64 | ((cl as u64 & 0xc0) << 2);
+                let sector = cl as u64 & 0x3f;
+                if sector == 0 {
+                    return Err(SECTOR_NOT_FOUND);
+                }
+                let lba = (cylinder * HEADS + dh as u64) * SECTORS_PER_TRACK + sector - 1;
+                let buf = mem
+                    .linear(Field::GuestEsBase, gprs.rbx)
+                    .map_err(|_| BOUNDARY_ERROR)?;
+                self.transfer(mem, lba, gprs.rax as u8 as usize, buf, ah == 0x03)?;
+            }
+            // Get drive parameters.
+            0x08 => {
+                let cylinders = (sectors / (HEADS * SECTORS_PER_TRACK)).clamp(1, 1024) - 1;
+                set_hi8(&mut gprs.rcx, cylinders as u8);
+                set_lo8(
+                    &mut gprs.rcx,
+                    SECTORS_PER_TRACK as u8 | ((cylinders >> 2) as u8 & 0xc0),
+                );
+                set_hi8(&mut gprs.rdx, HEADS as u8 - 1);
+                set_lo8(&mut gprs.rdx, 1);
+            }
+            // Get disk type: fixed disk.
+            0x15 => {
+                set_lo16(&mut gprs.rcx, (sectors >> 16) as u16);
+                set_lo16(&mut gprs.rdx, sectors as u16);
+                set_hi8(&mut gprs.rax, 0x03);
+                return Ok(());
+            }
+            // Check extensions present.
+            0x41 if gprs.rbx as u16 == 0x55aa => {
+                set_lo16(&mut gprs.rbx, 0xaa55);
+                // Version 2.1 with the disk address packet accesses.
+                set_lo16(&mut gprs.rcx, 0x1);
+                set_hi8(&mut gprs.rax, 0x21);
+                return Ok(());
+            }
+            // Extended read / write sectors.
+            ah @ (0x42 | 0x43) => {
+                let mut dap = [0u8; 16];
+                let dap_addr = mem
+                    .linear(Field::GuestDsBase, gprs.rsi)
+                    .map_err(|_| BOUNDARY_ERROR)?;
+                if !mem.read(dap_addr, &mut dap) || dap[0] < 16 {
+                    return Err(INVALID_COMMAND);
+                }
+                let count = u16::from_le_bytes([dap[2], dap[3]]) as usize;
+                let offset = u16::from_le_bytes([dap[4], dap[5]]) as usize;
+                let segment = u16::from_le_bytes([dap[6], dap[7]]) as usize;
+                let lba = u64::from_le_bytes(dap[8..16].try_into().unwrap());
+                self.transfer(mem, lba, count, (segment << 4) + offset, ah == 0x43)?;
+            }
+            // Extended get drive parameters.
+            0x48 => {
+                let mut params = [0u8; 26];
+                params[0..2].copy_from_slice(&26u16.to_le_bytes());
+                params[4..8].copy_from_slice(
+                    &((sectors / (HEADS * SECTORS_PER_TRACK)) as u32).to_le_bytes(),
+                );
+                params[8..12].copy_from_slice(&(HEADS as u32).to_le_bytes());
+                params[12..16].copy_from_slice(&(SECTORS_PER_TRACK as u32).to_le_bytes());
+                params[16..24].copy_from_slice(&sectors.to_le_bytes());
+                params[24..26].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+                let addr = mem
+                    .linear(Field::GuestDsBase, gprs.rsi)
+                    .map_err(|_| BOUNDARY_ERROR)?;
+                if !mem.write(addr, &params) {
+                    return Err(BOUNDARY_ERROR);
+                }
+            }
+            _ => return Err(INVALID_COMMAND),
+        }
+        set_hi8(&mut gprs.rax, 0);
+        Ok(())
+    }
+
+    /// INT 15h, system services.
+    fn system<P: Probe>(
+        &self,
+        mem: &GuestMemory<P>,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> Result<(), u8> {
+        const MB: u64 = 0x10_0000;
+        match gprs.rax as u16 {
+            // Query system address map.
+            0xe820 if gprs.rdx as u32 == SMAP => {
+                let index = gprs.rbx as u32 as usize;
+                let entry = self.memory_map.entries().get(index).ok_or(UNSUPPORTED)?;
+                let buf = mem
+                    .linear(Field::GuestEsBase, gprs.rdi)
+                    .map_err(|_| UNSUPPORTED)?;
+                if (gprs.rcx as u32) < 20 || !mem.write(buf, &entry.to_bytes()) {
+                    return Err(UNSUPPORTED);
+                }
+                let next = if index + 1 < self.memory_map.entries().len() {
+                    index + 1
+                } else {
+                    0
+                };
+                set_lo32(&mut gprs.rax, SMAP);
+                set_lo32(&mut gprs.rcx, 20);
+                set_lo32(&mut gprs.rbx, next as u32);
+            }
+            // Get memory size for large configurations.
+            0xe801 => {
+                let low = (self.memory_map.ram_within(MB, 16 * MB) / 1024) as u16;
+                let high = (self.memory_map.ram_within(16 * MB, 4096 * MB) / 0x10000) as u16;
+                set_lo16(&mut gprs.rax, low);
+                set_lo16(&mut gprs.rcx, low);
+                set_lo16(&mut gprs.rbx, high);
+                set_lo16(&mut gprs.rdx, high);
+            }
+            // Enable / Disable A20 gate. A20 is always enabled.
+            0x2400 | 0x2401 => set_hi8(&mut gprs.rax, 0),
+            // Query A20 gate status.
+            0x2402 => set_lo16(&mut gprs.rax, 0x0001),
+            // Query A20 gate support.
+            0x2403 => {
+                set_hi8(&mut gprs.rax, 0);
+                set_lo16(&mut gprs.rbx, 0x3);
+            }
+            // Get extended memory size.
+            ax if ax >> 8 == 0x88 => {
+                let kib = (self.memory_map.ram_within(MB, 4096 * MB) / 1024).min(0xffff);
+                set_lo16(&mut gprs.rax, kib as u16);
+            }
+            _ => return Err(UNSUPPORTED),
+        }
+        Ok(())
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        let GenericVCpuState { vmcs, gprs, .. } = generic_vcpu_state;
+        if !matches!(reason.get_basic_reason(), BasicExitReason::Vmcall)
+            || vmcs.read(Field::GuestCr0)? & Cr0::PE.bits() != 0
+        {
+            return Err(VmError::HandleVmexitFailed(reason));
+        }
+        let ip = (vmcs.read(Field::GuestCsBase)? + vmcs.read(Field::GuestRip)?) as usize;
+        let service = match ip.checked_sub(BIOS_BASE) {
+            Some(service) if service < BIOS_SIZE => service,
+            _ => return Err(VmError::HandleVmexitFailed(reason)),
+        };
+
+        let mem = GuestMemory { p: &*p, vmcs };
+        let result = match service {
+            BOOT => return self.boot(&mem, gprs),
+            INT10 => self.video(gprs),
+            INT12 => {
+                set_lo16(&mut gprs.rax, self.conventional_kib());
+                Ok(())
+            }
+            INT13 => self.disk(&mem, gprs),
+            INT15 => self.system(&mem, gprs),
+            _ => return Err(VmError::HandleVmexitFailed(reason)),
+        };
+        if let Err(status) = result {
+            set_hi8(&mut gprs.rax, status);
+        }
+        // Report the result on the carry flag of the FLAGS image that is
+        // restored by the `iret`.
+        let flags = mem.linear(Field::GuestSsBase, vmcs.read(Field::GuestRsp)? as usize + 4)?;
+        let mut image = [0; 2];
+        if mem.read(flags, &mut image) {
+            let image = (u16::from_le_bytes(image) & !1) | result.is_err() as u16;
+            mem.write(flags, &image.to_le_bytes());
+        }
+        Ok(VmexitResult::HandledAdvance)
+    }
+}
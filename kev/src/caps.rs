@@ -0,0 +1,297 @@
+//! VMX capabilities of the cpu.
+//!
+//! [`probe`] reads the VMX capability MSRs into a [`VmxCaps`]. The controls
+//! that a [`VmState`] requests are validated against it before they are
+//! written to the VMCS, so that an unsupported control is reported by its name
+//! instead of a failed vmentry:
+//!
+//! ```ignore
+//! let caps = kev::caps::probe();
+//! caps.procbased2
+//!     .check(VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST)?;
+//! println!("{}", caps);
+//! ```
+//!
+//! [`VmState`]: crate::vm::VmState
+use crate::{vm_control::*, VmError};
+use abyss::x86_64::msr::Msr;
+use alloc::format;
+use core::ops::{BitAnd, BitOr, Sub};
+
+/// The allowed settings of a VM-execution, VM-exit or VM-entry control field.
+#[derive(Clone, Copy, Debug)]
+pub struct Controls<T> {
+    /// The controls that must be 1 (allowed 0-settings).
+    pub allowed0: T,
+    /// The controls that may be 1 (allowed 1-settings).
+    pub allowed1: T,
+}
+
+impl<T> Controls<T>
+where
+    T: Copy
+        + core::fmt::Debug
+        + PartialEq
+        + BitOr<Output = T>
+        + BitAnd<Output = T>
+        + Sub<Output = T>,
+{
+    // Decode the capability MSR of the controls.
+    fn from_msr(msr: u64, from_bits: impl Fn(u32) -> T) -> Self {
+        Self {
+            allowed0: from_bits(msr as u32),
+            allowed1: from_bits((msr >> 32) as u32),
+        }
+    }
+
+    /// Check whether all of the `controls` are supported.
+    pub fn supports(&self, controls: T) -> bool {
+        controls & self.allowed1 == controls
+    }
+
+    /// Get the controls in `controls` that are not supported.
+    pub fn unsupported(&self, controls: T) -> T {
+        controls - self.allowed1
+    }
+
+    /// Validate the `controls`.
+    ///
+    /// Returns [`VmError::Unsupported`] that names the unsupported controls.
+    pub fn check(&self, controls: T) -> Result<(), VmError> {
+        if self.supports(controls) {
+            Ok(())
+        } else {
+            Err(VmError::Unsupported(format!(
+                "{:?} not supported on this CPU",
+                self.unsupported(controls)
+            )))
+        }
+    }
+
+    /// Get the value of the control field that enables the `controls`, with
+    /// the controls that must be 1.
+    pub fn adjust(&self, controls: T) -> T {
+        (controls | self.allowed0) & self.allowed1
+    }
+}
+
+bitflags::bitflags! {
+    /// A.10 VPID and EPT capabilities (IA32_VMX_EPT_VPID_CAP).
+    pub struct EptVpidCap: u64 {
+        /// The EPT supports the execute-only translations.
+        const EXECUTE_ONLY = 1 << 0;
+        /// The EPT supports the page-walk length of 4.
+        const PAGE_WALK_4 = 1 << 6;
+        /// The EPT supports the page-walk length of 5.
+        const PAGE_WALK_5 = 1 << 7;
+        /// The EPT paging-structure memory type can be uncacheable.
+        const MEMORY_TYPE_UC = 1 << 8;
+        /// The EPT paging-structure memory type can be write-back.
+        const MEMORY_TYPE_WB = 1 << 14;
+        /// The EPT supports the 2MB pages.
+        const PAGE_2M = 1 << 16;
+        /// The EPT supports the 1GB pages.
+        const PAGE_1G = 1 << 17;
+        /// INVEPT is supported.
+        const INVEPT = 1 << 20;
+        /// The accessed and dirty flags of the EPT are supported.
+        const ACCESSED_DIRTY = 1 << 21;
+        /// The single-context INVEPT is supported.
+        const INVEPT_SINGLE_CONTEXT = 1 << 25;
+        /// The all-context INVEPT is supported.
+        const INVEPT_ALL_CONTEXT = 1 << 26;
+        /// INVVPID is supported.
+        const INVVPID = 1 << 32;
+        /// The individual-address INVVPID is supported.
+        const INVVPID_INDIVIDUAL_ADDRESS = 1 << 40;
+        /// The single-context INVVPID is supported.
+        const INVVPID_SINGLE_CONTEXT = 1 << 41;
+        /// The all-context INVVPID is supported.
+        const INVVPID_ALL_CONTEXT = 1 << 42;
+    }
+}
+
+/// The VMX capabilities of the cpu.
+#[derive(Clone, Copy, Debug)]
+pub struct VmxCaps {
+    /// IA32_VMX_BASIC.
+    pub basic: u64,
+    /// IA32_VMX_MISC.
+    pub misc: u64,
+    /// Pin-based VM-execution controls.
+    pub pinbased: Controls<VmcsPinBasedVmexecCtl>,
+    /// Primary processor-based VM-execution controls.
+    pub procbased: Controls<VmcsProcBasedVmexecCtl>,
+    /// Secondary processor-based VM-execution controls.
+    ///
+    /// Empty if the secondary controls can not be activated.
+    pub procbased2: Controls<VmcsProcBasedSecondaryVmexecCtl>,
+    /// VM-exit controls.
+    pub exit: Controls<VmcsExitCtl>,
+    /// VM-entry controls.
+    pub entry: Controls<VmcsEntryCtl>,
+    /// EPT and VPID capabilities.
+    pub ept_vpid: EptVpidCap,
+}
+
+/// Read the VMX capabilities of the current cpu.
+pub fn probe() -> VmxCaps {
+    unsafe {
+        let procbased = Controls::from_msr(Msr::<IA32_VMX_PROC_BASED_CTLS>::read(), |b| {
+            VmcsProcBasedVmexecCtl::from_bits_unchecked(b)
+        });
+        let has_secondary = procbased
+            .allowed1
+            .contains(VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL);
+        VmxCaps {
+            basic: Msr::<IA32_VMX_BASIC>::read(),
+            misc: Msr::<IA32_VMX_MISC>::read(),
+            pinbased: Controls::from_msr(Msr::<IA32_VMX_PINBASED_CTLS>::read(), |b| {
+                VmcsPinBasedVmexecCtl::from_bits_unchecked(b)
+            }),
+            procbased,
+            // The MSRs below exist only if the secondary controls can be
+            // activated.
+            procbased2: Controls::from_msr(
+                if has_secondary {
+                    Msr::<IA32_VMX_PROC_BASED_CTLS2>::read()
+                } else {
+                    0
+                },
+                |b| VmcsProcBasedSecondaryVmexecCtl::from_bits_unchecked(b),
+            ),
+            exit: Controls::from_msr(Msr::<IA32_VMX_EXIT_CTLS>::read(), |b| {
+                VmcsExitCtl::from_bits_unchecked(b)
+            }),
+            entry: Controls::from_msr(Msr::<IA32_VMX_ENTRY_CTLS>::read(), |b| {
+                VmcsEntryCtl::from_bits_unchecked(b)
+            }),
+            ept_vpid: EptVpidCap::from_bits_truncate(if has_secondary {
+                Msr::<IA32_VMX_EPT_VPID_CAP>::read()
+            } else {
+                0
+            }),
+        }
+    }
+}
+
+impl core::fmt::Display for VmxCaps {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "VMCS revision: {:#x}", self.basic & 0x7fff_ffff)?;
+        writeln!(
+            f,
+            "pin-based: {:?}",
+            self.pinbased.allowed1 & VmcsPinBasedVmexecCtl::all()
+        )?;
+        writeln!(
+            f,
+            "proc-based: {:?}",
+            self.procbased.allowed1 & VmcsProcBasedVmexecCtl::all()
+        )?;
+        writeln!(
+            f,
+            "proc-based2: {:?}",
+            self.procbased2.allowed1 & VmcsProcBasedSecondaryVmexecCtl::all()
+        )?;
+        writeln!(f, "exit: {:?}", self.exit.allowed1 & VmcsExitCtl::all())?;
+        writeln!(f, "entry: {:?}", self.entry.allowed1 & VmcsEntryCtl::all())?;
+        write!(f, "ept/vpid: {:?}", self.ept_vpid)
+    }
+}
+
+impl VmxCaps {
+    /// Check whether the EPT supports the accessed and dirty flags.
+    pub fn ept_accessed_dirty(&self) -> bool {
+        self.ept_vpid.contains(EptVpidCap::ACCESSED_DIRTY)
+    }
+
+    /// Check whether the VMX-preemption timer is supported.
+    pub fn preemption_timer(&self) -> bool {
+        self.pinbased
+            .supports(VmcsPinBasedVmexecCtl::ACTIVE_VMX_PREEMPTION_TIMER)
+    }
+
+    /// Check whether the virtual-interrupt delivery (APICv) is supported.
+    pub fn apicv(&self) -> bool {
+        self.procbased2.supports(
+            VmcsProcBasedSecondaryVmexecCtl::VIRTUALIZE_APIC_ACCESSES
+                | VmcsProcBasedSecondaryVmexecCtl::VIRTUAL_INTERRUPT_DELIVERY,
+        ) && self
+            .procbased
+            .supports(VmcsProcBasedVmexecCtl::USETPRSHADOW)
+    }
+
+    /// Select the [`Features`] that the vms use on this cpu.
+    pub fn features(&self) -> Features {
+        Features {
+            dirty_tracking: if self.ept_accessed_dirty() {
+                DirtyTracking::AccessedDirty
+            } else {
+                DirtyTracking::WriteProtect
+            },
+            interrupt_delivery: InterruptDelivery::Software,
+            exit_timer: if self.preemption_timer() {
+                ExitTimer::PreemptionTimer {
+                    rate: (self.misc & 0x1f) as u8,
+                }
+            } else {
+                ExitTimer::HostTimer
+            },
+        }
+    }
+}
+
+/// How the writes to the guest memory are tracked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirtyTracking {
+    /// The cpu sets the dirty flags of the EPT entries.
+    AccessedDirty,
+    /// The pages are mapped read-only, and the first write to a page is
+    /// tracked on the EPT violation.
+    WriteProtect,
+}
+
+/// How the interrupts are delivered to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptDelivery {
+    /// The hypervisor injects the interrupts on the vm entries.
+    ///
+    /// This is the only delivery that kev implements; the cpus with APICv
+    /// also use it.
+    Software,
+}
+
+/// How a vcpu is forced to exit at a deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitTimer {
+    /// The VMX-preemption timer, which counts down once every `2^rate` tsc
+    /// cycles.
+    PreemptionTimer {
+        /// The rate of the timer relative to the tsc.
+        rate: u8,
+    },
+    /// The host timer interrupt forces a vmexit every tick, and the deadline
+    /// is checked on the vmexits.
+    HostTimer,
+}
+
+/// The features that a vm uses, selected by the capabilities of the cpu.
+///
+/// The features fall back to the software implementations on the cpus that
+/// lack them, such as the older nested-virtualization setups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features {
+    /// How the writes to the guest memory are tracked.
+    pub dirty_tracking: DirtyTracking,
+    /// How the interrupts are delivered.
+    pub interrupt_delivery: InterruptDelivery,
+    /// How the vcpus are forced to exit at a deadline.
+    pub exit_timer: ExitTimer,
+}
+
+impl Features {
+    /// Select the features of the current cpu.
+    pub fn probe() -> Self {
+        probe().features()
+    }
+}
@@ -0,0 +1,167 @@
+//! Console multiplexer.
+//!
+//! When the vms run concurrently, their outputs interleave on the single host
+//! console. Instead, each vm writes into its own [`Console`], and only the
+//! console in the foreground is printed to the host console. The output of
+//! the background consoles is buffered, and printed when the console is
+//! switched to the foreground:
+//!
+//! ```ignore
+//! for name in console::list() {
+//!     println!("{}", name);
+//! }
+//! console::switch("vm1");
+//! ```
+//!
+//! The first console becomes the foreground if no console is in the
+//! foreground. Each console keeps the last [`Console::CAPACITY`] bytes of
+//! its output, which the tests inspect with [`VmHandle::console_output`].
+//!
+//! [`VmHandle::console_output`]: crate::vm::VmHandle::console_output
+use alloc::{
+    collections::VecDeque,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use keos::sync::{Rcu, SpinLock};
+
+// The registered consoles.
+static CONSOLES: Rcu<Vec<Weak<Console>>> = Rcu::empty();
+// The console in the foreground, read on every write to the consoles.
+static FOREGROUND: Rcu<Weak<Console>> = Rcu::empty();
+
+struct Buffer {
+    data: VecDeque<u8>,
+    // Number of the bytes at the end of data that are not printed yet.
+    pending: usize,
+}
+
+/// A console of a vm.
+pub struct Console {
+    name: SpinLock<String>,
+    buffer: SpinLock<Buffer>,
+}
+
+impl Console {
+    /// Maximum number of the bytes kept in a console.
+    pub const CAPACITY: usize = 64 * 1024;
+
+    /// Create a new console of `name`, and register it to the multiplexer.
+    pub fn new(name: String) -> Arc<Self> {
+        let console = Arc::new(Self {
+            name: SpinLock::new(name),
+            buffer: SpinLock::new(Buffer {
+                data: VecDeque::new(),
+                pending: 0,
+            }),
+        });
+        CONSOLES.update(|consoles| {
+            let mut consoles = consoles
+                .into_iter()
+                .flatten()
+                .filter(|c| c.strong_count() != 0)
+                .cloned()
+                .collect::<Vec<_>>();
+            consoles.push(Arc::downgrade(&console));
+            Some(consoles)
+        });
+        let mut foreground = FOREGROUND.write();
+        if foreground.get().and_then(Weak::upgrade).is_none() {
+            foreground.replace(Some(Arc::downgrade(&console)));
+        }
+        console
+    }
+
+    /// Get the name of the console.
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    /// Rename the console.
+    pub fn rename(&self, name: String) {
+        *self.name.lock() = name;
+    }
+
+    /// Check whether the console is in the foreground.
+    pub fn is_foreground(&self) -> bool {
+        FOREGROUND
+            .read()
+            .is_some_and(|c| core::ptr::eq(c.as_ptr(), self))
+    }
+
+    /// Write the `bytes` to the console.
+    pub fn write(&self, bytes: &[u8]) {
+        let foreground = self.is_foreground();
+        let mut buffer = self.buffer.lock();
+        buffer.data.extend(bytes);
+        let overflow = buffer.data.len().saturating_sub(Self::CAPACITY);
+        buffer.data.drain(..overflow);
+        if foreground {
+            print!("{}", String::from_utf8_lossy(bytes));
+            buffer.pending = 0;
+        } else {
+            buffer.pending = (buffer.pending + bytes.len()).min(buffer.data.len());
+        }
+    }
+
+    /// Get the output of the console, up to the last [`Console::CAPACITY`]
+    /// bytes.
+    pub fn output(&self) -> Vec<u8> {
+        self.buffer.lock().data.iter().copied().collect()
+    }
+
+    // Print the output that is written in the background.
+    fn flush(&self) {
+        let mut buffer = self.buffer.lock();
+        let start = buffer.data.len() - buffer.pending;
+        let bytes = buffer.data.range(start..).copied().collect::<Vec<_>>();
+        print!("{}", String::from_utf8_lossy(&bytes));
+        buffer.pending = 0;
+    }
+}
+
+impl core::fmt::Write for &Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Get the names of the consoles.
+pub fn list() -> Vec<String> {
+    let consoles: Vec<Arc<Console>> = CONSOLES
+        .read()
+        .map(|consoles| consoles.iter().filter_map(Weak::upgrade).collect())
+        .unwrap_or_default();
+    consoles.iter().map(|c| c.name()).collect()
+}
+
+/// Get the name of the console in the foreground.
+pub fn foreground() -> Option<String> {
+    FOREGROUND
+        .read()
+        .and_then(|c| c.upgrade())
+        .map(|c| c.name())
+}
+
+/// Switch the console of `name` to the foreground.
+///
+/// The output of the console that is written in the background is printed.
+/// Returns `false` if no console has the `name`.
+pub fn switch(name: &str) -> bool {
+    let console = CONSOLES.read().and_then(|consoles| {
+        consoles
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|c| *c.name.lock() == name)
+    });
+    match console {
+        Some(console) => {
+            FOREGROUND.replace(Some(Arc::downgrade(&console)));
+            console.flush();
+            true
+        }
+        None => false,
+    }
+}
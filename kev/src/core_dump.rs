@@ -0,0 +1,345 @@
+//! Guest core dump.
+//!
+//! [`Vm::dump_core`] writes the guest RAM and the registers of the vcpus into
+//! a file of the filesystem, so that the guest can be inspected after it
+//! fails. As the filesystem only supports fixed-size files, the file must be
+//! created on the disk image in advance with enough size. The file is
+//! extracted from the disk image on the host with the `extract` example of
+//! `simple_fs`:
+//!
+//! ```text
+//! $ cargo run --example extract -- blk.bin core core.bin
+//! ```
+//!
+//! All integers are little-endian. The file starts with the [`Header`],
+//! followed by the [`VCpuRegs`] of each vcpu and the [`Region`] of each guest
+//! RAM region. The contents of the RAM follow at the offsets recorded in the
+//! regions. The pages that are not populated yet are dumped as zeros.
+//!
+//! The contents of the RAM are encrypted if the vm has a key of the
+//! [`ram_crypt`]. The header then records the nonce of the encryption, and the
+//! offsets of the cipher are the offsets in the file.
+//!
+//! If the vm has a [`Pipeline`] of the [`stream_codec`], the contents of a
+//! region are the frames of its pages instead: each frame is the u32 length
+//! of the page encoded by the pipeline, followed by the encoded page, which is
+//! then encrypted if the RAM is encrypted.
+//!
+//! [`ram_crypt`]: crate::ram_crypt
+//! [`stream_codec`]: crate::stream_codec
+//! [`Vm::dump_core`]: crate::vm::Vm::dump_core
+use crate::{
+    e820::MemoryMap, ram_crypt::RamCipher, stream_codec::Pipeline, vcpu::GenericVCpuState, vm::Gpa,
+    vmcs::Field, VmError,
+};
+use abyss::addressing::{Pa, PAGE_SIZE};
+use alloc::{vec, vec::Vec};
+use keos::fs::{record, File, Record};
+
+/// Magic of the core dump.
+pub const MAGIC: [u8; 8] = *b"KEVCORE\0";
+/// Version of the format.
+pub const VERSION: u32 = 2;
+
+/// Error of the core dump.
+#[derive(Debug)]
+pub enum CoreDumpError {
+    /// The filesystem is not mounted.
+    NoFileSystem,
+    /// The file does not exist.
+    NoFile,
+    /// The vm does not describe its memory.
+    NoMemoryMap,
+    /// The file is smaller than the core dump.
+    FileTooSmall {
+        /// The size of the core dump in bytes.
+        ///
+        /// With a [`Pipeline`], it is the size up to the frame that does not
+        /// fit, as the size of the rest is unknown.
+        required: usize,
+    },
+    /// The filesystem has an error.
+    Fs(keos::fs::Error),
+}
+
+/// Header of the core dump.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    /// Number of the vcpus.
+    pub vcpus: u32,
+    /// Number of the RAM regions.
+    pub regions: u32,
+    /// The nonce of the RAM encryption, or `None` if the RAM is in the clear.
+    pub nonce: Option<u64>,
+    /// Whether the RAM is in the frames of a [`Pipeline`].
+    pub framed: bool,
+}
+
+record! {
+    // Header on the file.
+    struct RawHeader {
+        magic: [u8; 8],
+        version: u32,
+        vcpus: u32,
+        regions: u32,
+        flags: u32,
+        nonce: u64,
+    }
+}
+
+impl Header {
+    /// The flag of the encrypted RAM.
+    pub const ENCRYPTED: u32 = 1;
+    /// The flag of the RAM in the frames.
+    pub const FRAMED: u32 = 2;
+    /// Size of the header in bytes.
+    pub const SIZE: usize = RawHeader::SIZE;
+
+    fn encode(&self, out: &mut [u8]) -> Option<()> {
+        RawHeader {
+            magic: MAGIC,
+            version: VERSION,
+            vcpus: self.vcpus,
+            regions: self.regions,
+            flags: (self.nonce.is_some() as u32 * Self::ENCRYPTED)
+                | (self.framed as u32 * Self::FRAMED),
+            nonce: self.nonce.unwrap_or(0),
+        }
+        .encode(out)
+    }
+
+    /// Decode the header from the start of `b`.
+    ///
+    /// Returns `None` if `b` is short, or is not a core dump of [`VERSION`].
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        match RawHeader::decode(b)? {
+            RawHeader {
+                magic: MAGIC,
+                version: VERSION,
+                vcpus,
+                regions,
+                flags,
+                nonce,
+            } => Some(Self {
+                vcpus,
+                regions,
+                nonce: (flags & Self::ENCRYPTED != 0).then_some(nonce),
+                framed: flags & Self::FRAMED != 0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Registers of a vcpu.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VCpuRegs {
+    /// Id of the vcpu.
+    pub id: u32,
+    /// Whether the registers are valid.
+    ///
+    /// The registers of a running vcpu can not be read.
+    pub valid: bool,
+    /// Rip.
+    pub rip: u64,
+    /// Rsp.
+    pub rsp: u64,
+    /// Rflags.
+    pub rflags: u64,
+    /// Cr0.
+    pub cr0: u64,
+    /// Cr3.
+    pub cr3: u64,
+    /// Cr4.
+    pub cr4: u64,
+    /// General purpose registers, in the order from rax to r15 except rsp.
+    pub gprs: [u64; 15],
+}
+
+record! {
+    // Registers on the file.
+    struct RawVCpuRegs {
+        id: u32,
+        valid: u32,
+        regs: [u64; 21],
+    }
+}
+
+impl VCpuRegs {
+    /// Size of the registers in bytes.
+    pub const SIZE: usize = RawVCpuRegs::SIZE;
+
+    pub(crate) fn read(generic_state: &GenericVCpuState) -> Result<Self, VmError> {
+        let (vmcs, gprs) = (&generic_state.vmcs, &generic_state.gprs);
+        Ok(Self {
+            id: generic_state.id() as u32,
+            valid: true,
+            rip: vmcs.read(Field::GuestRip)?,
+            rsp: vmcs.read(Field::GuestRsp)?,
+            rflags: vmcs.read(Field::GuestRflags)?,
+            cr0: vmcs.read(Field::GuestCr0)?,
+            cr3: vmcs.read(Field::GuestCr3)?,
+            cr4: vmcs.read(Field::GuestCr4)?,
+            gprs: [
+                gprs.rax, gprs.rbx, gprs.rcx, gprs.rdx, gprs.rbp, gprs.rdi, gprs.rsi, gprs.r8,
+                gprs.r9, gprs.r10, gprs.r11, gprs.r12, gprs.r13, gprs.r14, gprs.r15,
+            ]
+            .map(|r| r as u64),
+        })
+    }
+
+    fn encode(&self, out: &mut [u8]) -> Option<()> {
+        let mut regs = [0; 21];
+        regs[..6].copy_from_slice(&[
+            self.rip,
+            self.rsp,
+            self.rflags,
+            self.cr0,
+            self.cr3,
+            self.cr4,
+        ]);
+        regs[6..].copy_from_slice(&self.gprs);
+        RawVCpuRegs {
+            id: self.id,
+            valid: self.valid as u32,
+            regs,
+        }
+        .encode(out)
+    }
+
+    /// Decode the registers from the start of `b`.
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        let RawVCpuRegs { id, valid, regs } = RawVCpuRegs::decode(b)?;
+        let [rip, rsp, rflags, cr0, cr3, cr4, ..] = regs;
+        let mut gprs = [0; 15];
+        gprs.copy_from_slice(&regs[6..]);
+        Some(Self {
+            id,
+            valid: valid != 0,
+            rip,
+            rsp,
+            rflags,
+            cr0,
+            cr3,
+            cr4,
+            gprs,
+        })
+    }
+}
+
+record! {
+    /// A guest RAM region in the core dump.
+    ///
+    /// The region is a [`Record`] of 24 bytes.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Region {
+        /// Guest physical address of the region.
+        pub base: u64,
+        /// Size of the region in bytes.
+        pub len: u64,
+        /// Offset of the contents in the file.
+        pub offset: u64,
+    }
+}
+
+// Size of the records before the contents of the RAM.
+fn head_size(vcpus: usize, regions: usize) -> usize {
+    Header::SIZE + vcpus * VCpuRegs::SIZE + regions * Region::SIZE
+}
+
+// Encode the header, the registers and the regions.
+fn encode_head(
+    vcpus: &[VCpuRegs],
+    regions: &[Region],
+    cipher: Option<&RamCipher>,
+    framed: bool,
+) -> Option<Vec<u8>> {
+    let mut head = vec![0; head_size(vcpus.len(), regions.len())];
+    Header {
+        vcpus: vcpus.len() as u32,
+        regions: regions.len() as u32,
+        nonce: cipher.map(RamCipher::nonce),
+        framed,
+    }
+    .encode(&mut head)?;
+    let mut ofs = Header::SIZE;
+    for regs in vcpus {
+        regs.encode(head.get_mut(ofs..)?)?;
+        ofs += VCpuRegs::SIZE;
+    }
+    for region in regions {
+        region.encode(head.get_mut(ofs..)?)?;
+        ofs += Region::SIZE;
+    }
+    Some(head)
+}
+
+/// Write the core dump into the `file`.
+///
+/// `gpa2hpa` translates the guest physical page to the host physical page.
+/// The RAM is encoded in the frames with the `pipeline`, and encrypted with
+/// the `cipher` if any. Returns the size of the core dump in bytes.
+pub(crate) fn write(
+    file: &File,
+    vcpus: &[VCpuRegs],
+    memory_map: &MemoryMap,
+    gpa2hpa: impl Fn(Gpa) -> Option<Pa>,
+    cipher: Option<&RamCipher>,
+    mut pipeline: Option<&mut Pipeline>,
+) -> Result<usize, CoreDumpError> {
+    let ram = memory_map.ram().collect::<Vec<_>>();
+    let mut regions = ram
+        .iter()
+        .map(|e| Region {
+            base: e.base,
+            len: e.len,
+            offset: 0,
+        })
+        .collect::<Vec<_>>();
+    let mut pos = head_size(vcpus.len(), regions.len()) as u64;
+    let framed = pipeline.as_ref().is_some_and(|p| !p.is_empty());
+    if !framed {
+        let required = pos as usize + ram.iter().map(|e| e.len as usize).sum::<usize>();
+        if required > file.size() {
+            return Err(CoreDumpError::FileTooSmall { required });
+        }
+    }
+
+    let zeros = [0; PAGE_SIZE];
+    for region in regions.iter_mut() {
+        region.offset = pos;
+        for ofs in (0..region.len).step_by(PAGE_SIZE) {
+            let len = (region.len - ofs).min(PAGE_SIZE as u64) as usize;
+            let page = Gpa::new((region.base + ofs) as usize)
+                .and_then(&gpa2hpa)
+                .map(|pa| unsafe {
+                    core::slice::from_raw_parts(pa.into_va().into_usize() as *const u8, len)
+                })
+                .unwrap_or(&zeros[..len]);
+            let (mut data, payload) = match pipeline.as_deref_mut() {
+                Some(pipeline) if framed => {
+                    let encoded = pipeline.encode(page);
+                    let mut frame = Vec::with_capacity(4 + encoded.len());
+                    frame.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+                    frame.extend_from_slice(&encoded);
+                    (frame, 4)
+                }
+                _ => (page.to_vec(), 0),
+            };
+            if let Some(cipher) = cipher {
+                cipher.apply(pos + payload as u64, &mut data[payload..]);
+            }
+            let required = pos as usize + data.len();
+            if required > file.size() {
+                return Err(CoreDumpError::FileTooSmall { required });
+            }
+            file.write(pos as usize, &data).map_err(CoreDumpError::Fs)?;
+            pos += data.len() as u64;
+        }
+    }
+
+    // The buffer is sized to the records, so the encoding does not fail.
+    let head = encode_head(vcpus, &regions, cipher, framed).expect("core dump header overflows");
+    file.write(0, &head).map_err(CoreDumpError::Fs)?;
+    Ok(pos as usize)
+}
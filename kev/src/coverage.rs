@@ -0,0 +1,183 @@
+//! Coverage of the guest code execution.
+//!
+//! In the coverage mode, kev records which guest code the vcpus executed, to
+//! measure the completeness of the guest test suites, or to guide a fuzzer.
+//! The vcpu is single-stepped with the monitor trap flag (MTF), and the rip of
+//! each stepped instruction is recorded in the [`CoverageMap`] of the vm, at
+//! the [`Granularity`] of the [`Coverage`]:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?
+//!     .coverage(Coverage::new(Granularity::Page))
+//!     .finalize()?;
+//! vm.start_bsp()?;
+//! // ...
+//! for page in vm.coverage_map().addresses() {
+//!     println!("{:#x}", page);
+//! }
+//! vm.coverage_map().save(&file)?;
+//! ```
+//!
+//! The addresses are the guest virtual addresses of the code. At the
+//! [`Granularity::Instruction`], a basic block is covered if its first
+//! instruction is.
+//!
+//! Stepping every instruction costs a vmexit per instruction. With
+//! [`Coverage::interval`], only every n-th vm entry of the vm is stepped, and
+//! the coverage is sampled instead of exact. An instruction stepped while an
+//! event is injected on the vm entry is not recorded, as the guest jumps to
+//! the handler of the event instead.
+use crate::{
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use keos::{
+    fs::{Error, File},
+    sync::SpinLock,
+};
+
+/// The unit of the recorded code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    /// The 4 KiB pages of the code.
+    Page,
+    /// The instructions.
+    Instruction,
+}
+
+/// Configuration of the coverage mode.
+#[derive(Clone, Copy, Debug)]
+pub struct Coverage {
+    granularity: Granularity,
+    interval: u64,
+}
+
+impl Coverage {
+    /// Record the code at the `granularity`, stepping every instruction.
+    pub fn new(granularity: Granularity) -> Self {
+        Self {
+            granularity,
+            interval: 1,
+        }
+    }
+
+    /// Step only every `interval`-th vm entry.
+    pub fn interval(mut self, interval: u64) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+}
+
+/// The guest code executed by the vcpus of a vm.
+pub struct CoverageMap {
+    enabled: AtomicBool,
+    coverage: SpinLock<Option<Coverage>>,
+    addresses: SpinLock<BTreeSet<u64>>,
+    // Number of the vm entries.
+    entries: AtomicU64,
+}
+
+impl Default for CoverageMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoverageMap {
+    /// Create a map that is off.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            coverage: SpinLock::new(None),
+            addresses: SpinLock::new(BTreeSet::new()),
+            entries: AtomicU64::new(0),
+        }
+    }
+
+    /// Turn on the coverage mode with the `coverage`, or turn off with `None`.
+    ///
+    /// The recorded code is kept.
+    pub fn set(&self, coverage: Option<Coverage>) {
+        let mut guard = self.coverage.lock();
+        self.enabled.store(coverage.is_some(), Ordering::SeqCst);
+        *guard = coverage;
+    }
+
+    /// Check whether the coverage mode is on.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Get the addresses of the recorded code, in the ascending order.
+    pub fn addresses(&self) -> Vec<u64> {
+        self.addresses.lock().iter().copied().collect()
+    }
+
+    /// Get the number of the recorded pages or instructions.
+    pub fn len(&self) -> usize {
+        self.addresses.lock().len()
+    }
+
+    /// Check whether no code is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether the code at the guest virtual address `addr` is covered.
+    pub fn contains(&self, addr: u64) -> bool {
+        let granularity = self.coverage.lock().map(|c| c.granularity);
+        self.addresses.lock().contains(&match granularity {
+            Some(Granularity::Page) => addr & !0xfff,
+            _ => addr,
+        })
+    }
+
+    /// Forget the recorded code.
+    pub fn clear(&self) {
+        self.addresses.lock().clear();
+    }
+
+    /// Save the addresses into the `file`, one hexadecimal address per line.
+    ///
+    /// Returns the number of the written bytes.
+    pub fn save(&self, file: &File) -> Result<usize, Error> {
+        let mut out = alloc::string::String::new();
+        for addr in self.addresses.lock().iter() {
+            let _ = writeln!(out, "{:#x}", addr);
+        }
+        if out.len() > file.size() {
+            return Err(Error::FsError);
+        }
+        file.write(0, out.as_bytes())
+    }
+
+    /// Step the vcpu on the vm entry, if sampled, recording the instruction
+    /// at the rip.
+    pub(crate) fn arm(&self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        let Some(coverage) = *self.coverage.lock() else {
+            return Ok(());
+        };
+        if self.entries.fetch_add(1, Ordering::Relaxed) % coverage.interval != 0 {
+            return Ok(());
+        }
+        if vmcs.read(Field::VmentryInterruptionInfo)? & (1 << 31) == 0 {
+            let rip = vmcs.read(Field::GuestRip)?;
+            self.addresses.lock().insert(match coverage.granularity {
+                Granularity::Page => rip & !0xfff,
+                Granularity::Instruction => rip,
+            });
+        }
+        vmcs.set_mtf(true)
+    }
+
+    /// Stop stepping the vcpu on the vmexit.
+    pub(crate) fn on_vmexit(&self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        vmcs.set_mtf(false)
+    }
+}
@@ -0,0 +1,114 @@
+//! Calls on a specific cpu.
+//!
+//! Some operations only act on the cpu that executes them: INVEPT and
+//! INVVPID flush the TLB of the current cpu, and the VMX capabilities, the
+//! current VMCS and the VMX-preemption timer rate are per-cpu state. A thread
+//! may be migrated at any time, so [`on_cpu`] runs a closure on the cpu of the
+//! id instead of relying on the caller to be pinned on it:
+//!
+//! ```ignore
+//! // Flush the mappings of all EPTs on the cpu 2.
+//! kev::on_cpu(2, || Flush::AllEpt.flush_local())?;
+//! // Read the capabilities of the cpu 1.
+//! let caps = kev::on_cpu(1, kev::caps::probe)?;
+//! ```
+//!
+//! The closure is queued on the cpu, which is kicked with an IPI on the
+//! [`CPU_CALL_VECTOR`], and runs in its interrupt handler, in the VMX-root
+//! operation. The closure must not sleep or take a lock that sleeps. The
+//! caller waits with the interrupts disabled, while serving the calls queued
+//! on its own cpu, so two cpus may call each other at the same time.
+//!
+//! Only the cpus that entered the VMX operation with [`start_vmx_on_cpu`]
+//! take the calls.
+//!
+//! [`start_vmx_on_cpu`]: crate::start_vmx_on_cpu
+use abyss::interrupt::InterruptGuard;
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use keos::{
+    intrinsics::cpuid,
+    sync::{CachePadded, PerCpu, SpinLock},
+    MAX_CPU,
+};
+
+/// Interrupt vector of the calls.
+pub const CPU_CALL_VECTOR: usize = 102;
+
+/// Error of a call.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CpuCallError {
+    /// The cpu does not exist.
+    InvalidCpu(usize),
+    /// The cpu has not entered the VMX operation.
+    Offline(usize),
+}
+
+type Call = Box<dyn FnOnce() + Send>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const INIT: CachePadded<SpinLock<VecDeque<Call>>> =
+    CachePadded::new(SpinLock::new(VecDeque::new()));
+static QUEUES: PerCpu<SpinLock<VecDeque<Call>>> = PerCpu::new([INIT; MAX_CPU]);
+
+// Bitmap of the cpus that take the calls.
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+// Run the calls queued on the current cpu.
+fn process_local_queue() {
+    loop {
+        let call = QUEUES.get().lock().pop_front();
+        match call {
+            Some(call) => call(),
+            None => break,
+        }
+    }
+}
+
+// Start to take the calls on the cpu of `core_id`.
+pub(crate) fn init(core_id: usize) {
+    if !REGISTERED.swap(true, Ordering::SeqCst) {
+        keos::interrupt::register(CPU_CALL_VECTOR, process_local_queue);
+    }
+    ONLINE.fetch_or(1 << core_id, Ordering::SeqCst);
+}
+
+/// Run the closure `f` on the cpu of `core_id`, and returns its result.
+///
+/// The closure runs at once if the caller is on the cpu.
+pub fn on_cpu<R, F>(core_id: usize, f: F) -> Result<R, CpuCallError>
+where
+    R: Send + 'static,
+    F: FnOnce() -> R + Send + 'static,
+{
+    if core_id >= MAX_CPU {
+        return Err(CpuCallError::InvalidCpu(core_id));
+    }
+    if ONLINE.load(Ordering::SeqCst) & (1 << core_id) == 0 {
+        return Err(CpuCallError::Offline(core_id));
+    }
+    // The caller is not migrated with the interrupts disabled.
+    let _p = InterruptGuard::new();
+    if cpuid() == core_id {
+        return Ok(f());
+    }
+
+    let result = Arc::new(SpinLock::new(None));
+    let slot = result.clone();
+    QUEUES
+        .get_of(core_id)
+        .unwrap()
+        .lock()
+        .push_back(Box::new(move || *slot.lock() = Some(f())));
+    unsafe {
+        abyss::dev::x86_64::apic::send_ipi(core_id, CPU_CALL_VECTOR as u32);
+    }
+    loop {
+        if let Some(r) = result.lock().take() {
+            return Ok(r);
+        }
+        process_local_queue();
+        core::hint::spin_loop();
+    }
+}
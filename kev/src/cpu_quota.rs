@@ -0,0 +1,112 @@
+//! Cpu quota of the vms.
+//!
+//! A guest that spins, e.g. a runaway test, keeps its vcpus busy and starves
+//! the other threads of the host, including the harness that watches it. A
+//! [`CpuQuota`] limits the cpu time that a vm or a vcpu consumes in each
+//! period, in the manner of the CFS bandwidth control:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 4)?.finalize()?;
+//! // The vm may use one and a half cpus,
+//! vm.set_cpu_quota(CpuQuota::new(150));
+//! // and its vcpu 0 a quarter of a cpu.
+//! vm.set_vcpu_quota(0, Some(CpuQuota::new(25)));
+//! ```
+//!
+//! When a vcpu uses up the quota of the period, it leaves the guest on the
+//! VMX-preemption timer, or on the host timer tick if the timer is not
+//! supported, and sleeps until the next period. The cpu time of a vm is
+//! charged after each run of its vcpus, so the vcpus running at the same time
+//! may overrun the quota of the vm by one run each.
+use abyss::dev::x86_64::timer::tsc_khz;
+use core::time::Duration;
+
+/// Default period of a quota.
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(100);
+
+/// Limit on the cpu time in each period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuQuota {
+    percent: u64,
+    period: Duration,
+}
+
+impl CpuQuota {
+    /// Allow the `percent` of a cpu in each period of [`DEFAULT_PERIOD`].
+    ///
+    /// The quota of a vm may exceed 100 to allow more than a cpu to its vcpus.
+    pub fn new(percent: u64) -> Self {
+        assert!(percent > 0, "Quota must be positive.");
+        Self {
+            percent,
+            period: DEFAULT_PERIOD,
+        }
+    }
+
+    /// Set the period of the quota.
+    pub fn period(mut self, period: Duration) -> Self {
+        assert!(!period.is_zero(), "Period must be positive.");
+        self.period = period;
+        self
+    }
+
+    /// Get the percent of a cpu allowed in each period.
+    pub fn percent(&self) -> u64 {
+        self.percent
+    }
+}
+
+// Accounting of the cpu time against a quota.
+pub(crate) struct Throttle {
+    quota: CpuQuota,
+    // Length of the period and the budget in it, in tsc cycles.
+    period: u64,
+    budget: u64,
+    // Tsc at the start of the current period.
+    start: u64,
+    // Cpu time used in the current period, in tsc cycles.
+    used: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(quota: CpuQuota, now: u64) -> Self {
+        let period = (quota.period.as_nanos() * tsc_khz().max(1) as u128 / 1_000_000) as u64;
+        Self {
+            quota,
+            period: period.max(1),
+            budget: (period as u128 * quota.percent as u128 / 100) as u64,
+            start: now,
+            used: 0,
+        }
+    }
+
+    pub(crate) fn quota(&self) -> CpuQuota {
+        self.quota
+    }
+
+    // Check the budget at the tsc `now`.
+    //
+    // Returns the tsc until which the vcpu may run, or the tsc of the next
+    // period as an error if the budget is used up.
+    pub(crate) fn check(&mut self, now: u64) -> Result<u64, u64> {
+        if now >= self.start + self.period {
+            self.start += (now - self.start) / self.period * self.period;
+            self.used = 0;
+        }
+        if self.used >= self.budget {
+            Err(self.start + self.period)
+        } else {
+            Ok(now + (self.budget - self.used))
+        }
+    }
+
+    // Charge the `cycles` of the cpu time.
+    pub(crate) fn charge(&mut self, cycles: u64) {
+        self.used += cycles;
+    }
+}
+
+// Convert the tsc `cycles` into the duration.
+pub(crate) fn cycles_to_duration(cycles: u64) -> Duration {
+    Duration::from_nanos((cycles as u128 * 1_000_000 / tsc_khz().max(1) as u128) as u64)
+}
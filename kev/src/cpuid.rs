@@ -0,0 +1,110 @@
+//! Hypervisor CPUID leaves.
+//!
+//! The CPUID leaves 0x4000_0000 to 0x4000_00FF are reserved for the
+//! hypervisor. A guest that finds the hypervisor-present bit (CPUID.1:ECX[31])
+//! reads the leaf 0x4000_0000 to identify the hypervisor, and the following
+//! leaves to discover what the hypervisor provides.
+//!
+//! | Leaf          | Result                                                |
+//! |---------------|-------------------------------------------------------|
+//! | 0x4000_0000   | eax: maximum leaf, ebx:ecx:edx: "KeVKeVKeV\0\0\0"     |
+//! | 0x4000_0001   | eax: [`PvFeatures`] of the vm                         |
+//! | 0x4000_0002   | eax:ebx:ecx:edx: uuid of the vm                       |
+//! | 0x4000_0003   | eax:ebx: mask of the present vcpus, ecx: vcpu slots   |
+//!
+//! The guest drivers probe the leaf 0x4000_0001 instead of assuming which
+//! paravirtual devices the vm provides. The bits of the pvclock and the yield
+//! hypercall are at the positions of the KVM features (`KVM_FEATURE_CLOCKSOURCE2`
+//! and `KVM_FEATURE_PV_SCHED_YIELD`), so a kvmclock driver finds them as is.
+use crate::vm::VmOps;
+use core::arch::x86_64::CpuidResult;
+
+/// The leaf that reports the maximum leaf and the signature.
+pub const SIGNATURE_LEAF: u32 = 0x4000_0000;
+/// The leaf that reports the paravirtual features of the vm.
+pub const FEATURES_LEAF: u32 = 0x4000_0001;
+/// The leaf that reports the uuid of the vm.
+pub const UUID_LEAF: u32 = 0x4000_0002;
+/// The leaf that reports the present vcpus, for the [`hotplug`].
+///
+/// [`hotplug`]: crate::hotplug
+pub const TOPOLOGY_LEAF: u32 = keos::hotplug::TOPOLOGY_LEAF;
+/// The signature of KeV.
+pub const SIGNATURE: &[u8; 12] = b"KeVKeVKeV\0\0\0";
+
+/// Bit of the CPUID.1:ECX that indicates the hypervisor presents.
+pub const HYPERVISOR_PRESENT: u32 = 1 << 31;
+
+bitflags::bitflags! {
+    /// Paravirtual features that a vm provides to its guest.
+    pub struct PvFeatures: u32 {
+        /// The pvclock, compatible with the kvmclock.
+        const PVCLOCK = 1 << 3;
+        /// The hypercall to yield the cpu to another vcpu.
+        const YIELD = 1 << 13;
+        /// The asynchronous hypercalls.
+        const ASYNC = keos::async_call::FEATURE_ASYNC;
+        /// The batched hypercalls.
+        const BATCH = keos::hypercall::FEATURE_BATCH;
+        /// The hotplug of the vcpus.
+        const CPU_HOTPLUG = 1 << 27;
+        /// The channel of the memory shared with the host.
+        const SHARED_MEMORY = 1 << 28;
+        /// The paravirtual console.
+        const PV_CONSOLE = 1 << 29;
+        /// The page of the performance metrics.
+        const METRICS = keos::metrics::FEATURE_METRICS;
+    }
+}
+
+#[inline]
+fn u32_of(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Get the result of the hypervisor CPUID `leaf` for the `vm`.
+///
+/// Returns `None` if `leaf` is not a hypervisor leaf, so the caller can
+/// forward it to the host cpu.
+pub fn hypervisor_leaf(leaf: u32, vm: &dyn VmOps) -> Option<CpuidResult> {
+    match leaf {
+        SIGNATURE_LEAF => Some(CpuidResult {
+            eax: TOPOLOGY_LEAF,
+            ebx: u32_of(&SIGNATURE[0..4]),
+            ecx: u32_of(&SIGNATURE[4..8]),
+            edx: u32_of(&SIGNATURE[8..12]),
+        }),
+        FEATURES_LEAF => Some(CpuidResult {
+            eax: vm.pv_features().bits(),
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }),
+        UUID_LEAF => {
+            let uuid = vm.uuid();
+            let b = uuid.as_bytes();
+            Some(CpuidResult {
+                eax: u32_of(&b[0..4]),
+                ebx: u32_of(&b[4..8]),
+                ecx: u32_of(&b[8..12]),
+                edx: u32_of(&b[12..16]),
+            })
+        }
+        TOPOLOGY_LEAF => {
+            let present = vm.present_vcpus();
+            Some(CpuidResult {
+                eax: present as u32,
+                ebx: (present >> 32) as u32,
+                ecx: vm.vcpu_count() as u32,
+                edx: 0,
+            })
+        }
+        0x4000_0000..=0x4000_00ff => Some(CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }),
+        _ => None,
+    }
+}
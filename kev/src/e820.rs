@@ -0,0 +1,190 @@
+//! Guest physical memory map.
+//!
+//! The guest discovers its physical address space from the memory map (the
+//! "E820" map, named after the BIOS service that reports it). The map lists
+//! the usable RAM and the ranges that must not be used as RAM, such as the
+//! MMIO holes of the devices and the ACPI tables.
+//!
+//! The map is built with [`MemoryMapBuilder`]. Ranges added later take
+//! precedence over the overlapping ranges added before, so a device can carve
+//! a hole out of the RAM:
+//!
+//! ```
+//! use kev::e820::MemoryMapBuilder;
+//!
+//! let map = MemoryMapBuilder::new()
+//!     .ram(0, 0x1_4000_0000)
+//!     .mmio(0xc000_0000, 0x4000_0000)
+//!     .finalize();
+//! ```
+//!
+//! The map is exposed to the guest through the memory map of the boot
+//! protocol, and through INT 15h, E820h of the [`bios`].
+//!
+//! [`bios`]: crate::bios
+use crate::vm::Gpa;
+use alloc::vec::Vec;
+
+/// Type of the address range in the E820 memory map.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum E820Type {
+    /// Usable RAM.
+    Ram = 1,
+    /// Reserved. Must not be used by the operating system.
+    Reserved = 2,
+    /// ACPI reclaimable memory.
+    Acpi = 3,
+    /// ACPI NVS memory.
+    Nvs = 4,
+}
+
+/// An address range in the E820 memory map.
+#[derive(Clone, Copy, Debug)]
+pub struct E820Entry {
+    /// Base address of the range.
+    pub base: u64,
+    /// Length of the range in bytes.
+    pub len: u64,
+    /// Type of the range.
+    pub ty: E820Type,
+}
+
+impl E820Entry {
+    /// End address of the range (exclusive).
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.base + self.len
+    }
+
+    /// Serialize into the 20-byte layout of the E820 entry.
+    pub fn to_bytes(self) -> [u8; 20] {
+        let mut b = [0; 20];
+        b[0..8].copy_from_slice(&self.base.to_le_bytes());
+        b[8..16].copy_from_slice(&self.len.to_le_bytes());
+        b[16..20].copy_from_slice(&(self.ty as u32).to_le_bytes());
+        b
+    }
+}
+
+/// Builder of the [`MemoryMap`].
+#[derive(Default)]
+pub struct MemoryMapBuilder {
+    entries: Vec<E820Entry>,
+}
+
+impl MemoryMapBuilder {
+    /// Create a new builder with an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a range of type `ty`.
+    ///
+    /// The range overrides the overlapping part of the ranges added before.
+    pub fn region(mut self, base: u64, len: u64, ty: E820Type) -> Self {
+        let end = base + len;
+        let mut entries = Vec::with_capacity(self.entries.len() + 2);
+        for e in self.entries.into_iter() {
+            if e.end() <= base || end <= e.base {
+                entries.push(e);
+                continue;
+            }
+            if e.base < base {
+                entries.push(E820Entry {
+                    base: e.base,
+                    len: base - e.base,
+                    ty: e.ty,
+                });
+            }
+            if end < e.end() {
+                entries.push(E820Entry {
+                    base: end,
+                    len: e.end() - end,
+                    ty: e.ty,
+                });
+            }
+        }
+        if len != 0 {
+            entries.push(E820Entry { base, len, ty });
+        }
+        self.entries = entries;
+        self
+    }
+
+    /// Add a usable RAM.
+    pub fn ram(self, base: u64, len: u64) -> Self {
+        self.region(base, len, E820Type::Ram)
+    }
+
+    /// Add a hole for the memory-mapped I/O.
+    pub fn mmio(self, base: u64, len: u64) -> Self {
+        self.region(base, len, E820Type::Reserved)
+    }
+
+    /// Add a range that holds the ACPI tables.
+    pub fn acpi(self, base: u64, len: u64) -> Self {
+        self.region(base, len, E820Type::Acpi)
+    }
+
+    /// Add a reserved range.
+    pub fn reserved(self, base: u64, len: u64) -> Self {
+        self.region(base, len, E820Type::Reserved)
+    }
+
+    /// Finalize this builder.
+    ///
+    /// The entries are sorted by address, and the adjacent entries of the same
+    /// type are merged.
+    pub fn finalize(mut self) -> MemoryMap {
+        self.entries.sort_unstable_by_key(|e| e.base);
+        let mut entries: Vec<E820Entry> = Vec::with_capacity(self.entries.len());
+        for e in self.entries.into_iter() {
+            match entries.last_mut() {
+                Some(last) if last.end() == e.base && last.ty == e.ty => last.len += e.len,
+                _ => entries.push(e),
+            }
+        }
+        MemoryMap { entries }
+    }
+}
+
+/// The guest physical memory map.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    entries: Vec<E820Entry>,
+}
+
+impl MemoryMap {
+    /// Get the entries of the map sorted by the address.
+    #[inline]
+    pub fn entries(&self) -> &[E820Entry] {
+        &self.entries
+    }
+
+    /// Iterate over the usable RAM ranges.
+    pub fn ram(&self) -> impl Iterator<Item = &E820Entry> {
+        self.entries.iter().filter(|e| e.ty == E820Type::Ram)
+    }
+
+    /// Get the type of the range that contains `gpa`.
+    pub fn type_of(&self, gpa: Gpa) -> Option<E820Type> {
+        let gpa = unsafe { gpa.into_usize() } as u64;
+        self.entries
+            .iter()
+            .find(|e| e.base <= gpa && gpa < e.end())
+            .map(|e| e.ty)
+    }
+
+    /// Bytes of the usable RAM within `start..end`.
+    pub fn ram_within(&self, start: u64, end: u64) -> u64 {
+        self.ram()
+            .map(|e| e.end().min(end).saturating_sub(e.base.max(start)))
+            .sum()
+    }
+
+    /// Total bytes of the usable RAM.
+    pub fn ram_size(&self) -> u64 {
+        self.ram().map(|e| e.len).sum()
+    }
+}
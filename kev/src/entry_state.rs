@@ -0,0 +1,473 @@
+//! Templates of the guest state on the first vm entry.
+//!
+//! A vcpu enters the guest with the state written in the guest-state area of
+//! the vmcs, which must be consistent as a whole: the access rights of each
+//! segment must match the mode of the cpu, the control registers must have
+//! the fixed bits, and so on. A [`GuestEntryState`] is such a consistent set,
+//! built from a template and then adjusted:
+//!
+//! ```ignore
+//! fn setup_vbsp(&self, generic_state: &mut GenericVCpuState, ..) -> Result<(), Self::Error> {
+//!     GuestEntryState::protected_flat()
+//!         .rip(entry)
+//!         .rsp(0xa0000)
+//!         .write(&generic_state.vmcs)?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The templates are:
+//! - [`GuestEntryState::real_mode`]: the state after the processor reset,
+//!   e.g. to run the [`bios`].
+//! - [`GuestEntryState::protected_flat`]: the 32-bit protected mode without
+//!   paging, with the flat 4GiB segments.
+//! - [`GuestEntryState::long_mode_identity`]: the 64-bit long mode with the
+//!   given page table, e.g. of an identity mapping.
+//!
+//! The real mode and the protected mode without paging require the
+//! [`UNRESTRICTED_GUEST`] control. The entry controls are written when the
+//! vcpu is initialized, after [`VmState::setup_vbsp`], so the
+//! [`VCpuState::entry_ctls`] must include the [`GuestEntryState::entry_ctls`]
+//! of the state: [`IA32E_MODE_GUEST`] in the long mode, and
+//! [`LOAD_IA32_EFER`] to load the EFER.
+//!
+//! A [`GuestCpuState`] is the full register state of a paused vcpu, which is
+//! saved with [`VCpu::get_state`] and restored with [`VCpu::set_state`], e.g.
+//! to move a vcpu to another vm.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 27.3.1 Checks on the Guest State Area.
+//!
+//! [`bios`]: crate::bios
+//! [`VmState::setup_vbsp`]: crate::vm::VmState::setup_vbsp
+//! [`VCpuState::entry_ctls`]: crate::vcpu::VCpuState::entry_ctls
+//! [`VCpu::get_state`]: crate::vcpu::VCpu::get_state
+//! [`VCpu::set_state`]: crate::vcpu::VCpu::set_state
+//! [`UNRESTRICTED_GUEST`]: crate::vm_control::VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST
+//! [`IA32E_MODE_GUEST`]: crate::vm_control::VmcsEntryCtl::IA32E_MODE_GUEST
+//! [`LOAD_IA32_EFER`]: crate::vm_control::VmcsEntryCtl::LOAD_IA32_EFER
+use crate::{
+    vcpu::{Cr0, Cr4, GeneralPurposeRegisters, Rflags},
+    vm::Gpa,
+    vm_control::VmcsEntryCtl,
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+
+// EFER.LME: IA-32e mode enable.
+const EFER_LME: u64 = 1 << 8;
+// EFER.LMA: IA-32e mode active.
+const EFER_LMA: u64 = 1 << 10;
+
+// The selector, base, limit and access rights fields of each segment.
+const SEGMENT_FIELDS: [[Field; 4]; 8] = [
+    [
+        Field::GuestCsSelector,
+        Field::GuestCsBase,
+        Field::GuestCsLimit,
+        Field::GuestCsAccessRights,
+    ],
+    [
+        Field::GuestDsSelector,
+        Field::GuestDsBase,
+        Field::GuestDsLimit,
+        Field::GuestDsAccessRights,
+    ],
+    [
+        Field::GuestEsSelector,
+        Field::GuestEsBase,
+        Field::GuestEsLimit,
+        Field::GuestEsAccessRights,
+    ],
+    [
+        Field::GuestFsSelector,
+        Field::GuestFsBase,
+        Field::GuestFsLimit,
+        Field::GuestFsAccessRights,
+    ],
+    [
+        Field::GuestGsSelector,
+        Field::GuestGsBase,
+        Field::GuestGsLimit,
+        Field::GuestGsAccessRights,
+    ],
+    [
+        Field::GuestSsSelector,
+        Field::GuestSsBase,
+        Field::GuestSsLimit,
+        Field::GuestSsAccessRights,
+    ],
+    [
+        Field::GuestTrSelector,
+        Field::GuestTrBase,
+        Field::GuestTrLimit,
+        Field::GuestTrAccessRights,
+    ],
+    [
+        Field::GuestLdtrSelector,
+        Field::GuestLdtrBase,
+        Field::GuestLdtrLimit,
+        Field::GuestLdtrAccessRights,
+    ],
+];
+
+/// State of a segment register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentState {
+    /// The selector.
+    pub selector: u16,
+    /// The base address.
+    pub base: u64,
+    /// The limit, in bytes.
+    pub limit: u32,
+    /// The access rights in the format of the vmcs.
+    pub access_rights: u32,
+}
+
+impl SegmentState {
+    /// The access rights of an unusable segment.
+    pub const UNUSABLE: u32 = 1 << 16;
+
+    const fn new(selector: u16, base: u64, limit: u32, access_rights: u32) -> Self {
+        Self {
+            selector,
+            base,
+            limit,
+            access_rights,
+        }
+    }
+}
+
+/// State of a descriptor table register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableState {
+    /// The base address.
+    pub base: u64,
+    /// The limit, in bytes.
+    pub limit: u32,
+}
+
+/// A consistent guest state on the vm entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestEntryState {
+    /// Rip.
+    pub rip: u64,
+    /// Rsp.
+    pub rsp: u64,
+    /// Rflags.
+    pub rflags: u64,
+    /// Cs.
+    pub cs: SegmentState,
+    /// Ds.
+    pub ds: SegmentState,
+    /// Es.
+    pub es: SegmentState,
+    /// Fs.
+    pub fs: SegmentState,
+    /// Gs.
+    pub gs: SegmentState,
+    /// Ss.
+    pub ss: SegmentState,
+    /// Task register.
+    pub tr: SegmentState,
+    /// Local descriptor table register.
+    pub ldtr: SegmentState,
+    /// Global descriptor table register.
+    pub gdtr: TableState,
+    /// Interrupt descriptor table register.
+    pub idtr: TableState,
+    /// Cr0.
+    pub cr0: u64,
+    /// Cr3.
+    pub cr3: u64,
+    /// Cr4.
+    pub cr4: u64,
+    /// IA32_EFER.
+    pub efer: u64,
+}
+
+impl GuestEntryState {
+    /// The state after the processor reset, at the reset vector (F000:FFF0).
+    ///
+    /// The base of cs is the alias of the reset vector below 1MiB, where the
+    /// [`bios`](crate::bios) is loaded.
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// 9.1.2 Processor Built-In Self-Test (BIST), Table 9-1.
+    pub const fn real_mode() -> Self {
+        let data = SegmentState::new(0, 0, 0xffff, 0x93);
+        Self {
+            rip: 0xfff0,
+            rsp: 0,
+            rflags: Rflags::_1.bits(),
+            cs: SegmentState::new(0xf000, 0xf0000, 0xffff, 0x9b),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: SegmentState::new(0, 0, 0xffff, 0x8b),
+            ldtr: SegmentState::new(0, 0, 0xffff, 0x82),
+            gdtr: TableState {
+                base: 0,
+                limit: 0xffff,
+            },
+            idtr: TableState {
+                base: 0,
+                limit: 0xffff,
+            },
+            cr0: Cr0::NE.bits(),
+            cr3: 0,
+            cr4: Cr4::VMXE.bits(),
+            efer: 0,
+        }
+    }
+
+    /// The 32-bit protected mode without paging.
+    ///
+    /// Cs is the 4GiB code segment of the selector 0x10, and the others are
+    /// the 4GiB data segment of the selector 0x18, as the gdt of the
+    /// multiboot loaders. The gdt itself is left empty; the guest must load
+    /// its own before reloading a segment.
+    pub const fn protected_flat() -> Self {
+        let data = SegmentState::new(0x18, 0, 0xffff_ffff, 0xc093);
+        Self {
+            rip: 0,
+            rsp: 0,
+            rflags: Rflags::_1.bits(),
+            cs: SegmentState::new(0x10, 0, 0xffff_ffff, 0xc09b),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: SegmentState::new(0, 0, 0, 0x8b),
+            ldtr: SegmentState::new(0, 0, 0, 0x82),
+            gdtr: TableState { base: 0, limit: 0 },
+            idtr: TableState { base: 0, limit: 0 },
+            cr0: Cr0::NE.bits() | Cr0::PE.bits(),
+            cr3: 0,
+            cr4: Cr4::VMXE.bits(),
+            efer: 0,
+        }
+    }
+
+    /// The 64-bit long mode with the 4-level page table at `pgtable`.
+    ///
+    /// Cs is the 64-bit code segment of the selector 0x8, and the others are
+    /// the data segment of the selector 0x10. As [`protected_flat`], the gdt
+    /// is left empty.
+    ///
+    /// [`protected_flat`]: GuestEntryState::protected_flat
+    pub fn long_mode_identity(pgtable: Gpa) -> Self {
+        let data = SegmentState::new(0x10, 0, 0xffff_ffff, 0xc093);
+        Self {
+            rip: 0,
+            rsp: 0,
+            rflags: Rflags::_1.bits(),
+            cs: SegmentState::new(0x8, 0, 0xffff_ffff, 0xa09b),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: SegmentState::new(0, 0, 0x67, 0x8b),
+            ldtr: SegmentState::new(0, 0, 0, SegmentState::UNUSABLE),
+            gdtr: TableState { base: 0, limit: 0 },
+            idtr: TableState { base: 0, limit: 0 },
+            cr0: Cr0::PG.bits() | Cr0::NE.bits() | Cr0::ET.bits() | Cr0::PE.bits(),
+            cr3: unsafe { pgtable.into_usize() as u64 },
+            cr4: Cr4::VMXE.bits() | Cr4::PAE.bits(),
+            efer: EFER_LME | EFER_LMA,
+        }
+    }
+
+    /// Set the rip.
+    pub fn rip(mut self, rip: u64) -> Self {
+        self.rip = rip;
+        self
+    }
+
+    /// Set the rsp.
+    pub fn rsp(mut self, rsp: u64) -> Self {
+        self.rsp = rsp;
+        self
+    }
+
+    // The segments in the order of the `SEGMENT_FIELDS`.
+    fn segments(&self) -> [&SegmentState; 8] {
+        [
+            &self.cs, &self.ds, &self.es, &self.fs, &self.gs, &self.ss, &self.tr, &self.ldtr,
+        ]
+    }
+
+    fn segments_mut(&mut self) -> [&mut SegmentState; 8] {
+        [
+            &mut self.cs,
+            &mut self.ds,
+            &mut self.es,
+            &mut self.fs,
+            &mut self.gs,
+            &mut self.ss,
+            &mut self.tr,
+            &mut self.ldtr,
+        ]
+    }
+
+    /// Check whether the state is in the IA-32e mode.
+    pub fn is_long_mode(&self) -> bool {
+        self.efer & EFER_LMA != 0
+    }
+
+    /// Get the vm-entry controls that the state requires.
+    pub fn entry_ctls(&self) -> VmcsEntryCtl {
+        if self.is_long_mode() {
+            VmcsEntryCtl::LOAD_IA32_EFER | VmcsEntryCtl::IA32E_MODE_GUEST
+        } else {
+            VmcsEntryCtl::LOAD_IA32_EFER
+        }
+    }
+
+    /// Read the state from the guest-state area of the `vmcs`.
+    pub fn read(vmcs: &ActiveVmcs) -> Result<Self, VmError> {
+        let table = |base, limit| -> Result<TableState, VmError> {
+            Ok(TableState {
+                base: vmcs.read(base)?,
+                limit: vmcs.read(limit)? as u32,
+            })
+        };
+        let mut state = Self {
+            rip: vmcs.read(Field::GuestRip)?,
+            rsp: vmcs.read(Field::GuestRsp)?,
+            rflags: vmcs.read(Field::GuestRflags)?,
+            gdtr: table(Field::GuestGdtrBase, Field::GuestGdtrLimit)?,
+            idtr: table(Field::GuestIdtrBase, Field::GuestIdtrLimit)?,
+            cr0: vmcs.read(Field::GuestCr0)?,
+            cr3: vmcs.read(Field::GuestCr3)?,
+            cr4: vmcs.read(Field::GuestCr4)?,
+            efer: vmcs.read(Field::GuestIa32Efer)?,
+            ..Self::real_mode()
+        };
+        for (segment, [selector, base, limit, access_rights]) in
+            state.segments_mut().into_iter().zip(SEGMENT_FIELDS)
+        {
+            *segment = SegmentState {
+                selector: vmcs.read(selector)? as u16,
+                base: vmcs.read(base)?,
+                limit: vmcs.read(limit)? as u32,
+                access_rights: vmcs.read(access_rights)? as u32,
+            };
+        }
+        Ok(state)
+    }
+
+    /// Write the state into the guest-state area of the `vmcs`.
+    pub fn write(&self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        vmcs.write(Field::GuestRip, self.rip)?;
+        vmcs.write(Field::GuestRsp, self.rsp)?;
+        vmcs.write(Field::GuestRflags, self.rflags)?;
+
+        for (segment, [selector, base, limit, access_rights]) in
+            self.segments().into_iter().zip(SEGMENT_FIELDS)
+        {
+            vmcs.write(selector, segment.selector as u64)?;
+            vmcs.write(base, segment.base)?;
+            vmcs.write(limit, segment.limit as u64)?;
+            vmcs.write(access_rights, segment.access_rights as u64)?;
+        }
+        vmcs.write(Field::GuestGdtrBase, self.gdtr.base)?;
+        vmcs.write(Field::GuestGdtrLimit, self.gdtr.limit as u64)?;
+        vmcs.write(Field::GuestIdtrBase, self.idtr.base)?;
+        vmcs.write(Field::GuestIdtrLimit, self.idtr.limit as u64)?;
+
+        vmcs.write(Field::GuestCr0, self.cr0)?;
+        vmcs.write(Field::GuestCr3, self.cr3)?;
+        vmcs.write(Field::GuestCr4, self.cr4)?;
+        vmcs.write(Field::GuestIa32Efer, self.efer)?;
+
+        // Guest non-register state.
+        vmcs.write(Field::GuestActivityState, 0)?;
+        vmcs.write(Field::GuestInterruptibilityState, 0)?;
+        vmcs.write(Field::GuestLinkPointer, 0xffff_ffff)?;
+        vmcs.write(Field::GuestLinkPointerHi, 0xffff_ffff)?;
+        vmcs.write(Field::GuestDr7, 0)?;
+        vmcs.write(Field::GuestIa32Debugctl, 0)?;
+        Ok(())
+    }
+}
+
+/// The msrs of the guest held in the vmcs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestMsrs {
+    /// IA32_SYSENTER_CS.
+    pub sysenter_cs: u64,
+    /// IA32_SYSENTER_ESP.
+    pub sysenter_esp: u64,
+    /// IA32_SYSENTER_EIP.
+    pub sysenter_eip: u64,
+    /// IA32_PAT.
+    pub pat: u64,
+    /// IA32_DEBUGCTL.
+    pub debugctl: u64,
+}
+
+/// The full register state of a paused vcpu.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestCpuState {
+    /// General purpose registers.
+    pub gprs: GeneralPurposeRegisters,
+    /// Rip, rsp, rflags, segments and control registers.
+    pub regs: GuestEntryState,
+    /// Msrs.
+    pub msrs: GuestMsrs,
+    /// Dr7.
+    pub dr7: u64,
+    /// Activity state, e.g. 1 for the hlt.
+    pub activity: u32,
+    /// Interruptibility state, e.g. the blocking by sti.
+    pub interruptibility: u32,
+    /// Pending debug exceptions.
+    pub pending_dbg: u64,
+}
+
+impl GuestCpuState {
+    pub(crate) fn read(vmcs: &ActiveVmcs, gprs: &GeneralPurposeRegisters) -> Result<Self, VmError> {
+        Ok(Self {
+            gprs: *gprs,
+            regs: GuestEntryState::read(vmcs)?,
+            msrs: GuestMsrs {
+                sysenter_cs: vmcs.read(Field::GuestIa32SysenterCsMsr)?,
+                sysenter_esp: vmcs.read(Field::GuestIa32SysenterEspMsr)?,
+                sysenter_eip: vmcs.read(Field::GuestIa32SysenterEipMsr)?,
+                pat: vmcs.read(Field::GuestIa32Pat)?,
+                debugctl: vmcs.read(Field::GuestIa32Debugctl)?,
+            },
+            dr7: vmcs.read(Field::GuestDr7)?,
+            activity: vmcs.read(Field::GuestActivityState)? as u32,
+            interruptibility: vmcs.read(Field::GuestInterruptibilityState)? as u32,
+            pending_dbg: vmcs.read(Field::GuestPendingDbgExceptions)?,
+        })
+    }
+
+    pub(crate) fn write(
+        &self,
+        vmcs: &ActiveVmcs,
+        gprs: &mut GeneralPurposeRegisters,
+    ) -> Result<(), VmError> {
+        *gprs = self.gprs;
+        // This also resets the non-register state, which is written below.
+        self.regs.write(vmcs)?;
+        vmcs.write(Field::GuestIa32SysenterCsMsr, self.msrs.sysenter_cs)?;
+        vmcs.write(Field::GuestIa32SysenterEspMsr, self.msrs.sysenter_esp)?;
+        vmcs.write(Field::GuestIa32SysenterEipMsr, self.msrs.sysenter_eip)?;
+        vmcs.write(Field::GuestIa32Pat, self.msrs.pat)?;
+        vmcs.write(Field::GuestIa32Debugctl, self.msrs.debugctl)?;
+        vmcs.write(Field::GuestDr7, self.dr7)?;
+        vmcs.write(Field::GuestActivityState, self.activity as u64)?;
+        vmcs.write(
+            Field::GuestInterruptibilityState,
+            self.interruptibility as u64,
+        )?;
+        vmcs.write(Field::GuestPendingDbgExceptions, self.pending_dbg)
+    }
+}
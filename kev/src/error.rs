@@ -0,0 +1,228 @@
+//! Errors of the vms.
+//!
+//! A [`VmError`] that surfaces from the vcpu loop is often far from its
+//! cause: a failed vmread deep in a controller looks the same as a failed
+//! vmlaunch. As an error propagates, each layer attaches what it knows with
+//! [`VmError::Context`]: the failing [`Subsystem`], the VMCS field being
+//! accessed, and the guest rip of the exit. The context forms a chain down
+//! to the original error, and the [`Display`](core::fmt::Display) output
+//! prints the whole chain:
+//!
+//! ```text
+//! vcpu at rip 0xffffff0000104a2c: controller kev::hotplug::Controller: vmcs field GuestCsAccessRights: VMX instruction failed (VmreadVmwriteInvalidComponent)
+//! ```
+//!
+//! A controller declines an exit with [`VmError::HandleVmexitFailed`], which
+//! is matched by the chain of the controllers. The context is never attached
+//! to it until the exit leaves the chain, so a declined exit always falls
+//! through to the next controller.
+use crate::vmcs::{ExitReason, Field, InstructionError};
+use alloc::{boxed::Box, string::String};
+use core::fmt;
+
+/// The part of kev that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    /// The VMX instructions, e.g. the vm entry.
+    Vmx,
+    /// Accessing the VMCS.
+    Vmcs,
+    /// The run loop of a vcpu.
+    Vcpu,
+    /// Managing a vm, e.g. starting its vcpus.
+    Vm,
+    /// Decoding or emulating a guest instruction.
+    Decoder,
+    /// The vmexit controller of the name.
+    Controller(&'static str),
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vmx => write!(f, "vmx"),
+            Self::Vmcs => write!(f, "vmcs"),
+            Self::Vcpu => write!(f, "vcpu"),
+            Self::Vm => write!(f, "vm"),
+            Self::Decoder => write!(f, "decoder"),
+            Self::Controller(name) => write!(f, "controller {}", name),
+        }
+    }
+}
+
+/// Possible errorkind for Vm.
+#[derive(Debug)]
+pub enum VmError {
+    /// Vm operation has error.
+    VmxOperationError(InstructionError),
+    /// Failed to handle vmexit.
+    HandleVmexitFailed(ExitReason),
+    /// Controller-private error.
+    ControllerError(Box<dyn fmt::Debug + Send + Sync>),
+    /// Failed to decode instruction.
+    FailedToDecodeInstruction,
+    /// Vcpu related error.
+    VCpuError(Box<dyn fmt::Debug + Send + Sync>),
+    /// The requested feature is not supported on this cpu.
+    Unsupported(String),
+    /// The error `source` with the context where it occurred.
+    Context {
+        /// The failing subsystem.
+        subsystem: Subsystem,
+        /// The VMCS field being accessed, if any.
+        field: Option<Field>,
+        /// The guest rip at the failure, if known.
+        rip: Option<usize>,
+        /// The underlying error.
+        source: Box<VmError>,
+    },
+}
+
+impl VmError {
+    /// Wrap the error with the context of the `subsystem`.
+    pub fn context(self, subsystem: Subsystem) -> Self {
+        Self::Context {
+            subsystem,
+            field: None,
+            rip: None,
+            source: Box::new(self),
+        }
+    }
+
+    /// Attach the VMCS `field` being accessed.
+    ///
+    /// The field is set on the outermost context if it has none; otherwise,
+    /// the error is wrapped with the context of the [`Subsystem::Vmcs`].
+    pub fn with_field(self, field: Field) -> Self {
+        match self {
+            Self::Context {
+                subsystem,
+                field: None,
+                rip,
+                source,
+            } => Self::Context {
+                subsystem,
+                field: Some(field),
+                rip,
+                source,
+            },
+            e => Self::Context {
+                subsystem: Subsystem::Vmcs,
+                field: Some(field),
+                rip: None,
+                source: Box::new(e),
+            },
+        }
+    }
+
+    /// Attach the guest `rip` at the failure.
+    ///
+    /// The rip is set on the outermost context if it has none; otherwise, the
+    /// error is wrapped with the context of the [`Subsystem::Vcpu`].
+    pub fn with_rip(self, rip: usize) -> Self {
+        match self {
+            Self::Context {
+                subsystem,
+                field,
+                rip: None,
+                source,
+            } => Self::Context {
+                subsystem,
+                field,
+                rip: Some(rip),
+                source,
+            },
+            e => Self::Context {
+                subsystem: Subsystem::Vcpu,
+                field: None,
+                rip: Some(rip),
+                source: Box::new(e),
+            },
+        }
+    }
+
+    /// Attribute the error to the vmexit controller of the `name`.
+    ///
+    /// A declined exit and an error that already has the context are left
+    /// as is, so the innermost controller is reported.
+    pub fn in_controller(self, name: &'static str) -> Self {
+        match self {
+            e @ (Self::HandleVmexitFailed(_) | Self::Context { .. }) => e,
+            e => e.context(Subsystem::Controller(name)),
+        }
+    }
+
+    /// Get the underlying error of the context.
+    pub fn source(&self) -> Option<&VmError> {
+        match self {
+            Self::Context { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+
+    /// Get the original error, under all the contexts.
+    pub fn root(&self) -> &VmError {
+        let mut e = self;
+        while let Some(source) = e.source() {
+            e = source;
+        }
+        e
+    }
+
+    /// Get the outermost subsystem in the chain.
+    pub fn subsystem(&self) -> Option<Subsystem> {
+        match self {
+            Self::Context { subsystem, .. } => Some(*subsystem),
+            _ => None,
+        }
+    }
+
+    /// Get the outermost VMCS field in the chain.
+    pub fn field(&self) -> Option<Field> {
+        self.chain().find_map(|e| match e {
+            Self::Context { field, .. } => *field,
+            _ => None,
+        })
+    }
+
+    /// Get the outermost guest rip in the chain.
+    pub fn rip(&self) -> Option<usize> {
+        self.chain().find_map(|e| match e {
+            Self::Context { rip, .. } => *rip,
+            _ => None,
+        })
+    }
+
+    // Iterate the chain, from the outermost.
+    fn chain(&self) -> impl Iterator<Item = &VmError> {
+        core::iter::successors(Some(self), |e| e.source())
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VmxOperationError(e) => write!(f, "VMX instruction failed ({:?})", e),
+            Self::HandleVmexitFailed(reason) => write!(f, "unhandled vmexit {:?}", reason),
+            Self::ControllerError(e) => write!(f, "{:?}", e),
+            Self::FailedToDecodeInstruction => write!(f, "failed to decode the instruction"),
+            Self::VCpuError(e) => write!(f, "{:?}", e),
+            Self::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Self::Context {
+                subsystem,
+                field,
+                rip,
+                source,
+            } => {
+                write!(f, "{}", subsystem)?;
+                if let Some(field) = field {
+                    write!(f, " field {:?}", field)?;
+                }
+                if let Some(rip) = rip {
+                    write!(f, " at rip {:#x}", rip)?;
+                }
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Injection of the exceptions to the guest.
+//!
+//! The vmexit handlers raise an exception on the guest with
+//! [`inject_exception`] instead of writing the vm-entry interruption fields by
+//! themselves:
+//!
+//! ```ignore
+//! // Only the guest kernel issues the hypercall.
+//! if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+//!     return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+//! }
+//! ```
+//!
+//! The exception follows the architectural rules:
+//! - The error code is checked against the vector, and dropped in the real
+//!   mode, where the exceptions push no error code.
+//! - If the exit occurred while delivering an exception, or an exception is
+//!   already injected on this exit, the new one is raised while delivering it;
+//!   it escalates to a double fault, or to a triple fault that shuts the vcpu
+//!   down with [`EXIT_TRIPLE_FAULT`]. See [`kcore::exception`] for the rules.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 27.6 Event Injection.
+use crate::{
+    vcpu::{GenericVCpuState, VmexitResult},
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use alloc::boxed::Box;
+pub use kcore::exception::*;
+
+/// Exit code of the vcpu that triple faults.
+pub const EXIT_TRIPLE_FAULT: i32 = -3;
+
+// Interruption type of the hardware exceptions.
+const HARDWARE_EXCEPTION: u64 = 3;
+
+// Read the exception in the interruption information `info`, of which error
+// code is in the `error_code` field.
+fn exception_of(
+    vmcs: &ActiveVmcs,
+    info: u64,
+    error_code: Field,
+) -> Result<Option<Exception>, VmError> {
+    if info & (1 << 31) == 0 || (info >> 8) & 0x7 != HARDWARE_EXCEPTION {
+        return Ok(None);
+    }
+    let error_code = if info & (1 << 11) != 0 {
+        Some(vmcs.read(error_code)? as u32)
+    } else {
+        None
+    };
+    Ok(Exception::new(info as u8, error_code).ok())
+}
+
+/// Get the exception being delivered to the guest.
+///
+/// It is the exception injected on this exit, or the one whose delivery
+/// caused the exit.
+pub fn delivering(vmcs: &ActiveVmcs) -> Result<Option<Exception>, VmError> {
+    let injected = exception_of(
+        vmcs,
+        vmcs.read(Field::VmentryInterruptionInfo)?,
+        Field::VmentryExceptionErrCode,
+    )?;
+    if injected.is_some() {
+        return Ok(injected);
+    }
+    exception_of(
+        vmcs,
+        vmcs.read(Field::IdtVectoringInfo)?,
+        Field::IdtVectoringErrCode,
+    )
+}
+
+/// Inject the exception `vector` with the `error_code` to the vcpu on the next
+/// vm entry.
+///
+/// Returns the result of the vmexit: the vcpu continues from the rip as is,
+/// or exits with [`EXIT_TRIPLE_FAULT`]. Fails with [`VmError::VCpuError`] if
+/// the exception is invalid.
+pub fn inject_exception(
+    vcpu: &GenericVCpuState,
+    vector: u8,
+    error_code: Option<u32>,
+) -> Result<VmexitResult, VmError> {
+    let vmcs = &vcpu.vmcs;
+    let exception =
+        Exception::new(vector, error_code).map_err(|e| VmError::VCpuError(Box::new(e)))?;
+    match raise(delivering(vmcs)?, exception) {
+        Delivery::Deliver(exception) => {
+            // The exceptions push no error code in the real mode.
+            let protected = vmcs.read(Field::GuestCr0)? & 1 != 0;
+            let error_code = exception.error_code().filter(|_| protected);
+            vmcs.inject_exception(exception.vector(), error_code)?;
+            Ok(VmexitResult::HandledNoAdvance)
+        }
+        Delivery::TripleFault => {
+            vmcs.write(Field::VmentryInterruptionInfo, 0)?;
+            Ok(VmexitResult::Exited(EXIT_TRIPLE_FAULT))
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! History of the recent vmexits.
+//!
+//! Each vcpu keeps the last [`EXIT_HISTORY_LEN`] vmexits in a ring. When the
+//! guest livelocks or storms the vmexits, the pattern of the recent vmexits is
+//! usually the crucial clue. The history is printed when the vcpu fails to
+//! handle a vmexit, and can be queried with [`VCpu::recent_exits`]:
+//!
+//! ```ignore
+//! for exit in vcpu.lock().recent_exits() {
+//!     println!("{}", exit);
+//! }
+//! ```
+//!
+//! [`VCpu::recent_exits`]: crate::vcpu::VCpu::recent_exits
+use crate::vmcs::ExitReason;
+
+/// Number of the vmexits kept in the history.
+pub const EXIT_HISTORY_LEN: usize = 32;
+
+/// A vmexit.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitRecord {
+    /// The exit reason.
+    pub reason: ExitReason,
+    /// The exit qualification.
+    pub qualification: u64,
+    /// The guest rip at the vmexit.
+    pub rip: u64,
+    /// The tsc at the vmexit.
+    pub tsc: u64,
+}
+
+impl core::fmt::Display for ExitRecord {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{:>20}] rip: {:#018x} qual: {:#x} {:?}",
+            self.tsc, self.rip, self.qualification, self.reason
+        )
+    }
+}
+
+/// Ring of the recent vmexits.
+pub struct ExitHistory {
+    records: [Option<ExitRecord>; EXIT_HISTORY_LEN],
+    // Index of the slot for the next record.
+    next: usize,
+}
+
+impl Default for ExitHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExitHistory {
+    /// Create an empty history.
+    pub const fn new() -> Self {
+        Self {
+            records: [None; EXIT_HISTORY_LEN],
+            next: 0,
+        }
+    }
+
+    /// Record a vmexit, evicting the oldest one if the ring is full.
+    #[inline]
+    pub fn push(&mut self, record: ExitRecord) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % EXIT_HISTORY_LEN;
+    }
+
+    /// Iterate over the recorded vmexits, from the oldest to the latest.
+    pub fn iter(&self) -> impl Iterator<Item = &ExitRecord> {
+        let (latest, oldest) = self.records.split_at(self.next);
+        oldest.iter().chain(latest.iter()).flatten()
+    }
+
+    /// Print the recorded vmexits.
+    pub fn dump(&self) {
+        println!("Recent vmexits (oldest first):");
+        for record in self.iter() {
+            println!("  {}", record);
+        }
+    }
+}
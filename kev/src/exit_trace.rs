@@ -0,0 +1,357 @@
+//! Tracing of the vmexits.
+//!
+//! The hypervisor equivalent of `strace`: each vmexit of a traced vm is
+//! logged as a single line, with the decoded exit qualification and the
+//! result of the handler:
+//!
+//! ```text
+//! vcpu=0 rip=0x00000000001002f3 exit=IoInstruction out port=0x3f8 size=1 -> HandledAdvance
+//! vcpu=0 rip=0xffffff0000104a1c exit=EptViolation gpa=0xfee00030 access=w- -> HandledAdvance
+//! vcpu=1 rip=0xffffff00001051e0 exit=Cpuid leaf=0x40000000 sub=0 -> HandledAdvance
+//! ```
+//!
+//! The lines go to the serial or to a buffer of the last
+//! [`ExitTrace::CAPACITY`] lines, and are rate-limited to
+//! [`ExitTrace::set_rate`] lines per second; the lines over the rate are
+//! counted as dropped. The trace is toggled at runtime, by the host:
+//!
+//! ```ignore
+//! vm.exit_trace().set(Some(TraceOutput::Buffer));
+//! // ...
+//! for line in vm.exit_trace().lines() {
+//!     println!("{}", line);
+//! }
+//! ```
+//!
+//! or by the `xtrace` command of the [monitor], which names the vm by its
+//! console (see [`console`]):
+//!
+//! ```text
+//! monitor> xtrace vm1 serial
+//! ```
+//!
+//! [monitor]: keos::panicking
+//! [`console`]: crate::console
+use crate::{
+    console::Console,
+    exit_history::ExitRecord,
+    vcpu::{GeneralPurposeRegisters, VmexitResult},
+    vmcs::{BasicExitReason, EptViolationQualification},
+    VmError,
+};
+use alloc::{
+    collections::VecDeque,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
+use keos::{
+    panicking::{add_monitor_command, MonitorCommand},
+    sync::SpinLock,
+};
+
+// The traces of the vms, by their consoles.
+static TRACES: SpinLock<Vec<(Weak<Console>, Weak<ExitTrace>)>> = SpinLock::new(Vec::new());
+
+/// Where the lines of the trace go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceOutput {
+    /// Print the lines to the serial.
+    Serial = 1,
+    /// Keep the lines in the buffer of the trace.
+    Buffer = 2,
+}
+
+/// Result of a traced vmexit.
+pub(crate) enum Outcome<'a> {
+    /// Served by kev, before reaching the vmexit handler of the vm.
+    Kev,
+    /// Served by the vmexit handler of the vm.
+    Handled(&'a Result<VmexitResult, VmError>),
+}
+
+/// Trace of the vmexits of a vm.
+pub struct ExitTrace {
+    // 0 if the trace is off, the output otherwise.
+    output: AtomicU8,
+    // Maximum number of the lines per second.
+    rate: AtomicU32,
+    // Tsc at the start of the current second, and the lines in it.
+    window: AtomicU64,
+    lines_in_window: AtomicU32,
+    dropped: AtomicU64,
+    buffer: SpinLock<VecDeque<String>>,
+}
+
+impl Default for ExitTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExitTrace {
+    /// Maximum number of the lines kept in the buffer.
+    pub const CAPACITY: usize = 4096;
+    /// The rate limit by default, in lines per second.
+    pub const DEFAULT_RATE: u32 = 1000;
+
+    /// Create a trace that is off.
+    pub fn new() -> Self {
+        Self {
+            output: AtomicU8::new(0),
+            rate: AtomicU32::new(Self::DEFAULT_RATE),
+            window: AtomicU64::new(0),
+            lines_in_window: AtomicU32::new(0),
+            dropped: AtomicU64::new(0),
+            buffer: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Turn on the trace to the `output`, or turn off the trace with `None`.
+    pub fn set(&self, output: Option<TraceOutput>) {
+        self.output
+            .store(output.map_or(0, |o| o as u8), Ordering::SeqCst);
+    }
+
+    /// Get the output of the trace, or `None` if the trace is off.
+    pub fn output(&self) -> Option<TraceOutput> {
+        match self.output.load(Ordering::Relaxed) {
+            1 => Some(TraceOutput::Serial),
+            2 => Some(TraceOutput::Buffer),
+            _ => None,
+        }
+    }
+
+    /// Check whether the trace is on.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.output.load(Ordering::Relaxed) != 0
+    }
+
+    /// Limit the trace to `lines` lines per second.
+    pub fn set_rate(&self, lines: u32) {
+        self.rate.store(lines, Ordering::SeqCst);
+    }
+
+    /// Get the number of the lines dropped by the rate limit.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Get the lines in the buffer, from the oldest.
+    pub fn lines(&self) -> Vec<String> {
+        self.buffer.lock().iter().cloned().collect()
+    }
+
+    /// Discard the lines in the buffer.
+    pub fn clear(&self) {
+        self.buffer.lock().clear();
+    }
+
+    // Check whether a line fits in the rate limit.
+    fn admit(&self) -> bool {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        let second = abyss::dev::x86_64::timer::tsc_khz().max(1) * 1000;
+        let start = self.window.load(Ordering::Relaxed);
+        if now.wrapping_sub(start) >= second
+            && self
+                .window
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.lines_in_window.store(0, Ordering::Relaxed);
+        }
+        if self.lines_in_window.fetch_add(1, Ordering::Relaxed) < self.rate.load(Ordering::Relaxed)
+        {
+            true
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Start the line of a vmexit of the vcpu `id`.
+    ///
+    /// The exit is decoded before it is handled, as the handler updates the
+    /// registers. Returns `None` if the trace is off or over the rate.
+    pub(crate) fn start(
+        &self,
+        id: usize,
+        exit: &ExitRecord,
+        gprs: &GeneralPurposeRegisters,
+    ) -> Option<String> {
+        if !self.is_enabled() || !self.admit() {
+            return None;
+        }
+        let mut line = format!("vcpu={} rip={:#018x} exit=", id, exit.rip);
+        let _ = decode(&mut line, exit, gprs);
+        Some(line)
+    }
+
+    /// Finish the `line` with the `outcome` of the vmexit.
+    pub(crate) fn finish(&self, mut line: String, outcome: Outcome) {
+        let _ = match outcome {
+            Outcome::Kev => write!(line, " -> kev"),
+            Outcome::Handled(Ok(r)) => write!(
+                line,
+                " -> {}",
+                match r {
+                    VmexitResult::Ok => "Ok",
+                    VmexitResult::HandledAdvance => "HandledAdvance",
+                    VmexitResult::HandledNoAdvance => "HandledNoAdvance",
+                    VmexitResult::Redeliver => "Redeliver",
+                    VmexitResult::Exited(_) => "Exited",
+                    VmexitResult::ExtInt(_) => "ExtInt",
+                    VmexitResult::Kicked => "Kicked",
+                    VmexitResult::Throttled => "Throttled",
+                    VmexitResult::Icount => "Icount",
+                }
+            ),
+            Outcome::Handled(Err(e)) => write!(line, " -> error {:?}", e),
+        };
+        match self.output() {
+            Some(TraceOutput::Serial) => println!("{}", line),
+            Some(TraceOutput::Buffer) => {
+                let mut buffer = self.buffer.lock();
+                if buffer.len() == Self::CAPACITY {
+                    buffer.pop_front();
+                }
+                buffer.push_back(line);
+            }
+            // Turned off while the exit is handled.
+            None => (),
+        }
+    }
+}
+
+// Write the exit reason with its decoded qualification.
+//
+// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+// 28.2.1 Basic VM-Exit Information.
+fn decode(
+    line: &mut String,
+    exit: &ExitRecord,
+    gprs: &GeneralPurposeRegisters,
+) -> core::fmt::Result {
+    let q = exit.qualification;
+    match exit.reason.get_basic_reason() {
+        BasicExitReason::IoInstruction => write!(
+            line,
+            "IoInstruction {} port={:#x} size={}{}",
+            if q & (1 << 3) != 0 { "in" } else { "out" },
+            (q >> 16) & 0xffff,
+            (q & 7) + 1,
+            if q & (1 << 4) != 0 { " string" } else { "" },
+        ),
+        BasicExitReason::MovCr => write!(
+            line,
+            "MovCr cr{} {} gpr={}",
+            q & 0xf,
+            ["to", "from", "clts", "lmsw"][((q >> 4) & 3) as usize],
+            (q >> 8) & 0xf,
+        ),
+        BasicExitReason::EptViolation {
+            qualification,
+            fault_addr,
+        } => write!(
+            line,
+            "EptViolation gpa={:#x} access={}{}{}",
+            fault_addr.map_or(0, |gpa| unsafe { gpa.into_usize() }),
+            access(*qualification, EptViolationQualification::BIT0, 'r'),
+            access(*qualification, EptViolationQualification::BIT1, 'w'),
+            access(*qualification, EptViolationQualification::BIT2, 'x'),
+        ),
+        BasicExitReason::Cpuid => write!(
+            line,
+            "Cpuid leaf={:#x} sub={:#x}",
+            gprs.rax as u32, gprs.rcx as u32
+        ),
+        BasicExitReason::Rdmsr => write!(line, "Rdmsr msr={:#x}", gprs.rcx as u32),
+        BasicExitReason::Wrmsr => write!(
+            line,
+            "Wrmsr msr={:#x} value={:#x}",
+            gprs.rcx as u32,
+            (gprs.rdx as u64) << 32 | gprs.rax as u32 as u64
+        ),
+        BasicExitReason::Vmcall => write!(line, "Vmcall nr={:#x}", gprs.rax),
+        BasicExitReason::ExternalInt(Some(info)) => write!(line, "ExternalInt {:?}", info),
+        basic => write!(line, "{:?} qual={:#x}", basic, q),
+    }
+}
+
+fn access(
+    qualification: EptViolationQualification,
+    bit: EptViolationQualification,
+    c: char,
+) -> char {
+    if qualification.contains(bit) {
+        c
+    } else {
+        '-'
+    }
+}
+
+// Register the `trace` of the vm of the `console`.
+pub(crate) fn register(console: &Arc<Console>, trace: &Arc<ExitTrace>) {
+    let mut traces = TRACES.lock();
+    if traces.is_empty() {
+        add_monitor_command(MonitorCommand {
+            name: "xtrace",
+            usage: "xtrace [<vm> off|serial|buffer|dump]",
+            help: "trace the vmexits of a vm",
+            run: monitor_command,
+        });
+    }
+    traces.retain(|(c, t)| c.strong_count() != 0 && t.strong_count() != 0);
+    traces.push((Arc::downgrade(console), Arc::downgrade(trace)));
+}
+
+/// Find the trace of the vm, named by its console.
+pub fn find(name: &str) -> Option<Arc<ExitTrace>> {
+    TRACES.lock().iter().find_map(|(console, trace)| {
+        console
+            .upgrade()
+            .filter(|c| c.name() == name)
+            .and_then(|_| trace.upgrade())
+    })
+}
+
+// The `xtrace` command of the monitor.
+fn monitor_command(args: &[&str]) {
+    match args {
+        [] => {
+            for (console, trace) in TRACES.lock().iter() {
+                if let (Some(console), Some(trace)) = (console.upgrade(), trace.upgrade()) {
+                    println!(
+                        "  {}: {:?}, {} dropped",
+                        console.name(),
+                        trace.output(),
+                        trace.dropped()
+                    );
+                }
+            }
+        }
+        [name, command] => {
+            let Some(trace) = find(name) else {
+                return println!("No vm named `{}`.", name);
+            };
+            match *command {
+                "off" => trace.set(None),
+                "serial" => trace.set(Some(TraceOutput::Serial)),
+                "buffer" => trace.set(Some(TraceOutput::Buffer)),
+                "dump" => {
+                    for line in trace.lines() {
+                        println!("{}", line);
+                    }
+                }
+                _ => println!("Unknown trace command `{}`.", command),
+            }
+        }
+        _ => println!("usage: xtrace [<vm> off|serial|buffer|dump]"),
+    }
+}
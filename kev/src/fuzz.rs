@@ -0,0 +1,505 @@
+//! Fuzzing the vmexit controllers.
+//!
+//! A [`Fuzzer`] feeds random but structurally valid vmexits to a controller
+//! through the mocks of [`testing`](crate::testing): cpuid leaves, rdmsr and
+//! wrmsr of the msr indices, in and out of the io ports with the sizes, and
+//! mmio accesses whose instruction is decoded from the guest memory. The other
+//! registers of the vcpu are filled with random values. The controllers may
+//! fail an exit with an error, but must not panic on any input:
+//!
+//! ```ignore
+//! let fuzzer = Fuzzer::new(0x5eed).payloads(&[Payload::Msr, Payload::Mmio]);
+//! let stats = fuzzer.run(&mut controller, 0..100_000);
+//! println!("{:?}", stats);
+//! ```
+//!
+//! Each case is generated from the seed and its index only, so it is replayed
+//! without the earlier cases. If the controller panics, the case is printed
+//! on the panic, with the line to reproduce it:
+//!
+//! ```text
+//! fuzz: case 4321 of the seed 0x5eed: mmio read gpa=0xfee00020 size=4 insn=8b08
+//! fuzz: reproduce with Fuzzer::new(0x5eed).run_case(&mut controller, 4321)
+//! ```
+use crate::{
+    testing::{MockVcpu, MockVmcs, VecProbe, CPUID, IO_INSTRUCTION, RDMSR, WRMSR},
+    vcpu::{GeneralPurposeRegisters, VmexitResult},
+    vm::Gpa,
+    vmcs::Field,
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use keos::sync::SpinLock;
+
+/// A kind of the vmexits to generate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    /// The cpuid instruction.
+    Cpuid,
+    /// The rdmsr and wrmsr instructions.
+    Msr,
+    /// The in and out instructions, except the string ones.
+    Io,
+    /// The mov instructions to the mmio regions.
+    Mmio,
+}
+
+/// A generated vmexit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// Cpuid of the `leaf` and the `subleaf`.
+    Cpuid {
+        /// The leaf in eax.
+        leaf: u32,
+        /// The subleaf in ecx.
+        subleaf: u32,
+    },
+    /// Rdmsr of the `msr`.
+    Rdmsr {
+        /// The index in ecx.
+        msr: u32,
+    },
+    /// Wrmsr of the `value` to the `msr`.
+    Wrmsr {
+        /// The index in ecx.
+        msr: u32,
+        /// The value in edx:eax.
+        value: u64,
+    },
+    /// In of the `size` bytes from the `port`.
+    In {
+        /// The port in dx.
+        port: u16,
+        /// Size of the access in bytes.
+        size: u8,
+    },
+    /// Out of the `value` of the `size` bytes to the `port`.
+    Out {
+        /// The port in dx.
+        port: u16,
+        /// Size of the access in bytes.
+        size: u8,
+        /// The value in eax.
+        value: u32,
+    },
+    /// An access to the mmio at the `gpa` by the instruction of the index in
+    /// the [`MMIO_INSTRUCTIONS`].
+    Mmio {
+        /// The accessed address.
+        gpa: usize,
+        /// Index of the instruction.
+        instruction: usize,
+    },
+}
+
+/// The mov instructions of the mmio accesses, with whether they write and
+/// their size. The address is in rax.
+pub const MMIO_INSTRUCTIONS: &[(&[u8], bool, u8)] = &[
+    // mov [rax], cl
+    (&[0x88, 0x08], true, 1),
+    // mov [rax], cx
+    (&[0x66, 0x89, 0x08], true, 2),
+    // mov [rax], ecx
+    (&[0x89, 0x08], true, 4),
+    // mov [rax], r8d
+    (&[0x44, 0x89, 0x00], true, 4),
+    // mov [rax], rcx
+    (&[0x48, 0x89, 0x08], true, 8),
+    // mov byte [rax], 0x5a
+    (&[0xc6, 0x00, 0x5a], true, 1),
+    // mov dword [rax], 0x12345678
+    (&[0xc7, 0x00, 0x78, 0x56, 0x34, 0x12], true, 4),
+    // mov cl, [rax]
+    (&[0x8a, 0x08], false, 1),
+    // movzx ecx, word [rax]
+    (&[0x0f, 0xb7, 0x08], false, 2),
+    // mov ecx, [rax]
+    (&[0x8b, 0x08], false, 4),
+    // mov r8, [rax]
+    (&[0x4c, 0x8b, 0x00], false, 8),
+];
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Exit::Cpuid { leaf, subleaf } => {
+                write!(f, "cpuid leaf={:#x} subleaf={:#x}", leaf, subleaf)
+            }
+            Exit::Rdmsr { msr } => write!(f, "rdmsr msr={:#x}", msr),
+            Exit::Wrmsr { msr, value } => write!(f, "wrmsr msr={:#x} value={:#x}", msr, value),
+            Exit::In { port, size } => write!(f, "in port={:#x} size={}", port, size),
+            Exit::Out { port, size, value } => {
+                write!(f, "out port={:#x} size={} value={:#x}", port, size, value)
+            }
+            Exit::Mmio { gpa, instruction } => {
+                let (bytes, write, size) = MMIO_INSTRUCTIONS[instruction];
+                write!(
+                    f,
+                    "mmio {} gpa={:#x} size={} insn=",
+                    if write { "write" } else { "read" },
+                    gpa,
+                    size
+                )?;
+                bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+/// Outcomes of the fuzzed vmexits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FuzzStats {
+    /// Number of the handled exits.
+    pub handled: u64,
+    /// Number of the exits that no controller handles.
+    pub declined: u64,
+    /// Number of the exits that failed with the other errors.
+    pub failed: u64,
+}
+
+// The leaves of the cpuid, the msrs and the ports that the controllers
+// commonly handle.
+const LEAVES: &[u32] = &[
+    0x0,
+    0x1,
+    0x2,
+    0x4,
+    0x6,
+    0x7,
+    0xa,
+    0xb,
+    0xd,
+    0xf,
+    0x10,
+    0x14,
+    0x15,
+    0x16,
+    0x1f,
+    0x4000_0000,
+    0x4000_0001,
+    0x4000_0010,
+    0x8000_0000,
+    0x8000_0001,
+    0x8000_0007,
+    0x8000_0008,
+];
+const MSRS: &[u32] = &[
+    0x10,
+    0x11,
+    0x12,
+    0x1b,
+    0x3a,
+    0xc1,
+    0x174,
+    0x186,
+    0x1a0,
+    0x277,
+    0x309,
+    0x38d,
+    0x38f,
+    0x6e0,
+    0x802,
+    0x808,
+    0x80b,
+    0x830,
+    0x832,
+    0x838,
+    0x83e,
+    0xc000_0080,
+    0xc000_0081,
+    0xc000_0084,
+    0xc000_0100,
+    0xc000_0102,
+    0xc000_0103,
+    0x4b56_4d00,
+    0x4b56_4d01,
+    0x4b56_4d02,
+    0x4b56_4d03,
+    0x4b56_4d04,
+];
+const PORTS: &[u16] = &[
+    0x20,
+    0x21,
+    0x40,
+    0x42,
+    0x43,
+    0x60,
+    0x61,
+    0x64,
+    0x70,
+    0x71,
+    0x80,
+    0x84,
+    0xa0,
+    0xa1,
+    0xf4,
+    0x1f0,
+    0x1f7,
+    0x2f8,
+    0x3f6,
+    0x3f8,
+    0x3f9,
+    0x3fd,
+    crate::shutdown::PM1A_CNT,
+    0xcf8,
+    0xcfc,
+    0xcfe,
+];
+// The io apic and the local apic.
+const APICS: &[Range<usize>] = &[0xfec0_0000..0xfec0_1000, 0xfee0_0000..0xfee0_1000];
+const VALUES: &[u64] = &[0, 1, 0xff, 0xffff_ffff, 1 << 63, u64::MAX];
+
+// A splitmix64 generator.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    // Pick one of the `common` mostly, or near one of them, or any.
+    fn biased(&mut self, common: &[u64], any: u64) -> u64 {
+        match self.below(4) {
+            0 | 1 => self.pick(common),
+            2 => self.pick(common).wrapping_add(self.next() % 16),
+            _ => self.next() & any,
+        }
+    }
+}
+
+// The case being run, printed on a panic.
+static CURRENT: SpinLock<Option<(u64, u64, Exit)>> = SpinLock::new(None);
+static HOOKED: AtomicBool = AtomicBool::new(false);
+
+fn print_current() {
+    if let Ok(Some((seed, index, exit))) = CURRENT.try_lock().map(|current| *current) {
+        println!("fuzz: case {} of the seed {:#x}: {}", index, seed, exit);
+        println!(
+            "fuzz: reproduce with Fuzzer::new({:#x}).run_case(&mut controller, {})",
+            seed, index
+        );
+    }
+}
+
+/// A generator of the random vmexits.
+#[derive(Clone, Debug)]
+pub struct Fuzzer {
+    seed: u64,
+    payloads: Vec<Payload>,
+    mmio: Vec<Range<usize>>,
+}
+
+impl Fuzzer {
+    /// Create a fuzzer of the `seed`, generating all the payloads.
+    ///
+    /// The mmio accesses hit the io apic and the local apic.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            payloads: alloc::vec![Payload::Cpuid, Payload::Msr, Payload::Io, Payload::Mmio],
+            mmio: Vec::new(),
+        }
+    }
+
+    /// Generate only the `payloads`, or all of them if empty.
+    pub fn payloads(mut self, payloads: &[Payload]) -> Self {
+        if !payloads.is_empty() {
+            self.payloads = payloads.to_vec();
+        }
+        self
+    }
+
+    /// Access the mmio region of the `size` bytes at the `start`, instead of
+    /// the apics. Called repeatedly, the regions are added.
+    pub fn mmio(mut self, start: Gpa, size: usize) -> Self {
+        let start = unsafe { start.into_usize() };
+        self.mmio.push(start..start + size.max(1));
+        self
+    }
+
+    /// Generate the vmexit of the case `index`.
+    pub fn exit(&self, index: u64) -> Exit {
+        let mut rng = Rng(self.seed ^ index.wrapping_mul(0xd134_2543_de82_ef95));
+        match rng.pick(&self.payloads) {
+            Payload::Cpuid => {
+                let leaves: Vec<u64> = LEAVES.iter().map(|&l| l as u64).collect();
+                Exit::Cpuid {
+                    leaf: rng.biased(&leaves, u32::MAX as u64) as u32,
+                    subleaf: rng.biased(&[0, 1, 2], u32::MAX as u64) as u32,
+                }
+            }
+            Payload::Msr => {
+                let msrs: Vec<u64> = MSRS.iter().map(|&m| m as u64).collect();
+                let msr = rng.biased(&msrs, u32::MAX as u64) as u32;
+                if rng.below(2) == 0 {
+                    Exit::Rdmsr { msr }
+                } else {
+                    Exit::Wrmsr {
+                        msr,
+                        value: rng.biased(VALUES, u64::MAX),
+                    }
+                }
+            }
+            Payload::Io => {
+                let ports: Vec<u64> = PORTS.iter().map(|&p| p as u64).collect();
+                let port = rng.biased(&ports, u16::MAX as u64) as u16;
+                let size = rng.pick(&[1, 2, 4]);
+                if rng.below(2) == 0 {
+                    Exit::In { port, size }
+                } else {
+                    Exit::Out {
+                        port,
+                        size,
+                        value: rng.biased(VALUES, u32::MAX as u64) as u32,
+                    }
+                }
+            }
+            Payload::Mmio => {
+                let instruction = rng.below(MMIO_INSTRUCTIONS.len());
+                let size = MMIO_INSTRUCTIONS[instruction].2 as usize;
+                let regions = if self.mmio.is_empty() {
+                    APICS
+                } else {
+                    &self.mmio
+                };
+                let region = &regions[rng.below(regions.len())];
+                let mut gpa = region.start + rng.below(region.len());
+                // Mostly aligned to the size of the access.
+                if rng.below(4) != 0 {
+                    gpa &= !(size - 1);
+                }
+                Exit::Mmio { gpa, instruction }
+            }
+        }
+    }
+
+    /// Run the case `index` on the `controller`, and returns its result.
+    pub fn run_case<C: VmexitController>(
+        &self,
+        controller: &mut C,
+        index: u64,
+    ) -> Result<VmexitResult, VmError> {
+        if !HOOKED.swap(true, Ordering::SeqCst) {
+            keos::panicking::add_panic_hook(print_current);
+        }
+        let exit = self.exit(index);
+        let mut rng = Rng(self.seed.wrapping_add(index));
+        let mut vcpu = MockVcpu::new(MockVmcs::new());
+        vcpu.gprs = random_gprs(&mut rng);
+        let mut probe = VecProbe::new(0x1000);
+        vcpu.vmcs = match exit {
+            Exit::Cpuid { leaf, subleaf } => {
+                vcpu.gprs.rax = leaf as usize;
+                vcpu.gprs.rcx = subleaf as usize;
+                MockVmcs::new().exit(CPUID, 2)
+            }
+            Exit::Rdmsr { msr } => {
+                vcpu.gprs.rcx = msr as usize;
+                MockVmcs::new().exit(RDMSR, 2)
+            }
+            Exit::Wrmsr { msr, value } => {
+                vcpu.gprs.rcx = msr as usize;
+                vcpu.gprs.rax = value as u32 as usize;
+                vcpu.gprs.rdx = (value >> 32) as usize;
+                MockVmcs::new().exit(WRMSR, 2)
+            }
+            Exit::In { port, size } => {
+                vcpu.gprs.rdx = port as usize;
+                MockVmcs::new().exit(IO_INSTRUCTION, 1).set(
+                    Field::VmexitQualification,
+                    io_qualification(port, size, true),
+                )
+            }
+            Exit::Out { port, size, value } => {
+                vcpu.gprs.rdx = port as usize;
+                vcpu.gprs.rax = value as usize;
+                MockVmcs::new().exit(IO_INSTRUCTION, 1).set(
+                    Field::VmexitQualification,
+                    io_qualification(port, size, false),
+                )
+            }
+            Exit::Mmio { gpa, instruction } => {
+                let (bytes, write, _) = MMIO_INSTRUCTIONS[instruction];
+                // The instruction is at the rip 0, and the guest runs without
+                // paging.
+                probe.memory_mut()[..bytes.len()].copy_from_slice(bytes);
+                vcpu.gprs.rax = gpa;
+                // Table 27-7. Exit Qualification for EPT Violations: the
+                // access, and the valid linear address of the access.
+                let access = if write { 1 << 1 } else { 1 << 0 };
+                MockVmcs::new()
+                    .ept_violation(Gpa::new(gpa).unwrap(), access | (1 << 7) | (1 << 8))
+                    .set(Field::VmexitInstructionLength, bytes.len() as u64)
+                    .set(Field::GuestLinearAddr, gpa as u64)
+            }
+        };
+        *CURRENT.lock() = Some((self.seed, index, exit));
+        let result = vcpu
+            .vmcs
+            .activate()
+            .exit_reason()
+            .and_then(|reason| controller.handle(reason, &mut probe, &mut vcpu.state()));
+        *CURRENT.lock() = None;
+        result
+    }
+
+    /// Run the cases of the `indices` on the `controller`.
+    pub fn run<C: VmexitController>(&self, controller: &mut C, indices: Range<u64>) -> FuzzStats {
+        let mut stats = FuzzStats::default();
+        for index in indices {
+            match self.run_case(controller, index) {
+                Ok(_) => stats.handled += 1,
+                Err(VmError::HandleVmexitFailed(_)) => stats.declined += 1,
+                Err(_) => stats.failed += 1,
+            }
+        }
+        stats
+    }
+}
+
+// Table 28-5. Exit Qualification for I/O Instructions.
+fn io_qualification(port: u16, size: u8, input: bool) -> u64 {
+    ((port as u64) << 16) | ((input as u64) << 3) | (size as u64 - 1)
+}
+
+fn random_gprs(rng: &mut Rng) -> GeneralPurposeRegisters {
+    let mut gprs = GeneralPurposeRegisters::default();
+    for reg in [
+        &mut gprs.rax,
+        &mut gprs.rbx,
+        &mut gprs.rcx,
+        &mut gprs.rdx,
+        &mut gprs.rsi,
+        &mut gprs.rdi,
+        &mut gprs.rbp,
+        &mut gprs.r8,
+        &mut gprs.r9,
+        &mut gprs.r10,
+        &mut gprs.r11,
+        &mut gprs.r12,
+        &mut gprs.r13,
+        &mut gprs.r14,
+        &mut gprs.r15,
+    ] {
+        *reg = rng.biased(VALUES, u64::MAX) as usize;
+    }
+    gprs
+}
@@ -0,0 +1,221 @@
+//! Copies between the host and the guest memory.
+//!
+//! The host reaches a guest page through the host mapping of the host
+//! physical page that backs it. A bad EPT entry, e.g. from a bug in a pager,
+//! may back a guest page with a page of the host kernel, and a plain
+//! dereference would then write the kernel on behalf of the guest. The
+//! routines of this module check that each host page is allocated for the
+//! guest before touching it:
+//!
+//! ```ignore
+//! let mut header = [0; 16];
+//! guest_copy::read_gpa(p, vmcs, gpa, &mut header)?;
+//! guest_copy::write_gva(p, vmcs, gva, Access::at_cpl(vmcs, Access::WRITE)?, &reply)?;
+//! ```
+//!
+//! The host pages are accessed with the RFLAGS.AC set if the host enables
+//! the SMAP, so that the copies keep working if the guest memory is ever
+//! mapped as the user pages. Every copy goes through a single function,
+//! which is the place to hook the future hardening, e.g. the encryption of
+//! the guest RAM.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 4.6.1 Determination of Access Rights.
+use crate::{
+    page_walk::{Access, WalkError},
+    probe::Probe,
+    vm::{Gpa, Gva, VmOps},
+    vmcs::ActiveVmcs,
+    VmError,
+};
+use abyss::{
+    addressing::{Pa, PAGE_MASK, PAGE_SIZE},
+    x86_64::Cr4,
+};
+use alloc::boxed::Box;
+use core::arch::asm;
+
+/// Error of a copy.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CopyError {
+    /// Failed to translate the guest address.
+    Walk(WalkError),
+    /// The guest page is backed by a host page that is not allocated for the
+    /// guest.
+    HostPage(Pa),
+}
+
+impl From<WalkError> for CopyError {
+    fn from(e: WalkError) -> Self {
+        Self::Walk(e)
+    }
+}
+
+impl From<CopyError> for VmError {
+    fn from(e: CopyError) -> Self {
+        match e {
+            CopyError::Walk(e) => e.into(),
+            e => VmError::ControllerError(Box::new(e)),
+        }
+    }
+}
+
+// Allows the supervisor accesses to the user pages while alive.
+struct AcGuard(bool);
+
+impl AcGuard {
+    fn new() -> Self {
+        let smap = Cr4::current().contains(Cr4::SMAP);
+        if smap {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        Self(smap)
+    }
+}
+
+impl Drop for AcGuard {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+// Copy between the host buffer and the guest memory at `pa`, within a page.
+//
+// Writes the guest memory if `write`.
+fn copy(pa: Pa, buf: *mut u8, len: usize, write: bool) -> Result<(), CopyError> {
+    let page = Pa::new(unsafe { pa.into_usize() } & !PAGE_MASK).unwrap();
+    if !keos::mm::is_allocated(page) {
+        return Err(CopyError::HostPage(pa));
+    }
+    let hva = unsafe { pa.into_va().into_usize() } as *mut u8;
+    let _ac = AcGuard::new();
+    unsafe {
+        if write {
+            core::ptr::copy_nonoverlapping(buf, hva, len);
+        } else {
+            core::ptr::copy_nonoverlapping(hva, buf, len);
+        }
+    }
+    Ok(())
+}
+
+// Copy the `len` bytes at the guest address `addr`, page by page, with the
+// host page of each guest page from `translate`.
+fn copy_pages(
+    addr: usize,
+    buf: *mut u8,
+    len: usize,
+    write: bool,
+    mut translate: impl FnMut(usize) -> Result<Pa, WalkError>,
+) -> Result<(), CopyError> {
+    let mut ofs = 0;
+    while ofs < len {
+        let at = addr.checked_add(ofs).ok_or(WalkError::NonCanonical)?;
+        let chunk = (PAGE_SIZE - (at & PAGE_MASK)).min(len - ofs);
+        copy(translate(at)?, unsafe { buf.add(ofs) }, chunk, write)?;
+        ofs += chunk;
+    }
+    Ok(())
+}
+
+fn gpa2hpa<P: Probe + ?Sized>(p: &P, vmcs: &ActiveVmcs, at: usize) -> Result<Pa, WalkError> {
+    let gpa = Gpa::new(at).ok_or(WalkError::NonCanonical)?;
+    p.gpa2hpa(vmcs, gpa).ok_or(WalkError::Unmapped(gpa))
+}
+
+fn gva2hpa<P: Probe + ?Sized>(
+    p: &P,
+    vmcs: &ActiveVmcs,
+    at: usize,
+    access: Access,
+) -> Result<Pa, WalkError> {
+    p.translate(vmcs, Gva::new(at).ok_or(WalkError::NonCanonical)?, access)
+}
+
+/// Read the guest memory at `gpa` into `buf`.
+pub fn read_gpa<P: Probe + ?Sized>(
+    p: &P,
+    vmcs: &ActiveVmcs,
+    gpa: Gpa,
+    buf: &mut [u8],
+) -> Result<(), CopyError> {
+    copy_pages(
+        unsafe { gpa.into_usize() },
+        buf.as_mut_ptr(),
+        buf.len(),
+        false,
+        |at| gpa2hpa(p, vmcs, at),
+    )
+}
+
+/// Write `buf` into the guest memory at `gpa`.
+pub fn write_gpa<P: Probe + ?Sized>(
+    p: &P,
+    vmcs: &ActiveVmcs,
+    gpa: Gpa,
+    buf: &[u8],
+) -> Result<(), CopyError> {
+    copy_pages(
+        unsafe { gpa.into_usize() },
+        buf.as_ptr() as *mut u8,
+        buf.len(),
+        true,
+        |at| gpa2hpa(p, vmcs, at),
+    )
+}
+
+/// Read the guest memory at `gva` into `buf`, checking the permissions of
+/// the `access` as the cpu does.
+pub fn read_gva<P: Probe + ?Sized>(
+    p: &P,
+    vmcs: &ActiveVmcs,
+    gva: Gva,
+    access: Access,
+    buf: &mut [u8],
+) -> Result<(), CopyError> {
+    copy_pages(
+        unsafe { gva.into_usize() },
+        buf.as_mut_ptr(),
+        buf.len(),
+        false,
+        |at| gva2hpa(p, vmcs, at, access),
+    )
+}
+
+/// Write `buf` into the guest memory at `gva`, checking the permissions of
+/// the `access` as the cpu does.
+///
+/// The `access` should include [`Access::WRITE`].
+pub fn write_gva<P: Probe + ?Sized>(
+    p: &P,
+    vmcs: &ActiveVmcs,
+    gva: Gva,
+    access: Access,
+    buf: &[u8],
+) -> Result<(), CopyError> {
+    copy_pages(
+        unsafe { gva.into_usize() },
+        buf.as_ptr() as *mut u8,
+        buf.len(),
+        true,
+        |at| gva2hpa(p, vmcs, at, access),
+    )
+}
+
+/// Write `buf` into the guest memory of the `vm` at `gpa`, from a thread that
+/// does not run a vcpu of the vm, e.g. a worker of the
+/// [`async_call`](crate::async_call).
+pub fn write_vm(vm: &dyn VmOps, gpa: Gpa, buf: &[u8]) -> Result<(), CopyError> {
+    copy_pages(
+        unsafe { gpa.into_usize() },
+        buf.as_ptr() as *mut u8,
+        buf.len(),
+        true,
+        |at| {
+            let gpa = Gpa::new(at).ok_or(WalkError::NonCanonical)?;
+            vm.gpa2hpa(gpa).ok_or(WalkError::Unmapped(gpa))
+        },
+    )
+}
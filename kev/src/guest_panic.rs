@@ -0,0 +1,236 @@
+//! Crash reports of the guest.
+//!
+//! A guest keos reports its panic with the [`HC_GUEST_PANIC`] hypercall
+//! before it follows its panic policy; see [`keos::panicking`] for the
+//! protocol. The host records the panic into a [`CrashReport`] that is shared
+//! by the vcpus of the vm, with the frames of the backtrace resolved by a
+//! [`Symbolizer`], e.g. the symbols of the guest kernel image:
+//!
+//! ```ignore
+//! let report = CrashReport::new();
+//! let controller = guest_panic::Controller::new(report.clone())
+//!     .symbolizer(Arc::new(GuestSymbols::load("gKeOS")?));
+//! // ... after the vm exits,
+//! if let Some(panic) = report.first() {
+//!     println!("{}", panic);
+//! }
+//! ```
+//!
+//! A failed report returns the negated errno in rax: `EFAULT` (14) if a
+//! buffer is not mapped to the guest, and `EINVAL` (22) if a buffer is too
+//! large.
+use crate::{
+    event,
+    guest_slice::GuestSlice,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use keos::sync::SpinLock;
+
+pub use keos::panicking::{FRAMES_MAX, HC_GUEST_PANIC, MESSAGE_MAX};
+
+// Bad address.
+const EFAULT: usize = 14;
+// Invalid argument.
+const EINVAL: usize = 22;
+
+/// Resolves the guest addresses to the symbols.
+pub trait Symbolizer: Send + Sync {
+    /// Get the name of the symbol that contains the `addr`, and the offset of
+    /// the `addr` from the symbol.
+    fn symbolize(&self, addr: usize) -> Option<(String, usize)>;
+}
+
+/// A frame of the guest backtrace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The pc of the frame.
+    pub pc: u64,
+    /// The symbol that contains the pc and the offset from it, if resolved.
+    pub symbol: Option<(String, usize)>,
+}
+
+impl core::fmt::Display for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.symbol {
+            Some((name, ofs)) => write!(f, "0x{:016x}  - {}+{:#x}", self.pc, name, ofs),
+            None => write!(f, "0x{:016x}  - ?", self.pc),
+        }
+    }
+}
+
+/// A panic of the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuestPanic {
+    /// The vcpu that panicked.
+    pub vcpu: usize,
+    /// The panic message.
+    pub message: String,
+    /// The pc where the panic is raised.
+    pub rip: Frame,
+    /// The backtrace, from the innermost frame.
+    pub backtrace: Vec<Frame>,
+}
+
+impl core::fmt::Display for GuestPanic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Guest panic on vcpu {} at {}", self.vcpu, self.rip)?;
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "Stack Backtrace:")?;
+        for (depth, frame) in self.backtrace.iter().enumerate() {
+            writeln!(f, "  {:2}: {}", depth + 1, frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// Crash report of a vm.
+///
+/// The report is cheaply cloned and shared by the vcpus. It keeps the panics
+/// in the order they are reported; the vcpus that panic after the first one
+/// usually panic because of it.
+#[derive(Clone, Default)]
+pub struct CrashReport {
+    panics: Arc<SpinLock<Vec<GuestPanic>>>,
+}
+
+impl CrashReport {
+    /// Create an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a panic.
+    pub fn push(&self, panic: GuestPanic) {
+        self.panics.lock().push(panic);
+    }
+
+    /// Get the reported panics, from the first one.
+    pub fn panics(&self) -> Vec<GuestPanic> {
+        self.panics.lock().clone()
+    }
+
+    /// Get the first reported panic.
+    pub fn first(&self) -> Option<GuestPanic> {
+        self.panics.lock().first().cloned()
+    }
+
+    /// Check whether the guest never panicked.
+    pub fn is_empty(&self) -> bool {
+        self.panics.lock().is_empty()
+    }
+
+    /// Print the reported panics.
+    pub fn dump(&self) {
+        for panic in self.panics() {
+            println!("{}", panic);
+        }
+    }
+}
+
+/// Vmexit controller of the [`HC_GUEST_PANIC`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+pub struct Controller {
+    report: CrashReport,
+    symbolizer: Option<Arc<dyn Symbolizer>>,
+}
+
+impl Controller {
+    /// Create a new controller that records into the `report`.
+    pub fn new(report: CrashReport) -> Self {
+        Self {
+            report,
+            symbolizer: None,
+        }
+    }
+
+    /// Resolve the frames of the panics with the `symbolizer`.
+    pub fn symbolizer(mut self, symbolizer: Arc<dyn Symbolizer>) -> Self {
+        self.symbolizer = Some(symbolizer);
+        self
+    }
+
+    // Map the guest buffer of `len` bytes at `gpa`.
+    fn buffer<'a>(
+        p: &'a dyn Probe,
+        vmcs: &ActiveVmcs,
+        gpa: usize,
+        len: usize,
+    ) -> Result<GuestSlice<'a>, usize> {
+        let gpa = Gpa::new(gpa).ok_or(EFAULT)?;
+        GuestSlice::new(p, vmcs, [(gpa, len)]).ok_or(EFAULT)
+    }
+
+    fn frame(&self, pc: u64) -> Frame {
+        Frame {
+            pc,
+            symbol: self
+                .symbolizer
+                .as_ref()
+                .and_then(|s| s.symbolize(pc as usize)),
+        }
+    }
+
+    fn record(&self, p: &dyn Probe, generic_vcpu_state: &GenericVCpuState) -> Result<(), usize> {
+        let vmcs = &generic_vcpu_state.vmcs;
+        let gprs = &generic_vcpu_state.gprs;
+        if gprs.rsi > MESSAGE_MAX || gprs.r8 > FRAMES_MAX {
+            return Err(EINVAL);
+        }
+        let mut message = Vec::with_capacity(gprs.rsi);
+        Self::buffer(p, vmcs, gprs.rdi, gprs.rsi)?.drain_with(|_, seg| {
+            message.extend_from_slice(seg);
+            Ok::<_, usize>(seg.len())
+        })?;
+        let mut pcs = Vec::with_capacity(gprs.r8 * 8);
+        Self::buffer(p, vmcs, gprs.rcx, gprs.r8 * 8)?.drain_with(|_, seg| {
+            pcs.extend_from_slice(seg);
+            Ok::<_, usize>(seg.len())
+        })?;
+        self.report.push(GuestPanic {
+            vcpu: generic_vcpu_state.id(),
+            message: String::from_utf8_lossy(&message).to_string(),
+            rip: self.frame(gprs.rdx as u64),
+            backtrace: pcs
+                .chunks_exact(8)
+                .map(|pc| self.frame(u64::from_le_bytes(pc.try_into().unwrap())))
+                .collect(),
+        });
+        Ok(())
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_GUEST_PANIC => {
+                // Only the guest kernel reports its panic.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                generic_vcpu_state.gprs.rax = match self.record(p, generic_vcpu_state) {
+                    Ok(()) => 0,
+                    Err(errno) => -(errno as isize) as usize,
+                };
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Zero-copy access to the guest buffers.
+//!
+//! A device backend usually receives a scatter-gather list of the guest
+//! physical buffers. [`GuestSlice`] maps the list as the host slices, so the
+//! backend reads and writes the guest pages directly instead of copying
+//! through an intermediate buffer:
+//!
+//! ```ignore
+//! let mut buf = GuestSlice::new(p, vmcs, [(entry.addr, entry.size)])?;
+//! // Read the sectors straight into the guest pages.
+//! buf.fill_with(|ofs, seg| file.read(sector_ofs + ofs, seg))?;
+//! ```
+//!
+//! The slices borrow the [`Probe`], so the guest mappings can not change
+//! while a request holds them.
+use crate::{probe::Probe, vm::Gpa, vmcs::ActiveVmcs};
+use abyss::addressing::{Pa, PAGE_MASK, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+/// A guest scatter-gather list mapped as the host slices.
+pub struct GuestSlice<'a> {
+    // Host virtual address and length of each segment.
+    segments: Vec<(usize, usize)>,
+    len: usize,
+    _p: PhantomData<&'a dyn Probe>,
+}
+
+impl<'a> GuestSlice<'a> {
+    /// Map the guest buffers of the (`gpa`, `len`) pairs.
+    ///
+    /// The pages that are contiguous on the host are merged into a single
+    /// segment. Returns `None` if any page of the buffers is not mapped, or
+    /// is backed by a host page that is not allocated for the guest.
+    pub fn new(
+        p: &'a dyn Probe,
+        vmcs: &ActiveVmcs,
+        buffers: impl IntoIterator<Item = (Gpa, usize)>,
+    ) -> Option<Self> {
+        let mut segments: Vec<(usize, usize)> = Vec::new();
+        let mut total = 0;
+        for (gpa, len) in buffers {
+            let base = unsafe { gpa.into_usize() };
+            let mut done = 0;
+            while done < len {
+                let at = base.checked_add(done)?;
+                let size = (len - done).min(PAGE_SIZE - (at & PAGE_MASK));
+                let hpa = p.gpa2hpa(vmcs, Gpa::new(at)?)?;
+                // The page must be allocated for the guest; see `guest_copy`.
+                if !keos::mm::is_allocated(Pa::new(unsafe { hpa.into_usize() } & !PAGE_MASK)?) {
+                    return None;
+                }
+                let hva = unsafe { hpa.into_va().into_usize() };
+                match segments.last_mut() {
+                    Some((last, last_len)) if *last + *last_len == hva => *last_len += size,
+                    _ => segments.push((hva, size)),
+                }
+                done += size;
+            }
+            total += len;
+        }
+        Some(Self {
+            segments,
+            len: total,
+            _p: PhantomData,
+        })
+    }
+
+    /// Total length of the buffers in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the buffers are empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the host slices of the buffers.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments
+            .iter()
+            .map(|(va, len)| unsafe { core::slice::from_raw_parts(*va as *const u8, *len) })
+    }
+
+    /// Iterate over the mutable host slices of the buffers.
+    pub fn segments_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        self.segments
+            .iter()
+            .map(|(va, len)| unsafe { core::slice::from_raw_parts_mut(*va as *mut u8, *len) })
+    }
+
+    /// Fill the buffers with `f`, which writes into a segment at the offset of
+    /// the buffers and returns the number of the written bytes.
+    ///
+    /// Stops at the first segment that is not fully written. Returns the
+    /// number of the written bytes.
+    pub fn fill_with<E>(
+        &mut self,
+        mut f: impl FnMut(usize, &mut [u8]) -> Result<usize, E>,
+    ) -> Result<usize, E> {
+        let mut ofs = 0;
+        for seg in self.segments_mut() {
+            let len = seg.len();
+            let written = f(ofs, seg)?;
+            ofs += written;
+            if written != len {
+                break;
+            }
+        }
+        Ok(ofs)
+    }
+
+    /// Drain the buffers with `f`, which consumes a segment at the offset of
+    /// the buffers and returns the number of the consumed bytes.
+    ///
+    /// Stops at the first segment that is not fully consumed. Returns the
+    /// number of the consumed bytes.
+    pub fn drain_with<E>(
+        &self,
+        mut f: impl FnMut(usize, &[u8]) -> Result<usize, E>,
+    ) -> Result<usize, E> {
+        let mut ofs = 0;
+        for seg in self.segments() {
+            let consumed = f(ofs, seg)?;
+            ofs += consumed;
+            if consumed != seg.len() {
+                break;
+            }
+        }
+        Ok(ofs)
+    }
+}
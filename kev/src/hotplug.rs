@@ -0,0 +1,134 @@
+//! Cpu hotplug of the guest.
+//!
+//! A vm with the hotplug has more vcpu slots than the vcpus present at the
+//! boot. The host adds a vcpu to the running vm with
+//! [`VmHandle::plug_vcpu`], and removes one with [`VmHandle::unplug_vcpu`]:
+//!
+//! ```ignore
+//! // 8 slots, of which 2 are present at the boot.
+//! let vm = VmBuilder::new(state, 8)?.hotplug(2, HOTPLUG_VECTOR).finalize()?;
+//! vm.start_bsp()?;
+//! let id = vm.plug_vcpu()?;
+//! // ...
+//! vm.unplug_vcpu(id)?;
+//! ```
+//!
+//! kev does not build the ACPI tables, so the present vcpus are reported by
+//! the CPUID leaf [`TOPOLOGY_LEAF`] instead of the MADT. Each change is
+//! queued as a [`HotplugEvent`], and the bsp is notified with the interrupt
+//! of the vector. The guest takes the events with the [`HC_HOTPLUG`]
+//! hypercall; see [`keos::hotplug`] for the protocol.
+//!
+//! An added vcpu is started by the guest with the INIT-SIPI sequence, from
+//! the state set by [`VmState::setup_ap`]. A vcpu is removed only after it
+//! quiesces: the vcpu leaves the guest when it issues [`OP_OFFLINE`], and its
+//! slot can be plugged again. A vcpu that is not running is removed at once.
+//!
+//! [`VmHandle::plug_vcpu`]: crate::vm::VmHandle::plug_vcpu
+//! [`VmHandle::unplug_vcpu`]: crate::vm::VmHandle::unplug_vcpu
+//! [`TOPOLOGY_LEAF`]: crate::cpuid::TOPOLOGY_LEAF
+//! [`VmState::setup_ap`]: crate::vm::VmState::setup_ap
+use crate::{
+    entry_state::GuestCpuState,
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+pub use keos::hotplug::{
+    HotplugEvent, EVENT_ADDED, EVENT_NONE, EVENT_REMOVE, HC_HOTPLUG, OP_EVENT, OP_OFFLINE,
+};
+
+/// Maximum number of the vcpu slots of a vm with the hotplug.
+pub const MAX_VCPUS: usize = 64;
+
+// Invalid argument.
+const EINVAL: usize = 22;
+
+/// Error of the hotplug.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HotplugError {
+    /// The vm does not support the hotplug.
+    Unsupported,
+    /// All the vcpu slots are present.
+    NoSlot,
+    /// The vcpu is not present, or is being removed.
+    NotPresent(usize),
+    /// The vcpu can not be removed, e.g. the bsp.
+    Busy(usize),
+}
+
+// Hotplug state of a vm.
+pub(crate) struct Hotplug {
+    // Vector of the interrupt that notifies the events.
+    pub(crate) vector: u8,
+    pub(crate) events: VecDeque<HotplugEvent>,
+    // Bitmask of the vcpus being removed.
+    pub(crate) removing: u64,
+    // State of each vcpu at the reset.
+    pub(crate) reset: Vec<GuestCpuState>,
+}
+
+impl Hotplug {
+    pub(crate) fn new(vector: u8) -> Self {
+        Self {
+            vector,
+            events: VecDeque::new(),
+            removing: 0,
+            reset: Vec::new(),
+        }
+    }
+}
+
+/// Controller for the hotplug hypercall.
+#[derive(Default)]
+pub struct Controller;
+
+impl Controller {
+    /// Create a new hotplug controller.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_HOTPLUG => {
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let vm = generic_vcpu_state
+                    .vm
+                    .upgrade()
+                    .ok_or(VmError::VCpuError(Box::new("Vm is dropped.")))?;
+                let id = generic_vcpu_state.id();
+                let gprs = &mut generic_vcpu_state.gprs;
+                match gprs.rdi {
+                    OP_EVENT => {
+                        let (kind, id) = vm
+                            .hotplug_event()
+                            .map_or((EVENT_NONE, 0), HotplugEvent::into_raw);
+                        gprs.rax = kind;
+                        gprs.rdx = id;
+                    }
+                    OP_OFFLINE if vm.offline_vcpu(id) => {
+                        return Ok(VmexitResult::Exited(0));
+                    }
+                    _ => gprs.rax = -(EINVAL as isize) as usize,
+                }
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Instruction-count breakpoints.
+//!
+//! [`VCpu::run_until_icount`] runs a vcpu until it retires the given number
+//! of instructions, and stops it right before the next instruction. This is a
+//! building block of the deterministic replay, and of the debugging that
+//! stops the guest just before a bug:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?.finalize()?;
+//! let vcpu = vm.vcpu(0).unwrap();
+//! // The bug hits at the 1,000,000th instruction.
+//! vcpu.lock().run_until_icount(999_999)?;
+//! let state = vcpu.lock().get_state()?;
+//! ```
+//!
+//! The instructions are counted by a general-purpose counter of the PMU, that
+//! counts only in the guest and interrupts on the overflow with the
+//! [`PMI_VECTOR`]; the interrupt forces a vmexit. The interrupt is taken some
+//! instructions after the overflow, so the counter overflows [`SKID`]
+//! instructions before the target, and the rest are single-stepped with the
+//! monitor trap flag (MTF) to stop exactly at the count.
+//!
+//! The instructions emulated on the vmexits, i.e. those that move the rip,
+//! count as retired. An event injected on the vm entry is delivered without
+//! retiring an instruction.
+//!
+//! While the count runs, the last general-purpose counter is taken from the
+//! virtual PMU of the guest, so the processor must support the architectural
+//! performance monitoring version 2 and switch IA32_PERF_GLOBAL_CTRL on the
+//! vm entries and exits.
+//!
+//! [`VCpu::run_until_icount`]: crate::vcpu::VCpu::run_until_icount
+use crate::{
+    pmu,
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Interrupt vector of the counter overflow.
+pub const PMI_VECTOR: usize = 104;
+
+/// Number of the instructions before the target at which the counter
+/// overflows, and from which the instructions are single-stepped.
+pub const SKID: u64 = 256;
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Instruction count of a vcpu.
+pub(crate) struct Icount {
+    // Instructions to retire until the stop.
+    remaining: u64,
+    // The general-purpose counter.
+    counter: usize,
+    // The value of the counter started on the vm entry, or `None` if the vcpu
+    // is stepped.
+    start: Option<u64>,
+    // Whether the vm entry injects an event.
+    injected: bool,
+    // The rip at the last vmexit.
+    exit_rip: Option<u64>,
+}
+
+impl Icount {
+    /// Count the `n` instructions on the general-purpose `counter`.
+    pub(crate) fn new(n: u64, counter: usize) -> Self {
+        if !REGISTERED.swap(true, Ordering::SeqCst) {
+            // The interrupt only forces the vmexit.
+            keos::interrupt::register(PMI_VECTOR, || {});
+        }
+        Self {
+            remaining: n,
+            counter,
+            start: None,
+            injected: false,
+            exit_rip: None,
+        }
+    }
+
+    /// Retire the `n` instructions.
+    pub(crate) fn retire(&mut self, n: u64) {
+        self.remaining = self.remaining.saturating_sub(n);
+    }
+
+    /// Check whether the vcpu retired all the instructions, counting the
+    /// instruction emulated on the last vmexit.
+    pub(crate) fn reached(&mut self, vmcs: &ActiveVmcs) -> Result<bool, VmError> {
+        if let Some(rip) = self.exit_rip.take() {
+            if vmcs.read(Field::GuestRip)? != rip {
+                self.retire(1);
+            }
+        }
+        Ok(self.remaining == 0)
+    }
+
+    /// Start the count on the vm entry.
+    ///
+    /// The thread must be pinned until the vmexit, as the counter is per-cpu.
+    pub(crate) fn arm(&mut self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        self.injected = vmcs.read(Field::VmentryInterruptionInfo)? & (1 << 31) != 0;
+        if self.remaining > SKID {
+            unsafe { abyss::dev::x86_64::apic::set_pmi_vector(PMI_VECTOR as u8) };
+            self.start = Some(pmu::start_instruction_counter(
+                self.counter,
+                self.remaining - SKID,
+            ));
+            // The counter counts only while the guest enables it.
+            let ctrl = vmcs.read(Field::GuestIa32PerfGlobalCtrl)?;
+            vmcs.write(Field::GuestIa32PerfGlobalCtrl, ctrl | (1 << self.counter))
+        } else {
+            self.start = None;
+            vmcs.set_mtf(true)
+        }
+    }
+
+    /// Account the instructions retired until the vmexit at the `rip`.
+    pub(crate) fn on_vmexit(
+        &mut self,
+        vmcs: &ActiveVmcs,
+        rip: u64,
+        mtf: bool,
+    ) -> Result<(), VmError> {
+        match self.start.take() {
+            Some(start) => self.retire(pmu::stop_counter(self.counter, start)),
+            None => {
+                vmcs.set_mtf(false)?;
+                if mtf && !self.injected {
+                    self.retire(1);
+                }
+            }
+        }
+        self.exit_rip = Some(rip);
+        Ok(())
+    }
+}
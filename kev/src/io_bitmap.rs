@@ -0,0 +1,97 @@
+//! I/O bitmaps.
+//!
+//! When the "use I/O bitmaps" VM-execution control is 1, an I/O instruction
+//! causes a vmexit if any bit in the I/O bitmaps corresponding to the accessed
+//! ports is set. Otherwise, the guest accesses the port directly. Bitmap A
+//! covers the ports 0x0000 to 0x7FFF, and bitmap B covers the ports 0x8000 to
+//! 0xFFFF.
+//!
+//! [`IoBitmap`] builds the bitmaps from a declarative port policy:
+//!
+//! ```
+//! use kev::io_bitmap::IoBitmap;
+//!
+//! let (a, b) = IoBitmap::new()
+//!     .allow(0x3f8..=0x3fd) // Serial
+//!     .allow(0x20..=0x21) // 8259A
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! The bitmaps are installed on the vmcs when [`VCpuState::io_bitmap`]
+//! returns their addresses.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 25.6.4 I/O-Bitmap Addresses.
+//!
+//! [`VCpuState::io_bitmap`]: crate::vcpu::VCpuState::io_bitmap
+use alloc::{boxed::Box, vec};
+use core::ops::{Bound, RangeBounds};
+use keos::mm::Page;
+
+/// Size of a I/O bitmap.
+const BITMAP_SIZE: usize = 0x1000;
+
+/// Port policy of the guest.
+///
+/// Every port traps into the hypervisor unless it is explicitly allowed.
+pub struct IoBitmap {
+    // Bit set: trap the port.
+    bits: Box<[u8]>,
+}
+
+impl Default for IoBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IoBitmap {
+    /// Create a new policy that traps all ports.
+    pub fn new() -> Self {
+        Self {
+            bits: vec![0xff; BITMAP_SIZE * 2].into_boxed_slice(),
+        }
+    }
+
+    /// Allow the guest to directly access the `ports`.
+    pub fn allow(mut self, ports: impl RangeBounds<u16>) -> Self {
+        let start = match ports.start_bound() {
+            Bound::Included(s) => *s as u32,
+            Bound::Excluded(s) => *s as u32 + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match ports.end_bound() {
+            Bound::Included(e) => *e as u32 + 1,
+            Bound::Excluded(e) => *e as u32,
+            Bound::Unbounded => 0x10000,
+        };
+        for port in start..end {
+            self.bits[port as usize / 8] &= !(1 << (port % 8));
+        }
+        self
+    }
+
+    /// Trap the guest accesses on the `port`.
+    pub fn deny(mut self, port: u16) -> Self {
+        self.bits[port as usize / 8] |= 1 << (port % 8);
+        self
+    }
+
+    /// Check whether the guest can directly access the `port`.
+    pub fn is_allowed(&self, port: u16) -> bool {
+        self.bits[port as usize / 8] & (1 << (port % 8)) == 0
+    }
+
+    /// Build the I/O bitmap A and B.
+    ///
+    /// Returns `None` if failed to allocate the pages.
+    pub fn build(&self) -> Option<(Page, Page)> {
+        let (mut a, mut b) = (Page::new()?, Page::new()?);
+        unsafe {
+            a.inner_mut().copy_from_slice(&self.bits[..BITMAP_SIZE]);
+            b.inner_mut().copy_from_slice(&self.bits[BITMAP_SIZE..]);
+        }
+        Some((a, b))
+    }
+}
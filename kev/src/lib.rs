@@ -258,20 +258,68 @@ extern crate alloc;
 #[macro_use]
 extern crate keos;
 
+pub mod async_call;
+pub mod audit;
+pub mod batch;
+pub mod bios;
+pub mod caps;
+pub mod console;
+pub mod core_dump;
+pub mod coverage;
+pub mod cpu_call;
+pub mod cpu_quota;
+pub mod cpuid;
+pub mod e820;
+pub mod entry_state;
+pub mod error;
+pub mod event;
+pub mod exit_history;
+pub mod exit_trace;
+pub mod fuzz;
+pub mod guest_copy;
+pub mod guest_panic;
+pub mod guest_slice;
+pub mod hotplug;
+pub mod icount;
+pub mod io_bitmap;
+pub mod memory_map;
+pub mod metrics;
+pub mod msr_area;
+pub mod namespace;
+pub mod page_walk;
+pub mod partition;
+pub mod pmu;
 mod probe;
+pub mod ram_crypt;
+pub mod replay;
+pub mod selftest;
+pub mod shutdown;
+pub mod shared_fs;
+pub mod smbios;
+pub mod stepping;
+pub mod stream_codec;
+pub mod syscall_trace;
+pub mod testing;
+pub mod tlb;
 pub mod vcpu;
+pub mod vcpu_pool;
+pub mod virtual_time;
 pub mod vm;
 pub mod vm_control;
 #[allow(dead_code)]
 pub mod vmcs;
 pub mod vmexits;
+pub mod vmfunc;
+pub mod watchdog;
 
 use abyss::x86_64::{msr::Msr, Cr0, Cr4};
 use alloc::boxed::Box;
 use keos::{interrupt::register, intrinsics::cpuid};
+pub use cpu_call::on_cpu;
+pub use error::{Subsystem, VmError};
 pub use probe::Probe;
 use vm_control::*;
-use vmcs::{ExitReason, Vmcs};
+use vmcs::Vmcs;
 
 #[doc(hidden)]
 pub trait Bits {
@@ -307,21 +355,6 @@ pub enum VmxError {
     VmxOperationError(vmcs::InstructionError),
 }
 
-/// Possible errorkind for Vm.
-#[derive(Debug)]
-pub enum VmError {
-    /// Vm operation has error.
-    VmxOperationError(vmcs::InstructionError),
-    /// Failed to handle vmexit.
-    HandleVmexitFailed(ExitReason),
-    /// Controller-private error.
-    ControllerError(Box<dyn core::fmt::Debug + Send + Sync>),
-    /// Failed to decode instruction.
-    FailedToDecodeInstruction,
-    /// Vcpu related error.
-    VCpuError(Box<dyn core::fmt::Debug + Send + Sync>),
-}
-
 /// Enable the VM-eXtension on this cpu.
 pub unsafe fn start_vmx_on_cpu() -> Result<(), VmxError> {
     (Cr4::current() | Cr4::VMXE).apply();
@@ -377,5 +410,7 @@ pub unsafe fn start_vmx_on_cpu() -> Result<(), VmxError> {
 
     core::mem::ManuallyDrop::new(Box::new(Vmcs::new()))
         .on()
-        .map_err(VmxError::VmxOperationError)
+        .map_err(VmxError::VmxOperationError)?;
+    cpu_call::init(cpuid());
+    Ok(())
 }
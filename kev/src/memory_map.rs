@@ -0,0 +1,267 @@
+//! Layout of the guest physical address space.
+//!
+//! A [`GuestMemoryMap`] describes what each guest physical address means: the
+//! RAM, the reserved holes such as the PCI hole below 4GiB, and the MMIO
+//! ranges that the devices claim within the holes. The pager populates only
+//! the RAM, the MMIO bus only accepts the claimed ranges, and the E820 map is
+//! derived from it, so that they agree on the layout:
+//!
+//! ```ignore
+//! let mut map = GuestMemoryMap::new(ram_in_kib as u64 * 1024);
+//! map.claim_mmio(0xcafe_0000, 0x1000, "svirtb")?;
+//! for gpa in map.ram_pages() {
+//!     pager.map_page(gpa, loader.clone());
+//! }
+//! let e820 = map.e820();
+//! ```
+//!
+//! The RAM fills the address space from 0 up to [`PCI_HOLE_START`], and the
+//! rest of the RAM is placed from [`HIGH_RAM_START`].
+use crate::{
+    e820::{MemoryMap, MemoryMapBuilder},
+    vm::Gpa,
+};
+use alloc::vec::Vec;
+
+/// Start of the PCI hole below 4GiB.
+pub const PCI_HOLE_START: u64 = 0xbffd_a000;
+/// Start of the RAM above the PCI hole.
+pub const HIGH_RAM_START: u64 = 0x1_0000_0000;
+/// Base address of the IOAPIC.
+pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+/// Base address of the local APIC.
+pub const LAPIC_BASE: u64 = 0xfee0_0000;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Kind of a region of the guest physical address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Usable RAM.
+    Ram,
+    /// A hole that is not RAM, such as the PCI hole.
+    Reserved,
+    /// MMIO range of a device, within a reserved hole.
+    Mmio,
+}
+
+/// A region of the guest physical address space.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestRegion {
+    /// Base address of the region.
+    pub base: u64,
+    /// Length of the region in bytes.
+    pub len: u64,
+    /// Kind of the region.
+    pub kind: RegionKind,
+    /// Name of the region.
+    pub name: &'static str,
+}
+
+impl GuestRegion {
+    /// End address of the region (exclusive).
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.base + self.len
+    }
+
+    /// Check whether the region contains `addr`.
+    #[inline]
+    pub fn contains(&self, addr: u64) -> bool {
+        self.base <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, base: u64, end: u64) -> bool {
+        self.base < end && base < self.end()
+    }
+}
+
+/// Error on updating the [`GuestMemoryMap`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The range is empty or overflows.
+    InvalidRange,
+    /// The range overlaps with the region of the name.
+    Overlap(&'static str),
+    /// The MMIO range is not within a reserved hole.
+    NotInHole,
+}
+
+/// The layout of the guest physical address space.
+#[derive(Clone, Debug, Default)]
+pub struct GuestMemoryMap {
+    // The RAM and the reserved holes, sorted by the address.
+    regions: Vec<GuestRegion>,
+    // The MMIO claims of the devices, sorted by the address.
+    claims: Vec<GuestRegion>,
+}
+
+impl GuestMemoryMap {
+    /// Create a new map of `ram_size` bytes of RAM, with the PCI hole and the
+    /// interrupt controllers.
+    ///
+    /// `ram_size` is rounded down to the page size.
+    pub fn new(ram_size: u64) -> Self {
+        let ram_size = ram_size & !(PAGE_SIZE - 1);
+        let low = ram_size.min(PCI_HOLE_START);
+        let mut map = Self::default();
+        let mut push = |base, len, kind, name| {
+            if len != 0 {
+                map.regions.push(GuestRegion {
+                    base,
+                    len,
+                    kind,
+                    name,
+                });
+            }
+        };
+        push(0, low, RegionKind::Ram, "ram");
+        push(
+            PCI_HOLE_START,
+            HIGH_RAM_START - PCI_HOLE_START,
+            RegionKind::Reserved,
+            "pci",
+        );
+        push(HIGH_RAM_START, ram_size - low, RegionKind::Ram, "ram");
+        map.claim_mmio(IOAPIC_BASE, PAGE_SIZE, "ioapic").unwrap();
+        map.claim_mmio(LAPIC_BASE, PAGE_SIZE, "lapic").unwrap();
+        map
+    }
+
+    fn range(base: u64, len: u64) -> Result<u64, MemoryMapError> {
+        match base.checked_add(len) {
+            Some(end) if len != 0 => Ok(end),
+            _ => Err(MemoryMapError::InvalidRange),
+        }
+    }
+
+    /// Reserve a hole of `name` at `base..base + len`.
+    ///
+    /// The hole must not overlap with the other regions.
+    pub fn reserve(
+        &mut self,
+        base: u64,
+        len: u64,
+        name: &'static str,
+    ) -> Result<(), MemoryMapError> {
+        let end = Self::range(base, len)?;
+        if let Some(r) = self.regions.iter().find(|r| r.overlaps(base, end)) {
+            return Err(MemoryMapError::Overlap(r.name));
+        }
+        let idx = self.regions.partition_point(|r| r.base < base);
+        self.regions.insert(
+            idx,
+            GuestRegion {
+                base,
+                len,
+                kind: RegionKind::Reserved,
+                name,
+            },
+        );
+        Ok(())
+    }
+
+    /// Claim `base..base + len` as the MMIO range of the device `name`.
+    ///
+    /// The range must be within a reserved hole, and must not overlap with
+    /// the other claims.
+    pub fn claim_mmio(
+        &mut self,
+        base: u64,
+        len: u64,
+        name: &'static str,
+    ) -> Result<(), MemoryMapError> {
+        let end = Self::range(base, len)?;
+        if !self
+            .regions
+            .iter()
+            .any(|r| r.kind == RegionKind::Reserved && r.base <= base && end <= r.end())
+        {
+            return Err(MemoryMapError::NotInHole);
+        }
+        if let Some(c) = self.claims.iter().find(|c| c.overlaps(base, end)) {
+            return Err(MemoryMapError::Overlap(c.name));
+        }
+        let idx = self.claims.partition_point(|c| c.base < base);
+        self.claims.insert(
+            idx,
+            GuestRegion {
+                base,
+                len,
+                kind: RegionKind::Mmio,
+                name,
+            },
+        );
+        Ok(())
+    }
+
+    /// Release the MMIO claim at `base`.
+    pub fn release_mmio(&mut self, base: u64) -> Option<GuestRegion> {
+        let idx = self.claims.iter().position(|c| c.base == base)?;
+        Some(self.claims.remove(idx))
+    }
+
+    /// Get the RAM and the reserved holes, sorted by the address.
+    #[inline]
+    pub fn regions(&self) -> &[GuestRegion] {
+        &self.regions
+    }
+
+    /// Get the MMIO claims, sorted by the address.
+    #[inline]
+    pub fn claims(&self) -> &[GuestRegion] {
+        &self.claims
+    }
+
+    /// Iterate over the RAM regions.
+    pub fn ram(&self) -> impl Iterator<Item = &GuestRegion> {
+        self.regions.iter().filter(|r| r.kind == RegionKind::Ram)
+    }
+
+    /// Total bytes of the RAM.
+    pub fn ram_size(&self) -> u64 {
+        self.ram().map(|r| r.len).sum()
+    }
+
+    /// Iterate over the pages of the RAM.
+    pub fn ram_pages(&self) -> impl Iterator<Item = Gpa> + '_ {
+        self.ram()
+            .flat_map(|r| (r.base..r.end()).step_by(PAGE_SIZE as usize))
+            .filter_map(|gpa| Gpa::new(gpa as usize))
+    }
+
+    /// Get the region that contains `gpa`.
+    ///
+    /// The MMIO claim is returned if the address is claimed by a device.
+    pub fn lookup(&self, gpa: Gpa) -> Option<&GuestRegion> {
+        let addr = unsafe { gpa.into_usize() } as u64;
+        self.claims
+            .iter()
+            .find(|c| c.contains(addr))
+            .or_else(|| self.regions.iter().find(|r| r.contains(addr)))
+    }
+
+    /// Check whether `gpa` is in the RAM.
+    pub fn is_ram(&self, gpa: Gpa) -> bool {
+        self.lookup(gpa).is_some_and(|r| r.kind == RegionKind::Ram)
+    }
+
+    /// Check whether `gpa..gpa + len` is within a single MMIO claim.
+    pub fn is_claimed(&self, gpa: Gpa, len: usize) -> bool {
+        let addr = unsafe { gpa.into_usize() } as u64;
+        self.claims
+            .iter()
+            .any(|c| c.base <= addr && addr + len as u64 <= c.end())
+    }
+
+    /// Build the E820 memory map of the layout.
+    pub fn e820(&self) -> MemoryMap {
+        self.regions
+            .iter()
+            .fold(MemoryMapBuilder::new(), |builder, r| match r.kind {
+                RegionKind::Ram => builder.ram(r.base, r.len),
+                RegionKind::Reserved | RegionKind::Mmio => builder.mmio(r.base, r.len),
+            })
+            .finalize()
+    }
+}
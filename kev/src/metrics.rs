@@ -0,0 +1,167 @@
+//! Performance metrics shared with the guest.
+//!
+//! Kev keeps the metrics of each vcpu of a vm (the vmexits, the injected
+//! interrupts, the steal time and the run time), and publishes them to the
+//! host and to the guest:
+//!
+//! - The host reads them with [`VmHandle::metrics`].
+//! - The guest registers a page with the [`HC_METRICS`] hypercall, handled by
+//!   [`Controller`], and reads its slots without a vmexit; see
+//!   [`keos::metrics`] for the layout of the page and the guest side.
+//!
+//! The metrics of a vcpu are updated when the vcpu thread is back from the
+//! guest, e.g. on the host timer interrupts, so they lag behind by a tick at
+//! most. The steal time is the [`run delay`] of the vcpu thread while it runs
+//! the vcpu: the time that the vcpu was ready to run, but waited for a host
+//! cpu.
+//!
+//! [`VmHandle::metrics`]: crate::vm::VmHandle::metrics
+//! [`run delay`]: keos::thread::Thread::run_delay
+use crate::{
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+pub use keos::metrics::{MetricsPage, VCpuMetrics, HC_METRICS, MAX_VCPUS, METRICS_MAGIC};
+use keos::{addressing::PAGE_SIZE, sync::SpinLock};
+
+// Bad address.
+const EFAULT: usize = 14;
+// Invalid argument.
+const EINVAL: usize = 22;
+
+/// Counters of a vcpu, counted by the vcpu loop.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct VCpuCounters {
+    pub(crate) exits: u64,
+    pub(crate) injected: u64,
+}
+
+/// Metrics of the vcpus of a vm.
+pub struct Metrics {
+    // Host virtual address of the page of the guest, or 0.
+    page: AtomicUsize,
+    vcpus: Vec<SpinLock<VCpuMetrics>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(vcpus: usize) -> Self {
+        Self {
+            page: AtomicUsize::new(0),
+            vcpus: (0..vcpus)
+                .map(|_| SpinLock::new(VCpuMetrics::default()))
+                .collect(),
+        }
+    }
+
+    /// Get the metrics of the vcpu `id`.
+    pub fn vcpu(&self, id: usize) -> Option<VCpuMetrics> {
+        self.vcpus.get(id).map(|m| *m.lock())
+    }
+
+    /// Check whether the guest registered the page.
+    pub fn is_shared(&self) -> bool {
+        self.page.load(Ordering::SeqCst) != 0
+    }
+
+    // Share the metrics on the `page`, or stop sharing with `None`.
+    fn share(&self, page: Option<*mut MetricsPage>) {
+        let Some(page) = page else {
+            self.page.store(0, Ordering::SeqCst);
+            return;
+        };
+        unsafe {
+            (*page).vcpus = self.vcpus.len().min(MAX_VCPUS) as u32;
+            for (id, metrics) in self.vcpus.iter().enumerate() {
+                MetricsPage::write(page, id, &metrics.lock());
+            }
+            core::ptr::addr_of_mut!((*page).magic).write_volatile(METRICS_MAGIC);
+        }
+        self.page.store(page as usize, Ordering::SeqCst);
+    }
+
+    // Update the metrics of the vcpu `id` with `f`, and publish them.
+    pub(crate) fn update(&self, id: usize, f: impl FnOnce(&mut VCpuMetrics)) {
+        let Some(metrics) = self.vcpus.get(id) else {
+            return;
+        };
+        let mut metrics = metrics.lock();
+        f(&mut metrics);
+        let page = self.page.load(Ordering::SeqCst) as *mut MetricsPage;
+        if !page.is_null() {
+            unsafe {
+                MetricsPage::write(page, id, &metrics);
+            }
+        }
+    }
+}
+
+/// Convert the tsc `cycles` into nanoseconds.
+pub(crate) fn cycles_to_ns(cycles: u64) -> u64 {
+    let khz = abyss::dev::x86_64::timer::tsc_khz().max(1);
+    (cycles as u128 * 1_000_000 / khz as u128) as u64
+}
+
+/// Vmexit controller of the [`HC_METRICS`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+#[derive(Default)]
+pub struct Controller;
+
+impl Controller {
+    /// Create a new metrics controller.
+    pub fn new() -> Self {
+        Self
+    }
+
+    // Register the page at the `gpa`, or unregister with 0.
+    fn register(&self, generic_vcpu_state: &GenericVCpuState, gpa: usize) -> Result<(), usize> {
+        let vm = generic_vcpu_state.vm.upgrade().ok_or(EINVAL)?;
+        let metrics = vm.metrics().ok_or(EINVAL)?;
+        if gpa == 0 {
+            metrics.share(None);
+            return Ok(());
+        }
+        if gpa % PAGE_SIZE != 0 {
+            return Err(EINVAL);
+        }
+        let pa = Gpa::new(gpa)
+            .and_then(|gpa| vm.gpa2hpa(gpa))
+            .ok_or(EFAULT)?;
+        let page = unsafe { pa.into_va().into_usize() } as *mut MetricsPage;
+        metrics.share(Some(page));
+        Ok(())
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_METRICS => {
+                // The page is written by the host at any time.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let gpa = generic_vcpu_state.gprs.rdi;
+                generic_vcpu_state.gprs.rax = match self.register(generic_vcpu_state, gpa) {
+                    Ok(()) => 0,
+                    Err(errno) => errno.wrapping_neg(),
+                };
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Guest MSR save and restore areas.
+//!
+//! The vmcs switches only a few msrs of the guest, such as the EFER and the
+//! PAT, on the vm entries and exits. The guest kernels that use
+//! `syscall`/`sysret` and `swapgs` program the other msrs, such as
+//! [`IA32_LSTAR`] and [`IA32_KERNEL_GS_BASE`], which otherwise must be
+//! emulated on every `rdmsr` and `wrmsr`.
+//!
+//! [`MsrArea`] declares the msrs to switch with their initial values:
+//!
+//! ```ignore
+//! fn msr_area(&self) -> Option<MsrArea> {
+//!     Some(
+//!         MsrArea::new()
+//!             .switch(msr_area::IA32_STAR, 0)
+//!             .switch(msr_area::IA32_LSTAR, 0)
+//!             .switch(msr_area::IA32_FMASK, 0)
+//!             .switch(msr_area::IA32_KERNEL_GS_BASE, 0),
+//!     )
+//! }
+//! ```
+//!
+//! When [`VCpuState::msr_area`] returns an area, the guest values of the msrs
+//! are loaded from the VM-entry MSR-load area and stored to the VM-exit
+//! MSR-store area, which are the same page, and the host values are loaded
+//! from the VM-exit MSR-load area. The msr bitmap lets the guest access the
+//! switched msrs directly; the accesses on the other msrs still cause the
+//! vmexits.
+//!
+//! The EFER must be switched with the [`LOAD_IA32_EFER`] controls instead.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 25.6.9 MSR-Bitmap Address, 25.7.2 VM-Exit Controls for MSRs and
+//! 25.8.2 VM-Entry Controls for MSRs.
+//!
+//! [`VCpuState::msr_area`]: crate::vcpu::VCpuState::msr_area
+//! [`LOAD_IA32_EFER`]: crate::vm_control::VmcsEntryCtl::LOAD_IA32_EFER
+use crate::{
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use abyss::x86_64::msr::rdmsr;
+use alloc::vec::Vec;
+use keos::mm::Page;
+
+/// MSR - IA32_STAR.
+pub const IA32_STAR: u32 = 0xC000_0081;
+/// MSR - IA32_LSTAR.
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+/// MSR - IA32_CSTAR.
+pub const IA32_CSTAR: u32 = 0xC000_0083;
+/// MSR - IA32_FMASK.
+pub const IA32_FMASK: u32 = 0xC000_0084;
+/// MSR - IA32_KERNEL_GS_BASE.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+/// MSR - IA32_TSC_AUX.
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// Maximum number of the msrs in an area, which fits in a page.
+pub const MAX_MSRS: usize = 0x1000 / ENTRY_SIZE;
+
+// Size of an entry of the area: the index, the reserved and the data.
+const ENTRY_SIZE: usize = 16;
+
+/// The msrs of the guest switched on the vm entries and exits.
+#[derive(Clone, Debug, Default)]
+pub struct MsrArea {
+    msrs: Vec<(u32, u64)>,
+}
+
+impl MsrArea {
+    /// Create an area that switches no msr.
+    pub fn new() -> Self {
+        Self { msrs: Vec::new() }
+    }
+
+    /// Switch the msr `index`, of which the guest value starts with `value`.
+    pub fn switch(mut self, index: u32, value: u64) -> Self {
+        match self.msrs.iter_mut().find(|(i, _)| *i == index) {
+            Some(msr) => msr.1 = value,
+            None => self.msrs.push((index, value)),
+        }
+        self
+    }
+
+    /// Get the switched msrs.
+    pub fn msrs(&self) -> &[(u32, u64)] {
+        &self.msrs
+    }
+
+    // Build the pages of the area.
+    //
+    // Returns `None` if failed to allocate the pages, or there are more than
+    // the `MAX_MSRS` msrs.
+    pub(crate) fn build(&self) -> Option<SwitchedMsrs> {
+        if self.msrs.len() > MAX_MSRS {
+            return None;
+        }
+        let mut msrs = SwitchedMsrs {
+            guest: Page::new()?,
+            host: Page::new()?,
+            bitmap: Page::new()?,
+            count: self.msrs.len(),
+        };
+        unsafe {
+            // Trap all msrs but the switched ones.
+            msrs.bitmap.inner_mut().fill(0xff);
+            for (i, (index, value)) in self.msrs.iter().enumerate() {
+                for area in [msrs.guest.inner_mut(), msrs.host.inner_mut()] {
+                    area[i * ENTRY_SIZE..i * ENTRY_SIZE + 4].copy_from_slice(&index.to_le_bytes());
+                }
+                msrs.set_entry(i, *value);
+                if let Some(ofs) = bitmap_offset(*index) {
+                    let bitmap = msrs.bitmap.inner_mut();
+                    // The read bitmap, and then the write bitmap.
+                    bitmap[ofs / 8] &= !(1 << (ofs % 8));
+                    bitmap[0x800 + ofs / 8] &= !(1 << (ofs % 8));
+                }
+            }
+        }
+        Some(msrs)
+    }
+}
+
+// Bit offset of the msr `index` in the read or the write bitmap.
+fn bitmap_offset(index: u32) -> Option<usize> {
+    match index {
+        0..=0x1fff => Some(index as usize),
+        0xc000_0000..=0xc000_1fff => Some(0x2000 + (index - 0xc000_0000) as usize),
+        _ => None,
+    }
+}
+
+/// The pages of an installed [`MsrArea`].
+pub(crate) struct SwitchedMsrs {
+    guest: Page,
+    host: Page,
+    bitmap: Page,
+    count: usize,
+}
+
+impl SwitchedMsrs {
+    /// Install the areas and the bitmap on the `vmcs`.
+    pub(crate) fn install(&self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        let (guest, host, bitmap) = unsafe {
+            (
+                self.guest.pa().into_usize() as u64,
+                self.host.pa().into_usize() as u64,
+                self.bitmap.pa().into_usize() as u64,
+            )
+        };
+        vmcs.write(Field::VmexitMsrStoreAddr, guest)?;
+        vmcs.write(Field::VmexitMsrStoreCount, self.count as u64)?;
+        vmcs.write(Field::VmentryMsrLoadAddr, guest)?;
+        vmcs.write(Field::VmentryMsrLoadCount, self.count as u64)?;
+        vmcs.write(Field::VmexitMsrLoadAddr, host)?;
+        vmcs.write(Field::VmexitMsrLoadCount, self.count as u64)?;
+        vmcs.write(Field::MsrBitmaps, bitmap)
+    }
+
+    /// Save the host values of the msrs on the current cpu, to restore them
+    /// on the vmexits.
+    pub(crate) fn save_host(&mut self) {
+        for i in 0..self.count {
+            let host = unsafe { self.host.inner_mut() };
+            let index =
+                u32::from_le_bytes(host[i * ENTRY_SIZE..i * ENTRY_SIZE + 4].try_into().unwrap());
+            host[i * ENTRY_SIZE + 8..(i + 1) * ENTRY_SIZE]
+                .copy_from_slice(&rdmsr(index).to_le_bytes());
+        }
+    }
+
+    /// Get the guest value of the msr `index`.
+    pub(crate) fn get(&self, index: u32) -> Option<u64> {
+        let i = self.position(index)?;
+        let guest = unsafe { self.guest.inner() };
+        Some(u64::from_le_bytes(
+            guest[i * ENTRY_SIZE + 8..(i + 1) * ENTRY_SIZE]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+
+    /// Set the guest value of the msr `index`.
+    ///
+    /// Returns false if the msr is not switched.
+    pub(crate) fn set(&mut self, index: u32, value: u64) -> bool {
+        match self.position(index) {
+            Some(i) => {
+                self.set_entry(i, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Iterate over the switched msrs and their guest values.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        let guest = unsafe { self.guest.inner() };
+        guest[..self.count * ENTRY_SIZE]
+            .chunks(ENTRY_SIZE)
+            .map(|entry| {
+                (
+                    u32::from_le_bytes(entry[..4].try_into().unwrap()),
+                    u64::from_le_bytes(entry[8..].try_into().unwrap()),
+                )
+            })
+    }
+
+    fn position(&self, index: u32) -> Option<usize> {
+        self.iter().position(|(i, _)| i == index)
+    }
+
+    fn set_entry(&mut self, i: usize, value: u64) {
+        let guest = unsafe { self.guest.inner_mut() };
+        guest[i * ENTRY_SIZE + 8..(i + 1) * ENTRY_SIZE].copy_from_slice(&value.to_le_bytes());
+    }
+}
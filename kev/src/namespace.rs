@@ -0,0 +1,224 @@
+//! Namespace of the host resources visible to the guest.
+//!
+//! The host registers its objects that a vm may use, e.g. the disks, the
+//! shared folders, the consoles and the shared memory segments, under the
+//! names in a [`Namespace`]. The guest finds them by the names with the
+//! [`HC_DISCOVER`] hypercall, instead of hard-coding the slots of the devices:
+//!
+//! | Register | Value                                                  |
+//! |----------|--------------------------------------------------------|
+//! | rax      | [`HC_DISCOVER`] (0x203)                                |
+//! | rdi      | guest physical address of the name                     |
+//! | rsi      | length of the name                                     |
+//!
+//! On return, rax is the [`ResourceKind`] of the resource, or the negated
+//! [`NamespaceError`]. rdx and rcx describe the resource:
+//!
+//! | Kind                            | rdx            | rcx                |
+//! |---------------------------------|----------------|--------------------|
+//! | [`ResourceKind::Disk`]          | slot           |                    |
+//! | [`ResourceKind::SharedFolder`]  |                |                    |
+//! | [`ResourceKind::Console`]       |                |                    |
+//! | [`ResourceKind::SharedMemory`]  | guest address  | size in bytes      |
+//!
+//! ```ignore
+//! let namespace = Namespace::new();
+//! namespace.register("shared", Resource::SharedFolder(folder.clone()))?;
+//! (namespace::Controller::new(namespace.clone()), hypercall_ctl)
+//! ```
+use crate::{
+    console::Console,
+    event,
+    guest_slice::GuestSlice,
+    probe::Probe,
+    shared_fs::SharedFolder,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::Gpa,
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use keos::sync::SpinLock;
+
+/// Hypercall number to find a resource by the name.
+pub const HC_DISCOVER: usize = 0x203;
+
+/// A host resource visible to the guest.
+#[derive(Clone)]
+pub enum Resource {
+    /// A block device in the slot.
+    Disk(usize),
+    /// A folder shared with the [`HC_SHARED_FS`] hypercall.
+    ///
+    /// [`HC_SHARED_FS`]: crate::shared_fs::HC_SHARED_FS
+    SharedFolder(SharedFolder),
+    /// A console.
+    Console(Arc<Console>),
+    /// A memory segment shared with the host.
+    SharedMemory {
+        /// The guest physical address of the segment.
+        gpa: Gpa,
+        /// The size of the segment in bytes.
+        size: usize,
+    },
+}
+
+/// Kind of a [`Resource`], reported to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// [`Resource::Disk`].
+    Disk = 1,
+    /// [`Resource::SharedFolder`].
+    SharedFolder = 2,
+    /// [`Resource::Console`].
+    Console = 3,
+    /// [`Resource::SharedMemory`].
+    SharedMemory = 4,
+}
+
+impl Resource {
+    /// Get the kind of the resource.
+    pub fn kind(&self) -> ResourceKind {
+        match self {
+            Self::Disk(_) => ResourceKind::Disk,
+            Self::SharedFolder(_) => ResourceKind::SharedFolder,
+            Self::Console(_) => ResourceKind::Console,
+            Self::SharedMemory { .. } => ResourceKind::SharedMemory,
+        }
+    }
+
+    // Get the description of the resource for the guest.
+    fn describe(&self) -> (usize, usize) {
+        match self {
+            Self::Disk(slot) => (*slot, 0),
+            Self::SharedMemory { gpa, size } => (unsafe { gpa.into_usize() }, *size),
+            Self::SharedFolder(_) | Self::Console(_) => (0, 0),
+        }
+    }
+}
+
+/// Error on the namespace, returned to the guest as the negated value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamespaceError {
+    /// No resource has the name.
+    NotFound = 2,
+    /// The name of the guest is not mapped.
+    BadAddress = 14,
+    /// A resource already has the name.
+    AlreadyExists = 17,
+    /// The name is empty or too long.
+    InvalidName = 22,
+}
+
+/// Names of the host resources visible to a vm.
+///
+/// The namespace is cheaply cloned and shared by the vcpus.
+#[derive(Clone, Default)]
+pub struct Namespace {
+    resources: Arc<SpinLock<BTreeMap<String, Resource>>>,
+}
+
+impl Namespace {
+    /// Maximum length of a name in bytes.
+    pub const NAME_MAX: usize = 256;
+
+    /// Create an empty namespace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the `resource` under the `name`.
+    pub fn register(&self, name: &str, resource: Resource) -> Result<(), NamespaceError> {
+        if name.is_empty() || name.len() > Self::NAME_MAX {
+            return Err(NamespaceError::InvalidName);
+        }
+        let mut resources = self.resources.lock();
+        if resources.contains_key(name) {
+            return Err(NamespaceError::AlreadyExists);
+        }
+        resources.insert(String::from(name), resource);
+        Ok(())
+    }
+
+    /// Remove the resource of the `name`, and returns it.
+    pub fn unregister(&self, name: &str) -> Option<Resource> {
+        self.resources.lock().remove(name)
+    }
+
+    /// Find the resource of the `name`.
+    pub fn lookup(&self, name: &str) -> Option<Resource> {
+        self.resources.lock().get(name).cloned()
+    }
+
+    /// Get the registered names, in the lexicographic order.
+    pub fn names(&self) -> Vec<String> {
+        self.resources.lock().keys().cloned().collect()
+    }
+}
+
+/// Vmexit controller of the [`HC_DISCOVER`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+pub struct Controller {
+    namespace: Namespace,
+}
+
+impl Controller {
+    /// Create a new controller that finds the resources in the `namespace`.
+    pub fn new(namespace: Namespace) -> Self {
+        Self { namespace }
+    }
+
+    fn discover(
+        &self,
+        p: &dyn Probe,
+        generic_vcpu_state: &GenericVCpuState,
+    ) -> Result<Resource, NamespaceError> {
+        let (gpa, len) = (generic_vcpu_state.gprs.rdi, generic_vcpu_state.gprs.rsi);
+        if len == 0 || len > Namespace::NAME_MAX {
+            return Err(NamespaceError::InvalidName);
+        }
+        let mut name = alloc::vec![0; len];
+        Gpa::new(gpa)
+            .and_then(|gpa| GuestSlice::new(p, &generic_vcpu_state.vmcs, [(gpa, len)]))
+            .ok_or(NamespaceError::BadAddress)?
+            .drain_with(|ofs, seg| {
+                name[ofs..ofs + seg.len()].copy_from_slice(seg);
+                Ok::<_, NamespaceError>(seg.len())
+            })?;
+        let name = core::str::from_utf8(&name).map_err(|_| NamespaceError::InvalidName)?;
+        self.namespace.lookup(name).ok_or(NamespaceError::NotFound)
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_DISCOVER => {
+                // Only the guest kernel attaches the resources.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                match self.discover(p, generic_vcpu_state) {
+                    Ok(resource) => {
+                        let (rdx, rcx) = resource.describe();
+                        let gprs = &mut generic_vcpu_state.gprs;
+                        gprs.rax = resource.kind() as usize;
+                        gprs.rdx = rdx;
+                        gprs.rcx = rcx;
+                    }
+                    Err(e) => generic_vcpu_state.gprs.rax = -(e as isize) as usize,
+                }
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! Guest page-table walker.
+//!
+//! The hypervisor translates the guest virtual addresses when it emulates an
+//! instruction of the guest, for example to fetch the instruction or to read
+//! its memory operand. A [`PagingContext`] selects the paging mode of the guest
+//! from its CR0, CR4 and EFER, and walks the guest page tables through the
+//! [`Probe`]:
+//!
+//! ```ignore
+//! let ctx = PagingContext::current(vmcs)?;
+//! match ctx.walk(p, vmcs, gva, Access::WRITE) {
+//!     Ok(translation) => write_to(translation.gpa),
+//!     // Deliver the #PF to the guest.
+//!     Err(e) => inject_page_fault(gva, e.error_code(Access::WRITE)),
+//! }
+//! ```
+//!
+//! All of the paging modes are supported: no paging (real mode and the
+//! protected mode without paging), 32-bit, PAE, 4-level and 5-level paging,
+//! with the large pages of each level.
+//!
+//! Like the cpu, the walker sets the accessed flags of the entries that it
+//! walks and the dirty flag of the page on a write, so that the page
+//! replacement of the guest sees the emulated accesses. It also enforces SMEP
+//! and SMAP on the supervisor accesses. Both can be turned off in the
+//! [`PagingContext`].
+use crate::{
+    probe::Probe,
+    vm::{Gpa, Gva},
+    vmcs::{ActiveVmcs, Field, InstructionError},
+    VmError,
+};
+use abyss::x86_64::{Cr0, Cr4, Rflags};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+// EFER.LMA: IA-32e mode active.
+const EFER_LMA: u64 = 1 << 10;
+// EFER.NXE: execute-disable bit enable.
+const EFER_NXE: u64 = 1 << 11;
+// CR4.LA57: 57-bit linear addresses.
+const CR4_LA57: u64 = 1 << 12;
+// Maximum physical-address width that the walker accepts.
+const MAXPHYADDR: u32 = 52;
+
+// Bits of the paging-structure entries.
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const ACCESSED: u64 = 1 << 5;
+const DIRTY: u64 = 1 << 6;
+const PAGE_SIZE: u64 = 1 << 7;
+const EXECUTE_DISABLE: u64 = 1 << 63;
+
+/// Paging mode of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    /// The paging is disabled; the linear address is the physical address.
+    None,
+    /// 32-bit paging.
+    Bits32 {
+        /// CR4.PSE; the 4MB pages are enabled.
+        pse: bool,
+    },
+    /// PAE paging.
+    Pae {
+        /// EFER.NXE; the execute-disable bit is enabled.
+        nxe: bool,
+    },
+    /// 4-level paging.
+    Level4 {
+        /// EFER.NXE; the execute-disable bit is enabled.
+        nxe: bool,
+    },
+    /// 5-level paging.
+    Level5 {
+        /// EFER.NXE; the execute-disable bit is enabled.
+        nxe: bool,
+    },
+}
+
+impl PagingMode {
+    /// Select the paging mode of the guest from the `cr0`, `cr4` and `efer`.
+    pub fn from_regs(cr0: u64, cr4: u64, efer: u64) -> Self {
+        let nxe = efer & EFER_NXE != 0;
+        if cr0 & Cr0::PG.bits() == 0 {
+            Self::None
+        } else if cr4 & Cr4::PAE.bits() == 0 {
+            Self::Bits32 {
+                pse: cr4 & Cr4::PSE.bits() != 0,
+            }
+        } else if efer & EFER_LMA == 0 {
+            Self::Pae { nxe }
+        } else if cr4 & CR4_LA57 == 0 {
+            Self::Level4 { nxe }
+        } else {
+            Self::Level5 { nxe }
+        }
+    }
+
+    /// Get the paging mode of the guest of the `vmcs`.
+    pub fn current(vmcs: &ActiveVmcs) -> Result<Self, VmError> {
+        Ok(Self::from_regs(
+            vmcs.read(Field::GuestCr0)?,
+            vmcs.read(Field::GuestCr4)?,
+            vmcs.read(Field::GuestIa32Efer)?,
+        ))
+    }
+}
+
+/// The paging state of the guest that the walks depend on.
+#[derive(Clone, Copy, Debug)]
+pub struct PagingContext {
+    /// The paging mode.
+    pub mode: PagingMode,
+    /// CR3 of the guest.
+    pub cr3: u64,
+    /// CR0.WP; the supervisor can not write to the read-only pages.
+    pub wp: bool,
+    /// The PDPTEs of the PAE paging.
+    pub pdptes: [u64; 4],
+    /// CR4.SMEP; the supervisor can not fetch from the user pages.
+    pub smep: bool,
+    /// CR4.SMAP; the supervisor can not access the user data pages, unless
+    /// `ac` is set.
+    pub smap: bool,
+    /// RFLAGS.AC; the SMAP is suspended.
+    pub ac: bool,
+    /// Set the accessed and dirty flags of the walked entries.
+    pub update_accessed_dirty: bool,
+}
+
+// Read the guest state from the `vmcs`.
+fn read(vmcs: &ActiveVmcs, field: Field) -> Result<u64, WalkError> {
+    vmcs.read(field).map_err(|e| match e.root() {
+        VmError::VmxOperationError(e) => WalkError::Vmcs(*e),
+        _ => WalkError::Vmcs(InstructionError::Unknown),
+    })
+}
+
+impl PagingContext {
+    /// Get the paging state of the guest of the `vmcs`.
+    pub fn current(vmcs: &ActiveVmcs) -> Result<Self, WalkError> {
+        let mode = PagingMode::from_regs(
+            read(vmcs, Field::GuestCr0)?,
+            read(vmcs, Field::GuestCr4)?,
+            read(vmcs, Field::GuestIa32Efer)?,
+        );
+        // With EPT, the cpu loads the PDPTEs into the vmcs.
+        let pdptes = if let PagingMode::Pae { .. } = mode {
+            [
+                read(vmcs, Field::GuestIa32Pdpte0)?,
+                read(vmcs, Field::GuestIa32Pdpte1)?,
+                read(vmcs, Field::GuestIa32Pdpte2)?,
+                read(vmcs, Field::GuestIa32Pdpte3)?,
+            ]
+        } else {
+            [0; 4]
+        };
+        let cr4 = read(vmcs, Field::GuestCr4)?;
+        Ok(Self {
+            mode,
+            cr3: read(vmcs, Field::GuestCr3)?,
+            wp: read(vmcs, Field::GuestCr0)? & Cr0::WP.bits() != 0,
+            pdptes,
+            smep: cr4 & Cr4::SMEP.bits() != 0,
+            smap: cr4 & Cr4::SMAP.bits() != 0,
+            ac: read(vmcs, Field::GuestRflags)? & Rflags::AC.bits() != 0,
+            update_accessed_dirty: true,
+        })
+    }
+}
+
+bitflags::bitflags! {
+    /// Kind of a memory access.
+    pub struct Access: u32 {
+        /// The access is a write.
+        const WRITE = 1 << 1;
+        /// The access is made in the user mode (CPL 3).
+        const USER = 1 << 2;
+        /// The access is an instruction fetch.
+        const FETCH = 1 << 4;
+    }
+}
+
+impl Access {
+    /// Get the `access` made by the guest at its current privilege level.
+    pub fn at_cpl(vmcs: &ActiveVmcs, access: Access) -> Result<Self, VmError> {
+        Ok(if vmcs.guest_cpl()? == 3 {
+            access | Access::USER
+        } else {
+            access
+        })
+    }
+}
+
+/// Result of a successful walk.
+#[derive(Clone, Copy, Debug)]
+pub struct Translation {
+    /// The guest physical address.
+    pub gpa: Gpa,
+    /// The size of the page that maps the address.
+    pub page_size: usize,
+    /// The page is writable.
+    pub writable: bool,
+    /// The page is accessible in the user mode.
+    pub user: bool,
+    /// The page is executable.
+    pub executable: bool,
+    /// The accessed flag of the page.
+    pub accessed: bool,
+    /// The dirty flag of the page.
+    pub dirty: bool,
+}
+
+/// Failure of a walk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkError {
+    /// The entry of the `level` is not present.
+    NotPresent {
+        /// The level of the entry; 1 is the page table.
+        level: u8,
+    },
+    /// The entry of the `level` sets a reserved bit.
+    Reserved {
+        /// The level of the entry; 1 is the page table.
+        level: u8,
+    },
+    /// The page does not permit the access.
+    Protection,
+    /// The address is not canonical.
+    NonCanonical,
+    /// A paging structure is not backed by the host memory.
+    Unmapped(Gpa),
+    /// Failed to read the guest state from the vmcs.
+    Vmcs(InstructionError),
+}
+
+impl WalkError {
+    /// Get the error code of the page fault that the failure causes for the
+    /// `access`.
+    ///
+    /// Returns `None` if the failure is not a page fault.
+    pub fn error_code(&self, access: Access) -> Option<u32> {
+        match self {
+            Self::NotPresent { .. } => Some(access.bits()),
+            Self::Protection => Some(PRESENT as u32 | access.bits()),
+            // RSVD.
+            Self::Reserved { .. } => Some(PRESENT as u32 | 1 << 3 | access.bits()),
+            Self::NonCanonical | Self::Unmapped(_) | Self::Vmcs(_) => None,
+        }
+    }
+}
+
+impl From<WalkError> for VmError {
+    fn from(e: WalkError) -> Self {
+        match e {
+            WalkError::Vmcs(e) => VmError::VmxOperationError(e),
+            e => VmError::ControllerError(Box::new(e)),
+        }
+    }
+}
+
+// A paging-structure entry on the host memory.
+#[derive(Clone, Copy)]
+enum Entry {
+    Bits32(&'static AtomicU32),
+    Bits64(&'static AtomicU64),
+}
+
+impl Entry {
+    // Get the entry at `gpa`.
+    fn at<P: Probe + ?Sized>(
+        p: &P,
+        vmcs: &ActiveVmcs,
+        gpa: usize,
+        bits32: bool,
+    ) -> Result<Self, WalkError> {
+        let gpa = Gpa::new(gpa).ok_or(WalkError::Reserved { level: 0 })?;
+        let hva = unsafe {
+            p.gpa2hva(vmcs, gpa)
+                .ok_or(WalkError::Unmapped(gpa))?
+                .into_usize()
+        };
+        // The guest updates its page tables concurrently.
+        Ok(unsafe {
+            if bits32 {
+                Self::Bits32(&*(hva as *const AtomicU32))
+            } else {
+                Self::Bits64(&*(hva as *const AtomicU64))
+            }
+        })
+    }
+
+    fn get(&self) -> u64 {
+        match self {
+            Self::Bits32(e) => e.load(Ordering::SeqCst) as u64,
+            Self::Bits64(e) => e.load(Ordering::SeqCst),
+        }
+    }
+
+    // Set the `flags` with a locked operation, as the cpu does.
+    fn set(&self, flags: u64) {
+        match self {
+            Self::Bits32(e) => {
+                e.fetch_or(flags as u32, Ordering::SeqCst);
+            }
+            Self::Bits64(e) => {
+                e.fetch_or(flags, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+// The reserved bits of the physical address in the entries of PAE and
+// 4/5-level paging.
+const fn address_reserved() -> u64 {
+    !((1 << MAXPHYADDR) - 1) & !EXECUTE_DISABLE
+}
+
+impl PagingContext {
+    /// Translate the `gva` of the `access` by the guest page tables.
+    pub fn walk<P: Probe + ?Sized>(
+        &self,
+        p: &P,
+        vmcs: &ActiveVmcs,
+        gva: Gva,
+        access: Access,
+    ) -> Result<Translation, WalkError> {
+        let Self {
+            mode,
+            cr3,
+            wp,
+            pdptes,
+            smep,
+            smap,
+            ac,
+            update_accessed_dirty,
+        } = *self;
+        let va = unsafe { gva.into_usize() } as u64;
+        // The entries walked, from the top level.
+        let mut walked = [None; 5];
+        // The permissions of all the levels are accumulated.
+        let (mut writable, mut user, mut executable) = (true, true, true);
+        let (entry, level) = match mode {
+            PagingMode::None => {
+                return Ok(Translation {
+                    gpa: Gpa::new(va as usize).ok_or(WalkError::NonCanonical)?,
+                    page_size: 0x1000,
+                    writable,
+                    user,
+                    executable,
+                    accessed: true,
+                    dirty: true,
+                });
+            }
+            PagingMode::Bits32 { pse } => {
+                let va = va & 0xffff_ffff;
+                let mut table = cr3 & 0xffff_f000;
+                let mut level = 2;
+                loop {
+                    let index = (va >> (12 + 10 * (level - 1))) & 0x3ff;
+                    let e = Entry::at(p, vmcs, (table + index * 4) as usize, true)?;
+                    walked[2 - level as usize] = Some(e);
+                    let entry = e.get();
+                    if entry & PRESENT == 0 {
+                        return Err(WalkError::NotPresent { level });
+                    }
+                    writable &= entry & WRITABLE != 0;
+                    user &= entry & USER != 0;
+                    if level == 1 || (level == 2 && pse && entry & PAGE_SIZE != 0) {
+                        break (entry, level);
+                    }
+                    table = entry & 0xffff_f000;
+                    level -= 1;
+                }
+            }
+            PagingMode::Pae { nxe } => {
+                let va = va & 0xffff_ffff;
+                let pdpte = pdptes[(va >> 30) as usize];
+                if pdpte & PRESENT == 0 {
+                    return Err(WalkError::NotPresent { level: 3 });
+                }
+                // The PDPTEs do not have the permission bits.
+                if pdpte & (address_reserved() | EXECUTE_DISABLE | 0x1e6) != 0 {
+                    return Err(WalkError::Reserved { level: 3 });
+                }
+                let mut table = pdpte & 0x000f_ffff_ffff_f000;
+                let mut level = 2;
+                loop {
+                    let index = (va >> (12 + 9 * (level - 1))) & 0x1ff;
+                    let e = Entry::at(p, vmcs, (table + index * 8) as usize, false)?;
+                    walked[2 - level as usize] = Some(e);
+                    let entry = e.get();
+                    if entry & PRESENT == 0 {
+                        return Err(WalkError::NotPresent { level });
+                    }
+                    if entry & address_reserved() != 0 || (!nxe && entry & EXECUTE_DISABLE != 0) {
+                        return Err(WalkError::Reserved { level });
+                    }
+                    writable &= entry & WRITABLE != 0;
+                    user &= entry & USER != 0;
+                    executable &= entry & EXECUTE_DISABLE == 0;
+                    if level == 1 || entry & PAGE_SIZE != 0 {
+                        break (entry, level);
+                    }
+                    table = entry & 0x000f_ffff_ffff_f000;
+                    level -= 1;
+                }
+            }
+            PagingMode::Level4 { nxe } | PagingMode::Level5 { nxe } => {
+                let top: usize = if matches!(mode, PagingMode::Level5 { .. }) {
+                    5
+                } else {
+                    4
+                };
+                // The unused upper bits must be the copies of the top bit.
+                let shift = 64 - (12 + 9 * top);
+                if ((va << shift) as i64 >> shift) as u64 != va {
+                    return Err(WalkError::NonCanonical);
+                }
+                let mut table = cr3 & 0x000f_ffff_ffff_f000;
+                let mut level = top;
+                loop {
+                    let index = (va >> (12 + 9 * (level - 1))) & 0x1ff;
+                    let e = Entry::at(p, vmcs, (table + index * 8) as usize, false)?;
+                    walked[top - level] = Some(e);
+                    let entry = e.get();
+                    if entry & PRESENT == 0 {
+                        return Err(WalkError::NotPresent { level: level as u8 });
+                    }
+                    if entry & address_reserved() != 0
+                    || (!nxe && entry & EXECUTE_DISABLE != 0)
+                    // No large pages above the PDPTEs.
+                    || (level > 3 && entry & PAGE_SIZE != 0)
+                    {
+                        return Err(WalkError::Reserved { level: level as u8 });
+                    }
+                    writable &= entry & WRITABLE != 0;
+                    user &= entry & USER != 0;
+                    executable &= entry & EXECUTE_DISABLE == 0;
+                    if level == 1 || entry & PAGE_SIZE != 0 {
+                        break (entry, level as u8);
+                    }
+                    table = entry & 0x000f_ffff_ffff_f000;
+                    level -= 1;
+                }
+            }
+        };
+
+        // The physical address of the page.
+        let (page_size, base) = match (mode, level) {
+            (_, 1) => (0x1000, entry & 0x000f_ffff_ffff_f000),
+            (PagingMode::Bits32 { .. }, _) => {
+                // PSE-36: bits 20:13 of the entry are the bits 39:32 of the address.
+                if entry & (1 << 21) != 0 {
+                    return Err(WalkError::Reserved { level });
+                }
+                (
+                    0x40_0000,
+                    (entry & 0xffc0_0000) | ((entry & 0x001f_e000) << 19),
+                )
+            }
+            (_, level) => {
+                let page_size = 1 << (12 + 9 * (level as u64 - 1));
+                let base = entry & 0x000f_ffff_ffff_f000;
+                // The bits below the page size, except PAT, are reserved.
+                if base & (page_size - 1) & !0x1000 != 0 {
+                    return Err(WalkError::Reserved { level });
+                }
+                (page_size, base & !(page_size - 1))
+            }
+        };
+        let va = if matches!(mode, PagingMode::Bits32 { .. } | PagingMode::Pae { .. }) {
+            va & 0xffff_ffff
+        } else {
+            va
+        };
+
+        // 4.6 ACCESS RIGHTS
+        if access.contains(Access::USER) && !user {
+            return Err(WalkError::Protection);
+        }
+        if access.contains(Access::WRITE) && !writable {
+            // The supervisor writes to the read-only pages are allowed if
+            // CR0.WP = 0.
+            if access.contains(Access::USER) || wp {
+                return Err(WalkError::Protection);
+            }
+        }
+        if access.contains(Access::FETCH) && !executable {
+            return Err(WalkError::Protection);
+        }
+        // 4.6.1 Determination of Access Rights: SMEP and SMAP.
+        if !access.contains(Access::USER) && user {
+            if access.contains(Access::FETCH) && smep {
+                return Err(WalkError::Protection);
+            }
+            if !access.contains(Access::FETCH) && smap && !ac {
+                return Err(WalkError::Protection);
+            }
+        }
+
+        // 4.8 ACCESSED AND DIRTY FLAGS
+        let dirty = entry & DIRTY != 0 || access.contains(Access::WRITE);
+        if update_accessed_dirty {
+            let mut walked = walked.iter().flatten().peekable();
+            while let Some(e) = walked.next() {
+                // The dirty flag is only in the entry that maps the page.
+                if walked.peek().is_none() && access.contains(Access::WRITE) {
+                    e.set(ACCESSED | DIRTY);
+                } else {
+                    e.set(ACCESSED);
+                }
+            }
+        }
+
+        Ok(Translation {
+            gpa: Gpa::new((base | (va & (page_size - 1))) as usize)
+                .ok_or(WalkError::Reserved { level })?,
+            page_size: page_size as usize,
+            writable,
+            user,
+            executable,
+            accessed: update_accessed_dirty || entry & ACCESSED != 0,
+            dirty: if update_accessed_dirty {
+                dirty
+            } else {
+                entry & DIRTY != 0
+            },
+        })
+    }
+}
@@ -0,0 +1,162 @@
+//! Static partitioning of the host.
+//!
+//! By default, the vcpus are threads of the host, which share the cpus with
+//! the other threads and are rescheduled on the timer ticks. A partitioned vm
+//! instead owns a slice of the host, in the manner of the Jailhouse cells:
+//!
+//! - Each vcpu runs on its dedicated cpu. The vcpu thread moves to the cpu
+//!   when it starts, stops the periodic tick of the cpu, and never yields it
+//!   to the scheduler until the vcpu exits or is kicked.
+//! - The guest RAM is a contiguous range of the host memory, allocated up
+//!   front.
+//! - The I/O ports of the dedicated devices are accessed by the guest
+//!   directly.
+//!
+//! The cpus and the ports are claimed exclusively until the partition is
+//! dropped. The cpu 0 is kept for the host.
+//!
+//! ```ignore
+//! let partition = Partition::new(&[2, 3])?
+//!     .ram(256 * 1024 * 1024)?
+//!     .passthrough(0x3f8..=0x3ff)?;
+//! // The vm maps its RAM to the partition RAM, and installs the
+//! // `partition.io_bitmap()` on its vcpus.
+//! let (ram, size) = partition.ram_region().unwrap();
+//! let vm = VmBuilder::new(MyVm::new(ram, size, partition.io_bitmap()), 2)?
+//!     .partition(partition)
+//!     .finalize()?;
+//! ```
+use crate::io_bitmap::IoBitmap;
+use abyss::{addressing::Pa, x86_64::intrinsics::cpuid};
+use alloc::vec::Vec;
+use core::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use keos::{mm::ContigPages, sync::SpinLock, thread::scheduler::scheduler};
+
+// Bitmask of the cpus claimed by the partitions.
+static CLAIMED_CPUS: AtomicU64 = AtomicU64::new(0);
+// The ports claimed by the partitions.
+static CLAIMED_PORTS: SpinLock<Vec<RangeInclusive<u16>>> = SpinLock::new(Vec::new());
+
+/// Error of the partitioning.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PartitionError {
+    /// The cpu does not exist or is kept for the host.
+    InvalidCpu(usize),
+    /// The cpu is claimed by another partition.
+    CpuTaken(usize),
+    /// The ports are claimed by another partition.
+    PortsTaken(RangeInclusive<u16>),
+    /// Failed to allocate the RAM.
+    OutOfMemory,
+}
+
+/// A slice of the host dedicated to a vm.
+pub struct Partition {
+    cpus: Vec<usize>,
+    ram: Option<(ContigPages, usize)>,
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Partition {
+    /// Claim the `cpus`, one for each vcpu in the order of the vcpu ids.
+    pub fn new(cpus: &[usize]) -> Result<Self, PartitionError> {
+        let mut mask = 0;
+        for &cpu in cpus {
+            if cpu == 0 || cpu >= keos::ncpu() {
+                return Err(PartitionError::InvalidCpu(cpu));
+            }
+            if mask & (1 << cpu) != 0 {
+                return Err(PartitionError::CpuTaken(cpu));
+            }
+            mask |= 1 << cpu;
+        }
+        let claimed = CLAIMED_CPUS.fetch_or(mask, Ordering::SeqCst);
+        if claimed & mask != 0 {
+            // Release the cpus claimed by this call only.
+            CLAIMED_CPUS.fetch_and(!(mask & !claimed), Ordering::SeqCst);
+            let cpu = (claimed & mask).trailing_zeros() as usize;
+            return Err(PartitionError::CpuTaken(cpu));
+        }
+        Ok(Self {
+            cpus: cpus.to_vec(),
+            ram: None,
+            ports: Vec::new(),
+        })
+    }
+
+    /// Allocate `size` bytes of the contiguous RAM.
+    pub fn ram(mut self, size: usize) -> Result<Self, PartitionError> {
+        let pages = ContigPages::new(size).ok_or(PartitionError::OutOfMemory)?;
+        self.ram = Some((pages, size));
+        Ok(self)
+    }
+
+    /// Let the guest directly access the `ports` of a dedicated device.
+    pub fn passthrough(mut self, ports: RangeInclusive<u16>) -> Result<Self, PartitionError> {
+        let mut claimed = CLAIMED_PORTS.lock();
+        if claimed
+            .iter()
+            .any(|r| r.start() <= ports.end() && ports.start() <= r.end())
+        {
+            return Err(PartitionError::PortsTaken(ports));
+        }
+        claimed.push(ports.clone());
+        self.ports.push(ports);
+        Ok(self)
+    }
+
+    /// Get the dedicated cpu of the `vcpu`.
+    pub fn cpu(&self, vcpu: usize) -> Option<usize> {
+        self.cpus.get(vcpu).copied()
+    }
+
+    /// Get the number of the dedicated cpus.
+    pub fn cpus(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Get the host physical address and the size of the RAM.
+    pub fn ram_region(&self) -> Option<(Pa, usize)> {
+        self.ram.as_ref().map(|(pages, size)| (pages.pa(), *size))
+    }
+
+    /// Get the I/O bitmap that traps all ports but the dedicated ones.
+    pub fn io_bitmap(&self) -> IoBitmap {
+        self.ports
+            .iter()
+            .fold(IoBitmap::new(), |bitmap, ports| bitmap.allow(ports.clone()))
+    }
+}
+
+impl Drop for Partition {
+    fn drop(&mut self) {
+        let mask = self.cpus.iter().fold(0, |mask, cpu| mask | (1 << cpu));
+        CLAIMED_CPUS.fetch_and(!mask, Ordering::SeqCst);
+        CLAIMED_PORTS
+            .lock()
+            .retain(|ports| !self.ports.contains(ports));
+    }
+}
+
+/// Move the current thread to the `cpu`, and stop the periodic tick of the cpu
+/// so that the thread is never preempted.
+///
+/// The thread must be pinned, so that it stays on the cpu after the move.
+pub(crate) fn enter(cpu: usize) {
+    while cpuid() != cpu {
+        scheduler().reschedule();
+    }
+    unsafe {
+        abyss::dev::x86_64::timer::stop_tick(None);
+    }
+}
+
+/// Restart the periodic tick of the cpu, before yielding it to the host.
+pub(crate) fn leave() {
+    unsafe {
+        abyss::dev::x86_64::timer::set_tsc_timer();
+    }
+}
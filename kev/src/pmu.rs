@@ -0,0 +1,431 @@
+//! Virtualization of the performance monitoring unit.
+//!
+//! Each vcpu owns a virtual PMU that mirrors the architectural performance
+//! monitoring of the host: the general-purpose counters (IA32_PMCx and
+//! IA32_PERFEVTSELx), the fixed-function counters (IA32_FIXED_CTRx and
+//! IA32_FIXED_CTR_CTRL) and the global controls. The guest programs them
+//! with wrmsr, which kev intercepts, and reads the counters with either
+//! rdmsr or rdpmc.
+//!
+//! The host PMU counters are lent to the vcpu while it runs: the state of
+//! the virtual PMU is loaded into the hardware when the vcpu is activated on
+//! a core, and saved back when the vcpu is deactivated. If the processor
+//! supports it, IA32_PERF_GLOBAL_CTRL is switched on VM entries and exits, so
+//! the counters only count the events of the guest. As the counters hold the
+//! values of the guest while it runs, rdpmc is executed without a vmexit.
+//!
+//! The counts of the events that the guests measured are accumulated per vm,
+//! and can be read with [`VmHandle::pmu_counts`].
+//!
+//! Interrupts on the counter overflows are not delivered to the guest.
+//!
+//! The last general-purpose counter may be taken from the guest for the
+//! instruction count of kev; see [`icount`](crate::icount).
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 20.2 Architectural Performance Monitoring.
+//!
+//! [`VmHandle::pmu_counts`]: crate::vm::VmHandle::pmu_counts
+use crate::{
+    vcpu::GenericVCpuState,
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use abyss::x86_64::msr::{rdmsr, wrmsr};
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_FIXED_CTR0: u32 = 0x309;
+const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const MAX_GP_COUNTERS: usize = 8;
+const MAX_FIXED_COUNTERS: usize = 3;
+
+// Bits of the IA32_PERFEVTSELx.
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+// PMI enables of the IA32_FIXED_CTR_CTRL.
+const FIXED_CTRL_PMI: u64 = (1 << 3) | (1 << 7) | (1 << 11);
+// Table 20-1. UMask and Event Select Encodings for Pre-Defined Architectural
+// Performance Events.
+const EVENT_MASK: u64 = 0xffff;
+const INSTRUCTIONS_RETIRED: u64 = 0x00c0;
+const UNHALTED_CORE_CYCLES: u64 = 0x003c;
+const LLC_MISSES: u64 = 0x412e;
+
+/// Capabilities of the architectural performance monitoring.
+#[derive(Clone, Copy, Debug)]
+pub struct PmuInfo {
+    /// Version of the architectural performance monitoring.
+    pub version: u8,
+    /// Number of the general-purpose counters.
+    pub gp_counters: usize,
+    /// Bit width of the general-purpose counters.
+    pub gp_width: u8,
+    /// Number of the fixed-function counters.
+    pub fixed_counters: usize,
+    /// Bit width of the fixed-function counters.
+    pub fixed_width: u8,
+}
+
+/// Probe the architectural performance monitoring of the processor.
+///
+/// Returns `None` if the processor does not support it.
+pub fn info() -> Option<PmuInfo> {
+    // CPUID.0AH: Architectural Performance Monitoring Leaf.
+    if unsafe { __cpuid(0) }.eax < 0xa {
+        return None;
+    }
+    let r = unsafe { __cpuid(0xa) };
+    let version = r.eax as u8;
+    if version == 0 {
+        return None;
+    }
+    Some(PmuInfo {
+        version,
+        gp_counters: ((r.eax >> 8) as u8 as usize).min(MAX_GP_COUNTERS),
+        gp_width: (r.eax >> 16) as u8,
+        fixed_counters: if version > 1 {
+            ((r.edx & 0x1f) as usize).min(MAX_FIXED_COUNTERS)
+        } else {
+            0
+        },
+        fixed_width: (r.edx >> 5) as u8,
+    })
+}
+
+/// Counts of the events that the guest measured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PmuCounts {
+    /// Instructions retired.
+    pub instructions: u64,
+    /// Unhalted core cycles.
+    pub cycles: u64,
+    /// Last level cache misses.
+    pub llc_misses: u64,
+}
+
+impl PmuCounts {
+    fn add(&mut self, event: u64, delta: u64) {
+        match event {
+            INSTRUCTIONS_RETIRED => self.instructions += delta,
+            UNHALTED_CORE_CYCLES => self.cycles += delta,
+            LLC_MISSES => self.llc_misses += delta,
+            _ => (),
+        }
+    }
+}
+
+/// Per-vm accumulator of the [`PmuCounts`].
+#[derive(Default)]
+pub struct PmuStats {
+    instructions: AtomicU64,
+    cycles: AtomicU64,
+    llc_misses: AtomicU64,
+}
+
+impl PmuStats {
+    pub(crate) fn add(&self, counts: &PmuCounts) {
+        self.instructions
+            .fetch_add(counts.instructions, Ordering::Relaxed);
+        self.cycles.fetch_add(counts.cycles, Ordering::Relaxed);
+        self.llc_misses
+            .fetch_add(counts.llc_misses, Ordering::Relaxed);
+    }
+
+    /// Get the accumulated counts.
+    pub fn get(&self) -> PmuCounts {
+        PmuCounts {
+            instructions: self.instructions.load(Ordering::Relaxed),
+            cycles: self.cycles.load(Ordering::Relaxed),
+            llc_misses: self.llc_misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Virtual performance monitoring unit of a vcpu.
+pub struct VPmu {
+    info: Option<PmuInfo>,
+    evtsel: [u64; MAX_GP_COUNTERS],
+    pmc: [u64; MAX_GP_COUNTERS],
+    fixed_ctrl: u64,
+    fixed: [u64; MAX_FIXED_COUNTERS],
+    global_ctrl: u64,
+    // Whether IA32_PERF_GLOBAL_CTRL is switched by the vm entries and exits.
+    pub(crate) switch_global_ctrl: bool,
+    // Whether the state is loaded on the hardware.
+    loaded: bool,
+    // Whether the last general-purpose counter is taken from the guest.
+    reserved: bool,
+    // Counts not yet accumulated to the vm.
+    pending: PmuCounts,
+}
+
+impl VPmu {
+    pub(crate) fn new() -> Self {
+        let info = info();
+        let gp = info.map(|i| i.gp_counters).unwrap_or(0);
+        let fixed = info.map(|i| i.fixed_counters).unwrap_or(0);
+        Self {
+            info,
+            evtsel: [0; MAX_GP_COUNTERS],
+            pmc: [0; MAX_GP_COUNTERS],
+            fixed_ctrl: 0,
+            fixed: [0; MAX_FIXED_COUNTERS],
+            // On reset, the counters that exist are enabled globally.
+            global_ctrl: ((1 << gp) - 1) | (((1 << fixed) - 1) << 32),
+            switch_global_ctrl: false,
+            loaded: false,
+            reserved: false,
+            pending: PmuCounts::default(),
+        }
+    }
+
+    // Get the number of the general-purpose counters of the guest.
+    #[inline]
+    fn gp_counters(&self) -> usize {
+        self.info.map(|i| i.gp_counters).unwrap_or(0) - self.reserved as usize
+    }
+
+    /// Take the last general-purpose counter from the guest, and returns its
+    /// index.
+    ///
+    /// The counter must count only the events of the guest, so this fails if
+    /// IA32_PERF_GLOBAL_CTRL is not switched on the vm entries and exits.
+    pub(crate) fn reserve_counter(&mut self) -> Option<usize> {
+        if self.reserved || self.loaded || !self.switch_global_ctrl || self.gp_counters() == 0 {
+            return None;
+        }
+        self.reserved = true;
+        Some(self.gp_counters())
+    }
+
+    /// Give the counter taken by [`VPmu::reserve_counter`] back to the guest.
+    ///
+    /// The counter of the guest is restored on the next load.
+    pub(crate) fn release_counter(&mut self) {
+        if !self.loaded {
+            self.reserved = false;
+        }
+    }
+
+    /// Check whether `msr` is the register of the virtual PMU.
+    pub fn handles(&self, msr: u32) -> bool {
+        let Some(info) = self.info else {
+            return false;
+        };
+        let (gp, fixed) = (self.gp_counters() as u32, info.fixed_counters as u32);
+        match msr {
+            IA32_PMC0..=0xc8 => msr - IA32_PMC0 < gp,
+            IA32_PERFEVTSEL0..=0x18d => msr - IA32_PERFEVTSEL0 < gp,
+            IA32_FIXED_CTR0..=0x30b => msr - IA32_FIXED_CTR0 < fixed,
+            IA32_FIXED_CTR_CTRL
+            | IA32_PERF_GLOBAL_STATUS
+            | IA32_PERF_GLOBAL_CTRL
+            | IA32_PERF_GLOBAL_OVF_CTRL => info.version > 1,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn gp_mask(&self) -> u64 {
+        self.info
+            .map(|i| u64::MAX >> (64 - i.gp_width.clamp(1, 64)))
+            .unwrap_or(0)
+    }
+
+    #[inline]
+    fn fixed_mask(&self) -> u64 {
+        self.info
+            .map(|i| u64::MAX >> (64 - i.fixed_width.clamp(1, 64)))
+            .unwrap_or(0)
+    }
+
+    // Account the events counted on the general-purpose counter `i` since the
+    // last sync.
+    fn sync_gp(&mut self, i: usize) {
+        let now = rdmsr(IA32_PMC0 + i as u32) & self.gp_mask();
+        let delta = now.wrapping_sub(self.pmc[i]) & self.gp_mask();
+        self.pending.add(self.evtsel[i] & EVENT_MASK, delta);
+        self.pmc[i] = now;
+    }
+
+    // Account the events counted on the fixed-function counter `i` since the
+    // last sync.
+    fn sync_fixed(&mut self, i: usize) {
+        const EVENTS: [u64; MAX_FIXED_COUNTERS] = [INSTRUCTIONS_RETIRED, UNHALTED_CORE_CYCLES, 0];
+        let now = rdmsr(IA32_FIXED_CTR0 + i as u32) & self.fixed_mask();
+        let delta = now.wrapping_sub(self.fixed[i]) & self.fixed_mask();
+        self.pending.add(EVENTS[i], delta);
+        self.fixed[i] = now;
+    }
+
+    fn write_global_ctrl(&self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        if self.switch_global_ctrl {
+            vmcs.write(Field::GuestIa32PerfGlobalCtrl, self.global_ctrl)
+        } else {
+            unsafe { wrmsr(IA32_PERF_GLOBAL_CTRL, self.global_ctrl) };
+            Ok(())
+        }
+    }
+
+    /// Load the state of the virtual PMU on the hardware.
+    pub(crate) fn load(&mut self, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        let Some(info) = self.info else {
+            return Ok(());
+        };
+        unsafe {
+            if info.version > 1 {
+                wrmsr(IA32_PERF_GLOBAL_CTRL, 0);
+            }
+            for i in 0..self.gp_counters() {
+                wrmsr(IA32_PERFEVTSEL0 + i as u32, self.evtsel[i] & !EVTSEL_INT);
+                wrmsr(IA32_PMC0 + i as u32, self.pmc[i]);
+            }
+            if info.version > 1 {
+                wrmsr(IA32_FIXED_CTR_CTRL, self.fixed_ctrl & !FIXED_CTRL_PMI);
+                for i in 0..info.fixed_counters {
+                    wrmsr(IA32_FIXED_CTR0 + i as u32, self.fixed[i]);
+                }
+                self.write_global_ctrl(vmcs)?;
+            }
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    /// Save the state of the virtual PMU from the hardware.
+    ///
+    /// Returns the counts measured since the last save.
+    pub(crate) fn save(&mut self) -> PmuCounts {
+        let Some(info) = self.info.filter(|_| self.loaded) else {
+            return PmuCounts::default();
+        };
+        self.loaded = false;
+        unsafe {
+            if info.version > 1 {
+                wrmsr(IA32_PERF_GLOBAL_CTRL, 0);
+            }
+            for i in 0..self.gp_counters() {
+                self.sync_gp(i);
+                wrmsr(IA32_PERFEVTSEL0 + i as u32, 0);
+            }
+            for i in 0..info.fixed_counters {
+                self.sync_fixed(i);
+            }
+            if info.version > 1 {
+                wrmsr(IA32_FIXED_CTR_CTRL, 0);
+            }
+        }
+        core::mem::take(&mut self.pending)
+    }
+
+    fn rdmsr(&mut self, msr: u32) -> u64 {
+        match msr {
+            IA32_PERFEVTSEL0..=0x18d => self.evtsel[(msr - IA32_PERFEVTSEL0) as usize],
+            IA32_FIXED_CTR_CTRL => self.fixed_ctrl,
+            IA32_PERF_GLOBAL_CTRL => self.global_ctrl,
+            IA32_PERF_GLOBAL_OVF_CTRL => 0,
+            // Counters and the status are live on the hardware.
+            _ => rdmsr(msr),
+        }
+    }
+
+    fn wrmsr(&mut self, msr: u32, value: u64, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        unsafe {
+            match msr {
+                IA32_PMC0..=0xc8 => {
+                    let i = (msr - IA32_PMC0) as usize;
+                    self.sync_gp(i);
+                    self.pmc[i] = value & self.gp_mask();
+                    wrmsr(msr, self.pmc[i]);
+                }
+                IA32_PERFEVTSEL0..=0x18d => {
+                    let i = (msr - IA32_PERFEVTSEL0) as usize;
+                    self.sync_gp(i);
+                    self.evtsel[i] = value;
+                    wrmsr(msr, value & !EVTSEL_INT);
+                }
+                IA32_FIXED_CTR0..=0x30b => {
+                    let i = (msr - IA32_FIXED_CTR0) as usize;
+                    self.sync_fixed(i);
+                    self.fixed[i] = value & self.fixed_mask();
+                    wrmsr(msr, self.fixed[i]);
+                }
+                IA32_FIXED_CTR_CTRL => {
+                    for i in 0..self.info.map(|i| i.fixed_counters).unwrap_or(0) {
+                        self.sync_fixed(i);
+                    }
+                    self.fixed_ctrl = value;
+                    wrmsr(msr, value & !FIXED_CTRL_PMI);
+                }
+                IA32_PERF_GLOBAL_CTRL => {
+                    self.global_ctrl = value;
+                    self.write_global_ctrl(vmcs)?;
+                }
+                IA32_PERF_GLOBAL_OVF_CTRL => wrmsr(msr, value),
+                // IA32_PERF_GLOBAL_STATUS is read-only.
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Emulate the rdmsr or wrmsr on the register of the virtual PMU.
+    pub(crate) fn emulate(
+        &mut self,
+        write: bool,
+        GenericVCpuState { vmcs, gprs, .. }: &mut GenericVCpuState,
+    ) -> Result<(), VmError> {
+        let msr = gprs.rcx as u32;
+        if write {
+            let value = ((gprs.rdx as u64) << 32) | (gprs.rax as u32 as u64);
+            self.wrmsr(msr, value, vmcs)?;
+        } else {
+            let value = self.rdmsr(msr);
+            gprs.rax = value as u32 as usize;
+            gprs.rdx = (value >> 32) as usize;
+        }
+        vmcs.forward_rip()
+    }
+}
+
+/// Start the general-purpose counter `i` to count the instructions retired
+/// by the guest, interrupting on the overflow after the `count` instructions.
+///
+/// The `count` is at most `i32::MAX`, as the counter is written with the low
+/// 32 bits sign-extended. Returns the started value of the counter.
+pub(crate) fn start_instruction_counter(i: usize, count: u64) -> u64 {
+    let count = count.clamp(1, i32::MAX as u64) as i64;
+    unsafe {
+        wrmsr(IA32_PERFEVTSEL0 + i as u32, 0);
+        wrmsr(IA32_PMC0 + i as u32, (-count) as u32 as u64);
+        wrmsr(
+            IA32_PERFEVTSEL0 + i as u32,
+            INSTRUCTIONS_RETIRED | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN,
+        );
+    }
+    rdmsr(IA32_PMC0 + i as u32)
+}
+
+/// Stop the general-purpose counter `i` started at the `start`, clearing its
+/// overflow, and returns the number of the counted instructions.
+pub(crate) fn stop_counter(i: usize, start: u64) -> u64 {
+    unsafe {
+        wrmsr(IA32_PERFEVTSEL0 + i as u32, 0);
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << i);
+    }
+    let mask = info()
+        .map(|i| u64::MAX >> (64 - i.gp_width.clamp(1, 64)))
+        .unwrap_or(0);
+    rdmsr(IA32_PMC0 + i as u32).wrapping_sub(start) & mask
+}
@@ -1,4 +1,5 @@
 use crate::{
+    page_walk::{Access, PagingContext, WalkError},
     vm::{Gpa, Gva},
     vmcs::ActiveVmcs,
 };
@@ -20,4 +21,17 @@ pub trait Probe {
     fn gva2hva(&self, vmcs: &ActiveVmcs, gva: Gva) -> Option<Va> {
         self.gva2hpa(vmcs, gva).map(|pa| pa.into_va())
     }
+    /// Translate guest virtual address of the `access` to host physical
+    /// address, as the cpu does.
+    ///
+    /// Unlike [`gva2hpa`], this checks the permissions and updates the
+    /// accessed and dirty flags of the guest page tables. See
+    /// [`page_walk`](crate::page_walk).
+    ///
+    /// [`gva2hpa`]: Self::gva2hpa
+    fn translate(&self, vmcs: &ActiveVmcs, gva: Gva, access: Access) -> Result<Pa, WalkError> {
+        let translation = PagingContext::current(vmcs)?.walk(self, vmcs, gva, access)?;
+        self.gpa2hpa(vmcs, translation.gpa)
+            .ok_or(WalkError::Unmapped(translation.gpa))
+    }
 }
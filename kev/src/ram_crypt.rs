@@ -0,0 +1,196 @@
+//! Encryption of the guest RAM at rest.
+//!
+//! The guest RAM holds the secrets of the guest, which must not leak when the
+//! RAM leaves the host memory, e.g. into a [`core_dump`] on the disk. The host
+//! gives a vm its own [`RamKey`] with [`VmBuilder::encrypt_ram`], and the RAM
+//! is encrypted with AES-128 in the counter mode before it is written out:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?
+//!     .encrypt_ram(RamKey::new(*b"0123456789abcdef"))
+//!     .finalize()?;
+//! ```
+//!
+//! Each output is encrypted with a fresh nonce, which is stored in the clear
+//! next to the ciphertext. The counter block of the 16 bytes at the offset
+//! `ofs` of the output is the little-endian nonce followed by the
+//! little-endian `ofs / 16`, so a page is encrypted independently of the
+//! others at its offset, and the decryption is the same operation.
+//!
+//! AES is implemented in software, as the host does not preserve the sse
+//! registers across the threads and thus can not use AES-NI. The lookup of
+//! the s-box is not constant-time; the key is protected from the guest, not
+//! from the other tenants of the host cpus.
+//!
+//! [`core_dump`]: crate::core_dump
+//! [`VmBuilder::encrypt_ram`]: crate::vm::VmBuilder::encrypt_ram
+use crate::vm::Uuid;
+
+/// A per-vm key of the RAM encryption.
+///
+/// The key is wiped from the memory on drop.
+#[derive(Clone)]
+pub struct RamKey([u8; 16]);
+
+impl RamKey {
+    /// Create a key from the 16 bytes of the AES-128 key.
+    pub const fn new(key: [u8; 16]) -> Self {
+        Self(key)
+    }
+}
+
+impl core::fmt::Debug for RamKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "RamKey(..)")
+    }
+}
+
+impl Drop for RamKey {
+    fn drop(&mut self) {
+        for b in self.0.iter_mut() {
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+/// Cipher of an output of the guest RAM.
+pub struct RamCipher {
+    round_keys: [[u8; 16]; 11],
+    nonce: u64,
+}
+
+impl RamCipher {
+    /// Create a cipher of the `key` with a fresh nonce.
+    pub fn new(key: &RamKey) -> Self {
+        let uuid = Uuid::generate();
+        let nonce = u64::from_le_bytes(uuid.as_bytes()[8..].try_into().unwrap());
+        Self::with_nonce(key, nonce)
+    }
+
+    /// Create a cipher of the `key` with the `nonce` of an output, to decrypt
+    /// it.
+    pub fn with_nonce(key: &RamKey, nonce: u64) -> Self {
+        Self {
+            round_keys: expand_key(&key.0),
+            nonce,
+        }
+    }
+
+    /// Get the nonce of the cipher.
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Encrypt or decrypt the `buf` at the offset `ofs` of the output.
+    pub fn apply(&self, ofs: u64, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = ofs + done as u64;
+            let mut stream = [0; 16];
+            stream[..8].copy_from_slice(&self.nonce.to_le_bytes());
+            stream[8..].copy_from_slice(&(pos / 16).to_le_bytes());
+            self.encrypt_block(&mut stream);
+            // The first block may start in the middle of the counter block.
+            let skip = (pos % 16) as usize;
+            let len = (16 - skip).min(buf.len() - done);
+            for (b, s) in buf[done..done + len].iter_mut().zip(&stream[skip..]) {
+                *b ^= s;
+            }
+            done += len;
+        }
+    }
+
+    // Encrypt a block with AES-128.
+    //
+    // See FIPS 197, 5.1 Cipher.
+    fn encrypt_block(&self, state: &mut [u8; 16]) {
+        add_round_key(state, &self.round_keys[0]);
+        for round in 1..11 {
+            for b in state.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(state);
+            if round != 10 {
+                mix_columns(state);
+            }
+            add_round_key(state, &self.round_keys[round]);
+        }
+    }
+}
+
+impl Drop for RamCipher {
+    fn drop(&mut self) {
+        for b in self.round_keys.iter_mut().flatten() {
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+// See FIPS 197, 5.2 Key Expansion.
+fn expand_key(key: &[u8; 16]) -> [[u8; 16]; 11] {
+    const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+    let mut round_keys = [[0; 16]; 11];
+    round_keys[0] = *key;
+    for i in 1..11 {
+        let prev = round_keys[i - 1];
+        let mut word = [prev[13], prev[14], prev[15], prev[12]];
+        for b in word.iter_mut() {
+            *b = SBOX[*b as usize];
+        }
+        word[0] ^= RCON[i - 1];
+        for j in 0..16 {
+            let b = if j < 4 { word[j] } else { round_keys[i][j - 4] };
+            round_keys[i][j] = prev[j] ^ b;
+        }
+    }
+    round_keys
+}
+
+fn add_round_key(state: &mut [u8; 16], key: &[u8; 16]) {
+    for (b, k) in state.iter_mut().zip(key.iter()) {
+        *b ^= k;
+    }
+}
+
+// The state is in the column-major order.
+fn shift_rows(state: &mut [u8; 16]) {
+    let s = *state;
+    for row in 1..4 {
+        for col in 0..4 {
+            state[col * 4 + row] = s[((col + row) % 4) * 4 + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    // Multiply by x in GF(2^8).
+    let xtime = |b: u8| (b << 1) ^ (((b >> 7) & 1) * 0x1b);
+    for col in state.chunks_mut(4) {
+        let [a0, a1, a2, a3] = [col[0], col[1], col[2], col[3]];
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        col[0] ^= all ^ xtime(a0 ^ a1);
+        col[1] ^= all ^ xtime(a1 ^ a2);
+        col[2] ^= all ^ xtime(a2 ^ a3);
+        col[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
+
+#[rustfmt::skip]
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
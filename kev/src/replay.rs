@@ -0,0 +1,338 @@
+//! Deterministic record and replay of the vcpu execution.
+//!
+//! The execution of a vcpu is deterministic except for its inputs: the
+//! results of the emulated instructions (e.g. the value read from a port, or
+//! the return value of a hypercall), the time-stamp counter, and the points
+//! where the interrupts are injected. In the record mode, kev logs these
+//! inputs to a [`Log`]. In the replay mode, kev feeds the logged inputs back,
+//! so the vcpu follows the same execution path.
+//!
+//! The inputs are logged as follows:
+//! - After a vmexit is handled by the controllers, the general purpose
+//!   registers and the rip of the vcpu are logged. On the replay, the vmexit
+//!   is handled again (so the outputs such as the console are reproduced) and
+//!   the logged state overrides the state of the vcpu.
+//! - RDTSC and RDTSCP cause vmexits, and their results are logged.
+//! - Interrupts are only injected on the VM entries that follow a synchronous
+//!   vmexit (i.e. not an external interrupt), so the injection point is
+//!   identified by the position in the log. A pending interrupt waits for the
+//!   next synchronous vmexit with the interrupts enabled, instead of opening
+//!   the interrupt window (e.g. until the guest executes HLT).
+//!
+//! The inputs that the devices write to the guest memory directly (e.g. the
+//! DMA of the virtio devices) are not logged. Each vcpu has its own log, and
+//! the order of the shared memory accesses between the vcpus is not logged,
+//! so only the single vcpu execution is reproducible.
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?.record().finalize()?;
+//! let vcpu = vm.vcpu(0).unwrap().clone();
+//! vm.start_bsp()?;
+//! vm.join();
+//! let log = vcpu.lock().take_replay_log().unwrap();
+//! log.save(&file)?;
+//!
+//! let vm = VmBuilder::new(state, 1)?.replay(vec![Log::load(&file)?]).finalize()?;
+//! ```
+use crate::{vcpu::GenericVCpuState, vm_control::VmcsProcBasedVmexecCtl, vmcs::Field, VmError};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::arch::x86_64::__rdtscp;
+use keos::fs::{Error, File};
+
+const TAG_EXIT: u8 = 1;
+const TAG_INTERRUPT: u8 = 2;
+const TAG_TSC: u8 = 3;
+
+/// A logged input of the vcpu.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A synchronous vmexit is handled.
+    Exit {
+        /// Rip after the vmexit is handled.
+        rip: u64,
+        /// General purpose registers after the vmexit is handled, in the
+        /// order from rax to r15.
+        gprs: [u64; 15],
+    },
+    /// An interrupt is injected on the next VM entry.
+    Interrupt {
+        /// Vector of the interrupt.
+        vector: u8,
+    },
+    /// RDTSC or RDTSCP is executed.
+    Tsc {
+        /// The time-stamp counter.
+        value: u64,
+        /// IA32_TSC_AUX that RDTSCP reads.
+        aux: u32,
+    },
+}
+
+impl Event {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Event::Exit { rip, gprs } => {
+                out.push(TAG_EXIT);
+                out.extend_from_slice(&rip.to_le_bytes());
+                for r in gprs {
+                    out.extend_from_slice(&r.to_le_bytes());
+                }
+            }
+            Event::Interrupt { vector } => {
+                out.push(TAG_INTERRUPT);
+                out.push(*vector);
+            }
+            Event::Tsc { value, aux } => {
+                out.push(TAG_TSC);
+                out.extend_from_slice(&value.to_le_bytes());
+                out.extend_from_slice(&aux.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(b: &[u8]) -> Option<(Self, usize)> {
+        let u64_at = |ofs: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(b.get(ofs..ofs + 8)?.try_into().ok()?))
+        };
+        match *b.first()? {
+            TAG_EXIT => {
+                let mut gprs = [0; 15];
+                for (i, r) in gprs.iter_mut().enumerate() {
+                    *r = u64_at(9 + i * 8)?;
+                }
+                Some((
+                    Event::Exit {
+                        rip: u64_at(1)?,
+                        gprs,
+                    },
+                    9 + 15 * 8,
+                ))
+            }
+            TAG_INTERRUPT => Some((Event::Interrupt { vector: *b.get(1)? }, 2)),
+            TAG_TSC => Some((
+                Event::Tsc {
+                    value: u64_at(1)?,
+                    aux: u32::from_le_bytes(b.get(9..13)?.try_into().ok()?),
+                },
+                13,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The log of the inputs of a vcpu.
+#[derive(Clone, Debug, Default)]
+pub struct Log {
+    events: VecDeque<Event>,
+}
+
+impl Log {
+    /// Create a new empty log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of the events in the log.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Check whether the log is empty.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Iterate over the events.
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    /// Serialize the log.
+    ///
+    /// The log starts with the 8-byte length of the following events.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = alloc::vec![0; 8];
+        for e in self.events.iter() {
+            e.encode(&mut out);
+        }
+        let len = (out.len() - 8) as u64;
+        out[..8].copy_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    /// Deserialize the log.
+    ///
+    /// Returns `None` if `b` is malformed.
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        let len = u64::from_le_bytes(b.get(..8)?.try_into().ok()?) as usize;
+        let mut b = b.get(8..8 + len)?;
+        let mut events = VecDeque::new();
+        while !b.is_empty() {
+            let (e, size) = Event::decode(b)?;
+            events.push_back(e);
+            b = &b[size..];
+        }
+        Some(Self { events })
+    }
+
+    /// Save the log to the `file`.
+    ///
+    /// As the file is fixed-size, fails if the log does not fit in the file.
+    pub fn save(&self, file: &File) -> Result<(), Error> {
+        let b = self.to_bytes();
+        if b.len() > file.size() {
+            return Err(Error::FsError);
+        }
+        file.write(0, &b).map(|_| ())
+    }
+
+    /// Load the log from the `file`.
+    pub fn load(file: &File) -> Result<Self, Error> {
+        let mut b = alloc::vec![0; file.size()];
+        file.read(0, &mut b)?;
+        Self::from_bytes(&b).ok_or(Error::FsError)
+    }
+}
+
+/// Record and replay state of a vcpu.
+pub(crate) struct Replay {
+    log: Log,
+    replaying: bool,
+    // Whether the last vmexit is synchronous.
+    synchronous: bool,
+}
+
+fn diverged() -> VmError {
+    VmError::ControllerError(Box::new("Replay diverged from the log."))
+}
+
+impl Replay {
+    pub(crate) fn recording() -> Self {
+        Self {
+            log: Log::new(),
+            replaying: false,
+            synchronous: true,
+        }
+    }
+
+    pub(crate) fn replaying(log: Log) -> Self {
+        Self {
+            log,
+            replaying: true,
+            synchronous: true,
+        }
+    }
+
+    pub(crate) fn into_log(self) -> Log {
+        self.log
+    }
+
+    /// Whether an interrupt can be injected from the pending interrupts.
+    pub(crate) fn can_inject(&self) -> bool {
+        !self.replaying && self.synchronous
+    }
+
+    pub(crate) fn on_vmexit(&mut self, synchronous: bool) {
+        self.synchronous = synchronous;
+    }
+
+    /// Log the injected interrupt.
+    ///
+    /// At most one interrupt is injected after each synchronous vmexit.
+    pub(crate) fn record_interrupt(&mut self, vector: u8) {
+        self.synchronous = false;
+        self.log.events.push_back(Event::Interrupt { vector });
+    }
+
+    /// Get the interrupt to inject on this VM entry.
+    pub(crate) fn replay_interrupt(&mut self) -> Option<u8> {
+        match self.log.events.front() {
+            Some(Event::Interrupt { vector }) if self.replaying && self.synchronous => {
+                let vector = *vector;
+                self.synchronous = false;
+                self.log.events.pop_front();
+                Some(vector)
+            }
+            _ => None,
+        }
+    }
+
+    /// Emulate RDTSC or RDTSCP with the logged result.
+    pub(crate) fn emulate_tsc(
+        &mut self,
+        rdtscp: bool,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<(), VmError> {
+        let GenericVCpuState { vmcs, gprs, .. } = generic_vcpu_state;
+        let (value, aux) = if self.replaying {
+            match self.log.events.pop_front() {
+                Some(Event::Tsc { value, aux }) => (value, aux),
+                _ => return Err(diverged()),
+            }
+        } else {
+            let mut aux = 0;
+            let mut value = unsafe { __rdtscp(&mut aux) };
+            // The guest observes the counter with the offset applied.
+            let ctls = VmcsProcBasedVmexecCtl::from_bits_truncate(
+                vmcs.read(Field::ProcessorBasedVmexecControls)? as u32,
+            );
+            if ctls.contains(VmcsProcBasedVmexecCtl::USETSCOFF) {
+                value = value.wrapping_add(vmcs.read(Field::TscOffset)?);
+            }
+            self.log.events.push_back(Event::Tsc { value, aux });
+            (value, aux)
+        };
+        gprs.rax = value as u32 as usize;
+        gprs.rdx = (value >> 32) as usize;
+        if rdtscp {
+            gprs.rcx = aux as usize;
+        }
+        vmcs.forward_rip()
+    }
+
+    /// Log or replay the state after the vmexit is handled.
+    pub(crate) fn exit(
+        &mut self,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<(), VmError> {
+        let GenericVCpuState { vmcs, gprs, .. } = generic_vcpu_state;
+        let regs = [
+            &mut gprs.rax,
+            &mut gprs.rbx,
+            &mut gprs.rcx,
+            &mut gprs.rdx,
+            &mut gprs.rbp,
+            &mut gprs.rdi,
+            &mut gprs.rsi,
+            &mut gprs.r8,
+            &mut gprs.r9,
+            &mut gprs.r10,
+            &mut gprs.r11,
+            &mut gprs.r12,
+            &mut gprs.r13,
+            &mut gprs.r14,
+            &mut gprs.r15,
+        ];
+        if self.replaying {
+            match self.log.events.pop_front() {
+                Some(Event::Exit {
+                    rip: logged_rip,
+                    gprs: logged,
+                }) => {
+                    for (r, v) in regs.into_iter().zip(logged) {
+                        *r = v as usize;
+                    }
+                    vmcs.write(Field::GuestRip, logged_rip)
+                }
+                _ => Err(diverged()),
+            }
+        } else {
+            self.log.events.push_back(Event::Exit {
+                rip: vmcs.read(Field::GuestRip)?,
+                gprs: regs.map(|r| *r as u64),
+            });
+            Ok(())
+        }
+    }
+}
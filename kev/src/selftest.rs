@@ -0,0 +1,591 @@
+//! Self-tests of the VMX setup.
+//!
+//! A failed [`start_vmx_on_cpu`] or vm entry tells little about the cause,
+//! which is often the environment: VT-x disabled in the BIOS, a nested
+//! hypervisor that lacks a control, or a control register that the host left
+//! in a state not allowed in the VMX operation. [`run`] validates the
+//! configuration of the host and a sample VMCS against the rules of the SDM,
+//! and reports each violation with the section that states the rule:
+//!
+//! ```ignore
+//! unsafe { kev::start_vmx_on_cpu().expect("Failed to initialize VMX.") };
+//! let report = kev::selftest::run();
+//! if !report.is_ok() {
+//!     println!("{}", report);
+//! }
+//! ```
+//!
+//! [`check_vmcs`] checks a VMCS of a vm in the same way, e.g. after its vm
+//! entry failed. The checks cover the common rules of the SDM, not all of
+//! them; a VMCS that passes may still fail the vm entry.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 23 Introduction to Virtual Machine Extensions, 26.2 Checks on VMX Controls
+//! and Host-State Area and 26.3 Checking and Loading Guest State.
+//!
+//! [`start_vmx_on_cpu`]: crate::start_vmx_on_cpu
+use crate::{
+    caps::{self, EptVpidCap, VmxCaps},
+    entry_state::GuestEntryState,
+    testing::MockVmcs,
+    vm::Gpa,
+    vm_control::*,
+    vmcs::{ActiveVmcs, Field},
+    Bits, VmError,
+};
+use abyss::x86_64::{intrinsics::read_cr3, msr::Msr, segmentation::Segment, Cr0, Cr4, Rflags};
+use alloc::{format, string::String, vec::Vec};
+
+/// A violated rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The section of the SDM that states the rule.
+    pub section: &'static str,
+    /// What violates the rule.
+    pub detail: String,
+}
+
+/// Result of the self-tests.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// Number of the checked rules.
+    pub checks: usize,
+    /// The violated rules.
+    pub violations: Vec<Violation>,
+}
+
+impl Report {
+    /// Check whether no rule is violated.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    fn check(&mut self, section: &'static str, ok: bool, detail: impl FnOnce() -> String) {
+        self.checks += 1;
+        if !ok {
+            self.violations.push(Violation {
+                section,
+                detail: detail(),
+            });
+        }
+    }
+
+    // Check that the `value` of `name` sets the bits that must be 1 in
+    // `fixed0` and clears the bits that must be 0 in `fixed1`, except the
+    // `exempt` bits.
+    fn check_fixed(
+        &mut self,
+        section: &'static str,
+        name: &str,
+        value: u64,
+        (fixed0, fixed1): (u64, u64),
+        exempt: u64,
+    ) {
+        let (must1, must0) = (fixed0 & !exempt & !value, value & !fixed1 & !exempt);
+        self.check(section, must1 == 0, || {
+            format!("{} ({:#x}) must set the bits {:#x}", name, value, must1)
+        });
+        self.check(section, must0 == 0, || {
+            format!("{} ({:#x}) must clear the bits {:#x}", name, value, must0)
+        });
+    }
+
+    fn merge(&mut self, other: Report) {
+        self.checks += other.checks;
+        self.violations.extend(other.violations);
+    }
+}
+
+impl core::fmt::Display for Report {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "VMX self-test: {} checks, {} violations",
+            self.checks,
+            self.violations.len()
+        )?;
+        for v in self.violations.iter() {
+            write!(f, "\n  [{}] {}", v.section, v.detail)?;
+        }
+        Ok(())
+    }
+}
+
+const DISCOVERING: &str = "23.6 Discovering Support for VMX";
+const ENABLING: &str = "23.7 Enabling and Entering VMX Operation";
+const RESTRICTIONS: &str = "23.8 Restrictions on VMX Operation";
+const BASIC: &str = "A.1 Basic VMX Information";
+const REQUIRED: &str = "A.3 VM-Execution Controls";
+const CONTROLS: &str = "26.2.1 Checks on VMX Controls";
+const HOST_REGS: &str = "26.2.2 Checks on Host Control Registers";
+const HOST_SEGMENTS: &str = "26.2.3 Checks on Host Segment and Descriptor-Table Registers";
+const ADDRESS_SPACE: &str = "26.2.4 Checks Related to Address-Space Size";
+const GUEST_REGS: &str = "26.3.1.1 Checks on Guest Control Registers";
+const GUEST_SEGMENTS: &str = "26.3.1.2 Checks on Guest Segment Registers";
+const GUEST_RIP_RFLAGS: &str = "26.3.1.4 Checks on Guest RIP, RFLAGS, and SSP";
+const GUEST_NON_REGS: &str = "26.3.1.5 Checks on Guest Non-Register State";
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const CR4_PCIDE: u64 = 1 << 17;
+
+fn is_canonical(addr: u64) -> bool {
+    ((addr as i64) << 16 >> 16) as u64 == addr
+}
+
+// The allowed settings of the CR0 and the CR4 in the VMX operation.
+fn fixed_crs() -> ((u64, u64), (u64, u64)) {
+    (
+        (
+            Msr::<IA32_VMX_CR0_FIXED0>::read(),
+            Msr::<IA32_VMX_CR0_FIXED1>::read(),
+        ),
+        (
+            Msr::<IA32_VMX_CR4_FIXED0>::read(),
+            Msr::<IA32_VMX_CR4_FIXED1>::read(),
+        ),
+    )
+}
+
+/// Run the self-tests on the current cpu.
+///
+/// The cpu must have entered the VMX operation with
+/// [`start_vmx_on_cpu`](crate::start_vmx_on_cpu) to pass all of them.
+pub fn run() -> Report {
+    let mut report = check_host();
+    if report.is_ok() {
+        let caps = caps::probe();
+        match sample_vmcs(&caps) {
+            Ok(vmcs) => report.merge(check_vmcs(&vmcs.activate(), &caps)),
+            Err(e) => report.check(CONTROLS, false, || {
+                format!("failed to build the sample VMCS: {:?}", e)
+            }),
+        }
+    }
+    report
+}
+
+/// Check the configuration of the host for the VMX operation.
+pub fn check_host() -> Report {
+    let mut report = Report::default();
+    // The VMX capability MSRs exist only on the cpus with the VMX.
+    let vmx = unsafe { core::arch::x86_64::__cpuid(1) }.ecx.bit_test(5);
+    report.check(DISCOVERING, vmx, || {
+        String::from("CPUID.1:ECX.VMX[bit 5] is 0; the cpu does not support the VMX")
+    });
+    if !vmx {
+        return report;
+    }
+
+    let feature_control = Msr::<IA32_FEATURE_CONTROL>::read();
+    report.check(ENABLING, feature_control.bit_test(0), || {
+        format!(
+            "IA32_FEATURE_CONTROL ({:#x}) is not locked",
+            feature_control
+        )
+    });
+    report.check(ENABLING, feature_control.bit_test(2), || {
+        format!(
+            "IA32_FEATURE_CONTROL ({:#x}) disables the VMX outside SMX; enable the VT-x in the BIOS",
+            feature_control
+        )
+    });
+
+    let (cr0_fixed, cr4_fixed) = fixed_crs();
+    let cr4 = Cr4::current().bits();
+    report.check(ENABLING, cr4 & Cr4::VMXE.bits() != 0, || {
+        format!("CR4.VMXE is 0 ({:#x})", cr4)
+    });
+    report.check_fixed(RESTRICTIONS, "CR0", Cr0::current().bits(), cr0_fixed, 0);
+    report.check_fixed(RESTRICTIONS, "CR4", cr4, cr4_fixed, 0);
+
+    let basic = Msr::<IA32_VMX_BASIC>::read();
+    report.check(BASIC, basic & 0x7fff_ffff != 0, || {
+        String::from("the VMCS revision identifier is 0")
+    });
+    let region = (basic >> 32) & 0x1fff;
+    report.check(BASIC, region <= 0x1000, || {
+        format!("the VMCS region takes {} bytes, more than a page", region)
+    });
+    report.check(BASIC, !basic.bit_test(48), || {
+        String::from("the VMX structures are limited to the 32-bit physical addresses")
+    });
+    let memory_type = (basic >> 50) & 0xf;
+    report.check(BASIC, memory_type == 6, || {
+        format!(
+            "the VMCS memory type is {}, not the write-back (6)",
+            memory_type
+        )
+    });
+
+    // The controls that kev requires.
+    let caps = caps::probe();
+    report.check(
+        REQUIRED,
+        caps.procbased
+            .supports(VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL),
+        || String::from("the secondary processor-based controls are not supported"),
+    );
+    report.check(
+        REQUIRED,
+        caps.procbased2
+            .supports(VmcsProcBasedSecondaryVmexecCtl::ENABLE_EPT),
+        || String::from("the EPT is not supported"),
+    );
+    report.check(
+        REQUIRED,
+        caps.ept_vpid
+            .contains(EptVpidCap::PAGE_WALK_4 | EptVpidCap::MEMORY_TYPE_WB),
+        || {
+            format!(
+                "the EPT does not support the 4-level write-back page tables ({:?})",
+                caps.ept_vpid
+            )
+        },
+    );
+    report.check(
+        REQUIRED,
+        caps.exit.supports(VmcsExitCtl::HOST_ADDRESS_SPACE_SIZE),
+        || String::from("the 64-bit host is not supported"),
+    );
+    report.check(
+        REQUIRED,
+        caps.entry.supports(VmcsEntryCtl::IA32E_MODE_GUEST),
+        || String::from("the IA-32e mode guest is not supported"),
+    );
+    report
+}
+
+// Build a VMCS with the controls and the host state as kev sets up, and the
+// guest state of the 64-bit long mode.
+fn sample_vmcs(caps: &VmxCaps) -> Result<MockVmcs, VmError> {
+    let mut proc2 = VmcsProcBasedSecondaryVmexecCtl::ENABLE_EPT;
+    if caps
+        .procbased2
+        .supports(VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST)
+    {
+        proc2 |= VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST;
+    }
+    let mut guest = GuestEntryState::long_mode_identity(Gpa::new(0x1000).unwrap()).rip(0x1000);
+    let selector = |segment: Segment| segment.into_selector().pack() as u64;
+    let vmcs = MockVmcs::new()
+        .set(
+            Field::PinBasedExecControls,
+            caps.pinbased
+                .adjust(VmcsPinBasedVmexecCtl::EXTERNAL_INTERRUPT_EXITING)
+                .bits() as u64,
+        )
+        .set(
+            Field::ProcessorBasedVmexecControls,
+            caps.procbased
+                .adjust(VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL)
+                .bits() as u64,
+        )
+        .set(
+            Field::SecondaryVmexecControls,
+            caps.procbased2.adjust(proc2).bits() as u64,
+        )
+        .set(
+            Field::VmexitControls,
+            caps.exit
+                .adjust(VmcsExitCtl::HOST_ADDRESS_SPACE_SIZE)
+                .bits() as u64,
+        )
+        .set(
+            Field::VmentryControls,
+            caps.entry.adjust(guest.entry_ctls()).bits() as u64,
+        )
+        // The write-back, 4-level EPT.
+        .set(Field::Eptptr, 0x2000 | (3 << 3) | 6)
+        .set(Field::HostCr0, Cr0::current().bits())
+        .set(Field::HostCr3, read_cr3() as u64)
+        .set(Field::HostCr4, Cr4::current().bits())
+        .set(Field::HostCsSelector, selector(Segment::KernelCode))
+        .set(Field::HostSsSelector, selector(Segment::KernelData))
+        .set(Field::HostDsSelector, selector(Segment::KernelData))
+        .set(Field::HostEsSelector, selector(Segment::KernelData))
+        .set(Field::HostFsSelector, selector(Segment::KernelData))
+        .set(Field::HostGsSelector, selector(Segment::KernelData))
+        .set(Field::HostTrSelector, selector(Segment::Tss))
+        .set(Field::HostRip, run as *const () as usize as u64);
+    // The fixed bits of the VMX operation, as the guest sees its shadows.
+    let (cr0_fixed, cr4_fixed) = fixed_crs();
+    guest.cr0 |= cr0_fixed.0;
+    guest.cr4 |= cr4_fixed.0;
+    guest.write(&vmcs.activate())?;
+    Ok(vmcs)
+}
+
+/// Check the controls, the host state and the guest state of the `vmcs`
+/// against the capabilities `caps` of the cpu.
+pub fn check_vmcs(vmcs: &ActiveVmcs, caps: &VmxCaps) -> Report {
+    let mut report = Report::default();
+    let read = |field| vmcs.read(field).unwrap_or(0);
+
+    // 26.2.1 Checks on VMX Controls
+    let pin = read(Field::PinBasedExecControls) as u32;
+    let proc = read(Field::ProcessorBasedVmexecControls) as u32;
+    let proc2 = if proc.bit_test(31) {
+        read(Field::SecondaryVmexecControls) as u32
+    } else {
+        0
+    };
+    let exit = read(Field::VmexitControls) as u32;
+    let entry = read(Field::VmentryControls) as u32;
+    for (name, value, allowed0, allowed1) in [
+        (
+            "pin-based controls",
+            pin,
+            caps.pinbased.allowed0.bits(),
+            caps.pinbased.allowed1.bits(),
+        ),
+        (
+            "processor-based controls",
+            proc,
+            caps.procbased.allowed0.bits(),
+            caps.procbased.allowed1.bits(),
+        ),
+        (
+            "secondary processor-based controls",
+            proc2,
+            caps.procbased2.allowed0.bits(),
+            caps.procbased2.allowed1.bits(),
+        ),
+        (
+            "VM-exit controls",
+            exit,
+            caps.exit.allowed0.bits(),
+            caps.exit.allowed1.bits(),
+        ),
+        (
+            "VM-entry controls",
+            entry,
+            caps.entry.allowed0.bits(),
+            caps.entry.allowed1.bits(),
+        ),
+    ] {
+        report.check_fixed(
+            CONTROLS,
+            name,
+            value as u64,
+            (allowed0 as u64, allowed1 as u64),
+            0,
+        );
+    }
+    let cr3_targets = read(Field::Cr3TargetCount);
+    let max_targets = (caps.misc >> 16) & 0x1ff;
+    report.check(CONTROLS, cr3_targets <= max_targets, || {
+        format!(
+            "the CR3-target count {} exceeds {}",
+            cr3_targets, max_targets
+        )
+    });
+    let proc = VmcsProcBasedVmexecCtl::from_bits_truncate(proc);
+    let proc2 = VmcsProcBasedSecondaryVmexecCtl::from_bits_truncate(proc2);
+    let exit = VmcsExitCtl::from_bits_truncate(exit);
+    let entry = VmcsEntryCtl::from_bits_truncate(entry);
+    if proc.contains(VmcsProcBasedVmexecCtl::USEIOBMP) {
+        for field in [Field::IoBitmapA, Field::IoBitmapB] {
+            let addr = read(field);
+            report.check(CONTROLS, addr & 0xfff == 0, || {
+                format!("the I/O bitmap {:#x} is not aligned to a page", addr)
+            });
+        }
+    }
+    if proc.contains(VmcsProcBasedVmexecCtl::USEMSRBMP) {
+        let addr = read(Field::MsrBitmaps);
+        report.check(CONTROLS, addr & 0xfff == 0, || {
+            format!("the MSR bitmap {:#x} is not aligned to a page", addr)
+        });
+    }
+    if proc2.contains(VmcsProcBasedSecondaryVmexecCtl::ENABLE_EPT) {
+        let eptp = read(Field::Eptptr);
+        let memory_type = eptp & 7;
+        report.check(CONTROLS, memory_type == 0 || memory_type == 6, || {
+            format!("the EPTP memory type {} is invalid", memory_type)
+        });
+        let walk = ((eptp >> 3) & 7) + 1;
+        report.check(
+            CONTROLS,
+            walk == 4 || walk == 5 && caps.ept_vpid.contains(EptVpidCap::PAGE_WALK_5),
+            || format!("the EPT page-walk length {} is not supported", walk),
+        );
+        report.check(CONTROLS, eptp & 0xf80 == 0, || {
+            format!("the EPTP ({:#x}) sets the reserved bits 11:7", eptp)
+        });
+    }
+    report.check(
+        CONTROLS,
+        !proc2.contains(VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST)
+            || proc2.contains(VmcsProcBasedSecondaryVmexecCtl::ENABLE_EPT),
+        || String::from("the unrestricted guest requires the EPT"),
+    );
+    if proc2.contains(VmcsProcBasedSecondaryVmexecCtl::EANBLE_VPID) {
+        report.check(CONTROLS, read(Field::Vpid) != 0, || {
+            String::from("the VPID is 0")
+        });
+    }
+    report.check(
+        CONTROLS,
+        !exit.contains(VmcsExitCtl::SAVE_VMX_PREEMPTION_TIMER_VALUE)
+            || VmcsPinBasedVmexecCtl::from_bits_truncate(pin)
+                .contains(VmcsPinBasedVmexecCtl::ACTIVE_VMX_PREEMPTION_TIMER),
+        || String::from("saving the VMX-preemption timer requires the timer"),
+    );
+
+    // 26.2.2 Checks on Host Control Registers, MSRs, and SSP
+    let (cr0_fixed, cr4_fixed) = fixed_crs();
+    report.check_fixed(HOST_REGS, "host CR0", read(Field::HostCr0), cr0_fixed, 0);
+    report.check_fixed(HOST_REGS, "host CR4", read(Field::HostCr4), cr4_fixed, 0);
+
+    // 26.2.3 Checks on Host Segment and Descriptor-Table Registers
+    for (name, field) in [
+        ("CS", Field::HostCsSelector),
+        ("SS", Field::HostSsSelector),
+        ("DS", Field::HostDsSelector),
+        ("ES", Field::HostEsSelector),
+        ("FS", Field::HostFsSelector),
+        ("GS", Field::HostGsSelector),
+        ("TR", Field::HostTrSelector),
+    ] {
+        let selector = read(field);
+        report.check(HOST_SEGMENTS, selector & 7 == 0, || {
+            format!(
+                "the host {} selector {:#x} sets the RPL or the TI",
+                name, selector
+            )
+        });
+    }
+    report.check(HOST_SEGMENTS, read(Field::HostCsSelector) != 0, || {
+        String::from("the host CS selector is 0")
+    });
+    report.check(HOST_SEGMENTS, read(Field::HostTrSelector) != 0, || {
+        String::from("the host TR selector is 0")
+    });
+    for (name, field) in [
+        ("FS", Field::HostFsBase),
+        ("GS", Field::HostGsBase),
+        ("TR", Field::HostTrBase),
+        ("GDTR", Field::HostGdtrBase),
+        ("IDTR", Field::HostIdtrBase),
+    ] {
+        let base = read(field);
+        report.check(HOST_SEGMENTS, is_canonical(base), || {
+            format!("the host {} base {:#x} is not canonical", name, base)
+        });
+    }
+
+    // 26.2.4 Checks Related to Address-Space Size
+    let host_64 = exit.contains(VmcsExitCtl::HOST_ADDRESS_SPACE_SIZE);
+    report.check(ADDRESS_SPACE, host_64, || {
+        String::from("the host is in the IA-32e mode, but the host address-space size is 0")
+    });
+    report.check(
+        ADDRESS_SPACE,
+        read(Field::HostCr4) & Cr4::PAE.bits() != 0,
+        || String::from("the 64-bit host requires the host CR4.PAE"),
+    );
+    let rip = read(Field::HostRip);
+    report.check(ADDRESS_SPACE, is_canonical(rip), || {
+        format!("the host RIP {:#x} is not canonical", rip)
+    });
+    let ia32e = entry.contains(VmcsEntryCtl::IA32E_MODE_GUEST);
+    report.check(ADDRESS_SPACE, host_64 || !ia32e, || {
+        String::from("the IA-32e mode guest requires the 64-bit host")
+    });
+
+    // 26.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+    let unrestricted = proc2.contains(VmcsProcBasedSecondaryVmexecCtl::UNRESTRICTED_GUEST);
+    let (cr0, cr4) = (read(Field::GuestCr0), read(Field::GuestCr4));
+    report.check_fixed(
+        GUEST_REGS,
+        "guest CR0",
+        cr0,
+        cr0_fixed,
+        if unrestricted {
+            (Cr0::PE | Cr0::PG).bits()
+        } else {
+            0
+        },
+    );
+    report.check_fixed(GUEST_REGS, "guest CR4", cr4, cr4_fixed, 0);
+    report.check(
+        GUEST_REGS,
+        cr0 & Cr0::PG.bits() == 0 || cr0 & Cr0::PE.bits() != 0,
+        || format!("the guest CR0 ({:#x}) sets the PG without the PE", cr0),
+    );
+    if ia32e {
+        report.check(
+            GUEST_REGS,
+            cr0 & Cr0::PG.bits() != 0 && cr4 & Cr4::PAE.bits() != 0,
+            || String::from("the IA-32e mode guest requires the CR0.PG and the CR4.PAE"),
+        );
+    } else {
+        report.check(GUEST_REGS, cr4 & CR4_PCIDE == 0, || {
+            String::from("the guest outside the IA-32e mode sets the CR4.PCIDE")
+        });
+    }
+    if entry.contains(VmcsEntryCtl::LOAD_IA32_EFER) {
+        let efer = read(Field::GuestIa32Efer);
+        report.check(GUEST_REGS, (efer & EFER_LMA != 0) == ia32e, || {
+            format!(
+                "the guest EFER.LMA ({:#x}) differs from the IA-32e mode guest",
+                efer
+            )
+        });
+        report.check(
+            GUEST_REGS,
+            cr0 & Cr0::PG.bits() == 0 || (efer & EFER_LME != 0) == (efer & EFER_LMA != 0),
+            || format!("the guest EFER.LME differs from the EFER.LMA ({:#x})", efer),
+        );
+    }
+
+    // 26.3.1.2 Checks on Guest Segment Registers
+    let tr = read(Field::GuestTrAccessRights);
+    report.check(GUEST_SEGMENTS, tr & (1 << 16) == 0, || {
+        String::from("the guest TR is unusable")
+    });
+    report.check(
+        GUEST_SEGMENTS,
+        tr & 0xf == 11 || !ia32e && tr & 0xf == 3,
+        || format!("the guest TR type {} is not a busy TSS", tr & 0xf),
+    );
+    let cs = read(Field::GuestCsAccessRights);
+    report.check(GUEST_SEGMENTS, cs & (1 << 7) != 0, || {
+        String::from("the guest CS is not present")
+    });
+    report.check(
+        GUEST_SEGMENTS,
+        !ia32e || cs & (1 << 13) == 0 || cs & (1 << 14) == 0,
+        || String::from("the guest CS sets both the L and the D/B"),
+    );
+
+    // 26.3.1.4 Checks on Guest RIP, RFLAGS, and SSP
+    let rflags = read(Field::GuestRflags);
+    let reserved = !0x3f_ffff | (1 << 15) | (1 << 5) | (1 << 3);
+    report.check(GUEST_RIP_RFLAGS, rflags & reserved == 0, || {
+        format!("the guest RFLAGS ({:#x}) sets the reserved bits", rflags)
+    });
+    report.check(GUEST_RIP_RFLAGS, rflags & Rflags::_1.bits() != 0, || {
+        format!("the guest RFLAGS ({:#x}) clears the bit 1", rflags)
+    });
+    report.check(
+        GUEST_RIP_RFLAGS,
+        !(ia32e || cr0 & Cr0::PE.bits() == 0) || rflags & (1 << 17) == 0,
+        || String::from("the guest RFLAGS.VM is set in the IA-32e or the real mode"),
+    );
+
+    // 26.3.1.5 Checks on Guest Non-Register State
+    let activity = read(Field::GuestActivityState);
+    report.check(GUEST_NON_REGS, activity <= 3, || {
+        format!("the guest activity state {} is invalid", activity)
+    });
+    let link =
+        (read(Field::GuestLinkPointer) & 0xffff_ffff) | read(Field::GuestLinkPointerHi) << 32;
+    report.check(
+        GUEST_NON_REGS,
+        link == u64::MAX || link & 0xfff == 0,
+        || format!("the VMCS link pointer {:#x} is invalid", link),
+    );
+    report
+}
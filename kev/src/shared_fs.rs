@@ -0,0 +1,294 @@
+//! File sharing between the host and the guest.
+//!
+//! The host shares the files of its file system whose names start with a
+//! prefix, e.g. `"shared/"`, as a flat folder of the guest. The guest opens,
+//! reads, writes and lists them with the [`HC_SHARED_FS`] hypercall, so the
+//! test inputs need not be baked into the disk image of the guest:
+//!
+//! | Operation     | rdi | rsi        | rdx           | rcx     | r8  | Returns (rax)           |
+//! |---------------|-----|------------|---------------|---------|-----|-------------------------|
+//! | [`OP_OPEN`]   | 0   | name gpa   | name length   |         |     | handle; size in rdx     |
+//! | [`OP_READ`]   | 1   | handle     | offset        | buf gpa | len | bytes read              |
+//! | [`OP_WRITE`]  | 2   | handle     | offset        | buf gpa | len | bytes written           |
+//! | [`OP_CLOSE`]  | 3   | handle     |               |         |     | 0                       |
+//! | [`OP_LIST`]   | 4   | index      | buf gpa       | len     |     | length of the name      |
+//! | [`OP_READ_ASYNC`] | 5 | handle   | offset        | buf gpa | len | token                   |
+//!
+//! The names exchanged with the guest do not have the prefix. A failed
+//! operation returns the negated [`SharedFsError`]. As the files of the
+//! simple_fs have fixed sizes, a write does not grow the file.
+//!
+//! [`OP_READ_ASYNC`] is the [`OP_READ`] that completes on the worker of the
+//! vm; the bytes read are delivered with its token. See
+//! [`async_call`](crate::async_call).
+//!
+//! The host serves a folder by chaining the controller before the hypercall
+//! controller of the vm:
+//!
+//! ```ignore
+//! let folder = SharedFolder::new("shared/");
+//! (shared_fs::Controller::new(folder.clone()), hypercall_ctl)
+//! ```
+//!
+//! The guest accesses the folder with [`keos::fs::shared`], which also defines
+//! the protocol.
+use crate::{
+    event, guest_copy,
+    guest_slice::GuestSlice,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::{Gpa, VmOps},
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use keos::{
+    addressing::PAGE_SIZE,
+    fs::{file_system, File},
+    sync::SpinLock,
+};
+
+pub use keos::fs::shared::{
+    SharedFsError, HC_SHARED_FS, OP_CLOSE, OP_LIST, OP_OPEN, OP_READ, OP_READ_ASYNC, OP_WRITE,
+};
+
+/// A folder of the host shared with the guest.
+///
+/// The folder is cheaply cloned and shared by the vcpus, so a file opened on
+/// a vcpu can be accessed from the others.
+#[derive(Clone)]
+pub struct SharedFolder {
+    prefix: String,
+    handles: Arc<SpinLock<Vec<Option<File>>>>,
+}
+
+impl SharedFolder {
+    /// Maximum length of a file name in bytes.
+    pub const NAME_MAX: usize = 512 - 16;
+
+    /// Share the files whose names start with `prefix`.
+    pub fn new<I>(prefix: I) -> Self
+    where
+        String: From<I>,
+    {
+        Self {
+            prefix: String::from(prefix),
+            handles: Arc::new(SpinLock::new(Vec::new())),
+        }
+    }
+
+    /// Get the prefix of the shared files.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Get the names of the shared files, without the prefix.
+    pub fn files(&self) -> Result<Vec<String>, SharedFsError> {
+        let fs = file_system().ok_or(SharedFsError::Io)?;
+        Ok(fs
+            .files()
+            .map_err(|_| SharedFsError::Io)?
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(self.prefix.as_str()).map(String::from))
+            .collect())
+    }
+
+    /// Open the shared file `name`, and returns its handle and size.
+    pub fn open(&self, name: &str) -> Result<(usize, usize), SharedFsError> {
+        let fs = file_system().ok_or(SharedFsError::Io)?;
+        let mut path = self.prefix.clone();
+        path.push_str(name);
+        let file = fs.open(&path).ok_or(SharedFsError::NoEntry)?;
+        let size = file.size();
+        let mut handles = self.handles.lock();
+        let handle = match handles.iter().position(Option::is_none) {
+            Some(handle) => {
+                handles[handle] = Some(file);
+                handle
+            }
+            None => {
+                handles.push(Some(file));
+                handles.len() - 1
+            }
+        };
+        Ok((handle, size))
+    }
+
+    /// Close the file of the `handle`.
+    pub fn close(&self, handle: usize) -> Result<(), SharedFsError> {
+        self.handles
+            .lock()
+            .get_mut(handle)
+            .and_then(Option::take)
+            .map(|_| ())
+            .ok_or(SharedFsError::BadHandle)
+    }
+
+    // Run `f` on the file of the `handle`.
+    fn with_file<R>(
+        &self,
+        handle: usize,
+        f: impl FnOnce(&File) -> Result<R, SharedFsError>,
+    ) -> Result<R, SharedFsError> {
+        let handles = self.handles.lock();
+        let file = handles
+            .get(handle)
+            .and_then(Option::as_ref)
+            .ok_or(SharedFsError::BadHandle)?;
+        f(file)
+    }
+
+    // Read the `len` bytes from the `ofs` of the file of the `handle` into the
+    // guest memory of the `vm` at the `gpa`, page by page.
+    fn read_into(
+        &self,
+        vm: &dyn VmOps,
+        handle: usize,
+        ofs: usize,
+        gpa: usize,
+        len: usize,
+    ) -> Result<usize, SharedFsError> {
+        let mut buf = alloc::vec![0; len.min(PAGE_SIZE)];
+        self.with_file(handle, |file| {
+            let mut done = 0;
+            while done < len {
+                let chunk = (len - done).min(buf.len());
+                let read = file
+                    .read(ofs.saturating_add(done), &mut buf[..chunk])
+                    .map_err(|_| SharedFsError::Io)?;
+                let at = gpa
+                    .checked_add(done)
+                    .and_then(Gpa::new)
+                    .ok_or(SharedFsError::BadAddress)?;
+                guest_copy::write_vm(vm, at, &buf[..read])
+                    .map_err(|_| SharedFsError::BadAddress)?;
+                done += read;
+                if read < chunk {
+                    break;
+                }
+            }
+            Ok(done)
+        })
+    }
+}
+
+/// Vmexit controller of the [`HC_SHARED_FS`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+pub struct Controller {
+    folder: SharedFolder,
+}
+
+impl Controller {
+    /// Create a new controller that serves the `folder`.
+    pub fn new(folder: SharedFolder) -> Self {
+        Self { folder }
+    }
+
+    // Map the guest buffer of `len` bytes at `gpa`.
+    fn buffer<'a>(
+        p: &'a dyn Probe,
+        vmcs: &ActiveVmcs,
+        gpa: usize,
+        len: usize,
+    ) -> Result<GuestSlice<'a>, SharedFsError> {
+        let gpa = Gpa::new(gpa).ok_or(SharedFsError::BadAddress)?;
+        GuestSlice::new(p, vmcs, [(gpa, len)]).ok_or(SharedFsError::BadAddress)
+    }
+
+    fn serve(
+        &self,
+        p: &dyn Probe,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<usize, SharedFsError> {
+        let id = generic_vcpu_state.id();
+        let vmcs = &generic_vcpu_state.vmcs;
+        let gprs = &mut generic_vcpu_state.gprs;
+        match gprs.rdi {
+            OP_OPEN => {
+                if gprs.rdx > SharedFolder::NAME_MAX {
+                    return Err(SharedFsError::Invalid);
+                }
+                let mut name = alloc::vec![0; gprs.rdx];
+                Self::buffer(p, vmcs, gprs.rsi, gprs.rdx)?.drain_with(|ofs, seg| {
+                    name[ofs..ofs + seg.len()].copy_from_slice(seg);
+                    Ok::<_, SharedFsError>(seg.len())
+                })?;
+                let name = core::str::from_utf8(&name).map_err(|_| SharedFsError::Invalid)?;
+                let (handle, size) = self.folder.open(name)?;
+                gprs.rdx = size;
+                Ok(handle)
+            }
+            OP_READ => {
+                let (ofs, mut buf) = (gprs.rdx, Self::buffer(p, vmcs, gprs.rcx, gprs.r8)?);
+                self.folder.with_file(gprs.rsi, |file| {
+                    buf.fill_with(|at, seg| file.read(ofs.saturating_add(at), seg))
+                        .map_err(|_| SharedFsError::Io)
+                })
+            }
+            OP_WRITE => {
+                let (ofs, buf) = (gprs.rdx, Self::buffer(p, vmcs, gprs.rcx, gprs.r8)?);
+                self.folder.with_file(gprs.rsi, |file| {
+                    buf.drain_with(|at, seg| file.write(ofs.saturating_add(at), seg))
+                        .map_err(|_| SharedFsError::Io)
+                })
+            }
+            OP_CLOSE => self.folder.close(gprs.rsi).map(|_| 0),
+            OP_LIST => {
+                let files = self.folder.files()?;
+                let name = files.get(gprs.rsi).ok_or(SharedFsError::NoEntry)?;
+                let len = name.len().min(gprs.rcx);
+                Self::buffer(p, vmcs, gprs.rdx, len)?.fill_with(|ofs, seg| {
+                    seg.copy_from_slice(&name.as_bytes()[ofs..ofs + seg.len()]);
+                    Ok::<_, SharedFsError>(seg.len())
+                })?;
+                Ok(name.len())
+            }
+            OP_READ_ASYNC => {
+                let vm = generic_vcpu_state.vm.upgrade().ok_or(SharedFsError::Io)?;
+                let queue = vm.completions().ok_or(SharedFsError::Invalid)?;
+                // A bad handle fails at once.
+                self.folder.with_file(gprs.rsi, |_| Ok(()))?;
+                let folder = self.folder.clone();
+                let (handle, ofs, gpa, len) = (gprs.rsi, gprs.rdx, gprs.rcx, gprs.r8);
+                queue
+                    .submit(id, move |vm| {
+                        match folder.read_into(vm, handle, ofs, gpa, len) {
+                            Ok(read) => read,
+                            Err(e) => (e as usize).wrapping_neg(),
+                        }
+                    })
+                    .map(|token| token as usize)
+                    .map_err(SharedFsError::from_errno)
+            }
+            _ => Err(SharedFsError::Invalid),
+        }
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_SHARED_FS => {
+                // Only the guest kernel accesses the folder; the processes go
+                // through the file system of the guest.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                generic_vcpu_state.gprs.rax = match self.serve(p, generic_vcpu_state) {
+                    Ok(v) => v,
+                    Err(e) => -(e as isize) as usize,
+                };
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Guest shutdown protocol.
+//!
+//! The guest shuts down the vm in either way:
+//!
+//! | Request                          | Operation                                   | Exit code |
+//! |----------------------------------|---------------------------------------------|-----------|
+//! | Hypercall [`HC_SHUTDOWN`]        | `vmcall` with rax = 0x200                   | rdi       |
+//! | ACPI PM1a control [`PM1A_CNT`]   | `out` of a value with [`SLP_EN`] to 0x604   | 0         |
+//!
+//! The hypercall is handled by [`Controller`], and the port is emulated by
+//! a pio handler that checks the value with [`is_sleep`]. The vcpu that issues
+//! the request exits with the exit code, and the other vcpus stop when they
+//! are back from the guest.
+//!
+//! The host shuts down the vm with [`VmHandle::shutdown`]. It first asks the
+//! guest with [`VmState::request_shutdown`], and waits for the guest to issue
+//! one of the requests above. If the guest does not respond in time, the vm is
+//! terminated with the exit code of -1. The exit codes of the vm and the vcpus
+//! are reported in the [`ExitStatus`].
+//!
+//! [`VmHandle::shutdown`]: crate::vm::VmHandle::shutdown
+//! [`VmState::request_shutdown`]: crate::vm::VmState::request_shutdown
+//! [`ExitStatus`]: crate::vm::ExitStatus
+use crate::{
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+
+/// Hypercall number to shut down the vm.
+pub const HC_SHUTDOWN: usize = 0x200;
+/// Port of the ACPI PM1a control register.
+pub const PM1A_CNT: u16 = 0x604;
+/// Sleep enable bit of the PM1a control register.
+pub const SLP_EN: u32 = 1 << 13;
+
+/// Check whether the `value` written to the [`PM1A_CNT`] enters the sleep
+/// state, which powers off the vm.
+#[inline]
+pub fn is_sleep(value: u32) -> bool {
+    value & SLP_EN != 0
+}
+
+/// Vmexit controller of the [`HC_SHUTDOWN`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+#[derive(Default)]
+pub struct Controller;
+
+impl Controller {
+    /// Create a new shutdown controller.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_SHUTDOWN => {
+                // Only the guest kernel can shut down the vm.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let exit_code = generic_vcpu_state.gprs.rdi as i32;
+                if let Some(vm) = generic_vcpu_state.vm.upgrade() {
+                    vm.exit(exit_code);
+                }
+                Ok(VmexitResult::Exited(exit_code))
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! SMBIOS tables for the guest identification.
+//!
+//! The SMBIOS (a.k.a. DMI) tables describe the platform to the operating
+//! system. A guest scans the physical memory between 0xF0000 and 0xFFFFF for
+//! the "_SM_" anchor and finds the structure table from it. The tables built
+//! here carry the vendor "KeV", the uuid of the vm and the number of vcpus,
+//! so guests and tools (e.g. `dmidecode`) can tell that they run under KeV.
+//!
+//! See System Management BIOS (SMBIOS) Reference Specification 2.8.
+use crate::vm::Uuid;
+use alloc::vec::Vec;
+
+/// Default guest physical address to place the tables.
+pub const SMBIOS_BASE: usize = 0xf0000;
+/// The vendor string.
+pub const VENDOR: &str = "KeV";
+
+// Size of the entry point structure.
+const ENTRY_POINT_SIZE: usize = 0x1f;
+// The structure table follows the entry point on the next paragraph.
+const TABLE_OFFSET: usize = 0x20;
+
+#[inline]
+fn checksum(b: &[u8]) -> u8 {
+    b.iter().fold(0u8, |acc, b| acc.wrapping_sub(*b))
+}
+
+struct TableWriter {
+    buf: Vec<u8>,
+    count: u16,
+    max_size: u16,
+}
+
+impl TableWriter {
+    /// Append a structure of type `ty` with the formatted area `formatted`
+    /// (without the header) and the strings `strings`.
+    fn push(&mut self, ty: u8, formatted: &[u8], strings: &[&str]) {
+        let start = self.buf.len();
+        self.buf.push(ty);
+        self.buf.push(4 + formatted.len() as u8);
+        self.buf.extend_from_slice(&self.count.to_le_bytes());
+        self.buf.extend_from_slice(formatted);
+        for s in strings {
+            self.buf.extend_from_slice(s.as_bytes());
+            self.buf.push(0);
+        }
+        // The string-set is terminated with an additional null.
+        if strings.is_empty() {
+            self.buf.push(0);
+        }
+        self.buf.push(0);
+        self.count += 1;
+        self.max_size = self.max_size.max((self.buf.len() - start) as u16);
+    }
+}
+
+/// Build the SMBIOS entry point and the structure table to be placed at the
+/// guest physical address `addr`.
+///
+/// `addr` must be 16-byte aligned and within 0xF0000 to 0xFFFFF for the
+/// guest to find the entry point.
+pub fn build(uuid: Uuid, vcpus: usize, addr: u32) -> Vec<u8> {
+    let mut t = TableWriter {
+        buf: Vec::new(),
+        count: 0,
+        max_size: 0,
+    };
+
+    // Type 0, BIOS Information.
+    let mut bios = [0u8; 0x14];
+    bios[0] = 1; // Vendor.
+    bios[1] = 2; // BIOS version.
+    bios[2..4].copy_from_slice(&0xe800u16.to_le_bytes()); // Starting address segment.
+    bios[4] = 3; // BIOS release date.
+    bios[6..14].copy_from_slice(&(1u64 << 3).to_le_bytes()); // Characteristics not supported.
+    bios[15] = 1 << 4; // Virtual machine.
+    bios[16] = 0xff; // System BIOS major release.
+    bios[17] = 0xff; // System BIOS minor release.
+    bios[18] = 0xff; // Embedded controller firmware major release.
+    bios[19] = 0xff; // Embedded controller firmware minor release.
+    t.push(0, &bios, &[VENDOR, "0.1", "01/01/2023"]);
+
+    // Type 1, System Information.
+    let mut system = [0u8; 0x17];
+    system[0] = 1; // Manufacturer.
+    system[1] = 2; // Product name.
+    system[2] = 3; // Version.
+    system[3] = 4; // Serial number.
+
+    // The first three fields of the uuid are encoded in little-endian.
+    let b = uuid.as_bytes();
+    system[4..20].copy_from_slice(&[
+        b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13],
+        b[14], b[15],
+    ]);
+    system[20] = 0x06; // Wake-up type: power switch.
+    let serial = alloc::format!("{}", uuid);
+    t.push(1, &system, &[VENDOR, "KeV Virtual Machine", "0.1", &serial]);
+
+    // Type 4, Processor Information.
+    for id in 0..vcpus {
+        let mut processor = [0u8; 0x26];
+        processor[0] = 1; // Socket designation.
+        processor[1] = 0x03; // Processor type: central processor.
+        processor[2] = 0xfe; // Processor family: see processor family 2.
+        processor[3] = 2; // Processor manufacturer.
+        processor[13] = 0x80; // Voltage: legacy mode, unknown.
+        processor[20] = 0x41; // Status: populated, enabled.
+        processor[21] = 0x01; // Processor upgrade: other.
+        processor[22..28].copy_from_slice(&[0xff; 6]); // No cache information.
+        processor[31] = 1; // Core count.
+        processor[32] = 1; // Core enabled.
+        processor[33] = 1; // Thread count.
+        processor[34..36].copy_from_slice(&0x0002u16.to_le_bytes()); // Characteristics: unknown.
+        processor[36..38].copy_from_slice(&0x0001u16.to_le_bytes()); // Processor family 2: other.
+        let socket = alloc::format!("vCPU {}", id);
+        t.push(4, &processor, &[&socket, VENDOR]);
+    }
+
+    // Type 32, System Boot Information: no errors detected.
+    t.push(32, &[0; 7], &[]);
+    // Type 127, End-of-Table.
+    t.push(127, &[], &[]);
+
+    // 5.2.1 SMBIOS 2.1 (32-bit) Entry Point
+    let mut ep = [0u8; ENTRY_POINT_SIZE];
+    ep[0..4].copy_from_slice(b"_SM_");
+    ep[5] = ENTRY_POINT_SIZE as u8;
+    ep[6] = 2; // Major version.
+    ep[7] = 8; // Minor version.
+    ep[8..10].copy_from_slice(&t.max_size.to_le_bytes());
+    ep[16..21].copy_from_slice(b"_DMI_");
+    ep[22..24].copy_from_slice(&(t.buf.len() as u16).to_le_bytes());
+    ep[24..28].copy_from_slice(&(addr + TABLE_OFFSET as u32).to_le_bytes());
+    ep[28..30].copy_from_slice(&t.count.to_le_bytes());
+    ep[30] = 0x28; // BCD revision.
+    ep[21] = checksum(&ep[16..ENTRY_POINT_SIZE]);
+    ep[4] = checksum(&ep);
+
+    let mut out = Vec::with_capacity(TABLE_OFFSET + t.buf.len());
+    out.extend_from_slice(&ep);
+    out.resize(TABLE_OFFSET, 0);
+    out.extend_from_slice(&t.buf);
+    out
+}
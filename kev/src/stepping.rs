@@ -0,0 +1,629 @@
+//! Emulated stepping of the first guest instructions.
+//!
+//! A botched initial guest state, e.g. a wrong segment or paging setup, fails
+//! the first vm entry with an opaque error, or faults the guest on its first
+//! instructions into a triple fault. In the bring-up mode, kev executes the
+//! first instructions of each vcpu by the software emulation and logs each of
+//! them, before it runs the vcpu on the hardware:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?.step_emulated(64).finalize()?;
+//! ```
+//!
+//! ```text
+//! vcpu0 step 0: 0xffffff0000100000 4831ed          xor rbp,rbp  ; rbp=0x0
+//! vcpu0 step 1: 0xffffff0000100003 488b2595ff0f00  mov rsp,[...] ; rsp=0xffffff0000300000
+//! ```
+//!
+//! Before each step, the guest state is checked against the rules of the vm
+//! entry, and the fetch of the instruction walks the guest page tables. The
+//! vcpu fails with a [`StepError`] that names the first violation, instead
+//! of the opaque failure of the vm entry.
+//!
+//! Only a small subset of the 64-bit instructions is emulated: the moves, the
+//! arithmetic and logical operations, the stack operations and the near
+//! branches. The vcpu is handed over to the hardware at the first instruction
+//! out of the subset, on a pending event, or when the steps run out. The
+//! guest memory is accessed through [`VmState::gpa2hpa`].
+//!
+//! [`VmState::gpa2hpa`]: crate::vm::VmState::gpa2hpa
+use crate::{
+    guest_copy::{self, CopyError},
+    page_walk::{Access, WalkError},
+    probe::Probe,
+    vcpu::{GeneralPurposeRegisters, GenericVCpuState},
+    vm::{Gpa, Gva, VmOps},
+    vm_control::VmcsEntryCtl,
+    vmcs::{ActiveVmcs, Field},
+    VmError,
+};
+use abyss::{addressing::Pa, x86_64::Rflags};
+use alloc::{boxed::Box, string::String};
+use core::fmt::Write;
+use iced_x86::{
+    ConditionCode, Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter, Mnemonic,
+    OpKind, Register,
+};
+
+// EFER.LME: IA-32e mode enable.
+const EFER_LME: u64 = 1 << 8;
+// EFER.LMA: IA-32e mode active.
+const EFER_LMA: u64 = 1 << 10;
+
+// Bits of the segment access rights in the vmcs.
+const AR_S: u64 = 1 << 4;
+const AR_P: u64 = 1 << 7;
+const AR_L: u64 = 1 << 13;
+const AR_DB: u64 = 1 << 14;
+const AR_UNUSABLE: u64 = 1 << 16;
+
+// Flags updated by the arithmetic and logical operations.
+const STATUS_FLAGS: Rflags = Rflags::from_bits_truncate(
+    Rflags::CF.bits()
+        | Rflags::PF.bits()
+        | Rflags::AF.bits()
+        | Rflags::ZF.bits()
+        | Rflags::SF.bits()
+        | Rflags::OF.bits(),
+);
+
+/// Failure of an emulated step.
+#[derive(Debug)]
+pub enum StepError {
+    /// The guest state violates a rule of the vm entry.
+    GuestState(&'static str),
+    /// Failed to fetch the instruction at the `rip`.
+    Fetch {
+        /// The address of the instruction.
+        rip: u64,
+        /// The failure of the access.
+        error: CopyError,
+    },
+    /// Failed to decode the instruction at the `rip`.
+    Decode {
+        /// The address of the instruction.
+        rip: u64,
+    },
+    /// Failed to access the guest memory at the `addr`.
+    Memory {
+        /// The accessed address.
+        addr: u64,
+        /// The failure of the access.
+        error: CopyError,
+    },
+}
+
+impl From<StepError> for VmError {
+    fn from(e: StepError) -> Self {
+        VmError::VCpuError(Box::new(e))
+    }
+}
+
+// Probe of the guest memory, through the vm.
+pub(crate) struct VmProbe<'a>(pub(crate) &'a dyn VmOps);
+
+impl Probe for VmProbe<'_> {
+    fn gpa2hpa(&self, _vmcs: &ActiveVmcs, gpa: Gpa) -> Option<Pa> {
+        self.0.gpa2hpa(gpa)
+    }
+    fn gva2hpa(&self, vmcs: &ActiveVmcs, gva: Gva) -> Option<Pa> {
+        self.translate(vmcs, gva, Access::empty()).ok()
+    }
+}
+
+// Result of a decoded instruction.
+enum Step {
+    // Emulated, with the description of the written value.
+    Done(String),
+    // Not emulated; handed over to the hardware.
+    Unsupported,
+}
+
+/// Check the guest state against the rules of the vm entry.
+///
+/// Only the common mistakes of the initial state are checked.
+///
+/// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+/// 27.3.1 Checks on the Guest State Area.
+pub fn check_guest_state(vmcs: &ActiveVmcs) -> Result<(), StepError> {
+    let read = |field| vmcs.read(field).unwrap_or(0);
+    let check = |ok, msg| {
+        if ok {
+            Ok(())
+        } else {
+            Err(StepError::GuestState(msg))
+        }
+    };
+    let (cr0, cr4, efer) = (
+        read(Field::GuestCr0),
+        read(Field::GuestCr4),
+        read(Field::GuestIa32Efer),
+    );
+    let ia32e = VmcsEntryCtl::from_bits_truncate(read(Field::VmentryControls) as u32)
+        .contains(VmcsEntryCtl::IA32E_MODE_GUEST);
+    let pg = cr0 & (1 << 31) != 0;
+    check(!pg || cr0 & 1 != 0, "CR0.PG is set without CR0.PE")?;
+    check(
+        ia32e == (efer & EFER_LMA != 0),
+        "EFER.LMA differs from the IA-32e mode guest control",
+    )?;
+    check(!ia32e || pg, "the IA-32e mode guest runs without paging")?;
+    check(
+        !ia32e || cr4 & (1 << 5) != 0,
+        "the IA-32e mode guest runs without CR4.PAE",
+    )?;
+    check(
+        !pg || (efer & EFER_LMA != 0) == (efer & EFER_LME != 0),
+        "EFER.LMA differs from EFER.LME",
+    )?;
+
+    let cs = read(Field::GuestCsAccessRights);
+    check(cs & AR_UNUSABLE == 0, "CS is unusable")?;
+    check(
+        cs & (AR_S | AR_P) == AR_S | AR_P,
+        "CS is not a present code or data segment",
+    )?;
+    check(
+        cs & 0b1001 == 0b1001 || cr0 & 1 == 0,
+        "CS is not an accessed code segment",
+    )?;
+    check(
+        !(ia32e && cs & AR_L != 0 && cs & AR_DB != 0),
+        "CS sets both L and D/B",
+    )?;
+    let ss = read(Field::GuestSsAccessRights);
+    check(
+        cr0 & 1 == 0 || ss & AR_UNUSABLE != 0 || (ss >> 5) & 3 == (cs >> 5) & 3,
+        "the DPL of SS differs from the DPL of CS",
+    )?;
+    let tr = read(Field::GuestTrAccessRights);
+    check(
+        tr & AR_UNUSABLE == 0 && tr & AR_P != 0,
+        "TR is not a present segment",
+    )?;
+    check(
+        tr & 0xf == 11 || (!ia32e && tr & 0xf == 3),
+        "TR is not a busy TSS of the mode",
+    )?;
+
+    let rip = read(Field::GuestRip);
+    if ia32e && cs & AR_L != 0 {
+        check(Gva::new(rip as usize).is_some(), "RIP is not canonical")?;
+    } else {
+        check(rip >> 32 == 0, "RIP exceeds 32 bits out of the 64-bit mode")?;
+    }
+    let rflags = read(Field::GuestRflags);
+    check(rflags & Rflags::_1.bits() != 0, "RFLAGS bit 1 is not set")?;
+    check(
+        rflags & !0x3f_ffff == 0 && rflags & (1 << 15 | 1 << 5 | 1 << 3) == 0,
+        "RFLAGS sets a reserved bit",
+    )
+}
+
+/// Execute an instruction of the vcpu by the emulation.
+///
+/// Returns `false` if the instruction is not emulated, then the vcpu runs on
+/// the hardware from the instruction.
+pub(crate) fn step(generic_state: &mut GenericVCpuState, count: u64) -> Result<bool, VmError> {
+    let vmcs = &generic_state.vmcs;
+    check_guest_state(vmcs)?;
+    // The pending event is delivered by the hardware.
+    if vmcs.read(Field::VmentryInterruptionInfo)? & (1 << 31) != 0 {
+        return Ok(false);
+    }
+    let Some(vm) = generic_state.vm.upgrade() else {
+        return Ok(false);
+    };
+    let p = VmProbe(&*vm);
+
+    // Fetch and decode the instruction with the bitness of the CS.
+    let rip = vmcs.read(Field::GuestRip)?;
+    let cs = vmcs.read(Field::GuestCsAccessRights)?;
+    let bitness = if cs & AR_L != 0 {
+        64
+    } else if cs & AR_DB != 0 {
+        32
+    } else {
+        16
+    };
+    let linear = rip.wrapping_add(if bitness == 64 {
+        0
+    } else {
+        vmcs.read(Field::GuestCsBase)?
+    });
+    let mut bytes = [0; 15];
+    let mut len = 0;
+    let mut fault = None;
+    while len < bytes.len() {
+        match read_byte(&p, vmcs, linear + len as u64, Access::FETCH) {
+            Ok(b) => bytes[len] = b,
+            Err(e) => {
+                fault = Some(e);
+                break;
+            }
+        }
+        len += 1;
+    }
+    let mut insn = Instruction::default();
+    Decoder::with_ip(bitness, &bytes[..len], rip, DecoderOptions::NONE).decode_out(&mut insn);
+    if insn.is_invalid() {
+        return Err(match fault {
+            Some(error) => StepError::Fetch { rip: linear, error },
+            None => StepError::Decode { rip },
+        }
+        .into());
+    }
+
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "vcpu{} step {}: {:#018x} ",
+        generic_state.id(),
+        count,
+        rip
+    );
+    for b in &bytes[..insn.len()] {
+        let _ = write!(line, "{:02x}", b);
+    }
+    let _ = write!(line, "{:width$}", "", width = 2 * (15 - insn.len()) + 2);
+    IntelFormatter::new().format(&insn, &mut line);
+
+    let result = if bitness == 64 {
+        Emulator {
+            p: &p,
+            generic_state,
+            insn: &insn,
+        }
+        .run()?
+    } else {
+        Step::Unsupported
+    };
+    match result {
+        Step::Done(effect) => {
+            println!("{}  ; {}", line, effect);
+            Ok(true)
+        }
+        Step::Unsupported => {
+            println!("{}  ; handed over to the hardware", line);
+            Ok(false)
+        }
+    }
+}
+
+// Read a byte of the guest at the linear address `addr`.
+fn read_byte(p: &dyn Probe, vmcs: &ActiveVmcs, addr: u64, access: Access) -> Result<u8, CopyError> {
+    let gva = Gva::new(addr as usize).ok_or(WalkError::NonCanonical)?;
+    let access = Access::at_cpl(vmcs, access).map_err(|_| WalkError::NonCanonical)?;
+    let mut byte = [0];
+    guest_copy::read_gva(p, vmcs, gva, access, &mut byte)?;
+    Ok(byte[0])
+}
+
+// Emulator of an instruction in the 64-bit mode.
+struct Emulator<'a, 'b, 'c> {
+    p: &'a dyn Probe,
+    generic_state: &'a mut GenericVCpuState<'b>,
+    insn: &'c Instruction,
+}
+
+impl Emulator<'_, '_, '_> {
+    fn run(mut self) -> Result<Step, VmError> {
+        let insn = self.insn;
+        // Only the general purpose registers, the memory and the immediates.
+        let supported = (0..insn.op_count()).all(|n| match insn.op_kind(n) {
+            OpKind::Register => insn.op_register(n).is_gpr(),
+            OpKind::Memory
+            | OpKind::NearBranch64
+            | OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => true,
+            _ => false,
+        });
+        if !supported || insn.has_lock_prefix() {
+            return Ok(Step::Unsupported);
+        }
+        let mut next = insn.next_ip();
+        let effect = match insn.mnemonic() {
+            Mnemonic::Nop => String::from("-"),
+            Mnemonic::Mov | Mnemonic::Movzx => {
+                let v = self.operand(1)?;
+                self.write(0, v)?
+            }
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let bits = self.size(1) * 8;
+                let v = ((self.operand(1)? << (64 - bits)) as i64 >> (64 - bits)) as u64;
+                self.write(0, v)?
+            }
+            Mnemonic::Lea => {
+                let Some(ea) = self.address(1) else {
+                    return Ok(Step::Unsupported);
+                };
+                self.write(0, ea)?
+            }
+            m @ (Mnemonic::Add
+            | Mnemonic::Sub
+            | Mnemonic::Cmp
+            | Mnemonic::And
+            | Mnemonic::Or
+            | Mnemonic::Xor
+            | Mnemonic::Test) => {
+                let (a, b, size) = (self.operand(0)?, self.operand(1)?, self.size(0));
+                let (r, flags) = alu(m, a, b, size);
+                let rflags = self.generic_state.vmcs.read(Field::GuestRflags)?;
+                self.generic_state.vmcs.write(
+                    Field::GuestRflags,
+                    rflags & !STATUS_FLAGS.bits() | flags.bits(),
+                )?;
+                if matches!(m, Mnemonic::Cmp | Mnemonic::Test) {
+                    alloc::format!("rflags={:?}", flags)
+                } else {
+                    self.write(0, r)?
+                }
+            }
+            Mnemonic::Push => {
+                let v = self.operand(0)?;
+                self.push(v, self.size(0))?
+            }
+            Mnemonic::Pop => {
+                let v = self.pop(self.size(0))?;
+                self.write(0, v)?
+            }
+            Mnemonic::Jmp => {
+                next = self.operand(0)?;
+                alloc::format!("rip={:#x}", next)
+            }
+            Mnemonic::Call => {
+                let target = self.operand(0)?;
+                self.push(next, 8)?;
+                next = target;
+                alloc::format!("rip={:#x}", next)
+            }
+            Mnemonic::Ret if insn.op_count() <= 1 => {
+                next = self.pop(8)?;
+                if insn.op_count() == 1 {
+                    let rsp = self.generic_state.vmcs.read(Field::GuestRsp)?;
+                    self.generic_state
+                        .vmcs
+                        .write(Field::GuestRsp, rsp.wrapping_add(insn.immediate(0)))?;
+                }
+                alloc::format!("rip={:#x}", next)
+            }
+            _ if insn.is_jcc_short_or_near() => {
+                let rflags =
+                    Rflags::from_bits_truncate(self.generic_state.vmcs.read(Field::GuestRflags)?);
+                if taken(insn.condition_code(), rflags) {
+                    next = insn.near_branch_target();
+                    alloc::format!("taken, rip={:#x}", next)
+                } else {
+                    String::from("not taken")
+                }
+            }
+            _ => return Ok(Step::Unsupported),
+        };
+        self.generic_state.vmcs.write(Field::GuestRip, next)?;
+        Ok(Step::Done(effect))
+    }
+
+    // Get the size of the operand `n`, in bytes.
+    fn size(&self, n: u32) -> usize {
+        match self.insn.op_kind(n) {
+            OpKind::Register => self.insn.op_register(n).size(),
+            OpKind::Memory => self.insn.memory_size().size(),
+            OpKind::Immediate8 => 1,
+            OpKind::Immediate16 | OpKind::Immediate8to16 => 2,
+            OpKind::Immediate32 | OpKind::Immediate8to32 => 4,
+            _ => 8,
+        }
+    }
+
+    // Get the effective address of the memory operand `n`.
+    fn address(&self, n: u32) -> Option<u64> {
+        self.insn
+            .virtual_address(n, 0, |reg, _, _| self.register(reg).ok())
+    }
+
+    // Read the value of the operand `n`.
+    fn operand(&self, n: u32) -> Result<u64, VmError> {
+        match self.insn.op_kind(n) {
+            OpKind::Register => self.register(self.insn.op_register(n)),
+            OpKind::Memory => {
+                let addr = self.address(n).ok_or(VmError::FailedToDecodeInstruction)?;
+                self.load(addr, self.size(n))
+            }
+            OpKind::NearBranch64 => Ok(self.insn.near_branch_target()),
+            _ => Ok(self.insn.immediate(n)),
+        }
+    }
+
+    // Write the `value` to the operand `n`, and describe the write.
+    fn write(&mut self, n: u32, value: u64) -> Result<String, VmError> {
+        match self.insn.op_kind(n) {
+            OpKind::Register => {
+                let reg = self.insn.op_register(n);
+                self.set_register(reg, value)?;
+                Ok(alloc::format!("{:?}={:#x}", reg, self.register(reg)?).to_lowercase())
+            }
+            _ => {
+                let addr = self.address(n).ok_or(VmError::FailedToDecodeInstruction)?;
+                self.store(addr, value, self.size(n))?;
+                Ok(alloc::format!("[{:#x}]={:#x}", addr, value))
+            }
+        }
+    }
+
+    fn register(&self, reg: Register) -> Result<u64, VmError> {
+        let vmcs = &self.generic_state.vmcs;
+        let full = match reg {
+            // The segment bases, except FS and GS, are ignored in the 64-bit mode.
+            Register::ES | Register::CS | Register::SS | Register::DS => return Ok(0),
+            Register::FS => return vmcs.read(Field::GuestFsBase),
+            Register::GS => return vmcs.read(Field::GuestGsBase),
+            reg if reg.full_register() == Register::RSP => vmcs.read(Field::GuestRsp)?,
+            reg => match gpr(&mut self.generic_state.gprs.clone(), reg) {
+                Some(v) => *v as u64,
+                None => return Err(VmError::FailedToDecodeInstruction),
+            },
+        };
+        Ok(match reg {
+            Register::AH | Register::CH | Register::DH | Register::BH => (full >> 8) & 0xff,
+            reg if reg.size() == 8 => full,
+            reg => full & ((1 << (reg.size() * 8)) - 1),
+        })
+    }
+
+    fn set_register(&mut self, reg: Register, value: u64) -> Result<(), VmError> {
+        let old = match reg.full_register() {
+            Register::RSP => self.generic_state.vmcs.read(Field::GuestRsp)?,
+            _ => {
+                *gpr(self.generic_state.gprs, reg).ok_or(VmError::FailedToDecodeInstruction)? as u64
+            }
+        };
+        let new = match reg {
+            Register::AH | Register::CH | Register::DH | Register::BH => {
+                old & !0xff00 | (value & 0xff) << 8
+            }
+            // A 32-bit write zero-extends into the 64-bit register.
+            reg if reg.size() >= 4 => value & (u64::MAX >> (64 - reg.size() * 8)),
+            reg => {
+                let mask = (1 << (reg.size() * 8)) - 1;
+                old & !mask | value & mask
+            }
+        };
+        match reg.full_register() {
+            Register::RSP => self.generic_state.vmcs.write(Field::GuestRsp, new)?,
+            _ => {
+                *gpr(self.generic_state.gprs, reg).ok_or(VmError::FailedToDecodeInstruction)? =
+                    new as usize
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&self, addr: u64, size: usize) -> Result<u64, VmError> {
+        let mut value = 0;
+        for i in 0..size {
+            let b = read_byte(
+                self.p,
+                &self.generic_state.vmcs,
+                addr + i as u64,
+                Access::empty(),
+            )
+            .map_err(|error| StepError::Memory { addr, error })?;
+            value |= (b as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn store(&self, addr: u64, value: u64, size: usize) -> Result<(), VmError> {
+        let vmcs = &self.generic_state.vmcs;
+        let gva = Gva::new(addr as usize).ok_or(StepError::Memory {
+            addr,
+            error: CopyError::Walk(WalkError::NonCanonical),
+        })?;
+        let access = Access::at_cpl(vmcs, Access::WRITE)?;
+        guest_copy::write_gva(self.p, vmcs, gva, access, &value.to_le_bytes()[..size])
+            .map_err(|error| StepError::Memory { addr, error })?;
+        Ok(())
+    }
+
+    fn push(&mut self, value: u64, size: usize) -> Result<String, VmError> {
+        let rsp = self.generic_state.vmcs.read(Field::GuestRsp)? - size as u64;
+        self.store(rsp, value, size)?;
+        self.generic_state.vmcs.write(Field::GuestRsp, rsp)?;
+        Ok(alloc::format!("[{:#x}]={:#x}", rsp, value))
+    }
+
+    fn pop(&mut self, size: usize) -> Result<u64, VmError> {
+        let rsp = self.generic_state.vmcs.read(Field::GuestRsp)?;
+        let value = self.load(rsp, size)?;
+        self.generic_state
+            .vmcs
+            .write(Field::GuestRsp, rsp + size as u64)?;
+        Ok(value)
+    }
+}
+
+// Get the general purpose register that contains the `reg`.
+pub(crate) fn gpr(gprs: &mut GeneralPurposeRegisters, reg: Register) -> Option<&mut usize> {
+    Some(match reg.full_register() {
+        Register::RAX => &mut gprs.rax,
+        Register::RBX => &mut gprs.rbx,
+        Register::RCX => &mut gprs.rcx,
+        Register::RDX => &mut gprs.rdx,
+        Register::RSI => &mut gprs.rsi,
+        Register::RDI => &mut gprs.rdi,
+        Register::RBP => &mut gprs.rbp,
+        Register::R8 => &mut gprs.r8,
+        Register::R9 => &mut gprs.r9,
+        Register::R10 => &mut gprs.r10,
+        Register::R11 => &mut gprs.r11,
+        Register::R12 => &mut gprs.r12,
+        Register::R13 => &mut gprs.r13,
+        Register::R14 => &mut gprs.r14,
+        Register::R15 => &mut gprs.r15,
+        _ => return None,
+    })
+}
+
+// Compute the arithmetic or logical operation `m` of the `size` bytes.
+fn alu(m: Mnemonic, a: u64, b: u64, size: usize) -> (u64, Rflags) {
+    let bits = size as u32 * 8;
+    let mask = u64::MAX >> (64 - bits);
+    let sign = 1 << (bits - 1);
+    let (a, b) = (a & mask, b & mask);
+    let (r, cf, of) = match m {
+        Mnemonic::Add => {
+            let r = a.wrapping_add(b) & mask;
+            (r, r < a, (a ^ r) & (b ^ r) & sign != 0)
+        }
+        Mnemonic::Sub | Mnemonic::Cmp => {
+            let r = a.wrapping_sub(b) & mask;
+            (r, a < b, (a ^ b) & (a ^ r) & sign != 0)
+        }
+        Mnemonic::And | Mnemonic::Test => (a & b, false, false),
+        Mnemonic::Or => (a | b, false, false),
+        _ => (a ^ b, false, false),
+    };
+    let mut flags = Rflags::empty();
+    flags.set(Rflags::CF, cf);
+    flags.set(Rflags::OF, of);
+    flags.set(Rflags::ZF, r == 0);
+    flags.set(Rflags::SF, r & sign != 0);
+    flags.set(Rflags::PF, (r as u8).count_ones() % 2 == 0);
+    (r, flags)
+}
+
+// Check whether the branch of the condition `cc` is taken.
+fn taken(cc: ConditionCode, rflags: Rflags) -> bool {
+    let (cf, zf, sf, of, pf) = (
+        rflags.contains(Rflags::CF),
+        rflags.contains(Rflags::ZF),
+        rflags.contains(Rflags::SF),
+        rflags.contains(Rflags::OF),
+        rflags.contains(Rflags::PF),
+    );
+    match cc {
+        ConditionCode::None => true,
+        ConditionCode::o => of,
+        ConditionCode::no => !of,
+        ConditionCode::b => cf,
+        ConditionCode::ae => !cf,
+        ConditionCode::e => zf,
+        ConditionCode::ne => !zf,
+        ConditionCode::be => cf || zf,
+        ConditionCode::a => !cf && !zf,
+        ConditionCode::s => sf,
+        ConditionCode::ns => !sf,
+        ConditionCode::p => pf,
+        ConditionCode::np => !pf,
+        ConditionCode::l => sf != of,
+        ConditionCode::ge => sf == of,
+        ConditionCode::le => zf || sf != of,
+        ConditionCode::g => !zf && sf == of,
+    }
+}
@@ -0,0 +1,416 @@
+//! Codecs of the guest memory streams.
+//!
+//! A stream of the guest memory, e.g. the RAM of a [`core_dump`], is a plain
+//! copy of the pages by default: it is as large as the RAM, and a corrupted
+//! page goes unnoticed. A [`Pipeline`] of [`StreamCodec`]s transforms each
+//! chunk of the stream on the way out, and reverts them on the way in:
+//!
+//! ```ignore
+//! let pipeline = Pipeline::new()
+//!     .stage(Lz4::new())
+//!     .stage(Checksum::new(ChecksumKind::Crc32));
+//! let vm = VmBuilder::new(state, 1)?.stream_codec(pipeline).finalize()?;
+//! // ...
+//! vm.dump_core("core")?;
+//! for (name, stats) in vm.codec_stats() {
+//!     println!("{}: {} -> {} bytes", name, stats.bytes_in, stats.bytes_out);
+//! }
+//! ```
+//!
+//! The stages are applied in the order of the pipeline on the encoding, and
+//! in the reverse order on the decoding. Each stage counts the bytes in and
+//! out of it, and the chunks that it failed to decode in [`CodecStats`].
+//!
+//! [`core_dump`]: crate::core_dump
+use alloc::{boxed::Box, vec::Vec};
+
+/// Error on decoding a chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    /// The chunk is truncated or malformed.
+    Malformed,
+    /// The checksum of the chunk does not match.
+    Checksum {
+        /// The checksum in the chunk.
+        expected: u32,
+        /// The checksum of the contents.
+        actual: u32,
+    },
+}
+
+/// A stage of the [`Pipeline`].
+pub trait StreamCodec: Send {
+    /// Name of the codec, which names its statistics.
+    fn name(&self) -> &'static str;
+    /// Encode the chunk `input`, appending to the `out`.
+    fn encode(&mut self, input: &[u8], out: &mut Vec<u8>);
+    /// Decode the chunk `input` encoded by [`StreamCodec::encode`], appending
+    /// to the `out`.
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError>;
+}
+
+/// Statistics of a stage of the [`Pipeline`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CodecStats {
+    /// Bytes into the stage.
+    pub bytes_in: u64,
+    /// Bytes out of the stage.
+    pub bytes_out: u64,
+    /// Number of the chunks that failed to decode.
+    pub failures: u64,
+}
+
+/// A sequence of the [`StreamCodec`]s.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<(Box<dyn StreamCodec>, CodecStats)>,
+}
+
+impl Pipeline {
+    /// Create a pipeline without a stage, which copies the chunks as is.
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Append the `codec` to the pipeline.
+    pub fn stage<C: StreamCodec + 'static>(mut self, codec: C) -> Self {
+        self.stages.push((Box::new(codec), CodecStats::default()));
+        self
+    }
+
+    /// Check whether the pipeline has no stage.
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Encode the `chunk` through the stages.
+    pub fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut data = chunk.to_vec();
+        for (codec, stats) in self.stages.iter_mut() {
+            let mut out = Vec::with_capacity(data.len());
+            codec.encode(&data, &mut out);
+            stats.bytes_in += data.len() as u64;
+            stats.bytes_out += out.len() as u64;
+            data = out;
+        }
+        data
+    }
+
+    /// Decode the `chunk` through the stages, in the reverse order.
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let mut data = chunk.to_vec();
+        for (codec, stats) in self.stages.iter_mut().rev() {
+            let mut out = Vec::with_capacity(data.len());
+            stats.bytes_in += data.len() as u64;
+            if let Err(e) = codec.decode(&data, &mut out) {
+                stats.failures += 1;
+                return Err(e);
+            }
+            stats.bytes_out += out.len() as u64;
+            data = out;
+        }
+        Ok(data)
+    }
+
+    /// Get the statistics of the stages, in the order of the pipeline.
+    pub fn stats(&self) -> Vec<(&'static str, CodecStats)> {
+        self.stages
+            .iter()
+            .map(|(codec, stats)| (codec.name(), *stats))
+            .collect()
+    }
+}
+
+/// Algorithm of the [`Checksum`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumKind {
+    /// CRC-32 of IEEE 802.3.
+    Crc32,
+    /// XXH32 with the seed of zero.
+    XxHash32,
+}
+
+impl ChecksumKind {
+    /// Compute the checksum of the `data`.
+    pub fn compute(&self, data: &[u8]) -> u32 {
+        match self {
+            Self::Crc32 => crc32(data),
+            Self::XxHash32 => xxhash32(data, 0),
+        }
+    }
+}
+
+/// A codec that appends the little-endian checksum to the chunk, and
+/// verifies it on the decoding.
+pub struct Checksum(ChecksumKind);
+
+impl Checksum {
+    /// Create a checksum codec of the `kind`.
+    pub fn new(kind: ChecksumKind) -> Self {
+        Self(kind)
+    }
+}
+
+impl StreamCodec for Checksum {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            ChecksumKind::Crc32 => "crc32",
+            ChecksumKind::XxHash32 => "xxhash32",
+        }
+    }
+
+    fn encode(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(input);
+        out.extend_from_slice(&self.0.compute(input).to_le_bytes());
+    }
+
+    fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        let (data, sum) = input
+            .len()
+            .checked_sub(4)
+            .map(|len| input.split_at(len))
+            .ok_or(CodecError::Malformed)?;
+        let expected = u32::from_le_bytes(sum.try_into().unwrap());
+        let actual = self.0.compute(data);
+        if expected != actual {
+            return Err(CodecError::Checksum { expected, actual });
+        }
+        out.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+// Bitwise CRC-32 of the reflected polynomial 0xedb88320.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// See https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md.
+fn xxhash32(data: &[u8], seed: u32) -> u32 {
+    const P1: u32 = 0x9e37_79b1;
+    const P2: u32 = 0x85eb_ca77;
+    const P3: u32 = 0xc2b2_ae3d;
+    const P4: u32 = 0x27d4_eb2f;
+    const P5: u32 = 0x1656_67b1;
+    let word = |b: &[u8]| u32::from_le_bytes(b[..4].try_into().unwrap());
+    let round = |acc: u32, lane: u32| {
+        acc.wrapping_add(lane.wrapping_mul(P2))
+            .rotate_left(13)
+            .wrapping_mul(P1)
+    };
+
+    let mut stripes = data.chunks_exact(16);
+    let mut acc = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(P1).wrapping_add(P2),
+            seed.wrapping_add(P2),
+            seed,
+            seed.wrapping_sub(P1),
+        ];
+        for stripe in stripes.by_ref() {
+            for (i, v) in v.iter_mut().enumerate() {
+                *v = round(*v, word(&stripe[i * 4..]));
+            }
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(P5)
+    };
+    acc = acc.wrapping_add(data.len() as u32);
+
+    let mut rest = stripes.remainder();
+    while rest.len() >= 4 {
+        acc = acc
+            .wrapping_add(word(rest).wrapping_mul(P3))
+            .rotate_left(17)
+            .wrapping_mul(P4);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        acc = acc
+            .wrapping_add((b as u32).wrapping_mul(P5))
+            .rotate_left(11)
+            .wrapping_mul(P1);
+    }
+    acc ^= acc >> 15;
+    acc = acc.wrapping_mul(P2);
+    acc ^= acc >> 13;
+    acc = acc.wrapping_mul(P3);
+    acc ^ (acc >> 16)
+}
+
+/// A compressor of the LZ4 block format.
+///
+/// A chunk is a sequence of the sequences, each of which is a token, the
+/// literals, and a match of at least 4 bytes at a 16-bit offset back. The
+/// zero pages, which are common in the guest RAM, compress to a few bytes.
+pub struct Lz4 {
+    // Last position of the 4-byte prefixes, by their hashes.
+    table: Box<[u16; 1 << Self::HASH_BITS]>,
+}
+
+impl Default for Lz4 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lz4 {
+    const HASH_BITS: u32 = 12;
+    const MIN_MATCH: usize = 4;
+    // The last literals that are never in a match.
+    const LAST_LITERALS: usize = 5;
+    // The largest chunk, as the positions in the table are 16-bit.
+    const MAX_CHUNK: usize = 0xffff;
+
+    /// Create a compressor.
+    pub fn new() -> Self {
+        Self {
+            table: Box::new([0; 1 << Self::HASH_BITS]),
+        }
+    }
+
+    fn hash(b: &[u8]) -> usize {
+        let v = u32::from_le_bytes(b[..4].try_into().unwrap());
+        (v.wrapping_mul(2_654_435_761) >> (32 - Self::HASH_BITS)) as usize
+    }
+
+    // Write the length over 15 in the extension bytes.
+    fn push_len(out: &mut Vec<u8>, mut len: usize) {
+        while len >= 255 {
+            out.push(255);
+            len -= 255;
+        }
+        out.push(len as u8);
+    }
+
+    fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(u16, usize)>) {
+        let ml = matched.map_or(0, |(_, len)| len - Self::MIN_MATCH);
+        out.push(((literals.len().min(15) as u8) << 4) | ml.min(15) as u8);
+        if literals.len() >= 15 {
+            Self::push_len(out, literals.len() - 15);
+        }
+        out.extend_from_slice(literals);
+        if let Some((offset, _)) = matched {
+            out.extend_from_slice(&offset.to_le_bytes());
+            if ml >= 15 {
+                Self::push_len(out, ml - 15);
+            }
+        }
+    }
+
+    fn compress(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        self.table.fill(0);
+        let end = input.len().saturating_sub(Self::LAST_LITERALS);
+        let (mut anchor, mut pos) = (0, 0);
+        while pos + Self::MIN_MATCH <= end {
+            let h = Self::hash(&input[pos..]);
+            let candidate = self.table[h] as usize;
+            self.table[h] = pos as u16;
+            if candidate < pos
+                && pos - candidate <= 0xffff
+                && input[candidate..candidate + 4] == input[pos..pos + 4]
+            {
+                let mut len = Self::MIN_MATCH;
+                while pos + len < end && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+                Self::push_sequence(
+                    out,
+                    &input[anchor..pos],
+                    Some(((pos - candidate) as u16, len)),
+                );
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+        Self::push_sequence(out, &input[anchor..], None);
+    }
+
+    fn read_len(input: &[u8], pos: &mut usize, mut len: usize) -> Result<usize, CodecError> {
+        loop {
+            let b = *input.get(*pos).ok_or(CodecError::Malformed)?;
+            *pos += 1;
+            len = len.checked_add(b as usize).ok_or(CodecError::Malformed)?;
+            if b != 255 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl StreamCodec for Lz4 {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn encode(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        // Each piece is prefixed with its compressed length.
+        for piece in input.chunks(Self::MAX_CHUNK) {
+            let len = out.len();
+            out.extend_from_slice(&[0; 4]);
+            self.compress(piece, out);
+            let compressed = (out.len() - len - 4) as u32;
+            out[len..len + 4].copy_from_slice(&compressed.to_le_bytes());
+        }
+    }
+
+    fn decode(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        while !input.is_empty() {
+            let len = input
+                .get(..4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or(CodecError::Malformed)?;
+            let block = input.get(4..4 + len).ok_or(CodecError::Malformed)?;
+            input = &input[4 + len..];
+
+            let start = out.len();
+            let mut pos = 0;
+            while pos < block.len() {
+                let token = block[pos];
+                pos += 1;
+                let mut literals = (token >> 4) as usize;
+                if literals == 15 {
+                    literals = Self::read_len(block, &mut pos, literals)?;
+                }
+                let next = pos.checked_add(literals).ok_or(CodecError::Malformed)?;
+                out.extend_from_slice(block.get(pos..next).ok_or(CodecError::Malformed)?);
+                pos = next;
+                // The last sequence has no match.
+                if pos == block.len() {
+                    break;
+                }
+                let offset = block
+                    .get(pos..pos + 2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+                    .ok_or(CodecError::Malformed)?;
+                pos += 2;
+                let mut matched = (token & 0xf) as usize;
+                if matched == 15 {
+                    matched = Self::read_len(block, &mut pos, matched)?;
+                }
+                if offset == 0 || offset > out.len() - start {
+                    return Err(CodecError::Malformed);
+                }
+                // The match may overlap with itself.
+                let from = out.len() - offset;
+                for i in 0..matched + Self::MIN_MATCH {
+                    out.push(out[from + i]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
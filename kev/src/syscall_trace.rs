@@ -0,0 +1,173 @@
+//! Tracing of the guest system calls.
+//!
+//! The guest kernel reports each system call of its user processes with the
+//! [`HC_SYSCALL_TRACE`] hypercall:
+//!
+//! | Register     | Value                            |
+//! |--------------|----------------------------------|
+//! | rax          | [`HC_SYSCALL_TRACE`] (0x201)     |
+//! | rdi          | process id                       |
+//! | rsi          | system call number               |
+//! | rdx, rcx, r8 | the first three arguments        |
+//!
+//! keos reports them when the tracer is installed on its syscall entry:
+//!
+//! ```ignore
+//! fn trace(pid: u64, nr: usize, args: [usize; 3]) {
+//!     unsafe {
+//!         core::arch::asm!(
+//!             "vmcall",
+//!             inout("rax") 0x201 => _, in("rdi") pid, in("rsi") nr,
+//!             in("rdx") args[0], in("rcx") args[1], in("r8") args[2],
+//!         );
+//!     }
+//! }
+//! keos::process::syscall::set_tracer(Some(trace));
+//! ```
+//!
+//! The host records them into a [`SyscallTrace`] that is shared by the vcpus
+//! of the vm, and inspects it per process:
+//!
+//! ```ignore
+//! for record in trace.for_pid(1) {
+//!     println!("{}", record);
+//! }
+//! ```
+use crate::{
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use keos::sync::SpinLock;
+
+/// Hypercall number to report a system call of the guest.
+pub const HC_SYSCALL_TRACE: usize = 0x201;
+
+/// A system call of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyscallRecord {
+    /// The vcpu that runs the process.
+    pub vcpu: usize,
+    /// The process id.
+    pub pid: u64,
+    /// The system call number.
+    pub nr: u64,
+    /// The first three arguments.
+    pub args: [u64; 3],
+    /// The tsc when the system call is reported.
+    pub tsc: u64,
+}
+
+impl core::fmt::Display for SyscallRecord {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{:>20}] vcpu {} pid {}: syscall {}({:#x}, {:#x}, {:#x})",
+            self.tsc, self.vcpu, self.pid, self.nr, self.args[0], self.args[1], self.args[2]
+        )
+    }
+}
+
+/// Trace of the recent system calls of a vm.
+///
+/// The trace is cheaply cloned and shared by the vcpus. It keeps the last
+/// [`SyscallTrace::CAPACITY`] system calls.
+#[derive(Clone, Default)]
+pub struct SyscallTrace {
+    records: Arc<SpinLock<VecDeque<SyscallRecord>>>,
+}
+
+impl SyscallTrace {
+    /// Maximum number of the system calls kept in a trace.
+    pub const CAPACITY: usize = 1024;
+
+    /// Create an empty trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a system call, evicting the oldest one if the trace is full.
+    pub fn push(&self, record: SyscallRecord) {
+        let mut records = self.records.lock();
+        if records.len() == Self::CAPACITY {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Get the recorded system calls, from the oldest to the latest.
+    pub fn records(&self) -> Vec<SyscallRecord> {
+        self.records.lock().iter().copied().collect()
+    }
+
+    /// Get the recorded system calls of the process `pid`.
+    pub fn for_pid(&self, pid: u64) -> Vec<SyscallRecord> {
+        self.records
+            .lock()
+            .iter()
+            .filter(|record| record.pid == pid)
+            .copied()
+            .collect()
+    }
+
+    /// Discard the recorded system calls.
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+
+    /// Print the recorded system calls.
+    pub fn dump(&self) {
+        println!("Recent guest syscalls (oldest first):");
+        for record in self.records() {
+            println!("  {}", record);
+        }
+    }
+}
+
+/// Vmexit controller of the [`HC_SYSCALL_TRACE`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+pub struct Controller {
+    trace: SyscallTrace,
+}
+
+impl Controller {
+    /// Create a new controller that records into the `trace`.
+    pub fn new(trace: SyscallTrace) -> Self {
+        Self { trace }
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_SYSCALL_TRACE => {
+                // Only the guest kernel reports the system calls; a process
+                // can not forge the records of the others.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                let gprs = &generic_vcpu_state.gprs;
+                self.trace.push(SyscallRecord {
+                    vcpu: generic_vcpu_state.id(),
+                    pid: gprs.rdi as u64,
+                    nr: gprs.rsi as u64,
+                    args: [gprs.rdx as u64, gprs.rcx as u64, gprs.r8 as u64],
+                    tsc: unsafe { core::arch::x86_64::_rdtsc() },
+                });
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
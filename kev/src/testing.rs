@@ -0,0 +1,254 @@
+//! Testing the vmexit controllers without VMX.
+//!
+//! A [`MockVmcs`] keeps the vmcs fields in the memory, and a [`MockVcpu`]
+//! hands its [`GenericVCpuState`] to a controller with the registers and the
+//! [`VecProbe`] guest memory of the test. The mocks never execute the VMX
+//! instructions, so the controllers are tested on any cpu, e.g. in a QEMU
+//! without the nested virtualization:
+//!
+//! ```ignore
+//! let mut vcpu = MockVcpu::new(MockVmcs::new().exit(VMCALL, 3));
+//! vcpu.gprs.rax = HC_SYSCALL_TRACE;
+//! let reason = vcpu.vmcs.activate().exit_reason()?;
+//! let result = controller.handle(reason, &mut VecProbe::new(0x1000), &mut vcpu.state())?;
+//! assert!(matches!(result, VmexitResult::HandledAdvance));
+//! assert_eq!(trace.records().len(), 1);
+//! ```
+//!
+//! A field that is never written reads as zero.
+use crate::{
+    caps::{DirtyTracking, ExitTimer, Features, InterruptDelivery},
+    console::Console,
+    pmu::PmuStats,
+    probe::Probe,
+    vcpu::{GeneralPurposeRegisters, GenericVCpuState, VCpuOps},
+    vm::{Gpa, Gva, Uuid, VmOps},
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason, Field},
+    VmError,
+};
+use abyss::addressing::{Pa, Va};
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use keos::sync::SpinLock;
+
+/// Basic exit reason of the `cpuid` instruction.
+pub const CPUID: u16 = 0xA;
+/// Basic exit reason of the `hlt` instruction.
+pub const HLT: u16 = 0xC;
+/// Basic exit reason of the `vmcall` instruction.
+pub const VMCALL: u16 = 0x12;
+/// Basic exit reason of the I/O instructions.
+pub const IO_INSTRUCTION: u16 = 0x1E;
+/// Basic exit reason of the `rdmsr` instruction.
+pub const RDMSR: u16 = 0x1F;
+/// Basic exit reason of the `wrmsr` instruction.
+pub const WRMSR: u16 = 0x20;
+/// Basic exit reason of the EPT violation.
+pub const EPT_VIOLATION: u16 = 0x30;
+
+// The fields of a mock vmcs.
+pub(crate) type MockFields = Arc<SpinLock<BTreeMap<u64, u64>>>;
+
+/// A vmcs whose fields are kept in the memory.
+///
+/// The mock is cheaply cloned; the clones and the [`ActiveVmcs`] from
+/// [`MockVmcs::activate`] share the fields.
+#[derive(Clone, Default)]
+pub struct MockVmcs {
+    fields: MockFields,
+}
+
+impl MockVmcs {
+    /// Create a vmcs whose fields are all zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the `field` to `v`.
+    pub fn set(self, field: Field, v: u64) -> Self {
+        self.fields.lock().insert(field as u64, v);
+        self
+    }
+
+    /// Get the value of the `field`.
+    pub fn get(&self, field: Field) -> u64 {
+        self.fields
+            .lock()
+            .get(&(field as u64))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Set the exit reason to the `basic` reason, caused by an instruction of
+    /// `instruction_length` bytes.
+    pub fn exit(self, basic: u16, instruction_length: u64) -> Self {
+        self.set(Field::VmexitReason, basic as u64)
+            .set(Field::VmexitInstructionLength, instruction_length)
+    }
+
+    /// Set the exit reason to the EPT violation on the `gpa`.
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// Table 27-7. Exit Qualification for EPT Violations.
+    pub fn ept_violation(self, gpa: Gpa, qualification: u64) -> Self {
+        self.exit(EPT_VIOLATION, 0)
+            .set(Field::VmexitQualification, qualification)
+            .set(Field::GuestPhysicalAddr, unsafe { gpa.into_usize() } as u64)
+    }
+
+    /// Set the privilege level of the guest.
+    pub fn cpl(self, cpl: u8) -> Self {
+        self.set(Field::GuestSsAccessRights, ((cpl & 3) as u64) << 5)
+    }
+
+    /// Get the vmcs of the mock, as the controllers see it.
+    pub fn activate(&self) -> ActiveVmcs {
+        ActiveVmcs::mock(self.fields.clone())
+    }
+}
+
+/// Build the exit reason of the `basic` reason.
+pub fn exit_reason(basic: BasicExitReason) -> ExitReason {
+    ExitReason::BasicExitReason(basic)
+}
+
+/// A guest memory backed by a vector.
+///
+/// The guest physical address is the offset in the memory, and the guest
+/// virtual address is identically mapped to it.
+pub struct VecProbe {
+    memory: Vec<u8>,
+}
+
+impl VecProbe {
+    /// Create a zero-filled guest memory of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            memory: alloc::vec![0; size],
+        }
+    }
+
+    /// Get the guest memory.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Get the mutable guest memory.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+}
+
+impl Probe for VecProbe {
+    fn gpa2hpa(&self, _vmcs: &ActiveVmcs, gpa: Gpa) -> Option<Pa> {
+        let ofs = unsafe { gpa.into_usize() };
+        if ofs < self.memory.len() {
+            Va::new(self.memory.as_ptr() as usize + ofs).map(Va::into_pa)
+        } else {
+            None
+        }
+    }
+
+    fn gva2hpa(&self, vmcs: &ActiveVmcs, gva: Gva) -> Option<Pa> {
+        self.gpa2hpa(vmcs, Gpa::new(unsafe { gva.into_usize() })?)
+    }
+}
+
+/// A vcpu that hands its state to the controllers without running.
+///
+/// The vcpu is not attached to a vm, so [`GenericVCpuState::vm`] never
+/// upgrades.
+pub struct MockVcpu {
+    /// The vmcs of the vcpu.
+    pub vmcs: MockVmcs,
+    /// The general purpose registers of the vcpu.
+    pub gprs: GeneralPurposeRegisters,
+    /// The tsc deadline to force a vmexit.
+    pub exit_deadline: Option<u64>,
+    id: usize,
+    pending_interrupts: [AtomicU64; 4],
+}
+
+impl MockVcpu {
+    /// Create the vcpu 0 on the `vmcs`.
+    pub fn new(vmcs: MockVmcs) -> Self {
+        Self {
+            vmcs,
+            gprs: GeneralPurposeRegisters::default(),
+            exit_deadline: None,
+            id: 0,
+            pending_interrupts: Default::default(),
+        }
+    }
+
+    /// Set the smp id of the vcpu.
+    pub fn id(mut self, id: usize) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Check whether the interrupt `vec` is injected.
+    pub fn is_pending(&self, vec: u8) -> bool {
+        let (index, ofs) = (vec / 64, vec & 63);
+        self.pending_interrupts[index as usize].load(Ordering::SeqCst) & (1 << ofs) != 0
+    }
+
+    /// Get the state of the vcpu for a controller.
+    ///
+    /// The vcpu uses the software features, which every cpu supports.
+    pub fn state(&mut self) -> GenericVCpuState<'_> {
+        GenericVCpuState::detached(
+            self.vmcs.activate(),
+            &mut self.gprs,
+            Weak::<NoVm>::new(),
+            self.id,
+            &self.pending_interrupts,
+            Features {
+                dirty_tracking: DirtyTracking::WriteProtect,
+                interrupt_delivery: InterruptDelivery::Software,
+                exit_timer: ExitTimer::HostTimer,
+            },
+            &mut self.exit_deadline,
+        )
+    }
+}
+
+// The vm of the mock vcpus, which never exists.
+struct NoVm;
+
+impl VmOps for NoVm {
+    fn kick_vcpu(&self, _id: usize) -> Result<(), VmError> {
+        unreachable!()
+    }
+    fn exit(&self, _exit_code: i32) {
+        unreachable!()
+    }
+    fn start_vcpu(&self, _id: usize, _ip: u16) -> Result<(), VmError> {
+        unreachable!()
+    }
+    fn get_vcpu(&self, _id: usize) -> Option<&dyn VCpuOps> {
+        unreachable!()
+    }
+    fn resume_vcpu(&self, _id: usize) {
+        unreachable!()
+    }
+    fn vcpu_count(&self) -> usize {
+        unreachable!()
+    }
+    fn uuid(&self) -> Uuid {
+        unreachable!()
+    }
+    fn pmu(&self) -> &PmuStats {
+        unreachable!()
+    }
+    fn console(&self) -> &Console {
+        unreachable!()
+    }
+    fn vcpu_cpu_time(&self, _id: usize) -> Option<u64> {
+        unreachable!()
+    }
+}
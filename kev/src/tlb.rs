@@ -0,0 +1,114 @@
+//! Management of the cached guest translations.
+//!
+//! The processor caches the guest-physical mappings derived from the EPT, and
+//! the linear mappings tagged with the virtual-processor identifier (VPID).
+//! After modifying an EPT while a vcpu keeps running, the stale mappings must
+//! be invalidated with INVEPT, on every core that may have cached them.
+//!
+//! This module allocates VPIDs for vcpus, and issues INVEPT/INVVPID with the
+//! narrowest scope that the processor supports.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 28.3.3 Invalidating Cached Translation Information.
+use crate::{vm::Gva, vm_control::IA32_VMX_EPT_VPID_CAP, Bits};
+use abyss::x86_64::msr::Msr;
+use keos::{
+    addressing::Pa,
+    mm::tlb::{shootdown, Flush},
+    sync::SpinLock,
+};
+
+// A.10 VPID and EPT Capabilities.
+const INVEPT: usize = 20;
+const INVEPT_SINGLE_CONTEXT: usize = 25;
+const INVVPID: usize = 32;
+const INVVPID_INDIVIDUAL_ADDRESS: usize = 40;
+const INVVPID_SINGLE_CONTEXT: usize = 41;
+
+// VPID 0 is reserved for the host.
+static VPIDS: SpinLock<[u64; 1024]> = SpinLock::new({
+    let mut bitmap = [0; 1024];
+    bitmap[0] = 1;
+    bitmap
+});
+
+/// A virtual-processor identifier.
+///
+/// The identifier is released when this is dropped.
+#[derive(Debug)]
+pub struct Vpid(u16);
+
+impl Vpid {
+    /// Allocate a new virtual-processor identifier.
+    ///
+    /// Returns `None` if all identifiers are in use.
+    pub fn new() -> Option<Self> {
+        let mut vpids = VPIDS.lock();
+        let (pos, bits) = vpids
+            .iter_mut()
+            .enumerate()
+            .find(|(_, b)| **b != u64::MAX)?;
+        let ofs = bits.trailing_ones() as usize;
+        *bits |= 1 << ofs;
+        Some(Self((pos * 64 + ofs) as u16))
+    }
+
+    /// Get the underlying identifier.
+    #[inline]
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Drop for Vpid {
+    fn drop(&mut self) {
+        // The next owner of this vpid must not see our translations.
+        invalidate_vpid(self.0);
+        let (pos, ofs) = (self.0 as usize / 64, self.0 as usize % 64);
+        VPIDS.lock()[pos] &= !(1 << ofs);
+    }
+}
+
+/// Check whether the processor supports the VPID.
+pub fn vpid_supported() -> bool {
+    Msr::<IA32_VMX_EPT_VPID_CAP>::read().bit_test(INVVPID)
+}
+
+/// Invalidate the mappings derived from the EPT rooted at `ept` on every core.
+///
+/// Single-context invalidation is used if supported, otherwise mappings of all
+/// EPTs are invalidated.
+pub fn invalidate_ept(ept: Pa) {
+    let cap = Msr::<IA32_VMX_EPT_VPID_CAP>::read();
+    if !cap.bit_test(INVEPT) {
+        return;
+    }
+    if cap.bit_test(INVEPT_SINGLE_CONTEXT) {
+        shootdown(Flush::Ept(unsafe { ept.into_usize() } as u64));
+    } else {
+        shootdown(Flush::AllEpt);
+    }
+}
+
+/// Invalidate the linear mappings tagged with `vpid` on every core.
+pub fn invalidate_vpid(vpid: u16) {
+    let cap = Msr::<IA32_VMX_EPT_VPID_CAP>::read();
+    if !cap.bit_test(INVVPID) {
+        return;
+    }
+    if cap.bit_test(INVVPID_SINGLE_CONTEXT) {
+        shootdown(Flush::Vpid(vpid));
+    } else {
+        shootdown(Flush::AllVpid);
+    }
+}
+
+/// Invalidate the linear mapping of `gva` tagged with `vpid` on every core.
+pub fn invalidate_gva(vpid: u16, gva: Gva) {
+    let cap = Msr::<IA32_VMX_EPT_VPID_CAP>::read();
+    if cap.bit_test(INVVPID_INDIVIDUAL_ADDRESS) {
+        shootdown(Flush::VpidAddress(vpid, unsafe { gva.into_usize() } as u64));
+    } else {
+        invalidate_vpid(vpid)
+    }
+}
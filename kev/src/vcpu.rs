@@ -1,16 +1,34 @@
 //! Virtual CPU implementation.
 use crate::{
+    audit::AuditLog,
+    caps::{ExitTimer, Features},
+    coverage::CoverageMap,
+    entry_state::GuestCpuState,
+    exit_history::{ExitHistory, ExitRecord},
+    exit_trace::{ExitTrace, Outcome},
+    icount::Icount,
+    metrics::VCpuCounters,
+    msr_area::{MsrArea, SwitchedMsrs},
+    pmu::VPmu,
+    replay::{Log, Replay},
+    stepping,
+    tlb::Vpid,
     vm::{Vm, VmOps, VmState},
     vm_control::*,
     vmcs::{ActiveVmcs, BasicExitReason, ExternalIntInfo, Field, Vmcs},
-    VmError,
+    Bits, Subsystem, VmError,
 };
 use abyss::spin_lock::SpinLock;
-use alloc::sync::Weak;
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+};
 use core::{
     arch::asm,
+    ops::Range,
     sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
+use keos::{addressing::Pa, stats::PerCpuCounter, thread::Thread};
 
 pub use abyss::{interrupt::GeneralPurposeRegisters, x86_64::*};
 use interrupt::IDT;
@@ -19,6 +37,24 @@ use msr::Msr;
 use segmentation::{Segment, SegmentTable, SEGMENT_TABLE};
 use table::SystemTableRegister;
 
+/// Size of the stack of a vcpu thread.
+///
+/// The vmexits are handled on the stack of the vcpu thread, which is larger
+/// than the default [`keos::thread::STACK_SIZE`].
+pub const VCPU_STACK_SIZE: usize = 0x100000;
+
+static VMEXITS: PerCpuCounter = PerCpuCounter::new("kev.vmexits");
+
+/// Get the number of vmexits occurred on the cpu `cpu`.
+pub fn vmexit_count_of(cpu: usize) -> Option<u64> {
+    VMEXITS.get_of(cpu)
+}
+
+/// Get the total number of vmexits occurred on all cpus.
+pub fn vmexit_count() -> u64 {
+    VMEXITS.get()
+}
+
 #[naked]
 unsafe extern "C" fn vmlaunch_resume(
     _gp: &mut GeneralPurposeRegisters,
@@ -143,6 +179,31 @@ where
     fn exit_ctls(&self) -> VmcsExitCtl;
     /// Get enabled entry control fields.
     fn entry_ctls(&self) -> VmcsEntryCtl;
+    /// Get the physical addresses of the I/O bitmap A and B.
+    ///
+    /// If this returns `Some`, the "use I/O bitmaps" control is enabled and the
+    /// bitmaps are installed on the vmcs. See [`IoBitmap`].
+    ///
+    /// [`IoBitmap`]: crate::io_bitmap::IoBitmap
+    fn io_bitmap(&self) -> Option<(Pa, Pa)> {
+        None
+    }
+    /// Get the physical address of the EPTP list.
+    ///
+    /// If this returns `Some` and the processor supports it, the guest can
+    /// switch between the listed EPTs with VMFUNC. See [`EptpList`].
+    ///
+    /// [`EptpList`]: crate::vmfunc::EptpList
+    fn eptp_list(&self) -> Option<Pa> {
+        None
+    }
+    /// Get the msrs of the guest to switch on the vm entries and exits.
+    ///
+    /// If this returns `Some`, the guest accesses the msrs directly. See
+    /// [`MsrArea`].
+    fn msr_area(&self) -> Option<MsrArea> {
+        None
+    }
     /// Initialize the guest state.
     fn init_guest_state(&self, vmcs: &ActiveVmcs) -> Result<(), VmError>;
     /// Handle the vmexit on this vcpu.
@@ -150,6 +211,15 @@ where
         &mut self,
         genenric_state: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError>;
+    /// Called before the vm entry once the exit deadline passes.
+    ///
+    /// See [`GenericVCpuState::set_exit_deadline`].
+    fn on_exit_deadline(
+        &mut self,
+        _generic_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        Ok(VmexitResult::Ok)
+    }
 }
 
 /// A visible state for VCpu.
@@ -162,8 +232,14 @@ pub struct GenericVCpuState<'a> {
     pub vm: Weak<dyn VmOps>,
     // smp id of this vcpu.
     id: usize,
+    // Virtual-processor identifier of this vcpu.
+    vpid: Option<u16>,
     // Pending interrupts.
     pending_interrupts: &'a [AtomicU64; 4],
+    // Features of the vm.
+    features: Features,
+    // Tsc deadline to force a vmexit.
+    exit_deadline: &'a mut Option<u64>,
 }
 
 impl<'a> GenericVCpuState<'a> {
@@ -173,12 +249,63 @@ impl<'a> GenericVCpuState<'a> {
         self.id
     }
 
+    /// Get virtual-processor identifier of this vcpu.
+    ///
+    /// Returns `None` if the vcpu runs without VPID.
+    #[inline]
+    pub fn vpid(&self) -> Option<u16> {
+        self.vpid
+    }
+
+    /// Get the features of the vm.
+    #[inline]
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Get the tsc deadline to force a vmexit.
+    #[inline]
+    pub fn exit_deadline(&self) -> Option<u64> {
+        *self.exit_deadline
+    }
+
+    /// Force a vmexit once the tsc reaches `deadline`, and call
+    /// [`VCpuState::on_exit_deadline`].
+    ///
+    /// The vmexit is forced by the VMX-preemption timer, or by the host timer
+    /// tick on the cpus without it; see [`ExitTimer`].
+    pub fn set_exit_deadline(&mut self, deadline: Option<u64>) {
+        *self.exit_deadline = deadline;
+    }
+
     /// Inject the interrupt `vec` into the `active_vmcs`.
     pub fn inject_interrupt(&self, vec: u8) {
         // Inject interrupt to the interrupt window
         let (index, ofs) = (vec / 64, vec & 63);
         self.pending_interrupts[index as usize].store(1 << ofs, Ordering::SeqCst);
     }
+
+    // Build the state of the vcpu `id` that is not backed by a `VCpu`.
+    pub(crate) fn detached(
+        vmcs: ActiveVmcs,
+        gprs: &'a mut GeneralPurposeRegisters,
+        vm: Weak<dyn VmOps>,
+        id: usize,
+        pending_interrupts: &'a [AtomicU64; 4],
+        features: Features,
+        exit_deadline: &'a mut Option<u64>,
+    ) -> Self {
+        Self {
+            vmcs,
+            gprs,
+            vm,
+            id,
+            vpid: None,
+            pending_interrupts,
+            features,
+            exit_deadline,
+        }
+    }
 }
 
 /// Virtual cpu.
@@ -192,21 +319,63 @@ pub struct VCpu<S: VmState + 'static> {
     launched: bool,
     /// vcpu id.
     pub vcpu_id: usize,
+    /// Virtual-processor identifier.
+    vpid: Option<Vpid>,
+    /// Virtual performance monitoring unit.
+    vpmu: VPmu,
+    /// The msrs switched on the vm entries and exits.
+    msrs: Option<SwitchedMsrs>,
+    /// Record and replay state.
+    pub(crate) replay: Option<Replay>,
     /// The state of VCpu.
     state: S::VcpuState,
     /// Vm that owned this VCpu.
-    vm: Weak<Vm<S>>,
+    pub(crate) vm: Weak<Vm<S>>,
     /// pending interrupt bitmask
     pending_interrupts: [AtomicU64; 4],
+    /// Features of the vm.
+    features: Features,
+    /// Tsc deadline to force a vmexit.
+    exit_deadline: Option<u64>,
+    /// The recent vmexits.
+    exits: ExitHistory,
+    /// Trace of the vmexits of the vm.
+    trace: Arc<ExitTrace>,
+    /// Audit log of the device accesses of the vm.
+    audit: Arc<AuditLog>,
+    /// Coverage of the guest code of the vm.
+    coverage: Arc<CoverageMap>,
+    /// Counters of the vmexits and the injected interrupts.
+    pub(crate) counters: VCpuCounters,
+    /// The instructions to emulate before running on the hardware.
+    pub(crate) steps: Range<u64>,
+    /// The instruction count to stop at.
+    icount: Option<Icount>,
 }
 
 impl<'a, S: VmState + 'static> VCpu<S> {
-    pub(crate) fn new(vcpu_id: usize, state: S::VcpuState, vm: Weak<Vm<S>>) -> Self {
+    pub(crate) fn new(
+        vcpu_id: usize,
+        state: S::VcpuState,
+        vm: Weak<Vm<S>>,
+        features: Features,
+        trace: Arc<ExitTrace>,
+        audit: Arc<AuditLog>,
+        coverage: Arc<CoverageMap>,
+    ) -> Self {
         Self {
             vmcs: Vmcs::new(),
             gprs: GeneralPurposeRegisters::default(),
             launched: false,
             vcpu_id,
+            vpid: if crate::tlb::vpid_supported() {
+                Vpid::new()
+            } else {
+                None
+            },
+            vpmu: VPmu::new(),
+            msrs: None,
+            replay: None,
             state,
             vm,
             pending_interrupts: [
@@ -215,32 +384,149 @@ impl<'a, S: VmState + 'static> VCpu<S> {
                 AtomicU64::new(0),
                 AtomicU64::new(0),
             ],
+            features,
+            exit_deadline: None,
+            exits: ExitHistory::new(),
+            trace,
+            audit,
+            coverage,
+            counters: VCpuCounters::default(),
+            steps: 0..0,
+            icount: None,
         }
     }
 
+    /// Iterate over the recent vmexits of this vcpu, from the oldest to the
+    /// latest.
+    pub fn recent_exits(&self) -> impl Iterator<Item = &ExitRecord> {
+        self.exits.iter()
+    }
+
+    /// Take the log of the recorded or the remaining replayed inputs.
+    pub fn take_replay_log(&mut self) -> Option<Log> {
+        self.replay.take().map(Replay::into_log)
+    }
+
+    /// Save the full register state of the vcpu.
+    ///
+    /// The vcpu is paused while it is borrowed, as the vcpu thread holds its
+    /// lock while running it.
+    pub fn get_state(&mut self) -> Result<GuestCpuState, VmError> {
+        let activated = self.unpack_activate()?;
+        let generic_state = &activated.generic_state;
+        GuestCpuState::read(&generic_state.vmcs, generic_state.gprs)
+    }
+
+    /// Restore the full register state of the vcpu.
+    ///
+    /// The vm-entry controls are not changed; the [`VCpuState::entry_ctls`]
+    /// must be consistent with the mode of the `state`.
+    pub fn set_state(&mut self, state: &GuestCpuState) -> Result<(), VmError> {
+        let mut activated = self.unpack_activate()?;
+        let generic_state = &mut activated.generic_state;
+        state.write(&generic_state.vmcs, generic_state.gprs)
+    }
+
+    /// Get the guest value of the switched msr `index`.
+    ///
+    /// See [`VCpuState::msr_area`].
+    pub fn guest_msr(&self, index: u32) -> Option<u64> {
+        self.msrs.as_ref()?.get(index)
+    }
+
+    /// Set the guest value of the switched msr `index`.
+    ///
+    /// Returns false if the msr is not switched.
+    pub fn set_guest_msr(&mut self, index: u32, value: u64) -> bool {
+        self.msrs
+            .as_mut()
+            .is_some_and(|msrs| msrs.set(index, value))
+    }
+
     pub(crate) fn unpack_activate(&mut self) -> Result<Activated<S>, VmError> {
         let Self {
             vmcs,
             gprs,
             vcpu_id,
+            vpid,
+            vpmu,
+            msrs,
+            replay,
             state,
             launched,
             vm,
             pending_interrupts,
+            features,
+            exit_deadline,
+            exits,
+            trace,
+            audit,
+            coverage,
+            counters,
+            steps,
+            icount,
         } = self;
         Ok(Activated {
             generic_state: GenericVCpuState {
                 vmcs: Vmcs::activate(vmcs)?,
                 gprs,
                 id: *vcpu_id,
+                vpid: vpid.as_ref().map(Vpid::get),
                 vm: vm.clone(),
                 pending_interrupts,
+                features: *features,
+                exit_deadline,
             },
             vcpu_state: state,
+            vpmu,
+            msrs,
+            replay,
             launched,
             vmcs,
+            exits,
+            trace,
+            audit,
+            coverage,
+            counters,
+            steps,
+            icount,
         })
     }
+
+    /// Run the vcpu on the current thread until it retires the `n`
+    /// instructions.
+    ///
+    /// Returns `None` when the vcpu stops right before the next instruction,
+    /// or the exit code if the vcpu exits before. The vcpu must not be started
+    /// on its own thread. See [`icount`](crate::icount) for the details.
+    pub fn run_until_icount(&mut self, n: u64) -> Result<Option<i32>, VmError> {
+        crate::caps::probe()
+            .procbased
+            .check(VmcsProcBasedVmexecCtl::MTF)?;
+        let counter = self.vpmu.reserve_counter().ok_or_else(|| {
+            VmError::VCpuError(Box::new(
+                "The performance counters can not count the guest.",
+            ))
+        })?;
+        self.icount = Some(Icount::new(n, counter));
+        let have_kicked = AtomicBool::new(false);
+        let result = loop {
+            let _p = Thread::pin();
+            let r = self
+                .unpack_activate()
+                .and_then(|mut activated| activated.vcpu_loop(&have_kicked, None));
+            match r {
+                Ok(VmexitResult::ExtInt(vec)) => abyss::interrupt::irq_handler(vec as usize),
+                Ok(VmexitResult::Icount) => break Ok(None),
+                Ok(VmexitResult::Exited(exit_code)) => break Ok(Some(exit_code)),
+                Ok(_) => (),
+                Err(e) => break Err(e),
+            }
+        };
+        self.icount = None;
+        self.vpmu.release_counter();
+        result
+    }
 }
 
 /// VCpuOps
@@ -262,17 +548,44 @@ impl<'a, S: VmState + 'static> VCpuOps for SpinLock<VCpu<S>> {
 pub(crate) struct Activated<'a, S: VmState + 'static> {
     pub(crate) generic_state: GenericVCpuState<'a>,
     pub(crate) vcpu_state: &'a mut S::VcpuState,
+    vpmu: &'a mut VPmu,
+    msrs: &'a mut Option<SwitchedMsrs>,
+    replay: &'a mut Option<Replay>,
     vmcs: &'a mut Vmcs,
     launched: &'a mut bool,
+    exits: &'a mut ExitHistory,
+    trace: &'a ExitTrace,
+    audit: &'a AuditLog,
+    coverage: &'a CoverageMap,
+    counters: &'a mut VCpuCounters,
+    steps: &'a mut Range<u64>,
+    icount: &'a mut Option<Icount>,
 }
 
 impl<'a, S: VmState + 'static> Activated<'a, S> {
     pub(crate) unsafe fn init_vcpu(&mut self, exception_bitmap: u32) -> Result<(), VmError> {
         let Self {
-            generic_state: GenericVCpuState { vmcs, .. },
+            generic_state:
+                GenericVCpuState {
+                    vmcs,
+                    vpid,
+                    features,
+                    ..
+                },
             vcpu_state,
+            vpmu,
+            msrs,
+            replay,
             ..
         } = self;
+        // The controls requested by the vm must be supported. Otherwise, the
+        // vmentry fails or the control is silently dropped.
+        let caps = crate::caps::probe();
+        caps.pinbased.check(vcpu_state.pinbase_ctls())?;
+        caps.procbased.check(vcpu_state.procbase_ctls())?;
+        caps.procbased2.check(vcpu_state.procbase_ctls2())?;
+        caps.exit.check(vcpu_state.exit_ctls())?;
+        caps.entry.check(vcpu_state.entry_ctls())?;
         // 26.2.1.1 VM-Execution Control Fields
         {
             // Reserved bits in the pin-based VM-execution controls must be set properly. Software may consult the VMX
@@ -287,6 +600,10 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                 );
                 // enable the guest external interrupt exit
                 enabled |= vcpu_state.pinbase_ctls();
+                // Force the vmexits at the exit deadline.
+                if let ExitTimer::PreemptionTimer { .. } = features.exit_timer {
+                    enabled |= VmcsPinBasedVmexecCtl::ACTIVE_VMX_PREEMPTION_TIMER;
+                }
                 vmcs.write(
                     Field::PinBasedExecControls,
                     (enabled & supported).bits() as u64,
@@ -303,9 +620,30 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                     ),
                 );
                 // Make sure there are secondary controls.
-                assert!(supported.contains(VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL));
+                caps.procbased
+                    .check(VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL)?;
                 enabled |= VmcsProcBasedVmexecCtl::ACTIVATE_SECONDARY_CTL;
                 enabled |= vcpu_state.procbase_ctls();
+                // The time-stamp counter is an input of the guest.
+                if replay.is_some() {
+                    enabled |= VmcsProcBasedVmexecCtl::RDTSCEXIT;
+                }
+                // If the “use I/O bitmaps” VM-execution control is 1, bits 11:0 of each I/O-bitmap address must be 0.
+                if let Some((a, b)) = vcpu_state.io_bitmap() {
+                    enabled |= VmcsProcBasedVmexecCtl::USEIOBMP;
+                    vmcs.write(Field::IoBitmapA, a.into_usize() as u64)?;
+                    vmcs.write(Field::IoBitmapB, b.into_usize() as u64)?;
+                }
+                // Let the guest access the switched msrs directly.
+                if let Some(area) = vcpu_state.msr_area() {
+                    caps.procbased.check(VmcsProcBasedVmexecCtl::USEMSRBMP)?;
+                    let switched = area.build().ok_or_else(|| {
+                        VmError::VCpuError(Box::new("Failed to build the msr area."))
+                    })?;
+                    enabled |= VmcsProcBasedVmexecCtl::USEMSRBMP;
+                    switched.install(vmcs)?;
+                    **msrs = Some(switched);
+                }
                 vmcs.write(
                     Field::ProcessorBasedVmexecControls,
                     (enabled & supported).bits() as u64,
@@ -325,6 +663,24 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                     ),
                 );
                 enabled |= vcpu_state.procbase_ctls2();
+                // Tag the guest's linear mappings with the vpid, so that they
+                // survive across the VM entries and exits.
+                if let Some(vpid) = vpid {
+                    if supported.contains(VmcsProcBasedSecondaryVmexecCtl::EANBLE_VPID) {
+                        enabled |= VmcsProcBasedSecondaryVmexecCtl::EANBLE_VPID;
+                        vmcs.write(Field::Vpid, *vpid as u64)?;
+                    }
+                }
+                // Let the guest switch the EPT views without a vmexit.
+                if let Some(list) = vcpu_state.eptp_list() {
+                    if crate::vmfunc::eptp_switching_supported() {
+                        enabled |= VmcsProcBasedSecondaryVmexecCtl::ENABLE_VM_FUNCTIONS;
+                        vmcs.write(Field::VmfuncCtrls, crate::vmfunc::EPTP_SWITCHING)?;
+                        vmcs.write(Field::EptpListAddress, list.into_usize() as u64)?;
+                    } else {
+                        warning!("EPTP switching is not supported. VMFUNC will cause #UD.");
+                    }
+                }
                 vmcs.write(
                     Field::SecondaryVmexecControls,
                     (enabled & supported).bits() as u64,
@@ -340,6 +696,15 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                     VmcsExitCtl::from_bits_unchecked(exit_ctls as u32),
                 );
                 enabled |= vcpu_state.exit_ctls();
+                // Stop the performance counters of the guest on the vmexit.
+                if crate::pmu::info().is_some_and(|info| info.version > 1)
+                    && supported.contains(VmcsExitCtl::LOAD_IA32_PERF_GLOBAL_CTRL)
+                    && Msr::<IA32_VMX_ENTRY_CTLS>::read().bit_test(32 + 13)
+                {
+                    enabled |= VmcsExitCtl::LOAD_IA32_PERF_GLOBAL_CTRL;
+                    vmcs.write(Field::HostIa32PerfGlobalCtrl, 0)?;
+                    vpmu.switch_global_ctrl = true;
+                }
                 vmcs.write(Field::VmexitControls, (enabled & supported).bits() as u64)?;
             }
             // 26.2.1.3 VM-Entry Control Fields
@@ -352,9 +717,15 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                     VmcsEntryCtl::from_bits_unchecked(entry_ctls as u32),
                 );
                 enabled |= vcpu_state.entry_ctls();
+                // Start the performance counters of the guest on the vm entry.
+                if vpmu.switch_global_ctrl {
+                    enabled |= VmcsEntryCtl::LOAD_IA32_PERF_GLOBAL_CTRL;
+                }
                 vmcs.write(Field::VmentryControls, (supported & enabled).bits() as u64)?;
             }
             vmcs.write(Field::ExceptionBitmap, exception_bitmap as u64)?;
+            // No event to inject on the first vm entry.
+            vmcs.write(Field::VmentryInterruptionInfo, 0)?;
         }
         // 26.2.2 Checks on Host Control Registers, MSRs, and SSP
         // 26.2.3 Checks on Host Segment and Descriptor-Table Registers
@@ -408,7 +779,7 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
 
             // Load gs, fs, tr
             vmcs.write(Field::HostFsBase, 0)?;
-            vmcs.write(Field::HostGsBase, 0)?;
+            vmcs.write(Field::HostGsBase, gs::base())?;
             let tss = unsafe { SegmentTable::current_tss() };
             vmcs.write(Field::HostTrBase, tss as *mut _ as usize as u64)?;
 
@@ -418,7 +789,11 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
         vcpu_state.init_guest_state(vmcs)
     }
 
-    pub fn vcpu_loop(&mut self, have_kicked: &AtomicBool) -> Result<VmexitResult, VmError> {
+    pub fn vcpu_loop(
+        &mut self,
+        have_kicked: &AtomicBool,
+        quota_deadline: Option<u64>,
+    ) -> Result<VmexitResult, VmError> {
         assert_eq!(
             abyss::interrupt::InterruptState::current(),
             abyss::interrupt::InterruptState::Off
@@ -426,11 +801,37 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
         let Self {
             generic_state,
             vcpu_state,
+            vpmu,
+            msrs,
+            replay,
             launched,
+            exits,
+            trace,
+            audit,
+            coverage,
+            counters,
+            steps,
+            icount,
             ..
         } = self;
+        vpmu.load(&generic_state.vmcs)?;
+        // The thread is pinned while running the vcpu.
+        if let Some(msrs) = msrs {
+            msrs.save_host();
+        }
         unsafe {
             loop {
+                // The exit deadline passed.
+                if generic_state
+                    .exit_deadline
+                    .is_some_and(|deadline| core::arch::x86_64::_rdtsc() >= deadline)
+                {
+                    *generic_state.exit_deadline = None;
+                    let r = vcpu_state.on_exit_deadline(generic_state)?;
+                    if !complete_exit(&generic_state.vmcs, &r)? {
+                        return Ok(r);
+                    }
+                }
                 // CHAPTER 26. VM ENTRIES
                 //
                 // Each VM entry performs the following steps in the order indicated:
@@ -458,24 +859,43 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                 // the failure is stored in the VM-instruction error field. See Chapter 30 for the error numbers.
 
                 // Inject pending interrupt if exists.
+                //
+                // An event injected by the vmexit handler (e.g. an exception)
+                // is delivered first, and the interrupt waits for the next
+                // interrupt window.
+                //
+                // While recording or replaying, the interrupts are injected
+                // only right after the synchronous vmexits (see `replay`).
+                let event_pending =
+                    generic_state.vmcs.read(Field::VmentryInterruptionInfo)? & (1 << 31) != 0;
+                if let Some(vec) = replay.as_mut().and_then(Replay::replay_interrupt) {
+                    generic_state
+                        .vmcs
+                        .write(Field::VmentryInterruptionInfo, vec as u64 | (1 << 31))?;
+                }
+                let inject_allowed = replay.as_ref().map_or(true, Replay::can_inject);
                 for (index, intr_bitmap) in generic_state.pending_interrupts.iter().enumerate() {
                     let v = intr_bitmap.load(Ordering::SeqCst);
-                    if v != 0 {
+                    if v != 0 && inject_allowed {
                         let guest_rflags = Rflags::from_bits_truncate(
                             generic_state
                                 .vmcs
                                 .read(Field::GuestRflags)
                                 .expect("Failed to read guest rflags."),
                         );
-                        if guest_rflags.contains(Rflags::IF) {
+                        if guest_rflags.contains(Rflags::IF) && !event_pending {
                             let ofs = v.trailing_zeros() as usize;
                             intr_bitmap.fetch_and(!(1 << ofs), Ordering::SeqCst);
                             let vec = (index * 64 + ofs) as u64;
+                            counters.injected += 1;
                             generic_state
                                 .vmcs
                                 .write(Field::VmentryInterruptionInfo, vec as u64 | (1 << 31))
                                 .expect("Failed to set VmentryInterruptionInfo.");
-                        } else {
+                            if let Some(replay) = replay {
+                                replay.record_interrupt(vec as u8);
+                            }
+                        } else if replay.is_none() {
                             // We required to wait until Rflags::IF is set. Trap immediatly when it becomes 1.
                             let proc_based_ctls = VmcsProcBasedVmexecCtl::from_bits_unchecked(
                                 generic_state
@@ -501,17 +921,95 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                     return Ok(VmexitResult::Kicked);
                 }
 
+                // Check whether this vcpu used up its cpu quota.
+                if quota_deadline.is_some_and(|deadline| core::arch::x86_64::_rdtsc() >= deadline) {
+                    return Ok(VmexitResult::Throttled);
+                }
+
+                // Without a deadline, the preemption timer fires rarely, and
+                // the vmexit is ignored.
+                if let ExitTimer::PreemptionTimer { rate } = generic_state.features.exit_timer {
+                    let deadline = match (*generic_state.exit_deadline, quota_deadline) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                    let ticks = deadline.map_or(u32::MAX as u64, |deadline| {
+                        deadline.saturating_sub(core::arch::x86_64::_rdtsc()) >> rate
+                    });
+                    generic_state
+                        .vmcs
+                        .write(Field::GuestPreemptionTimerValue, ticks.min(u32::MAX as u64))?;
+                }
+
+                // Stop at the instruction count.
+                if let Some(icount) = icount.as_mut() {
+                    if icount.reached(&generic_state.vmcs)? {
+                        return Ok(VmexitResult::Icount);
+                    }
+                }
+
+                // Emulate the first instructions in the bring-up mode.
+                if !steps.is_empty() {
+                    if stepping::step(generic_state, steps.start)? {
+                        steps.start += 1;
+                        if let Some(icount) = icount.as_mut() {
+                            icount.retire(1);
+                        }
+                        continue;
+                    }
+                    **steps = 0..0;
+                }
+
+                if let Some(icount) = icount.as_mut() {
+                    icount.arm(&generic_state.vmcs)?;
+                }
+                if coverage.is_enabled() {
+                    coverage.arm(&generic_state.vmcs)?;
+                }
+
                 match vmlaunch_resume(generic_state.gprs, launched) {
                     0 => {
+                        // The guest may have swapped the IA32_KERNEL_GS_BASE.
+                        gs::restore();
+                        VMEXITS.inc();
+                        counters.exits += 1;
                         let rip = generic_state.vmcs.read(Field::GuestRip)?;
-                        if let Err(err) = match generic_state.vmcs.exit_reason()?.get_basic_reason()
-                        {
+                        let exit_reason = generic_state.vmcs.exit_reason()?;
+                        if let Some(icount) = icount.as_mut() {
+                            let mtf =
+                                matches!(exit_reason.get_basic_reason(), BasicExitReason::Mtf);
+                            icount.on_vmexit(&generic_state.vmcs, rip, mtf)?;
+                        }
+                        if coverage.is_enabled() {
+                            coverage.on_vmexit(&generic_state.vmcs)?;
+                        }
+                        let exit = ExitRecord {
+                            reason: exit_reason,
+                            qualification: generic_state.vmcs.read(Field::VmexitQualification)?,
+                            rip,
+                            tsc: core::arch::x86_64::_rdtsc(),
+                        };
+                        let mut line = trace.start(generic_state.id, &exit, generic_state.gprs);
+                        let mut access = audit.start(generic_state, &exit);
+                        exits.push(exit);
+                        let r = match exit_reason.get_basic_reason() {
                             BasicExitReason::ExternalInt(Some(ExternalIntInfo {
                                 host_int,
                                 ..
                             })) => {
+                                if let Some(replay) = replay {
+                                    replay.on_vmexit(false);
+                                }
+                                if let Some(line) = line {
+                                    trace.finish(line, Outcome::Kev);
+                                }
                                 return Ok(VmexitResult::ExtInt(*host_int));
                             }
+                            // Handled at the next vm entry.
+                            BasicExitReason::VmxPreemptTimer => Ok(()),
+                            BasicExitReason::Mtf if icount.is_some() || coverage.is_enabled() => {
+                                Ok(())
+                            }
                             BasicExitReason::InterruptWindow => {
                                 let proc_based_ctls = VmcsProcBasedVmexecCtl::from_bits_unchecked(
                                     generic_state
@@ -530,17 +1028,64 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
                                     .expect("Failed to update ProcessorBasedVmexecControls.");
                                 Ok(())
                             }
-                            _ => match vcpu_state.handle_vmexit(generic_state) {
-                                Ok(VmexitResult::Ok) => Ok(()),
-                                r => return r,
-                            },
-                        } {
-                            println!("err {:?} rip: {:x}", err, rip);
+                            reason @ (BasicExitReason::Rdmsr | BasicExitReason::Wrmsr)
+                                if vpmu.handles(generic_state.gprs.rcx as u32) =>
+                            {
+                                vpmu.emulate(
+                                    matches!(reason, BasicExitReason::Wrmsr),
+                                    generic_state,
+                                )
+                            }
+                            reason @ (BasicExitReason::Rdtsc | BasicExitReason::Rdtscp)
+                                if replay.is_some() =>
+                            {
+                                let replay = replay.as_mut().unwrap();
+                                replay.on_vmexit(true);
+                                replay.emulate_tsc(
+                                    matches!(reason, BasicExitReason::Rdtscp),
+                                    generic_state,
+                                )
+                            }
+                            _ => {
+                                let r = vcpu_state.handle_vmexit(generic_state);
+                                if let Some(line) = line.take() {
+                                    trace.finish(line, Outcome::Handled(&r));
+                                }
+                                if let Some(access) = access.take() {
+                                    audit.finish(access, generic_state, &r);
+                                }
+                                match r {
+                                    Ok(r) if complete_exit(&generic_state.vmcs, &r)? => {
+                                        match replay {
+                                            Some(replay) => {
+                                                replay.on_vmexit(true);
+                                                replay.exit(generic_state)
+                                            }
+                                            None => Ok(()),
+                                        }
+                                    }
+                                    r => return r.map_err(|e| e.with_rip(rip as usize)),
+                                }
+                            }
+                        };
+                        if let Some(line) = line {
+                            trace.finish(line, Outcome::Kev);
+                        }
+                        if let Err(err) = r {
+                            let err = err.with_rip(rip as usize);
+                            println!("{}", err);
                             generic_state.vmcs.dump();
+                            exits.dump();
                             return Err(err);
                         }
                     }
-                    1 | 2 => return Err(VmError::VmxOperationError(Vmcs::instruction_error())),
+                    1 | 2 => {
+                        exits.dump();
+                        let rip = generic_state.vmcs.read(Field::GuestRip)?;
+                        return Err(VmError::VmxOperationError(Vmcs::instruction_error())
+                            .context(Subsystem::Vmx)
+                            .with_rip(rip as usize));
+                    }
                     _ => unreachable!(),
                 }
             }
@@ -548,17 +1093,67 @@ impl<'a, S: VmState + 'static> Activated<'a, S> {
     }
 }
 
+// Complete the exit that is handled with the `result`.
+//
+// Returns `false` if the vcpu does not continue.
+fn complete_exit(vmcs: &ActiveVmcs, result: &VmexitResult) -> Result<bool, VmError> {
+    match result {
+        VmexitResult::Ok | VmexitResult::HandledNoAdvance => (),
+        VmexitResult::HandledAdvance => vmcs.forward_rip()?,
+        VmexitResult::Redeliver => {
+            let info = vmcs.read(Field::IdtVectoringInfo)?;
+            if info & (1 << 31) != 0 {
+                // Bit 12 is undefined in the vm-entry interruption info.
+                vmcs.write(Field::VmentryInterruptionInfo, info & !(1 << 12))?;
+                if info & (1 << 11) != 0 {
+                    vmcs.write(
+                        Field::VmentryExceptionErrCode,
+                        vmcs.read(Field::IdtVectoringErrCode)?,
+                    )?;
+                }
+                // The length of a software interrupt or exception.
+                vmcs.write(
+                    Field::VmentryInstructionLength,
+                    vmcs.read(Field::VmexitInstructionLength)?,
+                )?;
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
 impl<'a, S: VmState> Drop for Activated<'a, S> {
     fn drop(&mut self) {
+        let counts = self.vpmu.save();
+        if let Some(vm) = self.generic_state.vm.upgrade() {
+            vm.pmu().add(&counts);
+        }
         *self.launched = false;
         self.vmcs.clear().unwrap();
     }
 }
 
 /// Possible result of the Vmexit.
+///
+/// The vcpu loop completes the exit by the result, reading the length of the
+/// exiting instruction once, so the controllers do not move the rip by
+/// themselves.
 pub enum VmexitResult {
     /// VCpu can be continued.
     Ok,
+    /// The exiting instruction is emulated, and the vcpu continues from the
+    /// next instruction.
+    HandledAdvance,
+    /// The exit is handled, and the vcpu continues from the rip as is, e.g.
+    /// the handler injected a fault or moved the rip by itself.
+    ///
+    /// Same as [`VmexitResult::Ok`].
+    HandledNoAdvance,
+    /// The exit is handled, and the event that was being delivered when the
+    /// exit occurred (e.g. an exception whose delivery caused an ept
+    /// violation) is delivered again.
+    Redeliver,
     /// VCpu is exited.
     Exited(i32),
     /// External Interrupt is come.
@@ -569,4 +1164,12 @@ pub enum VmexitResult {
     ///
     /// This is for internal-control uses.
     Kicked,
+    /// VCpu used up its cpu quota.
+    ///
+    /// This is for internal-control uses.
+    Throttled,
+    /// VCpu retired the instructions of [`VCpu::run_until_icount`].
+    ///
+    /// This is for internal-control uses.
+    Icount,
 }
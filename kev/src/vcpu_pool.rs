@@ -0,0 +1,81 @@
+//! Pool of the vcpu threads.
+//!
+//! Creating a vm spawns a thread for each vcpu, and the threads exit with the
+//! vm. When the vms are created and destroyed repeatedly, e.g. in a test loop,
+//! the threads and their stacks are allocated and freed on every cycle. The vm
+//! built with [`VmBuilder::pooled`] instead runs its vcpus on the workers of
+//! this pool. A worker parks itself in the pool when its vcpu exits, and is
+//! reused by the next vcpu to start.
+//!
+//! The job of a worker owns all the per-vcpu state, which is dropped before
+//! the worker returns to the pool, so the next vcpu starts from a clean state.
+//! A worker takes the name of its vcpu while running it, and is named
+//! `vcpu-worker#<n>` while idle.
+//!
+//! [`VmBuilder::pooled`]: crate::vm::VmBuilder::pooled
+use crate::vcpu::VCPU_STACK_SIZE;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use keos::{
+    sync::SpinLock,
+    thread::{self, JoinHandle, ParkHandle, Thread, ThreadBuilder},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Worker {
+    job: SpinLock<Option<(String, Job)>>,
+    name: String,
+}
+
+// Parked workers.
+static IDLE: SpinLock<Vec<(ParkHandle, Arc<Worker>)>> = SpinLock::new(Vec::new());
+// Number of the workers ever spawned.
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+
+fn work(worker: Arc<Worker>) {
+    loop {
+        let job = worker.job.lock().take();
+        if let Some((name, job)) = job {
+            thread::with_current(|th| th.set_name(name));
+            job();
+            thread::with_current(|th| th.set_name(worker.name.as_str()));
+        }
+        let worker = worker.clone();
+        Thread::park_current_and(move |park| IDLE.lock().push((park, worker)));
+    }
+}
+
+/// Run `f` on an idle worker named `name`, or on a new worker if none is
+/// idle.
+pub fn spawn(name: String, f: impl FnOnce() + Send + 'static) -> JoinHandle {
+    let idle = IDLE.lock().pop();
+    match idle {
+        Some((park, worker)) => {
+            *worker.job.lock() = Some((name, Box::new(f)));
+            let handle = park.join_handle();
+            park.unpark();
+            handle
+        }
+        None => {
+            let id = SPAWNED.fetch_add(1, Ordering::Relaxed);
+            let worker = Arc::new(Worker {
+                job: SpinLock::new(Some((name, Box::new(f)))),
+                name: alloc::format!("vcpu-worker#{}", id),
+            });
+            ThreadBuilder::new(worker.name.clone())
+                .stack_size(VCPU_STACK_SIZE)
+                .spawn(move || work(worker))
+        }
+    }
+}
+
+/// Number of the idle workers.
+pub fn idle() -> usize {
+    IDLE.lock().len()
+}
+
+/// Number of the workers ever spawned.
+pub fn spawned() -> usize {
+    SPAWNED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,240 @@
+//! Virtual time of the guest.
+//!
+//! A guest that keeps its time by counting the periodic timer ticks, e.g. the
+//! jiffies, falls behind the host when the ticks are missed: the vcpu thread
+//! is descheduled when the host cpus are overcommitted, and the timer that
+//! fires meanwhile is delivered once at most, as the pending interrupts of a
+//! vector collapse into a bit.
+//!
+//! A [`TickClock`] schedules the ticks of an emulated periodic timer, and
+//! applies a [`TickPolicy`] to the missed ticks:
+//!
+//! ```ignore
+//! let mut clock = TickClock::new(period, vm.tick_policy(), _rdtsc());
+//! loop {
+//!     wait_until(clock.deadline());
+//!     if clock.expire(_rdtsc()) {
+//!         vm.kick_vcpu(0)?;
+//!         vcpu.inject_interrupt(vec);
+//!         vm.resume_vcpu(0);
+//!     }
+//! }
+//! ```
+//!
+//! The policy of a vm is set with [`VmBuilder::tick_policy`]. The ticks are
+//! discarded by default.
+//!
+//! A [`DriftMeter`] measures the drift of the guest time against the host
+//! time, and [`Overcommit`] overcommits the host cpus with busy threads, to
+//! test the timekeeping of a guest under the contention.
+//!
+//! [`VmBuilder::tick_policy`]: crate::vm::VmBuilder::tick_policy
+use abyss::dev::x86_64::timer::tsc_khz;
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use keos::thread::{JoinHandle, ThreadBuilder};
+
+/// Policy on the missed timer ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TickPolicy {
+    /// Deliver a single tick for the missed ticks, and drop the others. The guest
+    /// time falls behind by the missed ticks.
+    #[default]
+    Discard,
+    /// Deliver the missed ticks in a burst, one every 1/16 of the period,
+    /// until the guest catches up.
+    CatchUp {
+        /// Maximum number of the missed ticks to deliver. The ticks missed
+        /// beyond are dropped.
+        max_backlog: u64,
+    },
+    /// Deliver the ticks faster by `percent` of the period until the guest
+    /// catches up, so that the guest time converges smoothly.
+    Slew {
+        /// Percentage to shorten the period by, less than 100.
+        percent: u64,
+    },
+}
+
+/// Schedule of the ticks of a periodic timer.
+#[derive(Clone, Debug)]
+pub struct TickClock {
+    policy: TickPolicy,
+    // Period of the ticks, in tsc cycles.
+    period: u64,
+    // Tsc of the tick 0.
+    start: u64,
+    // Number of the ticks delivered or dropped.
+    ticks: u64,
+    dropped: u64,
+    deadline: u64,
+}
+
+impl TickClock {
+    /// Create a clock that ticks every `period` tsc cycles from the tsc `now`.
+    pub fn new(period: u64, policy: TickPolicy, now: u64) -> Self {
+        assert!(period > 0, "Period must be positive.");
+        if let TickPolicy::Slew { percent } = policy {
+            assert!(percent < 100, "Slew must be less than 100%.");
+        }
+        Self {
+            policy,
+            period,
+            start: now,
+            ticks: 0,
+            dropped: 0,
+            deadline: now + period,
+        }
+    }
+
+    /// Get the tsc at which the next tick is due.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Get the number of the ticks delivered to the guest.
+    pub fn delivered(&self) -> u64 {
+        self.ticks - self.dropped
+    }
+
+    /// Get the number of the ticks dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Get the number of the ticks that are due but not delivered yet.
+    pub fn backlog(&self, now: u64) -> u64 {
+        self.due(now).saturating_sub(self.ticks)
+    }
+
+    /// Check whether a tick is delivered at the tsc `now`, and schedule the
+    /// next one.
+    pub fn expire(&mut self, now: u64) -> bool {
+        if now < self.deadline {
+            return false;
+        }
+        let due = self.due(now);
+        let backlog = due.saturating_sub(self.ticks);
+        // Drop the missed ticks that the policy does not deliver.
+        let keep = match self.policy {
+            TickPolicy::Discard => 1,
+            TickPolicy::CatchUp { max_backlog } => max_backlog.max(1),
+            TickPolicy::Slew { .. } => u64::MAX,
+        };
+        if backlog > keep {
+            self.dropped += backlog - keep;
+            self.ticks += backlog - keep;
+        }
+        self.ticks += 1;
+        let next_on_grid = self.start + (self.ticks + 1) * self.period;
+        self.deadline = if self.ticks >= due {
+            next_on_grid
+        } else {
+            match self.policy {
+                TickPolicy::Discard => next_on_grid,
+                TickPolicy::CatchUp { .. } => now + (self.period >> 4).max(1),
+                TickPolicy::Slew { percent } => now + self.period * (100 - percent) / 100,
+            }
+        };
+        true
+    }
+
+    // Number of the ticks due by the tsc `now`.
+    fn due(&self, now: u64) -> u64 {
+        now.saturating_sub(self.start) / self.period
+    }
+}
+
+/// A sample of the drift.
+#[derive(Clone, Copy, Debug)]
+pub struct DriftSample {
+    /// Host time since the start, in nanoseconds.
+    pub host_ns: u64,
+    /// Drift of the guest time, in nanoseconds. Negative if the guest is
+    /// behind the host.
+    pub drift_ns: i64,
+}
+
+/// Meter of the drift of the guest time against the host time.
+pub struct DriftMeter {
+    start: u64,
+    samples: Vec<DriftSample>,
+}
+
+impl DriftMeter {
+    /// Start measuring from now, when the guest time is zero.
+    pub fn start() -> Self {
+        Self {
+            start: unsafe { _rdtsc() },
+            samples: Vec::new(),
+        }
+    }
+
+    /// Record the guest time `guest_ns`, e.g. the ticks of a [`TickClock`]
+    /// times the period, at now.
+    pub fn record(&mut self, guest_ns: u64) -> DriftSample {
+        let khz = tsc_khz().max(1);
+        let host_ns = ((unsafe { _rdtsc() } - self.start) as u128 * 1_000_000 / khz as u128) as u64;
+        let sample = DriftSample {
+            host_ns,
+            drift_ns: guest_ns as i64 - host_ns as i64,
+        };
+        self.samples.push(sample);
+        sample
+    }
+
+    /// Get the recorded samples.
+    pub fn samples(&self) -> &[DriftSample] {
+        &self.samples
+    }
+
+    /// Get the largest lag of the guest behind the host, in nanoseconds.
+    pub fn max_lag_ns(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|s| s.drift_ns.min(0).unsigned_abs())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Get the drift of the last sample, in nanoseconds.
+    pub fn last_drift_ns(&self) -> i64 {
+        self.samples.last().map_or(0, |s| s.drift_ns)
+    }
+}
+
+/// Busy threads that overcommit the host cpus, until dropped.
+pub struct Overcommit {
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle>,
+}
+
+impl Overcommit {
+    /// Spawn `threads` busy threads.
+    pub fn start(threads: usize) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = (0..threads)
+            .map(|i| {
+                let stop = stop.clone();
+                ThreadBuilder::new(alloc::format!("overcommit#{}", i)).spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        core::hint::spin_loop();
+                    }
+                })
+            })
+            .collect();
+        Self { stop, threads }
+    }
+}
+
+impl Drop for Overcommit {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for th in self.threads.drain(..) {
+            th.join();
+        }
+    }
+}
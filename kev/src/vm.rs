@@ -1,12 +1,36 @@
 //! Virtual machine interface.
 use crate::{
-    vcpu::{GenericVCpuState, VCpu, VCpuOps, VCpuState},
+    async_call::CompletionQueue,
+    audit::{Audit, AuditLog},
+    caps::Features,
+    console::Console,
+    core_dump::{self, CoreDumpError, VCpuRegs},
+    coverage::{Coverage, CoverageMap},
+    cpu_quota::{self, CpuQuota, Throttle},
+    cpuid::PvFeatures,
+    e820::MemoryMap,
+    exit_trace::{self, ExitTrace, TraceOutput},
+    hotplug::{self, Hotplug, HotplugError, HotplugEvent},
+    metrics::{self, Metrics},
+    partition::{self, Partition},
+    pmu::{PmuCounts, PmuStats},
+    ram_crypt::{RamCipher, RamKey},
+    replay::{Log, Replay},
+    stream_codec::{CodecStats, Pipeline},
+    vcpu::{GenericVCpuState, VCpu, VCpuOps, VCpuState, VCPU_STACK_SIZE},
+    vcpu_pool,
+    virtual_time::TickPolicy,
+    vm_control::VmcsProcBasedVmexecCtl,
     vmcs::Field,
+    watchdog::{self, Heartbeat},
     VmError,
 };
-use abyss::dev::x86_64::apic::send_ipi;
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use abyss::{addressing::Pa, dev::x86_64::apic::send_ipi};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
 use keos::{
     sync::SpinLock,
     thread::{self, JoinHandle, ParkHandle, Thread, ThreadBuilder},
@@ -123,6 +147,79 @@ impl core::fmt::Display for Gva {
     }
 }
 
+/// Universally unique identifier of a virtual machine.
+///
+/// The identifier is exposed to the guest through the SMBIOS and the
+/// hypervisor CPUID leaf, so the guest (and the test orchestration) can tell
+/// the vms apart.
+#[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    /// Create a uuid from the bytes in the big-endian (RFC 4122) order.
+    #[inline]
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes in the big-endian (RFC 4122) order.
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Generate a random (version 4) uuid.
+    ///
+    /// The randomness comes from the timestamp counter, which is enough to
+    /// make the uuids of the vms on the same host distinct.
+    pub fn generate() -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        // splitmix64.
+        let mut state = unsafe { core::arch::x86_64::_rdtsc() }
+            ^ SEQ
+                .fetch_add(1, Ordering::Relaxed)
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&next().to_be_bytes());
+        bytes[8..].copy_from_slice(&next().to_be_bytes());
+        // Version 4, variant 1.
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+
+    /// Name a thread of the vm after its `role`, as `<uuid>/<role>`.
+    ///
+    /// The name tells the vm of the thread in [`keos::thread::dump_all`].
+    pub fn thread_name(&self, role: impl core::fmt::Display) -> String {
+        alloc::format!("{}/{}", self, role)
+    }
+}
+
+impl core::fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Uuid({})", self)
+    }
+}
+impl core::fmt::Display for Uuid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
 impl core::fmt::Debug for Gpa {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "Gpa(0x{:x})", self.0)
@@ -146,6 +243,37 @@ where
 
     /// Create per-vcpu private state.
     fn vcpu_state(&self) -> Self::VcpuState;
+    /// Get the uuid of this vm.
+    ///
+    /// Returns `None` to use a randomly generated uuid.
+    fn uuid(&self) -> Option<Uuid> {
+        None
+    }
+    /// Get the memory map of the guest.
+    ///
+    /// Returns `None` if the vm does not describe its memory.
+    fn memory_map(&self) -> Option<MemoryMap> {
+        None
+    }
+    /// Get the paravirtual features that the vm provides, reported to the
+    /// guest through the cpuid.
+    fn pv_features(&self) -> PvFeatures {
+        PvFeatures::empty()
+    }
+    /// Translate the guest physical address to the host physical address.
+    ///
+    /// Returns `None` if the address is not mapped.
+    fn gpa2hpa(&self, _gpa: Gpa) -> Option<Pa> {
+        None
+    }
+    /// Ask the guest to shut down, e.g. by injecting the interrupt of the
+    /// power button.
+    ///
+    /// Returns `false` if the guest can not be asked, then
+    /// [`VmHandle::shutdown`] terminates the vm immediately.
+    fn request_shutdown(&self, _vm: &dyn VmOps) -> bool {
+        false
+    }
     /// Setup the virtual bootstrap processor (bsp) state.
     fn setup_vbsp(
         &self,
@@ -173,6 +301,27 @@ pub struct MsrEntry {
     pub msr_value: u64,
 }
 
+// Set on the exit codes when exited.
+const EXITED: u64 = 0x8000_0000_0000_0000;
+// Interval to check whether the vm exits.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Exit status of the vm.
+#[derive(Clone, Debug)]
+pub struct ExitStatus {
+    /// Exit code of the vm.
+    pub code: i32,
+    /// Whether the vm is terminated by the host, instead of shutting down by
+    /// itself.
+    pub forced: bool,
+    /// Exit code of each vcpu, or `None` if the vcpu is not started or is
+    /// parked.
+    ///
+    /// The vcpu that exits the vm has the exit code of the vm, and the others
+    /// have the exit code that they are stopped with.
+    pub vcpus: Vec<Option<i32>>,
+}
+
 #[doc(hidden)]
 pub enum VCpuRunningState {
     Halted,
@@ -188,7 +337,34 @@ pub struct Vm<S: VmState + 'static> {
     vcpu: Vec<Arc<SpinLock<VCpu<S>>>>,
     pub(crate) state: S,
     pub(crate) exit_code: AtomicU64,
+    uuid: Uuid,
+    pmu: PmuStats,
+    console: Arc<Console>,
     vcpu_states: Vec<Arc<SpinLock<VCpuRunningState>>>,
+    vcpu_exits: Vec<AtomicU64>,
+    vcpu_cycles: Vec<AtomicU64>,
+    forced: AtomicBool,
+    pooled: AtomicBool,
+    features: Features,
+    heartbeat: SpinLock<Option<Heartbeat>>,
+    // Time of the last heartbeat, in nanoseconds.
+    last_heartbeat: AtomicU64,
+    exit_trace: Arc<ExitTrace>,
+    audit_log: Arc<AuditLog>,
+    coverage: Arc<CoverageMap>,
+    metrics: Metrics,
+    completions: CompletionQueue,
+    ram_key: SpinLock<Option<RamKey>>,
+    stream_codec: SpinLock<Option<Pipeline>>,
+    partition: SpinLock<Option<Partition>>,
+    tick_policy: SpinLock<TickPolicy>,
+    // Bitmask of the present vcpus.
+    present: AtomicU64,
+    hotplug: SpinLock<Option<Hotplug>>,
+    quota: SpinLock<Option<Throttle>>,
+    vcpu_quotas: Vec<SpinLock<Option<Throttle>>>,
+    // Time that the vcpus are throttled, in tsc cycles.
+    throttled_cycles: AtomicU64,
 }
 
 /// Handle for maintaining a VM.
@@ -199,10 +375,35 @@ pub struct VmHandle<S: VmState + 'static> {
 
 impl<S: VmState + 'static> VmHandle<S> {
     pub(crate) fn new(vcpu: usize, state: S) -> Result<Self, S::Error> {
+        let uuid = state.uuid().unwrap_or_else(Uuid::generate);
         let vm = Arc::new(Vm {
             vcpu: Vec::new(),
+            uuid,
+            pmu: PmuStats::default(),
+            console: Console::new(alloc::format!("{}", uuid)),
             state,
             exit_code: AtomicU64::new(0),
+            vcpu_exits: (0..vcpu).map(|_| AtomicU64::new(0)).collect(),
+            vcpu_cycles: (0..vcpu).map(|_| AtomicU64::new(0)).collect(),
+            forced: AtomicBool::new(false),
+            pooled: AtomicBool::new(false),
+            features: Features::probe(),
+            heartbeat: SpinLock::new(None),
+            last_heartbeat: AtomicU64::new(0),
+            exit_trace: Arc::new(ExitTrace::new()),
+            audit_log: Arc::new(AuditLog::new()),
+            coverage: Arc::new(CoverageMap::new()),
+            metrics: Metrics::new(vcpu),
+            completions: CompletionQueue::new(),
+            ram_key: SpinLock::new(None),
+            stream_codec: SpinLock::new(None),
+            partition: SpinLock::new(None),
+            tick_policy: SpinLock::new(TickPolicy::Discard),
+            present: AtomicU64::new(vcpu_mask(vcpu)),
+            hotplug: SpinLock::new(None),
+            quota: SpinLock::new(None),
+            vcpu_quotas: (0..vcpu).map(|_| SpinLock::new(None)).collect(),
+            throttled_cycles: AtomicU64::new(0),
             vcpu_states: (0..vcpu)
                 .map(|_| Arc::new(SpinLock::new(VCpuRunningState::Halted)))
                 .collect(),
@@ -217,8 +418,13 @@ impl<S: VmState + 'static> VmHandle<S> {
                 id,
                 this.vm.state.vcpu_state(),
                 Arc::downgrade(&this.vm),
+                this.vm.features,
+                this.vm.exit_trace.clone(),
+                this.vm.audit_log.clone(),
+                this.vm.coverage.clone(),
             ))))
         }
+        exit_trace::register(&this.vm.console, &this.vm.exit_trace);
         // SAFETY:
         // vcpu is not running.
         unsafe {
@@ -248,20 +454,194 @@ impl<S: VmState + 'static> VmHandle<S> {
         self.vm.vcpu.get(idx)
     }
 
+    /// Get the uuid of the vm.
+    #[inline]
+    pub fn uuid(&self) -> Uuid {
+        self.vm.uuid
+    }
+
+    /// Get the features that the vm uses, selected by the capabilities of
+    /// the cpu at the creation.
+    #[inline]
+    pub fn features(&self) -> Features {
+        self.vm.features
+    }
+
+    /// Get the counts of the performance events measured by the guest,
+    /// accumulated over all vcpus.
+    pub fn pmu_counts(&self) -> PmuCounts {
+        self.vm.pmu.get()
+    }
+
+    /// Dump the guest RAM and the vcpu registers into the file at `path`.
+    ///
+    /// See [`core_dump`] for the format.
+    #[inline]
+    pub fn dump_core(&self, path: &str) -> Result<usize, CoreDumpError> {
+        self.vm.dump_core(path)
+    }
+
+    /// Get the statistics of the stages of the stream codec.
+    ///
+    /// See [`stream_codec`](crate::stream_codec) for the details.
+    #[inline]
+    pub fn codec_stats(&self) -> Vec<(&'static str, CodecStats)> {
+        self.vm
+            .stream_codec
+            .lock()
+            .as_ref()
+            .map_or_else(Vec::new, Pipeline::stats)
+    }
+
+    /// Get the cpu time consumed by the vcpus, in tsc cycles.
+    #[inline]
+    pub fn cpu_time(&self) -> u64 {
+        self.vm.cpu_time()
+    }
+
+    /// Limit the cpu time of the vm to the `quota`, or remove the limit if
+    /// `None`.
+    ///
+    /// See [`cpu_quota`] for the details.
+    #[inline]
+    pub fn set_cpu_quota(&self, quota: Option<CpuQuota>) {
+        self.vm.set_cpu_quota(quota)
+    }
+
+    /// Limit the cpu time of the vcpu `id` to the `quota`, or remove the
+    /// limit if `None`.
+    #[inline]
+    pub fn set_vcpu_quota(&self, id: usize, quota: Option<CpuQuota>) -> Result<(), VmError> {
+        self.vm.set_vcpu_quota(id, quota)
+    }
+
+    /// Get the time that the vcpus are throttled by the cpu quotas, in tsc
+    /// cycles.
+    #[inline]
+    pub fn throttled_time(&self) -> u64 {
+        self.vm.throttled_cycles.load(Ordering::Relaxed)
+    }
+
+    /// Get the output of the vm console.
+    ///
+    /// See [`console`](crate::console) for the details.
+    #[inline]
+    pub fn console_output(&self) -> Vec<u8> {
+        self.vm.console_output()
+    }
+
+    /// Get the trace of the vmexits.
+    ///
+    /// See [`exit_trace`] for the details.
+    #[inline]
+    pub fn exit_trace(&self) -> &ExitTrace {
+        &self.vm.exit_trace
+    }
+
+    /// Get the audit log of the device accesses.
+    ///
+    /// See [`audit`](crate::audit) for the details.
+    #[inline]
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.vm.audit_log
+    }
+
+    /// Get the coverage of the guest code.
+    ///
+    /// See [`coverage`](crate::coverage) for the details.
+    #[inline]
+    pub fn coverage_map(&self) -> &CoverageMap {
+        &self.vm.coverage
+    }
+
+    /// Get the performance metrics of the vcpus.
+    ///
+    /// See [`metrics`] for the details.
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.vm.metrics
+    }
+
+    // Sleep until the vm exits.
+    fn wait_exit(&self) -> i32 {
+        loop {
+            if let Some(exit_code) = self.vm.exit_status() {
+                break exit_code;
+            }
+            Thread::sleep(POLL_INTERVAL);
+        }
+    }
+
     /// Join the vm.
     pub fn join(self) -> i32 {
-        loop {
-            let v = self.vm.exit_code.load(Ordering::SeqCst);
-            if v >= 0x8000_0000_0000_0000 {
-                break v as i32;
+        self.wait_exit()
+    }
+
+    /// Join the vm, and collect the exit status of the vcpus.
+    ///
+    /// Unlike [`VmHandle::join`], waits until all the running vcpus stop.
+    pub fn join_status(self) -> ExitStatus {
+        let code = self.wait_exit();
+        for slot in self.vcpu_threads.iter() {
+            while matches!(&*slot.lock(), VCpuRunningState::Running { .. }) {
+                Thread::sleep(POLL_INTERVAL);
             }
         }
+        ExitStatus {
+            code,
+            forced: self.vm.forced.load(Ordering::SeqCst),
+            vcpus: self
+                .vm
+                .vcpu_exits
+                .iter()
+                .map(|v| match v.load(Ordering::SeqCst) {
+                    v if v & EXITED != 0 => Some(v as i32),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Shut down the vm.
+    ///
+    /// The guest is asked to shut down with [`VmState::request_shutdown`]. If
+    /// the guest does not exit within `timeout_us` microseconds, the vm is
+    /// terminated with the exit code of -1. See [`shutdown`] for the protocol.
+    ///
+    /// [`shutdown`]: crate::shutdown
+    pub fn shutdown(self, timeout_us: usize) -> ExitStatus {
+        self.vm.shutdown(timeout_us);
+        self.join_status()
+    }
+
+    /// Add a vcpu to the running vm, and returns the id of the vcpu.
+    ///
+    /// See [`hotplug`] for the details.
+    #[inline]
+    pub fn plug_vcpu(&self) -> Result<usize, VmError> {
+        self.vm.plug_vcpu()
+    }
+
+    /// Remove the vcpu `id` from the running vm.
+    ///
+    /// The vcpu is removed after the guest puts it offline. See [`hotplug`]
+    /// for the details.
+    #[inline]
+    pub fn unplug_vcpu(&self, id: usize) -> Result<(), VmError> {
+        self.vm.unplug_vcpu(id)
     }
 
     /// Start this vm's bsp.
+    ///
+    /// The watchdog of the heartbeats is armed, if configured.
     #[inline]
     pub fn start_bsp(&self) -> Result<(), VmError> {
-        self.vm.start_vcpu(0, |_| {})
+        self.vm.start_vcpu(0, |_| {})?;
+        if let Some(config) = self.vm.heartbeat.lock().clone() {
+            self.vm.heartbeat();
+            watchdog::watch(Arc::downgrade(&self.vm), config);
+        }
+        Ok(())
     }
 }
 
@@ -289,17 +669,239 @@ impl<S: VmState + 'static> Drop for Vm<S> {
 }
 
 impl<S: VmState + 'static> Vm<S> {
+    /// Dump the guest RAM and the vcpu registers into the file at `path`.
+    ///
+    /// The registers of the running vcpus are marked as invalid. Returns the
+    /// size of the core dump in bytes.
+    pub fn dump_core(&self, path: &str) -> Result<usize, CoreDumpError> {
+        let file = keos::fs::file_system()
+            .ok_or(CoreDumpError::NoFileSystem)?
+            .open(path)
+            .ok_or(CoreDumpError::NoFile)?;
+        let memory_map = self.state.memory_map().ok_or(CoreDumpError::NoMemoryMap)?;
+        let vcpus = self
+            .vcpu
+            .iter()
+            .enumerate()
+            .map(|(id, vcpu)| {
+                vcpu.try_lock()
+                    .ok()
+                    .and_then(|mut guard| {
+                        VCpuRegs::read(&guard.unpack_activate().ok()?.generic_state).ok()
+                    })
+                    .unwrap_or(VCpuRegs {
+                        id: id as u32,
+                        ..Default::default()
+                    })
+            })
+            .collect::<Vec<_>>();
+        let cipher = self.ram_key.lock().as_ref().map(RamCipher::new);
+        core_dump::write(
+            &file,
+            &vcpus,
+            &memory_map,
+            |gpa| self.state.gpa2hpa(gpa),
+            cipher.as_ref(),
+            self.stream_codec.lock().as_mut(),
+        )
+    }
+
+    /// Get the output of the vm console.
+    pub fn console_output(&self) -> Vec<u8> {
+        self.console.output()
+    }
+
+    /// Limit the cpu time of the vm to the `quota`, or remove the limit if
+    /// `None`.
+    ///
+    /// See [`cpu_quota`] for the details.
+    pub fn set_cpu_quota(&self, quota: Option<CpuQuota>) {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        *self.quota.lock() = quota.map(|quota| Throttle::new(quota, now));
+    }
+
+    /// Get the cpu quota of the vm.
+    pub fn cpu_quota(&self) -> Option<CpuQuota> {
+        self.quota.lock().as_ref().map(Throttle::quota)
+    }
+
+    /// Limit the cpu time of the vcpu `id` to the `quota`, or remove the
+    /// limit if `None`.
+    pub fn set_vcpu_quota(&self, id: usize, quota: Option<CpuQuota>) -> Result<(), VmError> {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        *self
+            .vcpu_quotas
+            .get(id)
+            .ok_or(VmError::VCpuError(Box::new("VCpu not exists.")))?
+            .lock() = quota.map(|quota| Throttle::new(quota, now));
+        Ok(())
+    }
+
+    // Check the cpu quotas of the vcpu `id` at the tsc `now`.
+    //
+    // Returns the tsc until which the vcpu may run, or the tsc of the next
+    // period as an error if a quota is used up.
+    fn check_quota(&self, id: usize, now: u64) -> Result<Option<u64>, u64> {
+        let mut deadline = None;
+        for throttle in [&self.quota, &self.vcpu_quotas[id]] {
+            if let Some(throttle) = throttle.lock().as_mut() {
+                let until = throttle.check(now)?;
+                deadline = Some(deadline.map_or(until, |d: u64| d.min(until)));
+            }
+        }
+        Ok(deadline)
+    }
+
+    // Charge the `cycles` of the cpu time of the vcpu `id` to the quotas.
+    fn charge_quota(&self, id: usize, cycles: u64) {
+        for throttle in [&self.quota, &self.vcpu_quotas[id]] {
+            if let Some(throttle) = throttle.lock().as_mut() {
+                throttle.charge(cycles);
+            }
+        }
+    }
+
+    // Exit code of the vm, or `None` if it is running.
+    pub(crate) fn exit_status(&self) -> Option<i32> {
+        match self.exit_code.load(Ordering::SeqCst) {
+            v if v & EXITED != 0 => Some(v as i32),
+            _ => None,
+        }
+    }
+
+    fn shutdown(&self, timeout_us: usize) {
+        use abyss::dev::x86_64::rtc::unix_time_ns;
+
+        if self.exit_status().is_some() {
+            return;
+        }
+        if self.state.request_shutdown(self) {
+            let deadline = unix_time_ns() + timeout_us as u64 * 1000;
+            while unix_time_ns() < deadline {
+                if self.exit_status().is_some() {
+                    return;
+                }
+                Thread::sleep(POLL_INTERVAL);
+            }
+        }
+        self.terminate(-1);
+    }
+
+    // Time of the last heartbeat of the guest, in nanoseconds.
+    pub(crate) fn last_heartbeat(&self) -> u64 {
+        self.last_heartbeat.load(Ordering::SeqCst)
+    }
+
+    fn plug_vcpu(&self) -> Result<usize, VmError> {
+        let (id, vector) = {
+            let mut guard = self.hotplug.lock();
+            let hotplug = guard
+                .as_mut()
+                .ok_or(VmError::VCpuError(Box::new(HotplugError::Unsupported)))?;
+            let present = self.present.load(Ordering::SeqCst);
+            // A removed vcpu may not have stopped yet.
+            let id = (0..self.vcpu.len())
+                .find(|&id| {
+                    present & (1 << id) == 0
+                        && matches!(&*self.vcpu_states[id].lock(), VCpuRunningState::Halted)
+                })
+                .ok_or(VmError::VCpuError(Box::new(HotplugError::NoSlot)))?;
+            // Reset the vcpu that ran before.
+            if self.vcpu_exits[id].swap(0, Ordering::SeqCst) & EXITED != 0 {
+                self.vcpu[id].lock().set_state(&hotplug.reset[id])?;
+            }
+            self.present.fetch_or(1 << id, Ordering::SeqCst);
+            hotplug.events.push_back(HotplugEvent::Added(id));
+            (id, hotplug.vector)
+        };
+        // Notify the hotplug events to the bsp.
+        self.notify_vcpu(0, vector);
+        Ok(id)
+    }
+
+    fn unplug_vcpu(&self, id: usize) -> Result<(), VmError> {
+        let vector = {
+            let mut guard = self.hotplug.lock();
+            let hotplug = guard
+                .as_mut()
+                .ok_or(VmError::VCpuError(Box::new(HotplugError::Unsupported)))?;
+            if id == 0 {
+                return Err(VmError::VCpuError(Box::new(HotplugError::Busy(id))));
+            }
+            if id >= self.vcpu.len()
+                || self.present.load(Ordering::SeqCst) & (1 << id) == 0
+                || hotplug.removing & (1 << id) != 0
+            {
+                return Err(VmError::VCpuError(Box::new(HotplugError::NotPresent(id))));
+            }
+            // The vcpu that is not running has nothing to quiesce.
+            if matches!(&*self.vcpu_states[id].lock(), VCpuRunningState::Halted) {
+                self.present.fetch_and(!(1 << id), Ordering::SeqCst);
+            } else {
+                hotplug.removing |= 1 << id;
+            }
+            hotplug.events.push_back(HotplugEvent::Remove(id));
+            hotplug.vector
+        };
+        // Notify the hotplug events to the bsp.
+        self.notify_vcpu(0, vector);
+        Ok(())
+    }
+
+    // Terminate the vm with the `exit_code`, without asking the guest.
+    pub(crate) fn terminate(&self, exit_code: i32) {
+        self.forced.store(true, Ordering::SeqCst);
+        self.exit(exit_code);
+        // The vcpus stop when they are back from the guest.
+        for (id, slot) in self.vcpu_states.iter().enumerate() {
+            if matches!(&*slot.lock(), VCpuRunningState::Running { .. }) {
+                let _ = self.kick_vcpu(id);
+            }
+            let mut guard = slot.lock();
+            if let VCpuRunningState::Kicked(handle) =
+                core::mem::replace(&mut *guard, VCpuRunningState::Halted)
+            {
+                *guard = VCpuRunningState::Running {
+                    handle: handle.join_handle(),
+                    have_kicked: Arc::new(AtomicBool::new(false)),
+                };
+                handle.unpark();
+            }
+        }
+    }
+
     /// The main loop of a VCpu.
     pub fn vcpu_thread_work(
         vcpu: Arc<SpinLock<VCpu<S>>>,
         state: Arc<SpinLock<VCpuRunningState>>,
         init: impl FnOnce(&SpinLock<VCpu<S>>),
     ) {
+        let exit_code = Self::run_vcpu(vcpu, state, init);
+        thread::with_current(|th| th.exit(exit_code));
+        unreachable!()
+    }
+
+    // Run the vcpu until it exits, and returns the exit code.
+    fn run_vcpu(
+        vcpu: Arc<SpinLock<VCpu<S>>>,
+        state: Arc<SpinLock<VCpuRunningState>>,
+        init: impl FnOnce(&SpinLock<VCpu<S>>),
+    ) -> i32 {
         use crate::vcpu::VmexitResult;
 
         init(&vcpu);
 
+        let (id, vm) = {
+            let guard = vcpu.lock();
+            (guard.vcpu_id, guard.vm.clone())
+        };
+        let dedicated = vm
+            .upgrade()
+            .and_then(|vm| vm.partition.lock().as_ref().and_then(|p| p.cpu(id)));
         let _pp = Thread::pin();
+        if let Some(cpu) = dedicated {
+            partition::enter(cpu);
+        }
         let have_kicked = {
             if let VCpuRunningState::Running { have_kicked, .. } = &*state.lock() {
                 have_kicked.clone()
@@ -307,15 +909,62 @@ impl<S: VmState + 'static> Vm<S> {
                 unreachable!()
             }
         };
+        // The run delay of the thread, accounted as the steal time.
+        let mut run_delay = thread::with_current(|th| th.run_delay());
         let exit_code = loop {
+            // Stop when the other vcpu or the host exits the vm.
+            if let Some(exit_code) = vm.upgrade().and_then(|vm| vm.exit_status()) {
+                break exit_code;
+            }
+            let now = unsafe { core::arch::x86_64::_rdtsc() };
+            let quota_deadline = match vm.upgrade().map(|vm| vm.check_quota(id, now)) {
+                // Sleep until the next period, while checking the kicks.
+                Some(Err(resume)) if !have_kicked.load(Ordering::SeqCst) => {
+                    if dedicated.is_some() {
+                        partition::leave();
+                    }
+                    Thread::sleep(cpu_quota::cycles_to_duration(resume - now).min(POLL_INTERVAL));
+                    if let Some(cpu) = dedicated {
+                        partition::enter(cpu);
+                    }
+                    if let Some(vm) = vm.upgrade() {
+                        vm.throttled_cycles.fetch_add(
+                            unsafe { core::arch::x86_64::_rdtsc() } - now,
+                            Ordering::Relaxed,
+                        );
+                    }
+                    continue;
+                }
+                Some(Err(_)) => Some(now),
+                Some(Ok(deadline)) => deadline,
+                None => None,
+            };
             let _p = Thread::pin();
             {
                 let mut vcpu_guard = vcpu.lock();
+                // The thread is pinned while running the vcpu, so the time
+                // of the loop is the cpu time of the vcpu.
+                let start = thread::with_current(|th| th.cpu_time());
                 let loop_result = vcpu_guard
                     .unpack_activate()
                     .expect("Failed to activate vcpu")
-                    .vcpu_loop(&have_kicked)
-                    .expect("Vm has error");
+                    .vcpu_loop(&have_kicked, quota_deadline)
+                    .unwrap_or_else(|e| panic!("Vcpu {} has error: {}", id, e));
+                if let Some(vm) = vm.upgrade() {
+                    let cycles = thread::with_current(|th| th.cpu_time()) - start;
+                    vm.vcpu_cycles[id].fetch_add(cycles, Ordering::Relaxed);
+                    vm.charge_quota(id, cycles);
+                    let delay = thread::with_current(|th| th.run_delay());
+                    let (counters, stolen) = (vcpu_guard.counters, delay - run_delay);
+                    run_delay = delay;
+                    vm.metrics.update(id, |m| {
+                        m.exits = counters.exits;
+                        m.injected = counters.injected;
+                        m.steal += metrics::cycles_to_ns(stolen);
+                        m.run_time =
+                            metrics::cycles_to_ns(vm.vcpu_cycles[id].load(Ordering::Relaxed));
+                    });
+                }
                 match loop_result {
                     VmexitResult::Exited(exit_code) => {
                         break exit_code;
@@ -328,8 +977,12 @@ impl<S: VmState + 'static> Vm<S> {
                             continue;
                         }
                     }
-                    VmexitResult::Kicked => (),
-                    VmexitResult::Ok => unreachable!(),
+                    VmexitResult::Kicked | VmexitResult::Throttled => (),
+                    VmexitResult::Ok
+                    | VmexitResult::HandledAdvance
+                    | VmexitResult::HandledNoAdvance
+                    | VmexitResult::Redeliver
+                    | VmexitResult::Icount => unreachable!(),
                 }
             }
             // Kicked.
@@ -341,6 +994,9 @@ impl<S: VmState + 'static> Vm<S> {
                         have_kicked,
                     } = core::mem::replace(&mut *guard, VCpuRunningState::Halted)
                     {
+                        if dedicated.is_some() {
+                            partition::leave();
+                        }
                         Thread::park_current_and(move |hdl| {
                             *guard = VCpuRunningState::Kicked(hdl);
                             drop(guard);
@@ -350,14 +1006,24 @@ impl<S: VmState + 'static> Vm<S> {
                             handle,
                             have_kicked,
                         };
+                        // The thread may be resumed on another cpu.
+                        if let Some(cpu) = dedicated {
+                            partition::enter(cpu);
+                        }
                     } else {
                         unreachable!()
                     }
                 }
             }
         };
-        thread::with_current(|th| th.exit(exit_code));
-        unreachable!()
+        if dedicated.is_some() {
+            partition::leave();
+        }
+        if let Some(vm) = vm.upgrade() {
+            vm.vcpu_exits[id].store(EXITED | (exit_code as u32 as u64), Ordering::SeqCst);
+        }
+        *state.lock() = VCpuRunningState::Halted;
+        exit_code
     }
 
     fn start_vcpu(
@@ -370,14 +1036,26 @@ impl<S: VmState + 'static> Vm<S> {
             .get(id)
             .cloned()
             .ok_or(VmError::VCpuError(Box::new("VCpu not exists.")))?;
+        if id < hotplug::MAX_VCPUS && self.present.load(Ordering::SeqCst) & (1 << id) == 0 {
+            return Err(VmError::VCpuError(Box::new("VCpu not present.")));
+        }
 
         let mut vcpu_slot = self.vcpu_states[id].lock();
         let slot = self.vcpu_states[id].clone();
         let have_kicked = Arc::new(AtomicBool::new(false));
         if matches!(&*vcpu_slot, VCpuRunningState::Halted) {
+            let name = self.uuid.thread_name(alloc::format!("vcpu#{}", id));
+            let handle = if self.pooled.load(Ordering::SeqCst) {
+                vcpu_pool::spawn(name, move || {
+                    Self::run_vcpu(vcpu, slot, init);
+                })
+            } else {
+                ThreadBuilder::new(name)
+                    .stack_size(VCPU_STACK_SIZE)
+                    .spawn(move || Self::vcpu_thread_work(vcpu, slot, init))
+            };
             *vcpu_slot = VCpuRunningState::Running {
-                handle: ThreadBuilder::new(alloc::format!("vcpu#{}", id))
-                    .spawn(move || Self::vcpu_thread_work(vcpu, slot, init)),
+                handle,
                 have_kicked,
             };
             Ok(())
@@ -402,6 +1080,78 @@ where
     fn get_vcpu(&self, id: usize) -> Option<&dyn VCpuOps>;
     /// Resum the vcpu.
     fn resume_vcpu(&self, id: usize);
+    /// Get the number of the vcpus.
+    fn vcpu_count(&self) -> usize;
+    /// Get the uuid of this vm.
+    fn uuid(&self) -> Uuid;
+    /// Get the counts of the performance events measured by the guest.
+    fn pmu(&self) -> &PmuStats;
+    /// Get the console of this vm.
+    fn console(&self) -> &Console;
+    /// Get the cpu time consumed by the vcpu, in tsc cycles.
+    fn vcpu_cpu_time(&self, id: usize) -> Option<u64>;
+    /// Get the paravirtual features that this vm provides.
+    fn pv_features(&self) -> PvFeatures {
+        PvFeatures::empty()
+    }
+    /// Translate the guest physical address to the host physical address.
+    fn gpa2hpa(&self, _gpa: Gpa) -> Option<Pa> {
+        None
+    }
+    /// Record a heartbeat of the guest.
+    ///
+    /// See [`watchdog`](crate::watchdog) for the details.
+    fn heartbeat(&self) {}
+    /// Get the policy on the missed timer ticks of the guest.
+    ///
+    /// See [`virtual_time`](crate::virtual_time) for the details.
+    fn tick_policy(&self) -> TickPolicy {
+        TickPolicy::Discard
+    }
+    /// Get the bitmask of the present vcpus.
+    fn present_vcpus(&self) -> u64 {
+        vcpu_mask(self.vcpu_count())
+    }
+    /// Take the next hotplug event of the guest.
+    ///
+    /// See [`hotplug`] for the details.
+    fn hotplug_event(&self) -> Option<HotplugEvent> {
+        None
+    }
+    /// Remove the vcpu `id` that the guest put offline.
+    ///
+    /// Returns `false` if the vcpu is not being removed.
+    fn offline_vcpu(&self, _id: usize) -> bool {
+        false
+    }
+    /// Get the performance metrics of the vcpus.
+    ///
+    /// See [`metrics`] for the details.
+    fn metrics(&self) -> Option<&Metrics> {
+        None
+    }
+    /// Get the completion queue of the asynchronous hypercalls.
+    ///
+    /// See [`async_call`](crate::async_call) for the details.
+    fn completions(&self) -> Option<&CompletionQueue> {
+        None
+    }
+    /// Raise the interrupt of the `vector` on the vcpu `id`, from a thread
+    /// other than the vcpu.
+    ///
+    /// The running vcpu is kicked out of the guest, and takes the interrupt
+    /// on the next vm entry.
+    fn notify_vcpu(&self, id: usize, vector: u8) {
+        if let Some(vcpu) = self.get_vcpu(id) {
+            vcpu.inject_interrupt(vector);
+        }
+    }
+    /// Get the cpu time consumed by all vcpus, in tsc cycles.
+    fn cpu_time(&self) -> u64 {
+        (0..self.vcpu_count())
+            .filter_map(|id| self.vcpu_cpu_time(id))
+            .sum()
+    }
 }
 
 impl<S: VmState + 'static> VmOps for Vm<S> {
@@ -463,7 +1213,7 @@ impl<S: VmState + 'static> VmOps for Vm<S> {
 
     fn exit(&self, exit_code: i32) {
         self.exit_code
-            .store(0x8000_0000_0000_0000 | (exit_code as u64), Ordering::SeqCst);
+            .store(EXITED | (exit_code as u64), Ordering::SeqCst);
     }
 
     fn start_vcpu(&self, id: usize, ip: u16) -> Result<(), VmError> {
@@ -481,6 +1231,83 @@ impl<S: VmState + 'static> VmOps for Vm<S> {
     fn get_vcpu(&self, id: usize) -> Option<&dyn VCpuOps> {
         self.vcpu.get(id).map(|cpu| cpu.as_ref() as &dyn VCpuOps)
     }
+
+    fn vcpu_count(&self) -> usize {
+        self.vcpu_states.len()
+    }
+
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+    fn pmu(&self) -> &PmuStats {
+        &self.pmu
+    }
+    fn console(&self) -> &Console {
+        &self.console
+    }
+    fn vcpu_cpu_time(&self, id: usize) -> Option<u64> {
+        self.vcpu_cycles
+            .get(id)
+            .map(|cycles| cycles.load(Ordering::Relaxed))
+    }
+    fn pv_features(&self) -> PvFeatures {
+        match *self.hotplug.lock() {
+            Some(_) => self.state.pv_features() | PvFeatures::CPU_HOTPLUG,
+            None => self.state.pv_features(),
+        }
+    }
+    fn gpa2hpa(&self, gpa: Gpa) -> Option<Pa> {
+        self.state.gpa2hpa(gpa)
+    }
+    fn metrics(&self) -> Option<&Metrics> {
+        Some(&self.metrics)
+    }
+    fn completions(&self) -> Option<&CompletionQueue> {
+        Some(&self.completions)
+    }
+    fn notify_vcpu(&self, id: usize, vector: u8) {
+        let Some(state) = self.vcpu_states.get(id) else {
+            return;
+        };
+        if matches!(&*state.lock(), VCpuRunningState::Running { .. }) {
+            let _ = self.kick_vcpu(id);
+        }
+        self.vcpu[id].inject_interrupt(vector);
+        if matches!(&*state.lock(), VCpuRunningState::Kicked(_)) {
+            self.resume_vcpu(id);
+        }
+    }
+    fn heartbeat(&self) {
+        self.last_heartbeat
+            .store(abyss::dev::x86_64::rtc::unix_time_ns(), Ordering::SeqCst);
+    }
+    fn tick_policy(&self) -> TickPolicy {
+        *self.tick_policy.lock()
+    }
+    fn present_vcpus(&self) -> u64 {
+        self.present.load(Ordering::SeqCst)
+    }
+    fn hotplug_event(&self) -> Option<HotplugEvent> {
+        self.hotplug.lock().as_mut()?.events.pop_front()
+    }
+    fn offline_vcpu(&self, id: usize) -> bool {
+        match self.hotplug.lock().as_mut() {
+            Some(hotplug) if id < hotplug::MAX_VCPUS && hotplug.removing & (1 << id) != 0 => {
+                hotplug.removing &= !(1 << id);
+                self.present.fetch_and(!(1 << id), Ordering::SeqCst);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Bitmask of the first `count` vcpus.
+fn vcpu_mask(count: usize) -> u64 {
+    match count {
+        0..=63 => (1 << count) - 1,
+        _ => u64::MAX,
+    }
 }
 
 impl<S: VmState> core::ops::Deref for Vm<S> {
@@ -499,9 +1326,18 @@ pub struct VmBuilder<S: VmState + 'static> {
 impl<S: VmState + 'static> VmBuilder<S> {
     /// Get a builder object to create a new vm.
     ///
-    /// The vm has `vcpu` numbers of virtual CPU.
+    /// The vm has `vcpu` numbers of virtual CPU. The number of virtual CPUs
+    /// is not limited by the number of the host cpus, however, the vcpus are
+    /// overcommitted if it exceeds [`keos::ncpu`].
     pub fn new(vmstate: S, vcpu: usize) -> Result<VmBuilder<S>, S::Error> {
         assert!(vcpu > 0);
+        if vcpu > keos::ncpu() {
+            warning!(
+                "VM has {} vcpus, but only {} cpus are available.",
+                vcpu,
+                keos::ncpu()
+            );
+        }
 
         VmHandle::new(vcpu, vmstate).map(|vm| VmBuilder {
             vm_handle: vm,
@@ -516,6 +1352,134 @@ impl<S: VmState + 'static> VmBuilder<S> {
         self
     }
 
+    /// Record the inputs of the vcpus.
+    ///
+    /// See [`replay`](crate::replay) for the details.
+    pub fn record(self) -> Self {
+        for vcpu in self.vm_handle.vm.vcpu.iter() {
+            vcpu.lock().replay = Some(Replay::recording());
+        }
+        self
+    }
+
+    /// Run the vcpus on the threads of the [`vcpu_pool`].
+    ///
+    /// The threads are recycled when the vm exits, instead of being destroyed.
+    pub fn pooled(self) -> Self {
+        self.vm_handle.vm.pooled.store(true, Ordering::SeqCst);
+        self
+    }
+
+    /// Name the console of the vm.
+    ///
+    /// The console is named after the uuid of the vm by default.
+    pub fn name(self, name: &str) -> Self {
+        self.vm_handle.vm.console.rename(String::from(name));
+        self
+    }
+
+    /// Watch the heartbeats of the guest.
+    ///
+    /// See [`watchdog`](crate::watchdog) for the details.
+    pub fn heartbeat(self, config: Heartbeat) -> Self {
+        *self.vm_handle.vm.heartbeat.lock() = Some(config);
+        self
+    }
+
+    /// Trace the vmexits to the `output` from the start.
+    ///
+    /// See [`exit_trace`] for the details.
+    pub fn trace_exits(self, output: TraceOutput) -> Self {
+        self.vm_handle.vm.exit_trace.set(Some(output));
+        self
+    }
+
+    /// Audit the guest accesses to the resources in the `audit`.
+    ///
+    /// See [`audit`](crate::audit) for the details.
+    pub fn audit(self, audit: Audit) -> Self {
+        self.vm_handle.vm.audit_log.set(Some(audit));
+        self
+    }
+
+    /// Record the guest code executed by the vcpus with the `coverage`.
+    ///
+    /// See [`coverage`](crate::coverage) for the details.
+    pub fn coverage(self, coverage: Coverage) -> Self {
+        self.vm_handle.vm.coverage.set(Some(coverage));
+        self
+    }
+
+    /// Encrypt the guest RAM with the `key` when it is written out.
+    ///
+    /// See [`ram_crypt`](crate::ram_crypt) for the details.
+    pub fn encrypt_ram(self, key: RamKey) -> Self {
+        *self.vm_handle.vm.ram_key.lock() = Some(key);
+        self
+    }
+
+    /// Encode the guest RAM with the `pipeline` when it is written out.
+    ///
+    /// See [`stream_codec`](crate::stream_codec) for the details.
+    pub fn stream_codec(self, pipeline: Pipeline) -> Self {
+        *self.vm_handle.vm.stream_codec.lock() = Some(pipeline);
+        self
+    }
+
+    /// Emulate the first `count` instructions of each vcpu, before running it
+    /// on the hardware.
+    ///
+    /// See [`stepping`](crate::stepping) for the details.
+    pub fn step_emulated(self, count: u64) -> Self {
+        for vcpu in self.vm_handle.vm.vcpu.iter() {
+            vcpu.lock().steps = 0..count;
+        }
+        self
+    }
+
+    /// Replay the vcpus with the `logs`, one for each vcpu.
+    ///
+    /// See [`replay`](crate::replay) for the details.
+    pub fn replay(self, logs: Vec<Log>) -> Self {
+        assert_eq!(logs.len(), self.vm_handle.vm.vcpu.len());
+        for (vcpu, log) in self.vm_handle.vm.vcpu.iter().zip(logs) {
+            vcpu.lock().replay = Some(Replay::replaying(log));
+        }
+        self
+    }
+
+    /// Dedicate the `partition` of the host to the vm.
+    ///
+    /// The partition must have a cpu for each vcpu. See
+    /// [`partition`](crate::partition) for the details.
+    pub fn partition(self, partition: Partition) -> Self {
+        assert!(partition.cpus() >= self.vm_handle.vm.vcpu.len());
+        *self.vm_handle.vm.partition.lock() = Some(partition);
+        self
+    }
+
+    /// Deliver the missed timer ticks of the guest with the `policy`.
+    ///
+    /// See [`virtual_time`](crate::virtual_time) for the details.
+    pub fn tick_policy(self, policy: TickPolicy) -> Self {
+        *self.vm_handle.vm.tick_policy.lock() = policy;
+        self
+    }
+
+    /// Make the vcpus hot-pluggable, of which the first `present` vcpus are
+    /// present at the boot. The hotplug events are notified to the bsp with
+    /// the interrupt of the `vector`.
+    ///
+    /// See [`hotplug`] for the details.
+    pub fn hotplug(self, present: usize, vector: u8) -> Self {
+        let vm = &self.vm_handle.vm;
+        assert!(vm.vcpu.len() <= hotplug::MAX_VCPUS);
+        assert!(present > 0 && present <= vm.vcpu.len());
+        vm.present.store(vcpu_mask(present), Ordering::SeqCst);
+        *vm.hotplug.lock() = Some(Hotplug::new(vector));
+        self
+    }
+
     /// Finalize this builder.
     #[inline]
     pub fn finalize(self) -> Result<VmHandle<S>, VmError> {
@@ -523,11 +1487,23 @@ impl<S: VmState + 'static> VmBuilder<S> {
             vm_handle,
             exception_bitmap,
         } = self;
+        // The coverage steps the vcpus with the monitor trap flag.
+        if vm_handle.vm.coverage.is_enabled() {
+            crate::caps::probe()
+                .procbased
+                .check(VmcsProcBasedVmexecCtl::MTF)?;
+        }
         for vcpu in vm_handle.vm.vcpu.iter() {
             unsafe {
                 vcpu.lock().unpack_activate()?.init_vcpu(exception_bitmap)?;
             }
         }
+        // Keep the reset state to restart the re-plugged vcpus.
+        if let Some(hotplug) = vm_handle.vm.hotplug.lock().as_mut() {
+            for vcpu in vm_handle.vm.vcpu.iter() {
+                hotplug.reset.push(vcpu.lock().get_state()?);
+            }
+        }
         Ok(vm_handle)
     }
 }
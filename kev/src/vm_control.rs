@@ -27,6 +27,8 @@ pub const IA32_VMX_CR4_FIXED1: usize = 0x489;
 pub const IA32_VMX_VMCS_ENUM: usize = 0x48A;
 /// MSR - IA32_VMX_EPT_VPID_CAP.
 pub const IA32_VMX_EPT_VPID_CAP: usize = 0x48C;
+/// MSR - IA32_VMX_VMFUNC.
+pub const IA32_VMX_VMFUNC: usize = 0x491;
 /// MSR - IA32_FEATURE_CONTROL.
 pub const IA32_FEATURE_CONTROL: usize = 0x03A;
 
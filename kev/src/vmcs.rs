@@ -1,5 +1,7 @@
 //! Virtual-Machine Control State (VMCS) related apis.
 use crate::{
+    page_walk::Access,
+    testing::MockFields,
     vm::{Gpa, Gva},
     Probe,
     {vm_control::*, VmError},
@@ -8,7 +10,6 @@ use abyss::{
     addressing::{Pa, Va},
     x86_64::msr::Msr,
 };
-use alloc::{boxed::Box, format};
 use core::arch::asm;
 use iced_x86::{Decoder, DecoderOptions, Instruction};
 
@@ -92,7 +93,7 @@ pub enum InstructionError {
 /// Vmcs field.
 #[allow(missing_docs)]
 #[repr(i32)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Field {
     // 16bit fields
     Vpid = 0x00000000,
@@ -358,7 +359,7 @@ impl Vmcs {
             if err != 0 {
                 Err(VmError::VmxOperationError(Self::instruction_error()))
             } else {
-                Ok(ActiveVmcs { _p: () })
+                Ok(ActiveVmcs { mock: None })
             }
         }
     }
@@ -411,11 +412,21 @@ impl Vmcs {
 }
 
 /// A representation of active vmcs.
+///
+/// The vmcs of [`MockVmcs`] keeps the fields in the memory instead, so the
+/// controllers run without VMX.
+///
+/// [`MockVmcs`]: crate::testing::MockVmcs
 pub struct ActiveVmcs {
-    _p: (),
+    mock: Option<MockFields>,
 }
 
 impl ActiveVmcs {
+    // Get the vmcs backed by the in-memory `fields`.
+    pub(crate) fn mock(fields: MockFields) -> ActiveVmcs {
+        ActiveVmcs { mock: Some(fields) }
+    }
+
     /// Get currently activated vmcs.
     pub unsafe fn activated() -> Result<(ActiveVmcs, Pa), VmError> {
         unsafe {
@@ -432,7 +443,7 @@ impl ActiveVmcs {
             if err != 0 {
                 Err(VmError::VmxOperationError(Vmcs::instruction_error()))
             } else {
-                Ok((ActiveVmcs { _p: () }, Pa::new(out).unwrap()))
+                Ok((ActiveVmcs { mock: None }, Pa::new(out).unwrap()))
             }
         }
     }
@@ -506,6 +517,10 @@ impl ActiveVmcs {
 
     /// Write to the vmcs field of the activated vmcs.
     pub fn write(&self, field: Field, v: u64) -> Result<(), VmError> {
+        if let Some(fields) = &self.mock {
+            fields.lock().insert(field as u64, v);
+            return Ok(());
+        }
         unsafe {
             let err: i8;
             asm!(
@@ -517,7 +532,7 @@ impl ActiveVmcs {
                 out(reg_byte) err
             );
             if err != 0 {
-                Err(VmError::VmxOperationError(Vmcs::instruction_error()))
+                Err(VmError::VmxOperationError(Vmcs::instruction_error()).with_field(field))
             } else {
                 Ok(())
             }
@@ -526,6 +541,9 @@ impl ActiveVmcs {
 
     /// Read from the vmcs field of the activated vmcs.
     pub fn read(&self, field: Field) -> Result<u64, VmError> {
+        if let Some(fields) = &self.mock {
+            return Ok(fields.lock().get(&(field as u64)).copied().unwrap_or(0));
+        }
         unsafe {
             let err: i8;
             let v: u64;
@@ -538,7 +556,7 @@ impl ActiveVmcs {
                 out(reg_byte) err
             );
             if err != 0 {
-                Err(VmError::VmxOperationError(Vmcs::instruction_error()))
+                Err(VmError::VmxOperationError(Vmcs::instruction_error()).with_field(field))
             } else {
                 Ok(v)
             }
@@ -622,6 +640,7 @@ impl ActiveVmcs {
             0x35 => BasicExitReason::Invvpid,
             0x36 => BasicExitReason::Wbinvd,
             0x37 => BasicExitReason::Xsetbv,
+            0x3B => BasicExitReason::Vmfunc,
             _ => BasicExitReason::Unknown,
         })
     }
@@ -654,12 +673,8 @@ impl ActiveVmcs {
         // Pull to the buffer.
         bytes[..len].copy_from_slice(unsafe {
             core::slice::from_raw_parts(
-                p.gva2hva(self, rip)
-                    .ok_or_else(|| {
-                        VmError::ControllerError(Box::new(format!(
-                            "Invalid memory access at {rip:?}",
-                        )))
-                    })?
+                p.translate(self, rip, Access::at_cpl(self, Access::FETCH)?)?
+                    .into_va()
                     .into_usize() as *const u8,
                 len,
             )
@@ -675,6 +690,49 @@ impl ActiveVmcs {
         }
     }
 
+    /// Get the current privilege level of the guest.
+    ///
+    /// The CPL is the DPL of the guest SS.
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// 26.3.1.5 Checks on Guest Non-Register State.
+    pub fn guest_cpl(&self) -> Result<u8, VmError> {
+        Ok(((self.read(Field::GuestSsAccessRights)? >> 5) & 3) as u8)
+    }
+
+    /// Inject the hardware exception `vector` to the guest on the next vm
+    /// entry.
+    ///
+    /// The exception is written as is; the vmexit handlers use
+    /// [`crate::event::inject_exception`], which checks the error code and
+    /// escalates to a double fault.
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// 24.8.3 VM-Entry Controls for Event Injection.
+    pub fn inject_exception(&self, vector: u8, error_code: Option<u32>) -> Result<(), VmError> {
+        // Valid | Hardware exception.
+        let mut info = (1 << 31) | (3 << 8) | vector as u64;
+        if let Some(error_code) = error_code {
+            info |= 1 << 11;
+            self.write(Field::VmentryExceptionErrCode, error_code as u64)?;
+        }
+        self.write(Field::VmentryInterruptionInfo, info)
+    }
+
+    /// Set the monitor trap flag, which causes a vmexit after the guest
+    /// executes an instruction.
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// 26.5.2 Monitor Trap Flag.
+    pub fn set_mtf(&self, enable: bool) -> Result<(), VmError> {
+        let ctls = self.read(Field::ProcessorBasedVmexecControls)?;
+        let mtf = VmcsProcBasedVmexecCtl::MTF.bits() as u64;
+        self.write(
+            Field::ProcessorBasedVmexecControls,
+            if enable { ctls | mtf } else { ctls & !mtf },
+        )
+    }
+
     /// Forward to the next instruction.
     pub fn forward_rip(&self) -> Result<(), VmError> {
         self.write(
@@ -774,9 +832,75 @@ pub enum BasicExitReason {
     Invvpid,
     Wbinvd,
     Xsetbv,
+    Vmfunc,
     Unknown,
 }
 
+impl BasicExitReason {
+    /// Get the basic exit reason number.
+    ///
+    /// Returns `u16::MAX` for [`BasicExitReason::Unknown`].
+    ///
+    /// See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+    /// Appendix C VMX Basic Exit Reasons.
+    pub const fn number(&self) -> u16 {
+        match self {
+            Self::ExceptionOrNmi => 0x0,
+            Self::ExternalInt(_) => 0x1,
+            Self::TripleFault => 0x2,
+            Self::InitSignal => 0x3,
+            Self::StartupIpi => 0x4,
+            Self::IoSmi => 0x5,
+            Self::OtherSmi => 0x6,
+            Self::InterruptWindow => 0x7,
+            Self::TaskSwitch => 0x9,
+            Self::Cpuid => 0xA,
+            Self::Hlt => 0xC,
+            Self::Invd => 0xD,
+            Self::Invlpg => 0xE,
+            Self::Rdpmc => 0xF,
+            Self::Rdtsc => 0x10,
+            Self::Rsm => 0x11,
+            Self::Vmcall => 0x12,
+            Self::Vmclear => 0x13,
+            Self::Vmlaunch => 0x14,
+            Self::Vmptrld => 0x15,
+            Self::Vmptrst => 0x16,
+            Self::Vmread => 0x17,
+            Self::Vmresume => 0x18,
+            Self::Vmwrite => 0x19,
+            Self::Vmxoff => 0x1A,
+            Self::Vmxon => 0x1B,
+            Self::MovCr => 0x1C,
+            Self::MovDr => 0x1D,
+            Self::IoInstruction => 0x1E,
+            Self::Rdmsr => 0x1F,
+            Self::Wrmsr => 0x20,
+            Self::EntfailGuestState => 0x21,
+            Self::EntfailMsrLoading => 0x22,
+            Self::Mwait => 0x24,
+            Self::Mtf => 0x25,
+            Self::Monitor => 0x27,
+            Self::Pause => 0x28,
+            Self::EntfailMachineChk => 0x29,
+            Self::TprBelowThreshold => 0x2B,
+            Self::ApicAccess => 0x2C,
+            Self::AccessGdtrOrIdtr => 0x2E,
+            Self::AccessLdtrOrTr => 0x2F,
+            Self::EptViolation { .. } => 0x30,
+            Self::EptMisconfig => 0x31,
+            Self::Invept => 0x32,
+            Self::Rdtscp => 0x33,
+            Self::VmxPreemptTimer => 0x34,
+            Self::Invvpid => 0x35,
+            Self::Wbinvd => 0x36,
+            Self::Xsetbv => 0x37,
+            Self::Vmfunc => 0x3B,
+            Self::Unknown => u16::MAX,
+        }
+    }
+}
+
 bitflags::bitflags! {
     /// Exit Qualification for EPT Violations
     ///
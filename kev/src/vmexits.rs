@@ -1,10 +1,14 @@
 //! Interface to play with vmexits.
 use crate::{
+    page_walk::{Access, WalkError},
     probe::Probe,
     vcpu::{GenericVCpuState, VmexitResult},
-    vmcs::ExitReason,
+    vm::{Gpa, Gva},
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason},
     VmError,
 };
+use abyss::addressing::Pa;
+use alloc::{boxed::Box, vec::Vec};
 
 /// Controller that defines action on vmexit.
 pub trait VmexitController {
@@ -17,6 +21,12 @@ pub trait VmexitController {
         p: &mut P,
         generic_vcpu_state: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError>;
+
+    /// Name of this controller, reported in the
+    /// [`Subsystem::Controller`](crate::Subsystem::Controller) of its errors.
+    fn name(&self) -> &'static str {
+        core::any::type_name::<Self>()
+    }
 }
 
 impl VmexitController for () {
@@ -39,8 +49,328 @@ impl<A: VmexitController, B: VmexitController> VmexitController for (A, B) {
     ) -> Result<VmexitResult, VmError> {
         let (a, b) = self;
         match a.handle(reason, p, generic_vcpu_state) {
-            Err(VmError::HandleVmexitFailed(reason)) => b.handle(reason, p, generic_vcpu_state),
-            r => r,
+            Err(VmError::HandleVmexitFailed(reason)) => b
+                .handle(reason, p, generic_vcpu_state)
+                .map_err(|e| e.in_controller(b.name())),
+            r => r.map_err(|e| e.in_controller(a.name())),
+        }
+    }
+}
+
+/// Define a vmexit controller from the plain handler functions.
+///
+/// `vmexit_handler! { $vis struct $name { $reason => $handler, ... } }`
+/// defines a unit struct `$name` that implements the [`VmexitController`].
+/// The exits that match a pattern `$reason` of the [`BasicExitReason`] are
+/// handled by the `$handler`, which is called as:
+///
+/// ```ignore
+/// fn handler(
+///     reason: &BasicExitReason,
+///     p: &mut dyn Probe,
+///     generic_vcpu_state: &mut GenericVCpuState,
+/// ) -> Result<VmexitResult, E>
+/// where
+///     VmError: From<E>;
+/// ```
+///
+/// The handler tells how to complete the exit with the [`VmexitResult`],
+/// e.g. [`VmexitResult::HandledAdvance`] to forward the rip past the
+/// instruction. The other exits fail with [`VmError::HandleVmexitFailed`], so
+/// the controller can be chained with the others:
+///
+/// ```ignore
+/// fn rdtsc(
+///     _reason: &BasicExitReason,
+///     _p: &mut dyn Probe,
+///     generic_vcpu_state: &mut GenericVCpuState,
+/// ) -> Result<VmexitResult, VmError> {
+///     let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+///     generic_vcpu_state.gprs.rax = tsc as u32 as usize;
+///     generic_vcpu_state.gprs.rdx = (tsc >> 32) as usize;
+///     Ok(VmexitResult::HandledAdvance)
+/// }
+///
+/// kev::vmexit_handler! {
+///     /// Emulate the rdtsc with the tsc of the host.
+///     pub struct Rdtsc {
+///         BasicExitReason::Rdtsc => rdtsc,
+///     }
+/// }
+/// ```
+///
+/// [`VmexitController`]: crate::vmexits::VmexitController
+/// [`BasicExitReason`]: crate::vmcs::BasicExitReason
+/// [`VmexitResult`]: crate::vcpu::VmexitResult
+/// [`VmexitResult::HandledAdvance`]: crate::vcpu::VmexitResult::HandledAdvance
+/// [`VmError::HandleVmexitFailed`]: crate::VmError::HandleVmexitFailed
+#[macro_export]
+macro_rules! vmexit_handler {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($reason:pat => $handler:expr),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default)]
+        $vis struct $name;
+
+        impl $crate::vmexits::VmexitController for $name {
+            fn handle<P: $crate::Probe>(
+                &mut self,
+                reason: $crate::vmcs::ExitReason,
+                p: &mut P,
+                generic_vcpu_state: &mut $crate::vcpu::GenericVCpuState,
+            ) -> Result<$crate::vcpu::VmexitResult, $crate::VmError> {
+                #[allow(unused_imports)]
+                use $crate::vmcs::BasicExitReason;
+                let basic = reason.get_basic_reason();
+                match basic {
+                    $(
+                        $reason => $handler(
+                            basic,
+                            &mut *p as &mut dyn $crate::Probe,
+                            generic_vcpu_state,
+                        )
+                        .map_err(|e| $crate::VmError::from(e).in_controller(stringify!($name))),
+                    )+
+                    _ => Err($crate::VmError::HandleVmexitFailed(reason)),
+                }
+            }
+        }
+    };
+}
+
+// Probe behind a trait object, to call the controllers from the dispatch table.
+struct DynProbe<'a>(&'a mut dyn Probe);
+
+impl Probe for DynProbe<'_> {
+    fn gpa2hpa(&self, vmcs: &ActiveVmcs, gpa: Gpa) -> Option<Pa> {
+        self.0.gpa2hpa(vmcs, gpa)
+    }
+    fn gva2hpa(&self, vmcs: &ActiveVmcs, gva: Gva) -> Option<Pa> {
+        self.0.gva2hpa(vmcs, gva)
+    }
+    fn translate(&self, vmcs: &ActiveVmcs, gva: Gva, access: Access) -> Result<Pa, WalkError> {
+        self.0.translate(vmcs, gva, access)
+    }
+}
+
+/// Object-safe form of the [`VmexitController`].
+pub trait ExitHandler
+where
+    Self: Send + Sync,
+{
+    /// Handle the vmexit on this handler.
+    fn handle_exit(
+        &mut self,
+        reason: ExitReason,
+        p: &mut dyn Probe,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError>;
+}
+
+impl<T: VmexitController + Send + Sync> ExitHandler for T {
+    fn handle_exit(
+        &mut self,
+        reason: ExitReason,
+        p: &mut dyn Probe,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        self.handle(reason, &mut DynProbe(p), generic_vcpu_state)
+            .map_err(|e| e.in_controller(self.name()))
+    }
+}
+
+/// Vmexit controller that dispatches the vmexits by the basic exit reason.
+///
+/// Unlike the tuple of the controllers, which asks each controller in turn,
+/// the handler of an exit is found with a single lookup. The handlers can be
+/// registered at any time:
+///
+/// ```ignore
+/// let mut dispatch = ExitDispatch::new();
+/// dispatch.register([BasicExitReason::Vmcall], hypercall::Controller::new(ctx));
+/// dispatch.register([BasicExitReason::Rdmsr, BasicExitReason::Wrmsr], msr::Controller::new());
+/// ```
+///
+/// The exits without a handler fail with [`VmError::HandleVmexitFailed`], so
+/// the dispatch table can be chained with the other controllers.
+#[derive(Default)]
+pub struct ExitDispatch {
+    handlers: Vec<Box<dyn ExitHandler>>,
+    // Index of the handler of each basic exit reason number.
+    table: Vec<Option<usize>>,
+}
+
+impl ExitDispatch {
+    /// Create a new empty dispatch table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route the vmexits of the `reasons` to the `handler`.
+    ///
+    /// Only the kind of each reason matters; the fields of the reason such as
+    /// the qualification of the ept violation are ignored. The handler
+    /// replaces the previous handler of the reasons.
+    pub fn register(
+        &mut self,
+        reasons: impl IntoIterator<Item = BasicExitReason>,
+        handler: impl ExitHandler + 'static,
+    ) -> &mut Self {
+        let idx = self.handlers.len();
+        self.handlers.push(Box::new(handler));
+        for reason in reasons {
+            let number = reason.number() as usize;
+            assert!(number != u16::MAX as usize, "Unknown exit reason.");
+            if self.table.len() <= number {
+                self.table.resize(number + 1, None);
+            }
+            self.table[number] = Some(idx);
+        }
+        self
+    }
+
+    /// Get the handler of the `reason`.
+    pub fn get_mut(&mut self, reason: &BasicExitReason) -> Option<&mut dyn ExitHandler> {
+        let idx = (*self.table.get(reason.number() as usize)?)?;
+        Some(self.handlers[idx].as_mut())
+    }
+}
+
+impl VmexitController for ExitDispatch {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match self.get_mut(reason.get_basic_reason()) {
+            Some(handler) => handler.handle_exit(reason, p, generic_vcpu_state),
+            None => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
+
+/// Identifier of a controller in a [`ControllerStack`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControllerId(usize);
+
+struct StackEntry {
+    id: ControllerId,
+    priority: i32,
+    handler: Box<dyn ExitHandler>,
+}
+
+/// Vmexit controller that asks the controllers in the order of their
+/// priorities.
+///
+/// Unlike the tuple of the controllers, the order is explicit, and the
+/// controllers can be added and removed at runtime, e.g. to attach a
+/// debugger for a while:
+///
+/// ```ignore
+/// let mut stack = ControllerStack::new();
+/// stack.push(shutdown::Controller::new());
+/// stack.push(hypercall::Controller::new(ctx));
+/// let debugger = stack.insert(ControllerStack::HIGHEST, debugger::Controller::new());
+/// // ...
+/// stack.remove(debugger);
+/// ```
+///
+/// The controller with the higher priority is asked first, and the
+/// controllers of the same priority are asked in the order that they are
+/// added. A controller declines an exit by returning
+/// [`VmError::HandleVmexitFailed`], and the exit falls through to the next
+/// controller. Any other result, including the other errors, is the result of
+/// the stack. The exits that all controllers decline fail with
+/// [`VmError::HandleVmexitFailed`], so the stack can be chained with the
+/// other controllers.
+#[derive(Default)]
+pub struct ControllerStack {
+    entries: Vec<StackEntry>,
+    next_id: usize,
+}
+
+impl ControllerStack {
+    /// The priority of the controllers added with [`ControllerStack::push`].
+    pub const DEFAULT: i32 = 0;
+    /// The highest priority.
+    pub const HIGHEST: i32 = i32::MAX;
+    /// The lowest priority.
+    pub const LOWEST: i32 = i32::MIN;
+
+    /// Create a new empty stack.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the `handler` with the [`ControllerStack::DEFAULT`] priority.
+    pub fn push(&mut self, handler: impl ExitHandler + 'static) -> ControllerId {
+        self.insert(Self::DEFAULT, handler)
+    }
+
+    /// Add the `handler` with the `priority`, after the controllers of the
+    /// same priority.
+    pub fn insert(&mut self, priority: i32, handler: impl ExitHandler + 'static) -> ControllerId {
+        let id = ControllerId(self.next_id);
+        self.next_id += 1;
+        let idx = self
+            .entries
+            .iter()
+            .position(|entry| entry.priority < priority)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(
+            idx,
+            StackEntry {
+                id,
+                priority,
+                handler: Box::new(handler),
+            },
+        );
+        id
+    }
+
+    /// Remove the controller of the `id`, and returns it.
+    pub fn remove(&mut self, id: ControllerId) -> Option<Box<dyn ExitHandler>> {
+        let idx = self.entries.iter().position(|entry| entry.id == id)?;
+        Some(self.entries.remove(idx).handler)
+    }
+
+    /// Get the priority of the controller of the `id`.
+    pub fn priority(&self, id: ControllerId) -> Option<i32> {
+        self.entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| entry.priority)
+    }
+
+    /// Number of the controllers in the stack.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the stack is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl VmexitController for ControllerStack {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        for entry in self.entries.iter_mut() {
+            match entry.handler.handle_exit(reason, p, generic_vcpu_state) {
+                Err(VmError::HandleVmexitFailed(_)) => continue,
+                r => return r,
+            }
         }
+        Err(VmError::HandleVmexitFailed(reason))
     }
 }
@@ -0,0 +1,119 @@
+//! VM functions.
+//!
+//! VM functions are operations that the guest invokes with the VMFUNC
+//! instruction without causing a vmexit. KeV supports the only VM function
+//! defined by the architecture, the EPTP switching (function 0): the guest
+//! selects one of up to 512 EPTs ("views") listed in the EPTP list by the
+//! hypervisor.
+//!
+//! ```text
+//! mov eax, 0      ; EPTP switching.
+//! mov ecx, index  ; Index into the EPTP list.
+//! vmfunc
+//! ```
+//!
+//! The hypervisor builds a view for each protection domain, registers the
+//! views in an [`EptpList`], and reports the list through
+//! [`VCpuState::eptp_list`]. Because a view can only be selected among the
+//! list, a guest is confined to the views prepared by the hypervisor, while
+//! switching between them takes no vmexit. An invalid index causes a vmexit
+//! with [`BasicExitReason::Vmfunc`].
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 25.5.6 VM Functions.
+//!
+//! [`VCpuState::eptp_list`]: crate::vcpu::VCpuState::eptp_list
+//! [`BasicExitReason::Vmfunc`]: crate::vmcs::BasicExitReason::Vmfunc
+use crate::{
+    vm_control::{IA32_VMX_PROC_BASED_CTLS2, IA32_VMX_VMFUNC},
+    Bits,
+};
+use abyss::x86_64::msr::Msr;
+use keos::{addressing::Pa, mm::Page};
+
+/// Maximum number of views in the EPTP list.
+pub const MAX_VIEWS: usize = 512;
+/// VM function controls that enables the EPTP switching.
+pub const EPTP_SWITCHING: u64 = 1 << 0;
+
+/// Check whether the processor supports the EPTP switching.
+pub fn eptp_switching_supported() -> bool {
+    // Bit 13 of the allowed 1-settings: "enable VM functions".
+    Msr::<IA32_VMX_PROC_BASED_CTLS2>::read().bit_test(32 + 13)
+        && Msr::<IA32_VMX_VMFUNC>::read() & EPTP_SWITCHING != 0
+}
+
+/// Build the EPT pointer of the EPT rooted at `pml4`.
+///
+/// The EPT is walked with 4 levels, and the paging structures are
+/// write-back cacheable.
+///
+/// See 25.6.11 Extended-Page-Table Pointer (EPTP).
+#[inline]
+pub fn eptp(pml4: Pa) -> u64 {
+    (unsafe { pml4.into_usize() } as u64) | (3 << 3) | 6
+}
+
+/// The EPTP list.
+///
+/// A 4-KByte page that holds up to [`MAX_VIEWS`] EPT pointers.
+pub struct EptpList {
+    page: Page,
+}
+
+impl EptpList {
+    /// Create a new empty list.
+    ///
+    /// Returns `None` if failed to allocate the page.
+    pub fn new() -> Option<Self> {
+        let mut page = Page::new()?;
+        unsafe {
+            page.inner_mut().fill(0);
+        }
+        Some(Self { page })
+    }
+
+    #[inline]
+    fn entries(&self) -> &[u64] {
+        unsafe { core::slice::from_raw_parts(self.page.inner().as_ptr() as *const u64, MAX_VIEWS) }
+    }
+
+    #[inline]
+    fn entries_mut(&mut self) -> &mut [u64] {
+        unsafe {
+            let inner = self.page.inner_mut();
+            core::slice::from_raw_parts_mut(inner.as_mut_ptr() as *mut u64, MAX_VIEWS)
+        }
+    }
+
+    /// Install the EPT rooted at `pml4` as the view `index`.
+    ///
+    /// Returns the EPT pointer that was installed at `index`.
+    ///
+    /// # Panics
+    /// Panics if `index` is not less than [`MAX_VIEWS`].
+    pub fn insert(&mut self, index: usize, pml4: Pa) -> Option<u64> {
+        assert!(index < MAX_VIEWS, "Index of the view is out of range.");
+        let prev = core::mem::replace(&mut self.entries_mut()[index], eptp(pml4));
+        (prev != 0).then_some(prev)
+    }
+
+    /// Remove the view `index`.
+    ///
+    /// Returns the EPT pointer that was installed at `index`.
+    pub fn remove(&mut self, index: usize) -> Option<u64> {
+        let prev = core::mem::replace(self.entries_mut().get_mut(index)?, 0);
+        (prev != 0).then_some(prev)
+    }
+
+    /// Get the EPT pointer of the view `index`.
+    pub fn get(&self, index: usize) -> Option<u64> {
+        self.entries().get(index).copied().filter(|e| *e != 0)
+    }
+
+    /// Get the physical address of the list.
+    #[inline]
+    pub fn pa(&self) -> Pa {
+        self.page.pa()
+    }
+}
@@ -0,0 +1,163 @@
+//! Watchdog of the guest heartbeats.
+//!
+//! A guest that is wedged, e.g. spinning with the interrupts off, neither
+//! exits nor makes progress, so the host waits for it forever. The guest
+//! proves its progress by issuing the [`HC_HEARTBEAT`] hypercall
+//! periodically, e.g. on its timer interrupts:
+//!
+//! | Register | Value                           |
+//! |----------|---------------------------------|
+//! | rax      | [`HC_HEARTBEAT`] (0x205)        |
+//!
+//! The host enables the watchdog of a vm with [`VmBuilder::heartbeat`]. The
+//! watchdog is armed when the bsp starts, and applies the [`CrashPolicy`]
+//! when the guest misses the heartbeats for the timeout:
+//!
+//! ```ignore
+//! let vm = VmBuilder::new(state, 1)?
+//!     .heartbeat(Heartbeat::new(Duration::from_secs(1)).policy(CrashPolicy::Terminate))
+//!     .finalize()?;
+//! vm.start_bsp()?;
+//! let status = vm.join_status();
+//! assert_ne!(status.code, watchdog::EXIT_WEDGED);
+//! ```
+//!
+//! [`VmBuilder::heartbeat`]: crate::vm::VmBuilder::heartbeat
+use crate::{
+    event,
+    probe::Probe,
+    vcpu::{GenericVCpuState, VmexitResult},
+    vm::{Vm, VmOps, VmState},
+    vmcs::{BasicExitReason, ExitReason},
+    vmexits::VmexitController,
+    VmError,
+};
+use abyss::dev::x86_64::rtc::unix_time_ns;
+use alloc::{string::String, sync::Weak};
+use core::time::Duration;
+use keos::thread::{Thread, ThreadBuilder};
+
+/// Hypercall number of the heartbeat.
+pub const HC_HEARTBEAT: usize = 0x205;
+/// Exit code of the vm terminated by the watchdog.
+pub const EXIT_WEDGED: i32 = -2;
+
+/// What the watchdog does to a wedged vm.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CrashPolicy {
+    /// Print a warning, and keep the vm running.
+    Warn,
+    /// Terminate the vm with the exit code of [`EXIT_WEDGED`].
+    Terminate,
+    /// Dump the core of the vm into the file, and terminate the vm.
+    DumpCore(String),
+}
+
+/// Heartbeat configuration of a vm.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    timeout: Duration,
+    policy: CrashPolicy,
+}
+
+impl Heartbeat {
+    /// Expect a heartbeat in every `timeout`.
+    ///
+    /// The vm is terminated on a missing heartbeat by default.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            policy: CrashPolicy::Terminate,
+        }
+    }
+
+    /// Set the policy on a missing heartbeat.
+    pub fn policy(mut self, policy: CrashPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Get the timeout of the heartbeats.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+// Watch the heartbeats of the `vm` until it exits.
+pub(crate) fn watch<S: VmState + 'static>(vm: Weak<Vm<S>>, config: Heartbeat) {
+    let timeout = config.timeout.as_nanos() as u64;
+    let interval = (config.timeout / 8).max(Duration::from_millis(1));
+    let name = match vm.upgrade() {
+        Some(vm) => vm.uuid().thread_name("watchdog"),
+        None => return,
+    };
+    ThreadBuilder::new(name).spawn(move || loop {
+        Thread::sleep(interval);
+        let Some(vm) = vm.upgrade() else {
+            return;
+        };
+        if vm.exit_status().is_some() {
+            return;
+        }
+        let now = unix_time_ns();
+        let last = vm.last_heartbeat();
+        if now.saturating_sub(last) < timeout {
+            continue;
+        }
+        warning!(
+            "vm {}: no heartbeat for {} ms.",
+            vm.uuid(),
+            (now - last) / 1_000_000
+        );
+        // Show the threads, to tell where the vm is stuck.
+        keos::thread::dump_all();
+        match &config.policy {
+            CrashPolicy::Warn => vm.heartbeat(),
+            CrashPolicy::Terminate => return vm.terminate(EXIT_WEDGED),
+            CrashPolicy::DumpCore(path) => {
+                if let Err(e) = vm.dump_core(path) {
+                    warning!("vm {}: failed to dump the core: {:?}", vm.uuid(), e);
+                }
+                return vm.terminate(EXIT_WEDGED);
+            }
+        }
+    });
+}
+
+/// Vmexit controller of the [`HC_HEARTBEAT`] hypercall.
+///
+/// The other hypercalls fail with [`VmError::HandleVmexitFailed`], so the
+/// controller is chained before the hypercall controller of the vm.
+#[derive(Default)]
+pub struct Controller;
+
+impl Controller {
+    /// Create a new heartbeat controller.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        match reason.get_basic_reason() {
+            BasicExitReason::Vmcall if generic_vcpu_state.gprs.rax == HC_HEARTBEAT => {
+                // A process of a wedged kernel must not keep the vm alive.
+                if generic_vcpu_state.vmcs.guest_cpl()? != 0 {
+                    return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+                }
+                if let Some(vm) = generic_vcpu_state.vm.upgrade() {
+                    vm.heartbeat();
+                }
+                generic_vcpu_state.gprs.rax = 0;
+                Ok(VmexitResult::HandledAdvance)
+            }
+            _ => Err(VmError::HandleVmexitFailed(reason)),
+        }
+    }
+}
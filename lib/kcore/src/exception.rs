@@ -0,0 +1,251 @@
+//! Delivery of the x86 exceptions.
+//!
+//! An exception raised while the cpu delivers another one is handled by the
+//! classes of the two (Table 6-5 of the SDM):
+//!
+//! | First \ Second | Benign         | Contributory | Page fault   |
+//! |----------------|----------------|--------------|--------------|
+//! | Benign         | second         | second       | second       |
+//! | Contributory   | second         | double fault | second       |
+//! | Page fault     | second         | double fault | double fault |
+//! | Double fault   | second         | triple fault | triple fault |
+//!
+//! A triple fault shuts the cpu down.
+//!
+//! See Intel® 64 and IA-32 Architectures Software Developer’s Manual,
+//! 6.15 Exception and Interrupt Reference, Interrupt 8—Double Fault Exception.
+
+/// Divide error.
+pub const DE: u8 = 0;
+/// Debug exception.
+pub const DB: u8 = 1;
+/// Non-maskable interrupt.
+pub const NMI: u8 = 2;
+/// Breakpoint.
+pub const BP: u8 = 3;
+/// Overflow.
+pub const OF: u8 = 4;
+/// Bound range exceeded.
+pub const BR: u8 = 5;
+/// Invalid opcode.
+pub const UD: u8 = 6;
+/// Device not available.
+pub const NM: u8 = 7;
+/// Double fault.
+pub const DF: u8 = 8;
+/// Invalid TSS.
+pub const TS: u8 = 10;
+/// Segment not present.
+pub const NP: u8 = 11;
+/// Stack-segment fault.
+pub const SS: u8 = 12;
+/// General protection.
+pub const GP: u8 = 13;
+/// Page fault.
+pub const PF: u8 = 14;
+/// X87 floating-point error.
+pub const MF: u8 = 16;
+/// Alignment check.
+pub const AC: u8 = 17;
+/// Machine check.
+pub const MC: u8 = 18;
+/// SIMD floating-point exception.
+pub const XM: u8 = 19;
+/// Virtualization exception.
+pub const VE: u8 = 20;
+/// Control protection exception.
+pub const CP: u8 = 21;
+
+/// Class of an exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Delivered after the first one, whatever it is.
+    Benign,
+    /// Escalates to a double fault after a contributory or a page fault.
+    Contributory,
+    /// Escalates to a double fault after a page fault.
+    PageFault,
+    /// The double fault; a further fault shuts the cpu down.
+    DoubleFault,
+}
+
+/// Get the class of the exception `vector`.
+pub fn class(vector: u8) -> Class {
+    match vector {
+        DE | TS | NP | SS | GP | CP => Class::Contributory,
+        PF | VE => Class::PageFault,
+        DF => Class::DoubleFault,
+        _ => Class::Benign,
+    }
+}
+
+/// Check whether the exception `vector` pushes an error code in the
+/// protected mode.
+pub fn has_error_code(vector: u8) -> bool {
+    matches!(vector, DF | TS | NP | SS | GP | PF | AC | CP)
+}
+
+/// An invalid exception.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionError {
+    /// The vector is not of an exception, e.g. the NMI or a reserved one.
+    InvalidVector(u8),
+    /// The error code is given to the exception without one, or is missing.
+    ErrorCode(u8),
+}
+
+/// An exception with its error code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Exception {
+    vector: u8,
+    error_code: Option<u32>,
+}
+
+impl Exception {
+    /// Create the exception `vector` with the `error_code`.
+    ///
+    /// The error code must be given iff the exception has one.
+    pub fn new(vector: u8, error_code: Option<u32>) -> Result<Self, ExceptionError> {
+        if vector == NMI || vector == 9 || vector == 15 || vector > CP {
+            Err(ExceptionError::InvalidVector(vector))
+        } else if has_error_code(vector) != error_code.is_some() {
+            Err(ExceptionError::ErrorCode(vector))
+        } else {
+            Ok(Self { vector, error_code })
+        }
+    }
+
+    /// The double fault, of which error code is always 0.
+    pub const fn double_fault() -> Self {
+        Self {
+            vector: DF,
+            error_code: Some(0),
+        }
+    }
+
+    /// Get the vector.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// Get the error code.
+    pub fn error_code(&self) -> Option<u32> {
+        self.error_code
+    }
+
+    /// Get the class.
+    pub fn class(&self) -> Class {
+        class(self.vector)
+    }
+}
+
+/// What the cpu delivers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Deliver the exception.
+    Deliver(Exception),
+    /// Shut down.
+    TripleFault,
+}
+
+/// Raise the exception `second` while delivering the `first`, if any.
+pub fn raise(first: Option<Exception>, second: Exception) -> Delivery {
+    let Some(first) = first else {
+        return Delivery::Deliver(second);
+    };
+    match (first.class(), second.class()) {
+        (_, Class::Benign) | (Class::Benign, _) => Delivery::Deliver(second),
+        (Class::DoubleFault, _) => Delivery::TripleFault,
+        (_, Class::DoubleFault) => Delivery::Deliver(second),
+        (Class::PageFault, _) | (Class::Contributory, Class::Contributory) => {
+            Delivery::Deliver(Exception::double_fault())
+        }
+        (Class::Contributory, Class::PageFault) => Delivery::Deliver(second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exception(vector: u8) -> Exception {
+        let error_code = has_error_code(vector).then_some(0);
+        Exception::new(vector, error_code).unwrap()
+    }
+
+    #[test]
+    fn error_code() {
+        assert_eq!(Exception::new(GP, None), Err(ExceptionError::ErrorCode(GP)));
+        assert_eq!(
+            Exception::new(UD, Some(0)),
+            Err(ExceptionError::ErrorCode(UD))
+        );
+        assert_eq!(Exception::new(PF, Some(2)).unwrap().error_code(), Some(2));
+        assert_eq!(
+            Exception::new(NMI, None),
+            Err(ExceptionError::InvalidVector(NMI))
+        );
+        assert_eq!(
+            Exception::new(15, None),
+            Err(ExceptionError::InvalidVector(15))
+        );
+        assert_eq!(
+            Exception::new(32, None),
+            Err(ExceptionError::InvalidVector(32))
+        );
+    }
+
+    #[test]
+    fn benign_is_serial() {
+        for first in [DE, UD, GP, PF, DF] {
+            for second in [DB, BP, UD, MC] {
+                assert_eq!(
+                    raise(Some(exception(first)), exception(second)),
+                    Delivery::Deliver(exception(second))
+                );
+            }
+        }
+        for second in [DE, GP, PF] {
+            assert_eq!(
+                raise(Some(exception(UD)), exception(second)),
+                Delivery::Deliver(exception(second))
+            );
+        }
+    }
+
+    #[test]
+    fn double_fault() {
+        let df = Delivery::Deliver(Exception::double_fault());
+        assert_eq!(raise(Some(exception(GP)), exception(NP)), df);
+        assert_eq!(raise(Some(exception(PF)), exception(GP)), df);
+        assert_eq!(raise(Some(exception(PF)), exception(PF)), df);
+        // A page fault while delivering a contributory one is serial.
+        assert_eq!(
+            raise(Some(exception(GP)), exception(PF)),
+            Delivery::Deliver(exception(PF))
+        );
+    }
+
+    #[test]
+    fn triple_fault() {
+        assert_eq!(
+            raise(Some(exception(DF)), exception(GP)),
+            Delivery::TripleFault
+        );
+        assert_eq!(
+            raise(Some(exception(DF)), exception(PF)),
+            Delivery::TripleFault
+        );
+        // Escalate step by step.
+        let first = match raise(Some(exception(PF)), exception(PF)) {
+            Delivery::Deliver(e) => e,
+            d => panic!("{:?}", d),
+        };
+        assert_eq!(raise(Some(first), exception(SS)), Delivery::TripleFault);
+    }
+
+    #[test]
+    fn no_first() {
+        assert_eq!(raise(None, exception(DF)), Delivery::Deliver(exception(DF)));
+    }
+}
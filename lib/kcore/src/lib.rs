@@ -0,0 +1,25 @@
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+#![feature(const_mut_refs)]
+
+//! The policies of keos and kev that do not touch the hardware.
+//!
+//! keos links this crate as a `no_std` library, and wraps the policies with
+//! its locks, threads and timer interrupts. The same code builds with `std` on
+//! the host, where the threads and the clock are simulated, so the policies
+//! are tested with `cargo test` without booting QEMU:
+//!
+//! ```bash
+//! $ cd lib/kcore && cargo test
+//! ```
+//!
+//! - [`exception`]: the escalation of the x86 exceptions.
+//! - [`slob`]: the heap allocator.
+//! - [`stride`]: the run queue and the time slices of the stride scheduler.
+//! - [`timer`]: the deadlines of the sleeping threads.
+
+extern crate alloc;
+
+pub mod exception;
+pub mod slob;
+pub mod stride;
+pub mod timer;
@@ -0,0 +1,277 @@
+// Copyright (c) 2023 KAIST Computer Architecture and System Lab
+
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! A singly linked list of the free regions (SLOB) allocator.
+//!
+//! The allocator does not own the memory; the caller hands the free regions
+//! with [`SlobAllocator::add_free_region`], e.g. the pages from the page
+//! allocator of keos or a leaked buffer on the host.
+use core::alloc::Layout;
+
+// Align upwards. The alignment must be a power of 2.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+pub struct SlobNode {
+    size: usize,
+    next: Option<&'static mut SlobNode>,
+}
+
+impl SlobNode {
+    const fn new(size: usize) -> Self {
+        SlobNode { size, next: None }
+    }
+
+    pub fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn end_addr(&self) -> usize {
+        match self.start_addr().overflowing_add(self.size) {
+            (_, true) => panic!("{:x} {:x}", self.start_addr(), self.size),
+            (e, false) => e,
+        }
+    }
+}
+
+pub struct SlobAllocator {
+    head: SlobNode,
+}
+
+impl Default for SlobAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlobAllocator {
+    /// Creates an empty LinkedListAllocator.
+    pub const fn new() -> Self {
+        Self {
+            head: SlobNode::new(0),
+        }
+    }
+
+    /// region is also capable of storing a `ListNode`.
+    ///
+    /// Returns the adjusted size and alignment as a (size, align) tuple.
+    pub fn align_to_slob_node(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(core::mem::align_of::<SlobNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(core::mem::size_of::<SlobNode>());
+        (size, layout.align())
+    }
+
+    /// Add the free region of `size` bytes at `addr`.
+    ///
+    /// # Safety
+    /// The region must be unused and live forever, and its `addr` must be
+    /// aligned to the `SlobNode`.
+    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // ensure that the freed region is capable of holding ListNode
+        assert_eq!(align_up(addr, core::mem::align_of::<SlobNode>()), addr);
+        assert!(size >= core::mem::size_of::<SlobNode>());
+        // create a new list node and append it at the start of the list
+        let mut node = SlobNode::new(size);
+        node.next = self.head.next.take();
+        let node_ptr = addr as *mut SlobNode;
+        node_ptr.write(node);
+        self.head.next = Some(&mut *node_ptr)
+    }
+
+    /// Allocate a block of the `layout`, and returns its address.
+    ///
+    /// Returns `None` if no free region fits; the caller adds a free region
+    /// and tries again.
+    pub fn alloc(&mut self, layout: Layout) -> Option<usize> {
+        let (size, align) = Self::align_to_slob_node(layout);
+        let (region, alloc_start) = self.find_region(size, align)?;
+        let alloc_end = alloc_start.checked_add(size).expect("overflow");
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 {
+            unsafe {
+                self.add_free_region(alloc_end, excess_size);
+            }
+        }
+        Some(alloc_start)
+    }
+
+    /// Free the block at `addr` of the `layout`.
+    ///
+    /// # Safety
+    /// The block must be allocated by [`SlobAllocator::alloc`] with the same
+    /// `layout`.
+    pub unsafe fn dealloc(&mut self, addr: usize, layout: Layout) {
+        let (size, _) = Self::align_to_slob_node(layout);
+        self.add_free_region(addr, size)
+    }
+
+    /// Looks for a free region with the given size and alignment and removes
+    /// it from the list.
+    ///
+    /// Returns a tuple of the list node and the start address of the allocation.
+    pub fn find_region(
+        &mut self,
+        size: usize,
+        align: usize,
+    ) -> Option<(&'static mut SlobNode, usize)> {
+        // reference to current list node, updated for each iteration
+        let mut current = &mut self.head;
+        // look for a large enough memory region in linked list
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                // region suitable for allocation -> remove node from list
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                // region not suitable -> continue with next region
+                current = current.next.as_mut().unwrap();
+            }
+        }
+
+        // no suitable region found
+        None
+    }
+
+    /// Try to use the given region for an allocation with given size and
+    /// alignment.
+    ///
+    /// Returns the allocation start address on success.
+    fn alloc_from_region(region: &SlobNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < core::mem::size_of::<SlobNode>() {
+            // rest of region too small to hold a ListNode (required because the
+            // allocation splits the region in a used and a free part)
+            return Err(());
+        }
+
+        // region suitable for allocation
+        Ok(alloc_start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const ARENA: usize = 1 << 20;
+
+    // Leak a buffer of `size` bytes as a free region of the allocator.
+    fn arena(allocator: &mut SlobAllocator, size: usize) -> (usize, usize) {
+        let buf = Vec::<u64>::with_capacity(size / 8).leak();
+        let start = buf.as_ptr() as usize;
+        unsafe {
+            allocator.add_free_region(start, size);
+        }
+        (start, start + size)
+    }
+
+    // A xorshift generator, to reproduce the failing sequence.
+    fn rng(mut seed: u64) -> impl FnMut() -> u64 {
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        }
+    }
+
+    #[test]
+    fn alloc_in_arena() {
+        let mut allocator = SlobAllocator::new();
+        let (start, end) = arena(&mut allocator, ARENA);
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        let a = allocator.alloc(layout).unwrap();
+        let b = allocator.alloc(layout).unwrap();
+        assert!(a != b);
+        for addr in [a, b] {
+            assert_eq!(addr % 64, 0);
+            assert!(start <= addr && addr + 100 <= end);
+        }
+    }
+
+    #[test]
+    fn exhaust() {
+        let mut allocator = SlobAllocator::new();
+        arena(&mut allocator, 4096);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let blocks: Vec<_> = (0..4).map(|_| allocator.alloc(layout)).collect();
+        assert!(blocks.iter().all(Option::is_some));
+        assert_eq!(allocator.alloc(layout), None);
+        unsafe {
+            allocator.dealloc(blocks[2].unwrap(), layout);
+        }
+        assert_eq!(allocator.alloc(layout), blocks[2]);
+    }
+
+    #[test]
+    fn random_alloc_dealloc() {
+        let mut allocator = SlobAllocator::new();
+        let (start, end) = arena(&mut allocator, ARENA);
+        let mut next = rng(0x5eed);
+        let mut live: Vec<(usize, Layout)> = Vec::new();
+        for _ in 0..10000 {
+            if live.len() > 64 || (!live.is_empty() && next() % 3 == 0) {
+                let (addr, layout) = live.swap_remove(next() as usize % live.len());
+                unsafe {
+                    allocator.dealloc(addr, layout);
+                }
+                continue;
+            }
+            let layout =
+                Layout::from_size_align(1 + next() as usize % 2048, 1 << (next() % 8)).unwrap();
+            let Some(addr) = allocator.alloc(layout) else {
+                // The freed regions are not merged, so the arena fragments.
+                continue;
+            };
+            assert_eq!(addr % layout.align(), 0);
+            assert!(start <= addr && addr + layout.size() <= end);
+            for (other, other_layout) in live.iter() {
+                assert!(
+                    addr + layout.size() <= *other || other + other_layout.size() <= addr,
+                    "{:#x} overlaps {:#x}",
+                    addr,
+                    other
+                );
+            }
+            live.push((addr, layout));
+        }
+    }
+}
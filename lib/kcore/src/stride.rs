@@ -0,0 +1,257 @@
+//! Run queue of the weighted stride scheduler.
+//!
+//! Each entity receives the cpu in proportion to its weight. An entity has a
+//! stride inversely proportional to its weight, and a pass that advances by
+//! the stride whenever the entity is scheduled. The queue always pops the
+//! entity with the smallest pass.
+//!
+//! An entity that joins the queue can not run ahead of the others with its
+//! old pass; its pass is lifted to the pass of the last popped entity.
+//!
+//! The running entity is preempted when its [`TimeSlice`] expires. The slice
+//! is charged for the time the entity really ran; under a hypervisor, the time
+//! stolen from the vcpu by the host is not charged.
+use alloc::{boxed::Box, vec::Vec};
+
+/// The stride of an entity of weight 1.
+pub const STRIDE1: u64 = 1 << 20;
+
+/// An entity scheduled by the stride, e.g. a thread.
+pub trait Strided {
+    /// Get the weight of the entity; it must be positive.
+    fn weight(&self) -> u32;
+    /// Get the pass of the entity.
+    fn pass(&self) -> u64;
+    /// Set the pass of the entity.
+    fn set_pass(&mut self, pass: u64);
+}
+
+impl<T: Strided> Strided for Box<T> {
+    fn weight(&self) -> u32 {
+        (**self).weight()
+    }
+
+    fn pass(&self) -> u64 {
+        (**self).pass()
+    }
+
+    fn set_pass(&mut self, pass: u64) {
+        (**self).set_pass(pass)
+    }
+}
+
+/// A run queue of the stride scheduler.
+pub struct StrideQueue<T: Strided> {
+    queue: Vec<T>,
+    // Pass of the last popped entity.
+    pass: u64,
+}
+
+impl<T: Strided> Default for StrideQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Strided> StrideQueue<T> {
+    /// Create an empty queue.
+    pub const fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            pass: 0,
+        }
+    }
+
+    /// Push the `entity` to the queue.
+    pub fn push(&mut self, mut entity: T) {
+        entity.set_pass(entity.pass().max(self.pass));
+        self.queue.push(entity);
+    }
+
+    /// Pop the entity of the smallest pass, and advances its pass.
+    pub fn pop(&mut self) -> Option<T> {
+        let (idx, _) = self
+            .queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entity)| entity.pass())?;
+        let mut entity = self.queue.swap_remove(idx);
+        self.pass = entity.pass();
+        entity.set_pass(self.pass + STRIDE1 / entity.weight() as u64);
+        Some(entity)
+    }
+
+    /// Get the number of the entities in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Check whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// The time slice of the running entity.
+///
+/// The slice is charged for the elapsed time on every tick, less the time
+/// stolen by the host since the last tick. Otherwise, an entity whose slice
+/// overlaps with the host preemption of the vcpu would lose its share.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeSlice {
+    len: u64,
+    used: u64,
+    stolen: u64,
+}
+
+impl TimeSlice {
+    /// Create a slice of the `len`.
+    pub const fn new(len: u64) -> Self {
+        Self {
+            len,
+            used: 0,
+            stolen: 0,
+        }
+    }
+
+    /// Credit the `stolen` time to the slice on the next tick.
+    pub fn steal(&mut self, stolen: u64) {
+        self.stolen = self.stolen.saturating_add(stolen);
+    }
+
+    /// Charge the `elapsed` time since the last tick, less the stolen time.
+    ///
+    /// Returns true if the slice expired, and starts a new slice.
+    pub fn tick(&mut self, elapsed: u64) -> bool {
+        // The stolen time beyond the elapsed time is of the earlier ticks.
+        self.used += elapsed.saturating_sub(core::mem::take(&mut self.stolen));
+        if self.used >= self.len {
+            self.used = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the time charged to the slice.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A simulated thread.
+    struct Thread {
+        id: usize,
+        weight: u32,
+        pass: u64,
+    }
+
+    impl Strided for Thread {
+        fn weight(&self) -> u32 {
+            self.weight
+        }
+
+        fn pass(&self) -> u64 {
+            self.pass
+        }
+
+        fn set_pass(&mut self, pass: u64) {
+            self.pass = pass;
+        }
+    }
+
+    fn thread(id: usize, weight: u32) -> Box<Thread> {
+        Box::new(Thread {
+            id,
+            weight,
+            pass: 0,
+        })
+    }
+
+    // Run the threads for `slices` time slices, and returns the slices of
+    // each thread.
+    fn run(queue: &mut StrideQueue<Box<Thread>>, slices: usize, runs: &mut [usize]) {
+        for _ in 0..slices {
+            let th = queue.pop().unwrap();
+            runs[th.id] += 1;
+            queue.push(th);
+        }
+    }
+
+    #[test]
+    fn proportional() {
+        let mut queue = StrideQueue::new();
+        for (id, weight) in [1, 2, 4].into_iter().enumerate() {
+            queue.push(thread(id, weight));
+        }
+        let mut runs = [0; 3];
+        run(&mut queue, 700, &mut runs);
+        assert_eq!(runs, [100, 200, 400]);
+    }
+
+    #[test]
+    fn late_joiner() {
+        let mut queue = StrideQueue::new();
+        queue.push(thread(0, 1));
+        let mut runs = [0; 2];
+        run(&mut queue, 100, &mut runs);
+        // A new thread shares the cpu from now on, instead of catching up.
+        queue.push(thread(1, 1));
+        runs = [0; 2];
+        run(&mut queue, 100, &mut runs);
+        assert_eq!(runs, [50, 50]);
+    }
+
+    // Run the threads on a vcpu that the host runs for `run` of every
+    // `period` ticks, and returns the ticks that each thread ran.
+    fn overcommit(credit: bool, run: usize, period: usize) -> [usize; 2] {
+        let mut queue = StrideQueue::new();
+        queue.push(thread(0, 1));
+        queue.push(thread(1, 1));
+        let mut slice = TimeSlice::new(5);
+        let mut current = queue.pop().unwrap();
+        let (mut runs, mut elapsed) = ([0; 2], 0);
+        for t in 0..10000 {
+            elapsed += 1;
+            if t % period >= run {
+                // The host runs another vcpu.
+                if credit {
+                    slice.steal(1);
+                }
+                continue;
+            }
+            runs[current.id] += 1;
+            if slice.tick(core::mem::take(&mut elapsed)) {
+                queue.push(current);
+                current = queue.pop().unwrap();
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn steal_is_not_charged() {
+        // The host preemption overlaps with the slices of the thread 1.
+        assert_eq!(overcommit(false, 6, 10), [5000, 1000]);
+        let [a, b] = overcommit(true, 6, 10);
+        assert!(a.abs_diff(b) <= 5, "{} {}", a, b);
+    }
+
+    #[test]
+    fn time_slice() {
+        let mut slice = TimeSlice::new(5);
+        assert!(!slice.tick(4));
+        slice.steal(3);
+        assert!(!slice.tick(2));
+        assert_eq!(slice.used(), 4);
+        // The steal beyond the elapsed time is dropped.
+        slice.steal(10);
+        assert!(!slice.tick(1));
+        assert!(slice.tick(1));
+        assert_eq!(slice.used(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Deadlines of the sleeping threads.
+//!
+//! The timers are kept in a list ordered by their deadlines; the earliest one
+//! is popped first. The deadlines are in any unit of time, e.g. nanoseconds.
+use alloc::vec::Vec;
+
+/// The timers, ordered by their deadlines.
+pub struct TimerQueue<T> {
+    // Ordered from the latest deadline to the earliest.
+    timers: Vec<(u64, T)>,
+}
+
+impl<T> Default for TimerQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TimerQueue<T> {
+    /// Create an empty queue.
+    pub const fn new() -> Self {
+        Self { timers: Vec::new() }
+    }
+
+    /// Add the timer of the `item` at the `deadline`.
+    ///
+    /// The timers of the same deadline expire in the order they are added.
+    pub fn add(&mut self, deadline: u64, item: T) {
+        let idx = self.timers.partition_point(|(at, _)| *at > deadline);
+        self.timers.insert(idx, (deadline, item));
+    }
+
+    /// Get the earliest deadline.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timers.last().map(|(deadline, _)| *deadline)
+    }
+
+    /// Pop the timer whose deadline passes at `now`, from the earliest.
+    pub fn pop_expired(&mut self, now: u64) -> Option<T> {
+        if self.next_deadline()? <= now {
+            self.timers.pop().map(|(_, item)| item)
+        } else {
+            None
+        }
+    }
+
+    /// Get the number of the timers.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Check whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Advance the simulated clock to `now`, and returns the woken items.
+    fn tick(queue: &mut TimerQueue<usize>, now: u64) -> Vec<usize> {
+        core::iter::from_fn(|| queue.pop_expired(now)).collect()
+    }
+
+    #[test]
+    fn expire_in_order() {
+        let mut queue = TimerQueue::new();
+        for (id, deadline) in [30, 10, 20, 10].into_iter().enumerate() {
+            queue.add(deadline, id);
+        }
+        assert_eq!(queue.next_deadline(), Some(10));
+        assert_eq!(tick(&mut queue, 5), []);
+        assert_eq!(tick(&mut queue, 10), [1, 3]);
+        assert_eq!(tick(&mut queue, 25), [2]);
+        assert_eq!(queue.next_deadline(), Some(30));
+        assert_eq!(tick(&mut queue, 100), [0]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn sleepers() {
+        // Each thread sleeps for its id + 1 ticks, and sleeps again when woken.
+        let mut queue = TimerQueue::new();
+        let mut wakeups = [0; 4];
+        for id in 0..4 {
+            queue.add(id as u64 + 1, id);
+        }
+        for now in 1..=12 {
+            for id in tick(&mut queue, now) {
+                wakeups[id] += 1;
+                queue.add(now + id as u64 + 1, id);
+            }
+        }
+        assert_eq!(wakeups, [12, 6, 4, 3]);
+        assert_eq!(queue.len(), 4);
+    }
+}
@@ -36,5 +36,7 @@ exec qemu-system-x86_64 \
     -cdrom ${OUTPUT}/target/kernel.iso \
     -device virtio-blk-pci,drive=kernel -drive format=raw,if=none,file=keos_kernel,id=kernel,cache=none,readonly \
     -device virtio-blk-pci,drive=disk -drive format=raw,if=none,file=blk.bin,id=disk,cache=none \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
     ${QEMU_CPU_TYPE} ${GDB} -s \
     -smp ${MP} -m ${MEM} -serial mon:stdio -no-reboot
@@ -26,6 +26,8 @@ pub unsafe fn main() {
         &round_robin::balance2,
         &round_robin::affinity,
         &round_robin::reschedule,
+        &stride::proportional,
+        &stride::late_comer,
         &page_table::simple,
         &page_table::complicate,
     ]);
@@ -36,6 +38,7 @@ mod round_robin {
     use core::sync::atomic::{AtomicUsize, Ordering};
     use keos::{
         intrinsics::cpuid,
+        ncpu,
         sync::SpinLock,
         thread::{scheduler::Scheduler, Thread, ThreadBuilder},
         MAX_CPU,
@@ -70,7 +73,7 @@ mod round_robin {
         let cnt = Arc::new(SpinLock::new(0));
         let scheduler = Arc::new(project1::rr::RoundRobin::new());
         let mut handles = VecDeque::new();
-        for i in 0..MAX_CPU {
+        for i in 0..ncpu() {
             let cnt = cnt.clone();
             let scheduler = scheduler.clone();
             let handle = ThreadBuilder::new(format!("t{}", i)).spawn(move || {
@@ -80,18 +83,18 @@ mod round_robin {
                 {
                     *cnt.lock() += 1;
                 }
-                while *cnt.lock() < MAX_CPU {}
+                while *cnt.lock() < ncpu() {}
 
                 // Generate N-1 Tasks and pull them on a single core.
-                for i in 0..MAX_CPU {
+                for i in 0..ncpu() {
                     if i != cid {
                         let thread = Thread::new(cid.to_string());
                         scheduler.push_to_queue(thread);
                         *cnt.lock() += 1;
-                        while *cnt.lock() < (2 + i) * MAX_CPU {}
+                        while *cnt.lock() < (2 + i) * ncpu() {}
                     } else {
-                        while *cnt.lock() != (2 + i) * MAX_CPU - 1 {}
-                        for _ in 0..MAX_CPU - 1 {
+                        while *cnt.lock() != (2 + i) * ncpu() - 1 {}
+                        for _ in 0..ncpu() - 1 {
                             assert!(scheduler.next_to_run().is_some());
                         }
                         assert!(scheduler.next_to_run().is_none());
@@ -110,7 +113,7 @@ mod round_robin {
         let cnt = Arc::new(SpinLock::new(0));
         let scheduler = Arc::new(project1::rr::RoundRobin::new());
         let mut handles = VecDeque::new();
-        for i in 0..MAX_CPU {
+        for i in 0..ncpu() {
             // Diable all cores' interrupt.
             let cnt = cnt.clone();
             let scheduler = scheduler.clone();
@@ -120,14 +123,14 @@ mod round_robin {
                 {
                     *cnt.lock() += 1;
                 }
-                while *cnt.lock() < MAX_CPU {}
+                while *cnt.lock() < ncpu() {}
 
                 // Now, all cores pushed a dummy thread into their run queue one by one.
                 loop {
                     let mut c = cnt.lock();
-                    if *c >= 5 * MAX_CPU {
+                    if *c >= 5 * ncpu() {
                         break;
-                    } else if *c % MAX_CPU == cid {
+                    } else if *c % ncpu() == cid {
                         scheduler.push_to_queue(Thread::new(cid.to_string()));
                         *c += 1;
                     }
@@ -137,9 +140,9 @@ mod round_robin {
                 loop {
                     let mut c = cnt.lock();
                     // Because each core pushes the thread with same frequency, threads MUST not be moved between queues.
-                    if *c == 9 * MAX_CPU {
+                    if *c == 9 * ncpu() {
                         break;
-                    } else if MAX_CPU - 1 - *c % MAX_CPU == cid {
+                    } else if ncpu() - 1 - *c % ncpu() == cid {
                         assert_eq!(
                             scheduler
                                 .next_to_run()
@@ -184,6 +187,56 @@ mod round_robin {
     }
 }
 
+mod stride {
+    use alloc::format;
+    use keos::thread::{scheduler::Scheduler, stride::Stride, Thread};
+
+    fn thread(id: usize, weight: u32) -> alloc::boxed::Box<Thread> {
+        let mut th = Thread::new(format!("{}", id));
+        th.set_weight(weight);
+        th
+    }
+
+    // Run the scheduler for `rounds` time slices, and count the slices of
+    // each thread.
+    fn run(scheduler: &Stride, counts: &mut [usize], rounds: usize) {
+        for _ in 0..rounds {
+            let th = scheduler.next_to_run().unwrap();
+            counts[th.name.parse::<usize>().unwrap()] += 1;
+            scheduler.push_to_queue(th);
+        }
+    }
+
+    pub fn proportional() {
+        let weights = [1, 2, 3, 6];
+        let scheduler = Stride::new();
+        for (id, weight) in weights.iter().enumerate() {
+            scheduler.push_to_queue(thread(id, *weight));
+        }
+        let mut counts = [0; 4];
+        run(&scheduler, &mut counts, 1200);
+        // Each thread runs in proportion to its weight.
+        for (count, weight) in counts.iter().zip(weights.iter()) {
+            assert!(count.abs_diff(100 * *weight as usize) <= 1);
+        }
+        while scheduler.next_to_run().is_some() {}
+    }
+
+    pub fn late_comer() {
+        let scheduler = Stride::new();
+        scheduler.push_to_queue(thread(0, 1));
+        scheduler.push_to_queue(thread(1, 1));
+        let mut counts = [0; 3];
+        run(&scheduler, &mut counts, 100);
+        // The new thread does not monopolize the cpu to catch up.
+        scheduler.push_to_queue(thread(2, 1));
+        let mut counts = [0; 3];
+        run(&scheduler, &mut counts, 90);
+        assert!(counts.iter().all(|count| count.abs_diff(30) <= 1));
+        while scheduler.next_to_run().is_some() {}
+    }
+}
+
 mod page_table {
     use keos::{
         addressing::{Va, PAGE_SHIFT},
@@ -67,7 +67,11 @@
 //! You can use [`TLBInvalidate`] to invalidate the TLB entry.
 //! Note that TLB is fully flushed when `cr3` is reloaded.
 //!
+//! Each core has its own TLB. [`TLBInvalidate::invalidate`] therefore shoots
+//! down the entry on every core, not only on the current core.
+//!
 //! [`TLBInvalidate`]: TLBInvalidate
+//! [`TLBInvalidate::invalidate`]: TLBInvalidate::invalidate
 
 use alloc::boxed::Box;
 use core::ops::{Deref, DerefMut};
@@ -80,17 +84,11 @@ use keos::{
 pub struct TLBInvalidate(Va);
 
 impl TLBInvalidate {
-    /// Invalidate the underlying va.
+    /// Invalidate the underlying va on every core.
     pub fn invalidate(self) {
         let va = core::mem::ManuallyDrop::new(self).0;
 
-        unsafe {
-            core::arch::asm!(
-                "invlpg [{0}]",
-                in(reg) va.into_usize(),
-                options(nostack)
-            );
-        }
+        keos::mm::tlb::shootdown(keos::mm::tlb::Flush::Page(va));
     }
 
     /// Forget this modification.
@@ -26,7 +26,7 @@
 //! currently running thread and restores the state of the thread we're switching to.
 //!
 //! ## IMPORTANT NOTES
-//! In KeOS, each thread is assigned a [`STACK_SIZE`]-size execution stack. The KeOS try to detect
+//! In KeOS, each thread is assigned a [`STACK_SIZE`]-size execution stack by default. The KeOS try to detect
 //! the stack overflow, however it is not perfect. If the stack is overflowed, you can encounter a
 //! mysterious kernel panics.
 //! To prevent the such situation, DO NOT DECLARE large data structures (e.g. `let v: [u8; 0x200000];`)
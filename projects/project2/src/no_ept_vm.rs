@@ -23,6 +23,7 @@ use keos::{
     mm::Page,
 };
 use kev::{
+    page_walk::{Access, WalkError},
     vcpu::{
         segmentation::{Segment, SEGMENT_TABLE},
         table::SystemTableRegister,
@@ -292,6 +293,11 @@ impl kev::Probe for NoEpt {
             .ok()?
             .map(|pa| pa + ofs)
     }
+    fn translate(&self, vmcs: &ActiveVmcs, gva: Gva, _access: Access) -> Result<Pa, WalkError> {
+        // There are no guest page tables to walk; the host page table maps the gva.
+        self.gva2hpa(vmcs, gva)
+            .ok_or(WalkError::NotPresent { level: 1 })
+    }
 }
 
 /// The Vcpu state of NoEptVmState.
@@ -1,5 +1,5 @@
 //! Pio handlers to test pio instructions correctly implemented.
-use crate::vmexit::pio::{Direction, PioHandler};
+use crate::vmexit::pio::{Direction, PioHandler, StringIo};
 use alloc::{boxed::Box, collections::LinkedList};
 use core::fmt::Write;
 use keos::spin_lock::SpinLock;
@@ -46,6 +46,25 @@ impl PioHandler for PioHandlerPrint {
         let _ = write!(&mut crate::PrinterProxy, "{}", b);
         Ok(VmexitResult::Ok)
     }
+
+    fn handle_string(
+        &self,
+        _port: u16,
+        size: usize,
+        io: StringIo,
+        _p: &dyn Probe,
+        _generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Option<Result<VmexitResult, VmError>> {
+        match io {
+            StringIo::Out(bytes) if size == 1 => {
+                for b in bytes {
+                    let _ = write!(&mut crate::PrinterProxy, "{}", *b as char);
+                }
+                Some(Ok(VmexitResult::Ok))
+            }
+            _ => None,
+        }
+    }
 }
 
 /// emulation of device that tests all In/Out/Ins/Outs instruction family with three queues.
@@ -133,4 +152,55 @@ impl PioHandler for PioHandlerQueue {
         }
         Ok(VmexitResult::Ok)
     }
+
+    fn handle_string(
+        &self,
+        _port: u16,
+        size: usize,
+        io: StringIo,
+        _p: &dyn Probe,
+        _generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Option<Result<VmexitResult, VmError>> {
+        match (io, size) {
+            (StringIo::Out(bytes), 1) => self.byte_queue.lock().extend(bytes),
+            (StringIo::Out(bytes), 2) => self.word_queue.lock().extend(
+                bytes
+                    .chunks_exact(2)
+                    .map(|e| u16::from_le_bytes([e[0], e[1]])),
+            ),
+            (StringIo::Out(bytes), _) => self.dword_queue.lock().extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]])),
+            ),
+            (StringIo::In(bytes), 1) => {
+                let mut queue = self.byte_queue.lock();
+                if queue.len() < bytes.len() {
+                    return Some(Err(VmError::ControllerError(Box::new("Empty byte queue"))));
+                }
+                bytes
+                    .iter_mut()
+                    .for_each(|b| *b = queue.pop_front().unwrap());
+            }
+            (StringIo::In(bytes), 2) => {
+                let mut queue = self.word_queue.lock();
+                if queue.len() < bytes.len() / 2 {
+                    return Some(Err(VmError::ControllerError(Box::new("Empty word queue"))));
+                }
+                bytes
+                    .chunks_exact_mut(2)
+                    .for_each(|e| e.copy_from_slice(&queue.pop_front().unwrap().to_le_bytes()));
+            }
+            (StringIo::In(bytes), _) => {
+                let mut queue = self.dword_queue.lock();
+                if queue.len() < bytes.len() / 4 {
+                    return Some(Err(VmError::ControllerError(Box::new("Empty dword queue"))));
+                }
+                bytes
+                    .chunks_exact_mut(4)
+                    .for_each(|e| e.copy_from_slice(&queue.pop_front().unwrap().to_le_bytes()));
+            }
+        }
+        Some(Ok(VmexitResult::Ok))
+    }
 }
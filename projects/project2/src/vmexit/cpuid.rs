@@ -48,6 +48,7 @@ impl kev::vmexits::VmexitController for Controller {
                 //    - Use `core::arch::x86_64::__cpuid` to execute `cpuid`.
                 //    - You should advance rip when an instruction is emulated.
                 //    - You must carefully handle the cpuid leaf 1. Because it holds the cpu id, you must change the value to the virtual cpu id.
+                //    - Leaves of the hypervisor are served by `kev::cpuid::hypervisor_leaf`.
                 todo!()
             }
             _ => Err(kev::VmError::HandleVmexitFailed(reason)),
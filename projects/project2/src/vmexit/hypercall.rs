@@ -1,12 +1,17 @@
 //! Hypercall vmexit controller.
 use alloc::boxed::Box;
 use kev::{
+    event,
     vcpu::{GenericVCpuState, VmexitResult},
     vmcs::{BasicExitReason, ExitReason},
     Probe, VmError,
 };
 
 /// Hypercall vmexit controller.
+///
+/// A hypercall is accepted only if the current privilege level of the guest
+/// satisfies [`Hypercall::privilege`]. Otherwise, #GP is injected into the
+/// guest.
 pub struct Controller<H: HypercallAbi> {
     inner: H,
 }
@@ -27,11 +32,19 @@ impl<H: HypercallAbi> kev::vmexits::VmexitController for Controller<H> {
     ) -> Result<VmexitResult, VmError> {
         match reason.get_basic_reason() {
             BasicExitReason::Vmcall => {
-                let hc = H::Call::resolve(generic_vcpu_state)
-                    .ok_or(VmError::ControllerError(Box::new("Unknown hypercall")))?;
-                self.inner
-                    .handle(hc, p, generic_vcpu_state)
-                    .and_then(|r| generic_vcpu_state.vmcs.forward_rip().map(|_| r))
+                let cpl = generic_vcpu_state.vmcs.guest_cpl()?;
+                match H::Call::resolve(generic_vcpu_state) {
+                    Some(hc) if cpl <= hc.privilege() as u8 => self
+                        .inner
+                        .handle(hc, p, generic_vcpu_state)
+                        .and_then(|r| generic_vcpu_state.vmcs.forward_rip().map(|_| r)),
+                    // Less privileged guest code can not issue the hypercall.
+                    // Raise #GP(0) on the vmcall like a privileged instruction.
+                    _ if cpl != 0 => {
+                        event::inject_exception(generic_vcpu_state, event::GP, Some(0))
+                    }
+                    _ => Err(VmError::ControllerError(Box::new("Unknown hypercall"))),
+                }
             }
             _ => Err(kev::VmError::HandleVmexitFailed(reason)),
         }
@@ -55,10 +68,26 @@ where
     ) -> Result<VmexitResult, kev::VmError>;
 }
 
+/// Least privilege level of the guest that can issue a hypercall.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    /// Only the guest kernel (CPL 0) can issue the hypercall.
+    Kernel = 0,
+    /// The guest user (CPL 3) can also issue the hypercall.
+    User = 3,
+}
+
 /// Trait that represent the enumeration of supported hypercall.
 pub trait Hypercall {
     /// Resolve the requested hypercall.
     fn resolve(generic_vcpu_state: &mut GenericVCpuState) -> Option<Self>
     where
         Self: Sized;
+
+    /// Least privilege level required to issue this hypercall.
+    ///
+    /// By default, only the guest kernel can issue the hypercall.
+    fn privilege(&self) -> Privilege {
+        Privilege::Kernel
+    }
 }
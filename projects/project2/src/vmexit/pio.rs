@@ -20,19 +20,28 @@
 //! The controller will then use this information to forward the request to the appropriate handler.
 //! When handling Outsw_DX_m8, Outsw_DX_m16 and Outsd_DX_m32, it is necessary to copy the memory contents by translating guest virtual address to host virtual address.
 //! You can translate the guest address to the host address by [`Probe`].
+//!
+//! The `rep ins` and `rep outs` instructions are emulated by the controller at once.
+//! The elements are handed to [`PioHandler::handle_string`] in the batches of a buffer,
+//! so that a device that supports it is called once per batch instead of once per element.
 use alloc::{
     boxed::Box,
     collections::btree_map::{BTreeMap, Entry},
-    format,
+    format, vec,
 };
-use iced_x86::{Code, Instruction};
+use iced_x86::{Code, Instruction, Register};
+use keos::addressing::{PAGE_MASK, PAGE_SIZE};
 use kev::{
+    page_walk::Access,
     vcpu::{GenericVCpuState, Rflags, VmexitResult},
     vm::Gva,
-    vmcs::{BasicExitReason, ExitReason, Field},
+    vmcs::{ActiveVmcs, BasicExitReason, ExitReason, Field},
     Probe, VmError,
 };
 
+// Maximum number of bytes in a batch of the rep string instruction.
+const STRING_BATCH: usize = PAGE_SIZE;
+
 /// Trait that represent handlers for port-mapped devices.
 pub trait PioHandler
 where
@@ -46,6 +55,21 @@ where
         p: &dyn Probe,
         generic_vcpu_state: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError>;
+
+    /// handle a batch of the `rep ins` or `rep outs` instruction on the device indicated by the port.
+    ///
+    /// The buffer holds the elements of `size` bytes in the order of the execution.
+    /// Returns `None` to handle each element with [`PioHandler::handle`], which is the default.
+    fn handle_string(
+        &self,
+        _port: u16,
+        _size: usize,
+        _io: StringIo,
+        _p: &dyn Probe,
+        _generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Option<Result<VmexitResult, VmError>> {
+        None
+    }
 }
 
 /// Pio vmexit controller.
@@ -127,6 +151,15 @@ pub enum Direction {
     Outd(u32),
 }
 
+#[derive(Debug)]
+/// A batch of the elements of the rep string io instruction.
+pub enum StringIo<'a> {
+    /// Input the elements from the port into the buffer.
+    In(&'a mut [u8]),
+    /// Output the elements in the buffer to the port.
+    Out(&'a [u8]),
+}
+
 #[derive(Debug)]
 /// The decoded information of io instruction.
 pub struct IoInstruction {
@@ -255,13 +288,215 @@ impl Controller {
         result
     }
 
+    // Copy between the guest memory at `gva` and the `buf`.
+    fn copy_guest<P: Probe>(
+        p: &P,
+        vmcs: &ActiveVmcs,
+        gva: usize,
+        buf: &mut [u8],
+        to_guest: bool,
+    ) -> Result<(), VmError> {
+        let access = Access::at_cpl(
+            vmcs,
+            if to_guest {
+                Access::WRITE
+            } else {
+                Access::empty()
+            },
+        )?;
+        let mut done = 0;
+        while done < buf.len() {
+            let va = gva.wrapping_add(done);
+            let len = (buf.len() - done).min(PAGE_SIZE - (va & PAGE_MASK));
+            let gva = Gva::new(va).ok_or_else(|| {
+                VmError::ControllerError(Box::new(format!("Invalid memory access at 0x{va:x}")))
+            })?;
+            let hva = p.translate(vmcs, gva, access)?.into_va();
+            let host = unsafe { core::slice::from_raw_parts_mut(hva.into_usize() as *mut u8, len) };
+            if to_guest {
+                host.copy_from_slice(&buf[done..done + len]);
+            } else {
+                buf[done..done + len].copy_from_slice(host);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Emulate the `rep ins` and `rep outs` in the batches.
+    fn handle_rep_string<P: Probe>(
+        &self,
+        insn: Instruction,
+        p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        let (size, is_in) = match insn.code() {
+            Code::Insb_m8_DX => (1, true),
+            Code::Insw_m16_DX => (2, true),
+            Code::Insd_m32_DX => (4, true),
+            Code::Outsb_DX_m8 => (1, false),
+            Code::Outsw_DX_m16 => (2, false),
+            Code::Outsd_DX_m32 => (4, false),
+            _ => unreachable!(),
+        };
+        let port = generic_vcpu_state.gprs.rdx as u16;
+        let handler = self.pios.get(&port).ok_or_else(|| {
+            VmError::ControllerError(Box::new(format!("Unknown io port: 0x{port:x}")))
+        })?;
+        // The address size decides the width of the rcx, rsi and rdi.
+        let mask = match insn.memory_base() {
+            Register::RSI | Register::RDI => usize::MAX,
+            Register::ESI | Register::EDI => u32::MAX as usize,
+            _ => u16::MAX as usize,
+        };
+        // Writing a 32-bit register clears the upper half, but writing a 16-bit one does not.
+        let merge = |old: usize, new: usize| {
+            if mask == u16::MAX as usize {
+                (old & !mask) | (new & mask)
+            } else {
+                new & mask
+            }
+        };
+        // In the 64-bit mode, only the fs and the gs have the base.
+        // The ins always uses the es, which can not be overridden.
+        let base = match insn.memory_segment() {
+            Register::FS if !is_in => generic_vcpu_state.vmcs.read(Field::GuestFsBase)?,
+            Register::GS if !is_in => generic_vcpu_state.vmcs.read(Field::GuestGsBase)?,
+            _ => 0,
+        } as usize;
+        let df = Rflags::from_bits_truncate(generic_vcpu_state.vmcs.read(Field::GuestRflags)?)
+            .contains(Rflags::DF);
+
+        let mut buf = vec![0; STRING_BATCH];
+        while generic_vcpu_state.gprs.rcx & mask != 0 {
+            let rcx = generic_vcpu_state.gprs.rcx & mask;
+            let index = if is_in {
+                generic_vcpu_state.gprs.rdi
+            } else {
+                generic_vcpu_state.gprs.rsi
+            } & mask;
+            let mut count = rcx.min(STRING_BATCH / size);
+            // The lowest address of the batch.
+            let mut low = if df {
+                index.wrapping_sub((count - 1) * size) & mask
+            } else {
+                index
+            };
+            // Do not batch the elements that wrap around the address size.
+            if low
+                .checked_add(count * size - 1)
+                .map_or(true, |end| end > mask)
+            {
+                count = 1;
+                low = index;
+            }
+            let elements = &mut buf[..count * size];
+            // Put the elements in the order of the execution.
+            let reorder = |elements: &mut [u8]| {
+                if df {
+                    elements.reverse();
+                    elements.chunks_mut(size).for_each(|e| e.reverse());
+                }
+            };
+
+            if !is_in {
+                Self::copy_guest(
+                    p,
+                    &generic_vcpu_state.vmcs,
+                    base.wrapping_add(low),
+                    elements,
+                    false,
+                )?;
+                reorder(elements);
+            }
+            let io = if is_in {
+                StringIo::In(&mut *elements)
+            } else {
+                StringIo::Out(&*elements)
+            };
+            let (result, done) = match handler.handle_string(port, size, io, p, generic_vcpu_state)
+            {
+                Some(Ok(VmexitResult::Ok)) if is_in => {
+                    reorder(elements);
+                    Self::copy_guest(
+                        p,
+                        &generic_vcpu_state.vmcs,
+                        base.wrapping_add(low),
+                        elements,
+                        true,
+                    )?;
+                    (Ok(VmexitResult::Ok), count)
+                }
+                Some(result) => (result, count),
+                // Handle each element.
+                None => {
+                    let mut result = Ok(VmexitResult::Ok);
+                    let mut done = 0;
+                    while done < count && matches!(result, Ok(VmexitResult::Ok)) {
+                        let ofs = if df {
+                            index.wrapping_sub(done * size)
+                        } else {
+                            index.wrapping_add(done * size)
+                        };
+                        let gva = Gva::new(base.wrapping_add(ofs & mask)).ok_or_else(|| {
+                            VmError::ControllerError(Box::new("Invalid guest address."))
+                        })?;
+                        let e = &elements[done * size..(done + 1) * size];
+                        let direction = match (size, is_in) {
+                            (1, true) => Direction::Inbm(gva),
+                            (2, true) => Direction::Inwm(gva),
+                            (_, true) => Direction::Indm(gva),
+                            (1, false) => Direction::Outb(e[0]),
+                            (2, false) => Direction::Outw(u16::from_le_bytes([e[0], e[1]])),
+                            (_, false) => {
+                                Direction::Outd(u32::from_le_bytes([e[0], e[1], e[2], e[3]]))
+                            }
+                        };
+                        result = handler.handle(port, direction, p, generic_vcpu_state);
+                        done += 1;
+                    }
+                    (result, done)
+                }
+            };
+
+            let gprs = &mut generic_vcpu_state.gprs;
+            let index = if df {
+                index.wrapping_sub(done * size)
+            } else {
+                index.wrapping_add(done * size)
+            };
+            if is_in {
+                gprs.rdi = merge(gprs.rdi, index);
+            } else {
+                gprs.rsi = merge(gprs.rsi, index);
+            }
+            gprs.rcx = merge(gprs.rcx, rcx - done);
+            match result {
+                Ok(VmexitResult::Ok) => (),
+                r => return r,
+            }
+        }
+        Ok(VmexitResult::Ok)
+    }
+
     fn handle_ioinsn<P: Probe>(
         &self,
         insn: Instruction,
         p: &mut P,
         generic_vcpu_state: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError> {
-        if insn.has_rep_prefix() || insn.has_repne_prefix() {
+        let is_string = matches!(
+            insn.code(),
+            Code::Insb_m8_DX
+                | Code::Insw_m16_DX
+                | Code::Insd_m32_DX
+                | Code::Outsb_DX_m8
+                | Code::Outsw_DX_m16
+                | Code::Outsd_DX_m32
+        );
+        if is_string && (insn.has_rep_prefix() || insn.has_repne_prefix()) {
+            self.handle_rep_string(insn, p, generic_vcpu_state)
+        } else if insn.has_rep_prefix() || insn.has_repne_prefix() {
             while generic_vcpu_state.gprs.rcx != 0 {
                 let result = self.handle_ioinsn_one(insn, p, generic_vcpu_state);
                 generic_vcpu_state.gprs.rcx -= 1;
@@ -24,6 +24,7 @@ use alloc::boxed::Box;
 use core::ops::{Deref, DerefMut};
 use keos::{
     addressing::{Pa, Va, PAGE_MASK, PAGE_SHIFT},
+    fault::FaultInjector,
     mm::Page,
 };
 use kev::{
@@ -40,6 +41,8 @@ pub enum EptMappingError {
     NotExist,
     /// Has a duplicated mapping.
     Duplicated,
+    /// Failed to allocate the memory.
+    OutOfMemory,
 }
 
 #[derive(Clone, Copy)]
@@ -436,11 +439,22 @@ impl DerefMut for Inner {
 }
 
 /// Second level page table that holds guest-physical to host-physical mapping.
-pub struct ExtendedPageTable(Box<Inner>);
+pub struct ExtendedPageTable(Box<Inner>, FaultInjector);
 
 impl ExtendedPageTable {
     pub fn new() -> Self {
-        Self(unsafe { Box::new_zeroed().assume_init() })
+        Self(
+            unsafe { Box::new_zeroed().assume_init() },
+            FaultInjector::new(),
+        )
+    }
+
+    /// Fail the mappings of this table with the `faults`.
+    ///
+    /// A failed mapping returns [`EptMappingError::OutOfMemory`] without changing the table, so the error path
+    /// of populating the guest memory can be tested.
+    pub fn set_faults(&mut self, faults: FaultInjector) {
+        self.1 = faults;
     }
 
     pub fn pa(&self) -> Pa {
@@ -449,8 +463,16 @@ impl ExtendedPageTable {
             .into_pa()
     }
 
+    /// Get the EPT pointer of this table.
+    pub fn eptp(&self) -> u64 {
+        kev::vmfunc::eptp(self.pa())
+    }
+
     /// Map `pg` into `va` with permission `perm`.
     pub fn map(&mut self, gpa: Gpa, pg: Page, perm: Permission) -> Result<(), EptMappingError> {
+        if self.1.should_fail() {
+            return Err(EptMappingError::OutOfMemory);
+        }
         unsafe { self.do_map(gpa, pg.into_raw(), perm) }
     }
 
@@ -468,9 +490,29 @@ impl ExtendedPageTable {
     /// Unmap the `gpa` and returns `Page` that was mapped to `gpa`.
     pub fn unmap(&mut self, gpa: Gpa) -> Result<Page, EptMappingError> {
         // Hint: Use `Page::from_pa()`.
+        //   - Use `ExtendedPageTable::invalidate()` to drop the stale mapping on every core.
         todo!()
     }
 
+    /// Map the page that is mapped to `gpa` on `view` into `gpa` with permission `perm`.
+    ///
+    /// This populates an alternate view of the guest physical memory that shares the pages with `view`, e.g. for
+    /// the EPT views switched with [`kev::vmfunc`]. The shared page is owned by `view`.
+    pub fn share(
+        &mut self,
+        view: &ExtendedPageTable,
+        gpa: Gpa,
+        perm: Permission,
+    ) -> Result<(), EptMappingError> {
+        let hpa = view.walk(gpa)?.pa().ok_or(EptMappingError::NotExist)?;
+        unsafe { self.do_map(gpa, hpa, perm) }
+    }
+
+    /// Invalidate the cached mappings derived from this table on every core.
+    pub fn invalidate(&self) {
+        kev::tlb::invalidate_ept(self.pa());
+    }
+
     /// Walk the extended page table and return corresponding eptpte of the `gpa` if exist.
     pub fn walk(&self, gpa: Gpa) -> Result<&EptPte, EptMappingError> {
         todo!()
@@ -1,3 +1,6 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+use keos::rtc;
 use kev::{
     vcpu::{GenericVCpuState, VmexitResult},
     vmcs::Field,
@@ -67,20 +70,98 @@ impl PioHandler for PciPio {
     }
 }
 
-pub struct CmosPio;
+/// Emulated CMOS real-time clock.
+///
+/// Port 0x70 selects the register, and port 0x71 reads the register. The clock
+/// reports the wall-clock time of the host in BCD and 24-hour mode. Writes to
+/// the registers are ignored.
+#[derive(Clone, Default)]
+pub struct CmosPio {
+    index: Arc<AtomicU8>,
+}
+
+impl CmosPio {
+    fn read(&self) -> u8 {
+        fn bcd(v: u8) -> u8 {
+            ((v / 10) << 4) | (v % 10)
+        }
+        let now = rtc::DateTime::from_unix(rtc::unix_time());
+        match self.index.load(Ordering::Relaxed) {
+            rtc::reg::SECONDS => bcd(now.second),
+            rtc::reg::MINUTES => bcd(now.minute),
+            rtc::reg::HOURS => bcd(now.hour),
+            rtc::reg::WEEKDAY => bcd(now.weekday()),
+            rtc::reg::DAY => bcd(now.day),
+            rtc::reg::MONTH => bcd(now.month),
+            rtc::reg::YEAR => bcd((now.year % 100) as u8),
+            rtc::reg::CENTURY => bcd((now.year / 100) as u8),
+            // No update in progress.
+            rtc::reg::STATUS_A => 0x26,
+            // 24-hour mode, BCD.
+            rtc::reg::STATUS_B => 0x02,
+            // Valid RAM and time.
+            rtc::reg::STATUS_D => 0x80,
+            _ => 0,
+        }
+    }
+}
+
 impl PioHandler for CmosPio {
     fn handle(
         &self,
-        _port: u16,
-        _direction: Direction,
-        _p: &dyn Probe,
-        _generic_vcpu_state: &mut GenericVCpuState,
+        port: u16,
+        direction: Direction,
+        p: &dyn Probe,
+        GenericVCpuState { vmcs, gprs, .. }: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError> {
-        // ignore.
+        match (port, direction) {
+            // Bit 7 disables the NMI.
+            (0x70, Direction::Outb(v)) => self.index.store(v & 0x7f, Ordering::Relaxed),
+            (0x71, Direction::InbAl) => gprs.rax = (gprs.rax & !0xff) | self.read() as usize,
+            (0x71, Direction::Inbm(gva)) => unsafe {
+                *p.gva2hva(vmcs, gva).unwrap().as_mut::<u8>().unwrap() = self.read();
+            },
+            // ignore.
+            _ => (),
+        }
         Ok(VmexitResult::Ok)
     }
 }
 
+/// Emulated serial port.
+///
+/// The characters transmitted through the port 0x3f8 are written into the
+/// [`console`](kev::console) of the vm. The line status register at 0x3fd
+/// always reports that the transmitter is empty and no data is received.
+/// The other registers are ignored.
+pub struct SerialPio;
+impl PioHandler for SerialPio {
+    fn handle(
+        &self,
+        port: u16,
+        direction: Direction,
+        p: &dyn Probe,
+        GenericVCpuState { vmcs, gprs, vm, .. }: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        // Transmitter empty, and transmitter holding register empty.
+        const LSR: u8 = 0x60;
+        match (port, direction) {
+            (0x3f8, Direction::Outb(v)) => {
+                if let Some(vm) = vm.upgrade() {
+                    vm.console().write(&[v]);
+                }
+            }
+            (0x3fd, Direction::InbAl) => gprs.rax = (gprs.rax & !0xff) | LSR as usize,
+            (0x3fd, Direction::Inbm(gva)) => unsafe {
+                *p.gva2hva(vmcs, gva).unwrap().as_mut::<u8>().unwrap() = LSR;
+            },
+            (_, Direction::InbAl) => gprs.rax &= !0xff,
+            // ignore.
+            _ => (),
+        }
+        Ok(VmexitResult::Ok)
+    }
+}
 
 pub struct ExitPio;
 impl PioHandler for ExitPio {
@@ -92,11 +173,16 @@ impl PioHandler for ExitPio {
         generic_vcpu_state: &mut GenericVCpuState,
     ) -> Result<VmexitResult, VmError> {
         // Pio::new(0x604).write_u32(0 | 0x2000);
-        if let Direction::Outd(v) = direction {
-            if v == 0 | 0x2000 {
-                generic_vcpu_state.vm.upgrade().unwrap().exit(0);
-                return Ok(VmexitResult::Exited(0));
-            }
+        // The ACPI PM1a control register is 16 bits wide, but keos writes it
+        // with a 32-bit write.
+        let value = match direction {
+            Direction::Outw(v) => v as u32,
+            Direction::Outd(v) => v,
+            _ => 0,
+        };
+        if kev::shutdown::is_sleep(value) {
+            generic_vcpu_state.vm.upgrade().unwrap().exit(0);
+            return Ok(VmexitResult::Exited(0));
         }
         Ok(VmexitResult::Ok)
     }
@@ -1,13 +1,16 @@
 mod header;
 mod phdr;
 mod shdr;
+mod sym;
 
+use alloc::{vec, vec::Vec};
 use core::convert::TryInto;
 use header::UHeader;
 
 pub use header::{Bit, EMachine, EType, Endian};
 pub use phdr::{PType, Phdr, PhdrIterator};
 pub use shdr::{SFlags, SType, Shdr, ShdrIterator};
+pub use sym::{StrTab, SymIterator, SymType, Symbol};
 
 /// Byte peekable object.
 pub trait Peeker {
@@ -174,4 +177,74 @@ where
             elf: self,
         }
     }
+
+    /// Get the `idx`-th section header.
+    pub fn shdr(&self, idx: usize) -> Option<Shdr> {
+        self.shdrs().nth(idx)?.ok()
+    }
+
+    /// Read the contents of the section `shdr`.
+    ///
+    /// The section without the data in the file, e.g. `.bss`, reads as zero.
+    pub fn read_section(&self, shdr: &Shdr) -> Result<Vec<u8>, Option<T::Error>> {
+        let mut buf = vec![0; shdr.size()];
+        if shdr.type_() != SType::Nobits {
+            self.peeker
+                .peek_bytes(shdr.offset(), &mut buf)
+                .map_err(Some)?;
+        }
+        Ok(buf)
+    }
+
+    /// Read the string table of the section `shdr`.
+    pub fn strtab(&self, shdr: &Shdr) -> Result<StrTab, Option<T::Error>> {
+        if shdr.type_() != SType::Strtab {
+            return Err(None);
+        }
+        Ok(StrTab {
+            inner: self.read_section(shdr)?,
+        })
+    }
+
+    /// Read the section header string table, which holds the section names.
+    pub fn shstrtab(&self) -> Result<StrTab, Option<T::Error>> {
+        self.strtab(&self.shdr(self.shstrndx()).ok_or(None)?)
+    }
+
+    /// Find the section header by the `name`, e.g. `.symtab`.
+    pub fn section_by_name(&self, name: &str) -> Option<Shdr> {
+        let shstrtab = self.shstrtab().ok()?;
+        self.shdrs()
+            .filter_map(Result::ok)
+            .find(|shdr| shstrtab.get(shdr.name() as usize) == Some(name))
+    }
+
+    /// Get iterator that iterates over the symbols in the section `symtab`.
+    ///
+    /// The names of the symbols are read from the string table linked to the
+    /// `symtab`.
+    pub fn symbols_of(&self, symtab: &Shdr) -> Result<SymIterator<T>, Option<T::Error>> {
+        if !matches!(symtab.type_(), SType::Symtab | SType::Dynsym) {
+            return Err(None);
+        }
+        let strtab = self.strtab(&self.shdr(symtab.link() as usize).ok_or(None)?)?;
+        Ok(SymIterator::new(self, symtab, strtab))
+    }
+
+    /// Get iterator that iterates over the symbols in this binary.
+    ///
+    /// The static symbol table is preferred to the dynamic one. Returns
+    /// `None` if the binary is stripped.
+    pub fn symbols(&self) -> Option<SymIterator<T>> {
+        let symtab = self
+            .shdrs()
+            .filter_map(Result::ok)
+            .find(|shdr| shdr.type_() == SType::Symtab)
+            .or_else(|| {
+                self.shdrs()
+                    .filter_map(Result::ok)
+                    .find(|shdr| shdr.type_() == SType::Dynsym)
+            })?;
+        self.symbols_of(&symtab).ok()
+    }
 }
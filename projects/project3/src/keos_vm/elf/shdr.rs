@@ -57,6 +57,35 @@ pub enum SType {
     Loos = 0x60000000,
 }
 
+impl SType {
+    /// Get the type of the raw `sh_type`.
+    ///
+    /// The types not listed above, e.g. the OS-specific ones, are [`SType::Loos`].
+    fn from_raw(v: u32) -> SType {
+        match v {
+            0x0 => SType::Null,
+            0x1 => SType::ProgBits,
+            0x2 => SType::Symtab,
+            0x3 => SType::Strtab,
+            0x4 => SType::Rela,
+            0x5 => SType::Hash,
+            0x6 => SType::Dynamic,
+            0x7 => SType::Note,
+            0x8 => SType::Nobits,
+            0x9 => SType::Rel,
+            0xa => SType::Shlib,
+            0xb => SType::Dynsym,
+            0xc => SType::InitArray,
+            0xf => SType::FiniArray,
+            0x10 => SType::PreinitArray,
+            0x11 => SType::Group,
+            0x12 => SType::SymtabShndx,
+            0x13 => SType::Num,
+            _ => SType::Loos,
+        }
+    }
+}
+
 bitflags! {
     #[allow(dead_code)]
     pub struct SFlags: u32 {
@@ -99,7 +128,7 @@ pub struct Shdr32 {
     /// of this section.
     name: u32,
     /// Identifies the type of this header.
-    sh_type: SType,
+    sh_type: u32,
     /// Identifies the attributes of the section.
     sh_flags: SFlags,
     /// Virtual address of the section in memory, for sections that are loaded.
@@ -130,7 +159,7 @@ pub struct Shdr64 {
     /// of this section.
     name: u32,
     /// Identifies the type of this header.
-    sh_type: SType,
+    sh_type: u32,
     /// Identifies the attributes of the section.
     sh_flags: SFlags,
     /// Virtual address of the section in memory, for sections that are loaded.
@@ -154,15 +183,15 @@ pub struct Shdr64 {
 }
 
 /// Section Header.
+#[derive(Clone, Copy, Debug)]
 pub enum Shdr {
     Shdr32(Shdr32),
     Shdr64(Shdr64),
 }
 
-#[allow(dead_code)]
 impl Shdr {
     #[inline]
-    fn name(&self) -> u32 {
+    pub fn name(&self) -> u32 {
         match self {
             Shdr::Shdr32(s) => s.name,
             Shdr::Shdr64(s) => s.name,
@@ -170,15 +199,15 @@ impl Shdr {
     }
 
     #[inline]
-    fn type_(&self) -> SType {
+    pub fn type_(&self) -> SType {
         match self {
-            Shdr::Shdr32(s) => s.sh_type,
-            Shdr::Shdr64(s) => s.sh_type,
+            Shdr::Shdr32(s) => SType::from_raw(s.sh_type),
+            Shdr::Shdr64(s) => SType::from_raw(s.sh_type),
         }
     }
 
     #[inline]
-    fn flags(&self) -> SFlags {
+    pub fn flags(&self) -> SFlags {
         match self {
             Shdr::Shdr32(s) => s.sh_flags,
             Shdr::Shdr64(s) => s.sh_flags,
@@ -186,7 +215,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn addr(&self) -> usize {
+    pub fn addr(&self) -> usize {
         match self {
             Shdr::Shdr32(s) => s.sh_addr as usize,
             Shdr::Shdr64(s) => s.sh_addr.try_into().unwrap(),
@@ -194,7 +223,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn offset(&self) -> usize {
+    pub fn offset(&self) -> usize {
         match self {
             Shdr::Shdr32(s) => s.sh_offset as usize,
             Shdr::Shdr64(s) => s.sh_offset.try_into().unwrap(),
@@ -202,7 +231,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn size(&self) -> usize {
+    pub fn size(&self) -> usize {
         match self {
             Shdr::Shdr32(s) => s.sh_size as usize,
             Shdr::Shdr64(s) => s.sh_size.try_into().unwrap(),
@@ -210,7 +239,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn link(&self) -> u32 {
+    pub fn link(&self) -> u32 {
         match self {
             Shdr::Shdr32(s) => s.sh_link,
             Shdr::Shdr64(s) => s.sh_link,
@@ -218,7 +247,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn info(&self) -> u32 {
+    pub fn info(&self) -> u32 {
         match self {
             Shdr::Shdr32(s) => s.sh_info,
             Shdr::Shdr64(s) => s.sh_info,
@@ -226,7 +255,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn addralign(&self) -> usize {
+    pub fn addralign(&self) -> usize {
         match self {
             Shdr::Shdr32(s) => s.sh_addralign as usize,
             Shdr::Shdr64(s) => s.sh_addralign.try_into().unwrap(),
@@ -234,7 +263,7 @@ impl Shdr {
     }
 
     #[inline]
-    fn ent_size(&self) -> usize {
+    pub fn ent_size(&self) -> usize {
         match self {
             Shdr::Shdr32(s) => s.sh_ent_size as usize,
             Shdr::Shdr64(s) => s.sh_ent_size.try_into().unwrap(),
@@ -244,7 +273,7 @@ impl Shdr {
 
 union Reader32 {
     shdr: Shdr32,
-    _raw: [u8; 0x20],
+    _raw: [u8; 0x28],
 }
 
 union Reader64 {
@@ -266,9 +295,9 @@ where
             unsafe {
                 let shdr = match bit {
                     Bit::Bit32 => {
-                        let mut inner = Reader32 { _raw: [0; 0x20] };
+                        let mut inner = Reader32 { _raw: [0; 0x28] };
                         peeker
-                            .peek_bytes(self.base + self.cursor as usize * 0x20, &mut inner._raw)
+                            .peek_bytes(self.base + self.cursor as usize * 0x28, &mut inner._raw)
                             .map(|_| Shdr::Shdr32(inner.shdr))
                             .map_err(|_| ())
                     }
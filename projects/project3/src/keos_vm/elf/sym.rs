@@ -0,0 +1,179 @@
+use super::{Bit, Shdr, ELF};
+use alloc::{string::String, vec::Vec};
+use core::convert::TryInto;
+
+/// String table, such as `.strtab` or `.shstrtab`.
+pub struct StrTab {
+    pub(super) inner: Vec<u8>,
+}
+
+impl StrTab {
+    /// Get the null-terminated string at the offset `ofs`.
+    pub fn get(&self, ofs: usize) -> Option<&str> {
+        let bytes = self.inner.get(ofs..)?;
+        let len = bytes.iter().position(|b| *b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// Type of a symbol.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymType {
+    /// Unspecified.
+    NoType,
+    /// Data object.
+    Object,
+    /// Code object.
+    Func,
+    /// Associated with a section.
+    Section,
+    /// Name of the source file.
+    File,
+    /// Other types, e.g. the OS-specific ones.
+    Other(u8),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+/// Symbol table entry for 32bit ELF.
+pub struct Sym32 {
+    st_name: u32,
+    st_value: u32,
+    st_size: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+/// Symbol table entry for 64bit ELF.
+pub struct Sym64 {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+/// Symbol of a symbol table, with its name.
+#[derive(Debug)]
+pub struct Symbol {
+    /// Name of the symbol.
+    pub name: String,
+    /// Value of the symbol, e.g. the address of a function.
+    pub value: usize,
+    /// Size of the object that the symbol represents.
+    pub size: usize,
+    /// Type of the symbol.
+    pub type_: SymType,
+    /// Index of the section that the symbol is defined in.
+    pub shndx: u16,
+}
+
+union Reader32 {
+    sym: Sym32,
+    _raw: [u8; 0x10],
+}
+
+union Reader64 {
+    sym: Sym64,
+    _raw: [u8; 0x18],
+}
+
+/// Symbol iterator created by [`ELF::symbols`] method.
+pub struct SymIterator<'a, T>
+where
+    T: super::Peeker,
+{
+    pub(super) base: usize,
+    pub(super) size: usize,
+    pub(super) cursor: usize,
+    pub(super) strtab: StrTab,
+    pub(super) elf: &'a ELF<T>,
+}
+
+impl<'a, T> SymIterator<'a, T>
+where
+    T: super::Peeker,
+{
+    pub(super) fn new(elf: &'a ELF<T>, symtab: &Shdr, strtab: StrTab) -> Self {
+        let entsize = match elf.bit() {
+            Bit::Bit32 => 0x10,
+            Bit::Bit64 => 0x18,
+        };
+        Self {
+            base: symtab.offset(),
+            size: symtab.size() / entsize,
+            cursor: 0,
+            strtab,
+            elf,
+        }
+    }
+}
+
+impl<'a, T> core::iter::Iterator for SymIterator<'a, T>
+where
+    T: super::Peeker,
+{
+    type Item = Result<Symbol, ()>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let bit = self.elf.bit();
+
+        let ELF { peeker, .. } = self.elf;
+
+        if self.size > self.cursor {
+            let raw = unsafe {
+                match bit {
+                    Bit::Bit32 => {
+                        let mut inner = Reader32 { _raw: [0; 0x10] };
+                        peeker
+                            .peek_bytes(self.base + self.cursor * 0x10, &mut inner._raw)
+                            .map(|_| {
+                                let s = inner.sym;
+                                (
+                                    s.st_name,
+                                    s.st_value as u64,
+                                    s.st_size as u64,
+                                    s.st_info,
+                                    s.st_shndx,
+                                )
+                            })
+                    }
+                    Bit::Bit64 => {
+                        let mut inner = Reader64 { _raw: [0; 0x18] };
+                        peeker
+                            .peek_bytes(self.base + self.cursor * 0x18, &mut inner._raw)
+                            .map(|_| {
+                                let s = inner.sym;
+                                (s.st_name, s.st_value, s.st_size, s.st_info, s.st_shndx)
+                            })
+                    }
+                }
+            };
+            self.cursor += 1;
+            Some(
+                raw.map_err(|_| ())
+                    .and_then(|(name, value, size, info, shndx)| {
+                        Ok(Symbol {
+                            name: self.strtab.get(name as usize).ok_or(())?.into(),
+                            value: value.try_into().map_err(|_| ())?,
+                            size: size.try_into().map_err(|_| ())?,
+                            type_: match info & 0xf {
+                                0 => SymType::NoType,
+                                1 => SymType::Object,
+                                2 => SymType::Func,
+                                3 => SymType::Section,
+                                4 => SymType::File,
+                                t => SymType::Other(t),
+                            },
+                            shndx,
+                        })
+                    }),
+            )
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Guest RAM backed by a file.
+//!
+//! Instead of copying a file, such as the kernel image, into the guest RAM,
+//! the pager maps the pages of a [`FileBackedRegion`] lazily. A page is read
+//! from the file on its first access, and mapped into the guest read-only.
+//! The guests that open the same file share the pages, and a guest gets its
+//! own copy of a page when it writes to the page (copy-on-write):
+//!
+//! ```ignore
+//! let image = FileBackedRegion::open("gKeOS").expect("gKeOS is not exist.");
+//! pager.map_file(Gpa::new(0x10_0000).unwrap(), &image);
+//! ```
+//!
+//! The shared pages live as long as the region, which the pagers of the
+//! guests hold.
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+};
+use keos::{
+    addressing::Pa,
+    fs::{file_system, File},
+    mm::Page,
+    spin_lock::SpinLock,
+};
+
+// The regions opened by the name, shared by the guests.
+static REGIONS: SpinLock<BTreeMap<String, Weak<FileBackedRegion>>> = SpinLock::new(BTreeMap::new());
+
+/// Pages of a file, shared by the guests.
+pub struct FileBackedRegion {
+    file: File,
+    // The pages read from the file, by the page index.
+    pages: SpinLock<BTreeMap<usize, Page>>,
+}
+
+impl FileBackedRegion {
+    /// Open the region of the file `name`.
+    ///
+    /// The region is shared with the guests that opened the same file.
+    pub fn open(name: &str) -> Option<Arc<Self>> {
+        let mut regions = REGIONS.lock();
+        if let Some(region) = regions.get(name).and_then(Weak::upgrade) {
+            return Some(region);
+        }
+        let region = Self::from_file(file_system()?.open(name)?);
+        regions.retain(|_, r| r.strong_count() != 0);
+        regions.insert(name.to_string(), Arc::downgrade(&region));
+        Some(region)
+    }
+
+    /// Create a new region of the `file` that is not shared.
+    pub fn from_file(file: File) -> Arc<Self> {
+        Arc::new(Self {
+            file,
+            pages: SpinLock::new(BTreeMap::new()),
+        })
+    }
+
+    /// Get the name of the file.
+    pub fn name(&self) -> &str {
+        self.file.name()
+    }
+
+    /// Get the number of the pages of the region.
+    pub fn nr_pages(&self) -> usize {
+        (self.file.size() + 0xfff) / 0x1000
+    }
+
+    /// Get the number of the pages read from the file.
+    pub fn cached(&self) -> usize {
+        self.pages.lock().len()
+    }
+
+    /// Get the host physical address of the `idx`-th page, reading the page
+    /// on its first access.
+    ///
+    /// The page must be mapped read-only, as it is shared by the guests.
+    pub fn page(&self, idx: usize) -> Option<Pa> {
+        if idx >= self.nr_pages() {
+            return None;
+        }
+        let mut pages = self.pages.lock();
+        if let Some(page) = pages.get(&idx) {
+            return Some(page.pa());
+        }
+        let mut page = Page::new()?;
+        self.read(idx, &mut page)?;
+        let pa = page.pa();
+        pages.insert(idx, page);
+        Some(pa)
+    }
+
+    /// Copy the `idx`-th page into the `page`.
+    pub fn copy_to(&self, idx: usize, page: &mut Page) -> Option<()> {
+        if idx >= self.nr_pages() {
+            return None;
+        }
+        if let Some(cached) = self.pages.lock().get(&idx) {
+            unsafe {
+                page.inner_mut().copy_from_slice(cached.inner());
+            }
+            return Some(());
+        }
+        self.read(idx, page)
+    }
+
+    // Read the `idx`-th page of the file. The bytes beyond the end of the
+    // file are zero.
+    fn read(&self, idx: usize, page: &mut Page) -> Option<()> {
+        let ofs = idx * 0x1000;
+        let len = (self.file.size() - ofs).min(0x1000);
+        unsafe {
+            self.file.read(ofs, &mut page.inner_mut()[..len]).ok()?;
+        }
+        Some(())
+    }
+}
@@ -4,7 +4,9 @@ use crate::{keos_vm::dev::PciPio, vmexit::mmio};
 use alloc::sync::Arc;
 use keos::{fs::file_system, mm::Page, spin_lock::SpinLock};
 use kev::{
-    vcpu::{Cr0, Cr4, GenericVCpuState, Rflags, VmexitResult},
+    cpuid::PvFeatures,
+    entry_state::GuestEntryState,
+    vcpu::{GenericVCpuState, VmexitResult},
     vm_control::*,
     vmcs::{ActiveVmcs, Field},
     vmexits::VmexitController,
@@ -18,7 +20,9 @@ use project2::{
 
 pub mod dev;
 pub mod elf;
+pub mod file_ram;
 pub mod pager;
+pub mod symbols;
 
 /// The Vmstate of VmBase.
 pub struct VmState {
@@ -86,84 +90,27 @@ impl kev::vm::VmState for VmState {
         }
     }
 
+    fn pv_features(&self) -> PvFeatures {
+        // The kvmclock msr is forwarded to the host.
+        PvFeatures::PVCLOCK
+    }
+
     fn setup_vbsp(
         &self,
         vbsp_generic_state: &mut GenericVCpuState,
         vbsp_vcpu_state: &mut Self::VcpuState,
     ) -> Result<(), Self::Error> {
-        vbsp_generic_state
-            .vmcs
-            .write(Field::GuestRip, self.pager.lock().entry() as u64)?;
-        vbsp_generic_state.vmcs.write(Field::GuestRsp, 0xa0000)?;
+        let entry = self.pager.lock().entry() as u64;
         vbsp_generic_state.gprs.rsi = vbsp_vcpu_state
             .pager
             .lock()
             .finalize_mem()
             .expect("Failed to finalize the memory.");
 
-        let vmcs = &vbsp_generic_state.vmcs;
-        vmcs.write(Field::GuestCsSelector, 0x10)?;
-        vmcs.write(Field::GuestCsBase, 0)?;
-        vmcs.write(Field::GuestCsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestCsAccessRights, 0xc09b)?;
-
-        vmcs.write(Field::GuestEsSelector, 0x18)?;
-        vmcs.write(Field::GuestEsBase, 0)?;
-        vmcs.write(Field::GuestEsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestEsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestSsSelector, 0x18)?;
-        vmcs.write(Field::GuestSsBase, 0)?;
-        vmcs.write(Field::GuestSsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestSsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestDsSelector, 0x18)?;
-        vmcs.write(Field::GuestDsBase, 0)?;
-        vmcs.write(Field::GuestDsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestDsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestFsSelector, 0x18)?;
-        vmcs.write(Field::GuestFsBase, 0)?;
-        vmcs.write(Field::GuestFsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestFsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestGsSelector, 0x18)?;
-        vmcs.write(Field::GuestGsBase, 0)?;
-        vmcs.write(Field::GuestGsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestGsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestTrSelector, 0)?;
-        vmcs.write(Field::GuestTrBase, 0)?;
-        vmcs.write(Field::GuestTrLimit, 0)?;
-        vmcs.write(Field::GuestTrAccessRights, 0x8b)?;
-
-        vmcs.write(Field::GuestLdtrSelector, 0)?;
-        vmcs.write(Field::GuestLdtrBase, 0)?;
-        vmcs.write(Field::GuestLdtrLimit, 0)?;
-        vmcs.write(Field::GuestLdtrAccessRights, 0x82)?;
-
-        vmcs.write(Field::GuestGdtrBase, 0)?;
-        vmcs.write(Field::GuestGdtrLimit, 0)?;
-
-        vmcs.write(Field::GuestIdtrBase, 0)?;
-        vmcs.write(Field::GuestIdtrLimit, 0)?;
-        // CR0=00000011 CR3=00000000 CR4=00000000
-        vmcs.write(Field::GuestCr0, (Cr0::NE | Cr0::PE).bits())?;
-        vmcs.write(Field::GuestCr3, 0)?;
-        vmcs.write(Field::GuestCr4, Cr4::VMXE.bits())?;
-
-        // Guest non-register state.
-        vmcs.write(Field::GuestActivityState, 0)?;
-        vmcs.write(Field::GuestInterruptibilityState, 0)?;
-        vmcs.write(Field::GuestLinkPointer, 0xffff_ffff)?;
-        vmcs.write(Field::GuestLinkPointerHi, 0xffff_ffff)?;
-
-        // DR7=0000000000000400
-        vmcs.write(Field::GuestDr7, 0)?;
-        vmcs.write(Field::GuestIa32Debugctl, 0)?;
-
-        // EFL=00200002
-        vmcs.write(Field::GuestRflags, Rflags::_1.bits())?;
+        GuestEntryState::protected_flat()
+            .rip(entry)
+            .rsp(0xa0000)
+            .write(&vbsp_generic_state.vmcs)?;
 
         Ok(())
     }
@@ -71,24 +71,30 @@
 
 use crate::{
     ept::{EptMappingError, EptPteFlags, ExtendedPageTable, Permission},
-    keos_vm::elf::{PType, Peeker, Phdr, ELF},
+    keos_vm::{
+        elf::{PType, Peeker, Phdr, ELF},
+        file_ram::FileBackedRegion,
+    },
 };
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc};
 use keos::{
     addressing::{Pa, PAGE_MASK},
+    fault::FaultInjector,
     fs::{self, File},
     mm::Page,
     spin_lock::SpinLock,
 };
 use kev::{
+    e820::{E820Type, MemoryMap},
+    memory_map::GuestMemoryMap,
     vcpu::VmexitResult,
     vm::{Gpa, Gva},
-    vmcs::{ActiveVmcs, ExitReason},
+    vmcs::{ActiveVmcs, EptViolationQualification, ExitReason},
     VmError,
 };
 
-struct FilePeeker {
-    file: File,
+pub(crate) struct FilePeeker {
+    pub(crate) file: File,
 }
 
 impl Peeker for FilePeeker {
@@ -104,6 +110,9 @@ pub type PageLoader = Arc<dyn Fn(&mut Page) -> bool + Send + Sync>;
 pub struct KernelVmPager {
     ept: ExtendedPageTable,
     pub loaders: BTreeMap<Gpa, PageLoader>,
+    // The pages backed by the files, until the guest writes to them.
+    files: BTreeMap<Gpa, (Arc<FileBackedRegion>, usize)>,
+    map: GuestMemoryMap,
     entry: usize,
 }
 
@@ -114,6 +123,8 @@ impl KernelVmPager {
         let mut pager = Self {
             ept: ExtendedPageTable::new(),
             loaders: BTreeMap::new(),
+            files: BTreeMap::new(),
+            map: GuestMemoryMap::new(ram_in_kb as u64 * 1024),
             entry: 0,
         };
 
@@ -128,62 +139,40 @@ impl KernelVmPager {
         pager.entry = todo!();
 
         // Fill usable mems.
-        let empty_pager = Arc::new(|_: &mut Page| true);
-        let mut remainder = (ram_in_kb * 1024) / 4096;
-        unsafe {
-            let (kernel_start, kernel_end) = (
-                pager.loaders.keys().next().unwrap().into_usize(),
-                pager.loaders.keys().last().unwrap().into_usize() + 0x1000,
-            );
-            remainder -= (kernel_end - kernel_start) / 0x1000;
-
-            for gpa in (0..kernel_start).step_by(0x1000) {
-                if remainder == 0 {
-                    break;
-                }
-                pager
-                    .map_page(Gpa::new(gpa).unwrap(), empty_pager.clone())
-                    .then(|| ())?;
-                remainder -= 1;
-            }
-
-            let mut gpa = kernel_end;
-            while remainder > 0 {
-                if gpa == 0xbffda000 {
-                    // Hole for mmio.
-                    gpa = 0x1_0000_0000;
-                    continue;
-                }
-                pager
-                    .map_page(Gpa::new(gpa).unwrap(), empty_pager.clone())
-                    .then(|| ())?;
-                remainder -= 1;
-                gpa += 0x1000;
-            }
+        let empty_pager: PageLoader = Arc::new(|_: &mut Page| true);
+        let pages = pager
+            .map
+            .ram_pages()
+            .filter(|gpa| !pager.loaders.contains_key(gpa))
+            .collect::<alloc::vec::Vec<_>>();
+        for gpa in pages {
+            pager.map_page(gpa, empty_pager.clone()).then(|| ())?;
         }
 
         Some(pager)
     }
 
+    /// Build the E820 memory map from the guest memory layout.
+    pub fn memory_map(&self) -> MemoryMap {
+        self.map.e820()
+    }
+
+    /// Get the layout of the guest physical address space.
+    #[inline]
+    pub fn guest_memory_map(&self) -> &GuestMemoryMap {
+        &self.map
+    }
+
+    /// Get the mutable layout of the guest physical address space, to claim
+    /// the MMIO ranges of the devices.
+    #[inline]
+    pub fn guest_memory_map_mut(&mut self) -> &mut GuestMemoryMap {
+        &mut self.map
+    }
+
     /// Setup the page for mbinfo.
     pub fn finalize_mem(&mut self) -> Option<usize> {
-        let mut section_start = self.loaders.keys().next().unwrap();
-        let mut section_end = section_start;
-        let mut sections = Vec::new();
-        for gpa in self.loaders.keys() {
-            if *gpa == *section_end + 0x1000 {
-                section_end = gpa;
-            } else {
-                if section_start != section_end {
-                    sections.push(*section_start..*section_end + 0x1000);
-                }
-                section_start = gpa;
-                section_end = gpa;
-            }
-        }
-        if section_start != section_end {
-            sections.push(*section_start..*section_end + 0x1000);
-        }
+        let memory_map = self.memory_map();
         assert!(self.loaders.remove(&Gpa::new(0).unwrap()).is_some());
 
         pub struct MbiWriter {
@@ -218,10 +207,15 @@ impl KernelVmPager {
                     .write_u32(24) // stride
                     .write_u32(0) // version
             }
-            fn write_memory_info(&mut self, base_addr: u64, length: u64, ty: u32) -> &mut Self {
+            fn write_memory_info(
+                &mut self,
+                base_addr: u64,
+                length: u64,
+                ty: E820Type,
+            ) -> &mut Self {
                 self.write_u64(base_addr)
                     .write_u64(length)
-                    .write_u32(ty)
+                    .write_u32(ty as u32)
                     .write_u32(0)
             }
             fn finalize(self) -> Page {
@@ -236,15 +230,9 @@ impl KernelVmPager {
         let mut writer = MbiWriter::new()?;
         writer
             .write_u32(0) // MutiBootInfo2._rev
-            .write_memory_info_head(sections.len() as u32);
-        for s in sections.into_iter() {
-            unsafe {
-                writer.write_memory_info(
-                    s.start.into_usize() as u64,
-                    (s.end.into_usize() - s.start.into_usize()) as u64,
-                    1, // Usable.
-                );
-            }
+            .write_memory_info_head(memory_map.entries().len() as u32);
+        for e in memory_map.entries() {
+            writer.write_memory_info(e.base, e.len, e.ty);
         }
         self.ept
             .map(Gpa::new(0).unwrap(), writer.finalize(), Permission::all())
@@ -274,20 +262,99 @@ impl KernelVmPager {
             .map(gpa, page, Permission::READ | Permission::EXECUTABLE)
     }
 
+    /// Fail populating the guest memory with the `faults`.
+    ///
+    /// See [`ExtendedPageTable::set_faults`].
+    pub fn faults(mut self, faults: FaultInjector) -> Self {
+        self.ept.set_faults(faults);
+        self
+    }
+
     /// Attach a page at `gpa`.
+    ///
+    /// Returns false if the `gpa` is not in the RAM.
     #[inline]
     pub fn map_page(&mut self, gpa: Gpa, loader: PageLoader) -> bool {
         assert_eq!(unsafe { gpa.into_usize() } & 0xfff, 0);
+        if !self.map.is_ram(gpa) {
+            return false;
+        }
         assert!(self.loaders.insert(gpa, loader).is_none());
         true
     }
 
+    /// Back the guest RAM from `gpa` with the pages of the `region`.
+    ///
+    /// The pages are mapped read-only on their first access, and copied on
+    /// the first write. This overrides the pages attached at the range
+    /// before. Returns false if the range is not in the RAM.
+    pub fn map_file(&mut self, gpa: Gpa, region: &Arc<FileBackedRegion>) -> bool {
+        let base = unsafe { gpa.into_usize() };
+        assert_eq!(base & 0xfff, 0);
+        let gpas = (0..region.nr_pages()).map(|idx| Gpa::new(base + idx * 0x1000));
+        if !gpas
+            .clone()
+            .all(|gpa| gpa.is_some_and(|gpa| self.map.is_ram(gpa)))
+        {
+            return false;
+        }
+        for (idx, gpa) in gpas.flatten().enumerate() {
+            self.loaders.remove(&gpa);
+            self.files.insert(gpa, (region.clone(), idx));
+        }
+        true
+    }
+
+    // Map the file-backed page at `gpa`: the shared page on read, and a copy
+    // of it on write.
+    fn load_file_page(&mut self, gpa: Gpa, write: bool) -> bool {
+        let Some((region, idx)) = self.files.get(&gpa).cloned() else {
+            return false;
+        };
+        if !write {
+            return match region.page(idx) {
+                Some(hpa) => unsafe {
+                    self.ept
+                        .do_map(gpa, hpa, Permission::READ | Permission::EXECUTABLE)
+                        .is_ok()
+                },
+                None => false,
+            };
+        }
+        let Some(mut page) = Page::new() else {
+            return false;
+        };
+        if region.copy_to(idx, &mut page).is_none() {
+            return false;
+        }
+        if self.ept.walk(gpa).is_ok_and(|pte| pte.pa().is_some()) {
+            // The shared page is owned by the region.
+            match self.ept.unmap(gpa) {
+                Ok(shared) => {
+                    shared.into_raw();
+                }
+                Err(_) => return false,
+            }
+        }
+        if self.ept.map(gpa, page, Permission::all()).is_err() {
+            return false;
+        }
+        self.files.remove(&gpa);
+        true
+    }
+
     /// Get ept ptr of the pager.
     #[inline]
     pub fn ept_ptr(&self) -> Pa {
         self.ept.pa()
     }
 
+    /// Get the host physical address of the populated guest page `gpa`.
+    #[inline]
+    pub fn populated(&self, gpa: Gpa) -> Option<Pa> {
+        self.ept.walk(gpa).ok()?.pa()
+    }
+
     /// Map page to the ept with permission READ, WRITE, and EXECUTABLE.
     fn load_page(&mut self, gpa: Gpa) -> bool {
         assert_eq!(unsafe { gpa.into_usize() } & 0xfff, 0);
@@ -296,12 +363,19 @@ impl KernelVmPager {
 
     /// Handle the ept violation and load the corresponding page.
     pub fn try_lazy_paging(&mut self, reason: ExitReason) -> Result<VmexitResult, VmError> {
-        if let kev::vmcs::BasicExitReason::EptViolation { fault_addr, .. } =
-            reason.get_basic_reason()
+        if let kev::vmcs::BasicExitReason::EptViolation {
+            fault_addr,
+            qualification,
+        } = reason.get_basic_reason()
         {
             if let Some(gpa) = fault_addr {
                 let gpa = Gpa::new(unsafe { gpa.into_usize() } & !PAGE_MASK).unwrap();
-                if self.load_page(gpa) {
+                if self.files.contains_key(&gpa) {
+                    let write = qualification.contains(EptViolationQualification::BIT1);
+                    if self.load_file_page(gpa, write) {
+                        return Ok(VmexitResult::Ok);
+                    }
+                } else if self.load_page(gpa) {
                     return Ok(VmexitResult::Ok);
                 }
             }
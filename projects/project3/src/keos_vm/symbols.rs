@@ -0,0 +1,133 @@
+//! Symbols of the guest kernel.
+//!
+//! The guest kernel image is usually stripped to save the disk and the guest
+//! memory, and its symbols are shipped in a separate ELF file, e.g. the
+//! output of `objcopy --only-keep-debug`. [`GuestSymbols`] loads the function
+//! and object symbols from such a file on the file system, and resolves a
+//! guest address to the symbol that contains it:
+//!
+//! ```ignore
+//! let symbols = GuestSymbols::load("gKeOS.sym").expect("gKeOS.sym is not exist.");
+//! if let Some((name, ofs)) = symbols.lookup(rip) {
+//!     println!("{:#x}: {}+{:#x}", rip, name, ofs);
+//! }
+//! ```
+//!
+//! The symbols resolve the backtraces of the guest panics as a
+//! [`Symbolizer`]. As a guest rarely panics, [`LazySymbols`] defers the
+//! loading to the first panic.
+use crate::keos_vm::{
+    elf::{Peeker, SymType, ELF},
+    pager::FilePeeker,
+};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+};
+use keos::{
+    fs::{file_system, File},
+    spin_lock::SpinLock,
+};
+use kev::guest_panic::Symbolizer;
+
+/// Symbols of a guest, ordered by their addresses.
+pub struct GuestSymbols {
+    // Start address to the (size, name) of the symbol.
+    symbols: BTreeMap<usize, (usize, String)>,
+}
+
+impl GuestSymbols {
+    /// Load the symbols from the file `name`.
+    ///
+    /// Returns `None` if the file is not exist or not an ELF with symbols.
+    pub fn load(name: &str) -> Option<Self> {
+        Self::from_file(file_system()?.open(name)?)
+    }
+
+    /// Load the symbols from the `file`.
+    pub fn from_file(file: File) -> Option<Self> {
+        Self::from_elf(&ELF::from_peeker(FilePeeker { file }).ok()?)
+    }
+
+    /// Load the symbols from the `elf`.
+    ///
+    /// Only the functions and the objects are loaded; the symbols that are
+    /// not readable are skipped.
+    pub fn from_elf<T: Peeker>(elf: &ELF<T>) -> Option<Self> {
+        let symbols = elf
+            .symbols()?
+            .filter_map(Result::ok)
+            .filter(|sym| {
+                matches!(sym.type_, SymType::Func | SymType::Object)
+                    && sym.value != 0
+                    && !sym.name.is_empty()
+            })
+            .map(|sym| (sym.value, (sym.size, sym.name)))
+            .collect();
+        Some(Self { symbols })
+    }
+
+    /// Find the symbol that contains the address `addr`.
+    ///
+    /// Returns the name of the symbol and the offset of `addr` from it. A
+    /// symbol of size zero only contains its own address.
+    pub fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        let (start, (size, name)) = self.symbols.range(..=addr).next_back()?;
+        let ofs = addr - start;
+        if ofs < *size || ofs == 0 {
+            Some((name, ofs))
+        } else {
+            None
+        }
+    }
+
+    /// Get the address of the symbol `name`.
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.symbols
+            .iter()
+            .find(|(_, (_, n))| n == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Get the number of the symbols.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Check whether there is no symbol.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+impl Symbolizer for GuestSymbols {
+    fn symbolize(&self, addr: usize) -> Option<(String, usize)> {
+        self.lookup(addr).map(|(name, ofs)| (name.to_string(), ofs))
+    }
+}
+
+/// Symbols loaded from a file on the first lookup.
+pub struct LazySymbols {
+    name: String,
+    symbols: SpinLock<Option<Option<GuestSymbols>>>,
+}
+
+impl LazySymbols {
+    /// Create the symbols of the file `name`, which is not read yet.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            symbols: SpinLock::new(None),
+        }
+    }
+}
+
+impl Symbolizer for LazySymbols {
+    fn symbolize(&self, addr: usize) -> Option<(String, usize)> {
+        self.symbols
+            .lock()
+            .get_or_insert_with(|| GuestSymbols::load(&self.name))
+            .as_ref()?
+            .symbolize(addr)
+    }
+}
@@ -48,6 +48,8 @@ pub mod mmio;
 pub mod simple_ept_vm;
 
 pub mod vmexit {
+    #[path = "enclave.rs"]
+    pub mod enclave;
     #[path = "mmio.rs"]
     pub mod mmio;
 }
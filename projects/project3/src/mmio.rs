@@ -43,12 +43,31 @@
 //! [`core::slice::from_utf8`]: https://doc.rust-lang.org/beta/core/str/fn.from_utf8.html
 //! [`core::slice::from_raw_parts`]: https://doc.rust-lang.org/std/slice/fn.from_raw_parts.html
 //! [`PrinterProxy`]: project2::PrinterProxy
+//!
+//! ## Coalesced printer
+//! The PrinterDev takes three MMIO exits per message. The [`RingPrinterDev`] lets the guest queue many messages on
+//! a descriptor ring in the shared memory and ring the doorbell once for all of them:
+//! * 0xcafe1000: Guest physical address of the ring
+//! * 0xcafe1008: Number of the descriptors in the ring
+//! * 0xcafe1010: The doorbell which notifies VMM to print the queued messages
+//!
+//! The ring starts with the producer index (8 bytes), advanced by the guest after filling a descriptor,
+//! and the consumer index (8 bytes), advanced by the VMM after printing a message.
+//! The descriptors follow, each of which has the guest physical address (8 bytes) and the length (8 bytes) of a
+//! utf8 string. The indices run freely, and the descriptor of an index is at the index modulo the number of the
+//! descriptors. The whole ring must be in a page.
 
-use crate::vmexit::mmio::{self, MmioInfo, MmioRegion};
-use core::fmt::Write;
+use crate::vmexit::mmio::{self, Direction, MmioInfo, MmioRegion};
+use alloc::{boxed::Box, vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use keos::addressing::{PAGE_MASK, PAGE_SIZE};
 use kev::{
     vcpu::{GenericVCpuState, VmexitResult},
     vm::Gpa,
+    vmcs::ActiveVmcs,
     Probe, VmError,
 };
 use project2::PrinterProxy;
@@ -91,3 +110,105 @@ impl mmio::MmioHandler for PrinterDev {
        todo!()
     }
 }
+
+/// MMIO printer device that prints the messages queued on a descriptor ring.
+#[derive(Default)]
+pub struct RingPrinterDev {
+    ring: u64,
+    len: u64,
+}
+
+impl RingPrinterDev {
+    const BASE: usize = 0xcafe1000;
+    // Size of the ring header and each descriptor.
+    const HEADER: usize = 16;
+    const DESC: usize = 16;
+
+    fn error(msg: &'static str) -> VmError {
+        VmError::ControllerError(Box::new(msg))
+    }
+
+    fn hva(p: &dyn Probe, vmcs: &ActiveVmcs, gpa: usize) -> Result<usize, VmError> {
+        Gpa::new(gpa)
+            .and_then(|gpa| p.gpa2hva(vmcs, gpa))
+            .map(|va| unsafe { va.into_usize() })
+            .ok_or_else(|| Self::error("Invalid guest physical address."))
+    }
+
+    // Print the message at `gpa`, which may cross the pages.
+    fn print(p: &dyn Probe, vmcs: &ActiveVmcs, gpa: usize, len: usize) -> Result<(), VmError> {
+        let mut buf = vec![0; len];
+        let mut done = 0;
+        while done < len {
+            let at = gpa + done;
+            let size = (len - done).min(PAGE_SIZE - (at & PAGE_MASK));
+            let hva = Self::hva(p, vmcs, at)?;
+            buf[done..done + size]
+                .copy_from_slice(unsafe { core::slice::from_raw_parts(hva as *const u8, size) });
+            done += size;
+        }
+        let s = core::str::from_utf8(&buf).map_err(|_| Self::error("Invalid utf8 string."))?;
+        let _ = write!(&mut PrinterProxy, "{}", s);
+        Ok(())
+    }
+
+    // Print all the queued messages.
+    fn drain(&self, p: &dyn Probe, vmcs: &ActiveVmcs) -> Result<(), VmError> {
+        let (ring, len) = (self.ring as usize, self.len as usize);
+        let size = Self::HEADER + len * Self::DESC;
+        if len == 0 || (ring & PAGE_MASK) + size > PAGE_SIZE {
+            return Err(Self::error("Invalid ring."));
+        }
+        let hva = Self::hva(p, vmcs, ring)?;
+        let (producer, consumer) = unsafe {
+            (
+                &*(hva as *const AtomicU64),
+                &*((hva + 8) as *const AtomicU64),
+            )
+        };
+        let head = producer.load(Ordering::Acquire);
+        let mut tail = consumer.load(Ordering::Relaxed);
+        while tail != head {
+            let desc = hva + Self::HEADER + (tail % self.len) as usize * Self::DESC;
+            let (addr, size) = unsafe { (*(desc as *const u64), *((desc + 8) as *const u64)) };
+            Self::print(p, vmcs, addr as usize, size as usize)?;
+            tail = tail.wrapping_add(1);
+            consumer.store(tail, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+impl mmio::MmioHandler for RingPrinterDev {
+    fn region(&self) -> MmioRegion {
+        MmioRegion {
+            start: Gpa::new(Self::BASE).unwrap(),
+            end: Gpa::new(Self::BASE + 0x18).unwrap(),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        p: &dyn Probe,
+        info: MmioInfo,
+        GenericVCpuState { vmcs, .. }: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        // Ignore the request with invalid io size.
+        let (dst, src) = match info.direction {
+            Direction::Write64 { dst, src } => (dst, src),
+            _ => return Ok(VmexitResult::Ok),
+        };
+        let ofs = unsafe { dst.into_usize() } - Self::BASE;
+        match ofs {
+            0x0 => self.ring = src,
+            0x8 => self.len = src,
+            0x10 => self.drain(p, vmcs)?,
+            _ => return Ok(VmexitResult::Ok),
+        }
+        // Reflect the change on the mmio area.
+        unsafe {
+            *(Self::hva(p, vmcs, Self::BASE + ofs)? as *mut u64) = src;
+        }
+        Ok(VmexitResult::Ok)
+    }
+}
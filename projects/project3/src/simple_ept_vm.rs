@@ -1,7 +1,7 @@
 //! Virtual machine configuration of project3-1.
 use crate::{
     ept::{EptMappingError, ExtendedPageTable, Permission as EptPermission},
-    mmio::PrinterDev,
+    mmio::{PrinterDev, RingPrinterDev},
     vmexit::mmio,
 };
 use keos::{
@@ -57,6 +57,7 @@ impl kev::vm::VmState for SimpleEptVmState {
     fn vcpu_state(&self) -> Self::VcpuState {
         let mut mmio_controller = mmio::Controller::new();
         mmio_controller.register(PrinterDev::default());
+        mmio_controller.register(RingPrinterDev::default());
         SimpleEptVcpuState {
             ept: ExtendedPageTable::new(),
             page_table: PageTable::new(),
@@ -121,25 +122,27 @@ impl kev::vm::VmState for SimpleEptVmState {
             }
         }
 
-        // Add mmio area.
-        unsafe {
+        // Add mmio areas.
+        for mmio in [0xcafe0000, 0xcafe1000] {
+            unsafe {
+                vbsp_vcpu_state
+                    .page_table
+                    .do_map(
+                        Va::new(mmio).unwrap(),
+                        Pa::new(mmio).unwrap(),
+                        Permission::READ | Permission::WRITE | Permission::EXECUTABLE,
+                    )
+                    .map_err(Error::PageTableError)?;
+            }
             vbsp_vcpu_state
-                .page_table
-                .do_map(
-                    Va::new(0xcafe0000).unwrap(),
-                    Pa::new(0xcafe0000).unwrap(),
-                    Permission::READ | Permission::WRITE | Permission::EXECUTABLE,
+                .ept
+                .map(
+                    Gpa::new(mmio).unwrap(),
+                    Page::new().expect("Failed to alloc page."),
+                    EptPermission::READ,
                 )
-                .map_err(Error::PageTableError)?;
+                .map_err(Error::EptError)?;
         }
-        vbsp_vcpu_state
-            .ept
-            .map(
-                Gpa::new(0xcafe0000).unwrap(),
-                Page::new().expect("Failed to alloc page."),
-                EptPermission::READ,
-            )
-            .map_err(Error::EptError)?;
         // gpa -> hpa mappings.
         unsafe {
             use core::slice::from_raw_parts;
@@ -161,7 +164,7 @@ impl kev::vm::VmState for SimpleEptVmState {
                             .map_err(Error::EptError)?;
                         for pte in from_raw_parts(ntable, 512).iter().filter(|e| *e & 1 != 0) {
                             let pa = Pte(*pte).pa().unwrap().into_usize();
-                            if pa != 0xcafe0000 {
+                            if pa != 0xcafe0000 && pa != 0xcafe1000 {
                                 vbsp_vcpu_state
                                     .add_gpa_mapping(pa)
                                     .map_err(Error::EptError)?;
@@ -0,0 +1,218 @@
+//! Enclave vmexit controller.
+//!
+//! An enclave is a region of the guest physical memory that is isolated from
+//! the rest of the guest, including the guest kernel. The isolation is built
+//! on two EPT views:
+//! - The normal view, which the guest runs on. The enclave pages are unmapped
+//!   from this view, so any access to them causes an EPT violation.
+//! - The enclave view, which maps the enclave pages and the pages explicitly
+//!   shared with the enclave (e.g. the guest page tables and the buffers for
+//!   the arguments).
+//!
+//! The guest switches between the views only through the hypercalls, which
+//! transfer the control to the fixed entry point of the enclave and back:
+//!
+//! | Hypercall                  | rax     | Arguments                        |
+//! |----------------------------|---------|----------------------------------|
+//! | [`HC_ENCLAVE_ENTER`]       | 0x100   | rdi: enclave id, rsi, rdx: args  |
+//! | [`HC_ENCLAVE_EXIT`]        | 0x101   | rdi: return value                |
+//!
+//! On the entry, the general purpose registers except the arguments are
+//! scrubbed, and the vcpu starts at the entry point of the enclave with the
+//! stack of the enclave. On the exit, all the general purpose registers are
+//! scrubbed except rax that holds the return value, and the vcpu resumes right
+//! after the entering hypercall.
+//!
+//! An invalid transition, such as entering an unknown enclave or exiting
+//! outside of the enclave, raises #GP(0) on the guest.
+//!
+//! The controller maintains the following invariants:
+//! - The normal view never maps the enclave pages.
+//! - The enclave is only entered at its entry point.
+//! - The enclave exit is only accepted while in the enclave.
+//! - No register value leaks across the boundary, except the arguments and
+//!   the return value.
+//!
+//! The guest must not take interrupts inside the enclave, as its interrupt
+//! handlers are not mapped in the enclave view.
+use crate::ept::{EptMappingError, ExtendedPageTable, Permission};
+use alloc::{sync::Arc, vec::Vec};
+use keos::addressing::PAGE_SIZE;
+use kev::{
+    event,
+    vcpu::{GeneralPurposeRegisters, GenericVCpuState, VmexitResult},
+    vm::{Gpa, Gva},
+    vmcs::{BasicExitReason, ExitReason, Field},
+    Probe, VmError,
+};
+
+/// Hypercall number to enter the enclave.
+pub const HC_ENCLAVE_ENTER: usize = 0x100;
+/// Hypercall number to exit the enclave.
+pub const HC_ENCLAVE_EXIT: usize = 0x101;
+
+/// Builder of the [`Enclave`].
+pub struct EnclaveBuilder {
+    start: Gpa,
+    size: usize,
+    entry: Gva,
+    stack: Gva,
+    shared: Vec<(Gpa, usize)>,
+}
+
+impl EnclaveBuilder {
+    /// Create a new builder of the enclave that occupies `size` bytes from
+    /// `start`.
+    ///
+    /// The enclave is entered at `entry` with the stack pointer `stack`.
+    pub fn new(start: Gpa, size: usize, entry: Gva, stack: Gva) -> Self {
+        Self {
+            start,
+            size,
+            entry,
+            stack,
+            shared: Vec::new(),
+        }
+    }
+
+    /// Share `size` bytes from `start` of the normal view with the enclave.
+    pub fn share(mut self, start: Gpa, size: usize) -> Self {
+        self.shared.push((start, size));
+        self
+    }
+
+    /// Finalize this builder.
+    ///
+    /// The enclave pages are moved from the `normal` view into the enclave view.
+    pub fn finalize(self, normal: &mut ExtendedPageTable) -> Result<Enclave, EptMappingError> {
+        let Self {
+            start,
+            size,
+            entry,
+            stack,
+            shared,
+        } = self;
+        if unsafe { start.into_usize() } % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(EptMappingError::Unaligned);
+        }
+
+        let mut view = ExtendedPageTable::new();
+        for (base, len) in shared {
+            for ofs in (0..len).step_by(PAGE_SIZE) {
+                view.share(normal, base + ofs, Permission::READ | Permission::WRITE)?;
+            }
+        }
+        for ofs in (0..size).step_by(PAGE_SIZE) {
+            let pg = normal.unmap(start + ofs)?;
+            view.map(start + ofs, pg, Permission::all())?;
+        }
+        // The guest may have cached the translations to the enclave pages.
+        normal.invalidate();
+
+        Ok(Enclave { view, entry, stack })
+    }
+}
+
+/// An enclave.
+pub struct Enclave {
+    view: ExtendedPageTable,
+    entry: Gva,
+    stack: Gva,
+}
+
+// Context of the normal world, saved while in the enclave.
+struct Outside {
+    eptp: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+/// Enclave vmexit controller.
+///
+/// The controller handles only the enclave hypercalls, and leaves the other
+/// vmexits to the following controllers.
+pub struct Controller {
+    enclaves: Vec<Arc<Enclave>>,
+    outside: Option<Outside>,
+}
+
+impl Controller {
+    /// Create a new enclave controller.
+    pub fn new() -> Self {
+        Self {
+            enclaves: Vec::new(),
+            outside: None,
+        }
+    }
+
+    /// Register the `enclave` and returns its id.
+    pub fn register(&mut self, enclave: Arc<Enclave>) -> usize {
+        self.enclaves.push(enclave);
+        self.enclaves.len() - 1
+    }
+
+    fn enter(&mut self, generic_vcpu_state: &mut GenericVCpuState) -> Result<bool, VmError> {
+        let GenericVCpuState { vmcs, gprs, .. } = generic_vcpu_state;
+        let enclave = match self.enclaves.get(gprs.rdi) {
+            Some(enclave) if self.outside.is_none() => enclave,
+            _ => return Ok(false),
+        };
+        vmcs.forward_rip()?;
+        self.outside = Some(Outside {
+            eptp: vmcs.read(Field::Eptptr)?,
+            rip: vmcs.read(Field::GuestRip)?,
+            rsp: vmcs.read(Field::GuestRsp)?,
+        });
+        let (rsi, rdx) = (gprs.rsi, gprs.rdx);
+        **gprs = GeneralPurposeRegisters::default();
+        (gprs.rsi, gprs.rdx) = (rsi, rdx);
+        vmcs.write(Field::Eptptr, enclave.view.eptp())?;
+        let (entry, stack) = unsafe { (enclave.entry.into_usize(), enclave.stack.into_usize()) };
+        vmcs.write(Field::GuestRip, entry as u64)?;
+        vmcs.write(Field::GuestRsp, stack as u64)?;
+        Ok(true)
+    }
+
+    fn exit(&mut self, generic_vcpu_state: &mut GenericVCpuState) -> Result<bool, VmError> {
+        let GenericVCpuState { vmcs, gprs, .. } = generic_vcpu_state;
+        let Some(Outside { eptp, rip, rsp }) = self.outside.take() else {
+            return Ok(false);
+        };
+        let rax = gprs.rdi;
+        **gprs = GeneralPurposeRegisters::default();
+        gprs.rax = rax;
+        vmcs.write(Field::Eptptr, eptp)?;
+        vmcs.write(Field::GuestRip, rip)?;
+        vmcs.write(Field::GuestRsp, rsp)?;
+        Ok(true)
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl kev::vmexits::VmexitController for Controller {
+    fn handle<P: Probe>(
+        &mut self,
+        reason: ExitReason,
+        _p: &mut P,
+        generic_vcpu_state: &mut GenericVCpuState,
+    ) -> Result<VmexitResult, VmError> {
+        let handled = match reason.get_basic_reason() {
+            BasicExitReason::Vmcall => match generic_vcpu_state.gprs.rax {
+                HC_ENCLAVE_ENTER => self.enter(generic_vcpu_state)?,
+                HC_ENCLAVE_EXIT => self.exit(generic_vcpu_state)?,
+                _ => return Err(VmError::HandleVmexitFailed(reason)),
+            },
+            _ => return Err(VmError::HandleVmexitFailed(reason)),
+        };
+        // Reject the invalid transition like a faulting instruction.
+        if !handled {
+            return event::inject_exception(generic_vcpu_state, event::GP, Some(0));
+        }
+        Ok(VmexitResult::Ok)
+    }
+}
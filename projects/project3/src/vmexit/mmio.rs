@@ -7,6 +7,7 @@ use alloc::{
 use core::cmp::Ordering;
 use iced_x86::{Instruction, OpKind, Register};
 use kev::{
+    memory_map::GuestMemoryMap,
     vcpu::{GeneralPurposeRegisters, GenericVCpuState, VmexitResult},
     vm::Gpa,
     vmcs::{BasicExitReason, EptViolationQualification, ExitReason, Field},
@@ -79,6 +80,8 @@ impl MmioRegion {
 /// Mmio vmexit controller.
 pub struct Controller {
     inner: BTreeMap<MmioRegion, Box<dyn MmioHandler>>,
+    // Layout of the guest that the regions must be claimed in.
+    memory_map: Option<GuestMemoryMap>,
 }
 
 #[derive(Debug)]
@@ -111,11 +114,26 @@ impl Controller {
     pub fn new() -> Self {
         Controller {
             inner: BTreeMap::new(),
+            memory_map: None,
         }
     }
+    /// Only accept the regions claimed in the `memory_map`.
+    pub fn with_memory_map(mut self, memory_map: GuestMemoryMap) -> Self {
+        self.memory_map = Some(memory_map);
+        self
+    }
     /// Add a mmio region to the controller.
     pub fn register(&mut self, p: impl MmioHandler + 'static) {
-        match self.inner.entry(p.region()) {
+        let region = p.region();
+        if let Some(memory_map) = self.memory_map.as_ref() {
+            let size = unsafe { region.end.into_usize() - region.start.into_usize() };
+            assert!(
+                memory_map.is_claimed(region.start, size),
+                "unclaimed mmio region {:?}",
+                region
+            );
+        }
+        match self.inner.entry(region) {
             Entry::Occupied(_) => panic!("overwrapping mmio region"),
             Entry::Vacant(v) => {
                 v.insert(Box::new(p));
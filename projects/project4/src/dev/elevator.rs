@@ -0,0 +1,265 @@
+//! I/O scheduler of the block backend.
+//!
+//! The backends of the guests share a single disk. Rather than serving the
+//! virtqueue entries one by one in the order they arrive, the backends submit
+//! them to an [`Elevator`], which keeps a queue for each vm and dispatches
+//! them in batches:
+//!
+//! 1. Up to [`Elevator::window`] entries are taken from the queues of the vms
+//!    in turn, so a busy vm does not starve the others. A vm takes as many
+//!    entries in its turn as its weight (see [`Elevator::set_weight`]).
+//! 2. The batch is sorted by the sector in the direction of the disk head
+//!    (C-LOOK): the entries at or after the last dispatched sector first,
+//!    then the rest from the lowest sector.
+//! 3. Adjacent entries of the same command on the consecutive sectors are
+//!    merged into a single [`Dispatch`] with a buffer for each entry.
+//!
+//! A [`VirtQueueEntryCmd::Flush`] is a barrier: the entries are never moved
+//! across it.
+//!
+//! ```ignore
+//! let mut elevator = Elevator::new().window(32);
+//! elevator.submit(vm_id, entry, (vm_id, index));
+//! for dispatch in elevator.next_batch() {
+//!     for (addr, size) in dispatch.buffers() { /* transfer */ }
+//!     for (vm_id, index) in dispatch.tokens { /* complete */ }
+//! }
+//! ```
+//!
+//! The [`Policy::Fifo`] dispatches the entries as they arrive, as a baseline
+//! to measure the effects of the scheduling against, with the
+//! [`ElevatorStats`].
+//!
+//! The sectors of the entries are of the shared disk; a backend that gives
+//! each vm a part of the disk translates the sectors before submitting them.
+use crate::virtio::virt_queue::{VirtQueueEntry, VirtQueueEntryCmd};
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use keos::addressing::Pa;
+
+/// Size of a sector in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Order to dispatch the entries in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// As they arrive, without merging.
+    Fifo,
+    /// Sorted in the direction of the disk head, and merged.
+    Elevator,
+}
+
+/// Merged entries to be served with a single disk access.
+#[derive(Debug)]
+pub struct Dispatch<T> {
+    /// The command.
+    pub cmd: VirtQueueEntryCmd,
+    /// The first sector.
+    pub sector: usize,
+    /// The entries, in the order of the sectors.
+    pub entries: Vec<VirtQueueEntry>,
+    /// The tokens of the entries, to complete them.
+    pub tokens: Vec<T>,
+}
+
+impl<T> Dispatch<T> {
+    /// Get the size of the access in bytes.
+    pub fn size(&self) -> usize {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    /// Get the number of the sectors of the access.
+    pub fn sectors(&self) -> usize {
+        self.size().div_ceil(SECTOR_SIZE)
+    }
+
+    /// Iterate over the guest buffers, in the order of the sectors.
+    pub fn buffers(&self) -> impl Iterator<Item = (Pa, usize)> + '_ {
+        self.entries.iter().map(|e| (e.addr, e.size))
+    }
+
+    // The sector after the access.
+    fn end(&self) -> usize {
+        self.sector + self.sectors()
+    }
+}
+
+/// Statistics of an [`Elevator`].
+#[derive(Clone, Debug, Default)]
+pub struct ElevatorStats {
+    /// Number of the submitted entries.
+    pub submitted: usize,
+    /// Number of the disk accesses dispatched.
+    pub dispatched: usize,
+    /// Number of the entries merged into another.
+    pub merged: usize,
+    /// Number of the batches.
+    pub batches: usize,
+    /// Total distance of the disk head between the accesses, in sectors.
+    pub seek_distance: usize,
+    /// Number of the entries served for each vm.
+    pub served: BTreeMap<usize, usize>,
+}
+
+/// An I/O scheduler with a queue for each vm.
+pub struct Elevator<T> {
+    policy: Policy,
+    window: usize,
+    queues: BTreeMap<usize, VecDeque<(VirtQueueEntry, T)>>,
+    // Weights of the vms other than 1.
+    weights: BTreeMap<usize, usize>,
+    // The vm to take an entry from first in the next batch.
+    next_vm: usize,
+    // The sector after the last access.
+    head: usize,
+    stats: ElevatorStats,
+}
+
+impl<T> Default for Elevator<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Elevator<T> {
+    /// Create an elevator with the window of 16 entries.
+    pub fn new() -> Self {
+        Self {
+            policy: Policy::Elevator,
+            window: 16,
+            queues: BTreeMap::new(),
+            weights: BTreeMap::new(),
+            next_vm: 0,
+            head: 0,
+            stats: ElevatorStats::default(),
+        }
+    }
+
+    /// Set the policy.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set the maximum number of the entries in a batch.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Set the number of the entries that the vm `vm` takes in its turn.
+    ///
+    /// The weight is 1 by default; a vm of the weight 2 gets twice the share
+    /// of the disk of the others while they are busy.
+    pub fn set_weight(&mut self, vm: usize, weight: usize) {
+        if weight <= 1 {
+            self.weights.remove(&vm);
+        } else {
+            self.weights.insert(vm, weight);
+        }
+    }
+
+    /// Get the statistics.
+    pub fn stats(&self) -> &ElevatorStats {
+        &self.stats
+    }
+
+    /// Get the number of the entries waiting.
+    pub fn pending(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    /// Submit the `entry` of the vm `vm`, with the `token` to complete it.
+    pub fn submit(&mut self, vm: usize, entry: VirtQueueEntry, token: T) {
+        self.stats.submitted += 1;
+        self.queues.entry(vm).or_default().push_back((entry, token));
+    }
+
+    /// Drop the entries of the vm `vm`, e.g. on the reset of its device.
+    pub fn remove_vm(&mut self, vm: usize) -> Vec<T> {
+        self.queues
+            .remove(&vm)
+            .map(|q| q.into_iter().map(|(_, token)| token).collect())
+            .unwrap_or_default()
+    }
+
+    // Take up to a window of the entries, from the vms in turn.
+    fn take_window(&mut self) -> Vec<(usize, VirtQueueEntry, T)> {
+        let mut batch = Vec::new();
+        let vms = self
+            .queues
+            .range(self.next_vm..)
+            .chain(self.queues.range(..self.next_vm))
+            .map(|(vm, _)| *vm)
+            .collect::<Vec<_>>();
+        while batch.len() < self.window && self.pending() != 0 {
+            for vm in vms.iter() {
+                for _ in 0..self.weights.get(vm).copied().unwrap_or(1) {
+                    if batch.len() == self.window {
+                        break;
+                    }
+                    match self.queues.get_mut(vm).and_then(VecDeque::pop_front) {
+                        Some((entry, token)) => {
+                            batch.push((*vm, entry, token));
+                            self.next_vm = vm + 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+        self.queues.retain(|_, q| !q.is_empty());
+        batch
+    }
+
+    /// Take the next batch of the accesses to dispatch.
+    ///
+    /// Returns an empty batch if no entry is waiting.
+    pub fn next_batch(&mut self) -> Vec<Dispatch<T>> {
+        let mut batch = self.take_window();
+        if batch.is_empty() {
+            return Vec::new();
+        }
+        self.stats.batches += 1;
+        if self.policy == Policy::Elevator {
+            // Sort between the flushes.
+            let head = self.head;
+            for part in batch.split_mut(|(_, entry, _)| entry.cmd == VirtQueueEntryCmd::Flush) {
+                part.sort_by_key(|(_, entry, _)| (entry.sector < head, entry.sector));
+            }
+        }
+
+        let mut dispatches: Vec<Dispatch<T>> = Vec::new();
+        for (vm, entry, token) in batch {
+            *self.stats.served.entry(vm).or_default() += 1;
+            if self.policy == Policy::Elevator {
+                if let Some(last) = dispatches.last_mut() {
+                    if last.cmd == entry.cmd
+                        && last.cmd != VirtQueueEntryCmd::Flush
+                        && last.size() % SECTOR_SIZE == 0
+                        && last.end() == entry.sector
+                    {
+                        last.entries.push(entry);
+                        last.tokens.push(token);
+                        self.stats.merged += 1;
+                        continue;
+                    }
+                }
+            }
+            dispatches.push(Dispatch {
+                cmd: entry.cmd,
+                sector: entry.sector,
+                entries: Vec::from([entry]),
+                tokens: Vec::from([token]),
+            });
+        }
+        for dispatch in dispatches
+            .iter()
+            .filter(|d| d.cmd != VirtQueueEntryCmd::Flush)
+        {
+            self.stats.seek_distance += self.head.abs_diff(dispatch.sector);
+            self.head = dispatch.end();
+        }
+        self.stats.dispatched += dispatches.len();
+        dispatches
+    }
+}
@@ -0,0 +1,224 @@
+//! Mediated devices sharing a single disk.
+//!
+//! A [`MediatedDisk`] slices the host disk into the isolated virtual disks, in
+//! the manner of the SR-IOV virtual functions. Each vm gets a [`MediatedDev`]
+//! for its slice, and its simple virtio block device serves its own virtqueue
+//! through it:
+//!
+//! - The guest sees the slice as a whole disk: the sector 0 of the guest is
+//!   the first sector of the slice, and the capacity is the size of the slice.
+//! - The entries out of the slice are rejected, so a vm never reaches the
+//!   sectors of another.
+//! - The entries of all the slices are dispatched by a shared [`Elevator`],
+//!   where each slice has its own queue and takes a share of the disk by its
+//!   weight.
+//!
+//! ```ignore
+//! let disk = MediatedDisk::new(capacity, Elevator::new().window(32));
+//! let dev1 = disk.create(Slice::new(0, 0x10000))?;
+//! let dev2 = disk.create(Slice::new(0x10000, 0x10000).weight(2))?;
+//! // In the backend of each vm.
+//! header.set_capacity(dev1.capacity() as u32);
+//! dev1.submit(entry, token)?;
+//! // In the host thread that serves the disk.
+//! for dispatch in disk.next_batch() {
+//!     // `dispatch.sector` is of the host disk.
+//! }
+//! ```
+//!
+//! A flush is not confined to a slice; it makes the completed writes of all
+//! the slices durable.
+use super::elevator::{Dispatch, Elevator, SECTOR_SIZE};
+use crate::virtio::virt_queue::{VirtQueueEntry, VirtQueueEntryCmd};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use keos::sync::SpinLock;
+
+/// A range of the sectors of the host disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Slice {
+    /// The first sector.
+    pub start: usize,
+    /// Number of the sectors.
+    pub sectors: usize,
+    /// Weight of the share of the disk bandwidth.
+    pub weight: usize,
+}
+
+impl Slice {
+    /// Create the slice of the `sectors` from the `start`, of the weight 1.
+    pub fn new(start: usize, sectors: usize) -> Self {
+        Self {
+            start,
+            sectors,
+            weight: 1,
+        }
+    }
+
+    /// Set the weight of the share of the disk bandwidth.
+    pub fn weight(mut self, weight: usize) -> Self {
+        self.weight = weight.max(1);
+        self
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.sectors
+    }
+}
+
+/// An error of the mediated devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MdevError {
+    /// The slice is empty or exceeds the host disk.
+    OutOfDisk(Slice),
+    /// The slice overlaps with the slice of another device.
+    Overlap(Slice),
+    /// The entry accesses out of the slice.
+    OutOfSlice {
+        /// The first sector of the entry, of the guest.
+        sector: usize,
+        /// Number of the sectors of the entry.
+        sectors: usize,
+    },
+}
+
+struct Inner<T> {
+    // Number of the sectors of the host disk.
+    sectors: usize,
+    slices: BTreeMap<usize, Slice>,
+    next_id: usize,
+    elevator: Elevator<T>,
+}
+
+/// A host disk sliced into the mediated devices.
+pub struct MediatedDisk<T> {
+    inner: Arc<SpinLock<Inner<T>>>,
+}
+
+impl<T> MediatedDisk<T> {
+    /// Create a disk of the `sectors`, dispatched by the `elevator`.
+    pub fn new(sectors: usize, elevator: Elevator<T>) -> Self {
+        Self {
+            inner: Arc::new(SpinLock::new(Inner {
+                sectors,
+                slices: BTreeMap::new(),
+                next_id: 0,
+                elevator,
+            })),
+        }
+    }
+
+    /// Create a mediated device of the `slice`.
+    pub fn create(&self, slice: Slice) -> Result<MediatedDev<T>, MdevError> {
+        let mut inner = self.inner.lock();
+        if slice.sectors == 0 || slice.start.saturating_add(slice.sectors) > inner.sectors {
+            return Err(MdevError::OutOfDisk(slice));
+        }
+        if inner
+            .slices
+            .values()
+            .any(|s| s.start < slice.end() && slice.start < s.end())
+        {
+            return Err(MdevError::Overlap(slice));
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.slices.insert(id, slice);
+        inner.elevator.set_weight(id, slice.weight);
+        Ok(MediatedDev {
+            id,
+            slice,
+            disk: self.inner.clone(),
+        })
+    }
+
+    /// Get the slices of the devices, by the ids of the devices.
+    pub fn slices(&self) -> Vec<(usize, Slice)> {
+        self.inner
+            .lock()
+            .slices
+            .iter()
+            .map(|(id, slice)| (*id, *slice))
+            .collect()
+    }
+
+    /// Take the next batch of the accesses to the host disk.
+    ///
+    /// See [`Elevator::next_batch`].
+    pub fn next_batch(&self) -> Vec<Dispatch<T>> {
+        self.inner.lock().elevator.next_batch()
+    }
+
+    /// Get the number of the entries served for each device, by its id.
+    pub fn served(&self) -> BTreeMap<usize, usize> {
+        self.inner.lock().elevator.stats().served.clone()
+    }
+}
+
+/// A virtual disk on a slice of the [`MediatedDisk`].
+///
+/// The slice is released when the device is dropped.
+pub struct MediatedDev<T> {
+    id: usize,
+    slice: Slice,
+    disk: Arc<SpinLock<Inner<T>>>,
+}
+
+impl<T> MediatedDev<T> {
+    /// Get the id of the device.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Get the slice of the host disk.
+    pub fn slice(&self) -> Slice {
+        self.slice
+    }
+
+    /// Get the capacity of the device in sectors.
+    pub fn capacity(&self) -> usize {
+        self.slice.sectors
+    }
+
+    /// Translate the `entry` of the guest to the host disk.
+    pub fn translate(&self, mut entry: VirtQueueEntry) -> Result<VirtQueueEntry, MdevError> {
+        if entry.cmd == VirtQueueEntryCmd::Flush {
+            return Ok(entry);
+        }
+        let sectors = entry.size.div_ceil(SECTOR_SIZE);
+        match entry.sector.checked_add(sectors) {
+            Some(end) if end <= self.slice.sectors => {
+                entry.sector += self.slice.start;
+                Ok(entry)
+            }
+            _ => Err(MdevError::OutOfSlice {
+                sector: entry.sector,
+                sectors,
+            }),
+        }
+    }
+
+    /// Submit the `entry` of the guest, with the `token` to complete it.
+    ///
+    /// The entry out of the slice is not submitted; the backend completes it
+    /// with an error.
+    pub fn submit(&self, entry: VirtQueueEntry, token: T) -> Result<(), MdevError> {
+        let entry = self.translate(entry)?;
+        self.disk.lock().elevator.submit(self.id, entry, token);
+        Ok(())
+    }
+
+    /// Drop the entries of the device waiting in the disk, e.g. on the reset of
+    /// the device.
+    pub fn reset(&self) -> Vec<T> {
+        self.disk.lock().elevator.remove_vm(self.id)
+    }
+}
+
+impl<T> Drop for MediatedDev<T> {
+    fn drop(&mut self) {
+        let mut disk = self.disk.lock();
+        disk.slices.remove(&self.id);
+        disk.elevator.set_weight(self.id, 1);
+        disk.elevator.remove_vm(self.id);
+    }
+}
@@ -1,5 +1,7 @@
 //! Collection of Emulated devices.
 
+pub mod elevator;
+pub mod mdev;
 pub mod simple_virtio;
 pub mod x2apic;
 
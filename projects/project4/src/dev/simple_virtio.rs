@@ -30,6 +30,10 @@
 //!     le32 queue_addr_lo;
 //!     le32 queue_head;
 //!     le32 queue_tail;
+//!     le32 features;
+//!     le32 capacity;
+//!     le32 config_generation;
+//!     le32 config_vector;
 //! }
 //! ```
 //! * status: Status of the device
@@ -38,6 +42,10 @@
 //! * queue_addr_lo: Lower 32bit of the virtqueue physical address
 //! * queue_head: Head of the ring buffer. Driver update the tail of the queue. Device must not update the field.
 //! * queue_tail: Tail of the ring buffer. Device update the tail of the queue. Driver must not update the field.
+//! * features: Features of the device; see [`2.3`](#23-features). Device sets the field when initialization.
+//! * capacity: Capacity of the disk in sectors. Device sets the field, and may change it; see [`2.4`](#24-configuration-change).
+//! * config_generation: Generation of the configuration. Device increases the field on every change.
+//! * config_vector: Vector of the config-change interrupt, or 0 to disable it. Driver sets the field.
 //!
 //! #### 2.1 Device status field
 //! During device initialization by a driver, the driver follows the sequence of steps specified in [`3`](#3-device-initialization).
@@ -63,6 +71,9 @@
 //!
 //! #### 2.1.2 Device Requirements: Device Status Field
 //! The device MUST initialize device status to 0x74726976 upon start and reset.
+//! On reset, the device MUST complete or drop the requests in flight before it sets the status, and MUST clear
+//! queue_size, queue_addr_hi, queue_addr_lo, queue_head, queue_tail and config_vector. The device MUST NOT serve
+//! an entry of the queue configured before the reset.
 //!
 //! The driver MUST enable READY after it validate the queue address and size negotiated
 //! with the virtio driver.
@@ -87,7 +98,25 @@
 //! * addr: Physical address of the buffer
 //! * size: size of the buffer
 //! * sector: sector of the vritual disk
-//! * cmd: indicates the command. 0 is read, and 1 is write.
+//! * cmd: indicates the command. 0 is read, 1 is write, 2 is flush, and 3 is write with the force unit access (FUA).
+//!
+//! #### 2.3 Features
+//! The device advertises the optional commands in the features field:
+//! * bit 0 ([`FEATURE_FLUSH`]): The flush command makes all the completed writes durable. Its addr, size and sector are ignored.
+//! * bit 1 ([`FEATURE_FUA`]): The write with FUA is durable when it completes.
+//!
+//! The driver MUST NOT use a command whose feature is not advertised. Without the features, the device MUST complete
+//! a write only after it is durable.
+//!
+//! The device MAY keep the writes in a volatile cache until a flush, as the [`CachedDisk`] with the policy picked by
+//! the `disk_cache` boot option (`writethrough`, `writeback` or `unsafe`). The device MUST NOT reorder a request
+//! across a flush.
+//!
+//! #### 2.4 Configuration change
+//! The device MAY change the capacity at any time, e.g. after the disk file is resized. On a change, the device
+//! MUST increase config_generation, and, if config_vector is not 0, inject the interrupt of the vector.
+//!
+//! The driver SHOULD read the capacity until config_generation is the same before and after the read.
 //!
 //! ### 3. Device Initialization
 //! The driver MUST follow this sequence to initialize a device:
@@ -95,8 +124,9 @@
 //! 2. Write the DRIVER_OK to the status field.
 //! 3. Check whether status field is still DRIVER_OK.
 //! 4. Perform ring buffer configuration; reading and writing device's ring buffer configuration space.
-//! 5. Set the status field to READY.
-//! 6. Check whether status field is READY. At this point the device is "live".
+//! 5. Optionally, set the config_vector to receive the config-change interrupts.
+//! 6. Set the status field to READY.
+//! 7. Check whether status field is READY. At this point the device is "live".
 //!
 //! The driver MUST NOT send any buffer available notifications to the device before setting READY.
 //!
@@ -112,6 +142,10 @@
 //!     le32 queue_addr_lo;
 //!     le32 queue_head;
 //!     le32 queue_tail;
+//!     le32 features;
+//!     le32 capacity;
+//!     le32 config_generation;
+//!     le32 config_vector;
 //! }
 //! ```
 //! * status: Device status bits. Reading from this register returns the current device status flags. Initialized with magic by device - 0x74726976 (a Little Endian equivalent of the 'virt' string).
@@ -120,6 +154,10 @@
 //! * queue_addr_lo: low part of ring buffer physical address (0-31 bits)
 //! * queue_head: head index of the ring buffer
 //! * queue_tail: tail index of the ring buffer
+//! * features: feature bits of the device
+//! * capacity: capacity of the disk in sectors
+//! * config_generation: generation of the configuration
+//! * config_vector: vector of the config-change interrupt
 //!
 //! ## Tasks
 //! In this project, you are required to implement the device part (backend driver) of Simple Virtio Block Device.
@@ -127,9 +165,44 @@
 //! After that, you can access [`VirtQueueEntry`] through an struct called [`VirtQueueFetcher`].
 //! You can utilize [`VirtQueueFetcher`] to implement this project.
 //!
+//! To exercise the error paths of the device and the guest driver, the disk and the virtqueue can fail on purpose
+//! with [`keos::fault::FaultInjector`]: remount the disk with [`keos::fs::mount`] on a [`keos::fs::FsDisk`] built with
+//! the faults, and reject the virtqueue entries with [`VirtQueue::faults`].
+//!
+//! The queue and its entries are written by the guest, and must not be trusted. Check the negotiated queue with
+//! [`VirtQueueLimits::check_queue`] before setting `READY`, and validate the entries against the guest memory map with
+//! [`VirtQueue::limits`]; a malformed entry then resets the device instead of hanging or crashing the host.
+//!
+//! To serve a request without an intermediate buffer, map the buffer of the entry with [`GuestSlice::new`] and
+//! read or write the disk file straight into the guest pages with [`GuestSlice::fill_with`] and
+//! [`GuestSlice::drain_with`].
+//!
+//! When the driver writes `RESET` to the status, reset the device with [`SimpleVirtioBlockDevInner::reset`] instead of
+//! serving the old queue, and report the capacity of the disk with [`SimpleVirtioBlockDevInner::resize`].
+//!
+//! When several guests share the disk, the entries of their queues can be submitted to an [`Elevator`], which merges
+//! the requests on the adjacent sectors and sorts them by the sector; compare its [`ElevatorStats`] with the
+//! [`Policy::Fifo`] to measure the effects of the I/O scheduling.
+//!
+//! To give each guest its own part of the disk instead, slice the disk with a [`MediatedDisk`]; the entries of a
+//! guest are confined to its slice, and the slices share the disk by their weights.
+//!
+//! [`Elevator`]: crate::dev::elevator::Elevator
+//! [`MediatedDisk`]: crate::dev::mdev::MediatedDisk
+//! [`FEATURE_FLUSH`]: crate::virtio::FEATURE_FLUSH
+//! [`FEATURE_FUA`]: crate::virtio::FEATURE_FUA
+//! [`CachedDisk`]: keos::fs::cache::CachedDisk
+//! [`ElevatorStats`]: crate::dev::elevator::ElevatorStats
+//! [`Policy::Fifo`]: crate::dev::elevator::Policy::Fifo
 //! [`VirtQueue`]: crate::virtio::virt_queue::VirtQueue::new_from_raw_ptr
 //! [`VirtQueueEntry`]: crate::virtio::virt_queue::VirtQueueEntry
 //! [`VirtQueueFetcher`]: crate::virtio::virt_queue::VirtQueueFetcher
+//! [`VirtQueue::faults`]: crate::virtio::virt_queue::VirtQueue::faults
+//! [`VirtQueue::limits`]: crate::virtio::virt_queue::VirtQueue::limits
+//! [`VirtQueueLimits::check_queue`]: crate::virtio::virt_queue::VirtQueueLimits::check_queue
+//! [`GuestSlice::new`]: kev::guest_slice::GuestSlice::new
+//! [`GuestSlice::fill_with`]: kev::guest_slice::GuestSlice::fill_with
+//! [`GuestSlice::drain_with`]: kev::guest_slice::GuestSlice::drain_with
 //!
 use crate::virtio::{
     virt_queue::{VirtQueue, VirtQueueEntry, VirtQueueEntryCmd},
@@ -143,7 +216,7 @@ use keos::{
     sync::SpinLock,
 };
 use kev::{
-    vcpu::{GenericVCpuState, VmexitResult},
+    vcpu::{GenericVCpuState, VCpuOps, VmexitResult},
     vm::Gpa,
     Probe, VmError,
 };
@@ -159,11 +232,47 @@ pub struct SimpleVirtioBlockDevInner {
     file_system: File,
 }
 
+impl SimpleVirtioBlockDevInner {
+    /// Reset the device when the driver writes `RESET` to the status.
+    ///
+    /// A request in flight holds the lock of the device, so it completes
+    /// before the reset. The entries of the old virtqueue are dropped with it,
+    /// and the queue fields of the `header` are cleared; no stale entry is
+    /// served after the driver initializes the device again. The requests
+    /// of the device queued in an [`Elevator`] must be dropped with
+    /// [`Elevator::remove_vm`] as well.
+    ///
+    /// [`Elevator`]: crate::dev::elevator::Elevator
+    /// [`Elevator::remove_vm`]: crate::dev::elevator::Elevator::remove_vm
+    pub fn reset(&mut self, header: &mut VirtIoMmioHeader) {
+        self.virt_queue = None;
+        self.status = VirtIoStatus::MAGIC;
+        header.reset();
+    }
+
+    /// Update the capacity in the `header` to the size of the disk file.
+    ///
+    /// Called on the start and after the disk file is resized. If the driver
+    /// enabled the config-change interrupt, it is injected to the `vcpu`; the
+    /// caller kicks the vcpu to deliver it.
+    pub fn resize(&mut self, header: &mut VirtIoMmioHeader, vcpu: &dyn VCpuOps) {
+        let sectors = (self.file_system.size() / 512) as u32;
+        if let Some(vector) = header.set_capacity(sectors) {
+            vcpu.inject_interrupt(vector);
+        }
+    }
+}
+
 pub struct SimpleVirtIoBlockDev {
     inner: Arc<SpinLock<SimpleVirtioBlockDevInner>>,
 }
 
 impl SimpleVirtIoBlockDev {
+    /// Base address of the mmio region.
+    pub const MMIO_BASE: u64 = 0xcafe0000;
+    /// Size of the mmio region claimed in the guest memory map.
+    pub const MMIO_SIZE: u64 = 0x1000;
+
     pub fn new() -> Self {
         let this = SimpleVirtioBlockDevInner {
             status: VirtIoStatus::MAGIC,
@@ -187,8 +296,8 @@ impl SimpleVirtIoBlockDev {
 impl mmio::MmioHandler for SimpleVirtIoBlockDev {
     fn region(&self) -> MmioRegion {
         MmioRegion {
-            start: Gpa::new(0xcafe0000).unwrap(),
-            end: Gpa::new(0xcafe0000 + size_of::<VirtIoMmioHeader>()).unwrap(),
+            start: Gpa::new(Self::MMIO_BASE as usize).unwrap(),
+            end: Gpa::new(Self::MMIO_BASE as usize + size_of::<VirtIoMmioHeader>()).unwrap(),
         }
     }
 
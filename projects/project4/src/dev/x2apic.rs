@@ -247,7 +247,8 @@ impl msr::Msr for X2Apic {
                 let (tx, rx) = channel(1);
                 todo!();
 
-                ThreadBuilder::new("timer_intr").spawn(move || {
+                let vm = generic_vcpu_state.vm.upgrade().unwrap();
+                ThreadBuilder::new(vm.uuid().thread_name("timer_intr")).spawn(move || {
                     // Hint:
                     //    - Receive the deadline from the rx.
                     //    - Wait until time stamp exceeds the deadline.
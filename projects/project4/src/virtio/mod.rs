@@ -1,6 +1,8 @@
 //! Simple Virtio block device
 pub mod virt_queue;
 
+use core::ptr::{read_volatile, write_volatile};
+
 /// The header of the virtio device.
 #[repr(C)]
 #[derive(Debug)]
@@ -21,8 +23,33 @@ pub struct VirtIoMmioHeader {
     ///
     /// Device update the tail of the queue. Driver must not update the field.
     pub queue_tail: u32,
+    /// Features of the device.
+    ///
+    /// Device sets the field when initialization. Driver must not update the
+    /// field.
+    pub features: u32,
+    /// Capacity of the disk in sectors.
+    ///
+    /// Device sets the field, and may change it at any time.
+    pub capacity: u32,
+    /// Generation of the configuration.
+    ///
+    /// Device increases the field on every change of the configuration.
+    /// Driver must not update the field.
+    pub config_generation: u32,
+    /// Vector of the config-change interrupt, or 0 to disable it.
+    pub config_vector: u32,
 }
 
+/// The device serves [`VirtQueueEntryCmd::Flush`].
+///
+/// [`VirtQueueEntryCmd::Flush`]: virt_queue::VirtQueueEntryCmd::Flush
+pub const FEATURE_FLUSH: u32 = 1 << 0;
+/// The device serves [`VirtQueueEntryCmd::WriteFua`].
+///
+/// [`VirtQueueEntryCmd::WriteFua`]: virt_queue::VirtQueueEntryCmd::WriteFua
+pub const FEATURE_FUA: u32 = 1 << 1;
+
 impl VirtIoMmioHeader {
     pub fn new() -> Self {
         VirtIoMmioHeader {
@@ -32,6 +59,43 @@ impl VirtIoMmioHeader {
             queue_addr_lo: 0,
             queue_head: 0,
             queue_tail: 0,
+            features: 0,
+            capacity: 0,
+            config_generation: 0,
+            config_vector: 0,
+        }
+    }
+
+    /// Reset the header to the state before the driver initialization.
+    ///
+    /// The queue and the config-change vector negotiated by the driver are
+    /// cleared, and the status is set to `MAGIC`. The fields set by the
+    /// device are kept.
+    pub fn reset(&mut self) {
+        unsafe {
+            write_volatile(&mut self.queue_size, 0);
+            write_volatile(&mut self.queue_addr_hi, 0);
+            write_volatile(&mut self.queue_addr_lo, 0);
+            write_volatile(&mut self.queue_head, 0);
+            write_volatile(&mut self.queue_tail, 0);
+            write_volatile(&mut self.config_vector, 0);
+            write_volatile(&mut self.status, VirtIoStatus::MAGIC as u32);
+        }
+    }
+
+    /// Change the capacity of the disk to `sectors`.
+    ///
+    /// Returns the vector of the config-change interrupt to deliver, if the
+    /// driver enabled it.
+    pub fn set_capacity(&mut self, sectors: u32) -> Option<u8> {
+        unsafe {
+            write_volatile(&mut self.capacity, sectors);
+            let generation = read_volatile(&self.config_generation);
+            write_volatile(&mut self.config_generation, generation.wrapping_add(1));
+            match read_volatile(&self.config_vector) {
+                0 => None,
+                vector => u8::try_from(vector).ok(),
+            }
         }
     }
 }
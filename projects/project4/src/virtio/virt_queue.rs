@@ -1,11 +1,23 @@
 //! Virtqueue implementation
+//!
+//! The entries and the indices of the virtqueue are written by the guest, so
+//! the device validates them against the [`VirtQueueLimits`] before it trusts
+//! them: an index out of the queue never reaches the consumed index and hangs
+//! the device, and a huge buffer size makes an oversized copy. An invalid
+//! entry is reported to the guest by setting the device status to `RESET`;
+//! see [`VirtQueueFetcher::pop_back`].
 use super::VirtIoMmioHeader;
 use alloc::{boxed::Box, vec::Vec};
 use core::{
     fmt::Debug,
+    ops::Range,
     ptr::{read_volatile, write_volatile},
 };
-use keos::addressing::{Pa, Va};
+use keos::{
+    addressing::{Pa, Va},
+    fault::FaultInjector,
+    fs::{record, Record},
+};
 
 /// Command for the virtqueue.
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -15,6 +27,12 @@ pub enum VirtQueueEntryCmd {
     Read = 0,
     /// Write
     Write = 1,
+    /// Make the completed writes durable. Requires the
+    /// [`FEATURE_FLUSH`](super::FEATURE_FLUSH).
+    Flush = 2,
+    /// Write, and make it durable before completing. Requires the
+    /// [`FEATURE_FUA`](super::FEATURE_FUA).
+    WriteFua = 3,
 }
 
 /// An entry for the virtqueue.
@@ -27,6 +45,147 @@ pub struct VirtQueueEntry {
     pub cmd: VirtQueueEntryCmd,
 }
 
+record! {
+    // The layout of the entry in the guest memory.
+    struct RawVirtQueueEntry {
+        addr: u64,
+        size: u64,
+        sector: u64,
+        cmd: u32,
+    }
+}
+
+/// A violation of the [`VirtQueueLimits`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VirtQueueError {
+    /// The size of the queue exceeds the limit.
+    QueueTooLarge(usize),
+    /// The queue is not in the guest RAM.
+    QueueOutOfRam(usize),
+    /// The head or the tail index is out of the queue.
+    BadIndex {
+        /// The head index.
+        head: usize,
+        /// The tail index.
+        tail: usize,
+    },
+    /// More entries than the limit are pending at once.
+    TooManyEntries(usize),
+    /// The entry at the `index` has an unknown command.
+    BadCommand {
+        /// The index of the entry.
+        index: usize,
+        /// The raw command.
+        cmd: u32,
+    },
+    /// The buffer of the entry at the `index` exceeds the size limit.
+    BufferTooLarge {
+        /// The index of the entry.
+        index: usize,
+        /// The size of the buffer.
+        size: usize,
+    },
+    /// The buffer of the entry at the `index` is not in the guest RAM.
+    BufferOutOfRam {
+        /// The index of the entry.
+        index: usize,
+        /// The address of the buffer.
+        addr: usize,
+        /// The size of the buffer.
+        size: usize,
+    },
+}
+
+/// Limits that the guest-written virtqueue is validated against.
+#[derive(Clone, Debug)]
+pub struct VirtQueueLimits {
+    /// Maximum number of the entries of the queue.
+    pub max_queue_size: usize,
+    /// Maximum number of the entries that are fetched at once.
+    pub max_pending: usize,
+    /// Maximum size of the buffer of an entry, in bytes.
+    pub max_buffer_size: usize,
+    /// The RAM ranges of the guest, e.g. of the e820 memory map, where the
+    /// queue and the buffers must be in. Unchecked if `None`.
+    pub ram: Option<Vec<Range<usize>>>,
+}
+
+impl Default for VirtQueueLimits {
+    fn default() -> Self {
+        Self {
+            max_queue_size: 1024,
+            max_pending: 1024,
+            max_buffer_size: 1 << 20,
+            ram: None,
+        }
+    }
+}
+
+impl VirtQueueLimits {
+    // Check whether `len` bytes at `addr` are in the guest RAM.
+    fn in_ram(&self, addr: usize, len: usize) -> bool {
+        match (&self.ram, addr.checked_add(len)) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(ram), Some(end)) => {
+                ram.iter()
+                    .map(|r| r.end.min(end).saturating_sub(r.start.max(addr)))
+                    .sum::<usize>()
+                    == len
+            }
+        }
+    }
+
+    /// Check the queue of `size` entries at `addr`, negotiated by the driver.
+    ///
+    /// The device must check the queue before it sets the status to `READY`.
+    pub fn check_queue(&self, size: usize, addr: usize) -> Result<(), VirtQueueError> {
+        if size == 0 || size > self.max_queue_size {
+            return Err(VirtQueueError::QueueTooLarge(size));
+        }
+        if !self.in_ram(addr, size * core::mem::size_of::<VirtQueueEntry>()) {
+            return Err(VirtQueueError::QueueOutOfRam(addr));
+        }
+        Ok(())
+    }
+
+    // Check the entry at the `index` of the `queue`, copied out at once as the
+    // guest may modify it concurrently.
+    fn check_entry(
+        &self,
+        queue: &[VirtQueueEntry],
+        index: usize,
+    ) -> Result<VirtQueueEntry, VirtQueueError> {
+        let bytes = unsafe {
+            read_volatile(
+                &queue[index] as *const VirtQueueEntry
+                    as *const [u8; core::mem::size_of::<VirtQueueEntry>()],
+            )
+        };
+        let raw = RawVirtQueueEntry::decode(&bytes).expect("virtqueue entry is too small");
+        let (addr, size, sector) = (raw.addr as usize, raw.size as usize, raw.sector as usize);
+        let cmd = match raw.cmd {
+            0 => VirtQueueEntryCmd::Read,
+            1 => VirtQueueEntryCmd::Write,
+            2 => VirtQueueEntryCmd::Flush,
+            3 => VirtQueueEntryCmd::WriteFua,
+            cmd => return Err(VirtQueueError::BadCommand { index, cmd }),
+        };
+        if size > self.max_buffer_size {
+            return Err(VirtQueueError::BufferTooLarge { index, size });
+        }
+        match Pa::new(addr) {
+            Some(pa) if self.in_ram(addr, size) => Ok(VirtQueueEntry {
+                addr: pa,
+                size,
+                sector,
+                cmd,
+            }),
+            _ => Err(VirtQueueError::BufferOutOfRam { index, addr, size }),
+        }
+    }
+}
+
 /// A container for holding virtqueue.
 #[repr(C)]
 pub struct VirtQueue<T>
@@ -34,6 +193,8 @@ where
     T: core::ops::Deref<Target = [VirtQueueEntry]>,
 {
     entries: T,
+    faults: FaultInjector,
+    limits: VirtQueueLimits,
 }
 
 impl<T> core::ops::Index<usize> for VirtQueue<T>
@@ -81,7 +242,11 @@ impl VirtQueue<Box<[VirtQueueEntry]>> {
             })
             .collect::<Vec<_>>()
             .into_boxed_slice();
-        VirtQueue { entries }
+        VirtQueue {
+            entries,
+            faults: FaultInjector::new(),
+            limits: VirtQueueLimits::default(),
+        }
     }
     /// Get a virtual address of the virtqueue.
     pub fn virt_queue_ptr(&self) -> usize {
@@ -90,12 +255,18 @@ impl VirtQueue<Box<[VirtQueueEntry]>> {
 }
 impl VirtQueue<&'static [VirtQueueEntry]> {
     /// Get a virtqueue from Va.
+    ///
+    /// The `size` must be checked with [`VirtQueueLimits::check_queue`].
     pub unsafe fn new_from_raw_ptr(size: usize, queue_va: Va) -> Self {
         let entries = unsafe {
             core::slice::from_raw_parts(queue_va.into_usize() as *mut VirtQueueEntry, size)
         };
 
-        VirtQueue { entries }
+        VirtQueue {
+            entries,
+            faults: FaultInjector::new(),
+            limits: VirtQueueLimits::default(),
+        }
     }
 }
 
@@ -103,15 +274,38 @@ impl<T> VirtQueue<T>
 where
     T: core::ops::Deref<Target = [VirtQueueEntry]>,
 {
+    /// Reject the entries of the virtqueue with the `faults`.
+    ///
+    /// See [`VirtQueueFetcher::pop_back`] for how the rejection is reported.
+    pub fn faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Validate the entries of the virtqueue against the `limits`.
+    pub fn limits(mut self, limits: VirtQueueLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Get a fetcher object of the virtqueue.
     pub fn fetcher<'a>(&'a mut self, mmio: &'a mut VirtIoMmioHeader) -> VirtQueueFetcher<T> {
         let head = unsafe { read_volatile(&mmio.queue_head as *const u32) as usize };
         let tail = unsafe { read_volatile(&mmio.queue_tail as *const u32) as usize };
+        let size = self.entries.len();
+        let error = if head >= size || tail >= size {
+            Some(VirtQueueError::BadIndex { head, tail })
+        } else {
+            None
+        };
         VirtQueueFetcher {
             inner: self,
             mmio,
             head,
             tail,
+            rejected: error.is_some(),
+            popped: 0,
+            error,
         }
     }
 }
@@ -125,6 +319,9 @@ where
     mmio: &'a mut VirtIoMmioHeader,
     head: usize,
     tail: usize,
+    rejected: bool,
+    popped: usize,
+    error: Option<VirtQueueError>,
 }
 
 impl<'a, T> VirtQueueFetcher<'a, T>
@@ -146,22 +343,70 @@ where
     fn is_full(&self) -> bool {
         self.size() == self.charge()
     }
+
+    /// Get the violation of the limits found while fetching, if any.
+    pub fn error(&self) -> Option<&VirtQueueError> {
+        self.error.as_ref()
+    }
 }
 
 impl<'a> VirtQueueFetcher<'a, &'static [VirtQueueEntry]> {
     /// Pop a single entry to the virtqueue.
+    ///
+    /// If the entry is rejected by the injected fault, no more entry is popped
+    /// and the following [`ack`] fails without acknowledging any entry, as if
+    /// the entry is malformed.
+    ///
+    /// The indices and the entries are validated against the limits of the
+    /// virtqueue. On a violation, no more entry is popped, and the following
+    /// [`ack`] fails and sets the device status to `RESET`; the violation is
+    /// available with [`error`].
+    ///
+    /// [`ack`]: VirtQueueFetcher::ack
+    /// [`error`]: VirtQueueFetcher::error
     pub fn pop_back(&mut self) -> Option<VirtQueueEntry> {
+        if self.rejected {
+            return None;
+        }
+        if !self.is_empty() && self.inner.faults.should_fail() {
+            self.rejected = true;
+            return None;
+        }
         if !self.is_empty() {
             let size = self.size();
-            let r = self.inner.entries[self.tail];
-            self.tail = (self.tail + 1) % size;
-            Some(r)
+            let entry = if self.popped == self.inner.limits.max_pending {
+                Err(VirtQueueError::TooManyEntries(self.popped + 1))
+            } else {
+                self.inner.limits.check_entry(self.inner.entries, self.tail)
+            };
+            match entry {
+                Ok(r) => {
+                    self.tail = (self.tail + 1) % size;
+                    self.popped += 1;
+                    Some(r)
+                }
+                Err(e) => {
+                    self.rejected = true;
+                    self.error = Some(e);
+                    None
+                }
+            }
         } else {
             None
         }
     }
     /// Acknowledge the consumed request.
     pub fn ack(self) -> Result<(), ()> {
+        if self.error.is_some() {
+            // The guest must reset the device to recover.
+            unsafe {
+                write_volatile(&mut self.mmio.status, super::VirtIoStatus::RESET as u32);
+            }
+            return Err(());
+        }
+        if self.rejected {
+            return Err(());
+        }
         // The sequence of the update in this function
         // is really important. Do not change the order.
         unsafe {
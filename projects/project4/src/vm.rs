@@ -1,12 +1,21 @@
 //! Vm to run keos.
 
 use alloc::sync::Arc;
-use keos::{fs::file_system, mm::Page, spin_lock::SpinLock};
+use keos::{addressing::Pa, fs::file_system, mm::Page, spin_lock::SpinLock};
 use kev::{
+    cpuid::PvFeatures,
+    entry_state::GuestEntryState,
+    guest_panic::CrashReport,
+    io_bitmap::IoBitmap,
+    namespace::{Namespace, Resource},
+    shared_fs::SharedFolder,
+    smbios,
+    syscall_trace::SyscallTrace,
     vcpu::{Cr0, Cr4, GenericVCpuState, Rflags, VmexitResult},
+    vm::Gpa,
     vm_control::*,
-    vmcs::{ActiveVmcs, Field},
-    vmexits::VmexitController,
+    vmcs::{ActiveVmcs, BasicExitReason, EptViolationQualification, Field},
+    vmexits::{ControllerStack, ExitDispatch, VmexitController},
     VmError,
 };
 use pager::KernelVmPager;
@@ -16,8 +25,9 @@ use project2::{
 };
 use project3::{
     keos_vm::{
-        dev::{self, CmosPio, ExitPio, PciPio},
+        dev::{self, CmosPio, ExitPio, PciPio, SerialPio},
         pager,
+        symbols::LazySymbols,
     },
     vmexit::mmio,
 };
@@ -29,54 +39,91 @@ pub struct VmState {
     virtio: Arc<SpinLock<SimpleVirtIoBlockDev>>,
     pager: Arc<SpinLock<KernelVmPager>>,
     io_bmap: Arc<(Page, Page)>,
+    syscall_trace: SyscallTrace,
+    crash_report: CrashReport,
+    // The symbols of the guest kernel, to resolve the panics.
+    symbols: Arc<LazySymbols>,
+    shared_folder: SharedFolder,
+    namespace: Namespace,
 }
 
 impl VmState {
     pub fn new(ram_in_kib: usize) -> Option<Self> {
-        let (mut io_bmap_a, mut io_bmap_b) = (Page::new()?, Page::new()?);
-        unsafe {
-            io_bmap_a.inner_mut().fill(0xff);
-            io_bmap_b.inner_mut().fill(0xff);
-            for port in [
-                0x3f8, 0x3f9, 0x3fa, 0x3fb, 0x3fc, 0x3fd, 0x84, // Serial series.
-                0x20, 0x21, 0xa0, 0xa1, // 8259A interrupt controller series.
-                0x42, 0x43, 0x61, // PIT
-            ] {
-                if port >= 0x8000 {
-                    let p = port - 0x8000;
-                    io_bmap_b.inner_mut()[p / 8] &= !(1 << (p % 8));
-                } else {
-                    let p = port;
-                    io_bmap_a.inner_mut()[p / 8] &= !(1 << (p % 8));
-                }
-            }
-        }
-
-        let io_bmap = Arc::new((io_bmap_a, io_bmap_b));
-        let pager = Arc::new(SpinLock::new(KernelVmPager::from_image(
+        let io_bmap = Arc::new(
+            IoBitmap::new()
+                .allow(0x84..=0x84)
+                .allow(0x20..=0x21) // 8259A interrupt controller series.
+                .allow(0xa0..=0xa1)
+                .allow(0x42..=0x43) // PIT
+                .allow(0x61..=0x61)
+                .build()?,
+        );
+        let mut pager = KernelVmPager::from_image(
             file_system()
                 .expect("Filesystem is not exist.")
                 .open("gKeOS")
                 .expect("gKeOS is not exist."),
             ram_in_kib,
-        )?));
+        )?;
+        pager
+            .guest_memory_map_mut()
+            .claim_mmio(
+                SimpleVirtIoBlockDev::MMIO_BASE,
+                SimpleVirtIoBlockDev::MMIO_SIZE,
+                "svirtb",
+            )
+            .ok()?;
+        let pager = Arc::new(SpinLock::new(pager));
         let virtio = Arc::new(SpinLock::new(SimpleVirtIoBlockDev::new()));
+        let shared_folder = SharedFolder::new("shared/");
+        let namespace = Namespace::new();
+        namespace
+            .register("shared", Resource::SharedFolder(shared_folder.clone()))
+            .ok()?;
 
         Some(VmState {
             virtio,
             pager,
             io_bmap,
+            syscall_trace: SyscallTrace::new(),
+            crash_report: CrashReport::new(),
+            symbols: Arc::new(LazySymbols::new("gKeOS")),
+            shared_folder,
+            namespace,
         })
     }
 }
 
+impl VmState {
+    /// Get the trace of the system calls that the guest reports.
+    pub fn syscall_trace(&self) -> &SyscallTrace {
+        &self.syscall_trace
+    }
+
+    /// Get the panics that the guest reports.
+    pub fn crash_report(&self) -> &CrashReport {
+        &self.crash_report
+    }
+
+    /// Get the folder shared with the guest, the files named `shared/*`.
+    pub fn shared_folder(&self) -> &SharedFolder {
+        &self.shared_folder
+    }
+
+    /// Get the host resources visible to the guest.
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+}
+
 impl kev::vm::VmState for VmState {
     type VcpuState = VcpuState;
     type Error = VmError;
 
     fn vcpu_state(&self) -> Self::VcpuState {
+        let memory_map = self.pager.lock().guest_memory_map().clone();
         let (mut mmio_ctl, mut pio_ctl, hypercall_ctl, cpuid_ctl, mut msr_ctl) = (
-            mmio::Controller::new(),
+            mmio::Controller::new().with_memory_map(memory_map),
             pio::Controller::new(),
             hypercall::Controller::new(HypercallCtx),
             cpuid::Controller::new(),
@@ -94,95 +141,96 @@ impl kev::vm::VmState for VmState {
         X2Apic::attach(&mut msr_ctl);
         assert!(pio_ctl.register(0xCF8, PciPio));
         assert!(pio_ctl.register(0xCFC, PciPio));
-        assert!(pio_ctl.register(0x70, CmosPio));
-        assert!(pio_ctl.register(0x71, CmosPio));
-        assert!(pio_ctl.register(0x604, ExitPio));
+        let cmos = CmosPio::default();
+        assert!(pio_ctl.register(0x70, cmos.clone()));
+        assert!(pio_ctl.register(0x71, cmos));
+        assert!(pio_ctl.register(kev::shutdown::PM1A_CNT, ExitPio));
+        // Serial series.
+        for port in 0x3f8..=0x3ff {
+            assert!(pio_ctl.register(port, SerialPio));
+        }
+
+        // The hypercalls of kev are served before the ones of the project.
+        let mut hypercalls = ControllerStack::new();
+        hypercalls.push(kev::shutdown::Controller::new());
+        hypercalls.push(kev::watchdog::Controller::new());
+        hypercalls.push(kev::syscall_trace::Controller::new(self.syscall_trace.clone()));
+        hypercalls.push(
+            kev::guest_panic::Controller::new(self.crash_report.clone())
+                .symbolizer(self.symbols.clone()),
+        );
+        hypercalls.push(kev::shared_fs::Controller::new(self.shared_folder.clone()));
+        hypercalls.push(kev::namespace::Controller::new(self.namespace.clone()));
+        hypercalls.push(kev::metrics::Controller::new());
+        hypercalls.push(kev::async_call::Controller::new());
+        hypercalls.insert(ControllerStack::LOWEST, hypercall_ctl);
+
+        let mut vmexit_controller = ExitDispatch::new();
+        vmexit_controller
+            .register(
+                [BasicExitReason::EptViolation {
+                    qualification: EptViolationQualification::empty(),
+                    fault_addr: None,
+                }],
+                mmio_ctl,
+            )
+            .register([BasicExitReason::IoInstruction], pio_ctl)
+            .register(
+                [BasicExitReason::Vmcall],
+                kev::batch::Controller::new(hypercalls),
+            )
+            .register([BasicExitReason::Cpuid], cpuid_ctl)
+            .register([BasicExitReason::Rdmsr, BasicExitReason::Wrmsr], msr_ctl);
 
         VcpuState {
             pager: self.pager.clone(),
-            vmexit_controller: (mmio_ctl, (pio_ctl, (hypercall_ctl, (cpuid_ctl, msr_ctl)))),
+            vmexit_controller,
             io_bmap: self.io_bmap.clone(),
         }
     }
 
+    fn memory_map(&self) -> Option<kev::e820::MemoryMap> {
+        Some(self.pager.lock().memory_map())
+    }
+
+    fn gpa2hpa(&self, gpa: Gpa) -> Option<Pa> {
+        self.pager.lock().populated(gpa)
+    }
+
+    fn pv_features(&self) -> PvFeatures {
+        // The kvmclock msr is forwarded to the host.
+        PvFeatures::PVCLOCK | PvFeatures::METRICS | PvFeatures::BATCH | PvFeatures::ASYNC
+    }
+
     fn setup_vbsp(
         &self,
         vbsp_generic_state: &mut GenericVCpuState,
         vbsp_vcpu_state: &mut Self::VcpuState,
     ) -> Result<(), Self::Error> {
-        vbsp_generic_state
-            .vmcs
-            .write(Field::GuestRip, self.pager.lock().entry() as u64)?;
-        vbsp_generic_state.vmcs.write(Field::GuestRsp, 0xa0000)?;
+        let entry = self.pager.lock().entry() as u64;
+        // Place the SMBIOS tables on the legacy BIOS area.
+        if let Some(vm) = vbsp_generic_state.vm.upgrade() {
+            let tables = smbios::build(vm.uuid(), vm.vcpu_count(), smbios::SMBIOS_BASE as u32);
+            vbsp_vcpu_state.pager.lock().loaders.insert(
+                Gpa::new(smbios::SMBIOS_BASE).unwrap(),
+                Arc::new(move |page: &mut Page| {
+                    unsafe {
+                        page.inner_mut()[..tables.len()].copy_from_slice(&tables);
+                    }
+                    true
+                }),
+            );
+        }
         vbsp_generic_state.gprs.rsi = vbsp_vcpu_state
             .pager
             .lock()
             .finalize_mem()
             .expect("Failed to finalize the memory.");
 
-        let vmcs = &vbsp_generic_state.vmcs;
-        vmcs.write(Field::GuestCsSelector, 0x10)?;
-        vmcs.write(Field::GuestCsBase, 0)?;
-        vmcs.write(Field::GuestCsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestCsAccessRights, 0xc09b)?;
-
-        vmcs.write(Field::GuestEsSelector, 0x18)?;
-        vmcs.write(Field::GuestEsBase, 0)?;
-        vmcs.write(Field::GuestEsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestEsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestSsSelector, 0x18)?;
-        vmcs.write(Field::GuestSsBase, 0)?;
-        vmcs.write(Field::GuestSsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestSsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestDsSelector, 0x18)?;
-        vmcs.write(Field::GuestDsBase, 0)?;
-        vmcs.write(Field::GuestDsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestDsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestFsSelector, 0x18)?;
-        vmcs.write(Field::GuestFsBase, 0)?;
-        vmcs.write(Field::GuestFsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestFsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestGsSelector, 0x18)?;
-        vmcs.write(Field::GuestGsBase, 0)?;
-        vmcs.write(Field::GuestGsLimit, 0xffffffff)?;
-        vmcs.write(Field::GuestGsAccessRights, 0xc093)?;
-
-        vmcs.write(Field::GuestTrSelector, 0)?;
-        vmcs.write(Field::GuestTrBase, 0)?;
-        vmcs.write(Field::GuestTrLimit, 0)?;
-        vmcs.write(Field::GuestTrAccessRights, 0x8b)?;
-
-        vmcs.write(Field::GuestLdtrSelector, 0)?;
-        vmcs.write(Field::GuestLdtrBase, 0)?;
-        vmcs.write(Field::GuestLdtrLimit, 0)?;
-        vmcs.write(Field::GuestLdtrAccessRights, 0x82)?;
-
-        vmcs.write(Field::GuestGdtrBase, 0)?;
-        vmcs.write(Field::GuestGdtrLimit, 0)?;
-
-        vmcs.write(Field::GuestIdtrBase, 0)?;
-        vmcs.write(Field::GuestIdtrLimit, 0)?;
-        // CR0=00000011 CR3=00000000 CR4=00000000
-        vmcs.write(Field::GuestCr0, (Cr0::NE | Cr0::PE).bits())?;
-        vmcs.write(Field::GuestCr3, 0)?;
-        vmcs.write(Field::GuestCr4, Cr4::VMXE.bits())?;
-
-        // Guest non-register state.
-        vmcs.write(Field::GuestActivityState, 0)?;
-        vmcs.write(Field::GuestInterruptibilityState, 0)?;
-        vmcs.write(Field::GuestLinkPointer, 0xffff_ffff)?;
-        vmcs.write(Field::GuestLinkPointerHi, 0xffff_ffff)?;
-
-        // DR7=0000000000000400
-        vmcs.write(Field::GuestDr7, 0)?;
-        vmcs.write(Field::GuestIa32Debugctl, 0)?;
-
-        // EFL=00200002
-        vmcs.write(Field::GuestRflags, Rflags::_1.bits())?;
+        GuestEntryState::protected_flat()
+            .rip(entry)
+            .rsp(0xa0000)
+            .write(&vbsp_generic_state.vmcs)?;
 
         Ok(())
     }
@@ -262,16 +310,7 @@ impl kev::vm::VmState for VmState {
 /// The Vcpu state of NoEptVmState.
 pub struct VcpuState {
     pager: Arc<SpinLock<KernelVmPager>>,
-    vmexit_controller: (
-        mmio::Controller,
-        (
-            pio::Controller,
-            (
-                hypercall::Controller<HypercallCtx>,
-                (cpuid::Controller, msr::Controller),
-            ),
-        ),
-    ),
+    vmexit_controller: ExitDispatch,
     io_bmap: Arc<(Page, Page)>,
 }
 
@@ -280,9 +319,7 @@ impl kev::vcpu::VCpuState for VcpuState {
         VmcsPinBasedVmexecCtl::EXTERNAL_INTERRUPT_EXITING
     }
     fn procbase_ctls(&self) -> VmcsProcBasedVmexecCtl {
-        VmcsProcBasedVmexecCtl::HLT_EXITING
-            | VmcsProcBasedVmexecCtl::UNCONDIOEXIT
-            | VmcsProcBasedVmexecCtl::USEIOBMP
+        VmcsProcBasedVmexecCtl::HLT_EXITING | VmcsProcBasedVmexecCtl::UNCONDIOEXIT
     }
     fn procbase_ctls2(&self) -> VmcsProcBasedSecondaryVmexecCtl {
         VmcsProcBasedSecondaryVmexecCtl::ENABLE_RDTSCP
@@ -292,6 +329,9 @@ impl kev::vcpu::VCpuState for VcpuState {
     fn entry_ctls(&self) -> VmcsEntryCtl {
         VmcsEntryCtl::LOAD_IA32_EFER
     }
+    fn io_bitmap(&self) -> Option<(Pa, Pa)> {
+        Some((self.io_bmap.0.pa(), self.io_bmap.1.pa()))
+    }
     fn exit_ctls(&self) -> VmcsExitCtl {
         VmcsExitCtl::ACK_INTR_ON_EXIT
             | VmcsExitCtl::HOST_ADDRESS_SPACE_SIZE
@@ -303,18 +343,6 @@ impl kev::vcpu::VCpuState for VcpuState {
             self.pager.lock().ept_ptr().into_usize() as u64 | (3 << 3) | 6
         })?;
 
-        // If the “use I/O bitmaps” VM-execution control is 1, bits 11:0 of each I/O-bitmap address must be 0. Neither
-        // address should set any bits beyond the processor’s physical-address width.1,2
-        {
-            vmcs.write(
-                Field::IoBitmapA,
-                unsafe { self.io_bmap.0.pa().into_usize() } as u64,
-            )?;
-            vmcs.write(
-                Field::IoBitmapB,
-                unsafe { self.io_bmap.1.pa().into_usize() } as u64,
-            )?;
-        }
         Ok(())
     }
 
@@ -1,4 +1,5 @@
 use core::panic;
+use std::path::Path;
 
 include!("../build.rs");
 
@@ -29,7 +29,8 @@
 //! ### Others
 //! - Use binary translate to support shadow page table on an x86 CPU without Extended Page Table
 //! - Use binary translate to support trap-and-emulate semantics on an x86 CPU without VMX or SVM support
-//! - Implement vmfunc based IPC (skybridge)
+//! - Extend the enclave on the EPT views (e.g. attestation, asynchronous exits). See [`project3::vmexit::enclave`].
+//! - Implement vmfunc based IPC (skybridge). See [`kev::vmfunc`] and [`project3::ept::ExtendedPageTable::share`].
 //! - Run another operating system on KeV
 //! - Any topic related to the virtualization that you want
 //!